@@ -0,0 +1,200 @@
+//! Route and neighbor lookups from many threads at once.
+//!
+//! Every thread looks up random destinations in a table of 1024 routes, or
+//! random neighbors in a full neighbor cache, through the snapshot the data
+//! path reads and through the same table behind a lock, which is how lookups
+//! went before. Each is run on its own and with another thread updating the
+//! table as fast as it can. Throughput only scales with the threads up to
+//! the number of cores:
+//!
+//! ```text
+//! cargo bench --bench lookup_contention
+//! ```
+
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Barrier, Mutex, RwLock};
+use std::thread;
+use std::time::Instant;
+
+use microps::context::{RouteSnapshot, RouteTable};
+use microps::device::DeviceIndex;
+use microps::device::ether::EtherAddr;
+use microps::iface::IpIface;
+use microps::protocol::ip::IpAddr;
+use microps::protocol::ipv6::Ipv6Addr;
+use microps::protocol::ndp::NeighborCache;
+
+const ROUTES: usize = 1024;
+const NEIGHBORS: usize = 64;
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+const LOOKUPS: usize = 500_000;
+
+/// xorshift64, so every run benchmarks the same tables
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u32
+    }
+}
+
+fn addr(n: u32) -> IpAddr {
+    IpAddr::from_ne_bytes(n.to_be_bytes())
+}
+
+fn netmask(len: u32) -> IpAddr {
+    addr(u32::MAX.checked_shl(32 - len).unwrap_or(0))
+}
+
+fn neighbor(n: usize) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets[..2].copy_from_slice(&[0xfe, 0x80]);
+    octets[8..].copy_from_slice(&(n as u64).to_be_bytes());
+    Ipv6Addr::from_octets(octets)
+}
+
+/// Run `lookup` `LOOKUPS` times on each of `threads` threads, with `update`
+/// running in a loop on another one if given; millions of lookups per second,
+/// all threads together
+fn run(
+    threads: usize,
+    lookup: &(dyn Fn(u32) + Sync),
+    update: Option<&(dyn Fn(u32) + Sync)>,
+) -> f64 {
+    let barrier = Barrier::new(threads + 1);
+    let done = AtomicBool::new(false);
+    let elapsed = thread::scope(|s| {
+        if let Some(update) = update {
+            s.spawn(|| {
+                let mut n = 0;
+                while !done.load(Ordering::Relaxed) {
+                    update(n);
+                    n = n.wrapping_add(1);
+                }
+            });
+        }
+        let readers: Vec<_> = (0..threads)
+            .map(|i| {
+                let barrier = &barrier;
+                s.spawn(move || {
+                    let mut rng = Rng(0x9e37_79b9_7f4a_7c15 + i as u64);
+                    barrier.wait();
+                    for _ in 0..LOOKUPS {
+                        lookup(rng.next());
+                    }
+                })
+            })
+            .collect();
+        barrier.wait();
+        let start = Instant::now();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+        done.store(true, Ordering::Relaxed);
+        elapsed
+    });
+    (threads * LOOKUPS) as f64 / elapsed.as_micros() as f64
+}
+
+fn report(name: &str, lookup: &(dyn Fn(u32) + Sync), update: &(dyn Fn(u32) + Sync)) {
+    for updating in [false, true] {
+        let label = if updating {
+            format!("{} +update", name)
+        } else {
+            name.to_string()
+        };
+        print!("{:<22}", label);
+        for threads in THREADS {
+            let rate = run(threads, lookup, updating.then_some(update));
+            print!("  {:>10.1}", rate);
+        }
+        println!();
+    }
+}
+
+fn main() {
+    let iface = IpIface::new("192.0.2.2", "255.255.255.0", DeviceIndex(0)).unwrap();
+    let nexthop = addr(0xc000_0201);
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+    let table = RouteTable::with_limit(ROUTES + 1);
+    table
+        .add(IpAddr::ANY, IpAddr::ANY, nexthop, iface.clone())
+        .unwrap();
+    while table.snapshot().len() <= ROUTES {
+        let netmask = netmask(8 + rng.next() % 23);
+        let network = addr(rng.next()) & netmask;
+        let _ = table.add(network, netmask, nexthop, iface.clone());
+    }
+    // Flaps a /32 no lookup lands on in particular
+    let flap = |n: u32| {
+        let host = addr(0xc633_6400 | (n & 0xff));
+        let _ = table.remove(host, IpAddr::BROADCAST);
+        let _ = table.add(host, IpAddr::BROADCAST, nexthop, iface.clone());
+    };
+    let locked = RwLock::new(table.snapshot());
+    let locked_flap = |_| {
+        // What an update under the stack's write lock amounted to
+        *locked.write().unwrap() = table.snapshot();
+    };
+
+    let hwaddr = EtherAddr::from_seed("bench");
+    let neighbors = NeighborCache::with_limit(NEIGHBORS);
+    let mut locked_neighbors = HashMap::new();
+    for n in 0..NEIGHBORS {
+        neighbors.insert(neighbor(n), hwaddr, DeviceIndex(0));
+        locked_neighbors.insert(neighbor(n), hwaddr);
+    }
+    let locked_neighbors = Mutex::new(locked_neighbors);
+    let relearn = |n: u32| {
+        let addr = neighbor(n as usize % NEIGHBORS);
+        neighbors.insert(addr, hwaddr, DeviceIndex(0));
+    };
+    let locked_relearn = |n: u32| {
+        let addr = neighbor(n as usize % NEIGHBORS);
+        locked_neighbors.lock().unwrap().insert(addr, hwaddr);
+    };
+
+    println!("million lookups per second");
+    print!("{:<22}", "threads");
+    for threads in THREADS {
+        print!("  {:>10}", threads);
+    }
+    println!();
+    report(
+        "route snapshot",
+        &|n| {
+            black_box(table.lookup(addr(n)));
+        },
+        &flap,
+    );
+    report(
+        "route rwlock",
+        &|n| {
+            let routes: &RouteSnapshot = &locked.read().unwrap();
+            black_box(routes.lookup(addr(n)));
+        },
+        &locked_flap,
+    );
+    report(
+        "neighbor snapshot",
+        &|n| {
+            black_box(neighbors.lookup(neighbor(n as usize % NEIGHBORS)));
+        },
+        &relearn,
+    );
+    report(
+        "neighbor mutex",
+        &|n| {
+            let addr = neighbor(n as usize % NEIGHBORS);
+            black_box(locked_neighbors.lock().unwrap().get(&addr).copied());
+        },
+        &locked_relearn,
+    );
+}
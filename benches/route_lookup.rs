@@ -0,0 +1,122 @@
+//! Routing table lookups, additions and removals as the table grows.
+//!
+//! Each size gets that many random /8 to /30 routes and a default route.
+//! Lookups go to random destinations, some inside a route and some falling
+//! through to the default, and are compared with a linear scan over the same
+//! routes, which is what the table did before it became a trie:
+//!
+//! ```text
+//! cargo bench --bench route_lookup
+//! ```
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use microps::context::{IpRoute, RouteTable};
+use microps::device::DeviceIndex;
+use microps::iface::IpIface;
+use microps::protocol::ip::IpAddr;
+
+const SIZES: [usize; 4] = [16, 256, 1024, 4096];
+const LOOKUPS: usize = 1_000_000;
+
+/// xorshift64, so every run benchmarks the same tables
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u32
+    }
+}
+
+fn addr(n: u32) -> IpAddr {
+    IpAddr::from_ne_bytes(n.to_be_bytes())
+}
+
+fn netmask(len: u32) -> IpAddr {
+    addr(u32::MAX.checked_shl(32 - len).unwrap_or(0))
+}
+
+/// `size` distinct routes and the default route
+fn routes(size: usize, rng: &mut Rng) -> Vec<(IpAddr, IpAddr)> {
+    let mut routes = vec![(IpAddr::ANY, IpAddr::ANY)];
+    while routes.len() <= size {
+        let netmask = netmask(8 + rng.next() % 23);
+        let route = (addr(rng.next()) & netmask, netmask);
+        if !routes.contains(&route) {
+            routes.push(route);
+        }
+    }
+    routes
+}
+
+/// Destinations, half of them inside one of `routes`
+fn destinations(routes: &[(IpAddr, IpAddr)], rng: &mut Rng) -> Vec<IpAddr> {
+    (0..LOOKUPS)
+        .map(|i| {
+            let host = addr(rng.next());
+            if i % 2 == 0 {
+                return host;
+            }
+            let (network, netmask) = routes[rng.next() as usize % routes.len()];
+            network | (host & !netmask)
+        })
+        .collect()
+}
+
+fn per_op(elapsed: Duration, ops: usize) -> f64 {
+    elapsed.as_nanos() as f64 / ops as f64
+}
+
+fn main() {
+    let iface = IpIface::new("192.0.2.2", "255.255.255.0", DeviceIndex(0)).unwrap();
+    let nexthop = addr(0xc000_0201);
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+
+    println!(
+        "{:>6}  {:>12}  {:>12}  {:>12}  {:>12}",
+        "routes", "lookup ns", "scan ns", "add ns", "remove ns"
+    );
+    for size in SIZES {
+        let routes = routes(size, &mut rng);
+        let dsts = destinations(&routes, &mut rng);
+
+        let table = RouteTable::with_limit(routes.len());
+        let start = Instant::now();
+        for &(network, netmask) in &routes {
+            table.add(network, netmask, nexthop, iface.clone()).unwrap();
+        }
+        let add = per_op(start.elapsed(), routes.len());
+
+        let start = Instant::now();
+        for &dst in &dsts {
+            black_box(table.lookup(black_box(dst)));
+        }
+        let lookup = per_op(start.elapsed(), dsts.len());
+
+        let list: Vec<IpRoute> = table.snapshot().iter().cloned().collect();
+        let start = Instant::now();
+        for &dst in &dsts {
+            black_box(
+                list.iter()
+                    .filter(|route| route.matches(black_box(dst)))
+                    .max_by_key(|route| route.netmask.prefix_len()),
+            );
+        }
+        let scan = per_op(start.elapsed(), dsts.len());
+
+        let start = Instant::now();
+        for &(network, netmask) in &routes {
+            table.remove(network, netmask).unwrap();
+        }
+        let remove = per_op(start.elapsed(), routes.len());
+
+        println!(
+            "{:>6}  {:>12.1}  {:>12.1}  {:>12.1}  {:>12.1}",
+            size, lookup, scan, add, remove
+        );
+    }
+}
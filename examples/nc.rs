@@ -0,0 +1,249 @@
+//! A netcat through the stack: pipes stdin and stdout over one TCP connection
+//! or UDP exchange.
+//!
+//! The stack gets one memory device whose frames are tunnelled over host UDP
+//! sockets, one frame per datagram: the protocol type as 2 big-endian bytes
+//! (`0x0800` for IPv4), then the IP packet. Frames are received on the
+//! `<bind>` address, from any port of the peer's host, and sent to `<peer>`
+//! from a port of their own. Two `nc`s pointed at each other talk through two
+//! stacks, and a host program that speaks the encapsulation can stand in for
+//! either of them:
+//!
+//! ```text
+//! cargo run --example nc -- --tunnel 127.0.0.1:7000 127.0.0.1:7001 --ip 10.0.0.1/24 --listen 10.0.0.1:8080
+//! cargo run --example nc -- --tunnel 127.0.0.1:7001 127.0.0.1:7000 --ip 10.0.0.2/24 --connect 10.0.0.1:8080
+//! ```
+//!
+//! `-u` exchanges UDP datagrams instead, one per line of input. A UDP
+//! listener answers whoever sent it the first datagram.
+
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, UdpSocket as HostUdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use microps::builder::memory;
+use microps::device::ether::EtherAddr;
+use microps::device::memory::MemoryQueue;
+use microps::device::{DeviceIndex, RX_BATCH_MAX};
+use microps::net::{TcpListener, TcpStream, UdpSocket};
+use microps::stack::NetStack;
+
+const USAGE: &str =
+    "usage: nc [-u] --tunnel <bind> <peer> --ip <cidr> (--listen|--connect) <addr:port>";
+
+/// How often transmitted frames are collected from the memory device
+const TUNNEL_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// Time for a frame taken off the queue to reach the tunnel socket
+const TUNNEL_DRAIN_DELAY: Duration = Duration::from_millis(20);
+/// Largest tunnelled frame: the type and an MTU-sized packet
+const TUNNEL_FRAME_MAX: usize = 2 + 1500;
+
+enum Mode {
+    Listen(String),
+    Connect(String),
+}
+
+struct Args {
+    udp: bool,
+    tunnel: (String, String),
+    ip: String,
+    mode: Mode,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut udp = false;
+    let mut tunnel = None;
+    let mut ip = None;
+    let mut mode = None;
+    let mut args = std::env::args().skip(1);
+    let value = |args: &mut std::iter::Skip<std::env::Args>| args.next().context(USAGE);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-u" | "--udp" => udp = true,
+            "--tunnel" => tunnel = Some((value(&mut args)?, value(&mut args)?)),
+            "--ip" => ip = Some(value(&mut args)?),
+            "-l" | "--listen" => mode = Some(Mode::Listen(value(&mut args)?)),
+            "-c" | "--connect" => mode = Some(Mode::Connect(value(&mut args)?)),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => anyhow::bail!(USAGE),
+        }
+    }
+    Ok(Args {
+        udp,
+        tunnel: tunnel.context(USAGE)?,
+        ip: ip.context(USAGE)?,
+        mode: mode.context(USAGE)?,
+    })
+}
+
+/// Carry the frames of device 0 to the peer `tx` is connected to, and those
+/// the peer sends to `rx` back. Each socket belongs to one thread.
+fn start_tunnel(
+    stack: &'static NetStack,
+    queue: MemoryQueue,
+    rx: HostUdpSocket,
+    tx: HostUdpSocket,
+) -> Result<()> {
+    let peer = tx.peer_addr()?.ip();
+    thread::spawn(move || {
+        loop {
+            let frames: Vec<_> = queue.lock().unwrap().drain(..).collect();
+            if frames.is_empty() {
+                thread::sleep(TUNNEL_POLL_INTERVAL);
+            }
+            for frame in frames {
+                let datagram = [&frame.type_.to_be_bytes()[..], &frame.data].concat();
+                // Refused while the other end is not up yet, like a cable
+                // nobody listens on
+                match tx.send(&datagram) {
+                    Err(e) if e.kind() != io::ErrorKind::ConnectionRefused => {
+                        eprintln!("nc: tunnel send: {}", e)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    });
+    thread::spawn(move || {
+        let mut bufs = vec![[0u8; TUNNEL_FRAME_MAX]; RX_BATCH_MAX];
+        loop {
+            match recv_batch(&rx, peer, &mut bufs) {
+                Ok(lens) => {
+                    let frames: Vec<(u16, &[u8])> = bufs
+                        .iter()
+                        .zip(lens)
+                        .filter(|&(_, n)| n >= 2)
+                        .map(|(buf, n)| (u16::from_be_bytes([buf[0], buf[1]]), &buf[2..n]))
+                        .collect();
+                    if let Err(e) = stack.inject_batch(DeviceIndex(0), &frames) {
+                        eprintln!("nc: inject: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("nc: tunnel receive: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Wait for a datagram from `peer`, then take those that arrived with it, one
+/// per buffer; returns their lengths. Only the receiving thread uses
+/// `socket`, which is nonblocking while the batch is drained.
+fn recv_batch(
+    socket: &HostUdpSocket,
+    peer: IpAddr,
+    bufs: &mut [[u8; TUNNEL_FRAME_MAX]],
+) -> io::Result<Vec<usize>> {
+    let mut lens = Vec::with_capacity(bufs.len());
+    socket.set_nonblocking(false)?;
+    while lens.len() < bufs.len() {
+        match socket.recv_from(&mut bufs[lens.len()]) {
+            Ok((n, from)) if from.ip() == peer => {
+                lens.push(n);
+                if lens.len() == 1 {
+                    socket.set_nonblocking(true)?;
+                }
+            }
+            // Not from the peer; the buffer takes the next one
+            Ok(_) => {}
+            Err(e) if lens.is_empty() => return Err(e),
+            // Nothing more queued, or an error the next wait reports
+            Err(_) => break,
+        }
+    }
+    Ok(lens)
+}
+
+/// Copy stdin to the connection and the connection to stdout until the peer
+/// closes; the end of stdin only closes our sending side
+fn tcp_pipe(stream: TcpStream<'static>) -> Result<()> {
+    let stream = Arc::new(stream);
+    let sender = Arc::clone(&stream);
+    thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin().lock(), &mut &*sender);
+        let _ = sender.shutdown(std::net::Shutdown::Write);
+    });
+    io::copy(&mut &*stream, &mut io::stdout().lock())?;
+    // Our FIN, if stdin is still open
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    Ok(())
+}
+
+/// Send every line of stdin as a datagram and print every datagram received,
+/// until stdin ends
+fn udp_pipe(socket: UdpSocket<'static>) -> Result<()> {
+    let socket = Arc::new(socket);
+    let receiver = Arc::clone(&socket);
+    thread::spawn(move || {
+        let mut buf = [0u8; TUNNEL_FRAME_MAX];
+        while let Ok(n) = receiver.recv(&mut buf) {
+            let mut stdout = io::stdout().lock();
+            let _ = stdout.write_all(&buf[..n]).and_then(|_| stdout.flush());
+        }
+    });
+    for line in io::stdin().lock().split(b'\n') {
+        let mut line = line?;
+        line.push(b'\n');
+        socket.send(&line)?;
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+
+    let nic = memory(EtherAddr::from_seed(&args.ip));
+    let queue = nic.queue().context("memory devices have a queue")?;
+    let stack = NetStack::builder()
+        .device(nic)
+        .ip(&args.ip)
+        .timer_thread()
+        .build()?;
+    // Sockets borrow the stack, and their threads run until the process exits
+    let stack: &'static NetStack = Box::leak(Box::new(stack));
+
+    let rx = HostUdpSocket::bind(&args.tunnel.0).context("Failed to bind tunnel socket")?;
+    let tx = HostUdpSocket::bind((rx.local_addr()?.ip(), 0))
+        .context("Failed to bind tunnel sending socket")?;
+    tx.connect(&args.tunnel.1)
+        .context("Failed to set tunnel peer")?;
+    start_tunnel(stack, Arc::clone(&queue), rx, tx)?;
+
+    match (args.mode, args.udp) {
+        (Mode::Listen(addr), false) => {
+            let listener = TcpListener::bind(stack, addr.as_str())?;
+            let (stream, peer) = listener.accept()?;
+            eprintln!("nc: connection from {}", peer);
+            tcp_pipe(stream)?;
+        }
+        (Mode::Connect(addr), false) => tcp_pipe(TcpStream::connect(stack, addr.as_str())?)?,
+        (Mode::Listen(addr), true) => {
+            let socket = UdpSocket::bind(stack, addr.as_str())?;
+            let mut buf = [0u8; TUNNEL_FRAME_MAX];
+            let (n, peer) = socket.recv_from(&mut buf)?;
+            eprintln!("nc: datagram from {}", peer);
+            io::stdout().write_all(&buf[..n])?;
+            io::stdout().flush()?;
+            socket.connect(peer)?;
+            udp_pipe(socket)?;
+        }
+        (Mode::Connect(addr), true) => {
+            let socket = UdpSocket::bind(stack, "0.0.0.0:0")?;
+            socket.connect(addr.as_str())?;
+            udp_pipe(socket)?;
+        }
+    }
+    // Let the tunnel send the last frames; its threads never end on their own
+    while !queue.lock().unwrap().is_empty() {
+        thread::sleep(TUNNEL_POLL_INTERVAL);
+    }
+    thread::sleep(TUNNEL_DRAIN_DELAY);
+    std::process::exit(0);
+}
@@ -0,0 +1,311 @@
+//! Traceroute across a chain of routers: probes leave `h1` with increasing TTL
+//! for `h2`, and each router where one runs out answers with a Time Exceeded.
+//!
+//! ```text
+//! h1 -- r1 -- r2 -- ... -- rN -- h2
+//!   10.0.0.0/24  10.0.1.0/24  10.0.N.0/24
+//! ```
+//!
+//! Link `i` numbers its left end `10.0.i.1` and its right end `10.0.i.2`. The
+//! probes are UDP datagrams to ports from 33434 up, which `h2` refuses with a
+//! Port Unreachable, or with `-I` Echo Requests, which it answers:
+//!
+//! ```text
+//! cargo run --example traceroute -- -r 4
+//! cargo run --example traceroute -- -I -q 1
+//! ```
+
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use microps::protocol::icmp::{
+    self, ICMP_CODE_HOST_UNREACH, ICMP_CODE_NET_UNREACH, ICMP_CODE_PORT_UNREACH, ICMP_HDR_SIZE,
+    IcmpHdr, IcmpType,
+};
+use microps::protocol::ip::{self, IpAddr, IpEndpoint, IpHdr, IpProtocol};
+use microps::protocol::udp::UDP_HDR_SIZE;
+use microps::socket::{self, SocketFd, SocketType};
+use microps::stack::NetStack;
+use microps::topology::Topology;
+
+const USAGE: &str = "usage: traceroute [-I] [-r <routers>] [-m <max-ttl>] [-q <probes>]";
+
+/// First destination port of UDP probes, as traditional traceroute uses
+const PROBE_PORT_BASE: u16 = 33434;
+/// Payload of every probe
+const PROBE_DATA: &[u8] = b"microps traceroute";
+/// How long to wait for the answer to a probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy)]
+enum Method {
+    Udp,
+    Icmp,
+}
+
+struct Args {
+    method: Method,
+    routers: usize,
+    max_ttl: u8,
+    probes: usize,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = Args {
+        method: Method::Udp,
+        routers: 3,
+        max_ttl: 30,
+        probes: 3,
+    };
+    let mut iter = std::env::args().skip(1);
+    let value = |iter: &mut std::iter::Skip<std::env::Args>| iter.next().context(USAGE);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-I" | "--icmp" => args.method = Method::Icmp,
+            "-r" | "--routers" => args.routers = value(&mut iter)?.parse().context(USAGE)?,
+            "-m" | "--max-ttl" => args.max_ttl = value(&mut iter)?.parse().context(USAGE)?,
+            "-q" | "--probes" => args.probes = value(&mut iter)?.parse().context(USAGE)?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => anyhow::bail!(USAGE),
+        }
+    }
+    if args.routers > 250 || args.max_ttl == 0 || args.probes == 0 {
+        anyhow::bail!(USAGE);
+    }
+    Ok(args)
+}
+
+/// `h1`, `routers` routers and `h2` in a line; returns the address of `h2`
+fn chain(routers: usize) -> Result<(Topology, IpAddr)> {
+    let nodes: Vec<String> = std::iter::once("h1".to_string())
+        .chain((1..=routers).map(|i| format!("r{}", i)))
+        .chain(std::iter::once("h2".to_string()))
+        .collect();
+    let mut builder = Topology::builder().host("h1");
+    for router in &nodes[1..=routers] {
+        builder = builder.router(router);
+    }
+    builder = builder.host("h2");
+    for (i, pair) in nodes.windows(2).enumerate() {
+        builder = builder.link(
+            (&pair[0], &format!("10.0.{}.1/24", i)),
+            (&pair[1], &format!("10.0.{}.2/24", i)),
+        );
+    }
+    // Everything is to the right of a node but the networks behind its left
+    // link, which it reaches through its left neighbor
+    for (i, node) in nodes.iter().enumerate().take(routers + 1) {
+        builder = builder.gateway(node, &format!("10.0.{}.2", i));
+        for network in 0..i.saturating_sub(1) {
+            builder = builder.route(
+                node,
+                &format!("10.0.{}.0/24", network),
+                &format!("10.0.{}.1", i - 1),
+            );
+        }
+    }
+    builder = builder.gateway("h2", &format!("10.0.{}.1", routers));
+    let topology = builder.build()?;
+    Ok((topology, IpAddr::from_str(&format!("10.0.{}.2", routers))?))
+}
+
+/// What the answer to a probe says about the path
+enum Answer {
+    /// A router the probe ran out of TTL at
+    Hop(IpAddr),
+    /// The destination itself
+    Reached(IpAddr),
+    /// A router or host that could not deliver the probe, with traceroute's
+    /// annotation
+    Unreachable(IpAddr, &'static str),
+}
+
+/// One probe in flight and how its answers are recognized
+struct Probe {
+    method: Method,
+    /// UDP source port or Echo identifier
+    id: u16,
+    seq: u16,
+}
+
+impl Probe {
+    /// The IP payload of the probe
+    fn encode(&self) -> Vec<u8> {
+        match self.method {
+            // No checksum, which UDP over IPv4 allows
+            Method::Udp => {
+                let len = (UDP_HDR_SIZE + PROBE_DATA.len()) as u16;
+                [
+                    &self.id.to_be_bytes()[..],
+                    &(PROBE_PORT_BASE + self.seq).to_be_bytes(),
+                    &len.to_be_bytes(),
+                    &[0, 0],
+                    PROBE_DATA,
+                ]
+                .concat()
+            }
+            Method::Icmp => icmp::build(
+                IcmpType::Echo,
+                0,
+                icmp::echo_values(self.id, self.seq),
+                PROBE_DATA,
+            )
+            .to_vec(),
+        }
+    }
+
+    fn protocol(&self) -> IpProtocol {
+        match self.method {
+            Method::Udp => IpProtocol::Udp,
+            Method::Icmp => IpProtocol::Icmp,
+        }
+    }
+
+    /// Whether `packet`, an IP header and at least 8 bytes of payload quoted
+    /// by an ICMP error, is this probe
+    fn is_quoted(&self, packet: &[u8]) -> bool {
+        let Some(hdr) = IpHdr::from_bytes(packet) else {
+            return false;
+        };
+        let Some(transport) = packet.get(hdr.hdr_len()..hdr.hdr_len() + 8) else {
+            return false;
+        };
+        match self.method {
+            Method::Udp => {
+                hdr.protocol() == IpProtocol::Udp
+                    && transport[..2] == self.id.to_be_bytes()
+                    && transport[2..4] == (PROBE_PORT_BASE + self.seq).to_be_bytes()
+            }
+            Method::Icmp => {
+                hdr.protocol() == IpProtocol::Icmp
+                    && transport[4..8] == icmp::echo_values(self.id, self.seq).to_be_bytes()
+            }
+        }
+    }
+
+    /// Decode an ICMP message from `src`, if it answers this probe
+    fn answer(&self, message: &[u8], src: IpAddr) -> Option<Answer> {
+        let hdr = IcmpHdr::from_bytes(message)?;
+        let body = &message[ICMP_HDR_SIZE..];
+        match hdr.type_enum()? {
+            IcmpType::EchoReply => {
+                let echo = matches!(self.method, Method::Icmp)
+                    && hdr.values() == icmp::echo_values(self.id, self.seq);
+                echo.then_some(Answer::Reached(src))
+            }
+            IcmpType::TimeExceeded if self.is_quoted(body) => Some(Answer::Hop(src)),
+            IcmpType::DestUnreachable if self.is_quoted(body) => Some(match hdr.code() {
+                ICMP_CODE_PORT_UNREACH => Answer::Reached(src),
+                ICMP_CODE_NET_UNREACH => Answer::Unreachable(src, "!N"),
+                ICMP_CODE_HOST_UNREACH => Answer::Unreachable(src, "!H"),
+                _ => Answer::Unreachable(src, "!X"),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Send `probe` with `ttl` and wait for its answer and round trip time
+fn send_probe(
+    stack: &NetStack,
+    fd: SocketFd,
+    probe: &Probe,
+    src: IpAddr,
+    dst: IpAddr,
+    ttl: u8,
+) -> Result<Option<(Answer, Duration)>> {
+    let sent = Instant::now();
+    ip::ip_output_ttl(
+        probe.protocol(),
+        &probe.encode(),
+        src,
+        dst,
+        Some(ttl),
+        &stack.ctx(),
+        &stack.devices(),
+    )?;
+    // The raw socket sees every ICMP message for h1, answers to earlier
+    // probes that came too late included
+    let mut buf = [0u8; 1500];
+    while let Some(remaining) = PROBE_TIMEOUT.checked_sub(sent.elapsed()) {
+        socket::set_read_timeout(fd, Some(remaining), &stack.ctx())?;
+        let (len, from) = match socket::recvfrom(fd, &mut buf, &stack.ctx(), &stack.devices()) {
+            Ok(got) => got,
+            Err(_) => break,
+        };
+        if let Some(answer) = probe.answer(&buf[..len], from.addr) {
+            return Ok(Some((answer, sent.elapsed())));
+        }
+    }
+    Ok(None)
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+    let (topology, dst) = chain(args.routers)?;
+    let h1 = topology.node("h1").context("h1 is in the chain")?;
+    let src = IpAddr::from_str("10.0.0.1")?;
+
+    let fd = socket::socket(SocketType::Raw(IpProtocol::Icmp), &h1.ctx())?;
+    socket::bind(fd, IpEndpoint::new(src, 0), &h1.ctx())?;
+
+    println!(
+        "traceroute to {}, {} hops max, {} byte packets",
+        dst,
+        args.max_ttl,
+        ip::IP_HDR_SIZE_MIN
+            + Probe {
+                method: args.method,
+                id: 0,
+                seq: 0
+            }
+            .encode()
+            .len()
+    );
+    let id = 0x8000 | std::process::id() as u16;
+    let mut seq = 0;
+    for ttl in 1..=args.max_ttl {
+        let mut line = format!("{:2} ", ttl);
+        let mut last = None;
+        let mut done = false;
+        for _ in 0..args.probes {
+            let probe = Probe {
+                method: args.method,
+                id,
+                seq,
+            };
+            seq += 1;
+            let Some((answer, rtt)) = send_probe(h1, fd, &probe, src, dst, ttl)? else {
+                line.push_str(" *");
+                continue;
+            };
+            let (from, note) = match answer {
+                Answer::Hop(from) => (from, ""),
+                Answer::Reached(from) => {
+                    done = true;
+                    (from, "")
+                }
+                Answer::Unreachable(from, note) => {
+                    done = true;
+                    (from, note)
+                }
+            };
+            if last != Some(from) {
+                line.push_str(&format!(" {}", from));
+                last = Some(from);
+            }
+            line.push_str(&format!("  {:.3} ms{}", rtt.as_secs_f64() * 1000.0, note));
+        }
+        println!("{}", line);
+        if done {
+            break;
+        }
+    }
+    socket::close(fd, &h1.ctx(), &h1.devices())?;
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! The stack fuzz input is fed to: one in-memory device at 192.0.2.2/24
+//! whose transmitted frames are queued and never read.
+
+#![allow(dead_code)]
+
+use std::str::FromStr;
+
+use microps::builder::memory;
+use microps::device::DeviceIndex;
+use microps::device::ether::EtherAddr;
+use microps::protocol::ip::{self, IP_HDR_SIZE_MIN, IpAddr, IpHdr, IpProtocol};
+use microps::stack::NetStack;
+use microps::util::cksum16;
+
+pub const PEER: &str = "192.0.2.1";
+pub const LOCAL: &str = "192.0.2.2";
+
+/// A fresh stack for every input, so a crash reproduces from its input alone
+pub fn stack() -> NetStack {
+    NetStack::builder()
+        .device(memory(EtherAddr::from_seed("fuzz")))
+        .ip("192.0.2.2/24")
+        .build()
+        .unwrap()
+}
+
+/// Feed `data` to `stack` as a frame of `type_` received on its device
+pub fn receive(stack: &NetStack, type_: u16, data: &[u8]) {
+    // Malformed input is expected to fail; only a panic is a finding
+    let _ = stack.inject(DeviceIndex(0), type_, data);
+}
+
+/// `payload` behind a valid IPv4 header from [`PEER`] to [`LOCAL`], with the
+/// transport checksum fixed up so the input gets past it to the parser.
+/// `None` if it does not fit in one packet.
+pub fn ip_packet(protocol: IpProtocol, payload: &[u8]) -> Option<Vec<u8>> {
+    let total = u16::try_from(IP_HDR_SIZE_MIN + payload.len()).ok()?;
+    let src = IpAddr::from_str(PEER).unwrap();
+    let dst = IpAddr::from_str(LOCAL).unwrap();
+
+    let mut payload = payload.to_vec();
+    let (offset, init) = match protocol {
+        IpProtocol::Icmp | IpProtocol::Igmp => (2, 0),
+        IpProtocol::Udp => (6, ip::pseudo_sum(src, dst, protocol, payload.len())),
+        IpProtocol::Tcp => (16, ip::pseudo_sum(src, dst, protocol, payload.len())),
+        IpProtocol::Other(_) => (usize::MAX, 0),
+    };
+    if payload.len() >= offset + 2 {
+        payload[offset..offset + 2].fill(0);
+        let sum = cksum16(&payload, init);
+        payload[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    let hdr = IpHdr::new(protocol, total, 0, 0, src, dst).with_checksum();
+    let mut packet = hdr.to_bytes().to_vec();
+    packet.extend_from_slice(&payload);
+    Some(packet)
+}
@@ -0,0 +1,20 @@
+//! Arbitrary bytes as an ICMP message in a valid IPv4 packet
+
+#![no_main]
+
+mod common;
+
+use libfuzzer_sys::fuzz_target;
+use microps::protocol::PROTOCOL_TYPE_IP;
+use microps::protocol::icmp::{ICMP_HDR_SIZE, IcmpHdr};
+use microps::protocol::ip::IpProtocol;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(hdr) = IcmpHdr::from_bytes(data) {
+        assert_eq!(hdr.to_bytes(), data[..ICMP_HDR_SIZE]);
+        let _ = hdr.to_string();
+    }
+    if let Some(packet) = common::ip_packet(IpProtocol::Icmp, data) {
+        common::receive(&common::stack(), PROTOCOL_TYPE_IP, &packet);
+    }
+});
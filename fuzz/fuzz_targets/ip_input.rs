@@ -0,0 +1,18 @@
+//! Arbitrary bytes as a received IPv4 packet: header and options parsing,
+//! length and fragment checks, forwarding and every transport behind them
+
+#![no_main]
+
+mod common;
+
+use libfuzzer_sys::fuzz_target;
+use microps::protocol::PROTOCOL_TYPE_IP;
+use microps::protocol::ip::{IP_HDR_SIZE_MIN, IpHdr};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(hdr) = IpHdr::from_bytes(data) {
+        assert_eq!(hdr.to_bytes(), data[..IP_HDR_SIZE_MIN]);
+        let _ = hdr.to_string();
+    }
+    common::receive(&common::stack(), PROTOCOL_TYPE_IP, data);
+});
@@ -0,0 +1,16 @@
+//! Arbitrary bytes as a received IPv6 packet, including neighbor discovery
+
+#![no_main]
+
+mod common;
+
+use libfuzzer_sys::fuzz_target;
+use microps::protocol::PROTOCOL_TYPE_IPV6;
+use microps::protocol::ipv6::{IPV6_HDR_SIZE, Ipv6Hdr};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(hdr) = Ipv6Hdr::from_bytes(data) {
+        assert_eq!(hdr.to_bytes(), data[..IPV6_HDR_SIZE]);
+    }
+    common::receive(&common::stack(), PROTOCOL_TYPE_IPV6, data);
+});
@@ -0,0 +1,29 @@
+//! Arbitrary bytes as a TCP segment in a valid IPv4 packet, arriving at a
+//! listening socket: header, options and the LISTEN state's handling
+
+#![no_main]
+
+mod common;
+
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+use microps::protocol::PROTOCOL_TYPE_IP;
+use microps::protocol::ip::{IpEndpoint, IpProtocol};
+use microps::protocol::tcp::{self, TCP_HDR_SIZE_MIN, TcpHdr};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(hdr) = TcpHdr::from_bytes(data) {
+        assert_eq!(hdr.to_bytes(), data[..TCP_HDR_SIZE_MIN]);
+        if let Some(options) = data.get(TCP_HDR_SIZE_MIN..hdr.hdr_len()) {
+            let _ = tcp::parse_options(options);
+        }
+    }
+    let Some(packet) = common::ip_packet(IpProtocol::Tcp, data) else {
+        return;
+    };
+    let stack = common::stack();
+    let local = IpEndpoint::from_str("192.0.2.2:80").unwrap();
+    stack.ctx().tcp.listen_backlog(local, 1).unwrap();
+    common::receive(&stack, PROTOCOL_TYPE_IP, &packet);
+});
@@ -0,0 +1,27 @@
+//! Arbitrary bytes as a UDP datagram in a valid IPv4 packet, delivered to a
+//! bound socket when the destination port is the one listened on
+
+#![no_main]
+
+mod common;
+
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+use microps::protocol::PROTOCOL_TYPE_IP;
+use microps::protocol::ip::{IpEndpoint, IpProtocol};
+use microps::protocol::udp::{UDP_HDR_SIZE, UdpHdr};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(hdr) = UdpHdr::from_bytes(data) {
+        assert_eq!(hdr.to_bytes(), data[..UDP_HDR_SIZE]);
+    }
+    let Some(packet) = common::ip_packet(IpProtocol::Udp, data) else {
+        return;
+    };
+    let stack = common::stack();
+    let id = stack.ctx().udp.open().unwrap();
+    let local = IpEndpoint::from_str("192.0.2.2:7").unwrap();
+    stack.ctx().udp.bind(id, local).unwrap();
+    common::receive(&stack, PROTOCOL_TYPE_IP, &packet);
+});
@@ -0,0 +1,367 @@
+//! Measure throughput between two stacks joined by a veth link, as iperf does.
+//!
+//! The client streams bulk data over TCP and reports goodput and
+//! retransmissions each interval; with `-u` it sends UDP datagrams at a fixed
+//! rate and the server reports loss and jitter. `--loss` drops frames on the
+//! link, which is what exercises the window and congestion control.
+//!
+//! Usage: `microps-iperf [-u] [-t <secs>] [-i <secs>] [-l <len>] [-b <rate>] [--loss <p>] [--seed <n>]`
+
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use microps::device::veth::Impairment;
+use microps::net::{TcpListener, TcpStream, UdpSocket};
+use microps::stack::NetStack;
+use microps::topology::Topology;
+
+const USAGE: &str = "usage: microps-iperf [-u] [-t <secs>] [-i <secs>] [-l <len>] [-b <rate>] [--loss <p>] [--seed <n>]
+
+options:
+  -u, --udp         send UDP datagrams at a fixed rate instead of a TCP stream
+  -t, --time        seconds to transmit for (default 5)
+  -i, --interval    seconds between reports (default 1)
+  -l, --len         bytes per write or datagram (default 8192, 1400 with -u)
+  -b, --bitrate     UDP target rate in bits/sec, with K, M or G (default 10M)
+  --loss            probability that the link drops a frame (default 0)
+  --seed            seed for the link's loss generator";
+
+const CLIENT: &str = "10.0.0.1";
+const SERVER: &str = "10.0.0.2";
+const PORT: u16 = 5201;
+
+/// A UDP datagram starts with its sequence number and its send time
+const UDP_STAMP_SIZE: usize = 16;
+/// Largest UDP payload that fits the veth MTU unfragmented
+const UDP_LEN_MAX: usize = 1472;
+/// How long the UDP server waits for stragglers once the client is done
+const UDP_LINGER: Duration = Duration::from_millis(500);
+
+struct Args {
+    udp: bool,
+    time: Duration,
+    interval: Duration,
+    len: Option<usize>,
+    bitrate: u64,
+    impairment: Impairment,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = Args {
+        udp: false,
+        time: Duration::from_secs(5),
+        interval: Duration::from_secs(1),
+        len: None,
+        bitrate: 10_000_000,
+        impairment: Impairment::default(),
+    };
+    let mut iter = std::env::args().skip(1);
+    let value = |iter: &mut std::iter::Skip<std::env::Args>| iter.next().context(USAGE);
+    let seconds =
+        |s: String| -> Result<Duration> { Ok(Duration::from_secs_f64(s.parse().context(USAGE)?)) };
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-u" | "--udp" => args.udp = true,
+            "-t" | "--time" => args.time = seconds(value(&mut iter)?)?,
+            "-i" | "--interval" => args.interval = seconds(value(&mut iter)?)?,
+            "-l" | "--len" => args.len = Some(value(&mut iter)?.parse().context(USAGE)?),
+            "-b" | "--bitrate" => args.bitrate = parse_rate(&value(&mut iter)?)?,
+            "--loss" => args.impairment.loss = value(&mut iter)?.parse().context(USAGE)?,
+            "--seed" => args.impairment.seed = value(&mut iter)?.parse().context(USAGE)?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => anyhow::bail!(USAGE),
+        }
+    }
+    if args.interval.is_zero() || args.bitrate == 0 {
+        anyhow::bail!(USAGE);
+    }
+    Ok(args)
+}
+
+/// Bits per second, optionally suffixed with K, M or G
+fn parse_rate(s: &str) -> Result<u64> {
+    let (digits, scale) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1e3),
+        Some((i, 'm' | 'M')) => (&s[..i], 1e6),
+        Some((i, 'g' | 'G')) => (&s[..i], 1e9),
+        _ => (s, 1.0),
+    };
+    let rate: f64 = digits
+        .parse()
+        .with_context(|| format!("invalid bitrate: {}", s))?;
+    Ok((rate * scale) as u64)
+}
+
+/// `bytes` with a binary unit, as iperf prints transfers
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["Bytes", "KBytes", "MBytes", "GBytes"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+/// `bytes` over `elapsed` in decimal bits per second
+fn format_rate(bytes: u64, elapsed: Duration) -> String {
+    const UNITS: [&str; 4] = ["bits/sec", "Kbits/sec", "Mbits/sec", "Gbits/sec"];
+    let mut value = bytes as f64 * 8.0 / elapsed.as_secs_f64().max(f64::EPSILON);
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+fn interval(from: Duration, to: Duration) -> String {
+    format!("{:6.2}-{:<6.2} sec", from.as_secs_f64(), to.as_secs_f64())
+}
+
+/// Read the stream to its end; returns the bytes read and how long it took
+fn tcp_server(listener: TcpListener<'_>) -> Result<(u64, Duration)> {
+    let (mut stream, _) = listener.accept()?;
+    let start = Instant::now();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0;
+    loop {
+        match stream.read(&mut buf)? {
+            0 => return Ok((total, start.elapsed())),
+            n => total += n as u64,
+        }
+    }
+}
+
+/// Write for the configured time; returns the bytes written and how long
+/// writing took
+fn tcp_client(client: &NetStack, args: &Args, len: usize) -> Result<(u64, Duration)> {
+    let mut stream = TcpStream::connect(client, format!("{}:{}", SERVER, PORT))?;
+    let retransmissions = || client.ctx().tcp.retransmissions();
+    let buf = vec![0xa5u8; len];
+    let start = Instant::now();
+    let (mut sent, mut retrans) = (0, retransmissions());
+    let (mut mark, mut mark_sent) = (Duration::ZERO, 0);
+    println!("[ ID] Interval           Transfer     Bitrate         Retr");
+    loop {
+        let elapsed = start.elapsed();
+        if elapsed - mark >= args.interval || elapsed >= args.time {
+            let now = retransmissions();
+            println!(
+                "[  1] {}  {:>12}  {:>15}  {:4}",
+                interval(mark, elapsed),
+                format_bytes(sent - mark_sent),
+                format_rate(sent - mark_sent, elapsed - mark),
+                now - retrans
+            );
+            (mark, mark_sent, retrans) = (elapsed, sent, now);
+        }
+        let Some(remaining) = args.time.checked_sub(elapsed).filter(|d| !d.is_zero()) else {
+            break;
+        };
+        // A full send buffer on a lossy link must not hold the test past its time
+        stream.set_write_timeout(Some(remaining.min(args.interval)))?;
+        match stream.write(&buf) {
+            Ok(n) => sent += n as u64,
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let elapsed = start.elapsed();
+    stream.shutdown(std::net::Shutdown::Write)?;
+    Ok((sent, elapsed))
+}
+
+fn run_tcp(client: &NetStack, server: &NetStack, args: &Args) -> Result<()> {
+    let len = args.len.unwrap_or(8192).max(1);
+    let listener = TcpListener::bind(server, format!("{}:{}", SERVER, PORT))?;
+    let first_retrans = client.ctx().tcp.retransmissions();
+    let ((sent, send_time), (received, receive_time)) = thread::scope(|scope| {
+        let receiver = scope.spawn(|| tcp_server(listener));
+        let sender = tcp_client(client, args, len);
+        let receiver = receiver.join().expect("TCP server panicked");
+        Ok::<_, anyhow::Error>((sender?, receiver?))
+    })?;
+    // Including the segments resent while the receiver caught up
+    let retrans = client.ctx().tcp.retransmissions() - first_retrans;
+    println!("- - - - - - - - - - - - - - - - - - - - - - - - - - - - -");
+    println!(
+        "[  1] {}  {:>12}  {:>15}  {:4}  sender",
+        interval(Duration::ZERO, send_time),
+        format_bytes(sent),
+        format_rate(sent, send_time),
+        retrans
+    );
+    println!(
+        "[  1] {}  {:>12}  {:>15}        receiver",
+        interval(Duration::ZERO, receive_time),
+        format_bytes(received),
+        format_rate(received, receive_time)
+    );
+    Ok(())
+}
+
+/// What the UDP server saw
+#[derive(Default)]
+struct UdpReport {
+    received: u64,
+    bytes: u64,
+    out_of_order: u64,
+    /// RFC 3550 interarrival jitter
+    jitter: Duration,
+}
+
+fn udp_server(socket: UdpSocket<'_>, epoch: Instant, done: &AtomicBool) -> Result<UdpReport> {
+    socket.set_read_timeout(Some(UDP_LINGER))?;
+    let mut report = UdpReport::default();
+    let mut buf = [0u8; UDP_LEN_MAX];
+    let (mut next_seq, mut last_transit) = (0, None);
+    let mut jitter = 0.0;
+    loop {
+        let n = match socket.recv(&mut buf) {
+            Ok(n) if n >= UDP_STAMP_SIZE => n,
+            Ok(_) => continue,
+            Err(_) if done.load(Ordering::Acquire) => break,
+            Err(_) => continue,
+        };
+        let seq = u64::from_be_bytes(buf[..8].try_into().unwrap());
+        let sent = Duration::from_nanos(u64::from_be_bytes(buf[8..16].try_into().unwrap()));
+        let transit = epoch.elapsed().as_secs_f64() - sent.as_secs_f64();
+        if let Some(last) = last_transit {
+            let d: f64 = transit - last;
+            jitter += (d.abs() - jitter) / 16.0;
+        }
+        last_transit = Some(transit);
+        if seq < next_seq {
+            report.out_of_order += 1;
+        }
+        next_seq = next_seq.max(seq + 1);
+        report.received += 1;
+        report.bytes += n as u64;
+    }
+    report.jitter = Duration::from_secs_f64(jitter);
+    Ok(report)
+}
+
+fn udp_client(client: &NetStack, args: &Args, len: usize, epoch: Instant) -> Result<(u64, u64)> {
+    let socket = UdpSocket::bind(client, format!("{}:0", CLIENT))?;
+    socket.connect(format!("{}:{}", SERVER, PORT))?;
+    let mut buf = vec![0xa5u8; len];
+    // Time between datagrams at the target rate
+    let gap = Duration::from_secs_f64(len as f64 * 8.0 / args.bitrate as f64);
+    let start = Instant::now();
+    let (mut datagrams, mut sent) = (0u64, 0u64);
+    let (mut mark, mut mark_sent) = (Duration::ZERO, 0);
+    println!("[ ID] Interval           Transfer     Bitrate         Datagrams");
+    let mut mark_datagrams = 0;
+    loop {
+        let elapsed = start.elapsed();
+        if elapsed - mark >= args.interval || elapsed >= args.time {
+            println!(
+                "[  1] {}  {:>12}  {:>15}  {}",
+                interval(mark, elapsed),
+                format_bytes(sent - mark_sent),
+                format_rate(sent - mark_sent, elapsed - mark),
+                datagrams - mark_datagrams
+            );
+            (mark, mark_sent, mark_datagrams) = (elapsed, sent, datagrams);
+        }
+        if elapsed >= args.time {
+            break;
+        }
+        let due = gap.mul_f64(datagrams as f64);
+        if let Some(ahead) = due.checked_sub(elapsed) {
+            thread::sleep(ahead.min(args.interval));
+            continue;
+        }
+        buf[..8].copy_from_slice(&datagrams.to_be_bytes());
+        buf[8..16].copy_from_slice(&(epoch.elapsed().as_nanos() as u64).to_be_bytes());
+        socket.send(&buf)?;
+        datagrams += 1;
+        sent += len as u64;
+    }
+    Ok((datagrams, sent))
+}
+
+fn run_udp(client: &NetStack, server: &NetStack, args: &Args) -> Result<()> {
+    let len = args.len.unwrap_or(1400).clamp(UDP_STAMP_SIZE, UDP_LEN_MAX);
+    let socket = UdpSocket::bind(server, format!("{}:{}", SERVER, PORT))?;
+    let epoch = Instant::now();
+    let done = AtomicBool::new(false);
+    let start = Instant::now();
+    let ((datagrams, sent), report) = thread::scope(|scope| {
+        let receiver = scope.spawn(|| udp_server(socket, epoch, &done));
+        let sender = udp_client(client, args, len, epoch);
+        done.store(true, Ordering::Release);
+        let receiver = receiver.join().expect("UDP server panicked");
+        Ok::<_, anyhow::Error>((sender?, receiver?))
+    })?;
+    let elapsed = start.elapsed().min(args.time);
+    let lost = datagrams.saturating_sub(report.received);
+    println!("- - - - - - - - - - - - - - - - - - - - - - - - - - - - -");
+    println!(
+        "[  1] {}  {:>12}  {:>15}  sender",
+        interval(Duration::ZERO, elapsed),
+        format_bytes(sent),
+        format_rate(sent, elapsed)
+    );
+    println!(
+        "[  1] {}  {:>12}  {:>15}  {:.3} ms  {}/{} ({:.2}%) lost, {} out of order  receiver",
+        interval(Duration::ZERO, elapsed),
+        format_bytes(report.bytes),
+        format_rate(report.bytes, elapsed),
+        report.jitter.as_secs_f64() * 1000.0,
+        lost,
+        datagrams,
+        lost as f64 * 100.0 / datagrams.max(1) as f64,
+        report.out_of_order
+    );
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+    let topology = Topology::builder()
+        .host("client")
+        .host("server")
+        .link_with(
+            ("client", &format!("{}/24", CLIENT)),
+            ("server", &format!("{}/24", SERVER)),
+            args.impairment.clone(),
+        )
+        .build()?;
+    let client = topology
+        .node("client")
+        .context("client is in the topology")?;
+    let server = topology
+        .node("server")
+        .context("server is in the topology")?;
+    // Retransmissions and delayed ACKs run off the timers
+    client.start_timer_thread()?;
+    server.start_timer_thread()?;
+
+    println!(
+        "Connecting to host {}, port {} ({}{})",
+        SERVER,
+        PORT,
+        if args.udp { "UDP" } else { "TCP" },
+        if args.impairment.is_none() {
+            String::new()
+        } else {
+            format!(", {:.1}% link loss", args.impairment.loss * 100.0)
+        }
+    );
+    if args.udp {
+        run_udp(client, server, &args)
+    } else {
+        run_tcp(client, server, &args)
+    }
+}
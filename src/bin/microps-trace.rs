@@ -0,0 +1,51 @@
+//! Convert a binary trace written by the stack (`MICROPS_TRACE_FILE`) into
+//! chrome://tracing JSON or text.
+//!
+//! Usage: `microps-trace [--text] <trace> [output]`
+
+use std::fs::File;
+use std::io::{BufReader, Write};
+
+use anyhow::{Context, Result};
+
+use microps::trace;
+
+const USAGE: &str = "usage: microps-trace [--text] <trace> [output]";
+
+fn main() -> Result<()> {
+    let mut text = false;
+    let mut paths = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--text" => text = true,
+            "--chrome" => text = false,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let (input, output) = match paths.as_slice() {
+        [input] => (input, None),
+        [input, output] => (input, Some(output)),
+        _ => anyhow::bail!(USAGE),
+    };
+
+    let file = File::open(input).with_context(|| format!("Failed to open {}", input))?;
+    let records = trace::read(BufReader::new(file))
+        .with_context(|| format!("Failed to read trace {}", input))?;
+    let converted = if text {
+        trace::to_text(&records)
+    } else {
+        trace::to_chrome_json(&records)
+    };
+
+    match output {
+        Some(path) => {
+            std::fs::write(path, converted).with_context(|| format!("Failed to write {}", path))?
+        }
+        None => std::io::stdout().write_all(converted.as_bytes())?,
+    }
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! Inspect and reconfigure a running stack through its control socket
+//! (`MICROPS_CONTROL_SOCKET`, see `microps::control`).
+//!
+//! Usage: `mipsctl [-s <socket>] <command> [args]`
+
+use anyhow::Result;
+
+use microps::control;
+
+const SOCKET_ENV: &str = "MICROPS_CONTROL_SOCKET";
+
+const USAGE: &str = "usage: mipsctl [-s <socket>] <command> [args]
+
+commands:
+  iface [show]                           devices and their addresses
+  iface up|down <dev>                    bring a device up or down
+  iface add <dev> <cidr>                 add an IPv4 or IPv6 address
+  route [show]                           IPv4 and IPv6 routes
+  route add <cidr>|default via <gateway>
+  route del <cidr>|default
+  arp                                    neighbor cache (resolved with NDP)
+  stat [<filter>]                        counters whose name contains <filter>
+  conn                                   UDP and TCP connections
+  top [<n>]                              peers with the most traffic
+  pktlog [<filter>|off]                  show or set the packet log filter
+
+The socket defaults to $MICROPS_CONTROL_SOCKET.";
+
+fn main() -> Result<()> {
+    let mut socket = std::env::var(SOCKET_ENV).ok();
+    let mut args = Vec::new();
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-s" | "--socket" => socket = iter.next(),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => args.push(arg),
+        }
+    }
+    let Some(socket) = socket else {
+        anyhow::bail!("no control socket: pass -s <socket> or set {}", SOCKET_ENV);
+    };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let request = |command: &str| control::request(&socket, command);
+
+    let rows = match args.as_slice() {
+        ["iface"] | ["iface", "show"] => iface_rows(&request("link show")?, &request("addr show")?),
+        ["iface", state @ ("up" | "down"), dev] => request(&format!("link set {} {}", dev, state))?,
+        ["iface", "add", dev, cidr] => request(&format!("addr add {} {}", dev, cidr))?,
+        ["route"] | ["route", "show"] => request("route show")?,
+        ["route", "add", prefix, "via", gateway] => {
+            request(&format!("route add {} via {}", prefix, gateway))?
+        }
+        ["route", "del", prefix] => request(&format!("route del {}", prefix))?,
+        ["arp"] => request("neigh show")?,
+        ["stat"] => request("stat")?,
+        ["stat", filter] => {
+            let rows = request("stat")?;
+            let header = rows.iter().take(1);
+            let matching = rows.iter().skip(1).filter(|row| row.contains(filter));
+            header.chain(matching).cloned().collect()
+        }
+        ["conn"] | ["conn", "show"] => request("conn show")?,
+        ["top"] => request("top")?,
+        ["top", n] => request(&format!("top {}", n))?,
+        ["pktlog", filter @ ..] => request(&format!("pktlog {}", filter.join(" ")))?,
+        _ => anyhow::bail!(USAGE),
+    };
+    print!("{}", control::table(&rows));
+    Ok(())
+}
+
+/// The rows of `link show` with an ADDRESSES column from `addr show`
+fn iface_rows(links: &[String], addrs: &[String]) -> Vec<String> {
+    let addrs: Vec<Vec<&str>> = addrs
+        .iter()
+        .skip(1)
+        .map(|row| row.split('\t').collect())
+        .collect();
+    let mut rows = Vec::with_capacity(links.len());
+    for (i, link) in links.iter().enumerate() {
+        if i == 0 {
+            rows.push(format!("{}\tADDRESSES", link));
+            continue;
+        }
+        let name = link.split('\t').next().unwrap_or_default();
+        let addresses: Vec<&str> = addrs
+            .iter()
+            .filter(|addr| addr.first() == Some(&name))
+            .filter_map(|addr| addr.get(2).copied())
+            .collect();
+        let addresses = if addresses.is_empty() {
+            "-".to_string()
+        } else {
+            addresses.join(",")
+        };
+        rows.push(format!("{}\t{}", link, addresses));
+    }
+    rows
+}
@@ -0,0 +1,333 @@
+//! Fluent setup of a single stack.
+//!
+//! ```
+//! # use microps::builder::{loopback, memory};
+//! # use microps::device::ether::EtherAddr;
+//! # use microps::stack::NetStack;
+//! let stack = NetStack::builder()
+//!     .device(loopback())
+//!     .device(memory(EtherAddr::from_seed("eth0")))
+//!     .ip("192.0.2.2/24")
+//!     .gateway("192.0.2.1")
+//!     .timer_thread()
+//!     .build()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+
+use crate::clock::Clock;
+use crate::device::DeviceIndex;
+use crate::device::ether::EtherAddr;
+use crate::device::memory::{self, MemoryQueue};
+use crate::limits::StackLimits;
+use crate::protocol::Protocol;
+use crate::protocol::ip::{self, IpAddr, IpEndpoint, IpProtocol};
+use crate::protocol::nat::PortForward;
+use crate::services::{SERVICES, Service};
+use crate::stack::NetStack;
+
+/// A device for [`NetStackBuilder::device`]
+#[derive(Clone)]
+pub enum DeviceSpec {
+    /// Loopback with 127.0.0.1/8 and ::1/128
+    Loopback,
+    /// In-memory device; its transmitted frames are queued on `tx`
+    Memory { addr: EtherAddr, tx: MemoryQueue },
+}
+
+impl DeviceSpec {
+    /// Queue the device's transmitted frames go to, for memory devices
+    pub fn queue(&self) -> Option<MemoryQueue> {
+        match self {
+            DeviceSpec::Loopback => None,
+            DeviceSpec::Memory { tx, .. } => Some(tx.clone()),
+        }
+    }
+}
+
+pub fn loopback() -> DeviceSpec {
+    DeviceSpec::Loopback
+}
+
+/// In-memory device with hardware address `addr` (see [`memory`](crate::device::memory))
+pub fn memory(addr: EtherAddr) -> DeviceSpec {
+    DeviceSpec::Memory {
+        addr,
+        tx: MemoryQueue::default(),
+    }
+}
+
+struct IfaceSpec {
+    /// Position of the device in [`NetStackBuilder::device`] order
+    device: Option<usize>,
+    cidr: String,
+}
+
+struct MtuSpec {
+    /// Position of the device in [`NetStackBuilder::device`] order
+    device: Option<usize>,
+    mtu: u16,
+}
+
+struct RouteSpec {
+    network: String,
+    gateway: String,
+}
+
+struct ForwardSpec {
+    protocol: IpProtocol,
+    external: String,
+    internal: String,
+}
+
+#[derive(Default)]
+pub struct NetStackBuilder {
+    limits: Option<StackLimits>,
+    clock: Clock,
+    devices: Vec<DeviceSpec>,
+    ifaces: Vec<IfaceSpec>,
+    mtus: Vec<MtuSpec>,
+    routes: Vec<RouteSpec>,
+    protocols: Vec<(u16, Arc<dyn Protocol>)>,
+    forwarding: bool,
+    masquerade: Option<String>,
+    forwards: Vec<ForwardSpec>,
+    services: Vec<Service>,
+    irq_thread: bool,
+    softirq_thread: bool,
+    timer_thread: bool,
+}
+
+impl NetStackBuilder {
+    /// Bound the stack's tables and queues by `limits`
+    pub fn limits(mut self, limits: StackLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Run timers and timeouts on `clock` (see [`clock`](crate::clock))
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Add a device; [`ip`](Self::ip) calls that follow configure it
+    pub fn device(mut self, device: DeviceSpec) -> Self {
+        self.devices.push(device);
+        self
+    }
+
+    /// Add an interface with `cidr` (IPv4 or IPv6) to the last added device
+    pub fn ip(mut self, cidr: &str) -> Self {
+        self.ifaces.push(IfaceSpec {
+            device: self.devices.len().checked_sub(1),
+            cidr: cidr.to_string(),
+        });
+        self
+    }
+
+    /// Set the MTU of the last added device (see [`NetStack::set_mtu`])
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtus.push(MtuSpec {
+            device: self.devices.len().checked_sub(1),
+            mtu,
+        });
+        self
+    }
+
+    /// Add an IPv4 route to `network` (CIDR) via `gateway`
+    pub fn route(mut self, network: &str, gateway: &str) -> Self {
+        self.routes.push(RouteSpec {
+            network: network.to_string(),
+            gateway: gateway.to_string(),
+        });
+        self
+    }
+
+    /// Add an IPv4 default route via `gateway`
+    pub fn gateway(self, gateway: &str) -> Self {
+        self.route("0.0.0.0/0", gateway)
+    }
+
+    /// Forward packets between the stack's interfaces
+    pub fn forwarding(mut self, forwarding: bool) -> Self {
+        self.forwarding = forwarding;
+        self
+    }
+
+    /// Rewrite the source of forwarded packets to `addr` (see
+    /// [`nat`](crate::protocol::nat)); implies forwarding
+    pub fn masquerade(mut self, addr: &str) -> Self {
+        self.masquerade = Some(addr.to_string());
+        self.forwarding = true;
+        self
+    }
+
+    /// Forward `protocol` packets for `external` (`addr:port`) to `internal`
+    /// (see [`PortForward`]); implies forwarding
+    pub fn port_forward(mut self, protocol: IpProtocol, external: &str, internal: &str) -> Self {
+        self.forwards.push(ForwardSpec {
+            protocol,
+            external: external.to_string(),
+            internal: internal.to_string(),
+        });
+        self.forwarding = true;
+        self
+    }
+
+    /// Name a port in the stack-wide [`SERVICES`] registry once the stack is
+    /// built
+    pub fn service(mut self, service: Service) -> Self {
+        self.services.push(service);
+        self
+    }
+
+    /// Handle received frames of `type_` with `protocol` (see
+    /// [`NetStack::register_protocol`])
+    pub fn protocol(mut self, type_: u16, protocol: Arc<dyn Protocol>) -> Self {
+        self.protocols.push((type_, protocol));
+        self
+    }
+
+    /// Start the IRQ dispatcher thread (see [`NetStack::start_irq_thread`])
+    pub fn irq_thread(mut self) -> Self {
+        self.irq_thread = true;
+        self
+    }
+
+    /// Start the softirq worker thread (see [`NetStack::start_softirq_thread`])
+    pub fn softirq_thread(mut self) -> Self {
+        self.softirq_thread = true;
+        self
+    }
+
+    /// Start the timer thread (see [`NetStack::start_timer_thread`])
+    pub fn timer_thread(mut self) -> Self {
+        self.timer_thread = true;
+        self
+    }
+
+    /// Create the stack with its devices, interfaces and routes, bring the
+    /// devices up and start the requested threads
+    pub fn build(self) -> Result<NetStack> {
+        let stack = NetStack::with_clock(self.limits.unwrap_or_default(), self.clock)?;
+        for (type_, protocol) in self.protocols {
+            stack.register_protocol(type_, protocol)?;
+        }
+
+        let mut indices: Vec<DeviceIndex> = Vec::new();
+        for device in self.devices {
+            let index = match device {
+                DeviceSpec::Loopback => stack.add_loopback()?,
+                DeviceSpec::Memory { addr, tx } => {
+                    memory::init_with_queue(&mut stack.devices_mut(), addr, tx)?
+                }
+            };
+            indices.push(index);
+        }
+
+        for spec in &self.mtus {
+            let index = spec
+                .device
+                .map(|i| indices[i])
+                .with_context(|| format!("mtu {} comes before any device", spec.mtu))?;
+            stack.set_mtu(index, spec.mtu)?;
+        }
+
+        for iface in &self.ifaces {
+            let index = iface
+                .device
+                .map(|i| indices[i])
+                .with_context(|| format!("ip {} comes before any device", iface.cidr))?;
+            if iface.cidr.contains(':') {
+                stack.register_ipv6_iface(index, &iface.cidr)?;
+            } else {
+                let (unicast, netmask) = ip::parse_cidr(&iface.cidr)?;
+                stack.register_ip_iface(index, &unicast.to_string(), &netmask.to_string())?;
+            }
+        }
+
+        stack.ctx_mut().ip_config.forwarding = self.forwarding;
+        if let Some(addr) = &self.masquerade {
+            stack.ctx_mut().ip_config.masquerade = Some(IpAddr::from_str(addr)?);
+        }
+        for forward in &self.forwards {
+            stack.ctx().nat.add_forward(PortForward {
+                protocol: forward.protocol,
+                external: IpEndpoint::from_str(&forward.external)?,
+                internal: IpEndpoint::from_str(&forward.internal)?,
+            })?;
+        }
+        for route in &self.routes {
+            let (network, netmask) = ip::parse_cidr(&route.network)?;
+            let gateway = IpAddr::from_str(&route.gateway)?;
+            ip::route_add_via(network, netmask, gateway, &stack.ctx())
+                .with_context(|| format!("route {} via {}", route.network, route.gateway))?;
+        }
+        for service in self.services {
+            SERVICES.add(service);
+        }
+
+        stack.run()?;
+        if self.irq_thread {
+            stack.start_irq_thread()?;
+        }
+        if self.softirq_thread {
+            stack.start_softirq_thread()?;
+        }
+        if self.timer_thread {
+            stack.start_timer_thread()?;
+        }
+        Ok(stack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_wires_devices_ifaces_and_routes() {
+        let mem = memory(EtherAddr::from_seed("builder"));
+        let tx = mem.queue().unwrap();
+        let stack = NetStack::builder()
+            .device(loopback())
+            .device(mem)
+            .ip("192.0.2.2/24")
+            .ip("2001:db8::2/64")
+            .gateway("192.0.2.1")
+            .build()
+            .unwrap();
+
+        let devices = stack.devices();
+        assert_eq!(devices.iter().count(), 2);
+        assert!(devices.iter().all(|dev| dev.is_up()));
+        assert_eq!(devices.iter().nth(1).unwrap().ifaces.len(), 2);
+        drop(devices);
+
+        let ctx = stack.ctx();
+        let route = ctx
+            .ip_routes
+            .lookup(IpAddr::from_str("203.0.113.1").unwrap());
+        assert_eq!(
+            route.map(|route| route.nexthop),
+            Some(IpAddr::from_str("192.0.2.1").unwrap())
+        );
+        assert!(tx.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_builder_rejects_ip_before_device() {
+        let err = NetStack::builder()
+            .ip("192.0.2.2/24")
+            .device(loopback())
+            .build()
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("before any device"), "{}", err);
+    }
+}
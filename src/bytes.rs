@@ -0,0 +1,298 @@
+//! Received packet data shared instead of copied.
+//!
+//! The protocols see a received frame as a [`Frame`]. A frame handled as soon
+//! as it arrives is borrowed from the driver and not copied at all. A frame
+//! queued for later (deferred input) is copied once, into a [`Bytes`] from
+//! the stack's [`BufferPool`]. From there each layer hands the next one a
+//! [`Frame::slice`] of it: the same bytes with a narrower view.
+//!
+//! Whatever keeps a packet for later (UDP, raw and ICMP socket queues) calls
+//! [`Frame::to_bytes`]. A borrowed frame is copied then, the part kept only.
+//! A queued one is shared, unless the part kept is under half of its buffer:
+//! that is copied into a buffer of its own (see [`Bytes::compact`]), so a
+//! queue of small datagrams does not hold on to the pool's full-sized
+//! buffers. TCP copies the payload into the connection's receive buffer in
+//! either case, as the application reads a stream, not segments.
+//!
+//! ```
+//! # use microps::bytes::Bytes;
+//! let frame = Bytes::from(b"ip  udp hdr payload".to_vec());
+//! let udp = frame.slice(4..);
+//! let payload = udp.slice(8..);
+//! assert_eq!(payload, b"payload");
+//! // All three views share the frame's buffer
+//! assert_eq!(payload.as_ptr(), frame[12..].as_ptr());
+//! ```
+//!
+//! A buffer taken from a [`BufferPool`] (see [`BufferPool::bytes_from`]) goes
+//! back to it when the last view of it is dropped.
+
+use std::fmt;
+use std::ops::{Bound, Deref, RangeBounds};
+use std::sync::Arc;
+
+use crate::pool::BufferPool;
+
+struct Buf {
+    data: Vec<u8>,
+    pool: Option<BufferPool>,
+}
+
+impl Drop for Buf {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+/// A view of a reference-counted, immutable buffer. Cloning and slicing
+/// share the buffer; dereferences to the bytes in view.
+#[derive(Clone)]
+pub struct Bytes {
+    buf: Arc<Buf>,
+    start: usize,
+    end: usize,
+}
+
+impl Bytes {
+    pub fn new() -> Self {
+        Self::from(Vec::new())
+    }
+
+    /// A copy of `data` in a buffer of its own
+    pub fn copy_from_slice(data: &[u8]) -> Self {
+        Self::from(data.to_vec())
+    }
+
+    /// Take over `data`, which goes back to `pool` when the last view is dropped
+    pub(crate) fn pooled(data: Vec<u8>, pool: BufferPool) -> Self {
+        let end = data.len();
+        Self {
+            buf: Arc::new(Buf {
+                data,
+                pool: Some(pool),
+            }),
+            start: 0,
+            end,
+        }
+    }
+
+    /// The bytes in `range` of this view, sharing the buffer.
+    /// Panics if the range is out of bounds, as slicing does.
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> Self {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.len(),
+        };
+        assert!(
+            start <= end && end <= self.len(),
+            "range {}..{} out of bounds of {} bytes",
+            start,
+            end,
+            self.len()
+        );
+        Self {
+            buf: Arc::clone(&self.buf),
+            start: self.start + start,
+            end: self.start + end,
+        }
+    }
+
+    /// This view, copied into a buffer of its own size if it takes up less
+    /// than half of the buffer it shares, so keeping it does not keep the
+    /// rest of the buffer too
+    pub fn compact(&self) -> Self {
+        if self.len() * 2 < self.buf.data.capacity() {
+            Self::copy_from_slice(self)
+        } else {
+            self.clone()
+        }
+    }
+}
+
+impl Default for Bytes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf.data[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Takes over `data` without copying
+impl From<Vec<u8>> for Bytes {
+    fn from(data: Vec<u8>) -> Self {
+        let end = data.len();
+        Self {
+            buf: Arc::new(Buf { data, pool: None }),
+            start: 0,
+            end,
+        }
+    }
+}
+
+impl PartialEq for Bytes {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for Bytes {}
+
+impl PartialEq<[u8]> for Bytes {
+    fn eq(&self, other: &[u8]) -> bool {
+        self[..] == *other
+    }
+}
+
+impl<const N: usize> PartialEq<&[u8; N]> for Bytes {
+    fn eq(&self, other: &&[u8; N]) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl PartialEq<Vec<u8>> for Bytes {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bytes({:?})", &self[..])
+    }
+}
+
+/// A received frame, or a part of it, as a protocol sees it (see the
+/// [module](self) docs). Dereferences to the bytes.
+#[derive(Clone)]
+pub enum Frame<'a> {
+    /// Handled at once, straight from the driver's buffer
+    Borrowed(&'a [u8]),
+    /// Queued before it was handled
+    Shared(Bytes),
+}
+
+impl<'a> Frame<'a> {
+    /// The bytes in `range` of this frame, without copying.
+    /// Panics if the range is out of bounds, as slicing does.
+    pub fn slice(&self, range: impl RangeBounds<usize>) -> Frame<'a> {
+        match self {
+            Frame::Borrowed(data) => {
+                let data: &'a [u8] = data;
+                Frame::Borrowed(&data[(range.start_bound().cloned(), range.end_bound().cloned())])
+            }
+            Frame::Shared(bytes) => Frame::Shared(bytes.slice(range)),
+        }
+    }
+
+    /// The bytes, to keep: a copy of a borrowed frame, a [compact](Bytes::compact)
+    /// view of a shared one
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            Frame::Borrowed(data) => Bytes::copy_from_slice(data),
+            Frame::Shared(bytes) => bytes.compact(),
+        }
+    }
+}
+
+impl Deref for Frame<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Frame::Borrowed(data) => data,
+            Frame::Shared(bytes) => bytes,
+        }
+    }
+}
+
+impl AsRef<[u8]> for Frame<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<'a> From<&'a [u8]> for Frame<'a> {
+    fn from(data: &'a [u8]) -> Self {
+        Frame::Borrowed(data)
+    }
+}
+
+impl From<Bytes> for Frame<'_> {
+    fn from(bytes: Bytes) -> Self {
+        Frame::Shared(bytes)
+    }
+}
+
+impl fmt::Debug for Frame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame({:?})", &self[..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_slices_share_the_buffer() {
+        let pool = BufferPool::new(1);
+        let frame = pool.bytes_from(b"headerpayload");
+        let payload = frame.slice(6..);
+        assert_eq!(payload, b"payload");
+        assert_eq!(payload.slice(..3), b"pay");
+        assert_eq!(payload.slice(3..=4), b"lo");
+        assert!(payload.slice(7..).is_empty());
+
+        // The buffer is given back once no view is left
+        drop(frame);
+        assert_eq!(payload, b"payload");
+        drop(payload);
+        assert!(pool.take().capacity() >= 13);
+    }
+
+    #[test]
+    fn test_frame_keeps_what_it_must() {
+        // A borrowed frame is copied, the part kept only
+        let data = b"headerpayload".to_vec();
+        let kept = Frame::Borrowed(&data).slice(6..).to_bytes();
+        assert_eq!(kept, b"payload");
+        assert_ne!(kept.as_ptr(), data[6..].as_ptr());
+
+        // A queued one is shared unless the part kept is small
+        let pool = BufferPool::preallocated(1, 64);
+        let frame = Frame::Shared(pool.bytes_from(&[7; 40]));
+        let large = frame.slice(8..).to_bytes();
+        assert_eq!(large.as_ptr(), frame[8..].as_ptr());
+        let small = frame.slice(..8).to_bytes();
+        assert_ne!(small.as_ptr(), frame.as_ptr());
+        drop((frame, large));
+        assert_eq!(small, &[7; 8]);
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn test_bytes_slice_out_of_bounds() {
+        Bytes::copy_from_slice(b"abc").slice(2..4);
+    }
+}
@@ -0,0 +1,112 @@
+//! What this build of the stack supports.
+//!
+//! Embedding applications and tools query [`NetStack::capabilities`] instead of
+//! assuming a feature set, since protocols and limits change between versions.
+//!
+//! [`NetStack::capabilities`]: crate::stack::NetStack::capabilities
+
+use std::fmt;
+
+use crate::limits::StackLimits;
+use crate::protocol::{ProtocolType, tcp};
+use crate::stack::RX_BUDGET;
+
+/// Protocols, drivers, features and limits of a stack instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Crate version
+    pub version: &'static str,
+    /// Frame types with a registered handler
+    pub link_protocols: Vec<ProtocolType>,
+    /// Protocols carried over IP
+    pub ip_protocols: Vec<&'static str>,
+    pub drivers: Vec<&'static str>,
+    /// Optional behaviors that can be switched on at run time
+    pub features: Vec<&'static str>,
+    pub limits: Limits,
+    /// Bounds the stack was created with
+    pub stack_limits: StackLimits,
+}
+
+/// Compiled-in sizes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub tcp_recv_buffer: usize,
+    pub tcp_default_mss: u16,
+    /// Frames handled per processing loop iteration
+    pub rx_budget: usize,
+}
+
+impl Capabilities {
+    pub(crate) fn new(link_protocols: Vec<ProtocolType>, stack_limits: StackLimits) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            link_protocols,
+            ip_protocols: vec!["icmp", "igmp", "udp", "tcp"],
+            drivers: vec!["loopback", "veth", "memory"],
+            features: vec![
+                "ip-forwarding",
+                "ip-source-route",
+                "nat-masquerade",
+                "nat-port-forward",
+                "fast-responder",
+                "deferred-input",
+                "irq-thread",
+                "softirq-thread",
+                "timer-thread",
+                "net-events",
+                "pause-step",
+                "tcp-congestion-control",
+                "tcp-delayed-ack",
+                "tcp-timestamps",
+                "tcp-auth",
+                "raw-sockets",
+            ],
+            limits: Limits {
+                tcp_recv_buffer: tcp::TCP_RECV_BUFFER_SIZE,
+                tcp_default_mss: tcp::TCP_DEFAULT_MSS,
+                rx_budget: RX_BUDGET,
+            },
+            stack_limits,
+        }
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "version: {}", self.version)?;
+        writeln!(f, "link protocols: {:?}", self.link_protocols)?;
+        writeln!(f, "ip protocols: {}", self.ip_protocols.join(", "))?;
+        writeln!(f, "drivers: {}", self.drivers.join(", "))?;
+        writeln!(f, "features: {}", self.features.join(", "))?;
+        writeln!(
+            f,
+            "limits: tcp_recv_buffer={}, tcp_default_mss={}, rx_budget={}",
+            self.limits.tcp_recv_buffer, self.limits.tcp_default_mss, self.limits.rx_budget
+        )?;
+        let l = &self.stack_limits;
+        write!(
+            f,
+            "stack limits: devices={}, ifaces={}, routes={}, udp_sockets={}, tcp_sockets={}, \
+             raw_sockets={}, neighbors={}, multicast_groups={}, nat_entries={}, peers={}, rx_queue_len={}, tx_queue_len={}, socket_queue_len={}, packet_buffers={}",
+            l.devices,
+            l.ifaces,
+            l.routes,
+            l.udp_sockets,
+            l.tcp_sockets,
+            l.raw_sockets,
+            l.neighbors,
+            l.multicast_groups,
+            l.nat_entries,
+            l.peers,
+            l.rx_queue_len,
+            l.tx_queue_len,
+            l.socket_queue_len,
+            l.packet_buffers
+        )
+    }
+}
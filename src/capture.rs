@@ -0,0 +1,324 @@
+//! pcapng packet capture.
+//!
+//! A [`Capture`] attached to a device writes every frame the device receives
+//! or transmits to a pcapng file that Wireshark and tcpdump can open. Each
+//! attached device gets an Interface Description Block named after it. Each
+//! frame becomes an Enhanced Packet Block with a microsecond timestamp and the
+//! direction in its `epb_flags`.
+//!
+//! Frames in this stack carry no link-layer header. Only the type travels with
+//! the payload, so frames are written as Linux cooked captures
+//! ([`LINKTYPE_LINUX_SLL`]): a 16-byte header with the direction, the sender's
+//! hardware address when known, and the protocol type. Wireshark dissects
+//! ARP, IPv4 and IPv6 from that type as it would from an Ethernet frame.
+//!
+//! ```
+//! # use microps::builder::memory;
+//! # use microps::capture::Capture;
+//! # use microps::device::DeviceIndex;
+//! # use microps::device::ether::EtherAddr;
+//! # use microps::stack::NetStack;
+//! # use std::sync::Arc;
+//! let stack = NetStack::builder()
+//!     .device(memory(EtherAddr::from_seed("eth0")))
+//!     .ip("192.0.2.1/24")
+//!     .build()?;
+//! let capture = Arc::new(Capture::create(std::env::temp_dir().join("microps.pcapng"))?);
+//! stack.capture(DeviceIndex(0), &capture)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result};
+
+use crate::device::{Device, DeviceType};
+use crate::platform;
+
+/// Linux cooked capture v1 (`DLT_LINUX_SLL`)
+pub const LINKTYPE_LINUX_SLL: u16 = 113;
+
+const BLOCK_SHB: u32 = 0x0a0d_0d0a;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// `epb_flags` direction bits
+const EPB_FLAGS_INBOUND: u32 = 0b01;
+const EPB_FLAGS_OUTBOUND: u32 = 0b10;
+
+const SLL_HDR_SIZE: usize = 16;
+/// `sll_pkttype`: to us, or sent by us
+const SLL_HOST: u16 = 0;
+const SLL_OUTGOING: u16 = 4;
+/// `sll_hatype` (`ARPHRD_*`)
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_LOOPBACK: u16 = 772;
+const ARPHRD_NONE: u16 = 0xfffe;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+struct Sink {
+    out: BufWriter<Box<dyn Write + Send>>,
+    interfaces: u32,
+}
+
+/// A pcapng file that devices write their frames to
+pub struct Capture {
+    enabled: AtomicBool,
+    sink: Mutex<Sink>,
+}
+
+impl Capture {
+    /// Start a capture on `out` by writing the Section Header Block
+    pub fn new(out: Box<dyn Write + Send>) -> Result<Self> {
+        let mut out = BufWriter::new(out);
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // Section length unknown
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut out, BLOCK_SHB, &body)?;
+        Ok(Self {
+            enabled: AtomicBool::new(true),
+            sink: Mutex::new(Sink { out, interfaces: 0 }),
+        })
+    }
+
+    /// Start a capture to a new file at `path`
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create capture file: {}", path.display()))?;
+        Self::new(Box::new(file))
+    }
+
+    /// Describe `dev` in the file and return its interface id
+    pub(crate) fn add_interface(&self, dev: &Device) -> Result<u32> {
+        let mut sink = self.sink.lock().unwrap();
+        let mut body = Vec::with_capacity(32);
+        body.extend_from_slice(&LINKTYPE_LINUX_SLL.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // No snapshot length limit
+        body.extend_from_slice(&0u32.to_le_bytes());
+        push_option(&mut body, OPT_IF_NAME, dev.name_string().as_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        write_block(&mut sink.out, BLOCK_IDB, &body)?;
+        let id = sink.interfaces;
+        sink.interfaces += 1;
+        Ok(id)
+    }
+
+    /// Write a frame of `dev`, interface `interface` in the file
+    pub(crate) fn record(
+        &self,
+        interface: u32,
+        dev: &Device,
+        direction: Direction,
+        type_: u16,
+        data: &[u8],
+    ) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        let micros = platform::since_epoch().as_micros() as u64;
+        let captured = SLL_HDR_SIZE + data.len();
+
+        let mut body = Vec::with_capacity(20 + captured.next_multiple_of(4) + 12);
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(captured as u32).to_le_bytes());
+        body.extend_from_slice(&(captured as u32).to_le_bytes());
+        push_sll_header(&mut body, dev, direction, type_);
+        body.extend_from_slice(data);
+        body.resize(body.len().next_multiple_of(4), 0);
+        let flags = match direction {
+            Direction::In => EPB_FLAGS_INBOUND,
+            Direction::Out => EPB_FLAGS_OUTBOUND,
+        };
+        push_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+
+        let mut sink = self.sink.lock().unwrap();
+        if let Err(e) = write_block(&mut sink.out, BLOCK_EPB, &body) {
+            tracing::error!("capture: write failed, capture stopped: {}", e);
+            self.enabled.store(false, Ordering::Release);
+        }
+    }
+
+    /// Write out buffered blocks
+    pub fn flush(&self) -> Result<()> {
+        self.sink.lock().unwrap().out.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            tracing::error!("capture: flush failed: {}", e);
+        }
+    }
+}
+
+/// The SLL header: packet type, `ARPHRD_*` type, sender address, protocol.
+/// Only transmitted frames have a known sender: the device itself.
+fn push_sll_header(buf: &mut Vec<u8>, dev: &Device, direction: Direction, type_: u16) {
+    let hatype = match dev.device_type {
+        DeviceType::Loopback => ARPHRD_LOOPBACK,
+        _ if dev.hw_addr().is_some() => ARPHRD_ETHER,
+        _ => ARPHRD_NONE,
+    };
+    let (pkttype, addr) = match direction {
+        Direction::In => (SLL_HOST, &[][..]),
+        Direction::Out => (SLL_OUTGOING, &dev.addr[..(dev.alen as usize).min(8)]),
+    };
+    buf.extend_from_slice(&pkttype.to_be_bytes());
+    buf.extend_from_slice(&hatype.to_be_bytes());
+    buf.extend_from_slice(&(addr.len() as u16).to_be_bytes());
+    let mut padded = [0u8; 8];
+    padded[..addr.len()].copy_from_slice(addr);
+    buf.extend_from_slice(&padded);
+    buf.extend_from_slice(&type_.to_be_bytes());
+}
+
+/// Append an option, its value padded to 32 bits
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+/// Type, total length, body (a multiple of 4 bytes), total length again
+fn write_block(out: &mut impl Write, type_: u32, body: &[u8]) -> std::io::Result<()> {
+    let total = (12 + body.len()) as u32;
+    out.write_all(&type_.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&total.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::builder::memory;
+    use crate::device::DeviceIndex;
+    use crate::device::ether::EtherAddr;
+    use crate::protocol::PROTOCOL_TYPE_IP;
+    use crate::protocol::icmp::{self, IcmpType};
+    use crate::protocol::ip::{IpAddr, IpHdr, IpProtocol};
+    use crate::stack::NetStack;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Split a pcapng file into (type, body) blocks
+    fn blocks(mut data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while !data.is_empty() {
+            let type_ = u32::from_le_bytes(data[0..4].try_into().unwrap());
+            let total = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+            assert_eq!(total % 4, 0);
+            assert_eq!(&data[total - 4..total], &data[4..8]);
+            blocks.push((type_, data[8..total - 4].to_vec()));
+            data = &data[total..];
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_capture_echo_in_and_out() {
+        let addr = EtherAddr::from_seed("eth0");
+        let stack = NetStack::builder()
+            .device(memory(addr))
+            .ip("192.0.2.1/24")
+            .build()
+            .unwrap();
+        let file = Shared::default();
+        let capture = Arc::new(Capture::new(Box::new(file.clone())).unwrap());
+        stack.capture(DeviceIndex(0), &capture).unwrap();
+
+        let echo = icmp::build(IcmpType::Echo, 0, 0x0001_0001, b"ping");
+        let mut request = IpHdr::new(
+            IpProtocol::Icmp,
+            (20 + echo.len()) as u16,
+            1,
+            0,
+            IpAddr::from_str("192.0.2.2").unwrap(),
+            IpAddr::from_str("192.0.2.1").unwrap(),
+        )
+        .with_checksum()
+        .to_bytes()
+        .to_vec();
+        request.extend_from_slice(&echo);
+        stack
+            .inject(DeviceIndex(0), PROTOCOL_TYPE_IP, &request)
+            .unwrap();
+        capture.flush().unwrap();
+
+        let data = file.0.lock().unwrap().clone();
+        let blocks = blocks(&data);
+        assert_eq!(blocks.len(), 4);
+
+        let (type_, shb) = &blocks[0];
+        assert_eq!(*type_, BLOCK_SHB);
+        assert_eq!(&shb[0..4], &BYTE_ORDER_MAGIC.to_le_bytes());
+
+        let (type_, idb) = &blocks[1];
+        assert_eq!(*type_, BLOCK_IDB);
+        assert_eq!(&idb[0..2], &LINKTYPE_LINUX_SLL.to_le_bytes());
+        assert_eq!(&idb[8..12], &[2, 0, 4, 0]);
+        assert_eq!(&idb[12..16], b"net0");
+
+        // The request in, then the reply out
+        for ((type_, epb), (pkttype, flags)) in blocks[2..].iter().zip([
+            (SLL_HOST, EPB_FLAGS_INBOUND),
+            (SLL_OUTGOING, EPB_FLAGS_OUTBOUND),
+        ]) {
+            assert_eq!(*type_, BLOCK_EPB);
+            assert_eq!(&epb[0..4], &0u32.to_le_bytes());
+            let len = u32::from_le_bytes(epb[12..16].try_into().unwrap()) as usize;
+            assert_eq!(len, SLL_HDR_SIZE + request.len());
+            let sll = &epb[20..20 + SLL_HDR_SIZE];
+            assert_eq!(&sll[0..2], &pkttype.to_be_bytes());
+            assert_eq!(&sll[2..4], &ARPHRD_ETHER.to_be_bytes());
+            assert_eq!(&sll[14..16], &PROTOCOL_TYPE_IP.to_be_bytes());
+            let options = &epb[20 + len.next_multiple_of(4)..];
+            assert_eq!(&options[4..8], &flags.to_le_bytes());
+        }
+        let out = &blocks[3].1[20..20 + SLL_HDR_SIZE];
+        assert_eq!(&out[4..6], &6u16.to_be_bytes());
+        assert_eq!(&out[6..12], &addr.0);
+        let reply = IpHdr::from_bytes(&blocks[3].1[20 + SLL_HDR_SIZE..]).unwrap();
+        assert_eq!(reply.dst(), IpAddr::from_str("192.0.2.2").unwrap());
+    }
+}
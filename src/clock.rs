@@ -0,0 +1,110 @@
+//! Where the stack reads the time.
+//!
+//! Protocol timers, neighbor cache aging and TCP retransmission read the time
+//! from the stack's [`Clock`] instead of [`Instant::now`]. A stack normally
+//! runs on [`SystemClock`]; tests and simulations give it a [`VirtualClock`]
+//! and move time forward by hand, so a retransmission that is due after a
+//! second happens at once and at the same point of every run:
+//!
+//! ```
+//! # use std::time::Duration;
+//! # use microps::clock::{Clock, VirtualClock};
+//! # use microps::limits::StackLimits;
+//! # use microps::stack::NetStack;
+//! let time = VirtualClock::new();
+//! let stack = NetStack::with_clock(StackLimits::default(), Clock::new(time.clone()))?;
+//! let before = stack.ctx().clock.now();
+//! time.advance(Duration::from_secs(60));
+//! assert_eq!(stack.ctx().clock.now() - before, Duration::from_secs(60));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Blocking socket calls still wait in real time; their timeouts are on the
+//! caller's clock, not the stack's.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::platform::Instant;
+
+/// A source of monotonic time
+pub trait TimeSource: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The host's monotonic clock
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl TimeSource for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the time, so a test
+/// keeps one to [`advance`](Self::advance) the clock it gave the stack.
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl VirtualClock {
+    /// A clock standing at the current host time
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::ZERO)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    /// Time advanced since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSource for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+/// The time source of one stack, shared by its tables
+#[derive(Clone)]
+pub struct Clock(Arc<dyn TimeSource>);
+
+impl Clock {
+    pub fn new(source: impl TimeSource + 'static) -> Self {
+        Self(Arc::new(source))
+    }
+
+    pub fn now(&self) -> Instant {
+        self.0.now()
+    }
+}
+
+/// [`SystemClock`]
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Clock").finish_non_exhaustive()
+    }
+}
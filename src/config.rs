@@ -0,0 +1,304 @@
+//! Stack configuration files.
+//!
+//! A config file describes one stack in TOML. Unknown tables and keys are
+//! rejected, since they are most likely typos.
+//!
+//! ```text
+//! [stack]
+//! forwarding = true
+//! masquerade = "192.0.2.2"          # implies forwarding
+//! gateway = "192.0.2.1"             # default route
+//!
+//! [[device]]
+//! type = "loopback"                 # 127.0.0.1/8 and ::1/128
+//!
+//! [[device]]
+//! type = "memory"
+//! hw_addr = "02:00:00:00:00:01"     # derived from the position if omitted
+//! mtu = 1500                        # the driver's default if omitted
+//! ip = ["192.0.2.2/24", "2001:db8::2/64"]
+//!
+//! [[route]]
+//! network = "198.51.100.0/24"
+//! gateway = "192.0.2.254"
+//!
+//! [[forward]]                       # static port forward (NAT)
+//! protocol = "tcp"
+//! external = "192.0.2.2:8080"
+//! internal = "10.0.0.2:80"
+//!
+//! [services]                        # port names (see `services`)
+//! http-alt = "8080/tcp"
+//! game = ["7777/tcp", "7777/udp"]
+//! ```
+//!
+//! [`load`] turns the file into a [`NetStackBuilder`], so the caller can add
+//! what a file cannot express (threads, custom protocols) before building.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::builder::{self, NetStackBuilder};
+use crate::device::ether::EtherAddr;
+use crate::protocol::ip::IpProtocol;
+use crate::services::{self, Service};
+use crate::stack::NetStack;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    stack: StackConfig,
+    #[serde(rename = "device")]
+    devices: Vec<DeviceConfig>,
+    #[serde(rename = "route")]
+    routes: Vec<RouteConfig>,
+    #[serde(rename = "forward")]
+    forwards: Vec<ForwardConfig>,
+    /// Name to `port/proto`
+    services: BTreeMap<String, OneOrMany>,
+}
+
+/// `[stack]`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StackConfig {
+    forwarding: Option<bool>,
+    masquerade: Option<String>,
+    gateway: Option<String>,
+}
+
+/// `[[device]]`, tagged by `type`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum DeviceConfig {
+    Loopback {
+        #[serde(default)]
+        ip: OneOrMany,
+        mtu: Option<u16>,
+    },
+    Memory {
+        hw_addr: Option<String>,
+        #[serde(default)]
+        ip: OneOrMany,
+        mtu: Option<u16>,
+    },
+}
+
+/// A single string or an array of them
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for OneOrMany {
+    fn default() -> Self {
+        OneOrMany::Many(Vec::new())
+    }
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(v) => v,
+        }
+    }
+}
+
+/// `[[route]]`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteConfig {
+    network: String,
+    gateway: String,
+}
+
+/// `[[forward]]`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForwardConfig {
+    protocol: ForwardProtocol,
+    external: String,
+    internal: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Read a stack configuration into a builder
+pub fn parse(text: &str) -> Result<NetStackBuilder> {
+    let config: Config = toml::from_str(text)?;
+    let mut builder = NetStack::builder();
+
+    let stack = config.stack;
+    if let Some(forwarding) = stack.forwarding {
+        builder = builder.forwarding(forwarding);
+    }
+    if let Some(addr) = stack.masquerade {
+        builder = builder.masquerade(&addr);
+    }
+    if let Some(gateway) = stack.gateway {
+        builder = builder.gateway(&gateway);
+    }
+
+    for (i, device) in config.devices.into_iter().enumerate() {
+        let (device, ip, mtu) = match device {
+            DeviceConfig::Loopback { ip, mtu } => (builder::loopback(), ip, mtu),
+            DeviceConfig::Memory { hw_addr, ip, mtu } => {
+                let addr = match hw_addr {
+                    Some(addr) => EtherAddr::from_str(&addr)
+                        .with_context(|| format!("device {}: hw_addr {}", i, addr))?,
+                    None => EtherAddr::from_seed(&format!("device{}", i)),
+                };
+                (builder::memory(addr), ip, mtu)
+            }
+        };
+        builder = builder.device(device);
+        if let Some(mtu) = mtu {
+            builder = builder.mtu(mtu);
+        }
+        for cidr in ip.into_vec() {
+            builder = builder.ip(&cidr);
+        }
+    }
+
+    for route in config.routes {
+        builder = builder.route(&route.network, &route.gateway);
+    }
+
+    for forward in config.forwards {
+        let protocol = match forward.protocol {
+            ForwardProtocol::Tcp => IpProtocol::Tcp,
+            ForwardProtocol::Udp => IpProtocol::Udp,
+        };
+        builder = builder.port_forward(protocol, &forward.external, &forward.internal);
+    }
+
+    for (name, ports) in config.services {
+        for port in ports.into_vec() {
+            let (port, protocol) =
+                services::parse_port(&port).with_context(|| format!("service {}", name))?;
+            builder = builder.service(Service {
+                name: name.clone(),
+                port,
+                protocol,
+                aliases: Vec::new(),
+            });
+        }
+    }
+    Ok(builder)
+}
+
+/// Read the stack configuration in `path` (see [`parse`])
+pub fn load(path: &Path) -> Result<NetStackBuilder> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ip::IpAddr;
+
+    const CONFIG: &str = r#"
+        # A router with one inside and one outside address
+        [stack]
+        masquerade = "192.0.2.2"
+        gateway = "192.0.2.1"
+
+        [[device]]
+        type = "loopback"
+
+        [[device]]
+        type = "memory"
+        mtu = 1500
+        ip = ["192.0.2.2/24", "10.0.0.1/24"]   # outside, inside
+
+        [[route]]
+        network = "198.51.100.0/24"
+        gateway = "192.0.2.254"
+
+        [[forward]]
+        protocol = "tcp"
+        external = "192.0.2.2:8080"
+        internal = "10.0.0.2:80"
+    "#;
+
+    #[test]
+    fn test_config_builds_stack() {
+        let stack = parse(CONFIG).unwrap().build().unwrap();
+        let ctx = stack.ctx();
+        assert!(ctx.ip_config.forwarding);
+        assert_eq!(ctx.ip_config.masquerade, Some("192.0.2.2".parse().unwrap()));
+        assert_eq!(ctx.nat.forwards().len(), 1);
+
+        let nexthop = |dst: &str| {
+            ctx.ip_routes
+                .lookup(dst.parse().unwrap())
+                .map(|route| route.nexthop)
+        };
+        assert_eq!(
+            nexthop("198.51.100.7"),
+            Some("192.0.2.254".parse().unwrap())
+        );
+        assert_eq!(nexthop("203.0.113.1"), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(nexthop("10.0.0.2"), Some(IpAddr::ANY));
+    }
+
+    #[test]
+    fn test_config_sets_mtu() {
+        let config = "[[device]]\ntype = \"memory\"\nmtu = 1280\nip = \"192.0.2.2/24\"";
+        let stack = parse(config).unwrap().build().unwrap();
+        assert_eq!(stack.devices().iter().next().unwrap().mtu, 1280);
+
+        let err = parse("[[device]]\ntype = \"memory\"\nmtu = 40")
+            .unwrap()
+            .build()
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", err).contains("MTU below 68"));
+    }
+
+    #[test]
+    fn test_config_names_services() {
+        let config = "[services]\nconfig-test = [\"7601/tcp\", \"7602/udp\"]\n";
+        let builder = parse(config).unwrap();
+        assert_eq!(services::SERVICES.port("config-test", None), None);
+        let _stack = builder.build().unwrap();
+        assert_eq!(
+            services::SERVICES.port("config-test", Some(IpProtocol::Tcp)),
+            Some(7601)
+        );
+        assert_eq!(
+            services::SERVICES.name(7602, IpProtocol::Udp).as_deref(),
+            Some("config-test")
+        );
+
+        let err = parse("[services]\nbad = \"1/sctp\"").err().unwrap();
+        assert!(format!("{:#}", err).contains("unknown protocol sctp"));
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let err = |text: &str| parse(text).err().unwrap().to_string();
+        assert!(err("forwarding = true").contains("unknown field `forwarding`"));
+        assert!(err("[stack]\nforwardin = true").contains("unknown field `forwardin`"));
+        assert!(err("[[device]]\ntype = \"tap\"").contains("unknown variant `tap`"));
+        assert!(err("[[device]]\ntype = \"loopback\"\nhw_addr = \"x\"").contains("hw_addr"));
+        assert!(err("[[route]]\nnetwork = \"10.0.0.0/8\"").contains("missing field `gateway`"));
+        assert!(err("[stack]\nforwarding = yes").contains("line 2"));
+        assert!(err("[[device]]\ntype = \"memory\"\nmtu = 70000").contains("expected u16"));
+    }
+}
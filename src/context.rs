@@ -1,8 +1,31 @@
-use anyhow::Result;
+use std::fmt;
 use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::iface::IpIface;
-use crate::protocol::ip::IpAddr;
+use crate::clock::Clock;
+use crate::diagnose::{Conflict, Conflicts};
+use crate::drop::DropMonitor;
+use crate::error::{NetError, Result};
+use crate::iface::{IpIface, Ipv6Iface};
+use crate::limits::StackLimits;
+use crate::lpm::PrefixTrie;
+use crate::platform::Instant;
+use crate::pool::{BufferPool, PACKET_BUFFER_SIZE};
+use crate::protocol::icmp::EchoReplyTable;
+use crate::protocol::igmp::IgmpGroups;
+use crate::protocol::ip::{IpAddr, IpEndpoint, IpProtocol};
+use crate::protocol::ipv6::Ipv6Addr;
+use crate::protocol::nat::NatTable;
+use crate::protocol::ndp::{NeighborCache, NeighborState};
+use crate::protocol::raw::RawPcbTable;
+use crate::protocol::tcp::{TcpPcbTable, TcpState};
+use crate::protocol::udp::UdpPcbTable;
+use crate::services::endpoint_name;
+use crate::snapshot::SnapshotCell;
+use crate::socket::SocketTable;
+use crate::stats::{PeerStatsTable, ProtocolStats};
 
 pub struct IpIdManager {
     next_id: AtomicU16,
@@ -27,25 +50,41 @@ impl Default for IpIdManager {
 }
 
 /// Global registry of IP interfaces (equivalent to C's `static struct ip_iface *ifaces`)
-#[derive(Default)]
 pub struct IpIfaceRegistry {
     ifaces: Vec<IpIface>,
+    limit: usize,
 }
 
 impl IpIfaceRegistry {
     pub fn new() -> Self {
-        Self { ifaces: Vec::new() }
+        Self::with_limit(StackLimits::default().ifaces)
+    }
+
+    /// Registry holding at most `limit` interfaces
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            ifaces: Vec::new(),
+            limit,
+        }
     }
 
     /// Register an IP interface
     pub fn register(&mut self, iface: IpIface) -> Result<()> {
-        // check for duplicates could be added here
-        if self
-            .ifaces
-            .iter()
-            .any(|existing| existing.unicast == iface.unicast)
-        {
-            anyhow::bail!("IP interface with address {} already exists", iface.unicast);
+        if self.ifaces.len() >= self.limit {
+            return Err(NetError::LimitReached {
+                what: "IP interfaces",
+                limit: self.limit,
+            });
+        }
+        if let Some(existing) = self.select(iface.unicast) {
+            return Err(NetError::AddressInUse(Conflicts {
+                request: format!("register iface {}", iface.unicast),
+                conflicts: vec![Conflict::IfaceAddress {
+                    device: existing.device_index,
+                    unicast: existing.unicast,
+                    netmask: existing.netmask,
+                }],
+            }));
         }
 
         self.ifaces.push(iface);
@@ -56,16 +95,964 @@ impl IpIfaceRegistry {
     pub fn select(&self, addr: IpAddr) -> Option<&IpIface> {
         self.ifaces.iter().find(|iface| iface.unicast == addr)
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = &IpIface> {
+        self.ifaces.iter()
+    }
+}
+
+impl Default for IpIfaceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// IP route entry (equivalent to C's `struct ip_route`)
+#[derive(Debug, Clone)]
+pub struct IpRoute {
+    pub network: IpAddr,
+    pub netmask: IpAddr,
+    /// Next hop address; `IpAddr::ANY` means the destination is on-link
+    pub nexthop: IpAddr,
+    pub iface: IpIface,
+}
+
+impl IpRoute {
+    pub fn matches(&self, dst: IpAddr) -> bool {
+        dst & self.netmask == self.network
+    }
+
+    pub fn info(&self) -> String {
+        let nexthop = if self.nexthop == IpAddr::ANY {
+            "none".to_string()
+        } else {
+            self.nexthop.to_string()
+        };
+        format!(
+            "network={}, netmask={}, nexthop={}, iface={}",
+            self.network, self.netmask, nexthop, self.iface.unicast
+        )
+    }
+}
+
+/// A change made to the routing table
+#[derive(Debug, Clone)]
+pub enum RouteChange {
+    Added(IpRoute),
+    Removed(IpRoute),
+}
+
+#[derive(Debug, Clone)]
+enum RouteOp {
+    Add(IpRoute),
+    Remove { network: IpAddr, netmask: IpAddr },
+}
+
+/// Route updates applied together by [`RouteTable::apply`]: all of them or none
+#[derive(Debug, Clone, Default)]
+pub struct RouteBatch {
+    ops: Vec<RouteOp>,
+}
+
+impl RouteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(
+        mut self,
+        network: IpAddr,
+        netmask: IpAddr,
+        nexthop: IpAddr,
+        iface: IpIface,
+    ) -> Self {
+        self.ops.push(RouteOp::Add(IpRoute {
+            network: network & netmask,
+            netmask,
+            nexthop,
+            iface,
+        }));
+        self
+    }
+
+    pub fn remove(mut self, network: IpAddr, netmask: IpAddr) -> Self {
+        self.ops.push(RouteOp::Remove {
+            network: network & netmask,
+            netmask,
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// An immutable view of the routing table at one point in time.
+///
+/// Cloning is cheap and lookups take no lock, so a forwarding path (or another
+/// thread) can keep using a snapshot while the table is being updated.
+#[derive(Debug, Clone, Default)]
+pub struct RouteSnapshot {
+    routes: Arc<PrefixTrie<IpRoute>>,
+}
+
+/// Trie key of `network`/`netmask`
+fn route_key(network: IpAddr, netmask: IpAddr) -> (u32, u8) {
+    (
+        u32::from_be_bytes(network.to_ne_bytes()),
+        netmask.prefix_len() as u8,
+    )
+}
+
+/// Longest prefix match in `routes`
+fn lookup(routes: &PrefixTrie<IpRoute>, dst: IpAddr) -> Option<&IpRoute> {
+    routes.lookup(route_key(dst, IpAddr::BROADCAST).0)
+}
+
+/// Route for exactly `network`/`netmask` in `routes`
+fn find(routes: &PrefixTrie<IpRoute>, network: IpAddr, netmask: IpAddr) -> Option<&IpRoute> {
+    let (prefix, len) = route_key(network, netmask);
+    routes
+        .get(prefix, len)
+        .filter(|route| route.network == network && route.netmask == netmask)
+}
+
+impl RouteSnapshot {
+    /// Longest prefix match lookup
+    pub fn lookup(&self, dst: IpAddr) -> Option<&IpRoute> {
+        lookup(&self.routes, dst)
+    }
+
+    /// Route for exactly `network`/`netmask`
+    pub fn find(&self, network: IpAddr, netmask: IpAddr) -> Option<&IpRoute> {
+        find(&self.routes, network, netmask)
+    }
+
+    /// Every route, shorter prefixes before the longer ones inside them
+    pub fn iter(&self) -> impl Iterator<Item = &IpRoute> {
+        self.routes.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Whether both snapshots are the same version of the table
+    pub fn same_version(&self, other: &RouteSnapshot) -> bool {
+        Arc::ptr_eq(&self.routes, &other.routes)
+    }
+}
+
+/// IP routing table (equivalent to C's `static struct ip_route *routes`)
+///
+/// Routes are kept in a [`PrefixTrie`], so a lookup takes at most 32 steps
+/// however many routes there are. The trie sits in a [`SnapshotCell`]: a
+/// lookup reads the current version without a lock, and an update builds
+/// the next version and swaps it in, so readers never observe a
+/// half-applied batch and updates need no write access to the stack.
+pub struct RouteTable {
+    routes: SnapshotCell<PrefixTrie<IpRoute>>,
+    /// Receivers of [`RouteChange`] events; dropped receivers are pruned.
+    /// Held while a batch is applied, so subscribers see batches in order.
+    subscribers: Mutex<Vec<Sender<RouteChange>>>,
+    limit: usize,
+}
+
+impl RouteTable {
+    pub fn new() -> Self {
+        Self::with_limit(StackLimits::default().routes)
+    }
+
+    /// Table holding at most `limit` routes
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            routes: SnapshotCell::default(),
+            subscribers: Mutex::new(Vec::new()),
+            limit,
+        }
+    }
+
+    /// Add a route (equivalent to C's `ip_route_add`)
+    pub fn add(
+        &self,
+        network: IpAddr,
+        netmask: IpAddr,
+        nexthop: IpAddr,
+        iface: IpIface,
+    ) -> Result<()> {
+        self.apply(RouteBatch::new().add(network, netmask, nexthop, iface))?;
+        Ok(())
+    }
+
+    /// Remove the route for exactly `network`/`netmask`
+    pub fn remove(&self, network: IpAddr, netmask: IpAddr) -> Result<IpRoute> {
+        let changes = self.apply(RouteBatch::new().remove(network, netmask))?;
+        match changes.into_iter().next() {
+            Some(RouteChange::Removed(route)) => Ok(route),
+            _ => unreachable!("a removal yields one Removed change"),
+        }
+    }
+
+    /// Apply every update in `batch`, in order, or none of them.
+    ///
+    /// The batch is applied to a copy of the trie that shares every node off
+    /// the updated paths, and the copy replaces the table only if every
+    /// update succeeded. Subscribers are told about the changes only once the
+    /// whole batch is in place.
+    pub fn apply(&self, batch: RouteBatch) -> Result<Vec<RouteChange>> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let changes = self.routes.update(|routes| -> Result<_> {
+            let mut changes = Vec::with_capacity(batch.ops.len());
+            Self::apply_ops(routes, batch.ops, &mut changes)?;
+            if routes.len() > self.limit {
+                return Err(NetError::LimitReached {
+                    what: "routes",
+                    limit: self.limit,
+                });
+            }
+            Ok(changes)
+        })?;
+
+        for change in &changes {
+            match change {
+                RouteChange::Added(route) => tracing::info!("route added: {}", route.info()),
+                RouteChange::Removed(route) => tracing::info!("route removed: {}", route.info()),
+            }
+        }
+        subscribers.retain(|tx| changes.iter().all(|change| tx.send(change.clone()).is_ok()));
+        Ok(changes)
+    }
+
+    /// Apply `ops` to `routes`, recording each one in `changes`, up to the
+    /// first that fails
+    fn apply_ops(
+        routes: &mut PrefixTrie<IpRoute>,
+        ops: Vec<RouteOp>,
+        changes: &mut Vec<RouteChange>,
+    ) -> Result<()> {
+        for op in ops {
+            match op {
+                RouteOp::Add(route) => {
+                    let (prefix, len) = route_key(route.network, route.netmask);
+                    if route_key(route.netmask, route.netmask).0.count_ones() != u32::from(len) {
+                        return Err(NetError::InvalidInput("netmask is not contiguous"));
+                    }
+                    if let Some(existing) = routes.get(prefix, len) {
+                        return Err(NetError::AddressInUse(Conflicts {
+                            request: format!("add route {}/{}", route.network, len),
+                            conflicts: vec![Conflict::Route(existing.clone())],
+                        }));
+                    }
+                    routes.insert(prefix, len, route.clone());
+                    changes.push(RouteChange::Added(route));
+                }
+                RouteOp::Remove { network, netmask } => {
+                    let (prefix, len) = route_key(network, netmask);
+                    let found = routes
+                        .get(prefix, len)
+                        .is_some_and(|r| r.network == network && r.netmask == netmask);
+                    if !found {
+                        return Err(NetError::NotFound(format!("route {}/{}", network, len)));
+                    }
+                    let Some(route) = routes.remove(prefix, len) else {
+                        unreachable!("the route was just found");
+                    };
+                    changes.push(RouteChange::Removed(route));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Receive a [`RouteChange`] for every route added or removed from now on
+    pub fn subscribe(&self) -> Receiver<RouteChange> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Add the default route via `gateway` (equivalent to C's `ip_route_set_default_gateway`)
+    pub fn set_default_gateway(&self, iface: IpIface, gateway: IpAddr) -> Result<()> {
+        self.add(IpAddr::ANY, IpAddr::ANY, gateway, iface)
+    }
+
+    /// Longest prefix match lookup (equivalent to C's `ip_route_lookup`),
+    /// taking no lock
+    pub fn lookup(&self, dst: IpAddr) -> Option<IpRoute> {
+        self.routes.read(|routes| lookup(routes, dst).cloned())
+    }
+
+    /// The current version of the table, to iterate over or to keep using
+    /// while the table changes
+    pub fn snapshot(&self) -> RouteSnapshot {
+        RouteSnapshot {
+            routes: self.routes.load(),
+        }
+    }
+
+    /// Route for exactly `network`/`netmask`
+    pub fn find(&self, network: IpAddr, netmask: IpAddr) -> Option<IpRoute> {
+        self.routes
+            .read(|routes| find(routes, network, netmask).cloned())
+    }
+}
+
+impl Default for RouteTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// IPv6 route entry
+#[derive(Debug, Clone)]
+pub struct Ipv6Route {
+    pub network: Ipv6Addr,
+    pub prefix_len: u8,
+    /// Router to send through; `None` means the destination is on-link
+    pub nexthop: Option<Ipv6Addr>,
+    pub iface: Ipv6Iface,
+}
+
+impl Ipv6Route {
+    pub fn matches(&self, dst: Ipv6Addr) -> bool {
+        dst.in_prefix(self.network, self.prefix_len)
+    }
+
+    pub fn is_on_link(&self) -> bool {
+        self.nexthop.is_none()
+    }
+
+    pub fn info(&self) -> String {
+        let nexthop = self
+            .nexthop
+            .map_or("none".to_string(), |nexthop| nexthop.to_string());
+        format!(
+            "network={}/{}, nexthop={}, iface={}",
+            self.network, self.prefix_len, nexthop, self.iface.unicast
+        )
+    }
+}
+
+/// A router on the Default Router List (RFC 4861 Section 6.3.6)
+#[derive(Debug, Clone)]
+pub struct DefaultRouter {
+    pub addr: Ipv6Addr,
+    pub iface: Ipv6Iface,
+    /// When the router stops being a default router; `None` for never
+    pub expires: Option<Instant>,
+}
+
+/// IPv6 routing table: prefix routes, looked up by longest prefix, and the
+/// default routers used for destinations no prefix covers.
+///
+/// Default routers may be learned from Router Advertisements while packets
+/// are being received, so that list can change through a shared reference.
+pub struct Ipv6RouteTable {
+    routes: Vec<Ipv6Route>,
+    routers: Mutex<Vec<DefaultRouter>>,
+    limit: usize,
+}
+
+impl Ipv6RouteTable {
+    pub fn new() -> Self {
+        Self::with_limit(StackLimits::default().routes)
+    }
+
+    /// Table holding at most `limit` routes and `limit` default routers
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            routes: Vec::new(),
+            routers: Mutex::new(Vec::new()),
+            limit,
+        }
+    }
+
+    /// Add a route to `network`/`prefix_len`, on-link if `nexthop` is `None`
+    pub fn add(
+        &mut self,
+        network: Ipv6Addr,
+        prefix_len: u8,
+        nexthop: Option<Ipv6Addr>,
+        iface: Ipv6Iface,
+    ) -> Result<()> {
+        if prefix_len > 128 {
+            return Err(NetError::InvalidInput("invalid prefix length"));
+        }
+        let network = network.prefix(prefix_len);
+        if self.find(network, prefix_len).is_some() {
+            return Err(NetError::AlreadyExists(format!(
+                "route {}/{}",
+                network, prefix_len
+            )));
+        }
+        if self.routes.len() >= self.limit {
+            return Err(NetError::LimitReached {
+                what: "routes",
+                limit: self.limit,
+            });
+        }
+        let route = Ipv6Route {
+            network,
+            prefix_len,
+            nexthop,
+            iface,
+        };
+        tracing::info!("route added: {}", route.info());
+        self.routes.push(route);
+        Ok(())
+    }
+
+    /// Remove the route for exactly `network`/`prefix_len`
+    pub fn remove(&mut self, network: Ipv6Addr, prefix_len: u8) -> Result<Ipv6Route> {
+        let network = network.prefix(prefix_len);
+        let Some(index) = self
+            .routes
+            .iter()
+            .position(|r| r.network == network && r.prefix_len == prefix_len)
+        else {
+            return Err(NetError::NotFound(format!(
+                "route {}/{}",
+                network, prefix_len
+            )));
+        };
+        let route = self.routes.remove(index);
+        tracing::info!("route removed: {}", route.info());
+        Ok(route)
+    }
+
+    /// Longest prefix match lookup over the prefix routes
+    pub fn lookup(&self, dst: Ipv6Addr) -> Option<&Ipv6Route> {
+        self.routes
+            .iter()
+            .filter(|route| route.matches(dst))
+            .max_by_key(|route| route.prefix_len)
+    }
+
+    /// Route for exactly `network`/`prefix_len`
+    pub fn find(&self, network: Ipv6Addr, prefix_len: u8) -> Option<&Ipv6Route> {
+        let network = network.prefix(prefix_len);
+        self.routes
+            .iter()
+            .find(|route| route.network == network && route.prefix_len == prefix_len)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Ipv6Route> {
+        self.routes.iter()
+    }
+
+    /// Add `addr` on the link of `iface` to the default routers for
+    /// `lifetime` (forever if `None`), or refresh its lifetime
+    pub fn add_default_router(
+        &self,
+        addr: Ipv6Addr,
+        iface: Ipv6Iface,
+        lifetime: Option<Duration>,
+    ) -> Result<()> {
+        let expires = lifetime.map(|lifetime| Instant::now() + lifetime);
+        let mut routers = self.routers.lock().unwrap();
+        if let Some(router) = routers.iter_mut().find(|router| router.addr == addr) {
+            router.iface = iface;
+            router.expires = expires;
+            return Ok(());
+        }
+        if routers.len() >= self.limit {
+            return Err(NetError::LimitReached {
+                what: "default routers",
+                limit: self.limit,
+            });
+        }
+        tracing::info!("default router added: {}, iface={}", addr, iface.unicast);
+        routers.push(DefaultRouter {
+            addr,
+            iface,
+            expires,
+        });
+        Ok(())
+    }
+
+    pub fn remove_default_router(&self, addr: Ipv6Addr) -> bool {
+        let mut routers = self.routers.lock().unwrap();
+        let before = routers.len();
+        routers.retain(|router| router.addr != addr);
+        routers.len() != before
+    }
+
+    /// Default routers whose lifetime has not run out, in the order they were added
+    pub fn default_routers(&self) -> Vec<DefaultRouter> {
+        let mut routers = self.routers.lock().unwrap();
+        Self::expire(&mut routers);
+        routers.clone()
+    }
+
+    /// The default router to send through: the first one whose link-layer
+    /// address is known in `neighbors`, or else the first one
+    pub fn select_default_router(&self, neighbors: &NeighborCache) -> Option<DefaultRouter> {
+        let mut routers = self.routers.lock().unwrap();
+        Self::expire(&mut routers);
+        routers
+            .iter()
+            .find(|router| {
+                neighbors
+                    .get(router.addr)
+                    .is_some_and(|neighbor| neighbor.state != NeighborState::Incomplete)
+            })
+            .or_else(|| routers.first())
+            .cloned()
+    }
+
+    fn expire(routers: &mut Vec<DefaultRouter>) {
+        let now = Instant::now();
+        routers.retain(|router| router.expires.is_none_or(|expires| expires > now));
+    }
+}
+
+impl Default for Ipv6RouteTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tunable IP layer behavior
+#[derive(Debug, Clone, Default)]
+pub struct IpConfig {
+    /// Forward packets not addressed to this host (act as a router)
+    pub forwarding: bool,
+    /// Forward packets carrying a Loose Source Route option through this host.
+    /// Disabled by default, as recommended by RFC 7126.
+    pub accept_source_route: bool,
+    /// Masquerade traffic forwarded out the interface with this address
+    /// behind it (see [`nat`](crate::protocol::nat))
+    pub masquerade: Option<IpAddr>,
+}
+
+/// One UDP or TCP control block as `netstat` shows it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub protocol: IpProtocol,
+    pub local: IpEndpoint,
+    /// Remote endpoint; `None` for UDP and for a listener accepting anyone
+    pub foreign: Option<IpEndpoint>,
+    /// Connection state; `None` for UDP
+    pub state: Option<TcpState>,
+    /// Bytes received but not yet read (connections waiting for a listener)
+    pub recv_queue: usize,
+    /// Bytes sent but not yet acknowledged
+    pub send_queue: usize,
+}
+
+impl fmt::Display for ConnectionInfo {
+    /// `proto recv-q send-q local foreign [state]`, naming well-known ports
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let proto = match self.protocol {
+            IpProtocol::Tcp => "tcp",
+            _ => "udp",
+        };
+        let foreign = self.foreign.map_or("*:*".to_string(), |foreign| {
+            endpoint_name(foreign, self.protocol)
+        });
+        write!(
+            f,
+            "{} {:>6} {:>6} {:<21} {:<21}",
+            proto,
+            self.recv_queue,
+            self.send_queue,
+            endpoint_name(self.local, self.protocol),
+            foreign
+        )?;
+        if let Some(state) = self.state {
+            write!(f, " {:?}", state)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Default)]
 pub struct ProtocolContexts {
+    pub ip_config: IpConfig,
+    pub drops: DropMonitor,
     pub ip_id: IpIdManager,
     pub ip_ifaces: IpIfaceRegistry,
+    /// Shared with [`NetStack::routes`](crate::stack::NetStack::routes)
+    pub ip_routes: Arc<RouteTable>,
+    pub ipv6_routes: Ipv6RouteTable,
+    /// Shared with [`NetStack::neighbors`](crate::stack::NetStack::neighbors)
+    pub neighbors: Arc<NeighborCache>,
+    pub igmp: IgmpGroups,
+    pub nat: NatTable,
+    pub peer_stats: PeerStatsTable,
+    /// Traffic per protocol (see [`stats`](crate::stats))
+    pub stats: ProtocolStats,
+    pub icmp_echo: EchoReplyTable,
+    pub udp: UdpPcbTable,
+    pub tcp: TcpPcbTable,
+    pub raw: RawPcbTable,
+    pub sockets: SocketTable,
+    /// Buffers for queued received frames and outgoing packets (see [`pool`](crate::pool))
+    pub packets: BufferPool,
+    /// Time the tables age their entries by (see [`clock`](crate::clock))
+    pub clock: Clock,
 }
 
 impl ProtocolContexts {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Protocol state whose tables are bounded by `limits`
+    pub fn with_limits(limits: &StackLimits) -> Self {
+        Self::with_clock(limits, Clock::default())
+    }
+
+    /// Like [`with_limits`](Self::with_limits), with neighbor cache aging,
+    /// NAT idle timeouts, IGMP report delays and TCP timeouts on `clock`
+    pub fn with_clock(limits: &StackLimits, clock: Clock) -> Self {
+        Self {
+            ip_ifaces: IpIfaceRegistry::with_limit(limits.ifaces),
+            ip_routes: Arc::new(RouteTable::with_limit(limits.routes)),
+            ipv6_routes: Ipv6RouteTable::with_limit(limits.routes),
+            neighbors: Arc::new(NeighborCache::with_clock(limits.neighbors, clock.clone())),
+            igmp: IgmpGroups::with_clock(limits.multicast_groups, clock.clone()),
+            nat: NatTable::with_clock(limits.nat_entries, clock.clone()),
+            peer_stats: PeerStatsTable::with_clock(limits.peers, clock.clone()),
+            icmp_echo: EchoReplyTable::with_limit(limits.socket_queue_len),
+            udp: UdpPcbTable::with_limits(limits.udp_sockets, limits.socket_queue_len),
+            tcp: TcpPcbTable::with_clock(limits.tcp_sockets, clock.clone()),
+            raw: RawPcbTable::with_limits(limits.raw_sockets, limits.socket_queue_len),
+            sockets: SocketTable::with_limit(
+                limits.udp_sockets + limits.tcp_sockets + limits.raw_sockets,
+            ),
+            packets: BufferPool::preallocated(limits.packet_buffers, PACKET_BUFFER_SIZE),
+            clock,
+            ..Self::default()
+        }
+    }
+
+    /// Every UDP and TCP control block, UDP first, for a `netstat`-style
+    /// listing or a debug dashboard. The tables are read once, so the
+    /// iterator does not see later changes.
+    pub fn connections(&self) -> impl Iterator<Item = ConnectionInfo> + use<> {
+        let mut connections = self.udp.connections();
+        connections.extend(self.tcp.connections());
+        connections.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::device::DeviceIndex;
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_route_lookup_longest_prefix() {
+        let iface0 = IpIface::new("192.0.2.2", "255.255.255.0", DeviceIndex(0)).unwrap();
+        let iface1 = IpIface::new("10.0.0.2", "255.0.0.0", DeviceIndex(1)).unwrap();
+
+        let routes = RouteTable::new();
+        routes
+            .add(
+                addr("192.0.2.0"),
+                addr("255.255.255.0"),
+                IpAddr::ANY,
+                iface0.clone(),
+            )
+            .unwrap();
+        routes
+            .add(
+                addr("10.0.0.0"),
+                addr("255.0.0.0"),
+                IpAddr::ANY,
+                iface1.clone(),
+            )
+            .unwrap();
+        routes
+            .add(
+                addr("10.1.0.0"),
+                addr("255.255.0.0"),
+                addr("192.0.2.254"),
+                iface0.clone(),
+            )
+            .unwrap();
+        routes
+            .set_default_gateway(iface0, addr("192.0.2.1"))
+            .unwrap();
+
+        let route = routes.lookup(addr("192.0.2.10")).unwrap();
+        assert_eq!(route.nexthop, IpAddr::ANY);
+        assert_eq!(route.iface.device_index, DeviceIndex(0));
+
+        let route = routes.lookup(addr("10.2.3.4")).unwrap();
+        assert_eq!(route.nexthop, IpAddr::ANY);
+        assert_eq!(route.iface.device_index, DeviceIndex(1));
+
+        let route = routes.lookup(addr("10.1.2.3")).unwrap();
+        assert_eq!(route.nexthop, addr("192.0.2.254"));
+
+        let route = routes.lookup(addr("203.0.113.1")).unwrap();
+        assert_eq!(route.nexthop, addr("192.0.2.1"));
+    }
+
+    #[test]
+    fn test_route_lookup_without_default() {
+        let iface = IpIface::new("127.0.0.1", "255.0.0.0", DeviceIndex(0)).unwrap();
+        let routes = RouteTable::new();
+        routes
+            .add(addr("127.0.0.0"), addr("255.0.0.0"), IpAddr::ANY, iface)
+            .unwrap();
+
+        assert!(routes.lookup(addr("127.1.2.3")).is_some());
+        assert!(routes.lookup(addr("192.0.2.1")).is_none());
+    }
+
+    #[test]
+    fn test_route_add_duplicate() {
+        let iface = IpIface::new("192.0.2.2", "255.255.255.0", DeviceIndex(0)).unwrap();
+        let routes = RouteTable::new();
+        routes
+            .set_default_gateway(iface.clone(), addr("192.0.2.1"))
+            .unwrap();
+        assert!(
+            routes
+                .set_default_gateway(iface, addr("192.0.2.254"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_route_add_noncontiguous_netmask() {
+        let iface = IpIface::new("192.0.2.2", "255.255.255.0", DeviceIndex(0)).unwrap();
+        let routes = RouteTable::new();
+        assert!(
+            routes
+                .add(addr("10.0.0.0"), addr("255.0.255.0"), IpAddr::ANY, iface)
+                .is_err()
+        );
+        assert!(routes.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_route_batch_is_atomic() {
+        let iface = IpIface::new("192.0.2.2", "255.255.255.0", DeviceIndex(0)).unwrap();
+        let routes = RouteTable::new();
+        let events = routes.subscribe();
+        routes
+            .add(
+                addr("10.0.0.0"),
+                addr("255.0.0.0"),
+                addr("192.0.2.1"),
+                iface.clone(),
+            )
+            .unwrap();
+        assert!(matches!(events.try_recv(), Ok(RouteChange::Added(_))));
+
+        // The second update conflicts, so the first is not applied either
+        let batch = RouteBatch::new()
+            .add(
+                addr("172.16.0.0"),
+                addr("255.240.0.0"),
+                addr("192.0.2.1"),
+                iface.clone(),
+            )
+            .add(
+                addr("10.0.0.0"),
+                addr("255.0.0.0"),
+                addr("192.0.2.9"),
+                iface.clone(),
+            );
+        assert!(routes.apply(batch).is_err());
+        assert!(routes.lookup(addr("172.16.1.1")).is_none());
+        assert!(events.try_recv().is_err());
+
+        // Replace a route in one step
+        let batch = RouteBatch::new()
+            .remove(addr("10.0.0.0"), addr("255.0.0.0"))
+            .add(
+                addr("10.0.0.0"),
+                addr("255.0.0.0"),
+                addr("192.0.2.9"),
+                iface,
+            );
+        assert_eq!(routes.apply(batch).unwrap().len(), 2);
+        assert_eq!(
+            routes.lookup(addr("10.1.1.1")).unwrap().nexthop,
+            addr("192.0.2.9")
+        );
+        assert!(matches!(events.try_recv(), Ok(RouteChange::Removed(_))));
+        assert!(matches!(events.try_recv(), Ok(RouteChange::Added(_))));
+
+        assert!(
+            routes
+                .remove(addr("172.16.0.0"), addr("255.240.0.0"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_route_snapshot_survives_update() {
+        let iface = IpIface::new("192.0.2.2", "255.255.255.0", DeviceIndex(0)).unwrap();
+        let routes = RouteTable::new();
+        routes
+            .add(
+                addr("10.0.0.0"),
+                addr("255.0.0.0"),
+                addr("192.0.2.1"),
+                iface,
+            )
+            .unwrap();
+        let before = routes.snapshot();
+        assert!(before.same_version(&routes.snapshot()));
+
+        routes.remove(addr("10.0.0.0"), addr("255.0.0.0")).unwrap();
+        assert!(routes.lookup(addr("10.1.1.1")).is_none());
+        // Readers holding the old snapshot keep a consistent view
+        assert_eq!(
+            before.lookup(addr("10.1.1.1")).unwrap().nexthop,
+            addr("192.0.2.1")
+        );
+        assert!(!before.same_version(&routes.snapshot()));
+    }
+
+    #[test]
+    fn test_connections() {
+        use crate::socket::{self, SocketType};
+        use crate::stack::NetStack;
+
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+        let ep = |s: &str| IpEndpoint::from_str(s).unwrap();
+
+        let dgram = socket::socket(SocketType::Dgram, &ctx).unwrap();
+        socket::bind(dgram, ep("127.0.0.1:7"), &ctx).unwrap();
+        let client = socket::socket(SocketType::Dgram, &ctx).unwrap();
+        socket::sendto(client, b"ping", ep("127.0.0.1:7"), &ctx, &devices).unwrap();
+        let listener = socket::socket(SocketType::Stream, &ctx).unwrap();
+        socket::bind(listener, ep("127.0.0.1:80"), &ctx).unwrap();
+        socket::listen(listener, 1, &ctx).unwrap();
+        let stream = socket::socket(SocketType::Stream, &ctx).unwrap();
+        socket::connect(stream, ep("127.0.0.1:80"), &ctx, &devices).unwrap();
+
+        let connections: Vec<_> = ctx.connections().collect();
+        assert_eq!(connections.len(), 5);
+        let echo = &connections[0];
+        assert_eq!(echo.protocol, IpProtocol::Udp);
+        assert_eq!(echo.local, ep("127.0.0.1:7"));
+        assert_eq!((echo.recv_queue, echo.state), (4, None));
+        assert_eq!(
+            echo.to_string().split_whitespace().nth(3),
+            Some("127.0.0.1:echo")
+        );
+        let listening = &connections[2];
+        assert_eq!(listening.state, Some(TcpState::Listen));
+        assert_eq!(listening.foreign, None);
+        assert_eq!(listening.recv_queue, 1);
+        assert!(listening.to_string().ends_with("Listen"));
+
+        let (conn, _) = socket::accept(listener, &ctx).unwrap();
+        socket::send(stream, b"hello", &ctx, &devices).unwrap();
+        let local = socket::local_addr(conn, &ctx).unwrap();
+        let accepted = ctx
+            .connections()
+            .find(|c| c.protocol == IpProtocol::Tcp && c.local == local && c.foreign.is_some())
+            .unwrap();
+        assert_eq!(accepted.state, Some(TcpState::Established));
+        assert_eq!(accepted.recv_queue, 5);
+        assert_eq!(accepted.send_queue, 0);
+    }
+
+    #[test]
+    fn test_ipv6_route_lookup() {
+        use crate::device::ether::EtherAddr;
+
+        let v6 = |s: &str| Ipv6Addr::from_str(s).unwrap();
+        let iface0 = Ipv6Iface::new(v6("2001:db8::2"), 64, DeviceIndex(0));
+        let iface1 = Ipv6Iface::new(v6("2001:db8:1::2"), 64, DeviceIndex(1));
+
+        let mut routes = Ipv6RouteTable::new();
+        routes
+            .add(v6("2001:db8::"), 64, None, iface0.clone())
+            .unwrap();
+        routes
+            .add(v6("2001:db8:1::5"), 64, None, iface1.clone())
+            .unwrap();
+        routes
+            .add(
+                v6("2001:db8:2::"),
+                48,
+                Some(v6("2001:db8::1")),
+                iface0.clone(),
+            )
+            .unwrap();
+        routes
+            .add(
+                v6("2001:db8:2:3::"),
+                64,
+                Some(v6("2001:db8:1::1")),
+                iface1.clone(),
+            )
+            .unwrap();
+        assert!(
+            routes
+                .add(v6("2001:db8:1::"), 64, None, iface1.clone())
+                .is_err()
+        );
+        assert!(routes.add(v6("::"), 129, None, iface1.clone()).is_err());
+
+        let route = routes.lookup(v6("2001:db8::42")).unwrap();
+        assert!(route.is_on_link());
+        assert_eq!(route.iface.device_index, DeviceIndex(0));
+        let route = routes.lookup(v6("2001:db8:1:0:ffff::1")).unwrap();
+        assert_eq!(route.network, v6("2001:db8:1::"));
+        let route = routes.lookup(v6("2001:db8:2:4::1")).unwrap();
+        assert_eq!(route.nexthop, Some(v6("2001:db8::1")));
+        let route = routes.lookup(v6("2001:db8:2:3::1")).unwrap();
+        assert_eq!(route.nexthop, Some(v6("2001:db8:1::1")));
+        assert!(routes.lookup(v6("2001:db9::1")).is_none());
+
+        routes.remove(v6("2001:db8:2:3::"), 64).unwrap();
+        assert_eq!(
+            routes.lookup(v6("2001:db8:2:3::1")).unwrap().nexthop,
+            Some(v6("2001:db8::1"))
+        );
+        assert!(routes.remove(v6("2001:db8:2:3::"), 64).is_err());
+
+        // Without a known link-layer address, the first router is used; a
+        // router whose neighbor is known is preferred
+        let neighbors = NeighborCache::new();
+        assert!(routes.select_default_router(&neighbors).is_none());
+        routes
+            .add_default_router(v6("fe80::1"), iface0.clone(), None)
+            .unwrap();
+        routes
+            .add_default_router(v6("fe80::2"), iface1.clone(), None)
+            .unwrap();
+        routes
+            .add_default_router(v6("fe80::3"), iface0, Some(Duration::ZERO))
+            .unwrap();
+        assert_eq!(routes.default_routers().len(), 2);
+        let router = routes.select_default_router(&neighbors).unwrap();
+        assert_eq!(router.addr, v6("fe80::1"));
+        neighbors.insert(v6("fe80::2"), EtherAddr::from_seed("r2"), DeviceIndex(1));
+        let router = routes.select_default_router(&neighbors).unwrap();
+        assert_eq!(router.addr, v6("fe80::2"));
+        assert_eq!(router.iface.device_index, DeviceIndex(1));
+        assert!(routes.remove_default_router(v6("fe80::2")));
+        assert!(!routes.remove_default_router(v6("fe80::2")));
+        assert_eq!(
+            routes.select_default_router(&neighbors).unwrap().addr,
+            v6("fe80::1")
+        );
+    }
 }
@@ -0,0 +1,708 @@
+//! Runtime control channel.
+//!
+//! A [`ControlServer`] listens on a Unix domain socket and reconfigures a
+//! running stack on request, a netlink of sorts for this user-space stack.
+//! Clients write one command per line; every command is answered with zero or
+//! more rows of tab-separated fields, the first naming the columns, followed
+//! by `ok` or by `error <message>`:
+//!
+//! ```text
+//! link [show]                      name, type, state, mtu, hardware address
+//! link set <dev> up|down
+//! addr [show]                      IPv4 and IPv6 addresses
+//! addr add <dev> <cidr>
+//! route [show]                     IPv4 and IPv6 routes
+//! route add <cidr>|default via <gateway>
+//! route del <cidr>|default
+//! neigh [show]                     IPv6 neighbor cache
+//! conn [show]                      UDP and TCP control blocks
+//! stat                             the counters of [`metrics`](crate::metrics)
+//! top [<n>]                        the `n` peers with the most traffic (10 by default)
+//! pktlog [<filter>|off]            show or set the [packet log](crate::pktlog) filter
+//! dump                             every `show` above, one table after another
+//! ```
+//!
+//! `default` is `0.0.0.0/0`; give `::/0` for an IPv6 default route.
+//!
+//! ```no_run
+//! # use microps::control;
+//! # use microps::stack::NetStack;
+//! let stack = NetStack::new()?;
+//! let _server = stack.serve_control("/tmp/microps.sock")?;
+//! // or: echo "route show" | socat - UNIX-CONNECT:/tmp/microps.sock
+//! for row in control::request("/tmp/microps.sock", "route show")? {
+//!     println!("{}", row);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Connections are served one at a time, on a thread of the server's own.
+
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::context::ProtocolContexts;
+use crate::device::{Device, DeviceManager};
+use crate::event::NetEvent;
+use crate::lock::StackLock;
+use crate::metrics;
+use crate::pktlog;
+use crate::protocol::ProtocolManager;
+use crate::protocol::ip::{self, IpAddr};
+use crate::protocol::ipv6::{self, Ipv6Addr};
+
+/// How often the server checks whether it is to stop while waiting
+const CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// A client that sends nothing for this long is disconnected
+const CONTROL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Longest command accepted
+const CONTROL_LINE_MAX: usize = 4096;
+
+/// Peers `top` lists unless told otherwise
+const TOP_PEERS: usize = 10;
+
+/// Last line of a successful response
+const RESPONSE_OK: &str = "ok";
+/// Prefix of the last line of a failed response
+const RESPONSE_ERROR: &str = "error ";
+
+/// The parts of a stack commands act on
+pub(crate) struct Target<'a> {
+    pub devices: &'a StackLock<DeviceManager>,
+    pub protocols: &'a StackLock<ProtocolManager>,
+    pub ctx: &'a StackLock<ProtocolContexts>,
+}
+
+impl Target<'_> {
+    fn raise_event(&self, event: NetEvent) {
+        let protocols = self.protocols.read();
+        protocols.events().raise(event, &self.ctx.read());
+    }
+}
+
+/// Server of the control socket
+pub struct ControlServer {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ControlServer {
+    /// Answer the commands sent to the socket at `path` with what `execute`
+    /// returns, until dropped. A socket left behind at `path` by a stack that
+    /// did not shut down is replaced.
+    pub fn start(
+        path: impl AsRef<Path>,
+        execute: impl Fn(&str) -> Result<Vec<String>> + Send + 'static,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(metadata) = fs::symlink_metadata(&path)
+            && metadata.file_type().is_socket()
+            && UnixStream::connect(&path).is_err()
+        {
+            fs::remove_file(&path).context("Failed to remove stale control socket")?;
+        }
+        let listener = UnixListener::bind(&path).context("Failed to bind control socket")?;
+        listener.set_nonblocking(true)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::Builder::new()
+            .name("microps-control".to_string())
+            .spawn(move || {
+                while !stopped.load(Ordering::Acquire) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            if let Err(e) = serve(stream, &execute, &stopped) {
+                                tracing::debug!("control: {}", e);
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            thread::sleep(CONTROL_POLL_INTERVAL);
+                        }
+                        Err(e) => tracing::error!("control: accept failed: {}", e),
+                    }
+                }
+            })
+            .context("Failed to start control thread")?;
+        tracing::info!("Control socket listening at {}", path.display());
+        Ok(Self {
+            path,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Answer the commands of one client until it disconnects, idles out or the
+/// server stops
+fn serve(
+    stream: UnixStream,
+    execute: &impl Fn(&str) -> Result<Vec<String>>,
+    stop: &AtomicBool,
+) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CONTROL_POLL_INTERVAL))?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    let mut idle_since = Instant::now();
+    while !stop.load(Ordering::Acquire) {
+        // A partial line stays in `line` across timeouts, so the bound is
+        // what is left of it
+        let left = CONTROL_LINE_MAX + 1 - line.len();
+        let read = reader
+            .by_ref()
+            .take(left as u64)
+            .read_until(b'\n', &mut line);
+        if line.len() > CONTROL_LINE_MAX {
+            anyhow::bail!("command too long");
+        }
+        // A last command without a newline is still answered
+        let closed = match read {
+            Ok(0) if line.is_empty() => return Ok(()),
+            Ok(_) => !line.ends_with(b"\n"),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if idle_since.elapsed() >= CONTROL_IDLE_TIMEOUT {
+                    anyhow::bail!("client idle");
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let command = String::from_utf8_lossy(&line).trim().to_string();
+        line.clear();
+        idle_since = Instant::now();
+        if command.is_empty() {
+            if closed {
+                return Ok(());
+            }
+            continue;
+        }
+        tracing::debug!("control: {}", command);
+        let mut response = String::new();
+        match execute(&command) {
+            Ok(rows) => {
+                for row in rows {
+                    response.push_str(&row);
+                    response.push('\n');
+                }
+                response.push_str(RESPONSE_OK);
+            }
+            Err(e) => {
+                response.push_str(RESPONSE_ERROR);
+                response.push_str(&format!("{:#}", e).replace('\n', " "));
+            }
+        }
+        response.push('\n');
+        writer.write_all(response.as_bytes())?;
+        if closed {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Send `command` to the control socket at `path` and return the rows of the
+/// response; a response ending in `error` is returned as an error
+pub fn request(path: impl AsRef<Path>, command: &str) -> Result<Vec<String>> {
+    let mut stream = UnixStream::connect(path.as_ref()).with_context(|| {
+        format!(
+            "Failed to connect to control socket {}",
+            path.as_ref().display()
+        )
+    })?;
+    writeln!(stream, "{}", command.trim())?;
+    let mut rows = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line == RESPONSE_OK {
+            return Ok(rows);
+        }
+        if let Some(message) = line.strip_prefix(RESPONSE_ERROR) {
+            anyhow::bail!("{}", message);
+        }
+        rows.push(line);
+    }
+    anyhow::bail!("control socket closed before the response ended")
+}
+
+/// Lay out the tab-separated `rows` of a response as a table, each column as
+/// wide as its widest field
+pub fn table(rows: &[String]) -> String {
+    let rows: Vec<Vec<&str>> = rows.iter().map(|row| row.split('\t').collect()).collect();
+    let mut widths = Vec::new();
+    for row in &rows {
+        for (column, field) in row.iter().enumerate() {
+            if column == widths.len() {
+                widths.push(0);
+            }
+            widths[column] = widths[column].max(field.chars().count());
+        }
+    }
+    let mut out = String::new();
+    for row in &rows {
+        let mut line = String::new();
+        for (column, field) in row.iter().enumerate() {
+            if column > 0 {
+                line.push_str("  ");
+            }
+            line.push_str(&format!("{:<1$}", field, widths[column]));
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+/// Run one command against the stack
+pub(crate) fn execute(command: &str, stack: &Target) -> Result<Vec<String>> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    match words.as_slice() {
+        ["link"] | ["link", "show"] => Ok(link_show(&stack.devices.read())),
+        ["link", "set", dev, state @ ("up" | "down")] => {
+            link_set(stack, dev, *state == "up").map(|_| Vec::new())
+        }
+        ["addr"] | ["addr", "show"] => Ok(addr_show(&stack.devices.read())),
+        ["addr", "add", dev, cidr] => addr_add(stack, dev, cidr).map(|_| Vec::new()),
+        ["route"] | ["route", "show"] => Ok(route_show(&stack.devices.read(), &stack.ctx.read())),
+        ["route", "add", prefix, "via", gateway] => {
+            route_add(stack, prefix, gateway).map(|_| Vec::new())
+        }
+        ["route", "del", prefix] => route_del(stack, prefix).map(|_| Vec::new()),
+        ["neigh"] | ["neigh", "show"] => Ok(neigh_show(&stack.devices.read(), &stack.ctx.read())),
+        ["conn"] | ["conn", "show"] => Ok(conn_show(&stack.ctx.read())),
+        ["stat"] => Ok(stat(&stack.devices.read(), &stack.ctx.read())),
+        ["top"] => Ok(top(&stack.ctx.read(), TOP_PEERS)),
+        ["top", n] => {
+            let n = n
+                .parse()
+                .with_context(|| format!("invalid peer count: {}", n))?;
+            Ok(top(&stack.ctx.read(), n))
+        }
+        ["pktlog"] => Ok(vec![
+            "FILTER".to_string(),
+            pktlog::filter().map_or("off".to_string(), |filter| filter.to_string()),
+        ]),
+        ["pktlog", "off"] => {
+            pktlog::set_filter(None);
+            Ok(Vec::new())
+        }
+        ["pktlog", filter @ ..] => {
+            pktlog::set_filter(Some(filter.join(" ").parse()?));
+            Ok(Vec::new())
+        }
+        ["dump"] => {
+            let devices = stack.devices.read();
+            let ctx = stack.ctx.read();
+            let mut rows = link_show(&devices);
+            rows.extend(addr_show(&devices));
+            rows.extend(route_show(&devices, &ctx));
+            rows.extend(neigh_show(&devices, &ctx));
+            rows.extend(conn_show(&ctx));
+            Ok(rows)
+        }
+        [] => anyhow::bail!("empty command"),
+        _ => anyhow::bail!("unknown command: {}", command),
+    }
+}
+
+fn row<const N: usize>(fields: [&str; N]) -> String {
+    fields.join("\t")
+}
+
+fn find_device<'a>(devices: &'a mut DeviceManager, name: &str) -> Result<&'a mut Device> {
+    devices
+        .iter_mut()
+        .find(|dev| dev.name_string() == name)
+        .ok_or_else(|| anyhow::anyhow!("no such device: {}", name))
+}
+
+fn device_name(devices: &DeviceManager, index: crate::device::DeviceIndex) -> String {
+    devices
+        .get(index)
+        .map_or_else(|| index.to_string(), Device::name_string)
+}
+
+fn link_show(devices: &DeviceManager) -> Vec<String> {
+    let mut rows = vec![row(["NAME", "TYPE", "STATE", "MTU", "HWADDR"])];
+    for dev in devices.iter() {
+        rows.push(row([
+            &dev.name_string(),
+            &format!("{:?}", dev.device_type).to_lowercase(),
+            dev.state(),
+            &dev.mtu.to_string(),
+            &dev.hw_addr_string(),
+        ]));
+    }
+    rows
+}
+
+fn link_set(stack: &Target, name: &str, up: bool) -> Result<()> {
+    let mut devices = stack.devices.write();
+    let dev = find_device(&mut devices, name)?;
+    let index = dev.index;
+    if up {
+        dev.open()?;
+    } else {
+        dev.close()?;
+    }
+    drop(devices);
+    stack.raise_event(if up {
+        NetEvent::InterfaceUp(index)
+    } else {
+        NetEvent::InterfaceDown(index)
+    });
+    Ok(())
+}
+
+fn addr_show(devices: &DeviceManager) -> Vec<String> {
+    let mut rows = vec![row(["DEVICE", "FAMILY", "ADDRESS"])];
+    for dev in devices.iter() {
+        let name = dev.name_string();
+        for iface in dev.ip_ifaces() {
+            let cidr = format!("{}/{}", iface.unicast, iface.netmask.prefix_len());
+            rows.push(row([&name, "inet", &cidr]));
+        }
+        for iface in dev.ipv6_ifaces() {
+            let cidr = format!("{}/{}", iface.unicast, iface.prefix_len);
+            rows.push(row([&name, "inet6", &cidr]));
+        }
+    }
+    rows
+}
+
+fn addr_add(stack: &Target, name: &str, cidr: &str) -> Result<()> {
+    let mut devices = stack.devices.write();
+    let dev = find_device(&mut devices, name)?;
+    let index = dev.index;
+    if cidr.contains(':') {
+        ipv6::register_iface(dev, cidr, &mut stack.ctx.write())?;
+    } else {
+        let (unicast, netmask) = ip::parse_cidr(cidr)?;
+        ip::register_iface(
+            dev,
+            &unicast.to_string(),
+            &netmask.to_string(),
+            &mut stack.ctx.write(),
+        )?;
+    }
+    drop(devices);
+    stack.raise_event(NetEvent::AddressChanged(index));
+    Ok(())
+}
+
+fn route_show(devices: &DeviceManager, ctx: &ProtocolContexts) -> Vec<String> {
+    let mut rows = vec![row(["DESTINATION", "GATEWAY", "DEVICE", "SOURCE"])];
+    for route in ctx.ip_routes.snapshot().iter() {
+        let gateway = if route.nexthop == IpAddr::ANY {
+            "-".to_string()
+        } else {
+            route.nexthop.to_string()
+        };
+        rows.push(row([
+            &format!("{}/{}", route.network, route.netmask.prefix_len()),
+            &gateway,
+            &device_name(devices, route.iface.device_index),
+            &route.iface.unicast.to_string(),
+        ]));
+    }
+    for route in ctx.ipv6_routes.iter() {
+        rows.push(row([
+            &format!("{}/{}", route.network, route.prefix_len),
+            &route
+                .nexthop
+                .map_or("-".to_string(), |nexthop| nexthop.to_string()),
+            &device_name(devices, route.iface.device_index),
+            &route.iface.unicast.to_string(),
+        ]));
+    }
+    for router in ctx.ipv6_routes.default_routers() {
+        rows.push(row([
+            "::/0",
+            &router.addr.to_string(),
+            &device_name(devices, router.iface.device_index),
+            &router.iface.unicast.to_string(),
+        ]));
+    }
+    rows
+}
+
+fn parse_prefix(prefix: &str) -> &str {
+    if prefix == "default" {
+        "0.0.0.0/0"
+    } else {
+        prefix
+    }
+}
+
+/// IPv4 routes are updated with the stack readable throughout; IPv6 routes
+/// still need it exclusively
+fn route_add(stack: &Target, prefix: &str, gateway: &str) -> Result<()> {
+    let prefix = parse_prefix(prefix);
+    if prefix.contains(':') {
+        let (network, prefix_len) = ipv6::parse_cidr(prefix)?;
+        let gateway: Ipv6Addr = gateway.parse()?;
+        ipv6::route_add_via(network, prefix_len, gateway, &mut stack.ctx.write())?;
+    } else {
+        let (network, netmask) = ip::parse_cidr(prefix)?;
+        let gateway: IpAddr = gateway.parse()?;
+        ip::route_add_via(network & netmask, netmask, gateway, &stack.ctx.read())?;
+    }
+    Ok(())
+}
+
+fn route_del(stack: &Target, prefix: &str) -> Result<()> {
+    let prefix = parse_prefix(prefix);
+    if prefix.contains(':') {
+        let (network, prefix_len) = ipv6::parse_cidr(prefix)?;
+        stack.ctx.write().ipv6_routes.remove(network, prefix_len)?;
+    } else {
+        let (network, netmask) = ip::parse_cidr(prefix)?;
+        stack
+            .ctx
+            .read()
+            .ip_routes
+            .remove(network & netmask, netmask)?;
+    }
+    Ok(())
+}
+
+fn neigh_show(devices: &DeviceManager, ctx: &ProtocolContexts) -> Vec<String> {
+    let mut rows = vec![row(["ADDRESS", "HWADDR", "STATE", "ROUTER", "DEVICE"])];
+    for neighbor in ctx.neighbors.entries() {
+        rows.push(row([
+            &neighbor.addr.to_string(),
+            &neighbor
+                .hwaddr
+                .map_or("-".to_string(), |hwaddr| hwaddr.to_string()),
+            &format!("{:?}", neighbor.state).to_uppercase(),
+            if neighbor.router { "yes" } else { "no" },
+            &device_name(devices, neighbor.device),
+        ]));
+    }
+    rows
+}
+
+fn conn_show(ctx: &ProtocolContexts) -> Vec<String> {
+    let mut rows = vec![row([
+        "PROTO", "RECV-Q", "SEND-Q", "LOCAL", "FOREIGN", "STATE",
+    ])];
+    for conn in ctx.connections() {
+        rows.push(row([
+            &format!("{:?}", conn.protocol).to_lowercase(),
+            &conn.recv_queue.to_string(),
+            &conn.send_queue.to_string(),
+            &conn.local.to_string(),
+            &conn
+                .foreign
+                .map_or("*:*".to_string(), |foreign| foreign.to_string()),
+            &conn
+                .state
+                .map_or("-".to_string(), |state| format!("{:?}", state)),
+        ]));
+    }
+    rows
+}
+
+fn stat(devices: &DeviceManager, ctx: &ProtocolContexts) -> Vec<String> {
+    let mut rows = vec![row(["COUNTER", "VALUE"])];
+    for line in metrics::gather(devices, ctx).render().lines() {
+        if let Some((counter, value)) = line.rsplit_once(' ')
+            && !line.starts_with('#')
+        {
+            rows.push(row([counter, value]));
+        }
+    }
+    rows
+}
+
+fn top(ctx: &ProtocolContexts, n: usize) -> Vec<String> {
+    let mut rows = vec![row([
+        "PEER",
+        "PKTS_IN",
+        "BYTES_IN",
+        "PKTS_OUT",
+        "BYTES_OUT",
+        "LAST_SEEN",
+    ])];
+    let now = ctx.clock.now();
+    for (addr, stats) in ctx.peer_stats.top(n) {
+        let idle = now.duration_since(stats.last_seen);
+        rows.push(row([
+            &addr.to_string(),
+            &stats.packets_in.to_string(),
+            &stats.bytes_in.to_string(),
+            &stats.packets_out.to_string(),
+            &stats.bytes_out.to_string(),
+            &format!("{:.1}s", idle.as_secs_f64()),
+        ]));
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceIndex;
+    use crate::stack::NetStack;
+
+    #[test]
+    fn test_table() {
+        let rows = ["NAME\tSTATE\tMTU", "net0\tUP\t65535", "veth10\tDOWN\t-"].map(String::from);
+        assert_eq!(
+            table(&rows),
+            "NAME    STATE  MTU\nnet0    UP     65535\nveth10  DOWN   -\n"
+        );
+        assert_eq!(table(&[]), "");
+    }
+
+    #[test]
+    fn test_control_reconfigures_running_stack() {
+        let stack = NetStack::builder()
+            .device(crate::builder::loopback())
+            .device(crate::builder::memory(
+                crate::device::ether::EtherAddr::from_seed("control"),
+            ))
+            .build()
+            .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("microps-control-{}.sock", std::process::id()));
+        let server = stack.serve_control(&path).unwrap();
+        let events = stack.listen_events();
+
+        let link = request(&path, "link").unwrap();
+        assert_eq!(link[0], "NAME\tTYPE\tSTATE\tMTU\tHWADDR");
+        assert_eq!(link.len(), 3);
+        let name = stack.devices().get(DeviceIndex(1)).unwrap().name_string();
+        assert!(link[2].starts_with(&format!("{}\tmemory\tUP\t", name)));
+
+        request(&path, &format!("addr add {} 192.0.2.1/24", name)).unwrap();
+        request(&path, &format!("addr add {} 2001:db8::1/64", name)).unwrap();
+        assert_eq!(
+            events.try_recv(),
+            Ok(NetEvent::AddressChanged(DeviceIndex(1)))
+        );
+        let addrs = request(&path, "addr show").unwrap();
+        assert!(addrs.contains(&format!("{}\tinet\t192.0.2.1/24", name)));
+        assert!(addrs.contains(&format!("{}\tinet6\t2001:db8::1/64", name)));
+
+        request(&path, "route add 198.51.100.0/24 via 192.0.2.254").unwrap();
+        request(&path, "route add default via 192.0.2.254").unwrap();
+        let routes = request(&path, "route").unwrap();
+        assert!(routes.contains(&format!(
+            "198.51.100.0/24\t192.0.2.254\t{}\t192.0.2.1",
+            name
+        )));
+        assert!(routes.iter().any(|route| route.starts_with("0.0.0.0/0\t")));
+        request(&path, "route del 198.51.100.7/24").unwrap();
+        let (network, netmask) = ip::parse_cidr("198.51.100.0/24").unwrap();
+        assert!(stack.ctx().ip_routes.find(network, netmask).is_none());
+        assert!(
+            stack
+                .ctx()
+                .ip_routes
+                .snapshot()
+                .iter()
+                .all(|route| route.netmask.prefix_len() != 24
+                    || route.network.to_string() != "198.51.100.0")
+        );
+
+        let err = request(&path, "route add 203.0.113.0/24 via 10.9.9.9").unwrap_err();
+        assert!(
+            err.to_string().contains("no route to host, dst=10.9.9.9"),
+            "{}",
+            err
+        );
+        assert!(request(&path, "frobnicate").is_err());
+
+        request(&path, &format!("link set {} down", name)).unwrap();
+        assert!(!stack.devices().get(DeviceIndex(1)).unwrap().is_up());
+        assert!(request(&path, &format!("link set {} down", name)).is_err());
+        request(&path, &format!("link set {} up", name)).unwrap();
+        assert!(stack.devices().get(DeviceIndex(1)).unwrap().is_up());
+
+        assert!(
+            request(&path, "stat")
+                .unwrap()
+                .iter()
+                .any(|row| row.starts_with(&format!(
+                    "microps_device_transmit_packets_total{{device=\"{}\"}}\t",
+                    name
+                )))
+        );
+        assert!(request(&path, "dump").unwrap().len() > link.len());
+
+        request(&path, "pktlog proto=udp port=53").unwrap();
+        assert_eq!(
+            request(&path, "pktlog").unwrap(),
+            ["FILTER", "proto=udp port=53 level=debug"]
+        );
+        assert!(request(&path, "pktlog port=dns").is_err());
+        crate::pktlog::set_filter(Some(crate::pktlog::PacketFilter::ALL));
+
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_top_talkers() {
+        let stack = NetStack::new().unwrap();
+        let path = std::env::temp_dir().join(format!("microps-top-{}.sock", std::process::id()));
+        let _server = stack.serve_control(&path).unwrap();
+        let peers = &stack.ctx().peer_stats;
+        peers.record_in("192.0.2.1".parse().unwrap(), 100);
+        peers.record_out("192.0.2.2".parse().unwrap(), 1500);
+        peers.record_in("192.0.2.2".parse().unwrap(), 40);
+        peers.record_out("192.0.2.3".parse().unwrap(), 10);
+
+        let rows = request(&path, "top").unwrap();
+        assert_eq!(
+            rows[0],
+            "PEER\tPKTS_IN\tBYTES_IN\tPKTS_OUT\tBYTES_OUT\tLAST_SEEN"
+        );
+        assert_eq!(rows.len(), 4);
+        assert!(rows[1].starts_with("192.0.2.2\t1\t40\t1\t1500\t"));
+        assert!(rows[2].starts_with("192.0.2.1\t1\t100\t0\t0\t"));
+
+        assert_eq!(request(&path, "top 1").unwrap().len(), 2);
+        assert!(request(&path, "top many").is_err());
+    }
+
+    #[test]
+    fn test_control_line_is_bounded() {
+        let path = std::env::temp_dir().join(format!("microps-line-{}.sock", std::process::id()));
+        let _server = ControlServer::start(&path, |_| Ok(vec!["pong".to_string()])).unwrap();
+
+        // A line that never ends is cut off, and the client with it
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(&[b'a'; CONTROL_LINE_MAX * 2]).unwrap();
+        let mut response = Vec::new();
+        let _ = client.read_to_end(&mut response);
+        assert!(response.is_empty());
+
+        assert_eq!(request(&path, "x").unwrap(), ["pong"]);
+    }
+}
@@ -0,0 +1,104 @@
+//! Ethernet (IEEE 802) hardware addresses.
+//!
+//! Devices without a configured address get a locally-administered one derived
+//! from a seed, so the same topology produces the same addresses on every run.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::error::{NetError, Result};
+
+pub const ETHER_ADDR_LEN: usize = 6;
+
+/// Bit set in the first octet of group (multicast/broadcast) addresses
+const ETHER_ADDR_GROUP: u8 = 0x01;
+/// Bit set in the first octet of locally-administered addresses
+const ETHER_ADDR_LOCAL: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct EtherAddr(pub [u8; ETHER_ADDR_LEN]);
+
+impl EtherAddr {
+    pub const ANY: EtherAddr = EtherAddr([0x00; ETHER_ADDR_LEN]);
+    pub const BROADCAST: EtherAddr = EtherAddr([0xff; ETHER_ADDR_LEN]);
+
+    /// A unicast, locally-administered address derived from `seed`
+    pub fn from_seed(seed: &str) -> Self {
+        // FNV-1a: stable across runs and platforms, unlike `DefaultHasher`
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in seed.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+
+        let mut bytes = [0u8; ETHER_ADDR_LEN];
+        bytes.copy_from_slice(&hash.to_be_bytes()[..ETHER_ADDR_LEN]);
+        bytes[0] = (bytes[0] | ETHER_ADDR_LOCAL) & !ETHER_ADDR_GROUP;
+        EtherAddr(bytes)
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & ETHER_ADDR_GROUP != 0
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.0[0] & ETHER_ADDR_LOCAL != 0
+    }
+}
+
+impl FromStr for EtherAddr {
+    type Err = NetError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split([':', '-']).collect();
+        if parts.len() != ETHER_ADDR_LEN {
+            return Err(NetError::InvalidInput("invalid MAC address"));
+        }
+
+        let mut bytes = [0u8; ETHER_ADDR_LEN];
+        for (i, part) in parts.iter().enumerate() {
+            if part.len() != 2 {
+                return Err(NetError::InvalidInput("invalid octet in MAC address"));
+            }
+            bytes[i] = u8::from_str_radix(part, 16)
+                .map_err(|_| NetError::InvalidInput("invalid octet in MAC address"))?;
+        }
+
+        Ok(EtherAddr(bytes))
+    }
+}
+
+impl Display for EtherAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_seed_is_stable_local_unicast() {
+        let addr = EtherAddr::from_seed("h1/192.0.2.1/24");
+        assert_eq!(addr, EtherAddr::from_seed("h1/192.0.2.1/24"));
+        assert_ne!(addr, EtherAddr::from_seed("h2/192.0.2.2/24"));
+        assert!(addr.is_local());
+        assert!(!addr.is_multicast());
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        let addr = EtherAddr::from_str("02:00:5E-10:aB:ff").unwrap();
+        assert_eq!(addr.0, [0x02, 0x00, 0x5e, 0x10, 0xab, 0xff]);
+        assert_eq!(addr.to_string(), "02:00:5e:10:ab:ff");
+        assert!(EtherAddr::from_str("02:00:5e:10:ab").is_err());
+        assert!(EtherAddr::from_str("02:00:5e:10:ab:fff").is_err());
+        assert!(EtherAddr::BROADCAST.is_multicast());
+    }
+}
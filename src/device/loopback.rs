@@ -1,57 +1,121 @@
-use anyhow::Result;
-use std::rc::Rc;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 
-use super::{Device, DeviceIndex, DeviceManager, DeviceOps, DeviceType, NET_DEVICE_FLAG_LOOPBACK};
-use crate::util::debugdump;
+use crate::error::Result;
+use tracing::Level;
+
+use super::{
+    BatchCallback, Device, DeviceIndex, DeviceManager, DeviceOps, DeviceType,
+    NET_DEVICE_FLAG_LOOPBACK, RX_BATCH_MAX,
+};
+use crate::error::NetError;
+use crate::irq::{IRQ_BASE, IRQ_SHARED, IrqController};
+use crate::util::LOG_DRIVER;
 
 const LOOPBACK_MTU: u16 = u16::MAX;
+pub const LOOPBACK_IRQ: u32 = IRQ_BASE;
 
-// Will be replaced with IRQ-based signaling in the future
-pub type OutputCallback = Rc<dyn Fn(u16, &[u8], &Device)>;
+/// Frames transmitted and not yet received, with their type
+type LoopbackQueue = Arc<Mutex<VecDeque<(u16, Vec<u8>)>>>;
 
 struct LoopbackOps {
-    output_callback: OutputCallback,
+    queue: LoopbackQueue,
+    irqs: Arc<IrqController>,
 }
 
 impl DeviceOps for LoopbackOps {
-    fn open(&self, _dev: &Device) -> Result<()> {
+    fn open(&mut self, _dev: &Device) -> Result<()> {
         Ok(())
     }
 
-    fn close(&self, _dev: &Device) -> Result<()> {
+    fn close(&mut self, _dev: &Device) -> Result<()> {
+        self.queue.lock().unwrap().clear();
         Ok(())
     }
 
-    fn transmit(&self, dev: &Device, type_: u16, data: &[u8], dst: Option<&[u8]>) -> Result<()> {
-        tracing::debug!(
-            "loopback_transmit: type=0x{:04x}, len={}, dst={:?}",
-            type_,
-            data.len(),
-            dst
-        );
-        debugdump(data);
-
-        // HACK: Will be replaced with IRQ-based signaling in the future
-        (self.output_callback)(type_, data, dev);
+    fn transmit(
+        &mut self,
+        dev: &Device,
+        type_: u16,
+        data: &[u8],
+        dst: Option<&[u8]>,
+    ) -> Result<()> {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.len() >= dev.tx_queue_len {
+                return Err(NetError::LimitReached {
+                    what: "frames queued on loopback",
+                    limit: dev.tx_queue_len,
+                });
+            }
+            queue.push_back((type_, data.to_vec()));
+        }
+        if LOG_DRIVER.allow(Level::DEBUG) {
+            tracing::debug!(
+                "loopback_transmit: type=0x{:04x}, len={}, dst={:?}",
+                type_,
+                data.len(),
+                dst
+            );
+        }
 
+        self.irqs.raise_from(LOOPBACK_IRQ, dev);
         Ok(())
     }
 }
 
-pub fn init(devices: &mut DeviceManager, output_callback: OutputCallback) -> Result<DeviceIndex> {
+/// Pass every queued frame to the receiving side, up to [`RX_BATCH_MAX`] at a time
+fn isr(queue: &LoopbackQueue, input: &BatchCallback, dev: &Device) {
+    loop {
+        // Not locked while the frames are handled, which may transmit more
+        let batch: Vec<(u16, Vec<u8>)> = {
+            let mut queue = queue.lock().unwrap();
+            let len = queue.len().min(RX_BATCH_MAX);
+            queue.drain(..len).collect()
+        };
+        if batch.is_empty() {
+            break;
+        }
+        if LOG_DRIVER.allow(Level::DEBUG) {
+            tracing::debug!(
+                "loopback_isr: dev={}, frames={}",
+                dev.name_string(),
+                batch.len()
+            );
+        }
+        let frames: Vec<(u16, &[u8])> = batch
+            .iter()
+            .map(|(type_, data)| (*type_, data.as_slice()))
+            .collect();
+        input(&frames, dev);
+    }
+}
+
+/// Register a loopback device whose frames are handed to `input_callback` in
+/// batches from its IRQ handler
+pub fn init(devices: &mut DeviceManager, input_callback: BatchCallback) -> Result<DeviceIndex> {
     let dev = Device {
         device_type: DeviceType::Loopback,
         mtu: LOOPBACK_MTU,
         flags: NET_DEVICE_FLAG_LOOPBACK,
-        // Set after registration to avoid circular dependency
-        ops: None,
+        // Driver set after registration to avoid circular dependency
         ..Default::default()
     };
 
     let index = devices.register(dev)?;
+    let queue = LoopbackQueue::default();
+    let irqs = Arc::clone(devices.irqs());
+    let handler_queue = Arc::clone(&queue);
+    irqs.request_irq(
+        LOOPBACK_IRQ,
+        Arc::new(move |_, dev| isr(&handler_queue, &input_callback, dev)),
+        IRQ_SHARED,
+        "loopback",
+        index,
+    )?;
 
     if let Some(dev) = devices.get_mut(index) {
-        dev.ops = Some(Box::new(LoopbackOps { output_callback }));
+        dev.set_driver(Box::new(LoopbackOps { queue, irqs }));
         tracing::info!("Loopback device initialized: {}", dev.name_string());
     }
 
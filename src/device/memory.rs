@@ -0,0 +1,103 @@
+//! In-memory device whose wire is the embedder: transmitted frames wait in a
+//! queue until the embedder collects them, and frames for the device are fed
+//! in with [`NetStack::inject`](crate::stack::NetStack::inject). The browser
+//! demo uses it to show every frame before passing it on.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::error::Result;
+use tracing::Level;
+
+use super::ether::EtherAddr;
+use super::{Device, DeviceIndex, DeviceManager, DeviceOps, DeviceType, NET_DEVICE_FLAG_P2P};
+use crate::error::NetError;
+use crate::util::LOG_DRIVER;
+
+const MEMORY_MTU: u16 = 1500;
+
+/// A frame transmitted by a memory device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryFrame {
+    pub type_: u16,
+    pub data: Vec<u8>,
+}
+
+/// Transmitted frames of one memory device, oldest first
+pub type MemoryQueue = Arc<Mutex<VecDeque<MemoryFrame>>>;
+
+struct MemoryOps {
+    tx: MemoryQueue,
+}
+
+impl DeviceOps for MemoryOps {
+    fn open(&mut self, _dev: &Device) -> Result<()> {
+        Ok(())
+    }
+
+    fn close(&mut self, _dev: &Device) -> Result<()> {
+        self.tx.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn transmit(
+        &mut self,
+        dev: &Device,
+        type_: u16,
+        data: &[u8],
+        _dst: Option<&[u8]>,
+    ) -> Result<()> {
+        if LOG_DRIVER.allow(Level::DEBUG) {
+            tracing::debug!(
+                "memory_transmit: dev={}, type=0x{:04x}, len={}",
+                dev.name_string(),
+                type_,
+                data.len()
+            );
+        }
+
+        let mut tx = self.tx.lock().unwrap();
+        // Frames not yet collected by the embedder count as the device's queue
+        if tx.len() >= dev.tx_queue_len {
+            return Err(NetError::LimitReached {
+                what: "frames queued for transmit",
+                limit: dev.tx_queue_len,
+            });
+        }
+        tx.push_back(MemoryFrame {
+            type_,
+            data: data.to_vec(),
+        });
+        Ok(())
+    }
+}
+
+/// Register a memory device with hardware address `addr`; its transmitted
+/// frames are queued on the returned queue
+pub fn init(devices: &mut DeviceManager, addr: EtherAddr) -> Result<(DeviceIndex, MemoryQueue)> {
+    let tx = MemoryQueue::default();
+    let index = init_with_queue(devices, addr, Arc::clone(&tx))?;
+    Ok((index, tx))
+}
+
+/// Register a memory device whose transmitted frames are queued on `tx`
+pub fn init_with_queue(
+    devices: &mut DeviceManager,
+    addr: EtherAddr,
+    tx: MemoryQueue,
+) -> Result<DeviceIndex> {
+    let mut dev = Device {
+        device_type: DeviceType::Memory,
+        mtu: MEMORY_MTU,
+        flags: NET_DEVICE_FLAG_P2P,
+        ..Default::default()
+    };
+    dev.set_hw_addr(addr);
+
+    let index = devices.register(dev)?;
+    if let Some(dev) = devices.get_mut(index) {
+        dev.set_driver(Box::new(MemoryOps { tx }));
+        tracing::info!("Memory device initialized: {}", dev.name_string());
+    }
+    Ok(index)
+}
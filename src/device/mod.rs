@@ -1,12 +1,29 @@
+pub mod ether;
 pub mod loopback;
-
-use anyhow::{Context, Result};
-
-use crate::iface::NetIface;
-use crate::util::debugdump;
+pub mod memory;
+pub mod veth;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, TryLockError};
+
+use tracing::Level;
+
+use self::ether::{ETHER_ADDR_LEN, EtherAddr};
+use crate::capture::{Capture, Direction};
+use crate::error::{NetError, Result};
+use crate::iface::{IpIface, Ipv6Iface, NetIface, NetIfaceFamily};
+use crate::irq::IrqController;
+use crate::limits::StackLimits;
+use crate::packet::PacketBuf;
+use crate::pktlog;
+use crate::stats::Traffic;
+use crate::trace::{TRACE, TraceEvent};
+use crate::util::LOG_DEVICE;
 
 pub const IFNAMSIZ: usize = 16;
 pub const NET_DEVICE_ADDR_LEN: usize = 16;
+/// Frames a device holds for transmission (like `txqueuelen`)
+pub const TX_QUEUE_LEN: usize = 1000;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 #[repr(u16)]
@@ -15,6 +32,8 @@ pub enum DeviceType {
     Dummy = 0x0000,
     Loopback = 0x0001,
     Ethernet = 0x0002,
+    Veth = 0x0003,
+    Memory = 0x0004,
 }
 
 pub const NET_DEVICE_FLAG_UP: u16 = 0x0001;
@@ -22,6 +41,8 @@ pub const NET_DEVICE_FLAG_LOOPBACK: u16 = 0x0010;
 pub const NET_DEVICE_FLAG_BROADCAST: u16 = 0x0020;
 pub const NET_DEVICE_FLAG_P2P: u16 = 0x0040;
 pub const NET_DEVICE_FLAG_NEED_ARP: u16 = 0x0100;
+/// Answer ICMP Echo in the receive path, bypassing protocol dispatch (benchmarking)
+pub const NET_DEVICE_FLAG_FAST_RESPONDER: u16 = 0x0200;
 
 // Newtype pattern for type safety
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
@@ -33,10 +54,33 @@ impl std::fmt::Display for DeviceIndex {
     }
 }
 
-pub trait DeviceOps {
-    fn open(&self, dev: &Device) -> Result<()>;
-    fn close(&self, dev: &Device) -> Result<()>;
-    fn transmit(&self, dev: &Device, type_: u16, data: &[u8], dst: Option<&[u8]>) -> Result<()>;
+/// Delivers frames transmitted by a device to their receiver (a veth peer)
+pub type OutputCallback = Arc<dyn Fn(u16, &[u8], &Device) + Send + Sync>;
+
+/// Like [`OutputCallback`], for a batch of frames (type and data) handed over
+/// in one call, so the receiver takes its locks once per batch
+pub type BatchCallback = Arc<dyn Fn(&[(u16, &[u8])], &Device) + Send + Sync>;
+
+/// Frames a driver hands over per batch at most (like NAPI's poll weight)
+pub const RX_BATCH_MAX: usize = 64;
+
+/// A device driver.
+///
+/// The driver owns its state (peers, sockets, ring indices) and is called with
+/// `&mut self`; the stack-facing metadata is passed in as the [`Device`].
+/// Any thread may transmit, one at a time.
+pub trait DeviceOps: Send {
+    fn open(&mut self, dev: &Device) -> Result<()>;
+    fn close(&mut self, dev: &Device) -> Result<()>;
+    fn transmit(&mut self, dev: &Device, type_: u16, data: &[u8], dst: Option<&[u8]>)
+    -> Result<()>;
+}
+
+/// A frame output while the driver was busy transmitting
+struct PendingFrame {
+    type_: u16,
+    data: PacketBuf,
+    dst: Option<Vec<u8>>,
 }
 
 pub struct Device {
@@ -49,8 +93,18 @@ pub struct Device {
     pub alen: u16,
     pub addr: [u8; NET_DEVICE_ADDR_LEN],
     pub broadcast: [u8; NET_DEVICE_ADDR_LEN],
-    pub ops: Option<Box<dyn DeviceOps>>,
     pub ifaces: Vec<NetIface>,
+    /// Frames held for transmission; set from [`StackLimits`] on registration
+    pub tx_queue_len: usize,
+    /// Frames received and transmitted by the driver
+    pub stats: Traffic,
+    driver: Option<Mutex<Box<dyn DeviceOps>>>,
+    /// Frames output while the driver was transmitting (from within its own
+    /// transmit or from another thread), sent once it returns
+    tx_pending: Mutex<VecDeque<PendingFrame>>,
+    /// Where received and transmitted frames are recorded, and this device's
+    /// interface id there
+    capture: Option<(Arc<Capture>, u32)>,
 }
 
 impl Default for Device {
@@ -65,8 +119,12 @@ impl Default for Device {
             alen: 0,
             addr: [0; NET_DEVICE_ADDR_LEN],
             broadcast: [0; NET_DEVICE_ADDR_LEN],
-            ops: None,
             ifaces: Vec::new(),
+            tx_queue_len: TX_QUEUE_LEN,
+            stats: Traffic::default(),
+            driver: None,
+            tx_pending: Mutex::new(VecDeque::new()),
+            capture: None,
         }
     }
 }
@@ -86,38 +144,180 @@ impl Device {
             .to_string()
     }
 
-    pub fn output(&self, device_type: u16, data: &[u8], dst: Option<&[u8]>) -> Result<()> {
-        let dev_name = self.name_string();
-        tracing::debug!(
-            "device_output: dev={}, type=0x{:04x}, len={}",
-            dev_name,
-            device_type,
-            data.len()
-        );
-        debugdump(data);
+    /// Hardware address, for devices that have an Ethernet-style one
+    pub fn hw_addr(&self) -> Option<EtherAddr> {
+        if self.alen as usize != ETHER_ADDR_LEN {
+            return None;
+        }
+        let mut bytes = [0u8; ETHER_ADDR_LEN];
+        bytes.copy_from_slice(&self.addr[..ETHER_ADDR_LEN]);
+        Some(EtherAddr(bytes))
+    }
+
+    /// Assign an Ethernet-style hardware address and the matching broadcast address
+    pub fn set_hw_addr(&mut self, addr: EtherAddr) {
+        self.alen = ETHER_ADDR_LEN as u16;
+        self.addr = [0; NET_DEVICE_ADDR_LEN];
+        self.addr[..ETHER_ADDR_LEN].copy_from_slice(&addr.0);
+        self.broadcast = [0; NET_DEVICE_ADDR_LEN];
+        self.broadcast[..ETHER_ADDR_LEN].copy_from_slice(&EtherAddr::BROADCAST.0);
+    }
+
+    /// Hardware address for display, `-` if the device has none
+    pub fn hw_addr_string(&self) -> String {
+        self.hw_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    pub fn output(
+        &self,
+        device_type: u16,
+        data: &[u8],
+        dst: Option<&[u8]>,
+    ) -> Result<(), NetError> {
+        self.output_frame(device_type, data, dst)
+    }
+
+    /// Like [`output`](Self::output), taking over the buffer so a frame that has
+    /// to wait for the driver is queued without a copy
+    pub fn output_buf(
+        &self,
+        device_type: u16,
+        buf: PacketBuf,
+        dst: Option<&[u8]>,
+    ) -> Result<(), NetError> {
+        self.output_frame(device_type, buf, dst)
+    }
+
+    fn output_frame<F>(
+        &self,
+        device_type: u16,
+        frame: F,
+        dst: Option<&[u8]>,
+    ) -> Result<(), NetError>
+    where
+        F: AsRef<[u8]>,
+        PacketBuf: From<F>,
+    {
+        let data = frame.as_ref();
+        if LOG_DEVICE.allow(Level::DEBUG) {
+            tracing::debug!(
+                "device_output: dev={}, type=0x{:04x}, len={}",
+                self.name_string(),
+                device_type,
+                data.len()
+            );
+        }
 
         if !self.is_up() {
-            anyhow::bail!("device not opened");
+            return Err(NetError::DeviceDown);
         }
         if data.len() > self.mtu as usize {
-            anyhow::bail!("data too long");
+            return Err(NetError::MtuExceeded {
+                len: data.len(),
+                mtu: self.mtu as usize,
+            });
         }
 
-        if let Some(ops) = &self.ops {
-            ops.transmit(self, device_type, data, dst)?;
+        let Some(driver) = &self.driver else {
+            return Ok(());
+        };
+        // Loopback and veth deliver synchronously, so a reply may be output on this
+        // device while its driver is still transmitting the request
+        let mut pending = self.tx_pending.lock().unwrap();
+        let mut driver = match driver.try_lock() {
+            Ok(driver) => driver,
+            Err(TryLockError::WouldBlock) => {
+                if pending.len() >= self.tx_queue_len {
+                    return Err(NetError::LimitReached {
+                        what: "frames queued for transmit",
+                        limit: self.tx_queue_len,
+                    });
+                }
+                pending.push_back(PendingFrame {
+                    type_: device_type,
+                    data: PacketBuf::from(frame),
+                    dst: dst.map(<[u8]>::to_vec),
+                });
+                return Ok(());
+            }
+            Err(TryLockError::Poisoned(_)) => {
+                return Err(NetError::DriverPanicked(self.name_string()));
+            }
+        };
+        drop(pending);
+        let result = self.transmit(&mut driver, device_type, frame.as_ref(), dst);
+        loop {
+            let mut pending = self.tx_pending.lock().unwrap();
+            let Some(frame) = pending.pop_front() else {
+                // Released with the queue locked, so a frame queued meanwhile is not left behind
+                drop(driver);
+                break;
+            };
+            drop(pending);
+            if let Err(e) =
+                self.transmit(&mut driver, frame.type_, &frame.data, frame.dst.as_deref())
+            {
+                tracing::error!("device_output: dev={}, {}", self.name_string(), e);
+            }
         }
+        result
+    }
+
+    fn transmit(
+        &self,
+        driver: &mut Box<dyn DeviceOps>,
+        type_: u16,
+        data: &[u8],
+        dst: Option<&[u8]>,
+    ) -> Result<(), NetError> {
+        TRACE.record(TraceEvent::PacketOut {
+            dev: self.index,
+            type_,
+            len: data.len(),
+        });
+        self.record(Direction::Out, type_, data);
+        driver.transmit(self, type_, data, dst)?;
+        self.stats.tx.add(data.len());
+        Ok(())
+    }
 
+    /// Record this device's frames in `capture` from now on, replacing any
+    /// capture attached before
+    pub fn attach_capture(&mut self, capture: Arc<Capture>) -> anyhow::Result<()> {
+        let interface = capture.add_interface(self)?;
+        self.capture = Some((capture, interface));
         Ok(())
     }
 
+    pub fn detach_capture(&mut self) -> Option<Arc<Capture>> {
+        self.capture.take().map(|(capture, _)| capture)
+    }
+
+    /// Write a received or transmitted frame to the attached capture, if any,
+    /// and to the [packet log](crate::pktlog)
+    pub fn record(&self, direction: Direction, type_: u16, data: &[u8]) {
+        pktlog::log(direction, self, type_, data);
+        if let Some((capture, interface)) = &self.capture {
+            capture.record(*interface, self, direction, type_, data);
+        }
+    }
+
+    /// Attach the driver that opens, closes and transmits for this device
+    pub fn set_driver(&mut self, driver: Box<dyn DeviceOps>) {
+        self.driver = Some(Mutex::new(driver));
+    }
+
     pub fn input(&self, type_: u16, data: &[u8]) -> Result<()> {
-        tracing::debug!(
-            "device_input: dev={}, type=0x{:04x}, len={}",
-            self.name_string(),
-            type_,
-            data.len()
-        );
-        debugdump(data);
+        if LOG_DEVICE.allow(Level::DEBUG) {
+            tracing::debug!(
+                "device_input: dev={}, type=0x{:04x}, len={}",
+                self.name_string(),
+                type_,
+                data.len()
+            );
+        }
         Ok(())
     }
 
@@ -126,11 +326,11 @@ impl Device {
         tracing::info!("Opening device: {}", dev_name);
 
         if self.is_up() {
-            anyhow::bail!("device already opened");
+            return Err(NetError::InvalidInput("device already opened"));
         }
 
-        if let Some(ops) = &self.ops {
-            ops.open(self)?;
+        if let Some(driver) = &self.driver {
+            driver.lock().unwrap().open(self)?;
         }
 
         self.flags |= NET_DEVICE_FLAG_UP;
@@ -142,64 +342,117 @@ impl Device {
         tracing::info!("Closing device: {}", dev_name);
 
         if !self.is_up() {
-            anyhow::bail!("device not opened");
+            return Err(NetError::DeviceDown);
         }
 
-        if let Some(ops) = &self.ops {
-            ops.close(self)?;
+        if let Some(driver) = &self.driver {
+            driver.lock().unwrap().close(self)?;
         }
 
         self.flags &= !NET_DEVICE_FLAG_UP;
         Ok(())
     }
 
+    /// Add an interface. A device may carry any number of interfaces of each
+    /// family, but an address only once.
     pub fn register_iface(&mut self, iface: NetIface) -> Result<()> {
-        let has_same_family = self
+        if self
             .ifaces
             .iter()
-            .any(|cur_iface| iface.family() == cur_iface.family());
-        if has_same_family {
-            anyhow::bail!("Interface family already registered: {:?}", iface.family());
+            .any(|cur_iface| cur_iface.same_address(&iface))
+        {
+            return Err(NetError::AlreadyExists(format!(
+                "interface address {}",
+                iface.addr_string()
+            )));
         }
 
         match &iface {
             NetIface::Ip(ip_iface) => {
                 tracing::info!("Registering IP interface: {}", ip_iface.info());
             }
+            NetIface::Ipv6(ipv6_iface) => {
+                tracing::info!("Registering IPv6 interface: {}", ipv6_iface.info());
+            }
         }
 
         self.ifaces.push(iface);
         Ok(())
     }
 
-    pub fn get_ip_iface(&self) -> Option<&crate::iface::IpIface> {
-        self.ifaces.iter().find_map(|iface| iface.as_ip())
+    /// Whether the device has an interface of `family`
+    pub fn has_family(&self, family: NetIfaceFamily) -> bool {
+        self.ifaces.iter().any(|iface| iface.family() == family)
+    }
+
+    /// IPv4 interfaces, in registration order
+    pub fn ip_ifaces(&self) -> impl Iterator<Item = &IpIface> {
+        self.ifaces.iter().filter_map(|iface| iface.as_ip())
+    }
+
+    /// IPv6 interfaces, in registration order
+    pub fn ipv6_ifaces(&self) -> impl Iterator<Item = &Ipv6Iface> {
+        self.ifaces.iter().filter_map(|iface| iface.as_ipv6())
+    }
+
+    /// The first IPv4 interface
+    pub fn get_ip_iface(&self) -> Option<&IpIface> {
+        self.ip_ifaces().next()
+    }
+
+    /// The first IPv6 interface
+    pub fn get_ipv6_iface(&self) -> Option<&Ipv6Iface> {
+        self.ipv6_ifaces().next()
     }
 }
 
 pub struct DeviceManager {
     devices: Vec<Device>,
+    limit: usize,
+    tx_queue_len: usize,
+    irqs: Arc<IrqController>,
 }
 
 impl DeviceManager {
     pub fn new() -> Self {
+        Self::with_limits(&StackLimits::default())
+    }
+
+    /// Register at most `limits.devices` devices, each with `limits.tx_queue_len`
+    pub fn with_limits(limits: &StackLimits) -> Self {
         Self {
             devices: Vec::new(),
+            limit: limits.devices,
+            tx_queue_len: limits.tx_queue_len,
+            irqs: Arc::new(IrqController::new()),
         }
     }
 
+    /// IRQs the drivers of these devices requested
+    pub fn irqs(&self) -> &Arc<IrqController> {
+        &self.irqs
+    }
+
     pub fn register(&mut self, mut dev: Device) -> Result<DeviceIndex> {
+        if self.devices.len() >= self.limit {
+            return Err(NetError::LimitReached {
+                what: "devices",
+                limit: self.limit,
+            });
+        }
         let index = DeviceIndex(self.devices.len());
         dev.index = index;
+        dev.tx_queue_len = self.tx_queue_len;
 
         let name_str = format!("net{}", index.0);
         let name_bytes = name_str.as_bytes();
         dev.name[..name_bytes.len()].copy_from_slice(name_bytes);
 
         tracing::info!(
-            "Device registered: {}, type={:?}",
+            "Device registered: {}, type={:?}, addr={}",
             name_str,
-            dev.device_type
+            dev.device_type,
+            dev.hw_addr_string()
         );
 
         self.devices.push(dev);
@@ -226,9 +479,9 @@ impl DeviceManager {
         tracing::info!("Starting devices...");
 
         for dev in self.iter_mut() {
-            let dev_name = dev.name_string();
-            dev.open()
-                .with_context(|| format!("Failed to open device: {}", dev_name))?;
+            dev.open().inspect_err(|e| {
+                tracing::error!("Failed to open device: {}, {}", dev.name_string(), e);
+            })?;
         }
 
         tracing::info!("All devices started");
@@ -239,9 +492,9 @@ impl DeviceManager {
         tracing::info!("Shutting down devices...");
 
         for dev in self.iter_mut() {
-            let dev_name = dev.name_string();
-            dev.close()
-                .with_context(|| format!("Failed to close device: {}", dev_name))?;
+            dev.close().inspect_err(|e| {
+                tracing::error!("Failed to close device: {}, {}", dev.name_string(), e);
+            })?;
         }
 
         tracing::info!("All devices stopped");
@@ -254,3 +507,78 @@ impl Default for DeviceManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records transmitted types and answers type 1 by outputting type 2 on the same device
+    struct EchoDriver {
+        sent: Arc<Mutex<Vec<u16>>>,
+    }
+
+    impl DeviceOps for EchoDriver {
+        fn open(&mut self, _dev: &Device) -> Result<()> {
+            Ok(())
+        }
+
+        fn close(&mut self, _dev: &Device) -> Result<()> {
+            Ok(())
+        }
+
+        fn transmit(
+            &mut self,
+            dev: &Device,
+            type_: u16,
+            data: &[u8],
+            _dst: Option<&[u8]>,
+        ) -> Result<()> {
+            self.sent.lock().unwrap().push(type_);
+            if type_ == 1 {
+                dev.output(2, data, None)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output_from_within_transmit_is_deferred() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut dev = Device {
+            mtu: 1500,
+            ..Default::default()
+        };
+        dev.set_driver(Box::new(EchoDriver {
+            sent: Arc::clone(&sent),
+        }));
+        dev.open().unwrap();
+
+        dev.output(1, b"ping", None).unwrap();
+        assert_eq!(*sent.lock().unwrap(), [1, 2]);
+        assert!(dev.tx_pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ifaces_of_both_families() {
+        let mut dev = Device::default();
+        let v6 =
+            |addr: &str| NetIface::Ipv6(Ipv6Iface::new(addr.parse().unwrap(), 64, DeviceIndex(0)));
+        assert!(!dev.has_family(NetIfaceFamily::Ipv6));
+
+        let ip = IpIface::new("192.0.2.1", "255.255.255.0", DeviceIndex(0)).unwrap();
+        dev.register_iface(NetIface::Ip(ip)).unwrap();
+        dev.register_iface(v6("fe80::1")).unwrap();
+        dev.register_iface(v6("2001:db8::1")).unwrap();
+        assert!(dev.register_iface(v6("fe80::1")).is_err());
+
+        assert!(dev.has_family(NetIfaceFamily::Ip));
+        assert!(dev.has_family(NetIfaceFamily::Ipv6));
+        assert_eq!(dev.ip_ifaces().count(), 1);
+        let v6_addrs: Vec<String> = dev
+            .ipv6_ifaces()
+            .map(|iface| iface.unicast.to_string())
+            .collect();
+        assert_eq!(v6_addrs, ["fe80::1", "2001:db8::1"]);
+        assert_eq!(dev.get_ipv6_iface().unwrap().unicast.to_string(), "fe80::1");
+    }
+}
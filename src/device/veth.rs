@@ -0,0 +1,118 @@
+use crate::error::Result;
+use tracing::Level;
+
+use super::ether::EtherAddr;
+use super::{
+    Device, DeviceIndex, DeviceManager, DeviceOps, DeviceType, NET_DEVICE_FLAG_P2P, OutputCallback,
+};
+use crate::util::LOG_DRIVER;
+
+const VETH_MTU: u16 = 1500;
+
+/// One end of a virtual point-to-point link. Transmitted frames are handed
+/// to the peer end, which may belong to another stack instance.
+struct VethOps {
+    peer: OutputCallback,
+    /// Probability that a transmitted frame is dropped
+    loss: f64,
+    /// xorshift64 state for the loss generator; must be non-zero
+    rng: u64,
+}
+
+impl VethOps {
+    /// Whether the next frame is lost to the link impairment
+    fn lose(&mut self) -> bool {
+        if self.loss <= 0.0 {
+            return false;
+        }
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng = x;
+
+        let sample = (x >> 11) as f64 / (1u64 << 53) as f64;
+        sample < self.loss
+    }
+}
+
+impl DeviceOps for VethOps {
+    fn open(&mut self, _dev: &Device) -> Result<()> {
+        Ok(())
+    }
+
+    fn close(&mut self, _dev: &Device) -> Result<()> {
+        Ok(())
+    }
+
+    fn transmit(
+        &mut self,
+        dev: &Device,
+        type_: u16,
+        data: &[u8],
+        dst: Option<&[u8]>,
+    ) -> Result<()> {
+        if LOG_DRIVER.allow(Level::DEBUG) {
+            tracing::debug!(
+                "veth_transmit: dev={}, type=0x{:04x}, len={}, dst={:?}",
+                dev.name_string(),
+                type_,
+                data.len(),
+                dst
+            );
+        }
+
+        if self.lose() {
+            tracing::debug!("veth_impair: dev={}, frame dropped", dev.name_string());
+            return Ok(());
+        }
+        (self.peer)(type_, data, dev);
+
+        Ok(())
+    }
+}
+
+/// Register a veth end with hardware address `addr`.
+/// It cannot transmit until [`attach`] connects it to a peer.
+pub fn init(devices: &mut DeviceManager, addr: EtherAddr) -> Result<DeviceIndex> {
+    let mut dev = Device {
+        device_type: DeviceType::Veth,
+        mtu: VETH_MTU,
+        flags: NET_DEVICE_FLAG_P2P,
+        ..Default::default()
+    };
+    dev.set_hw_addr(addr);
+
+    let index = devices.register(dev)?;
+    tracing::info!("Veth device initialized: net{}", index);
+    Ok(index)
+}
+
+/// Connect a veth end to the callback delivering frames to its peer,
+/// dropping transmitted frames as `impairment` says
+pub fn attach(dev: &mut Device, peer: OutputCallback, impairment: &Impairment) {
+    dev.set_driver(Box::new(VethOps {
+        peer,
+        loss: impairment.loss,
+        rng: impairment.seed | 1,
+    }));
+}
+
+/// Impairments applied to frames crossing a veth link
+#[derive(Debug, Clone, Default)]
+pub struct Impairment {
+    /// Probability (0.0 to 1.0) that a frame is dropped
+    pub loss: f64,
+    /// Seed for the loss generator, so impaired runs are reproducible
+    pub seed: u64,
+}
+
+impl Impairment {
+    pub fn loss(loss: f64) -> Self {
+        Self { loss, seed: 0 }
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.loss <= 0.0
+    }
+}
@@ -0,0 +1,187 @@
+//! Explanations for failed binds and interface registrations.
+//!
+//! Bind, listen and interface registration fail with a [`Conflicts`] error that
+//! names what already holds the resource, carried by
+//! [`NetError::PortInUse`] or [`NetError::AddressInUse`]. The functions here
+//! answer the same question up front.
+//!
+//! [`NetError::PortInUse`]: crate::error::NetError::PortInUse
+//! [`NetError::AddressInUse`]: crate::error::NetError::AddressInUse
+
+use std::fmt;
+
+use crate::context::{IpRoute, ProtocolContexts};
+use crate::device::DeviceIndex;
+use crate::protocol::ip::{IpAddr, IpEndpoint};
+use crate::protocol::tcp::{TcpPcbId, TcpState};
+use crate::protocol::udp::UdpPcbId;
+
+/// Something that prevents a bind or registration from succeeding
+#[derive(Debug, Clone)]
+pub enum Conflict {
+    /// A UDP control block is bound to an overlapping endpoint
+    UdpPort { owner: UdpPcbId, local: IpEndpoint },
+    /// A TCP control block listens on an overlapping endpoint
+    TcpPort {
+        owner: TcpPcbId,
+        local: IpEndpoint,
+        state: TcpState,
+    },
+    /// An interface already has the address
+    IfaceAddress {
+        device: DeviceIndex,
+        unicast: IpAddr,
+        netmask: IpAddr,
+    },
+    /// A route for the same network and netmask already exists
+    Route(IpRoute),
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conflict::UdpPort { owner, local } => {
+                write!(f, "UDP control block {} is bound to {}", owner, local)
+            }
+            Conflict::TcpPort {
+                owner,
+                local,
+                state,
+            } => write!(
+                f,
+                "TCP control block {} ({:?}) is bound to {}",
+                owner, state, local
+            ),
+            Conflict::IfaceAddress {
+                device,
+                unicast,
+                netmask,
+            } => write!(
+                f,
+                "interface {}/{} on device {} has the address",
+                unicast, netmask, device
+            ),
+            Conflict::Route(route) => write!(f, "route exists: {}", route.info()),
+        }
+    }
+}
+
+/// Error returned when a resource is already taken
+#[derive(Debug, Clone)]
+pub struct Conflicts {
+    /// What was requested, e.g. `bind 0.0.0.0:7`
+    pub request: String,
+    pub conflicts: Vec<Conflict>,
+}
+
+impl fmt::Display for Conflicts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: already in use", self.request)?;
+        for conflict in &self.conflicts {
+            write!(f, "; {}", conflict)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Conflicts {}
+
+/// Why binding a UDP control block to `local` would fail
+pub fn udp_bind(ctx: &ProtocolContexts, local: IpEndpoint) -> Vec<Conflict> {
+    ctx.udp.conflicts(local)
+}
+
+/// Why listening on `local` for `foreign` (`None` = anyone) would fail
+pub fn tcp_listen(
+    ctx: &ProtocolContexts,
+    local: IpEndpoint,
+    foreign: Option<IpEndpoint>,
+) -> Vec<Conflict> {
+    ctx.tcp.conflicts(local, foreign.unwrap_or_default())
+}
+
+/// Why registering `unicast`/`netmask` as an interface would fail
+pub fn iface_register(ctx: &ProtocolContexts, unicast: IpAddr, netmask: IpAddr) -> Vec<Conflict> {
+    let mut conflicts: Vec<Conflict> = ctx
+        .ip_ifaces
+        .iter()
+        .filter(|iface| iface.unicast == unicast)
+        .map(|iface| Conflict::IfaceAddress {
+            device: iface.device_index,
+            unicast: iface.unicast,
+            netmask: iface.netmask,
+        })
+        .collect();
+    if let Some(route) = ctx.ip_routes.find(unicast & netmask, netmask) {
+        conflicts.push(Conflict::Route(route.clone()));
+    }
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::error::NetError;
+    use crate::protocol::ip;
+    use crate::testing::setup_loopback;
+
+    fn ep(s: &str) -> IpEndpoint {
+        IpEndpoint::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_udp_bind_conflict_names_owner() {
+        let (_devices, ctx, _captured) = setup_loopback();
+        let owner = ctx.udp.open().unwrap();
+        ctx.udp.bind(owner, ep("0.0.0.0:53")).unwrap();
+        let other = ctx.udp.open().unwrap();
+
+        let conflicts = udp_bind(&ctx, ep("127.0.0.1:53"));
+        assert!(matches!(
+            conflicts.as_slice(),
+            [Conflict::UdpPort { owner: o, .. }] if *o == owner
+        ));
+        assert!(udp_bind(&ctx, ep("127.0.0.1:54")).is_empty());
+
+        let err = ctx.udp.bind(other, ep("127.0.0.1:53")).unwrap_err();
+        let NetError::PortInUse(conflicts) = &err else {
+            panic!("not a port conflict: {}", err);
+        };
+        assert_eq!(conflicts.conflicts.len(), 1);
+        assert!(err.to_string().contains("0.0.0.0:53"));
+    }
+
+    #[test]
+    fn test_tcp_listen_conflict() {
+        let (_devices, ctx, _captured) = setup_loopback();
+        let owner = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        assert!(matches!(
+            tcp_listen(&ctx, ep("127.0.0.1:80"), None).as_slice(),
+            [Conflict::TcpPort { owner: o, state: TcpState::Listen, .. }] if *o == owner
+        ));
+        let err = ctx.tcp.listen(ep("127.0.0.1:80"), None).unwrap_err();
+        assert!(matches!(err, NetError::PortInUse(_)));
+    }
+
+    #[test]
+    fn test_iface_register_conflicts() {
+        let (mut devices, mut ctx, _captured) = setup_loopback();
+        let addr = IpAddr::from_str("127.0.0.1").unwrap();
+        let mask = IpAddr::from_str("255.0.0.0").unwrap();
+        let conflicts = iface_register(&ctx, addr, mask);
+        assert!(matches!(conflicts[0], Conflict::IfaceAddress { .. }));
+        assert!(matches!(conflicts[1], Conflict::Route(_)));
+
+        let dev = devices.get_mut(DeviceIndex(0)).unwrap();
+        let before = dev.ifaces.len();
+        let err = ip::register_iface(dev, "127.0.0.1", "255.0.0.0", &mut ctx).unwrap_err();
+        let NetError::AddressInUse(conflicts) = &err else {
+            panic!("not an address conflict: {}", err);
+        };
+        assert_eq!(conflicts.conflicts.len(), 2);
+        // A failed registration leaves the device untouched
+        assert_eq!(dev.ifaces.len(), before);
+    }
+}
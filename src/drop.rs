@@ -0,0 +1,164 @@
+//! Hook for packets the stack is about to drop.
+//!
+//! Every place that discards a received packet reports it here first, with the
+//! reason and the bytes being dropped (the frame or the layer's own packet).
+//! A hook can log or capture the packet and, for reasons where carrying on
+//! makes sense, rescue it: the stack then continues as if the check had passed.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DropReason {
+    /// The device is down (rescue: receive anyway)
+    DeviceDown,
+    /// No handler for the frame type
+    NoHandler,
+    /// The protocol's receive queue is full (rescue: queue past the limit)
+    RxQueueFull,
+    /// Too short, bad lengths or unsupported version
+    Malformed,
+    /// IP header checksum mismatch (rescue: accept)
+    IpChecksum,
+    /// Fragments are not reassembled
+    Fragmented,
+    /// Not addressed to this host and not forwarded (rescue: accept locally)
+    NotForUs,
+    /// Source routed packet refused by `accept_source_route` (rescue: forward)
+    SourceRoute,
+    /// TTL ran out while forwarding
+    TtlExceeded,
+    /// No route to forward the packet
+    NoRoute,
+    /// A packet to masquerade could not be translated (unsupported protocol or
+    /// the NAT table is full)
+    Nat,
+    /// IP protocol without a handler
+    UnknownProtocol,
+    /// ICMP checksum mismatch (rescue: accept)
+    IcmpChecksum,
+    /// IGMP checksum mismatch (rescue: accept)
+    IgmpChecksum,
+    /// UDP checksum mismatch (rescue: accept)
+    UdpChecksum,
+    /// TCP checksum mismatch (rescue: accept)
+    TcpChecksum,
+    /// TCP authentication option missing or wrong
+    TcpAuth,
+    /// No socket is bound to the destination
+    NoSocket,
+    /// The TCP listener has as many pending connections as its backlog allows
+    BacklogFull,
+    /// The socket holds as many unread datagrams as its limit allows
+    SocketQueueFull,
+}
+
+impl DropReason {
+    /// Whether the stack honors [`DropVerdict::Rescue`] for this reason
+    pub fn is_rescuable(self) -> bool {
+        matches!(
+            self,
+            DropReason::DeviceDown
+                | DropReason::RxQueueFull
+                | DropReason::IpChecksum
+                | DropReason::NotForUs
+                | DropReason::SourceRoute
+                | DropReason::IcmpChecksum
+                | DropReason::IgmpChecksum
+                | DropReason::UdpChecksum
+                | DropReason::TcpChecksum
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropVerdict {
+    Drop,
+    /// Keep the packet; ignored for reasons that are not rescuable
+    Rescue,
+}
+
+pub type DropHook = Arc<dyn Fn(DropReason, &[u8]) -> DropVerdict + Send + Sync>;
+
+/// Drop counters and the user's hook
+#[derive(Default)]
+pub struct DropMonitor {
+    hook: Mutex<Option<DropHook>>,
+    counts: Mutex<HashMap<DropReason, u64>>,
+}
+
+impl DropMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_hook(&self, hook: Option<DropHook>) {
+        *self.hook.lock().unwrap() = hook;
+    }
+
+    /// Report that `packet` is about to be dropped for `reason`.
+    ///
+    /// Returns `true` if it should be dropped, `false` if the hook rescued it.
+    pub fn drop(&self, reason: DropReason, packet: &[u8]) -> bool {
+        // The hook runs without the lock so it may replace itself
+        let hook = self.hook.lock().unwrap().clone();
+        let verdict = match hook {
+            Some(hook) => hook(reason, packet),
+            None => DropVerdict::Drop,
+        };
+        if verdict == DropVerdict::Rescue && reason.is_rescuable() {
+            tracing::debug!("drop rescued: reason={:?}, len={}", reason, packet.len());
+            return false;
+        }
+        *self.counts.lock().unwrap().entry(reason).or_default() += 1;
+        true
+    }
+
+    /// Packets dropped for `reason`
+    pub fn count(&self, reason: DropReason) -> u64 {
+        self.counts
+            .lock()
+            .unwrap()
+            .get(&reason)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Every reason packets were dropped for, with its count
+    pub fn counts(&self) -> Vec<(DropReason, u64)> {
+        let mut counts: Vec<_> = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(reason, count)| (*reason, *count))
+            .collect();
+        counts.sort();
+        counts
+    }
+
+    /// Packets dropped for any reason
+    pub fn total(&self) -> u64 {
+        self.counts.lock().unwrap().values().sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rescue_only_where_allowed() {
+        let drops = DropMonitor::new();
+        assert!(drops.drop(DropReason::IpChecksum, &[0; 20]));
+
+        drops.set_hook(Some(Arc::new(|_, _| DropVerdict::Rescue)));
+        assert!(!drops.drop(DropReason::IpChecksum, &[0; 20]));
+        assert!(!drops.drop(DropReason::RxQueueFull, &[0; 20]));
+        assert!(drops.drop(DropReason::Malformed, &[0; 4]));
+
+        assert_eq!(drops.count(DropReason::IpChecksum), 1);
+        assert_eq!(drops.count(DropReason::Malformed), 1);
+        assert_eq!(drops.total(), 2);
+    }
+}
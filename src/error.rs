@@ -0,0 +1,204 @@
+//! Failure causes callers can match on.
+//!
+//! The calls an application makes return a [`NetError`] directly: the
+//! [`socket`](crate::socket) API and [`Device::output`](crate::device::Device::output).
+//!
+//! ```
+//! # use microps::error::NetError;
+//! # use microps::protocol::ip::IpEndpoint;
+//! # use microps::socket::{self, SocketType};
+//! # use microps::stack::NetStack;
+//! let stack = NetStack::new()?;
+//! let ctx = stack.ctx();
+//! let fd = socket::socket(SocketType::Dgram, &ctx)?;
+//! socket::bind(fd, IpEndpoint::new("127.0.0.1".parse()?, 7), &ctx)?;
+//! socket::set_nonblocking(fd, true, &ctx)?;
+//! let mut buf = [0; 64];
+//! let err = socket::recv(fd, &mut buf, &ctx, &stack.devices()).unwrap_err();
+//! assert!(matches!(err, NetError::WouldBlock));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The protocol layers underneath (input handlers, `output` functions, the
+//! control block tables) and the devices return a `NetError` as well, so a
+//! caller can tell [`NoRoute`](NetError::NoRoute) from
+//! [`MtuExceeded`](NetError::MtuExceeded) wherever it calls in. Code built on
+//! top that adds context with `anyhow` keeps the cause, which [`NetError::of`]
+//! finds again.
+
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::device::DeviceIndex;
+use crate::diagnose::Conflicts;
+use crate::socket::SocketFd;
+
+/// Result of the calls that fail with a [`NetError`]
+pub type Result<T, E = NetError> = std::result::Result<T, E>;
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[non_exhaustive]
+pub enum NetError {
+    /// A packet is shorter than its headers
+    #[error("packet too short: len={len}")]
+    Truncated { len: usize },
+    /// A header checksum does not verify
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+    /// A header or option field holds a value the protocol does not allow
+    #[error("malformed packet: {0}")]
+    Malformed(String),
+    /// A valid packet or request uses a feature this stack does not implement
+    #[error("not supported: {0}")]
+    Unsupported(&'static str),
+    /// No interface or route reaches the destination
+    #[error("no route to host, dst={0}")]
+    NoRoute(IpAddr),
+    /// No interface has the address
+    #[error("no interface with address {0}")]
+    NoInterface(IpAddr),
+    /// No device is registered under the index
+    #[error("device not found: {0}")]
+    NoDevice(DeviceIndex),
+    /// A frame or packet does not fit the device MTU
+    #[error("too long, mtu={mtu} < {len}")]
+    MtuExceeded { len: usize, mtu: usize },
+    /// A payload exceeds what the protocol's length field can carry
+    #[error("too long, max={max} < {len}")]
+    MessageTooLong { len: usize, max: usize },
+    /// The device is not open
+    #[error("device not opened")]
+    DeviceDown,
+    /// A system call failed, e.g. starting a stack thread
+    #[error("{0}")]
+    Io(#[source] Arc<io::Error>),
+    /// The device driver panicked while transmitting
+    #[error("driver panicked: dev={0}")]
+    DriverPanicked(String),
+    /// A control block already holds the port (see [`Conflicts`])
+    #[error("{0}")]
+    PortInUse(Conflicts),
+    /// An interface already has the address, or the route exists
+    #[error("{0}")]
+    AddressInUse(Conflicts),
+    /// Every ephemeral port is taken
+    #[error("no free ephemeral port")]
+    NoFreePort,
+    /// A table entry (a registration, a forwarding rule, a connection) exists
+    #[error("already exists: {0}")]
+    AlreadyExists(String),
+    /// No table entry (a control block, a group membership, a forwarding
+    /// rule) matches
+    #[error("not found: {0}")]
+    NotFound(String),
+    /// The operation would have had to wait, in non-blocking mode
+    #[error("operation would block")]
+    WouldBlock,
+    /// A blocking call was given up because of [`NetEvent::Interrupt`]
+    /// (`EINTR`)
+    ///
+    /// [`NetEvent::Interrupt`]: crate::event::NetEvent::Interrupt
+    #[error("interrupted")]
+    Interrupted,
+    #[error("timed out")]
+    TimedOut,
+    #[error("connection refused")]
+    ConnectionRefused,
+    #[error("not connected")]
+    NotConnected,
+    /// The control block was closed while a call waited on it, or the
+    /// connection is closing
+    #[error("connection closed")]
+    Closed,
+    /// No socket is open under the descriptor (`EBADF`)
+    #[error("bad socket descriptor: {0}")]
+    BadDescriptor(SocketFd),
+    /// The socket is of the wrong type or in the wrong state for the call
+    /// (`EINVAL`, `EISCONN`, `EOPNOTSUPP`)
+    #[error("{reason}: {fd}")]
+    InvalidSocket { fd: SocketFd, reason: &'static str },
+    /// An argument is out of range (`EINVAL`)
+    #[error("{0}")]
+    InvalidInput(&'static str),
+    /// A table or queue is at its limit (`EMFILE`, `ENOBUFS`)
+    #[error("too many {what} (limit {limit})")]
+    LimitReached { what: &'static str, limit: usize },
+}
+
+impl NetError {
+    /// The cause of `e`, if it has one
+    pub fn of(e: &anyhow::Error) -> Option<&NetError> {
+        e.downcast_ref()
+    }
+
+    /// The `std::io` equivalent, for [`net`](crate::net)
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            NetError::Truncated { .. } | NetError::ChecksumMismatch | NetError::Malformed(_) => {
+                io::ErrorKind::InvalidData
+            }
+            NetError::Unsupported(_) => io::ErrorKind::Unsupported,
+            NetError::NoRoute(_) => io::ErrorKind::HostUnreachable,
+            NetError::NoInterface(_) => io::ErrorKind::AddrNotAvailable,
+            NetError::NoDevice(_) | NetError::NotFound(_) => io::ErrorKind::NotFound,
+            NetError::MtuExceeded { .. } | NetError::MessageTooLong { .. } => {
+                io::ErrorKind::InvalidInput
+            }
+            NetError::DeviceDown => io::ErrorKind::NetworkDown,
+            NetError::PortInUse(_) | NetError::AddressInUse(_) | NetError::NoFreePort => {
+                io::ErrorKind::AddrInUse
+            }
+            NetError::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+            NetError::WouldBlock => io::ErrorKind::WouldBlock,
+            NetError::Interrupted => io::ErrorKind::Interrupted,
+            NetError::TimedOut => io::ErrorKind::TimedOut,
+            NetError::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+            NetError::NotConnected => io::ErrorKind::NotConnected,
+            NetError::Closed => io::ErrorKind::ConnectionAborted,
+            NetError::BadDescriptor(_)
+            | NetError::InvalidSocket { .. }
+            | NetError::InvalidInput(_) => io::ErrorKind::InvalidInput,
+            NetError::Io(e) => e.kind(),
+            NetError::DriverPanicked(_) | NetError::LimitReached { .. } => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> Self {
+        NetError::Io(Arc::new(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_net_error_survives_context() {
+        let err = Err::<(), _>(NetError::TimedOut)
+            .context("receive timed out: 3")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "receive timed out: 3");
+        assert!(matches!(NetError::of(&err), Some(NetError::TimedOut)));
+        assert_eq!(NetError::of(&err).unwrap().kind(), io::ErrorKind::TimedOut);
+        assert!(NetError::of(&anyhow::anyhow!("timed out")).is_none());
+    }
+
+    #[test]
+    fn test_net_error_display() {
+        let err = NetError::MtuExceeded {
+            len: 1600,
+            mtu: 1500,
+        };
+        assert_eq!(err.to_string(), "too long, mtu=1500 < 1600");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = NetError::NoRoute("192.0.2.1".parse().unwrap());
+        assert_eq!(err.to_string(), "no route to host, dst=192.0.2.1");
+        assert_eq!(err.kind(), io::ErrorKind::HostUnreachable);
+        let err = NetError::Malformed("hlen=4".to_string());
+        assert_eq!(err.to_string(), "malformed packet: hlen=4");
+    }
+}
@@ -0,0 +1,214 @@
+//! Stack events (microps' `net_event_subscribe` / `net_raise_event`).
+//!
+//! Protocols and applications subscribe to an [`EventBus`] (each stack has
+//! one, see [`ProtocolManager::events`]) with a handler, or [`listen`] for a
+//! channel that a thread can block on instead of polling the stack. The stack
+//! raises [`NetEvent`]s when devices open or close and when interfaces are
+//! registered; applications raise [`NetEvent::Interrupt`] with
+//! [`NetStack::raise_event`], which wakes the UDP and TCP calls blocked on the
+//! stack with [`NetError::Interrupted`].
+//!
+//! [`ProtocolManager::events`]: crate::protocol::ProtocolManager::events
+//! [`listen`]: EventBus::listen
+//! [`NetStack::raise_event`]: crate::stack::NetStack::raise_event
+//! [`NetError::Interrupted`]: crate::error::NetError::Interrupted
+
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::context::ProtocolContexts;
+use crate::device::DeviceIndex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetEvent {
+    /// The device was opened
+    InterfaceUp(DeviceIndex),
+    /// The device was closed
+    InterfaceDown(DeviceIndex),
+    /// An interface address was registered on the device
+    AddressChanged(DeviceIndex),
+    /// The user asked blocked calls to give up (`SIGINT` in microps)
+    Interrupt,
+}
+
+impl fmt::Display for NetEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetEvent::InterfaceUp(dev) => write!(f, "interface up: dev={}", dev),
+            NetEvent::InterfaceDown(dev) => write!(f, "interface down: dev={}", dev),
+            NetEvent::AddressChanged(dev) => write!(f, "address changed: dev={}", dev),
+            NetEvent::Interrupt => write!(f, "interrupt"),
+        }
+    }
+}
+
+/// Runs on the thread that raised the event, with the stack read-locked: it
+/// must not reconfigure the stack (use [`EventBus::listen`] for that)
+pub type EventHandler = Arc<dyn Fn(&NetEvent, &ProtocolContexts) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+enum Subscriber {
+    Handler(EventHandler),
+    Channel(Sender<NetEvent>),
+}
+
+#[derive(Default)]
+struct BusState {
+    subscribers: Vec<(SubscriptionId, Subscriber)>,
+    next_id: u64,
+}
+
+/// Subscribers to the events of one stack
+#[derive(Default)]
+pub struct EventBus {
+    state: Mutex<BusState>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(&self, subscriber: Subscriber) -> SubscriptionId {
+        let mut state = self.state.lock().unwrap();
+        let id = SubscriptionId(state.next_id);
+        state.next_id += 1;
+        state.subscribers.push((id, subscriber));
+        id
+    }
+
+    /// Run `handler` for every event raised from now on
+    pub fn subscribe(&self, handler: EventHandler) -> SubscriptionId {
+        self.add(Subscriber::Handler(handler))
+    }
+
+    /// Receive every event raised from now on. The subscription ends when the
+    /// receiver is dropped.
+    pub fn listen(&self) -> Receiver<NetEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.add(Subscriber::Channel(tx));
+        rx
+    }
+
+    /// Returns false if `id` was not subscribed
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let before = state.subscribers.len();
+        state.subscribers.retain(|(cur, _)| *cur != id);
+        state.subscribers.len() != before
+    }
+
+    /// Number of subscriptions, including receivers dropped since the last event
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().subscribers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Deliver `event` to every subscriber; returns how many got it
+    pub fn raise(&self, event: NetEvent, ctx: &ProtocolContexts) -> usize {
+        tracing::debug!("event: {}", event);
+        let handlers: Vec<EventHandler> = {
+            let mut state = self.state.lock().unwrap();
+            // Channels whose receiver is gone unsubscribe here
+            state
+                .subscribers
+                .retain(|(_, subscriber)| match subscriber {
+                    Subscriber::Handler(_) => true,
+                    Subscriber::Channel(tx) => tx.send(event).is_ok(),
+                });
+            state
+                .subscribers
+                .iter()
+                .filter_map(|(_, subscriber)| match subscriber {
+                    Subscriber::Handler(handler) => Some(Arc::clone(handler)),
+                    Subscriber::Channel(_) => None,
+                })
+                .collect()
+        };
+        // Not locked while handlers run, so they may subscribe
+        for handler in &handlers {
+            handler(&event, ctx);
+        }
+        self.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::error::NetError;
+    use crate::protocol::ip::{IpAddr, IpEndpoint};
+    use crate::stack::NetStack;
+
+    #[test]
+    fn test_event_subscribe_and_listen() {
+        let bus = EventBus::new();
+        let ctx = ProtocolContexts::new();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let id = bus.subscribe({
+            let seen = Arc::clone(&seen);
+            Arc::new(move |_, _| {
+                seen.fetch_add(1, Ordering::Relaxed);
+            })
+        });
+        let rx = bus.listen();
+
+        assert_eq!(bus.raise(NetEvent::InterfaceUp(DeviceIndex(0)), &ctx), 2);
+        assert_eq!(rx.try_recv(), Ok(NetEvent::InterfaceUp(DeviceIndex(0))));
+        assert_eq!(seen.load(Ordering::Relaxed), 1);
+
+        drop(rx);
+        assert!(bus.unsubscribe(id));
+        assert!(!bus.unsubscribe(id));
+        assert_eq!(bus.raise(NetEvent::Interrupt, &ctx), 0);
+        assert_eq!(seen.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_event_stack_lifecycle() {
+        let stack = NetStack::new().unwrap();
+        let rx = stack.listen_events();
+        let index = stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        stack.shutdown().unwrap();
+
+        let events: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            events,
+            [
+                NetEvent::AddressChanged(index),
+                NetEvent::AddressChanged(index),
+                NetEvent::InterfaceUp(index),
+                NetEvent::InterfaceDown(index),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_event_interrupt_wakes_receiver() {
+        let stack = NetStack::new().unwrap();
+        let ctx = stack.ctx();
+        let id = ctx.udp.open().unwrap();
+        ctx.udp.bind(id, IpEndpoint::new(IpAddr::ANY, 7)).unwrap();
+
+        thread::scope(|s| {
+            let receiver = s.spawn(|| ctx.udp.recvfrom(id));
+            while !receiver.is_finished() {
+                stack.raise_event(NetEvent::Interrupt);
+                thread::sleep(Duration::from_millis(1));
+            }
+            let err = receiver.join().unwrap().unwrap_err();
+            assert!(matches!(err, NetError::Interrupted));
+        });
+    }
+}
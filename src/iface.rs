@@ -1,7 +1,9 @@
-use anyhow::Result;
+use std::str::FromStr;
 
 use crate::device::DeviceIndex;
+use crate::error::{NetError, Result};
 use crate::protocol::ip::IpAddr;
+use crate::protocol::ipv6::{IPV6_HOP_LIMIT_DEFAULT, Ipv6Addr};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NetIfaceFamily {
@@ -19,8 +21,10 @@ pub struct IpIface {
 
 impl IpIface {
     pub fn new(unicast: &str, netmask: &str, device_index: DeviceIndex) -> Result<Self> {
-        let unicast_addr = IpAddr::from_str(unicast)?;
-        let netmask_addr = IpAddr::from_str(netmask)?;
+        let unicast_addr = IpAddr::from_str(unicast)
+            .map_err(|_| NetError::InvalidInput("invalid unicast address"))?;
+        let netmask_addr =
+            IpAddr::from_str(netmask).map_err(|_| NetError::InvalidInput("invalid netmask"))?;
         let broadcast_addr = (unicast_addr & netmask_addr) | !netmask_addr;
 
         Ok(IpIface {
@@ -43,21 +47,79 @@ impl IpIface {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Ipv6Iface {
+    pub unicast: Ipv6Addr,
+    pub prefix_len: u8,
+    /// Hop limit of the packets sent through the interface
+    pub hop_limit: u8,
+    pub device_index: DeviceIndex,
+}
+
+impl Ipv6Iface {
+    pub fn new(unicast: Ipv6Addr, prefix_len: u8, device_index: DeviceIndex) -> Self {
+        Ipv6Iface {
+            unicast,
+            prefix_len,
+            hop_limit: IPV6_HOP_LIMIT_DEFAULT,
+            device_index,
+        }
+    }
+
+    pub fn is_destination_match(&self, dst: Ipv6Addr) -> bool {
+        dst == self.unicast || dst == Ipv6Addr::ALL_NODES || dst == self.unicast.solicited_node()
+    }
+
+    pub fn info(&self) -> String {
+        format!(
+            "unicast={}/{}, hop_limit={}",
+            self.unicast, self.prefix_len, self.hop_limit
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum NetIface {
     Ip(IpIface),
+    Ipv6(Ipv6Iface),
 }
 
 impl NetIface {
     pub fn family(&self) -> NetIfaceFamily {
         match self {
             NetIface::Ip(_) => NetIfaceFamily::Ip,
+            NetIface::Ipv6(_) => NetIfaceFamily::Ipv6,
         }
     }
 
     pub fn as_ip(&self) -> Option<&IpIface> {
         match self {
             NetIface::Ip(iface) => Some(iface),
+            NetIface::Ipv6(_) => None,
+        }
+    }
+
+    /// Whether both are of the same family and have the same unicast address
+    pub fn same_address(&self, other: &NetIface) -> bool {
+        match (self, other) {
+            (NetIface::Ip(a), NetIface::Ip(b)) => a.unicast == b.unicast,
+            (NetIface::Ipv6(a), NetIface::Ipv6(b)) => a.unicast == b.unicast,
+            _ => false,
+        }
+    }
+
+    /// The unicast address, for display
+    pub fn addr_string(&self) -> String {
+        match self {
+            NetIface::Ip(iface) => iface.unicast.to_string(),
+            NetIface::Ipv6(iface) => iface.unicast.to_string(),
+        }
+    }
+
+    pub fn as_ipv6(&self) -> Option<&Ipv6Iface> {
+        match self {
+            NetIface::Ipv6(iface) => Some(iface),
+            NetIface::Ip(_) => None,
         }
     }
 }
@@ -0,0 +1,440 @@
+//! Interrupt requests (a port of microps' `intr.c`).
+//!
+//! Device drivers register a handler for an IRQ number with
+//! [`IrqController::request_irq`] and their backends [`raise`](IrqController::raise)
+//! it when there is work, e.g. a received frame waiting in the driver's queue.
+//! Each stack has its own controller (see [`DeviceManager::irqs`]), so IRQ
+//! numbers only need to be unique within a stack.
+//!
+//! A raised IRQ stays pending until it is dispatched: by the dispatcher thread
+//! of [`NetStack::start_irq_thread`], which sleeps on a condvar in place of
+//! microps' `sigwait`, or otherwise by [`NetStack::run_once`]. Several raises
+//! before a dispatch are handled once, like signals.
+//!
+//! Protocol processing has its own [`SoftIrq`]: with
+//! [`NetStack::start_softirq_thread`] received frames only go into the
+//! protocol queues on the device thread, and a worker thread handles them.
+//!
+//! [`DeviceManager::irqs`]: crate::device::DeviceManager::irqs
+//! [`NetStack::start_irq_thread`]: crate::stack::NetStack::start_irq_thread
+//! [`NetStack::run_once`]: crate::stack::NetStack::run_once
+//! [`NetStack::start_softirq_thread`]: crate::stack::NetStack::start_softirq_thread
+
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+
+use crate::error::{NetError, Result};
+
+use crate::device::{Device, DeviceIndex, DeviceManager};
+use crate::lock::StackLock;
+
+/// First IRQ number for devices (`SIGRTMIN + 1` in microps)
+pub const IRQ_BASE: u32 = 35;
+
+/// The IRQ may be shared with other devices that also set this flag
+pub const IRQ_SHARED: u16 = 0x0001;
+
+/// Services an IRQ for the device it was requested for
+pub type IrqHandler = Arc<dyn Fn(u32, &Device) + Send + Sync>;
+
+/// A requested IRQ as [`IrqController::irqs`] reports it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrqInfo {
+    pub irq: u32,
+    pub flags: u16,
+    pub name: String,
+    pub dev: DeviceIndex,
+    /// Times the handler was run
+    pub count: u64,
+}
+
+struct IrqEntry {
+    info: IrqInfo,
+    handler: IrqHandler,
+}
+
+#[derive(Default)]
+struct IrqState {
+    entries: Vec<IrqEntry>,
+    /// Raised and not yet dispatched, in order
+    pending: Vec<u32>,
+    dispatcher: Option<JoinHandle<()>>,
+    stop: bool,
+}
+
+/// IRQ table and pending IRQs of one stack
+#[derive(Default)]
+pub struct IrqController {
+    state: Mutex<IrqState>,
+    raised: Condvar,
+}
+
+impl IrqController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `handler` for `dev` whenever `irq` is raised. An IRQ already
+    /// requested can only be requested again if both set [`IRQ_SHARED`].
+    pub fn request_irq(
+        &self,
+        irq: u32,
+        handler: IrqHandler,
+        flags: u16,
+        name: &str,
+        dev: DeviceIndex,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.entries.iter().find(|entry| entry.info.irq == irq)
+            && (entry.info.flags & IRQ_SHARED == 0 || flags & IRQ_SHARED == 0)
+        {
+            return Err(NetError::AlreadyExists(format!(
+                "IRQ {} requested by {}",
+                irq, entry.info.name
+            )));
+        }
+        tracing::debug!("IRQ registered: irq={}, name={}, dev={}", irq, name, dev);
+        state.entries.push(IrqEntry {
+            info: IrqInfo {
+                irq,
+                flags,
+                name: name.to_string(),
+                dev,
+                count: 0,
+            },
+            handler,
+        });
+        Ok(())
+    }
+
+    /// Mark `irq` pending and wake the dispatcher; callable from any thread
+    pub fn raise(&self, irq: u32) {
+        let mut state = self.state.lock().unwrap();
+        if !state.pending.contains(&irq) {
+            state.pending.push(irq);
+        }
+        self.raised.notify_one();
+    }
+
+    /// Raise `irq` from the transmit path of `dev`. Without a dispatcher
+    /// thread the handlers for `dev` run right away, so a device looped back
+    /// onto its own stack keeps delivering synchronously.
+    pub fn raise_from(&self, irq: u32, dev: &Device) {
+        if self.has_dispatcher() {
+            self.raise(irq);
+            return;
+        }
+        for (_, handler) in self.handlers(irq, Some(dev.index)) {
+            handler(irq, dev);
+        }
+    }
+
+    /// Whether a dispatcher thread is running
+    pub fn has_dispatcher(&self) -> bool {
+        self.state.lock().unwrap().dispatcher.is_some()
+    }
+
+    /// Requested IRQs in the order they were requested
+    pub fn irqs(&self) -> Vec<IrqInfo> {
+        let state = self.state.lock().unwrap();
+        state
+            .entries
+            .iter()
+            .map(|entry| entry.info.clone())
+            .collect()
+    }
+
+    /// Handlers for `irq`, for all devices or only `dev`; counts the runs
+    fn handlers(&self, irq: u32, dev: Option<DeviceIndex>) -> Vec<(DeviceIndex, IrqHandler)> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .entries
+            .iter_mut()
+            .filter(|entry| entry.info.irq == irq && dev.is_none_or(|dev| entry.info.dev == dev))
+            .map(|entry| {
+                entry.info.count += 1;
+                (entry.info.dev, Arc::clone(&entry.handler))
+            })
+            .collect()
+    }
+
+    /// Run the handlers of every pending IRQ; returns how many IRQs were pending
+    pub(crate) fn dispatch(&self, devices: &DeviceManager) -> usize {
+        let pending = std::mem::take(&mut self.state.lock().unwrap().pending);
+        for &irq in &pending {
+            // The table is not locked while handlers run, so they may raise IRQs
+            for (index, handler) in self.handlers(irq, None) {
+                match devices.get(index) {
+                    Some(dev) => handler(irq, dev),
+                    None => tracing::debug!("IRQ {}: device {} is gone", irq, index),
+                }
+            }
+        }
+        pending.len()
+    }
+
+    /// Start a thread dispatching IRQs as they are raised, for the devices of
+    /// `devices`. It stops with [`stop`](Self::stop) or once the devices are gone.
+    pub(crate) fn start(self: &Arc<Self>, devices: Weak<StackLock<DeviceManager>>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.dispatcher.is_some() {
+            return Err(NetError::AlreadyExists("IRQ dispatcher".to_string()));
+        }
+        state.stop = false;
+        let controller = Arc::clone(self);
+        let handle = thread::Builder::new()
+            .name("microps-irq".to_string())
+            .spawn(move || controller.run(devices))?;
+        state.dispatcher = Some(handle);
+        tracing::info!("IRQ dispatcher started");
+        Ok(())
+    }
+
+    fn run(&self, devices: Weak<StackLock<DeviceManager>>) {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                while state.pending.is_empty() && !state.stop {
+                    state = self.raised.wait(state).unwrap();
+                }
+                if state.stop {
+                    return;
+                }
+            }
+            let Some(devices) = devices.upgrade() else {
+                return;
+            };
+            self.dispatch(&devices.read());
+        }
+    }
+
+    /// Stop the dispatcher thread, if any, and wait for it to finish. IRQs
+    /// raised from then on wait for [`NetStack::run_once`](crate::stack::NetStack::run_once).
+    pub fn stop(&self) {
+        let handle = {
+            let mut state = self.state.lock().unwrap();
+            state.stop = true;
+            state.dispatcher.take()
+        };
+        self.raised.notify_all();
+        if let Some(handle) = handle
+            && handle.thread().id() != thread::current().id()
+        {
+            let _ = handle.join();
+            tracing::info!("IRQ dispatcher stopped");
+        }
+    }
+}
+
+#[derive(Default)]
+struct SoftIrqState {
+    raised: bool,
+    worker: Option<JoinHandle<()>>,
+    stop: bool,
+}
+
+/// Deferred protocol processing (microps' `INTR_IRQ_SOFTIRQ`): raised when
+/// a frame is queued, serviced by a worker thread
+#[derive(Default)]
+pub struct SoftIrq {
+    state: Mutex<SoftIrqState>,
+    raised: Condvar,
+}
+
+impl SoftIrq {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wake the worker; callable from any thread
+    pub fn raise(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.worker.is_some() {
+            state.raised = true;
+            self.raised.notify_one();
+        }
+    }
+
+    /// Whether a worker thread is running
+    pub fn has_worker(&self) -> bool {
+        self.state.lock().unwrap().worker.is_some()
+    }
+
+    /// Start a thread calling `work` whenever raised until [`stop`](Self::stop)
+    /// or until `work` returns `None`. `Some(true)` means work is left, so
+    /// `work` runs again right away.
+    pub(crate) fn start(
+        self: &Arc<Self>,
+        work: impl Fn() -> Option<bool> + Send + 'static,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.worker.is_some() {
+            return Err(NetError::AlreadyExists("softirq worker".to_string()));
+        }
+        state.stop = false;
+        // Frames may have been queued before the worker started
+        state.raised = true;
+        let softirq = Arc::clone(self);
+        let handle = thread::Builder::new()
+            .name("microps-softirq".to_string())
+            .spawn(move || {
+                while softirq.wait() {
+                    match work() {
+                        Some(true) => softirq.state.lock().unwrap().raised = true,
+                        Some(false) => {}
+                        None => return,
+                    }
+                }
+            })?;
+        state.worker = Some(handle);
+        tracing::info!("Softirq worker started");
+        Ok(())
+    }
+
+    /// Wait until raised; false if the worker is to stop
+    fn wait(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while !state.raised && !state.stop {
+            state = self.raised.wait(state).unwrap();
+        }
+        state.raised = false;
+        !state.stop
+    }
+
+    /// Stop the worker thread, if any, and wait for it to finish
+    pub fn stop(&self) {
+        let handle = {
+            let mut state = self.state.lock().unwrap();
+            state.stop = true;
+            state.worker.take()
+        };
+        self.raised.notify_all();
+        if let Some(handle) = handle
+            && handle.thread().id() != thread::current().id()
+        {
+            let _ = handle.join();
+            tracing::info!("Softirq worker stopped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crate::protocol::icmp::{self, IcmpType};
+    use crate::protocol::ip::IpAddr;
+    use crate::stack::NetStack;
+
+    #[test]
+    fn test_irq_request_and_dispatch() {
+        let irqs = IrqController::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let handler: IrqHandler = {
+            let runs = Arc::clone(&runs);
+            Arc::new(move |_, _| {
+                runs.fetch_add(1, Ordering::Relaxed);
+            })
+        };
+        let mut devices = DeviceManager::new();
+        let a = devices.register(Device::default()).unwrap();
+        let b = devices.register(Device::default()).unwrap();
+
+        irqs.request_irq(IRQ_BASE, Arc::clone(&handler), 0, "a", a)
+            .unwrap();
+        assert!(
+            irqs.request_irq(IRQ_BASE, Arc::clone(&handler), IRQ_SHARED, "b", b)
+                .is_err()
+        );
+        irqs.request_irq(IRQ_BASE + 1, Arc::clone(&handler), IRQ_SHARED, "a", a)
+            .unwrap();
+        irqs.request_irq(IRQ_BASE + 1, handler, IRQ_SHARED, "b", b)
+            .unwrap();
+
+        // Raises coalesce until dispatched
+        irqs.raise(IRQ_BASE + 1);
+        irqs.raise(IRQ_BASE + 1);
+        assert_eq!(irqs.dispatch(&devices), 1);
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+        assert_eq!(irqs.dispatch(&devices), 0);
+
+        irqs.raise_from(IRQ_BASE + 1, devices.get(b).unwrap());
+        assert_eq!(runs.load(Ordering::Relaxed), 3);
+        let counts: Vec<_> = irqs.irqs().iter().map(|info| info.count).collect();
+        assert_eq!(counts, [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_irq_dispatcher_thread() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        stack.start_irq_thread().unwrap();
+        assert!(stack.start_irq_thread().is_err());
+
+        let loopback = IpAddr::from_str("127.0.0.1").unwrap();
+        stack.ctx().icmp_echo.register(7).unwrap();
+        icmp::output(
+            IcmpType::Echo,
+            0,
+            icmp::echo_values(7, 1),
+            b"irq",
+            loopback,
+            loopback,
+            &stack.ctx(),
+            &stack.devices(),
+        )
+        .unwrap();
+        // The request and the reply are both delivered on the dispatcher thread
+        let reply = stack
+            .ctx()
+            .icmp_echo
+            .recv(7, Some(Duration::from_secs(5)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.data, b"irq");
+        assert!(stack.devices().irqs().irqs()[0].count >= 1);
+
+        stack.shutdown().unwrap();
+        assert!(!stack.devices().irqs().has_dispatcher());
+    }
+
+    #[test]
+    fn test_softirq_worker() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        stack.start_softirq_thread().unwrap();
+        assert!(stack.start_softirq_thread().is_err());
+        assert!(stack.protocols().is_deferred());
+
+        let loopback = IpAddr::from_str("127.0.0.1").unwrap();
+        stack.ctx().icmp_echo.register(9).unwrap();
+        icmp::output(
+            IcmpType::Echo,
+            0,
+            icmp::echo_values(9, 1),
+            b"softirq",
+            loopback,
+            loopback,
+            &stack.ctx(),
+            &stack.devices(),
+        )
+        .unwrap();
+        // Nobody polls the stack: the worker handles the request and the reply
+        let reply = stack
+            .ctx()
+            .icmp_echo
+            .recv(9, Some(Duration::from_secs(5)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.data, b"softirq");
+        assert_eq!(stack.protocols().backlog(), 0);
+
+        stack.shutdown().unwrap();
+        assert!(!stack.protocols().softirq().has_worker());
+    }
+}
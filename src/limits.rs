@@ -0,0 +1,86 @@
+//! Upper bounds on what one stack instance may hold.
+//!
+//! The limits are fixed when the stack is created ([`NetStack::with_limits`]).
+//! Every table that grows at run time checks its bound: registrations and
+//! opens fail once it is reached, and queues drop what arrives while full.
+//!
+//! [`NetStack::with_limits`]: crate::stack::NetStack::with_limits
+
+use crate::device::TX_QUEUE_LEN;
+use crate::error::{NetError, Result};
+use crate::pool::PACKET_BUFFERS;
+use crate::protocol::{RX_QUEUE_LEN, igmp, nat, ndp, raw, tcp, udp};
+use crate::stats::PEER_STATS_CAPACITY_DEFAULT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackLimits {
+    /// Network devices
+    pub devices: usize,
+    /// IP interfaces
+    pub ifaces: usize,
+    /// Routing table entries
+    pub routes: usize,
+    /// UDP control blocks
+    pub udp_sockets: usize,
+    /// TCP control blocks, including listeners and connections not yet accepted
+    pub tcp_sockets: usize,
+    /// Raw IP control blocks
+    pub raw_sockets: usize,
+    /// IPv6 neighbor cache entries; the least recently used is evicted
+    pub neighbors: usize,
+    /// IPv4 multicast group memberships
+    pub multicast_groups: usize,
+    /// NAT translations of a masquerading router
+    pub nat_entries: usize,
+    /// Remote addresses with traffic counters; the least recently seen is evicted
+    pub peers: usize,
+    /// Frames a protocol receive queue holds per device and receiving thread
+    /// in deferred mode
+    pub rx_queue_len: usize,
+    /// Frames a device holds for transmission
+    pub tx_queue_len: usize,
+    /// Datagrams (or echo replies) a socket holds until the application reads them
+    pub socket_queue_len: usize,
+    /// Packet buffers allocated up front and kept for reuse (see [`pool`](crate::pool))
+    pub packet_buffers: usize,
+}
+
+impl Default for StackLimits {
+    fn default() -> Self {
+        Self {
+            devices: 16,
+            ifaces: 16,
+            routes: 256,
+            udp_sockets: udp::UDP_PCB_SIZE,
+            tcp_sockets: tcp::TCP_PCB_SIZE,
+            raw_sockets: raw::RAW_PCB_SIZE,
+            neighbors: ndp::NEIGHBOR_CACHE_SIZE,
+            multicast_groups: igmp::MULTICAST_GROUPS_SIZE,
+            nat_entries: nat::NAT_TABLE_SIZE,
+            peers: PEER_STATS_CAPACITY_DEFAULT,
+            rx_queue_len: RX_QUEUE_LEN,
+            tx_queue_len: TX_QUEUE_LEN,
+            socket_queue_len: 256,
+            packet_buffers: PACKET_BUFFERS,
+        }
+    }
+}
+
+impl StackLimits {
+    /// Fails if a limit would make the stack unusable
+    pub fn validate(&self) -> Result<()> {
+        let limits = [
+            ("devices", self.devices),
+            ("ifaces", self.ifaces),
+            ("routes", self.routes),
+            ("rx_queue_len", self.rx_queue_len),
+            ("tx_queue_len", self.tx_queue_len),
+            ("socket_queue_len", self.socket_queue_len),
+        ];
+        if let Some((name, _)) = limits.iter().find(|(_, value)| *value == 0) {
+            tracing::error!("limit must not be zero: {}", name);
+            return Err(NetError::InvalidInput("limit must not be zero"));
+        }
+        Ok(())
+    }
+}
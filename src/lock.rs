@@ -0,0 +1,192 @@
+//! The lock around each of a stack's managers.
+//!
+//! Frames are delivered synchronously: a thread that holds a stack's read
+//! guard may transmit onto a loopback or veth device and end up reading the
+//! same stack again, nested inside its first guard. `std::sync::RwLock`
+//! makes new readers queue behind a waiting writer, so a writer arriving in
+//! between would deadlock with that thread.
+//!
+//! [`StackLock`] never lets a waiting writer hold up a reader. A writer
+//! sleeps until the readers have left and takes the lock then; every guard
+//! that leaves while a writer waits wakes it. Writers can starve while
+//! readers keep overlapping, which packet processing does not do for long:
+//! blocking socket calls in [`net`](crate::net) wait with the stack unlocked.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering, fence};
+use std::sync::{Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+/// A reader-preferring read-write lock (see the [module](self) docs)
+#[derive(Debug, Default)]
+pub struct StackLock<T> {
+    lock: RwLock<T>,
+    /// Writers sleeping on `left`
+    waiting: AtomicUsize,
+    gate: Mutex<()>,
+    /// Notified when a guard is dropped while a writer waits
+    left: Condvar,
+}
+
+impl<T> StackLock<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            lock: RwLock::new(value),
+            waiting: AtomicUsize::new(0),
+            gate: Mutex::new(()),
+            left: Condvar::new(),
+        }
+    }
+
+    /// Shared access; waits only while a writer holds the lock
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        ReadGuard {
+            // Only `write` below takes the inner lock exclusively, and it never
+            // waits inside it, so this does not queue behind waiting writers
+            guard: Some(self.lock.read().unwrap()),
+            lock: self,
+        }
+    }
+
+    /// Exclusive access, sleeping until every reader has left
+    pub fn write(&self) -> WriteGuard<'_, T> {
+        let mut gate = self.gate.lock().unwrap();
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        // Pairs with the fence in `leave`: either the attempt below sees a
+        // guard gone, or that guard's owner sees this writer waiting
+        fence(Ordering::SeqCst);
+        loop {
+            match self.lock.try_write() {
+                Ok(guard) => {
+                    self.waiting.fetch_sub(1, Ordering::Relaxed);
+                    return WriteGuard {
+                        guard: Some(guard),
+                        lock: self,
+                    };
+                }
+                Err(TryLockError::WouldBlock) => gate = self.left.wait(gate).unwrap(),
+                Err(TryLockError::Poisoned(e)) => panic!("{}", e),
+            }
+        }
+    }
+
+    /// Wake the waiting writers, if any, after a guard is released
+    fn leave(&self) {
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::Relaxed) > 0 {
+            let _gate = self.gate.lock().unwrap();
+            self.left.notify_all();
+        }
+    }
+}
+
+/// Shared access to a [`StackLock`]
+pub struct ReadGuard<'a, T> {
+    /// Taken when dropped, so the lock is released before writers are woken
+    guard: Option<RwLockReadGuard<'a, T>>,
+    lock: &'a StackLock<T>,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().expect("held until dropped")
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.guard = None;
+        self.lock.leave();
+    }
+}
+
+/// Exclusive access to a [`StackLock`]
+pub struct WriteGuard<'a, T> {
+    guard: Option<RwLockWriteGuard<'a, T>>,
+    lock: &'a StackLock<T>,
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().expect("held until dropped")
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().expect("held until dropped")
+    }
+}
+
+impl<T> Drop for WriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.guard = None;
+        self.lock.leave();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_nested_read_while_writer_waits() {
+        let lock = Arc::new(StackLock::new(0));
+        let outer = lock.read();
+
+        let (tx, rx) = mpsc::channel();
+        let writer = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || {
+                *lock.write() += 1;
+                tx.send(()).unwrap();
+            })
+        };
+        while lock.waiting.load(Ordering::Relaxed) == 0 {
+            thread::yield_now();
+        }
+
+        // A frame delivered back into the stack reads it again
+        let inner = lock.read();
+        assert_eq!(*inner, 0);
+        drop(inner);
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        // The writer gets in once the last reader leaves
+        drop(outer);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        writer.join().unwrap();
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn test_writers_take_turns() {
+        let lock = Arc::new(StackLock::new(0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let lock = Arc::clone(&lock);
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let reader = lock.read();
+                        let seen = *reader;
+                        drop(reader);
+                        *lock.write() += 1;
+                        assert!(*lock.read() > seen);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.read(), 4000);
+    }
+}
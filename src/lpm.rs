@@ -0,0 +1,274 @@
+//! Longest prefix match over IPv4 prefixes.
+//!
+//! [`PrefixTrie`] is a binary trie on the address bits, most significant
+//! first: a prefix of length `n` sits `n` levels below the root. A lookup
+//! walks the address down at most 32 levels and keeps the last prefix it
+//! passed, so its cost does not grow with the number of prefixes.
+//!
+//! Nodes are shared between clones: cloning a trie copies only its root, and
+//! an update copies the at most 32 nodes on its path that another clone still
+//! shares. That makes a trie cheap to swap in as a new version of a table
+//! (see [`snapshot`](crate::snapshot)).
+
+use std::sync::Arc;
+
+/// Value stored under `prefix`/`len`, and the one or two subtrees below
+#[derive(Debug, Clone)]
+struct Node<V> {
+    value: Option<V>,
+    children: [Option<Arc<Node<V>>>; 2],
+}
+
+impl<V> Default for Node<V> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            children: [None, None],
+        }
+    }
+}
+
+impl<V> Node<V> {
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.iter().all(Option::is_none)
+    }
+}
+
+impl<V: Clone> Node<V> {
+    /// Take the value `depth` levels down along `prefix`, pruning the nodes
+    /// that are left with nothing below them
+    fn remove(&mut self, prefix: u32, depth: u8, len: u8) -> Option<V> {
+        if depth == len {
+            return self.value.take();
+        }
+        let slot = &mut self.children[bit(prefix, depth)];
+        let child = Arc::make_mut(slot.as_mut()?);
+        let value = child.remove(prefix, depth + 1, len);
+        if child.is_empty() {
+            *slot = None;
+        }
+        value
+    }
+}
+
+/// Bit `depth` of `addr`, counting from the most significant
+fn bit(addr: u32, depth: u8) -> usize {
+    (addr >> (31 - depth)) as usize & 1
+}
+
+/// Values keyed on IPv4 prefixes, given as host-order `u32`s and a length
+/// of 0 to 32. Bits past the length are ignored.
+#[derive(Debug, Clone)]
+pub struct PrefixTrie<V> {
+    root: Node<V>,
+    len: usize,
+}
+
+impl<V> Default for PrefixTrie<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> PrefixTrie<V> {
+    pub fn new() -> Self {
+        Self {
+            root: Node::default(),
+            len: 0,
+        }
+    }
+
+    /// The value stored under exactly `prefix`/`len`
+    pub fn get(&self, prefix: u32, len: u8) -> Option<&V> {
+        if len > 32 {
+            return None;
+        }
+        let mut node = &self.root;
+        for depth in 0..len {
+            node = node.children[bit(prefix, depth)].as_deref()?;
+        }
+        node.value.as_ref()
+    }
+
+    /// The value of the longest prefix `addr` falls in
+    pub fn lookup(&self, addr: u32) -> Option<&V> {
+        let mut node = &self.root;
+        let mut best = node.value.as_ref();
+        for depth in 0..32 {
+            let Some(child) = node.children[bit(addr, depth)].as_deref() else {
+                break;
+            };
+            node = child;
+            best = node.value.as_ref().or(best);
+        }
+        best
+    }
+
+    /// Every value, shorter prefixes before the longer ones inside them and
+    /// otherwise in address order
+    pub fn iter(&self) -> impl Iterator<Item = &V> {
+        let mut stack = vec![&self.root];
+        std::iter::from_fn(move || {
+            while let Some(node) = stack.pop() {
+                stack.extend(node.children.iter().rev().flatten().map(|c| &**c));
+                if let Some(value) = &node.value {
+                    return Some(value);
+                }
+            }
+            None
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<V: Clone> PrefixTrie<V> {
+    /// Store `value` under `prefix`/`len`, returning the value it replaces
+    pub fn insert(&mut self, prefix: u32, len: u8, value: V) -> Option<V> {
+        assert!(len <= 32, "prefix length {} is longer than 32", len);
+        let mut node = &mut self.root;
+        for depth in 0..len {
+            let child = node.children[bit(prefix, depth)].get_or_insert_with(Default::default);
+            node = Arc::make_mut(child);
+        }
+        let old = node.value.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Take the value stored under exactly `prefix`/`len`
+    pub fn remove(&mut self, prefix: u32, len: u8) -> Option<V> {
+        // Not copying the path to a prefix that is not there
+        self.get(prefix, len)?;
+        let value = self.root.remove(prefix, 0, len);
+        if value.is_some() {
+            self.len -= 1;
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn net(a: u8, b: u8, c: u8, d: u8) -> u32 {
+        u32::from_be_bytes([a, b, c, d])
+    }
+
+    #[test]
+    fn test_lookup_longest_prefix() {
+        let mut trie = PrefixTrie::new();
+        trie.insert(0, 0, "default");
+        trie.insert(net(10, 0, 0, 0), 8, "10/8");
+        trie.insert(net(10, 1, 0, 0), 16, "10.1/16");
+        trie.insert(net(10, 1, 2, 3), 32, "host");
+
+        assert_eq!(trie.lookup(net(10, 1, 2, 3)), Some(&"host"));
+        assert_eq!(trie.lookup(net(10, 1, 2, 4)), Some(&"10.1/16"));
+        assert_eq!(trie.lookup(net(10, 2, 0, 1)), Some(&"10/8"));
+        assert_eq!(trie.lookup(net(192, 0, 2, 1)), Some(&"default"));
+        assert_eq!(trie.get(net(10, 1, 0, 0), 16), Some(&"10.1/16"));
+        assert_eq!(trie.get(net(10, 1, 0, 0), 17), None);
+
+        // Shorter prefixes first, then in address order
+        let all: Vec<_> = trie.iter().copied().collect();
+        assert_eq!(all, ["default", "10/8", "10.1/16", "host"]);
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut trie = PrefixTrie::new();
+        assert_eq!(trie.insert(net(192, 0, 2, 0), 24, 1), None);
+        assert_eq!(trie.insert(net(192, 0, 2, 0), 24, 2), Some(1));
+        trie.insert(net(192, 0, 2, 128), 25, 3);
+        assert_eq!(trie.len(), 2);
+
+        assert_eq!(trie.remove(net(192, 0, 2, 0), 25), None);
+        assert_eq!(trie.remove(net(192, 0, 2, 128), 25), Some(3));
+        assert_eq!(trie.lookup(net(192, 0, 2, 200)), Some(&2));
+        assert_eq!(trie.remove(net(192, 0, 2, 0), 24), Some(2));
+        assert!(trie.is_empty());
+        // Nothing is left hanging off the root
+        assert!(trie.root.is_empty());
+        assert_eq!(trie.lookup(net(192, 0, 2, 200)), None);
+    }
+
+    #[test]
+    fn test_clone_is_unaffected_by_updates() {
+        let mut trie = PrefixTrie::new();
+        trie.insert(net(10, 0, 0, 0), 8, "10/8");
+        trie.insert(net(192, 0, 2, 0), 24, "192.0.2/24");
+        let old = trie.clone();
+
+        // Only the path to the new prefix is copied
+        trie.insert(net(10, 1, 0, 0), 16, "10.1/16");
+        let [Some(old_low), Some(old_high)] = &old.root.children else {
+            unreachable!()
+        };
+        let [Some(low), Some(high)] = &trie.root.children else {
+            unreachable!()
+        };
+        assert!(!Arc::ptr_eq(old_low, low));
+        assert!(Arc::ptr_eq(old_high, high));
+
+        trie.remove(net(192, 0, 2, 0), 24);
+        assert_eq!(old.lookup(net(10, 1, 2, 3)), Some(&"10/8"));
+        assert_eq!(old.lookup(net(192, 0, 2, 1)), Some(&"192.0.2/24"));
+        assert_eq!(old.len(), 2);
+        assert_eq!(trie.lookup(net(10, 1, 2, 3)), Some(&"10.1/16"));
+        assert_eq!(trie.lookup(net(192, 0, 2, 1)), None);
+        assert_eq!(trie.len(), 2);
+    }
+
+    fn mask(len: u8) -> u32 {
+        u32::MAX.checked_shl(32 - len as u32).unwrap_or(0)
+    }
+
+    proptest! {
+        #[test]
+        fn test_lookup_matches_linear_scan(
+            prefixes in vec((any::<u32>(), 0u8..=32), 0..64),
+            removed in vec(any::<prop::sample::Index>(), 0..16),
+            addrs in vec(any::<u32>(), 32),
+        ) {
+            let mut trie = PrefixTrie::new();
+            let mut list: Vec<(u32, u8)> = Vec::new();
+            for (prefix, len) in prefixes {
+                let prefix = prefix & mask(len);
+                trie.insert(prefix, len, (prefix, len));
+                if !list.contains(&(prefix, len)) {
+                    list.push((prefix, len));
+                }
+            }
+            for index in removed {
+                if list.is_empty() {
+                    break;
+                }
+                let (prefix, len) = list.remove(index.index(list.len()));
+                prop_assert_eq!(trie.remove(prefix, len), Some((prefix, len)));
+            }
+            prop_assert_eq!(trie.len(), list.len());
+            // Addresses inside the prefixes as well as random ones
+            let inside = list.iter().map(|&(prefix, len)| prefix | (!mask(len) & 0x5a5a_5a5a));
+            for addr in addrs.into_iter().chain(inside) {
+                let expected = list
+                    .iter()
+                    .filter(|&&(prefix, len)| addr & mask(len) == prefix)
+                    .max_by_key(|&&(_, len)| len);
+                prop_assert_eq!(trie.lookup(addr), expected);
+            }
+        }
+    }
+}
@@ -1,83 +1,180 @@
-pub mod context;
-pub mod device;
-pub mod iface;
-pub mod protocol;
-pub mod util;
-
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 
-use crate::context::ProtocolContexts;
-use crate::device::loopback::OutputCallback;
-use crate::device::{DeviceIndex, DeviceManager};
-use crate::protocol::{
-    ProtocolManager,
-    ip::{self, IpProtocol},
+use microps::builder::loopback;
+use microps::capture::Capture;
+use microps::control::ControlServer;
+use microps::event::NetEvent;
+use microps::metrics::Exporter;
+use microps::persist;
+use microps::protocol::{
+    icmp::{self, IcmpType},
+    ip,
 };
+use microps::stack::NetStack;
+use microps::tftp::{TFTP_PORT, TftpOptions, TftpServer};
+use microps::{config, pktlog, services, trace, util};
 
 const MAIN_LOOP_INTERVAL: Duration = Duration::from_secs(1);
 
+/// When set, the stack's devices, addresses, routes and NAT rules are read
+/// from this file (see `microps::config`) instead of using a lone loopback
+const CONFIG_FILE_ENV: &str = "MICROPS_CONFIG_FILE";
+
+/// When set, learned routes are saved to this file on shutdown and restored on start
+const STATE_FILE_ENV: &str = "MICROPS_STATE_FILE";
+
+/// When set, a binary event trace is written to this file (see `microps-trace`)
+const TRACE_FILE_ENV: &str = "MICROPS_TRACE_FILE";
+
+/// When set, the frames of every device are captured to this pcapng file
+const PCAP_FILE_ENV: &str = "MICROPS_PCAP_FILE";
+
+/// When set, the stack's counters are served for Prometheus at
+/// `http://<addr>/metrics` (`127.0.0.1:9100`, say)
+const METRICS_ADDR_ENV: &str = "MICROPS_METRICS_ADDR";
+
+/// Which frames the packet log reports and at what level, e.g.
+/// `proto=tcp port=80 level=info` or `off` (see `microps::pktlog`)
+const PKTLOG_ENV: &str = "MICROPS_PKTLOG";
+
+/// When set, the running stack accepts commands on a Unix domain socket at
+/// this path (see `microps::control`)
+const CONTROL_SOCKET_ENV: &str = "MICROPS_CONTROL_SOCKET";
+
+/// When set, service names in this `/etc/services`-style file are added to
+/// the built-in ones
+const SERVICES_FILE_ENV: &str = "MICROPS_SERVICES_FILE";
+
+/// When set, the TCP states each connection walked through are written to this
+/// file on shutdown: Graphviz for `.dot`/`.gv`, Markdown with Mermaid otherwise
+const WALK_FILE_ENV: &str = "MICROPS_WALK_FILE";
+
+/// When set, a TFTP server on port 69 serves (and accepts new) files in this directory
+const TFTP_ROOT_ENV: &str = "MICROPS_TFTP_ROOT";
+
 const TEST_ICMP_PAYLOAD: &[u8] = &[
     0x08, 0x00, 0x35, 0x64, 0x00, 0x80, 0x00, 0x01, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38,
     0x39, 0x30, 0x21, 0x40, 0x23, 0x24, 0x25, 0x5e, 0x26, 0x2a, 0x28, 0x29,
 ];
 
-type SharedDeviceManager = Rc<RefCell<DeviceManager>>;
-type SharedProtocolManager = Rc<RefCell<ProtocolManager>>;
-type SharedProtocolContexts = Rc<RefCell<ProtocolContexts>>;
-
 struct App {
-    devices: SharedDeviceManager,
-    protocols: SharedProtocolManager,
-    ctx: SharedProtocolContexts,
+    stack: NetStack,
     terminate: Arc<AtomicBool>,
-    loopback_index: DeviceIndex,
+    state_file: Option<PathBuf>,
+    walk_file: Option<PathBuf>,
+    tftp_root: Option<PathBuf>,
+    capture: Option<Arc<Capture>>,
+    _metrics: Option<Exporter>,
+    _control: Option<ControlServer>,
+    echo_seq: Cell<u16>,
 }
 
 impl App {
     fn new() -> Result<Self> {
         let terminate = Arc::new(AtomicBool::new(false));
-        let devices = Rc::new(RefCell::new(DeviceManager::new()));
-        let protocols = Rc::new(RefCell::new(ProtocolManager::new()));
-        let ctx = Rc::new(RefCell::new(ProtocolContexts::new()));
+        let builder = match std::env::var_os(CONFIG_FILE_ENV) {
+            Some(path) => config::load(Path::new(&path))?,
+            None => NetStack::builder().device(loopback()),
+        };
+        let stack = builder
+            .irq_thread()
+            .softirq_thread()
+            .timer_thread()
+            .build()?;
 
         Self::setup_signal_handler(Arc::clone(&terminate))?;
 
-        protocols
-            .borrow_mut()
-            .init()
-            .context("Failed to initialize protocols")?;
+        let state_file = std::env::var_os(STATE_FILE_ENV).map(PathBuf::from);
+        if let Some(path) = &state_file {
+            persist::load(path, &mut stack.ctx_mut(), persist::STATE_MAX_AGE_DEFAULT)
+                .context("Failed to restore saved state")?;
+        }
+
+        if let Some(path) = std::env::var_os(TRACE_FILE_ENV) {
+            let file = std::fs::File::create(&path).context("Failed to create trace file")?;
+            trace::TRACE.start(Box::new(file))?;
+        }
 
-        let loopback_index = Self::setup_loopback(&devices, &protocols, &ctx)?;
+        let capture = match std::env::var_os(PCAP_FILE_ENV) {
+            Some(path) => {
+                let capture = Arc::new(Capture::create(&path)?);
+                for dev in stack.devices_mut().iter_mut() {
+                    dev.attach_capture(Arc::clone(&capture))?;
+                }
+                Some(capture)
+            }
+            None => None,
+        };
 
-        devices
-            .borrow_mut()
-            .run()
-            .context("Failed to start devices")?;
+        let metrics = match std::env::var(METRICS_ADDR_ENV) {
+            Ok(addr) => Some(stack.serve_metrics(addr.as_str())?),
+            Err(_) => None,
+        };
+
+        let control = match std::env::var_os(CONTROL_SOCKET_ENV) {
+            Some(path) => Some(stack.serve_control(path)?),
+            None => None,
+        };
+
+        if let Some(path) = std::env::var_os(SERVICES_FILE_ENV) {
+            services::SERVICES.load(Path::new(&path))?;
+        }
+
+        let walk_file = std::env::var_os(WALK_FILE_ENV).map(PathBuf::from);
+        if walk_file.is_some() {
+            stack.ctx().tcp.record_walks(true);
+        }
+
+        let tftp_root = std::env::var_os(TFTP_ROOT_ENV).map(PathBuf::from);
 
         Ok(Self {
-            devices,
-            protocols,
-            ctx,
+            stack,
             terminate,
-            loopback_index,
+            state_file,
+            walk_file,
+            tftp_root,
+            capture,
+            _metrics: metrics,
+            _control: control,
+            echo_seq: Cell::new(0),
         })
     }
 
     fn run(&self) -> Result<()> {
+        let mut tftp = match &self.tftp_root {
+            Some(root) => Some(
+                TftpServer::bind(
+                    &self.stack,
+                    format!("0.0.0.0:{}", TFTP_PORT),
+                    root,
+                    TftpOptions::default(),
+                )
+                .context("Failed to start TFTP server")?,
+            ),
+            None => None,
+        };
+
         tracing::info!("Application started. Press Ctrl+C to exit.");
 
         while !self.terminate.load(Ordering::SeqCst) {
             self.send_test_packet()?;
+            self.stack.run_once();
+            if let Some(tftp) = &mut tftp {
+                tftp.poll()?;
+            }
             std::thread::sleep(MAIN_LOOP_INTERVAL);
         }
 
         tracing::info!("Shutting down...");
+        // Wake anything still blocked on the stack
+        self.stack.raise_event(NetEvent::Interrupt);
         Ok(())
     }
 
@@ -88,41 +185,20 @@ impl App {
         .context("Failed to set signal handler")
     }
 
-    fn setup_loopback(
-        devices: &SharedDeviceManager,
-        protocols: &SharedProtocolManager,
-        ctx: &SharedProtocolContexts,
-    ) -> Result<DeviceIndex> {
-        let protocols_for_cb = Rc::clone(protocols);
-        let ctx_for_cb = Rc::clone(ctx);
-
-        let callback: OutputCallback = Rc::new(move |type_, data, dev| {
-            let protocols = protocols_for_cb.borrow();
-            let ctx = ctx_for_cb.borrow();
-            protocols.dispatch(type_, data, dev, &ctx);
-        });
-
-        let index = device::loopback::init(&mut devices.borrow_mut(), callback)
-            .context("Failed to initialize loopback device")?;
-
-        // Register IP interface using single API (registers on both device and global registry)
-        if let Some(dev) = devices.borrow_mut().get_mut(index) {
-            ip::register_iface(dev, "127.0.0.1", "255.0.0.0", &mut ctx.borrow_mut())
-                .context("Failed to register IP interface")?;
-        }
-
-        Ok(index)
-    }
-
     fn send_test_packet(&self) -> Result<()> {
         let src = ip::IpAddr::from_str("127.0.0.1")?;
         let dst = ip::IpAddr::from_str("127.0.0.1")?;
-        let devices = self.devices.borrow();
-        let ctx = self.ctx.borrow();
+        let devices = self.stack.devices();
+        let ctx = self.stack.ctx();
 
-        ip::ip_output(
-            IpProtocol::Icmp,
-            TEST_ICMP_PAYLOAD,
+        let seq = self.echo_seq.get().wrapping_add(1);
+        self.echo_seq.set(seq);
+
+        icmp::output(
+            IcmpType::Echo,
+            0,
+            icmp::echo_values(std::process::id() as u16, seq),
+            &TEST_ICMP_PAYLOAD[icmp::ICMP_HDR_SIZE..],
             src,
             dst,
             &ctx,
@@ -130,13 +206,51 @@ impl App {
         )?;
         Ok(())
     }
+
+    fn save_walks(&self, path: &Path) -> Result<()> {
+        let walks = self.stack.ctx().tcp.walks();
+        let graphviz = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("dot" | "gv")
+        );
+        let out: String = walks
+            .iter()
+            .map(|walk| {
+                if graphviz {
+                    walk.to_dot()
+                } else {
+                    format!("```mermaid\n{}```\n\n", walk.to_mermaid())
+                }
+            })
+            .collect();
+        std::fs::write(path, out)?;
+        Ok(())
+    }
 }
 
 impl Drop for App {
     fn drop(&mut self) {
-        if let Err(e) = self.devices.borrow_mut().shutdown() {
+        if let Some(path) = &self.state_file
+            && let Err(e) = persist::save(path, &self.stack.ctx().ip_routes)
+        {
+            tracing::error!("Saving state failed: {:?}", e);
+        }
+        if let Err(e) = self.stack.shutdown() {
             tracing::error!("Shutdown failed: {:?}", e);
         }
+        if let Some(path) = &self.walk_file
+            && let Err(e) = self.save_walks(path)
+        {
+            tracing::error!("Writing state walks failed: {:?}", e);
+        }
+        if let Some(capture) = &self.capture
+            && let Err(e) = capture.flush()
+        {
+            tracing::error!("Writing capture failed: {:?}", e);
+        }
+        if let Err(e) = trace::TRACE.stop() {
+            tracing::error!("Writing trace failed: {:?}", e);
+        }
     }
 }
 
@@ -156,4 +270,23 @@ fn init_logging() {
         .with_line_number(true)
         .with_target(false)
         .init();
+
+    let env_u64 = |key: &str, default: u64| {
+        std::env::var(key)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    };
+    match std::env::var(PKTLOG_ENV).as_deref() {
+        Ok("off") => pktlog::set_filter(None),
+        Ok(filter) => match filter.parse() {
+            Ok(filter) => pktlog::set_filter(Some(filter)),
+            Err(e) => tracing::error!("Ignoring {}: {}", PKTLOG_ENV, e),
+        },
+        Err(_) => {}
+    }
+    util::set_log_sampling(
+        env_u64("MICROPS_LOG_SAMPLE", 1),
+        env_u64("MICROPS_LOG_RATE", 0),
+    );
 }
@@ -0,0 +1,421 @@
+//! Counters in the Prometheus text format.
+//!
+//! [`gather`] reads the stack's counters into a [`Registry`]:
+//!
+//! - frames and bytes each device received and transmitted;
+//! - packets and bytes per protocol, in each direction (see [`ProtocolStats`]);
+//! - drops by reason, and checksum errors by protocol;
+//! - TCP retransmissions.
+//!
+//! For soak tests, an [`Exporter`] serves the registry at `/metrics` over HTTP
+//! for Prometheus to scrape. It listens on a host socket, not through the
+//! stack, so scraping adds no traffic to what is being measured:
+//!
+//! ```no_run
+//! # use microps::stack::NetStack;
+//! let stack = NetStack::new()?;
+//! let exporter = stack.serve_metrics("127.0.0.1:9100")?;
+//! // curl http://127.0.0.1:9100/metrics
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! [`ProtocolStats`]: crate::stats::ProtocolStats
+
+use std::fmt::Write as _;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::context::ProtocolContexts;
+use crate::device::DeviceManager;
+use crate::drop::DropReason;
+
+/// How often the exporter checks whether it is to stop while idle
+const EXPORTER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// A scrape that has not sent its request by then is dropped
+const EXPORTER_READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// Requests are a request line and a few headers; anything longer is refused
+const EXPORTER_REQUEST_SIZE_MAX: usize = 8192;
+
+/// Counters of one name and their samples, one per set of labels
+struct Family {
+    name: String,
+    help: &'static str,
+    samples: Vec<(String, u64)>,
+}
+
+/// Counter samples ready to be rendered
+#[derive(Default)]
+pub struct Registry {
+    families: Vec<Family>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a sample of counter `name`; the first sample of a name sets its help
+    pub fn counter(&mut self, name: &str, help: &'static str, labels: &[(&str, &str)], value: u64) {
+        let labels = format_labels(labels);
+        match self.families.iter_mut().find(|f| f.name == name) {
+            Some(family) => family.samples.push((labels, value)),
+            None => self.families.push(Family {
+                name: name.to_string(),
+                help,
+                samples: vec![(labels, value)],
+            }),
+        }
+    }
+
+    /// Value of the sample of `name` with exactly `labels`
+    pub fn get(&self, name: &str, labels: &[(&str, &str)]) -> Option<u64> {
+        let labels = format_labels(labels);
+        self.families
+            .iter()
+            .find(|f| f.name == name)?
+            .samples
+            .iter()
+            .find(|(l, _)| *l == labels)
+            .map(|(_, value)| *value)
+    }
+
+    /// Text exposition format 0.0.4
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for family in &self.families {
+            let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+            let _ = writeln!(out, "# TYPE {} counter", family.name);
+            for (labels, value) in &family.samples {
+                let _ = writeln!(out, "{}{} {}", family.name, labels, value);
+            }
+        }
+        out
+    }
+}
+
+/// `{a="1",b="2"}`, or nothing without labels
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// `IpChecksum` as `ip_checksum`
+fn snake_case(reason: DropReason) -> String {
+    let mut out = String::new();
+    for (i, c) in format!("{:?}", reason).chars().enumerate() {
+        if c.is_ascii_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Protocol whose checksum failed, for the checksum drop reasons
+fn checksum_protocol(reason: DropReason) -> Option<&'static str> {
+    match reason {
+        DropReason::IpChecksum => Some("ip"),
+        DropReason::IcmpChecksum => Some("icmp"),
+        DropReason::IgmpChecksum => Some("igmp"),
+        DropReason::UdpChecksum => Some("udp"),
+        DropReason::TcpChecksum => Some("tcp"),
+        _ => None,
+    }
+}
+
+/// Read the counters of a stack
+pub fn gather(devices: &DeviceManager, ctx: &ProtocolContexts) -> Registry {
+    let mut registry = Registry::new();
+
+    for dev in devices.iter() {
+        let name = dev.name_string();
+        let labels = [("device", name.as_str())];
+        let stats = &dev.stats;
+        registry.counter(
+            "microps_device_receive_packets_total",
+            "Frames received by the device",
+            &labels,
+            stats.rx.packets(),
+        );
+        registry.counter(
+            "microps_device_receive_bytes_total",
+            "Bytes of the frames received by the device",
+            &labels,
+            stats.rx.bytes(),
+        );
+        registry.counter(
+            "microps_device_transmit_packets_total",
+            "Frames transmitted by the device",
+            &labels,
+            stats.tx.packets(),
+        );
+        registry.counter(
+            "microps_device_transmit_bytes_total",
+            "Bytes of the frames transmitted by the device",
+            &labels,
+            stats.tx.bytes(),
+        );
+    }
+
+    for (protocol, traffic) in ctx.stats.iter() {
+        for (direction, counter) in [("rx", &traffic.rx), ("tx", &traffic.tx)] {
+            let labels = [("protocol", protocol), ("direction", direction)];
+            registry.counter(
+                "microps_protocol_packets_total",
+                "Packets per protocol and direction",
+                &labels,
+                counter.packets(),
+            );
+            registry.counter(
+                "microps_protocol_bytes_total",
+                "Bytes per protocol and direction (the payload, for transport protocols)",
+                &labels,
+                counter.bytes(),
+            );
+        }
+    }
+
+    let drops = ctx.drops.counts();
+    for (reason, count) in &drops {
+        registry.counter(
+            "microps_drops_total",
+            "Received packets dropped, by reason",
+            &[("reason", &snake_case(*reason))],
+            *count,
+        );
+    }
+    for protocol in ["ip", "icmp", "igmp", "udp", "tcp"] {
+        let count = drops
+            .iter()
+            .filter(|(reason, _)| checksum_protocol(*reason) == Some(protocol))
+            .map(|(_, count)| count)
+            .sum();
+        registry.counter(
+            "microps_checksum_errors_total",
+            "Received packets dropped for a bad checksum",
+            &[("protocol", protocol)],
+            count,
+        );
+    }
+
+    registry.counter(
+        "microps_tcp_retransmissions_total",
+        "TCP segments sent again",
+        &[],
+        ctx.tcp.retransmissions(),
+    );
+
+    registry
+}
+
+/// HTTP server for a [`Registry`], on a thread of its own
+pub struct Exporter {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Exporter {
+    /// Serve what `gather` returns at `http://addr/metrics` until dropped;
+    /// `None` (the stack is gone) is answered with 503
+    pub fn start(
+        addr: impl ToSocketAddrs,
+        gather: impl Fn() -> Option<Registry> + Send + 'static,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr).context("Failed to bind metrics exporter")?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::Builder::new()
+            .name("microps-metrics".to_string())
+            .spawn(move || {
+                while !stopped.load(Ordering::Acquire) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            if let Err(e) = serve(stream, &gather) {
+                                tracing::debug!("metrics: {}", e);
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            thread::sleep(EXPORTER_POLL_INTERVAL);
+                        }
+                        Err(e) => tracing::error!("metrics: accept failed: {}", e),
+                    }
+                }
+            })
+            .context("Failed to start metrics exporter thread")?;
+        tracing::info!("Metrics exporter listening on http://{}/metrics", addr);
+        Ok(Self {
+            addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Answer one request and close the connection
+fn serve(mut stream: TcpStream, gather: &impl Fn() -> Option<Registry>) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(EXPORTER_READ_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > EXPORTER_REQUEST_SIZE_MAX {
+            anyhow::bail!("request too long");
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            anyhow::bail!("connection closed before the request ended");
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|&b| b == b' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => match gather() {
+            Some(registry) => ("200 OK", registry.render()),
+            None => ("503 Service Unavailable", "stack is gone\n".to_string()),
+        },
+        (Some(b"GET"), _) => ("404 Not Found", "metrics are at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::device::DeviceIndex;
+    use crate::protocol::icmp::{self, IcmpType};
+    use crate::protocol::ip::{IpAddr, IpProtocol};
+    use crate::stack::NetStack;
+
+    #[test]
+    fn test_registry_render() {
+        let mut registry = Registry::new();
+        registry.counter("a_total", "A", &[("x", "1")], 3);
+        registry.counter("a_total", "ignored", &[("x", "say \"hi\"\n")], 4);
+        registry.counter("b_total", "B", &[], 5);
+        assert_eq!(
+            registry.render(),
+            "# HELP a_total A\n# TYPE a_total counter\na_total{x=\"1\"} 3\n\
+             a_total{x=\"say \\\"hi\\\"\\n\"} 4\n\
+             # HELP b_total B\n# TYPE b_total counter\nb_total 5\n"
+        );
+        assert_eq!(registry.get("a_total", &[("x", "1")]), Some(3));
+        assert_eq!(registry.get("b_total", &[]), Some(5));
+        assert_eq!(registry.get("b_total", &[("x", "1")]), None);
+        assert_eq!(snake_case(DropReason::IpChecksum), "ip_checksum");
+    }
+
+    #[test]
+    fn test_gather_and_export() {
+        let stack = NetStack::builder()
+            .device(crate::builder::loopback())
+            .build()
+            .unwrap();
+        let lo = IpAddr::from_str("127.0.0.1").unwrap();
+        let echo = icmp::build(IcmpType::Echo, 0, 0x0001_0001, b"ping");
+        crate::protocol::ip::ip_output(
+            IpProtocol::Icmp,
+            &echo,
+            lo,
+            lo,
+            &stack.ctx(),
+            &stack.devices(),
+        )
+        .unwrap();
+        stack.run_once();
+
+        let registry = stack.metrics();
+        let icmp_tx = [("protocol", "icmp"), ("direction", "tx")];
+        // The request and the reply
+        assert_eq!(
+            registry.get("microps_protocol_packets_total", &icmp_tx),
+            Some(2)
+        );
+        assert_eq!(
+            registry.get("microps_protocol_bytes_total", &icmp_tx),
+            Some(2 * echo.len() as u64)
+        );
+        let name = stack.devices().get(DeviceIndex(0)).unwrap().name_string();
+        assert_eq!(
+            registry.get(
+                "microps_device_transmit_packets_total",
+                &[("device", &name)]
+            ),
+            Some(2)
+        );
+        assert_eq!(
+            registry.get("microps_checksum_errors_total", &[("protocol", "tcp")]),
+            Some(0)
+        );
+        assert_eq!(
+            registry.get("microps_tcp_retransmissions_total", &[]),
+            Some(0)
+        );
+
+        let exporter = stack.serve_metrics("127.0.0.1:0").unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(exporter.local_addr()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP microps_device_receive_packets_total"));
+        assert!(
+            response
+                .contains("microps_protocol_packets_total{protocol=\"icmp\",direction=\"tx\"} 2\n")
+        );
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,834 @@
+//! `std::net`-style sockets backed by a [`NetStack`].
+//!
+//! [`TcpListener`], [`TcpStream`] and [`UdpSocket`] follow the standard
+//! library's types method for method (constructors take the stack as their
+//! first argument), so code written against `std::net` ports over with few
+//! changes. They wrap the descriptors of [`crate::socket`] and close them when
+//! dropped.
+//!
+//! [`AsyncTcpListener`], [`AsyncTcpStream`] and [`AsyncUdpSocket`] are their
+//! `async` counterparts for use from any executor: a call that would block
+//! registers the task's waker with the control block and returns
+//! `Poll::Pending`, and the protocol input path wakes the task when the control
+//! block changes. Something still has to drive the stack (its devices and
+//! [`NetStack::run_once`]); the futures only react to it.
+//!
+//! The blocking types wait the same way: they try the call without waiting,
+//! and while it would block they sleep on [`READINESS`] with the stack's
+//! guards released. A thread blocked in `accept`, `read` or `recv_from` so
+//! never holds up [`NetStack::ctx_mut`] or [`NetStack::devices_mut`].
+
+use std::future::poll_fn;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::context::ProtocolContexts;
+use crate::device::DeviceManager;
+use crate::error::NetError;
+use crate::platform::Instant;
+use crate::protocol::READINESS;
+use crate::protocol::ip::IpEndpoint;
+use crate::protocol::tcp::Shutdown;
+use crate::services;
+use crate::socket::{self, SocketFd, SocketType};
+use crate::stack::NetStack;
+
+/// Something that names an endpoint, like [`std::net::ToSocketAddrs`]
+pub trait ToEndpoint {
+    fn to_endpoint(&self) -> io::Result<IpEndpoint>;
+}
+
+impl ToEndpoint for IpEndpoint {
+    fn to_endpoint(&self) -> io::Result<IpEndpoint> {
+        Ok(*self)
+    }
+}
+
+impl ToEndpoint for SocketAddrV4 {
+    fn to_endpoint(&self) -> io::Result<IpEndpoint> {
+        Ok((*self).into())
+    }
+}
+
+/// `addr:port`, `addr:service` or `service://addr[:port]`, see
+/// [`services::resolve`]
+impl ToEndpoint for str {
+    fn to_endpoint(&self) -> io::Result<IpEndpoint> {
+        services::resolve(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+impl ToEndpoint for String {
+    fn to_endpoint(&self) -> io::Result<IpEndpoint> {
+        self.as_str().to_endpoint()
+    }
+}
+
+impl<T: ToEndpoint + ?Sized> ToEndpoint for &T {
+    fn to_endpoint(&self) -> io::Result<IpEndpoint> {
+        (**self).to_endpoint()
+    }
+}
+
+fn io_error(e: NetError) -> io::Error {
+    io::Error::new(e.kind(), e)
+}
+
+/// Run `op` on `fd` the way a blocking call would, for as long as the
+/// socket's timeout for a receive (`write` false) or send allows. `op` is
+/// given a zero timeout; between tries the stack is unlocked.
+fn blocking<T>(
+    stack: &NetStack,
+    fd: SocketFd,
+    write: bool,
+    mut op: impl FnMut(Option<Duration>, &ProtocolContexts, &DeviceManager) -> Result<T, NetError>,
+) -> io::Result<T> {
+    let (timeout, interrupts) = {
+        let ctx = stack.ctx();
+        let timeout = socket::wait_time(fd, write, &ctx).map_err(io_error)?;
+        (timeout, socket::interrupts(&ctx))
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        // Read the generation first so that a change while trying is not missed
+        let seen = READINESS.generation();
+        {
+            let (ctx, devices) = (stack.ctx(), stack.devices());
+            match op(Some(Duration::ZERO), &ctx, &devices) {
+                Err(NetError::WouldBlock) if timeout != Some(Duration::ZERO) => {}
+                result => return result.map_err(io_error),
+            }
+            if socket::interrupts(&ctx) != interrupts {
+                return Err(io_error(NetError::Interrupted));
+            }
+        }
+        if !READINESS.wait(seen, deadline) {
+            return Err(io_error(NetError::TimedOut));
+        }
+    }
+}
+
+/// Open a socket of `type_` and run `setup` on it, closing it again on failure
+fn open(
+    stack: &NetStack,
+    type_: SocketType,
+    setup: impl FnOnce(SocketFd) -> Result<(), NetError>,
+) -> io::Result<SocketFd> {
+    let fd = socket::socket(type_, &stack.ctx()).map_err(io_error)?;
+    if let Err(e) = setup(fd) {
+        let _ = socket::close(fd, &stack.ctx(), &stack.devices());
+        return Err(io_error(e));
+    }
+    Ok(fd)
+}
+
+/// A TCP socket listening for connections, like [`std::net::TcpListener`]
+pub struct TcpListener<'a> {
+    stack: &'a NetStack,
+    fd: SocketFd,
+}
+
+/// Backlog of a [`TcpListener`] (the one the standard library uses)
+const LISTEN_BACKLOG: usize = 128;
+
+impl<'a> TcpListener<'a> {
+    pub fn bind(stack: &'a NetStack, addr: impl ToEndpoint) -> io::Result<Self> {
+        let local = addr.to_endpoint()?;
+        let fd = open(stack, SocketType::Stream, |fd| {
+            let ctx = stack.ctx();
+            socket::bind(fd, local, &ctx)?;
+            socket::listen(fd, LISTEN_BACKLOG, &ctx)
+        })?;
+        Ok(Self { stack, fd })
+    }
+
+    /// Block until a connection arrives
+    pub fn accept(&self) -> io::Result<(TcpStream<'a>, IpEndpoint)> {
+        let (fd, peer) = blocking(self.stack, self.fd, false, |timeout, ctx, _| {
+            socket::accept_timeout(self.fd, timeout, ctx)
+        })?;
+        Ok((
+            TcpStream {
+                stack: self.stack,
+                fd,
+            },
+            peer,
+        ))
+    }
+
+    /// Connections as they arrive; never ends
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<TcpStream<'a>>> + '_ {
+        std::iter::repeat_with(|| self.accept().map(|(stream, _)| stream))
+    }
+
+    pub fn local_addr(&self) -> io::Result<IpEndpoint> {
+        socket::local_addr(self.fd, &self.stack.ctx()).map_err(io_error)
+    }
+
+    /// In non-blocking mode `accept` fails with [`io::ErrorKind::WouldBlock`]
+    /// when no connection is waiting
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        socket::set_nonblocking(self.fd, nonblocking, &self.stack.ctx()).map_err(io_error)
+    }
+}
+
+impl Drop for TcpListener<'_> {
+    fn drop(&mut self) {
+        let _ = socket::close(self.fd, &self.stack.ctx(), &self.stack.devices());
+    }
+}
+
+/// A TCP connection, like [`std::net::TcpStream`]
+pub struct TcpStream<'a> {
+    stack: &'a NetStack,
+    fd: SocketFd,
+}
+
+impl<'a> TcpStream<'a> {
+    pub fn connect(stack: &'a NetStack, addr: impl ToEndpoint) -> io::Result<Self> {
+        let foreign = addr.to_endpoint()?;
+        let fd = open(stack, SocketType::Stream, |fd| {
+            socket::connect(fd, foreign, &stack.ctx(), &stack.devices())
+        })?;
+        Ok(Self { stack, fd })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<IpEndpoint> {
+        socket::peer_addr(self.fd, &self.stack.ctx()).map_err(io_error)
+    }
+
+    pub fn local_addr(&self) -> io::Result<IpEndpoint> {
+        socket::local_addr(self.fd, &self.stack.ctx()).map_err(io_error)
+    }
+
+    /// In non-blocking mode reads and writes fail with
+    /// [`io::ErrorKind::WouldBlock`] instead of waiting
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        socket::set_nonblocking(self.fd, nonblocking, &self.stack.ctx()).map_err(io_error)
+    }
+
+    /// Make reads fail with [`io::ErrorKind::TimedOut`] after waiting
+    /// `timeout`; `None` waits forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        socket::set_read_timeout(self.fd, timeout, &self.stack.ctx()).map_err(io_error)
+    }
+
+    /// Make writes fail with [`io::ErrorKind::TimedOut`] after waiting
+    /// `timeout`; `None` waits forever
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        socket::set_write_timeout(self.fd, timeout, &self.stack.ctx()).map_err(io_error)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        socket::read_timeout(self.fd, &self.stack.ctx()).map_err(io_error)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        socket::write_timeout(self.fd, &self.stack.ctx()).map_err(io_error)
+    }
+
+    pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+        let how = match how {
+            std::net::Shutdown::Read => Shutdown::Read,
+            std::net::Shutdown::Write => Shutdown::Write,
+            std::net::Shutdown::Both => Shutdown::Both,
+        };
+        socket::shutdown(self.fd, how, &self.stack.ctx(), &self.stack.devices()).map_err(io_error)
+    }
+}
+
+impl Read for TcpStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+/// Like `&std::net::TcpStream`, so one thread can read while another writes
+impl Read for &TcpStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        blocking(self.stack, self.fd, false, |timeout, ctx, devices| {
+            socket::recvfrom_timeout(self.fd, buf, timeout, ctx, devices).map(|(len, _)| len)
+        })
+    }
+}
+
+impl Write for TcpStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Write for &TcpStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        blocking(self.stack, self.fd, true, |timeout, ctx, devices| {
+            socket::send_timeout(self.fd, buf, timeout, ctx, devices)
+        })
+    }
+
+    /// Segments go out as soon as they are written
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream<'_> {
+    fn drop(&mut self) {
+        let _ = socket::close(self.fd, &self.stack.ctx(), &self.stack.devices());
+    }
+}
+
+/// A UDP socket, like [`std::net::UdpSocket`]
+pub struct UdpSocket<'a> {
+    stack: &'a NetStack,
+    fd: SocketFd,
+}
+
+impl<'a> UdpSocket<'a> {
+    pub fn bind(stack: &'a NetStack, addr: impl ToEndpoint) -> io::Result<Self> {
+        let local = addr.to_endpoint()?;
+        let fd = open(stack, SocketType::Dgram, |fd| {
+            socket::bind(fd, local, &stack.ctx())
+        })?;
+        Ok(Self { stack, fd })
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: impl ToEndpoint) -> io::Result<usize> {
+        let foreign = addr.to_endpoint()?;
+        socket::sendto(
+            self.fd,
+            buf,
+            foreign,
+            &self.stack.ctx(),
+            &self.stack.devices(),
+        )
+        .map_err(io_error)
+    }
+
+    /// Block until a datagram arrives; a datagram longer than `buf` is truncated
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, IpEndpoint)> {
+        blocking(self.stack, self.fd, false, |timeout, ctx, devices| {
+            socket::recvfrom_timeout(self.fd, buf, timeout, ctx, devices)
+        })
+    }
+
+    /// Send to `addr` from now on when no destination is given
+    pub fn connect(&self, addr: impl ToEndpoint) -> io::Result<()> {
+        let foreign = addr.to_endpoint()?;
+        socket::connect(self.fd, foreign, &self.stack.ctx(), &self.stack.devices())
+            .map_err(io_error)
+    }
+
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        socket::send(self.fd, buf, &self.stack.ctx(), &self.stack.devices()).map_err(io_error)
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(len, _)| len)
+    }
+
+    pub fn local_addr(&self) -> io::Result<IpEndpoint> {
+        socket::local_addr(self.fd, &self.stack.ctx()).map_err(io_error)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<IpEndpoint> {
+        socket::peer_addr(self.fd, &self.stack.ctx()).map_err(io_error)
+    }
+
+    /// Receive datagrams sent to multicast group `multiaddr` on the interface
+    /// with address `interface` (unspecified: the one routing picks)
+    pub fn join_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        socket::join_multicast(
+            self.fd,
+            (*multiaddr).into(),
+            (*interface).into(),
+            &self.stack.ctx(),
+            &self.stack.devices(),
+        )
+        .map_err(io_error)
+    }
+
+    pub fn leave_multicast_v4(&self, multiaddr: &Ipv4Addr, interface: &Ipv4Addr) -> io::Result<()> {
+        socket::leave_multicast(
+            self.fd,
+            (*multiaddr).into(),
+            (*interface).into(),
+            &self.stack.ctx(),
+            &self.stack.devices(),
+        )
+        .map_err(io_error)
+    }
+
+    /// In non-blocking mode receiving fails with [`io::ErrorKind::WouldBlock`]
+    /// when no datagram is waiting
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        socket::set_nonblocking(self.fd, nonblocking, &self.stack.ctx()).map_err(io_error)
+    }
+
+    /// Make reads fail with [`io::ErrorKind::TimedOut`] after waiting
+    /// `timeout`; `None` waits forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        socket::set_read_timeout(self.fd, timeout, &self.stack.ctx()).map_err(io_error)
+    }
+
+    /// Make writes fail with [`io::ErrorKind::TimedOut`] after waiting
+    /// `timeout`; `None` waits forever
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        socket::set_write_timeout(self.fd, timeout, &self.stack.ctx()).map_err(io_error)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        socket::read_timeout(self.fd, &self.stack.ctx()).map_err(io_error)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        socket::write_timeout(self.fd, &self.stack.ctx()).map_err(io_error)
+    }
+}
+
+impl Drop for UdpSocket<'_> {
+    fn drop(&mut self) {
+        let _ = socket::close(self.fd, &self.stack.ctx(), &self.stack.devices());
+    }
+}
+
+/// Run `op` on `fd`, leaving the task's waker with the socket's control block
+/// while it would block
+fn poll_io<T>(
+    stack: &NetStack,
+    fd: SocketFd,
+    cx: &mut Context<'_>,
+    mut op: impl FnMut(&ProtocolContexts, &DeviceManager) -> Result<T, NetError>,
+) -> Poll<io::Result<T>> {
+    let ctx = stack.ctx();
+    let devices = stack.devices();
+    let mut registered = false;
+    loop {
+        match op(&ctx, &devices) {
+            Err(NetError::WouldBlock) => {}
+            result => return Poll::Ready(result.map_err(io_error)),
+        }
+        if registered {
+            return Poll::Pending;
+        }
+        // Try once more after registering, so a change in between is not missed
+        if let Err(e) = socket::register_waker(fd, cx.waker(), &ctx) {
+            return Poll::Ready(Err(io_error(e)));
+        }
+        registered = true;
+    }
+}
+
+/// An `async` [`TcpListener`]
+pub struct AsyncTcpListener<'a> {
+    inner: TcpListener<'a>,
+}
+
+impl<'a> AsyncTcpListener<'a> {
+    pub fn bind(stack: &'a NetStack, addr: impl ToEndpoint) -> io::Result<Self> {
+        let inner = TcpListener::bind(stack, addr)?;
+        inner.set_nonblocking(true)?;
+        Ok(Self { inner })
+    }
+
+    /// Wait for a connection
+    pub async fn accept(&self) -> io::Result<(AsyncTcpStream<'a>, IpEndpoint)> {
+        let stack = self.inner.stack;
+        let (fd, peer) = poll_fn(|cx| {
+            poll_io(stack, self.inner.fd, cx, |ctx, _| {
+                socket::accept(self.inner.fd, ctx)
+            })
+        })
+        .await?;
+        let inner = TcpStream { stack, fd };
+        inner.set_nonblocking(true)?;
+        Ok((AsyncTcpStream { inner }, peer))
+    }
+
+    pub fn local_addr(&self) -> io::Result<IpEndpoint> {
+        self.inner.local_addr()
+    }
+}
+
+/// An `async` [`TcpStream`]
+pub struct AsyncTcpStream<'a> {
+    inner: TcpStream<'a>,
+}
+
+impl<'a> AsyncTcpStream<'a> {
+    /// Connect to `addr`, waiting for the handshake without blocking
+    pub async fn connect(stack: &'a NetStack, addr: impl ToEndpoint) -> io::Result<Self> {
+        let foreign = addr.to_endpoint()?;
+        let fd = open(stack, SocketType::Stream, |fd| {
+            socket::set_nonblocking(fd, true, &stack.ctx())
+        })?;
+        let inner = TcpStream { stack, fd };
+        poll_fn(|cx| {
+            poll_io(stack, fd, cx, |ctx, devices| {
+                socket::connect(fd, foreign, ctx, devices)
+            })
+        })
+        .await?;
+        Ok(Self { inner })
+    }
+
+    /// Wait for data and read it into `buf`; 0 at end of stream
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (stack, fd) = (self.inner.stack, self.inner.fd);
+        poll_fn(|cx| {
+            poll_io(stack, fd, cx, |ctx, devices| {
+                socket::recv(fd, buf, ctx, devices)
+            })
+        })
+        .await
+    }
+
+    /// Wait for window space and send as much of `buf` as fits
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (stack, fd) = (self.inner.stack, self.inner.fd);
+        poll_fn(|cx| {
+            poll_io(stack, fd, cx, |ctx, devices| {
+                socket::send(fd, buf, ctx, devices)
+            })
+        })
+        .await
+    }
+
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let len = self.write(buf).await?;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+
+    pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<IpEndpoint> {
+        self.inner.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<IpEndpoint> {
+        self.inner.local_addr()
+    }
+}
+
+/// An `async` [`UdpSocket`]
+pub struct AsyncUdpSocket<'a> {
+    inner: UdpSocket<'a>,
+}
+
+impl<'a> AsyncUdpSocket<'a> {
+    pub fn bind(stack: &'a NetStack, addr: impl ToEndpoint) -> io::Result<Self> {
+        let inner = UdpSocket::bind(stack, addr)?;
+        inner.set_nonblocking(true)?;
+        Ok(Self { inner })
+    }
+
+    /// Sending never waits; this is `async` to match the standard socket APIs
+    pub async fn send_to(&self, buf: &[u8], addr: impl ToEndpoint) -> io::Result<usize> {
+        self.inner.send_to(buf, addr)
+    }
+
+    /// Wait for a datagram; one longer than `buf` is truncated
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, IpEndpoint)> {
+        let (stack, fd) = (self.inner.stack, self.inner.fd);
+        poll_fn(|cx| {
+            poll_io(stack, fd, cx, |ctx, devices| {
+                socket::recvfrom(fd, buf, ctx, devices)
+            })
+        })
+        .await
+    }
+
+    /// Send to `addr` from now on when no destination is given
+    pub fn connect(&self, addr: impl ToEndpoint) -> io::Result<()> {
+        self.inner.connect(addr)
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.send(buf)
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let (stack, fd) = (self.inner.stack, self.inner.fd);
+        poll_fn(|cx| {
+            poll_io(stack, fd, cx, |ctx, devices| {
+                socket::recv(fd, buf, ctx, devices)
+            })
+        })
+        .await
+    }
+
+    pub fn local_addr(&self) -> io::Result<IpEndpoint> {
+        self.inner.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<IpEndpoint> {
+        self.inner.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+
+    use super::*;
+    use crate::drop::DropReason;
+
+    /// Waker that counts how often it was woken
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Poll `future` once; loopback delivers synchronously, so whatever the
+    /// stack does in response has happened when this returns
+    fn ready<F: Future>(future: F) -> F::Output {
+        let waker = Arc::new(CountingWaker(AtomicUsize::new(0))).into();
+        match pin!(future).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future not ready"),
+        }
+    }
+
+    #[test]
+    fn test_net_tcp_read_write() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+
+        let listener = TcpListener::bind(&stack, "0.0.0.0:8080").unwrap();
+        assert_eq!(listener.local_addr().unwrap().port, 8080);
+        let mut client = TcpStream::connect(&stack, "127.0.0.1:8080").unwrap();
+        let (mut server, peer) = listener.accept().unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut request = String::new();
+        server.read_to_string(&mut request).unwrap();
+        assert_eq!(request, "GET / HTTP/1.0\r\n\r\n");
+        server.write_all(b"HTTP/1.0 200 OK\r\n").unwrap();
+        drop(server);
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"HTTP/1.0 200 OK\r\n");
+        drop(client);
+        drop(listener);
+        assert!(stack.ctx().sockets.is_empty());
+
+        let refused = TcpStream::connect(&stack, SocketAddrV4::new([127, 0, 0, 1].into(), 8080));
+        assert_eq!(
+            refused.err().unwrap().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+        assert!(stack.ctx().sockets.is_empty());
+    }
+
+    #[test]
+    fn test_net_tcp_read_while_writing() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+
+        let listener = TcpListener::bind(&stack, "0.0.0.0:8081").unwrap();
+        let client = TcpStream::connect(&stack, "127.0.0.1:8081").unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        std::thread::scope(|s| {
+            let reader = s.spawn(|| {
+                let mut reply = Vec::new();
+                (&client).read_to_end(&mut reply).unwrap();
+                reply
+            });
+            (&client).write_all(b"ping").unwrap();
+            let mut request = [0u8; 4];
+            server.read_exact(&mut request).unwrap();
+            server.write_all(b"pong").unwrap();
+            drop(server);
+            assert_eq!(reader.join().unwrap(), b"pong");
+        });
+    }
+
+    #[test]
+    fn test_net_udp() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+
+        let server = UdpSocket::bind(&stack, "127.0.0.1:7").unwrap();
+        let client = UdpSocket::bind(&stack, "0.0.0.0:0").unwrap();
+        client.connect("127.0.0.1:7").unwrap();
+        client.send(b"ping").unwrap();
+
+        let mut buf = [0u8; 8];
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        server.send_to(b"pong", from).unwrap();
+        assert_eq!(client.recv(&mut buf).unwrap(), 4);
+        assert!(UdpSocket::bind(&stack, "not an address").is_err());
+
+        server.set_nonblocking(true).unwrap();
+        let err = server.recv_from(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_net_udp_multicast() {
+        let topo = crate::topology::Topology::builder()
+            .host("h1")
+            .host("h2")
+            .link(("h1", "192.0.2.1/24"), ("h2", "192.0.2.2/24"))
+            .build()
+            .unwrap();
+        let (h1, h2) = (topo.node("h1").unwrap(), topo.node("h2").unwrap());
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        let iface = Ipv4Addr::new(192, 0, 2, 2);
+
+        let receiver = UdpSocket::bind(h2, "0.0.0.0:5000").unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        receiver.join_multicast_v4(&group, &iface).unwrap();
+        assert!(receiver.join_multicast_v4(&group, &iface).is_err());
+        let sender = UdpSocket::bind(h1, "192.0.2.1:0").unwrap();
+
+        let mut buf = [0u8; 8];
+        sender.send_to(b"hello", "239.1.2.3:5000").unwrap();
+        let (len, from) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(from.addr, sender.local_addr().unwrap().addr);
+
+        // Groups not joined are not delivered, nor is anything once the group is left
+        sender.send_to(b"other", "239.9.9.9:5000").unwrap();
+        receiver.leave_multicast_v4(&group, &iface).unwrap();
+        sender.send_to(b"late", "239.1.2.3:5000").unwrap();
+        let err = receiver.recv_from(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(h2.ctx().drops.count(DropReason::NotForUs), 2);
+
+        // Closing the socket leaves its groups
+        receiver.join_multicast_v4(&group, &iface).unwrap();
+        drop(receiver);
+        assert!(h2.ctx().igmp.is_empty());
+    }
+
+    #[test]
+    fn test_net_timeouts() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let timeout = Some(Duration::from_millis(20));
+
+        let udp = UdpSocket::bind(&stack, "127.0.0.1:7").unwrap();
+        assert_eq!(udp.read_timeout().unwrap(), None);
+        udp.set_read_timeout(timeout).unwrap();
+        assert_eq!(udp.read_timeout().unwrap(), timeout);
+        let mut buf = [0u8; 8];
+        let err = udp.recv_from(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err = udp.set_read_timeout(Some(Duration::ZERO)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let listener = TcpListener::bind(&stack, "0.0.0.0:8080").unwrap();
+        let mut client = TcpStream::connect(&stack, "127.0.0.1:8080").unwrap();
+        let (_server, _) = listener.accept().unwrap();
+        client.set_read_timeout(timeout).unwrap();
+        let err = client.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // The server never reads, so the window fills up
+        client.set_write_timeout(timeout).unwrap();
+        let chunk = [0u8; 4096];
+        let err = loop {
+            if let Err(e) = client.write(&chunk) {
+                break e;
+            }
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_net_blocked_call_lets_configuration_through() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+
+        let listener = TcpListener::bind(&stack, "0.0.0.0:8082").unwrap();
+        std::thread::scope(|s| {
+            let accepted = s.spawn(|| listener.accept().map(|(_, peer)| peer));
+            std::thread::sleep(Duration::from_millis(50));
+            let (tx, rx) = std::sync::mpsc::channel();
+            let stack = &stack;
+            s.spawn(move || {
+                stack.ctx_mut().ip_config.forwarding = true;
+                tx.send(()).unwrap();
+            });
+            let configured = rx.recv_timeout(Duration::from_secs(5)).is_ok();
+
+            let client = TcpStream::connect(stack, "127.0.0.1:8082").unwrap();
+            let peer = accepted.join().unwrap().unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+            assert!(configured, "configuration waited for the blocked accept");
+        });
+        assert!(stack.ctx().ip_config.forwarding);
+    }
+
+    #[test]
+    fn test_net_async() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let woken = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Arc::clone(&woken).into();
+        let mut cx = Context::from_waker(&waker);
+
+        let listener = AsyncTcpListener::bind(&stack, "0.0.0.0:8080").unwrap();
+        let mut accept = pin!(listener.accept());
+        assert!(accept.as_mut().poll(&mut cx).is_pending());
+        let mut client = ready(AsyncTcpStream::connect(&stack, "127.0.0.1:8080")).unwrap();
+        assert!(woken.0.load(Ordering::SeqCst) > 0);
+        let Poll::Ready(Ok((mut server, peer))) = accept.as_mut().poll(&mut cx) else {
+            panic!("connection not accepted");
+        };
+        assert_eq!(peer, client.local_addr().unwrap());
+
+        let mut buf = [0u8; 16];
+        let before = woken.0.load(Ordering::SeqCst);
+        {
+            let mut read = pin!(server.read(&mut buf));
+            assert!(read.as_mut().poll(&mut cx).is_pending());
+            ready(client.write_all(b"hello")).unwrap();
+            assert!(woken.0.load(Ordering::SeqCst) > before);
+            assert!(matches!(read.as_mut().poll(&mut cx), Poll::Ready(Ok(5))));
+        }
+        assert_eq!(&buf[..5], b"hello");
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(ready(server.read(&mut buf)).unwrap(), 0);
+
+        let udp = AsyncUdpSocket::bind(&stack, "127.0.0.1:7").unwrap();
+        let before = woken.0.load(Ordering::SeqCst);
+        let mut recv = pin!(udp.recv_from(&mut buf));
+        assert!(recv.as_mut().poll(&mut cx).is_pending());
+        let sender = AsyncUdpSocket::bind(&stack, "0.0.0.0:0").unwrap();
+        ready(sender.send_to(b"ping", "127.0.0.1:echo")).unwrap();
+        assert!(woken.0.load(Ordering::SeqCst) > before);
+        assert!(matches!(
+            recv.as_mut().poll(&mut cx),
+            Poll::Ready(Ok((4, _)))
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+//! Outgoing packet buffers with room for headers.
+//!
+//! A [`PacketBuf`] keeps free space in front of its data (headroom) and after
+//! it (tailroom). A transport writes its payload once, then every layer on the
+//! way down prepends its header in place with [`PacketBuf::push`], so the
+//! bytes the driver transmits are the ones the transport wrote:
+//!
+//! ```
+//! # use microps::packet::PacketBuf;
+//! let mut buf = PacketBuf::from(&b"payload"[..]);
+//! buf.push(8).copy_from_slice(b"udp hdr ");
+//! buf.push(4).copy_from_slice(b"ip  ");
+//! assert_eq!(&buf[..], b"ip  udp hdr payload");
+//! ```
+
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+use crate::pool::BufferPool;
+use crate::protocol::ip::IP_HDR_SIZE_MAX;
+use crate::protocol::tcp::TCP_HDR_SIZE_MAX;
+
+/// Headroom of new buffers: the largest IP and transport headers
+pub const PACKET_HEADROOM: usize = IP_HDR_SIZE_MAX + TCP_HDR_SIZE_MAX;
+
+#[derive(Clone)]
+pub struct PacketBuf {
+    /// Headroom followed by the data; the tailroom is the spare capacity
+    buf: Vec<u8>,
+    /// Start of the data
+    head: usize,
+    /// Where the buffer goes back to when dropped (see [`BufferPool::packet`])
+    pool: Option<BufferPool>,
+}
+
+impl PacketBuf {
+    /// Empty buffer with [`PACKET_HEADROOM`] and room for `capacity` bytes of data
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_headroom(PACKET_HEADROOM, capacity)
+    }
+
+    pub fn with_headroom(headroom: usize, capacity: usize) -> Self {
+        let mut buf = Vec::with_capacity(headroom + capacity);
+        buf.resize(headroom, 0);
+        Self {
+            buf,
+            head: headroom,
+            pool: None,
+        }
+    }
+
+    /// Reuse the empty `buf` of `pool`, with [`PACKET_HEADROOM`]
+    pub(crate) fn pooled(mut buf: Vec<u8>, capacity: usize, pool: BufferPool) -> Self {
+        buf.reserve(PACKET_HEADROOM + capacity);
+        buf.resize(PACKET_HEADROOM, 0);
+        Self {
+            buf,
+            head: PACKET_HEADROOM,
+            pool: Some(pool),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len() - self.head
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn headroom(&self) -> usize {
+        self.head
+    }
+
+    pub fn tailroom(&self) -> usize {
+        self.buf.capacity() - self.buf.len()
+    }
+
+    /// Grow the data by `len` bytes at the front and return them for the
+    /// caller to fill. Reallocates (with [`PACKET_HEADROOM`] to spare) only if
+    /// the headroom is too small.
+    pub fn push(&mut self, len: usize) -> &mut [u8] {
+        if self.head < len {
+            let mut grown = Self::with_headroom(PACKET_HEADROOM + len, self.len());
+            grown.buf.extend_from_slice(self);
+            grown.pool = self.pool.take();
+            *self = grown;
+        }
+        self.head -= len;
+        &mut self.buf[self.head..self.head + len]
+    }
+
+    /// Append `data` at the back, using the tailroom first
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The data, moved to the front of the allocation, which leaves its pool
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.pool = None;
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.drain(..self.head);
+        buf
+    }
+}
+
+impl Drop for PacketBuf {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
+impl PartialEq for PacketBuf {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for PacketBuf {}
+
+impl Default for PacketBuf {
+    fn default() -> Self {
+        Self::with_capacity(0)
+    }
+}
+
+impl Deref for PacketBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[self.head..]
+    }
+}
+
+impl DerefMut for PacketBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.head..]
+    }
+}
+
+impl AsRef<[u8]> for PacketBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// A copy of `data` with [`PACKET_HEADROOM`]
+impl From<&[u8]> for PacketBuf {
+    fn from(data: &[u8]) -> Self {
+        let mut buf = Self::with_capacity(data.len());
+        buf.extend_from_slice(data);
+        buf
+    }
+}
+
+/// Takes over `data` without copying; there is no headroom
+impl From<Vec<u8>> for PacketBuf {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            buf: data,
+            head: 0,
+            pool: None,
+        }
+    }
+}
+
+impl fmt::Debug for PacketBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PacketBuf(len={}, headroom={}, tailroom={})",
+            self.len(),
+            self.headroom(),
+            self.tailroom()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_buf_prepends_in_place() {
+        let mut buf = PacketBuf::with_capacity(4);
+        let tailroom = buf.tailroom();
+        buf.extend_from_slice(b"data");
+        assert_eq!(buf.tailroom(), tailroom - 4);
+        let start = buf.as_ptr();
+
+        buf.push(2).copy_from_slice(b"l4");
+        buf.push(2).copy_from_slice(b"l3");
+        assert_eq!(&buf[..], b"l3l4data");
+        assert_eq!(buf.headroom(), PACKET_HEADROOM - 4);
+        // Same allocation, the headers went right in front of the payload
+        assert_eq!(buf.as_ptr().wrapping_add(4), start);
+        assert_eq!(buf.into_vec(), b"l3l4data");
+    }
+
+    #[test]
+    fn test_packet_buf_grows_without_headroom() {
+        let mut buf = PacketBuf::from(b"data".to_vec());
+        assert_eq!(buf.headroom(), 0);
+        buf.push(3).copy_from_slice(b"hdr");
+        assert_eq!(&buf[..], b"hdrdata");
+        assert_eq!(buf.headroom(), PACKET_HEADROOM);
+    }
+}
@@ -0,0 +1,202 @@
+//! Persistence of learned stack state across restarts.
+//!
+//! The state file is line-oriented text:
+//!
+//! ```text
+//! saved_at <unix seconds>
+//! route <network> <netmask> <nexthop> <iface unicast>
+//! ```
+//!
+//! Only routes via a gateway are saved; connected routes are recreated when
+//! interfaces are registered.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use crate::context::{ProtocolContexts, RouteTable};
+use crate::protocol::ip::IpAddr;
+
+/// State older than this is considered stale and ignored on load
+pub const STATE_MAX_AGE_DEFAULT: Duration = Duration::from_secs(60 * 60);
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Serialize gateway routes to `path`
+pub fn save(path: &Path, routes: &RouteTable) -> Result<usize> {
+    let mut out = format!("saved_at {}\n", now_secs());
+    let mut count = 0;
+
+    for route in routes
+        .snapshot()
+        .iter()
+        .filter(|route| route.nexthop != IpAddr::ANY)
+    {
+        out.push_str(&format!(
+            "route {} {} {} {}\n",
+            route.network, route.netmask, route.nexthop, route.iface.unicast
+        ));
+        count += 1;
+    }
+
+    fs::write(path, out).with_context(|| format!("Failed to write {}", path.display()))?;
+    tracing::info!("saved {} routes to {}", count, path.display());
+    Ok(count)
+}
+
+/// Restore routes saved by [`save`] into `ctx`.
+///
+/// Returns the number of routes restored. A missing or stale file restores nothing.
+/// Routes whose interface is no longer registered or which already exist are skipped.
+pub fn load(path: &Path, ctx: &mut ProtocolContexts, max_age: Duration) -> Result<usize> {
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut lines = content.lines();
+
+    let saved_at: u64 = lines
+        .next()
+        .and_then(|line| line.strip_prefix("saved_at "))
+        .and_then(|secs| secs.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("missing saved_at header: {}", path.display()))?;
+    let age = now_secs().saturating_sub(saved_at);
+    if age > max_age.as_secs() {
+        tracing::info!(
+            "ignoring stale state file {} (age={}s)",
+            path.display(),
+            age
+        );
+        return Ok(0);
+    }
+
+    let mut restored = 0;
+    for line in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let ["route", network, netmask, nexthop, unicast] = fields[..] else {
+            tracing::warn!("skipping malformed state entry: {}", line);
+            continue;
+        };
+
+        let network = IpAddr::from_str(network)?;
+        let netmask = IpAddr::from_str(netmask)?;
+        let nexthop = IpAddr::from_str(nexthop)?;
+        let unicast = IpAddr::from_str(unicast)?;
+
+        let Some(iface) = ctx.ip_ifaces.select(unicast).cloned() else {
+            tracing::debug!("skipping route for unknown iface {}", unicast);
+            continue;
+        };
+        if let Err(e) = ctx.ip_routes.add(network, netmask, nexthop, iface) {
+            tracing::debug!("skipping route: {}", e);
+            continue;
+        }
+        restored += 1;
+    }
+
+    tracing::info!("restored {} routes from {}", restored, path.display());
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceIndex;
+    use crate::iface::IpIface;
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    fn ctx_with_iface() -> ProtocolContexts {
+        let mut ctx = ProtocolContexts::new();
+        let iface = IpIface::new("192.0.2.2", "255.255.255.0", DeviceIndex(0)).unwrap();
+        ctx.ip_ifaces.register(iface.clone()).unwrap();
+        ctx.ip_routes
+            .add(addr("192.0.2.0"), addr("255.255.255.0"), IpAddr::ANY, iface)
+            .unwrap();
+        ctx
+    }
+
+    fn state_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("microps-{}-{}.state", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_save_load_routes() {
+        let path = state_path("roundtrip");
+        let ctx = ctx_with_iface();
+        let iface = ctx.ip_ifaces.select(addr("192.0.2.2")).cloned().unwrap();
+        ctx.ip_routes
+            .set_default_gateway(iface.clone(), addr("192.0.2.1"))
+            .unwrap();
+        ctx.ip_routes
+            .add(
+                addr("10.0.0.0"),
+                addr("255.0.0.0"),
+                addr("192.0.2.254"),
+                iface,
+            )
+            .unwrap();
+        assert_eq!(save(&path, &ctx.ip_routes).unwrap(), 2);
+
+        let mut restarted = ctx_with_iface();
+        assert_eq!(
+            load(&path, &mut restarted, STATE_MAX_AGE_DEFAULT).unwrap(),
+            2
+        );
+        assert_eq!(
+            restarted
+                .ip_routes
+                .lookup(addr("10.1.1.1"))
+                .unwrap()
+                .nexthop,
+            addr("192.0.2.254")
+        );
+        assert_eq!(
+            restarted
+                .ip_routes
+                .lookup(addr("203.0.113.1"))
+                .unwrap()
+                .nexthop,
+            addr("192.0.2.1")
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_skips_stale_and_unknown() {
+        let path = state_path("stale");
+        fs::write(
+            &path,
+            "saved_at 0\nroute 0.0.0.0 0.0.0.0 192.0.2.1 192.0.2.2\n",
+        )
+        .unwrap();
+        let mut ctx = ctx_with_iface();
+        assert_eq!(load(&path, &mut ctx, STATE_MAX_AGE_DEFAULT).unwrap(), 0);
+
+        fs::write(
+            &path,
+            format!(
+                "saved_at {}\nroute 0.0.0.0 0.0.0.0 10.0.0.1 10.0.0.2\n",
+                now_secs()
+            ),
+        )
+        .unwrap();
+        assert_eq!(load(&path, &mut ctx, STATE_MAX_AGE_DEFAULT).unwrap(), 0);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(load(&path, &mut ctx, STATE_MAX_AGE_DEFAULT).unwrap(), 0);
+    }
+}
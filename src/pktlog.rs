@@ -0,0 +1,450 @@
+//! Packet log: one structured `tracing` event per frame.
+//!
+//! Every frame a device receives or transmits is decoded (IPv4 or IPv6, then
+//! the TCP, UDP or ICMP header) and logged under the `microps::packet` target
+//! with its fields: `dev`, `dir`, `len`, `src`, `dst`, `proto`, `ttl`, `sport`,
+//! `dport`, `flags`, `seq`, `ack`, `wnd`, `icmp_type` and `icmp_code`. At
+//! `trace` the frame's bytes follow in `hex`.
+//!
+//! A [`PacketFilter`] picks the frames and the level they are logged at. It is
+//! written as space- or comma-separated `key=value` pairs:
+//!
+//! ```text
+//! dev=net1 proto=tcp host=192.0.2.1 port=80 level=info
+//! ```
+//!
+//! `proto` is `icmp`, `icmpv6`, `igmp`, `tcp`, `udp` or a protocol number;
+//! `host` matches either address and `port` either port. Every frame is logged
+//! at `debug` until [`set_filter`] says otherwise; the subscriber's own filter
+//! (`RUST_LOG=microps::packet=debug`, say) still applies on top.
+//!
+//! ```
+//! # use microps::pktlog::{self, PacketFilter};
+//! let filter: PacketFilter = "proto=tcp port=80".parse()?;
+//! pktlog::set_filter(Some(filter));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::fmt::{self, Write as _};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use anyhow::{Result, bail};
+use tracing::Level;
+
+use crate::capture::Direction;
+use crate::device::Device;
+use crate::protocol::icmp::IcmpHdr;
+use crate::protocol::ip::{IP_HDR_OFFSET_MASK, IpAddr, IpHdr, IpProtocol};
+use crate::protocol::ipv6::{IPV6_HDR_SIZE, IPV6_NEXT_HEADER_ICMPV6, Ipv6Addr, Ipv6Hdr};
+use crate::protocol::tcp::{TcpHdr, flags_ntoa};
+use crate::protocol::udp::UdpHdr;
+use crate::protocol::{PROTOCOL_TYPE_IP, PROTOCOL_TYPE_IPV6};
+use crate::util::LOG_PACKET;
+
+/// `tracing` target of packet events
+pub const PACKET_LOG_TARGET: &str = "microps::packet";
+
+static FILTER: RwLock<Option<PacketFilter>> = RwLock::new(Some(PacketFilter::ALL));
+
+/// Log the frames `filter` matches from now on; `None` turns the log off
+pub fn set_filter(filter: Option<PacketFilter>) {
+    *FILTER.write().unwrap() = filter;
+}
+
+/// The filter in use, `None` if the log is off
+pub fn filter() -> Option<PacketFilter> {
+    FILTER.read().unwrap().clone()
+}
+
+/// An IPv4 or IPv6 address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Host {
+    V4(IpAddr),
+    V6(Ipv6Addr),
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::V4(addr) => addr.fmt(f),
+            Host::V6(addr) => addr.fmt(f),
+        }
+    }
+}
+
+impl FromStr for Host {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.contains(':') {
+            Ok(Host::V6(s.parse()?))
+        } else {
+            Ok(Host::V4(s.parse()?))
+        }
+    }
+}
+
+/// Which frames to log, and at what level
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketFilter {
+    /// Device name (`net0`)
+    pub device: Option<String>,
+    pub protocol: Option<IpProtocol>,
+    /// Source or destination address
+    pub host: Option<Host>,
+    /// Source or destination port
+    pub port: Option<u16>,
+    pub level: Level,
+}
+
+impl PacketFilter {
+    /// Every frame, at `debug`
+    pub const ALL: Self = Self {
+        device: None,
+        protocol: None,
+        host: None,
+        port: None,
+        level: Level::DEBUG,
+    };
+
+    pub fn matches(&self, dev: &str, packet: &Packet) -> bool {
+        self.device.as_deref().is_none_or(|device| device == dev)
+            && self
+                .protocol
+                .is_none_or(|protocol| protocol == packet.protocol)
+            && self
+                .host
+                .is_none_or(|host| host == packet.src || host == packet.dst)
+            && self.port.is_none_or(|port| {
+                packet
+                    .ports
+                    .is_some_and(|(sport, dport)| port == sport || port == dport)
+            })
+    }
+}
+
+impl Default for PacketFilter {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl FromStr for PacketFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut filter = Self::ALL;
+        for pair in s.split([',', ' ']).filter(|pair| !pair.is_empty()) {
+            let Some((key, value)) = pair.split_once('=') else {
+                bail!("expected key=value: {}", pair);
+            };
+            match key {
+                "dev" => filter.device = Some(value.to_string()),
+                "proto" => filter.protocol = Some(parse_protocol(value)?),
+                "host" => filter.host = Some(value.parse()?),
+                "port" => filter.port = Some(value.parse()?),
+                "level" => {
+                    filter.level = value
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("unknown level: {}", value))?
+                }
+                _ => bail!("unknown key: {}", key),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for PacketFilter {
+    /// The filter in the form it is parsed from
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(device) = &self.device {
+            write!(f, "dev={} ", device)?;
+        }
+        if let Some(protocol) = self.protocol {
+            write!(f, "proto={} ", protocol_name(protocol))?;
+        }
+        if let Some(host) = self.host {
+            write!(f, "host={} ", host)?;
+        }
+        if let Some(port) = self.port {
+            write!(f, "port={} ", port)?;
+        }
+        write!(f, "level={}", self.level.as_str().to_lowercase())
+    }
+}
+
+fn parse_protocol(s: &str) -> Result<IpProtocol> {
+    Ok(match s {
+        "icmp" => IpProtocol::Icmp,
+        "igmp" => IpProtocol::Igmp,
+        "tcp" => IpProtocol::Tcp,
+        "udp" => IpProtocol::Udp,
+        "icmpv6" => IpProtocol::Other(IPV6_NEXT_HEADER_ICMPV6),
+        number => IpProtocol::from_u8(
+            number
+                .parse()
+                .map_err(|_| anyhow::anyhow!("unknown protocol: {}", s))?,
+        ),
+    })
+}
+
+fn protocol_name(protocol: IpProtocol) -> String {
+    match protocol {
+        IpProtocol::Icmp => "icmp".to_string(),
+        IpProtocol::Igmp => "igmp".to_string(),
+        IpProtocol::Tcp => "tcp".to_string(),
+        IpProtocol::Udp => "udp".to_string(),
+        IpProtocol::Other(IPV6_NEXT_HEADER_ICMPV6) => "icmpv6".to_string(),
+        IpProtocol::Other(number) => number.to_string(),
+    }
+}
+
+/// TCP header fields of a [`Packet`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpFields {
+    pub flags: u8,
+    pub seq: u32,
+    pub ack: u32,
+    pub wnd: u16,
+}
+
+/// Header fields of an IP packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet {
+    pub src: Host,
+    pub dst: Host,
+    pub protocol: IpProtocol,
+    /// TTL or hop limit
+    pub ttl: u8,
+    /// Source and destination port (TCP and UDP)
+    pub ports: Option<(u16, u16)>,
+    pub tcp: Option<TcpFields>,
+    /// Type and code (ICMP and ICMPv6)
+    pub icmp: Option<(u8, u8)>,
+}
+
+impl Packet {
+    /// Decode a frame of protocol `type_`; `None` if it is not IP or too
+    /// short for its IP header. A transport header that is cut short, or
+    /// missing because the packet is a later fragment, is left out.
+    pub fn decode(type_: u16, data: &[u8]) -> Option<Self> {
+        let (mut packet, payload) = match type_ {
+            PROTOCOL_TYPE_IP => {
+                let hdr = IpHdr::from_bytes(data)?;
+                let packet = Self::new(
+                    Host::V4(hdr.src()),
+                    Host::V4(hdr.dst()),
+                    hdr.protocol(),
+                    hdr.ttl(),
+                );
+                if hdr.offset() & IP_HDR_OFFSET_MASK != 0 {
+                    return Some(packet);
+                }
+                (packet, data.get(hdr.hdr_len()..).unwrap_or_default())
+            }
+            PROTOCOL_TYPE_IPV6 => {
+                let hdr = Ipv6Hdr::from_bytes(data)?;
+                let packet = Self::new(
+                    Host::V6(hdr.src()),
+                    Host::V6(hdr.dst()),
+                    hdr.next_header(),
+                    hdr.hop_limit(),
+                );
+                (packet, &data[IPV6_HDR_SIZE..])
+            }
+            _ => return None,
+        };
+        match packet.protocol {
+            IpProtocol::Tcp => {
+                if let Some(hdr) = TcpHdr::from_bytes(payload) {
+                    packet.ports = Some((hdr.src(), hdr.dst()));
+                    packet.tcp = Some(TcpFields {
+                        flags: hdr.flg(),
+                        seq: hdr.seq(),
+                        ack: hdr.ack(),
+                        wnd: hdr.wnd(),
+                    });
+                }
+            }
+            IpProtocol::Udp => {
+                if let Some(hdr) = UdpHdr::from_bytes(payload) {
+                    packet.ports = Some((hdr.src(), hdr.dst()));
+                }
+            }
+            IpProtocol::Icmp | IpProtocol::Other(IPV6_NEXT_HEADER_ICMPV6) => {
+                if let Some(hdr) = IcmpHdr::from_bytes(payload) {
+                    packet.icmp = Some((hdr.type_(), hdr.code()));
+                }
+            }
+            _ => {}
+        }
+        Some(packet)
+    }
+
+    fn new(src: Host, dst: Host, protocol: IpProtocol, ttl: u8) -> Self {
+        Self {
+            src,
+            dst,
+            protocol,
+            ttl,
+            ports: None,
+            tcp: None,
+            icmp: None,
+        }
+    }
+}
+
+/// Emit `tracing::event!` at a level only known at run time
+macro_rules! packet_event {
+    ($level:expr, $($fields:tt)*) => {
+        match $level {
+            Level::ERROR => tracing::event!(target: PACKET_LOG_TARGET, Level::ERROR, $($fields)*),
+            Level::WARN => tracing::event!(target: PACKET_LOG_TARGET, Level::WARN, $($fields)*),
+            Level::INFO => tracing::event!(target: PACKET_LOG_TARGET, Level::INFO, $($fields)*),
+            Level::DEBUG => tracing::event!(target: PACKET_LOG_TARGET, Level::DEBUG, $($fields)*),
+            Level::TRACE => tracing::event!(target: PACKET_LOG_TARGET, Level::TRACE, $($fields)*),
+        }
+    };
+}
+
+/// Log a frame `dev` received or transmitted, if the filter matches it
+pub(crate) fn log(direction: Direction, dev: &Device, type_: u16, data: &[u8]) {
+    let filter = FILTER.read().unwrap();
+    let Some(filter) = filter.as_ref() else {
+        return;
+    };
+    if !tracing::level_enabled!(filter.level) {
+        return;
+    }
+    let name = dev.name_string();
+    let Some(packet) = Packet::decode(type_, data) else {
+        return;
+    };
+    if !filter.matches(&name, &packet) || !LOG_PACKET.allow(filter.level) {
+        return;
+    }
+
+    let dir = match direction {
+        Direction::In => "in",
+        Direction::Out => "out",
+    };
+    let tcp = packet.tcp;
+    let hex = (filter.level == Level::TRACE).then(|| hex(data));
+    packet_event!(
+        filter.level,
+        dev = %name,
+        dir = %dir,
+        len = data.len(),
+        src = %packet.src,
+        dst = %packet.dst,
+        proto = %protocol_name(packet.protocol),
+        ttl = packet.ttl,
+        sport = packet.ports.map(|(sport, _)| sport),
+        dport = packet.ports.map(|(_, dport)| dport),
+        flags = tcp.map(|tcp| flags_ntoa(tcp.flags)),
+        seq = tcp.map(|tcp| tcp.seq),
+        ack = tcp.map(|tcp| tcp.ack),
+        wnd = tcp.map(|tcp| tcp.wnd),
+        icmp_type = packet.icmp.map(|(type_, _)| type_),
+        icmp_code = packet.icmp.map(|(_, code)| code),
+        hex,
+        "packet"
+    );
+}
+
+fn hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for byte in data {
+        let _ = write!(s, "{:02x}", byte);
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ip::IpEndpoint;
+    use crate::protocol::tcp::{self, TCP_FLG_ACK, TCP_FLG_SYN, TcpSegment};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_filter_parse_and_display() {
+        let filter: PacketFilter = "dev=net1, proto=tcp host=192.0.2.1 port=80 level=info"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.device.as_deref(), Some("net1"));
+        assert_eq!(filter.protocol, Some(IpProtocol::Tcp));
+        assert_eq!(filter.host, Some(Host::V4(ip("192.0.2.1"))));
+        assert_eq!(filter.port, Some(80));
+        assert_eq!(filter.level, Level::INFO);
+        assert_eq!(filter.to_string().parse::<PacketFilter>().unwrap(), filter);
+        assert_eq!(
+            "proto=icmpv6 host=2001:db8::1"
+                .parse::<PacketFilter>()
+                .unwrap()
+                .to_string(),
+            "proto=icmpv6 host=2001:db8::1 level=debug"
+        );
+        assert_eq!("".parse::<PacketFilter>().unwrap(), PacketFilter::ALL);
+        assert!("port=http".parse::<PacketFilter>().is_err());
+        assert!("level=loud".parse::<PacketFilter>().is_err());
+        assert!("proto".parse::<PacketFilter>().is_err());
+    }
+
+    #[test]
+    fn test_decode_and_match() {
+        let src = ip("192.0.2.1");
+        let dst = ip("192.0.2.2");
+        let seg = TcpSegment {
+            seq: 1000,
+            ack: 0,
+            flags: TCP_FLG_SYN | TCP_FLG_ACK,
+            wnd: 65535,
+            options: &[],
+            data: &[],
+        };
+        let segment =
+            tcp::build(IpEndpoint::new(src, 49152), IpEndpoint::new(dst, 80), &seg).unwrap();
+        let hdr = IpHdr::new(IpProtocol::Tcp, 40, 1, 0, src, dst);
+        let frame = [hdr.to_bytes().as_slice(), segment.as_ref()].concat();
+
+        let packet = Packet::decode(PROTOCOL_TYPE_IP, &frame).unwrap();
+        assert_eq!(packet.src, Host::V4(src));
+        assert_eq!(packet.ports, Some((49152, 80)));
+        let tcp = packet.tcp.unwrap();
+        assert_eq!((tcp.seq, tcp.flags), (1000, TCP_FLG_SYN | TCP_FLG_ACK));
+
+        let matches = |filter: &str, dev: &str| {
+            filter
+                .parse::<PacketFilter>()
+                .unwrap()
+                .matches(dev, &packet)
+        };
+        assert!(matches("", "net0"));
+        assert!(matches(
+            "dev=net0 proto=tcp host=192.0.2.2 port=49152",
+            "net0"
+        ));
+        assert!(!matches("dev=net1", "net0"));
+        assert!(!matches("proto=udp", "net0"));
+        assert!(!matches("host=192.0.2.3", "net0"));
+        assert!(!matches("port=443", "net0"));
+
+        // A header cut short keeps the IP fields only
+        let cut = Packet::decode(PROTOCOL_TYPE_IP, &frame[..30]).unwrap();
+        assert_eq!((cut.ports, cut.tcp), (None, None));
+        assert!(
+            !"port=80"
+                .parse::<PacketFilter>()
+                .unwrap()
+                .matches("net0", &cut)
+        );
+        assert!(Packet::decode(PROTOCOL_TYPE_IP, &frame[..10]).is_none());
+        assert!(Packet::decode(0x88b5, &frame).is_none());
+    }
+}
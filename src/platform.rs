@@ -0,0 +1,97 @@
+//! What the stack needs from the host: a clock and a process id.
+//!
+//! Native builds use `std`. On `wasm32-unknown-unknown`, `std` has neither (they
+//! panic), so the embedder provides the time through two imported functions,
+//! `microps_now_ms` (monotonic, like `performance.now()`) and
+//! `microps_epoch_ms` (like `Date.now()`).
+
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use std::time::Instant;
+
+#[cfg(target_arch = "wasm32")]
+pub use self::wasm::Instant;
+
+/// Time since the Unix epoch, for seeding identifiers
+#[cfg(not(target_arch = "wasm32"))]
+pub fn since_epoch() -> Duration {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn since_epoch() -> Duration {
+    Duration::from_secs_f64(unsafe { wasm::microps_epoch_ms() } / 1000.0)
+}
+
+/// Id of this process; each browser tab counts as one
+pub fn process_id() -> u32 {
+    #[cfg(not(target_arch = "wasm32"))]
+    return std::process::id();
+    #[cfg(target_arch = "wasm32")]
+    return 1;
+}
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use std::ops::{Add, AddAssign, Sub};
+    use std::time::Duration;
+
+    unsafe extern "C" {
+        pub fn microps_now_ms() -> f64;
+        pub fn microps_epoch_ms() -> f64;
+    }
+
+    /// Monotonic instant read from the host clock
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Instant(Duration);
+
+    impl Instant {
+        pub fn now() -> Self {
+            Self(Duration::from_secs_f64(
+                unsafe { microps_now_ms() } / 1000.0,
+            ))
+        }
+
+        pub fn duration_since(&self, earlier: Instant) -> Duration {
+            self.0.saturating_sub(earlier.0)
+        }
+
+        pub fn elapsed(&self) -> Duration {
+            Self::now().duration_since(*self)
+        }
+    }
+
+    impl Add<Duration> for Instant {
+        type Output = Instant;
+
+        fn add(self, rhs: Duration) -> Instant {
+            Instant(self.0 + rhs)
+        }
+    }
+
+    impl AddAssign<Duration> for Instant {
+        fn add_assign(&mut self, rhs: Duration) {
+            self.0 += rhs;
+        }
+    }
+
+    impl Sub<Duration> for Instant {
+        type Output = Instant;
+
+        fn sub(self, rhs: Duration) -> Instant {
+            Instant(self.0.saturating_sub(rhs))
+        }
+    }
+
+    impl Sub<Instant> for Instant {
+        type Output = Duration;
+
+        fn sub(self, rhs: Instant) -> Duration {
+            self.duration_since(rhs)
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Reusable packet buffers and zero-copy loans.
+//!
+//! A [`BufferPool`] hands out `Vec<u8>`s that keep their allocation when they
+//! come back, so received data can be stored without allocating per packet.
+//! Received data handed to the application as a [`Loan`] is tracked until the
+//! loan is dropped; loans still held are listed by [`BufferPool::loans`],
+//! which makes leaked ones easy to spot.
+//!
+//! Each stack also keeps a pool of [`PACKET_BUFFER_SIZE`] buffers (see
+//! [`ProtocolContexts::packets`]) for received frames queued for later, as
+//! [`Bytes`], and packets on their way out through `ip_output`;
+//! [`PoolStats::exhausted`] counts the times it ran dry.
+//!
+//! [`ProtocolContexts::packets`]: crate::context::ProtocolContexts::packets
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::bytes::Bytes;
+use crate::packet::{PACKET_HEADROOM, PacketBuf};
+use crate::platform::Instant;
+
+/// Capacity of preallocated packet buffers: headers and a 1500-byte MTU
+/// frame. A larger packet (on loopback) grows its buffer, which keeps the
+/// capacity when it comes back.
+pub const PACKET_BUFFER_SIZE: usize = PACKET_HEADROOM + 1500;
+
+/// Packet buffers a stack preallocates by default
+pub const PACKET_BUFFERS: usize = 64;
+
+/// Counters of a [`BufferPool`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers taken from the pool without allocating
+    pub reused: u64,
+    /// Buffers allocated, up front or because the pool was empty
+    pub allocated: u64,
+    /// Requests that found no free buffer
+    pub exhausted: u64,
+    /// Loans handed out so far
+    pub lent: u64,
+    /// Loans given back so far
+    pub returned: u64,
+    /// Loans held at the same time at most
+    pub max_outstanding: usize,
+}
+
+impl PoolStats {
+    /// Loans not given back yet
+    pub fn outstanding(&self) -> u64 {
+        self.lent - self.returned
+    }
+}
+
+/// A loan the application still holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoanInfo {
+    pub id: u64,
+    pub len: usize,
+    /// How long the loan has been held
+    pub age: Duration,
+}
+
+struct PoolState {
+    free: Vec<Vec<u8>>,
+    /// Free buffers kept at most; more are released to the allocator
+    max_free: usize,
+    /// Outstanding loans and when they were handed out
+    loans: HashMap<u64, (usize, Instant)>,
+    next_loan: u64,
+    stats: PoolStats,
+}
+
+/// Free list of packet buffers, shared by the table that owns it and the
+/// loans it handed out
+#[derive(Clone)]
+pub struct BufferPool {
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl BufferPool {
+    /// Pool keeping at most `max_free` buffers for reuse
+    pub fn new(max_free: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(PoolState {
+                free: Vec::new(),
+                max_free,
+                loans: HashMap::new(),
+                next_loan: 0,
+                stats: PoolStats::default(),
+            })),
+        }
+    }
+
+    /// Pool starting with `count` free buffers of `size` bytes, keeping at most `count`
+    pub fn preallocated(count: usize, size: usize) -> Self {
+        let pool = Self::new(count);
+        {
+            let mut state = pool.state.lock().unwrap();
+            state.free = (0..count).map(|_| Vec::with_capacity(size)).collect();
+            state.stats.allocated = count as u64;
+        }
+        pool
+    }
+
+    /// Empty buffer, reusing a free allocation if there is one
+    pub fn take(&self) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        match state.free.pop() {
+            Some(buf) => {
+                state.stats.reused += 1;
+                buf
+            }
+            None => {
+                state.stats.allocated += 1;
+                state.stats.exhausted += 1;
+                Vec::new()
+            }
+        }
+    }
+
+    /// Buffer holding a copy of `data`, reusing a free allocation if there is one
+    pub fn copy_from(&self, data: &[u8]) -> Vec<u8> {
+        let mut buf = self.take();
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    /// Empty [`PacketBuf`] with room for `capacity` bytes of data; its buffer
+    /// comes back when it is dropped
+    pub fn packet(&self, capacity: usize) -> PacketBuf {
+        PacketBuf::pooled(self.take(), capacity, self.clone())
+    }
+
+    /// [`PacketBuf`] holding a copy of `data` (see [`packet`](Self::packet))
+    pub fn packet_from(&self, data: &[u8]) -> PacketBuf {
+        let mut buf = self.packet(data.len());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    /// [`Bytes`] holding a copy of `data`; the buffer comes back when the last
+    /// view of it is dropped
+    pub fn bytes_from(&self, data: &[u8]) -> Bytes {
+        Bytes::pooled(self.copy_from(data), self.clone())
+    }
+
+    /// Give a buffer back for reuse
+    pub fn put(&self, mut buf: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        if state.free.len() < state.max_free {
+            buf.clear();
+            state.free.push(buf);
+        }
+    }
+
+    /// Hand `buf` to the application; the loan is given back when it is dropped
+    pub fn lend(&self, buf: Bytes) -> Loan {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_loan;
+        state.next_loan += 1;
+        state.loans.insert(id, (buf.len(), Instant::now()));
+        state.stats.lent += 1;
+        state.stats.max_outstanding = state.stats.max_outstanding.max(state.loans.len());
+        Loan {
+            id,
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    /// Loans not given back yet, oldest first
+    pub fn loans(&self) -> Vec<LoanInfo> {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let mut loans: Vec<LoanInfo> = state
+            .loans
+            .iter()
+            .map(|(&id, &(len, since))| LoanInfo {
+                id,
+                len,
+                age: now.duration_since(since),
+            })
+            .collect();
+        loans.sort_by_key(|loan| std::cmp::Reverse(loan.age));
+        loans
+    }
+
+    /// Free buffers ready to be taken
+    pub fn available(&self) -> usize {
+        self.state.lock().unwrap().free.len()
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.state.lock().unwrap().stats
+    }
+
+    fn give_back(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.loans.remove(&id);
+        state.stats.returned += 1;
+    }
+}
+
+/// Keeps no buffers
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("BufferPool")
+            .field("free", &state.free.len())
+            .field("loans", &state.loans.len())
+            .field("stats", &state.stats)
+            .finish()
+    }
+}
+
+/// Received data lent by a [`BufferPool`] instead of copied out.
+/// Dereferences to the bytes; dropping it gives the loan back, and the
+/// buffer to the pool it came from once no other view of it is left.
+pub struct Loan {
+    id: u64,
+    buf: Bytes,
+    pool: BufferPool,
+}
+
+impl Loan {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Deref for Loan {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl fmt::Debug for Loan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Loan")
+            .field("id", &self.id)
+            .field("len", &self.buf.len())
+            .finish()
+    }
+}
+
+impl Drop for Loan {
+    fn drop(&mut self) {
+        self.pool.give_back(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_pool_reuse_and_exhaustion() {
+        let pool = BufferPool::preallocated(1, PACKET_BUFFER_SIZE);
+        let first = pool.packet_from(b"data");
+        assert!(first.headroom() >= PACKET_HEADROOM);
+        let second = pool.packet(0);
+        assert_eq!(pool.stats().exhausted, 1);
+
+        drop(first);
+        drop(second);
+        let stats = pool.stats();
+        assert_eq!((stats.reused, stats.allocated), (1, 2));
+        // Only one buffer is kept, and it is the preallocated size
+        assert!(pool.take().capacity() >= PACKET_BUFFER_SIZE);
+        assert_eq!(pool.take().capacity(), 0);
+        assert_eq!(pool.stats().exhausted, 2);
+    }
+}
@@ -1,111 +1,21 @@
-use std::fmt;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
 
-use crate::context::ProtocolContexts;
-use crate::device::Device;
-use crate::protocol::ip::IpAddr;
-use crate::util::{cksum16, debugdump, ntoh16, ntoh32};
-
-pub const ICMP_HDR_SIZE: usize = 8;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum IcmpType {
-    EchoReply = 0,
-    DestUnreachable = 3,
-    SourceQuench = 4,
-    Redirect = 5,
-    Echo = 8,
-    TimeExceeded = 11,
-    ParameterProblem = 12,
-    Timestamp = 13,
-    TimestampReply = 14,
-    InfoRequest = 15,
-    InfoReply = 16,
-}
-
-impl IcmpType {
-    pub fn from_u8(value: u8) -> Option<Self> {
-        match value {
-            0 => Some(IcmpType::EchoReply),
-            3 => Some(IcmpType::DestUnreachable),
-            4 => Some(IcmpType::SourceQuench),
-            5 => Some(IcmpType::Redirect),
-            8 => Some(IcmpType::Echo),
-            11 => Some(IcmpType::TimeExceeded),
-            12 => Some(IcmpType::ParameterProblem),
-            13 => Some(IcmpType::Timestamp),
-            14 => Some(IcmpType::TimestampReply),
-            15 => Some(IcmpType::InfoRequest),
-            16 => Some(IcmpType::InfoReply),
-            _ => None,
-        }
-    }
-}
-
-/// ICMP Header (RFC 792)
-///
-/// Generic ICMP header format:
-/// ```text
-///  0                   1                   2                   3
-///  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
-/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-/// |     Type      |     Code      |          Checksum             |
-/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-/// |                         Values (varies)                       |
-/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-/// ```
-#[repr(C, packed)]
-#[derive(Debug, Clone, Copy)]
-pub struct IcmpHdr {
-    pub type_: u8,
-    pub code: u8,
-    pub sum: u16,
-    pub values: u32,
-}
-
-impl IcmpHdr {
-    /// Parse ICMP header from byte slice
-    pub fn from_bytes(data: &[u8]) -> Option<Self> {
-        if data.len() < ICMP_HDR_SIZE {
-            return None;
-        }
-        // Copy to avoid unaligned access issues with packed struct
-        Some(Self {
-            type_: data[0],
-            code: data[1],
-            sum: u16::from_be_bytes([data[2], data[3]]),
-            values: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
-        })
-    }
-
-    /// Get the ICMP type as an enum
-    pub fn type_enum(&self) -> Option<IcmpType> {
-        IcmpType::from_u8(self.type_)
-    }
+use tracing::Level;
 
-    /// For Echo Request/Reply: extract identifier
-    pub fn echo_id(&self) -> u16 {
-        (self.values >> 16) as u16
-    }
+use crate::bytes::{Bytes, Frame};
+use crate::context::ProtocolContexts;
+use crate::device::{Device, DeviceManager};
+use crate::drop::DropReason;
+use crate::error::{NetError, Result};
+use crate::limits::StackLimits;
+use crate::packet::PacketBuf;
+use crate::platform::Instant;
+use crate::protocol::ip::{self, IpAddr, IpProtocol};
+use crate::util::{LOG_ICMP_INPUT, LOG_ICMP_OUTPUT, cksum16};
 
-    /// For Echo Request/Reply: extract sequence number
-    pub fn echo_seq(&self) -> u16 {
-        (self.values & 0xFFFF) as u16
-    }
-}
-
-impl fmt::Display for IcmpHdr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // Copy fields to avoid unaligned access issues with packed struct
-        let sum = self.sum;
-        let values = self.values;
-        write!(
-            f,
-            "type={}, code={}, sum={:#06x}, values={:#010x}",
-            self.type_, self.code, sum, values
-        )
-    }
-}
+pub use crate::wire::icmp::*;
 
 /// Get ICMP type name string
 fn icmp_type_ntoa(type_: u8) -> &'static str {
@@ -131,9 +41,9 @@ fn icmp_print(data: &[u8]) {
         return;
     };
 
-    tracing::debug!("   type: {} ({})", hdr.type_, icmp_type_ntoa(hdr.type_));
-    tracing::debug!("   code: {}", hdr.code);
-    tracing::debug!("    sum: {:#06x}", ntoh16(hdr.sum));
+    tracing::debug!("   type: {} ({})", hdr.type_(), icmp_type_ntoa(hdr.type_()));
+    tracing::debug!("   code: {}", hdr.code());
+    tracing::debug!("    sum: {:#06x}", hdr.sum());
 
     match hdr.type_enum() {
         Some(IcmpType::EchoReply) | Some(IcmpType::Echo) => {
@@ -141,37 +51,327 @@ fn icmp_print(data: &[u8]) {
             tracing::debug!("    seq: {}", hdr.echo_seq());
         }
         Some(IcmpType::DestUnreachable) => {
-            tracing::debug!(" unused: {}", ntoh32(hdr.values));
+            tracing::debug!(" unused: {}", hdr.values());
         }
         _ => {
-            tracing::debug!("    dep: {:#010x}", ntoh32(hdr.values));
+            tracing::debug!("    dep: {:#010x}", hdr.values());
+        }
+    }
+}
+
+/// An Echo Reply received for a registered identifier
+#[derive(Debug, Clone)]
+pub struct EchoReply {
+    pub src: IpAddr,
+    pub id: u16,
+    pub seq: u16,
+    /// A slice of the received frame, or a copy (see [`crate::bytes`])
+    pub data: Bytes,
+    pub received_at: Instant,
+}
+
+/// ICMP echo "datagram sockets": Echo Replies collected per identifier for
+/// applications waiting on them, so ping-like tools need no raw IP access.
+/// Replies for identifiers nobody registered are discarded.
+pub struct EchoReplyTable {
+    replies: Mutex<HashMap<u16, Vec<EchoReply>>>,
+    /// Signalled when a reply is queued or an identifier is released
+    arrived: Condvar,
+    /// Replies kept per identifier until taken
+    queue_len: usize,
+}
+
+impl EchoReplyTable {
+    pub fn new() -> Self {
+        Self::with_limit(StackLimits::default().socket_queue_len)
+    }
+
+    /// Table keeping at most `queue_len` replies per identifier
+    pub fn with_limit(queue_len: usize) -> Self {
+        Self {
+            replies: Mutex::new(HashMap::new()),
+            arrived: Condvar::new(),
+            queue_len,
+        }
+    }
+
+    /// Start collecting replies for `id`. Fails if `id` is already in use.
+    pub fn register(&self, id: u16) -> Result<()> {
+        let mut replies = self.replies.lock().unwrap();
+        if replies.contains_key(&id) {
+            return Err(NetError::AlreadyExists(format!("echo identifier {}", id)));
         }
+        replies.insert(id, Vec::new());
+        Ok(())
+    }
+
+    /// Register a free identifier and return it
+    pub fn open(&self) -> Result<u16> {
+        let mut replies = self.replies.lock().unwrap();
+        let id =
+            (1..=u16::MAX)
+                .find(|id| !replies.contains_key(id))
+                .ok_or(NetError::LimitReached {
+                    what: "echo identifiers",
+                    limit: usize::from(u16::MAX),
+                })?;
+        replies.insert(id, Vec::new());
+        Ok(id)
     }
 
-    debugdump(data);
+    /// Stop collecting replies for `id`; blocked receivers fail
+    pub fn unregister(&self, id: u16) {
+        self.replies.lock().unwrap().remove(&id);
+        self.arrived.notify_all();
+    }
+
+    /// Block until a reply for `id` arrives and return it; `None` if `timeout`
+    /// passes first. Fails if `id` is not (or no longer) registered.
+    pub fn recv(&self, id: u16, timeout: Option<Duration>) -> Result<Option<EchoReply>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut replies = self.replies.lock().unwrap();
+        loop {
+            let Some(queue) = replies.get_mut(&id) else {
+                return Err(NetError::NotFound(format!("echo identifier {}", id)));
+            };
+            if !queue.is_empty() {
+                return Ok(Some(queue.remove(0)));
+            }
+            replies = match deadline {
+                None => self.arrived.wait(replies).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    self.arrived
+                        .wait_timeout(replies, deadline - now)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+    }
+
+    /// Take the replies collected for `id` so far
+    pub fn take(&self, id: u16) -> Vec<EchoReply> {
+        self.replies
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn deliver(&self, reply: EchoReply) -> Result<(), DropReason> {
+        match self.replies.lock().unwrap().get_mut(&reply.id) {
+            Some(queue) if queue.len() >= self.queue_len => Err(DropReason::SocketQueueFull),
+            Some(queue) => {
+                queue.push(reply);
+                self.arrived.notify_all();
+                Ok(())
+            }
+            None => {
+                tracing::debug!("no listener for echo reply, id={}", reply.id);
+                Err(DropReason::NoSocket)
+            }
+        }
+    }
 }
 
-pub fn input(data: &[u8], src: IpAddr, dst: IpAddr, _dev: &Device, _ctx: &ProtocolContexts) {
+impl Default for EchoReplyTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn input(
+    data: &Frame<'_>,
+    src: IpAddr,
+    dst: IpAddr,
+    dev: &Device,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) {
     // Validate minimum header size
     if data.len() < ICMP_HDR_SIZE {
+        ctx.drops.drop(DropReason::Malformed, data);
         tracing::error!("icmp_input: too short, len={}", data.len());
         return;
     }
 
     // Verify checksum
-    if cksum16(data, 0) != 0 {
+    if cksum16(data, 0) != 0 && ctx.drops.drop(DropReason::IcmpChecksum, data) {
         tracing::error!("icmp_input: checksum error");
         return;
     }
 
-    tracing::debug!("{} => {}, len={}", src, dst, data.len());
+    if LOG_ICMP_INPUT.allow(Level::DEBUG) {
+        tracing::debug!("{} => {}, len={}", src, dst, data.len());
+        icmp_print(data);
+    }
+
+    let Some(hdr) = IcmpHdr::from_bytes(data) else {
+        return;
+    };
+    match hdr.type_enum() {
+        Some(IcmpType::Echo) => {
+            // Reply from our unicast address even if the request was sent to a broadcast
+            let Some(iface) = dev
+                .ip_ifaces()
+                .find(|iface| iface.unicast == dst)
+                .or_else(|| dev.get_ip_iface())
+            else {
+                return;
+            };
+            if let Err(e) = output(
+                IcmpType::EchoReply,
+                hdr.code(),
+                hdr.values(),
+                &data[ICMP_HDR_SIZE..],
+                iface.unicast,
+                src,
+                ctx,
+                devices,
+            ) {
+                tracing::error!("icmp_input: failed to send echo reply: {}", e);
+            }
+        }
+        Some(IcmpType::EchoReply) => {
+            let reply = EchoReply {
+                src,
+                id: hdr.echo_id(),
+                seq: hdr.echo_seq(),
+                data: data.slice(ICMP_HDR_SIZE..).to_bytes(),
+                received_at: Instant::now(),
+            };
+            if let Err(reason) = ctx.icmp_echo.deliver(reply) {
+                ctx.drops.drop(reason, data);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Build the `values` field of an Echo Request/Reply from identifier and sequence number
+pub fn echo_values(id: u16, seq: u16) -> u32 {
+    ((id as u32) << 16) | seq as u32
+}
+
+/// Send an Echo Request for identifier `id` (see [`EchoReplyTable::open`]);
+/// replies are read with [`EchoReplyTable::recv`]
+pub fn send_echo(
+    id: u16,
+    seq: u16,
+    data: &[u8],
+    dst: IpAddr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    output(
+        IcmpType::Echo,
+        0,
+        echo_values(id, seq),
+        data,
+        IpAddr::ANY,
+        dst,
+        ctx,
+        devices,
+    )?;
+    Ok(())
+}
+
+/// Encode an ICMP message with its checksum filled in
+pub fn build(type_: IcmpType, code: u8, values: u32, data: &[u8]) -> PacketBuf {
+    let mut buf = PacketBuf::from(data);
+    let hdr = IcmpHdr::new(type_, code, values);
+    buf.push(ICMP_HDR_SIZE).copy_from_slice(&hdr.to_bytes());
 
-    icmp_print(data);
+    let sum = cksum16(&buf, 0);
+    buf[2..4].copy_from_slice(&sum.to_be_bytes());
+    buf
+}
+
+/// Answer an Echo Request straight from the driver receive path.
+///
+/// Used by devices in fast responder mode for latency benchmarks: the request is
+/// validated and the reply encoded with the same builders as the full pipeline,
+/// but routing, statistics and protocol dispatch are skipped. Returns the reply
+/// IP packet, or `None` if `packet` is not an Echo Request for `dev`.
+pub fn fast_echo_reply(packet: &[u8], dev: &Device) -> Option<PacketBuf> {
+    let hdr = ip::IpHdr::from_bytes(packet)?;
+    let hlen = hdr.hdr_len();
+    let total = hdr.total() as usize;
+    if hdr.version() != ip::IP_VERSION_IPV4
+        || hlen < ip::IP_HDR_SIZE_MIN
+        || total < hlen
+        || packet.len() < total
+        || hdr.protocol() != IpProtocol::Icmp
+        || hdr.offset() & 0x3fff != 0
+        || cksum16(&packet[..hlen], 0) != 0
+    {
+        return None;
+    }
+    let iface = dev.ip_ifaces().find(|iface| iface.unicast == hdr.dst())?;
+    let message = &packet[hlen..total];
+    let icmp = IcmpHdr::from_bytes(message)?;
+    if icmp.type_enum() != Some(IcmpType::Echo) || cksum16(message, 0) != 0 {
+        return None;
+    }
+
+    let mut reply = build(
+        IcmpType::EchoReply,
+        icmp.code(),
+        icmp.values(),
+        &message[ICMP_HDR_SIZE..],
+    );
+    ip::push_header(
+        &mut reply,
+        IpProtocol::Icmp,
+        &[],
+        hdr.id(),
+        0,
+        iface.unicast,
+        hdr.src(),
+        None,
+    )
+    .ok()?;
+    Some(reply)
+}
+
+/// Send an ICMP message (equivalent to C's `icmp_output`).
+/// `values` is the type-specific second word of the header in host byte order.
+#[allow(clippy::too_many_arguments)]
+pub fn output(
+    type_: IcmpType,
+    code: u8,
+    values: u32,
+    data: &[u8],
+    src: IpAddr,
+    dst: IpAddr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<isize> {
+    let buf = build(type_, code, values, data);
+
+    if LOG_ICMP_OUTPUT.allow(Level::DEBUG) {
+        tracing::debug!("{} => {}, len={}", src, dst, buf.len());
+        icmp_print(&buf);
+    }
+
+    ip::ip_output_buf(IpProtocol::Icmp, buf, src, dst, ctx, devices)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
     use super::*;
+    use crate::protocol::ip::{IP_HDR_SIZE_MIN, IpHdr};
+    use crate::testing::setup_loopback;
 
     #[test]
     fn test_icmp_hdr_from_bytes() {
@@ -183,13 +383,37 @@ mod tests {
         ];
 
         let hdr = IcmpHdr::from_bytes(&icmp_data).unwrap();
-        assert_eq!(hdr.type_, 8); // Echo Request
-        assert_eq!(hdr.code, 0);
+        assert_eq!(hdr.type_(), 8); // Echo Request
+        assert_eq!(hdr.code(), 0);
         assert_eq!(hdr.type_enum(), Some(IcmpType::Echo));
         assert_eq!(hdr.echo_id(), 128);
         assert_eq!(hdr.echo_seq(), 1);
     }
 
+    proptest! {
+        #[test]
+        fn test_icmp_hdr_bytes_roundtrip(bytes in any::<[u8; ICMP_HDR_SIZE]>()) {
+            let hdr = IcmpHdr::from_bytes(&bytes).unwrap();
+            prop_assert_eq!(hdr.to_bytes(), bytes);
+        }
+
+        #[test]
+        fn test_icmp_build_parse_roundtrip(
+            type_ in any::<u8>().prop_filter_map("known type", IcmpType::from_u8),
+            code in any::<u8>(),
+            values in any::<u32>(),
+            data in vec(any::<u8>(), 0..1024),
+        ) {
+            let buf = build(type_, code, values, &data);
+            let hdr = IcmpHdr::from_bytes(&buf).unwrap();
+            prop_assert_eq!(hdr.type_enum(), Some(type_));
+            prop_assert_eq!(hdr.code(), code);
+            prop_assert_eq!(hdr.values(), values);
+            prop_assert_eq!(cksum16(&buf, 0), 0);
+            prop_assert_eq!(&buf[ICMP_HDR_SIZE..], &data[..]);
+        }
+    }
+
     #[test]
     fn test_icmp_hdr_too_short() {
         let short_data = [0x08, 0x00, 0x35]; // Only 3 bytes
@@ -222,4 +446,118 @@ mod tests {
         assert_eq!(icmp_type_ntoa(11), "TimeExceeded");
         assert_eq!(icmp_type_ntoa(255), "Unknown");
     }
+
+    #[test]
+    fn test_icmp_output() {
+        let (devices, ctx, captured) = setup_loopback();
+        let loopback = IpAddr::from_str("127.0.0.1").unwrap();
+        let payload = b"0123456789";
+
+        output(
+            IcmpType::Echo,
+            0,
+            echo_values(128, 1),
+            payload,
+            loopback,
+            loopback,
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+
+        let packet = captured.lock().unwrap().pop().unwrap();
+        let ip_hdr = IpHdr::from_bytes(&packet).unwrap();
+        assert_eq!(ip_hdr.protocol(), IpProtocol::Icmp);
+
+        let icmp = &packet[IP_HDR_SIZE_MIN..];
+        assert_eq!(icmp.len(), ICMP_HDR_SIZE + payload.len());
+        assert_eq!(cksum16(icmp, 0), 0);
+
+        let hdr = IcmpHdr::from_bytes(icmp).unwrap();
+        assert_eq!(hdr.type_enum(), Some(IcmpType::Echo));
+        assert_eq!(hdr.echo_id(), 128);
+        assert_eq!(hdr.echo_seq(), 1);
+        assert_eq!(&icmp[ICMP_HDR_SIZE..], payload);
+    }
+
+    #[test]
+    fn test_icmp_input_echo_reply() {
+        let (devices, ctx, captured) = setup_loopback();
+        let loopback = IpAddr::from_str("127.0.0.1").unwrap();
+        let dev = devices.iter().next().unwrap();
+
+        let mut request = vec![0x08, 0x00, 0x00, 0x00, 0x12, 0x34, 0x00, 0x07];
+        request.extend_from_slice(b"hello");
+        let sum = cksum16(&request, 0);
+        request[2..4].copy_from_slice(&sum.to_be_bytes());
+
+        input(
+            &Frame::Borrowed(&request),
+            loopback,
+            loopback,
+            dev,
+            &ctx,
+            &devices,
+        );
+
+        let packet = captured.lock().unwrap().pop().unwrap();
+        let reply = &packet[IP_HDR_SIZE_MIN..];
+        let hdr = IcmpHdr::from_bytes(reply).unwrap();
+        assert_eq!(hdr.type_enum(), Some(IcmpType::EchoReply));
+        assert_eq!(hdr.echo_id(), 0x1234);
+        assert_eq!(hdr.echo_seq(), 7);
+        assert_eq!(&reply[ICMP_HDR_SIZE..], b"hello");
+
+        // The reply is collected only once someone listens for its identifier
+        input(
+            &Frame::Borrowed(reply),
+            loopback,
+            loopback,
+            dev,
+            &ctx,
+            &devices,
+        );
+        assert!(ctx.icmp_echo.take(0x1234).is_empty());
+
+        ctx.icmp_echo.register(0x1234).unwrap();
+        assert!(ctx.icmp_echo.register(0x1234).is_err());
+        input(
+            &Frame::Borrowed(reply),
+            loopback,
+            loopback,
+            dev,
+            &ctx,
+            &devices,
+        );
+        let replies = ctx.icmp_echo.take(0x1234);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].seq, 7);
+        assert_eq!(replies[0].data, b"hello");
+    }
+
+    #[test]
+    fn test_echo_socket_roundtrip() {
+        let stack = crate::stack::NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        let id = ctx.icmp_echo.open().unwrap();
+        let other = ctx.icmp_echo.open().unwrap();
+        assert_ne!(id, other);
+
+        let dst = IpAddr::from_str("127.0.0.1").unwrap();
+        send_echo(id, 3, b"ping", dst, &ctx, &devices).unwrap();
+        let reply = ctx.icmp_echo.recv(id, None).unwrap().unwrap();
+        assert_eq!((reply.src, reply.seq), (dst, 3));
+        assert_eq!(reply.data, b"ping");
+
+        // Replies go to the socket whose identifier they carry
+        let timeout = Some(Duration::from_millis(10));
+        assert!(ctx.icmp_echo.recv(other, timeout).unwrap().is_none());
+
+        ctx.icmp_echo.unregister(id);
+        assert!(ctx.icmp_echo.recv(id, timeout).is_err());
+    }
 }
@@ -0,0 +1,555 @@
+//! Internet Group Management Protocol, version 2 (RFC 2236), host side.
+//!
+//! Memberships are per interface and kept in the stack's [`IgmpGroups`].
+//! [`join`] reports a new membership right away and once more after a short
+//! random delay; leaving the last membership of a group sends a Leave Group
+//! message. Membership Queries are answered after a random delay of up to the
+//! query's maximum response time, unless another member of the group on the
+//! link reports first. Every host is a member of the all-hosts group
+//! (224.0.0.1), which is never reported.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::context::ProtocolContexts;
+use crate::device::ether::EtherAddr;
+use crate::device::{Device, DeviceIndex, DeviceManager};
+use crate::drop::DropReason;
+use crate::error::{NetError, Result};
+use crate::iface::IpIface;
+use crate::limits::StackLimits;
+use crate::platform::{self, Instant};
+use crate::protocol::ProtocolManager;
+use crate::protocol::ip::{self, IpAddr, IpProtocol};
+use crate::util::{cksum16, header_accessors, wire_codec};
+
+pub const IGMP_HDR_SIZE: usize = 8;
+
+pub const IGMP_TYPE_MEMBERSHIP_QUERY: u8 = 0x11;
+pub const IGMP_TYPE_V1_MEMBERSHIP_REPORT: u8 = 0x12;
+pub const IGMP_TYPE_V2_MEMBERSHIP_REPORT: u8 = 0x16;
+pub const IGMP_TYPE_LEAVE_GROUP: u8 = 0x17;
+
+/// Memberships the table holds by default
+pub const MULTICAST_GROUPS_SIZE: usize = 32;
+
+/// Largest delay before the repeated report of a new membership
+const IGMP_UNSOLICITED_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// Maximum response time of an IGMPv1 query, which leaves the field zero
+const IGMP_V1_MAX_RESPONSE_TIME: Duration = Duration::from_secs(10);
+
+/// IP Router Alert option (RFC 2113) that IGMPv2 messages carry
+const IP_OPT_ROUTER_ALERT: [u8; 4] = [148, 4, 0, 0];
+
+/// IGMP message (RFC 2236 Section 2)
+///
+/// ```text
+///  0                   1                   2                   3
+///  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |      Type     | Max Resp Time |           Checksum            |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |                         Group Address                         |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IgmpHdr {
+    type_: u8,
+    /// Tenths of a second
+    max_resp: u8,
+    sum: u16,
+    group: IpAddr,
+}
+
+wire_codec!(IgmpHdr, IGMP_HDR_SIZE, {
+    type_: u8,
+    max_resp: u8,
+    sum: u16,
+    group: IpAddr,
+});
+
+header_accessors!(IgmpHdr, {
+    type_: u8,
+    max_resp: u8,
+    group: IpAddr,
+});
+
+impl IgmpHdr {
+    pub fn new(type_: u8, max_resp: u8, group: IpAddr) -> Self {
+        Self {
+            type_,
+            max_resp,
+            sum: 0,
+            group,
+        }
+    }
+
+    /// The header with its checksum filled in
+    pub fn with_checksum(mut self) -> Self {
+        self.sum = 0;
+        self.sum = cksum16(&self.to_bytes(), 0);
+        self
+    }
+}
+
+impl fmt::Display for IgmpHdr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "type={:#04x}, max_resp={}, group={}",
+            self.type_(),
+            self.max_resp(),
+            self.group()
+        )
+    }
+}
+
+/// Ethernet address a multicast group maps to (RFC 1112 Section 6.4)
+pub fn multicast_hwaddr(group: IpAddr) -> EtherAddr {
+    let octets = group.to_ne_bytes();
+    EtherAddr([0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3]])
+}
+
+/// A random delay of at most `max`
+fn random_delay(max: Duration) -> Duration {
+    let millis = max.as_millis() as u64;
+    if millis == 0 {
+        return Duration::ZERO;
+    }
+    let seed = platform::since_epoch().subsec_nanos() as u64 ^ platform::process_id() as u64;
+    Duration::from_millis(seed % (millis + 1))
+}
+
+/// Membership of an interface in a group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupMembership {
+    pub group: IpAddr,
+    /// Unicast address of the interface
+    pub iface: IpAddr,
+    pub device: DeviceIndex,
+    /// Joins not yet matched by a leave
+    pub users: usize,
+}
+
+struct MembershipEntry {
+    group: IpAddr,
+    iface: IpIface,
+    users: usize,
+    /// When to send the next report; `None` when none is pending
+    report_at: Option<Instant>,
+}
+
+/// Multicast groups joined per interface. Joining an interface to a group
+/// several times takes as many leaves before it is left.
+pub struct IgmpGroups {
+    entries: Mutex<Vec<MembershipEntry>>,
+    limit: usize,
+    clock: Clock,
+}
+
+impl IgmpGroups {
+    pub fn new() -> Self {
+        Self::with_limit(StackLimits::default().multicast_groups)
+    }
+
+    /// Table of at most `limit` memberships
+    pub fn with_limit(limit: usize) -> Self {
+        Self::with_clock(limit, Clock::default())
+    }
+
+    /// Like [`with_limit`](Self::with_limit), with reports delayed by `clock`
+    pub fn with_clock(limit: usize, clock: Clock) -> Self {
+        Self {
+            entries: Mutex::new(Vec::new()),
+            limit,
+            clock,
+        }
+    }
+
+    /// Add a user of `group` on `iface`; returns whether the membership is new
+    pub fn join(&self, iface: &IpIface, group: IpAddr) -> Result<bool> {
+        if !group.is_multicast() || group == IpAddr::ALL_HOSTS {
+            return Err(NetError::InvalidInput("not a group that can be joined"));
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries
+            .iter_mut()
+            .find(|entry| entry.group == group && entry.iface.unicast == iface.unicast)
+        {
+            entry.users += 1;
+            return Ok(false);
+        }
+        if entries.len() >= self.limit {
+            return Err(NetError::LimitReached {
+                what: "multicast memberships",
+                limit: self.limit,
+            });
+        }
+        entries.push(MembershipEntry {
+            group,
+            iface: iface.clone(),
+            users: 1,
+            report_at: Some(self.clock.now() + random_delay(IGMP_UNSOLICITED_REPORT_INTERVAL)),
+        });
+        Ok(true)
+    }
+
+    /// Remove a user of `group` on the interface with address `unicast`;
+    /// returns the interface if that was the last one
+    pub fn leave(&self, unicast: IpAddr, group: IpAddr) -> Result<Option<IpIface>> {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(index) = entries
+            .iter()
+            .position(|entry| entry.group == group && entry.iface.unicast == unicast)
+        else {
+            return Err(NetError::NotFound(format!(
+                "membership of {} on {}",
+                group, unicast
+            )));
+        };
+        entries[index].users -= 1;
+        if entries[index].users > 0 {
+            return Ok(None);
+        }
+        Ok(Some(entries.remove(index).iface))
+    }
+
+    /// Whether packets to `group` are accepted on `device`
+    pub fn is_member(&self, device: DeviceIndex, group: IpAddr) -> bool {
+        group == IpAddr::ALL_HOSTS
+            || self
+                .entries
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|entry| entry.group == group && entry.iface.device_index == device)
+    }
+
+    /// Every membership, ordered by group
+    pub fn memberships(&self) -> Vec<GroupMembership> {
+        let entries = self.entries.lock().unwrap();
+        let mut memberships: Vec<_> = entries
+            .iter()
+            .map(|entry| GroupMembership {
+                group: entry.group,
+                iface: entry.iface.unicast,
+                device: entry.iface.device_index,
+                users: entry.users,
+            })
+            .collect();
+        memberships.sort_by_key(|membership| membership.group.to_ne_bytes());
+        memberships
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Schedule reports for a query on `device` about `group` (every group if
+    /// unspecified), keeping any report already due sooner
+    fn query(&self, device: DeviceIndex, group: IpAddr, max_resp: Duration) {
+        let now = self.clock.now();
+        for entry in self.entries.lock().unwrap().iter_mut() {
+            if entry.iface.device_index != device || (group != IpAddr::ANY && entry.group != group)
+            {
+                continue;
+            }
+            let at = now + random_delay(max_resp);
+            if entry.report_at.is_none_or(|pending| at < pending) {
+                entry.report_at = Some(at);
+            }
+        }
+    }
+
+    /// Cancel our pending report of `group` on `device`; another member sent one
+    fn suppress(&self, device: DeviceIndex, group: IpAddr) {
+        for entry in self.entries.lock().unwrap().iter_mut() {
+            if entry.iface.device_index == device && entry.group == group {
+                entry.report_at = None;
+            }
+        }
+    }
+
+    /// Reports whose time has come, as interface and group
+    fn due(&self) -> Vec<(IpIface, IpAddr)> {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .iter_mut()
+            .filter(|entry| entry.report_at.is_some_and(|at| at <= now))
+            .map(|entry| {
+                entry.report_at = None;
+                (entry.iface.clone(), entry.group)
+            })
+            .collect()
+    }
+}
+
+impl Default for IgmpGroups {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn output(
+    type_: u8,
+    group: IpAddr,
+    dst: IpAddr,
+    iface: &IpIface,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    let msg = IgmpHdr::new(type_, 0, group).with_checksum().to_bytes();
+    tracing::debug!(
+        "igmp_output: {} => {}, type={:#04x}, group={}",
+        iface.unicast,
+        dst,
+        type_,
+        group
+    );
+    ip::ip_output_options(
+        IpProtocol::Igmp,
+        ctx.packets.packet_from(&msg),
+        &IP_OPT_ROUTER_ALERT,
+        iface.unicast,
+        dst,
+        None,
+        ctx,
+        devices,
+    )?;
+    Ok(())
+}
+
+/// Join `group` on the interface with address `unicast`, or on the interface
+/// the route to the group leaves through if unspecified. Returns the address of
+/// the interface joined.
+pub fn join(
+    unicast: IpAddr,
+    group: IpAddr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<IpAddr> {
+    let iface = if unicast == IpAddr::ANY {
+        ctx.ip_routes
+            .lookup(group)
+            .map(|route| route.iface)
+            .ok_or(NetError::NoRoute(group.into()))?
+    } else {
+        ctx.ip_ifaces
+            .select(unicast)
+            .cloned()
+            .ok_or(NetError::NoInterface(unicast.into()))?
+    };
+
+    if ctx.igmp.join(&iface, group)? {
+        tracing::debug!("igmp: joined {} on {}", group, iface.unicast);
+        output(
+            IGMP_TYPE_V2_MEMBERSHIP_REPORT,
+            group,
+            group,
+            &iface,
+            ctx,
+            devices,
+        )?;
+    }
+    Ok(iface.unicast)
+}
+
+/// Leave `group` on the interface with address `unicast`
+pub fn leave(
+    unicast: IpAddr,
+    group: IpAddr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    if let Some(iface) = ctx.igmp.leave(unicast, group)? {
+        tracing::debug!("igmp: left {} on {}", group, iface.unicast);
+        output(
+            IGMP_TYPE_LEAVE_GROUP,
+            group,
+            IpAddr::ALL_ROUTERS,
+            &iface,
+            ctx,
+            devices,
+        )?;
+    }
+    Ok(())
+}
+
+pub fn input(data: &[u8], src: IpAddr, dst: IpAddr, dev: &Device, ctx: &ProtocolContexts) {
+    let Some(hdr) = IgmpHdr::from_bytes(data) else {
+        ctx.drops.drop(DropReason::Malformed, data);
+        tracing::debug!("igmp_input: too short, len={}", data.len());
+        return;
+    };
+    if cksum16(data, 0) != 0 && ctx.drops.drop(DropReason::IgmpChecksum, data) {
+        tracing::debug!("igmp_input: checksum error");
+        return;
+    }
+    tracing::debug!("igmp_input: {} => {}, {}", src, dst, hdr);
+
+    match hdr.type_() {
+        IGMP_TYPE_MEMBERSHIP_QUERY => {
+            let max_resp = match hdr.max_resp() {
+                0 => IGMP_V1_MAX_RESPONSE_TIME,
+                tenths => Duration::from_millis(tenths as u64 * 100),
+            };
+            ctx.igmp.query(dev.index, hdr.group(), max_resp);
+        }
+        IGMP_TYPE_V1_MEMBERSHIP_REPORT | IGMP_TYPE_V2_MEMBERSHIP_REPORT => {
+            ctx.igmp.suppress(dev.index, hdr.group());
+        }
+        // Leave Group messages are for routers
+        _ => {}
+    }
+}
+
+/// How often [`timer`] runs
+pub const IGMP_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn init(protocols: &mut ProtocolManager) -> Result<()> {
+    protocols.register_timer("igmp", IGMP_TIMER_INTERVAL, Arc::new(timer))
+}
+
+/// Send the reports whose delay has passed
+pub fn timer(ctx: &ProtocolContexts, devices: &DeviceManager) {
+    for (iface, group) in ctx.igmp.due() {
+        if let Err(e) = output(
+            IGMP_TYPE_V2_MEMBERSHIP_REPORT,
+            group,
+            group,
+            &iface,
+            ctx,
+            devices,
+        ) {
+            tracing::debug!("igmp: report of {} failed: {}", group, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::protocol::ip::IpHdr;
+    use crate::testing::setup_loopback;
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    /// The IGMP message of a captured packet, with the packet's TTL and destination
+    fn igmp_of(packet: &[u8]) -> (u8, IpAddr, u8, IpAddr) {
+        let hdr = IpHdr::from_bytes(packet).unwrap();
+        assert_eq!(hdr.protocol(), IpProtocol::Igmp);
+        let igmp = IgmpHdr::from_bytes(&packet[hdr.hdr_len()..]).unwrap();
+        (hdr.ttl(), hdr.dst(), igmp.type_(), igmp.group())
+    }
+
+    #[test]
+    fn test_igmp_join_query_leave() {
+        let (devices, ctx, captured) = setup_loopback();
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        let lo = addr("127.0.0.1");
+        let group = addr("239.1.2.3");
+
+        assert!(ctx.igmp.is_member(dev.index, IpAddr::ALL_HOSTS));
+        assert!(!ctx.igmp.is_member(dev.index, group));
+        // No route to pick the interface by
+        assert!(join(IpAddr::ANY, group, &ctx, &devices).is_err());
+        assert_eq!(join(lo, group, &ctx, &devices).unwrap(), lo);
+        assert_eq!(join(lo, group, &ctx, &devices).unwrap(), lo);
+        assert!(join(lo, addr("192.0.2.1"), &ctx, &devices).is_err());
+        assert!(ctx.igmp.is_member(dev.index, group));
+        assert_eq!(ctx.igmp.memberships()[0].users, 2);
+
+        // Only the first join is reported, with TTL 1 and a Router Alert option
+        let packet = captured.lock().unwrap().remove(0);
+        assert!(captured.lock().unwrap().is_empty());
+        assert_eq!(IpHdr::from_bytes(&packet).unwrap().hdr_len(), 24);
+        assert_eq!(
+            igmp_of(&packet),
+            (1, group, IGMP_TYPE_V2_MEMBERSHIP_REPORT, group)
+        );
+
+        // A query is answered within its maximum response time
+        let query = IgmpHdr::new(IGMP_TYPE_MEMBERSHIP_QUERY, 1, IpAddr::ANY)
+            .with_checksum()
+            .to_bytes();
+        input(&query, addr("127.0.0.2"), IpAddr::ALL_HOSTS, dev, &ctx);
+        std::thread::sleep(Duration::from_millis(110));
+        timer(&ctx, &devices);
+        let packet = captured.lock().unwrap().pop().unwrap();
+        assert_eq!(igmp_of(&packet).2, IGMP_TYPE_V2_MEMBERSHIP_REPORT);
+
+        // ... unless another member reports first
+        input(&query, addr("127.0.0.2"), IpAddr::ALL_HOSTS, dev, &ctx);
+        let report = IgmpHdr::new(IGMP_TYPE_V2_MEMBERSHIP_REPORT, 0, group)
+            .with_checksum()
+            .to_bytes();
+        input(&report, addr("127.0.0.3"), group, dev, &ctx);
+        std::thread::sleep(Duration::from_millis(110));
+        timer(&ctx, &devices);
+        assert!(captured.lock().unwrap().is_empty());
+
+        leave(lo, group, &ctx, &devices).unwrap();
+        assert!(captured.lock().unwrap().is_empty());
+        leave(lo, group, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        assert_eq!(
+            igmp_of(&packet),
+            (1, IpAddr::ALL_ROUTERS, IGMP_TYPE_LEAVE_GROUP, group)
+        );
+        assert!(ctx.igmp.is_empty());
+        assert!(leave(lo, group, &ctx, &devices).is_err());
+    }
+
+    #[test]
+    fn test_igmp_reports_on_stack_clock() {
+        let time = VirtualClock::new();
+        let groups = IgmpGroups::with_clock(4, Clock::new(time.clone()));
+        let iface = IpIface::new("192.0.2.1", "255.255.255.0", DeviceIndex(0)).unwrap();
+        let group = addr("239.1.2.3");
+        groups.join(&iface, group).unwrap();
+        let due = || -> Vec<_> {
+            groups
+                .due()
+                .into_iter()
+                .map(|(iface, group)| (iface.unicast, group))
+                .collect()
+        };
+
+        // The unsolicited report is due within its interval of virtual time
+        time.advance(IGMP_UNSOLICITED_REPORT_INTERVAL);
+        assert_eq!(due(), [(iface.unicast, group)]);
+        assert!(due().is_empty());
+
+        groups.query(DeviceIndex(0), IpAddr::ANY, Duration::from_secs(1));
+        time.advance(Duration::from_secs(1));
+        assert_eq!(due(), [(iface.unicast, group)]);
+    }
+
+    proptest! {
+        #[test]
+        fn test_igmp_hdr_build_parse((type_, max_resp, group) in any::<(u8, u8, [u8; 4])>()) {
+            let group = IpAddr::from_ne_bytes(group);
+            let bytes = IgmpHdr::new(type_, max_resp, group).with_checksum().to_bytes();
+            prop_assert_eq!(cksum16(&bytes, 0), 0);
+            let parsed = IgmpHdr::from_bytes(&bytes).unwrap();
+            prop_assert_eq!(parsed.type_(), type_);
+            prop_assert_eq!(parsed.max_resp(), max_resp);
+            prop_assert_eq!(parsed.group(), group);
+            prop_assert_eq!(parsed.to_bytes(), bytes);
+        }
+    }
+}
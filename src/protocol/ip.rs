@@ -1,306 +1,198 @@
-use std::fmt;
-use std::fmt::Display;
-use std::ops::{BitAnd, BitOr, Not};
+use std::str::FromStr;
+use std::sync::Arc;
 
-use anyhow::Result;
+use tracing::Level;
 
 use super::{PROTOCOL_TYPE_IP, ProtocolManager, ProtocolType};
+use crate::bytes::Frame;
 use crate::context::ProtocolContexts;
 use crate::device::{Device, DeviceManager, NET_DEVICE_FLAG_NEED_ARP};
-use crate::iface::{IpIface, NetIface};
-use crate::protocol::icmp;
-use crate::util::{cksum16, debugdump, hton16, ntoh16};
-
-pub const IP_VERSION_IPV4: u8 = 4;
-
-pub const IP_HDR_SIZE_MIN: usize = 20;
-pub const IP_HDR_SIZE_MAX: usize = 60;
-
-pub const IP_TOTAL_SIZE_MAX: usize = u16::MAX as usize;
-pub const IP_PAYLOAD_SIZE_MAX: usize = IP_TOTAL_SIZE_MAX - IP_HDR_SIZE_MIN;
-
-pub const IP_ADDR_LEN: usize = 4;
-pub const IP_ADDR_STR_LEN: usize = 16;
-
-const IP_HDR_FLAG_MF: u16 = 0x2000;
-#[allow(dead_code)]
-const IP_HDR_FLAG_DF: u16 = 0x4000;
-#[allow(dead_code)]
-const IP_HDR_FLAG_RF: u16 = 0x8000;
-const IP_HDR_OFFSET_MASK: u16 = 0x1fff;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum IpProtocol {
-    Icmp,
-    Tcp,
-    Udp,
-    Other(u8),
-}
-
-impl IpProtocol {
-    pub fn from_u8(value: u8) -> Self {
-        match value {
-            1 => IpProtocol::Icmp,
-            6 => IpProtocol::Tcp,
-            17 => IpProtocol::Udp,
-            other => IpProtocol::Other(other),
-        }
-    }
-
-    pub fn to_u8(self) -> u8 {
-        match self {
-            IpProtocol::Icmp => 1,
-            IpProtocol::Tcp => 6,
-            IpProtocol::Udp => 17,
-            IpProtocol::Other(v) => v,
-        }
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
-pub struct IpAddr(u32);
+use crate::diagnose::{self, Conflicts};
+use crate::drop::DropReason;
+use crate::error::{NetError, Result};
+use crate::iface::{IpIface, NetIface, NetIfaceFamily};
+use crate::packet::PacketBuf;
+use crate::platform;
+use crate::protocol::icmp::{
+    self, ICMP_CODE_EXCEEDED_TTL, ICMP_CODE_NET_UNREACH, ICMP_CODE_PORT_UNREACH, IcmpType,
+};
+use crate::protocol::{igmp, nat, raw, tcp, udp};
+use crate::util::{LOG_IP_INPUT, LOG_IP_OUTPUT, cksum16};
+
+pub use crate::wire::ip::*;
 
-impl IpAddr {
-    pub const ANY: Self = IpAddr(0x00000000);
-    pub const BROADCAST: Self = IpAddr(0xffffffff);
-
-    #[inline]
-    pub fn from_ne_bytes(bytes: [u8; 4]) -> Self {
-        IpAddr(u32::from_ne_bytes(bytes))
-    }
-
-    #[inline]
-    pub fn to_ne_bytes(self) -> [u8; 4] {
-        self.0.to_ne_bytes()
-    }
-
-    pub fn from_str(s: &str) -> Result<Self> {
-        let parts: Vec<&str> = s.split('.').collect();
-        if parts.len() != 4 {
-            anyhow::bail!("Invalid IP address format: {}", s);
-        }
-
-        let mut bytes = [0u8; 4];
-        for (i, part) in parts.iter().enumerate() {
-            let octet: u8 = part
-                .parse()
-                .map_err(|_| anyhow::anyhow!("Invalid octet in IP address: {}", part))?;
-            bytes[i] = octet;
-        }
-
-        Ok(IpAddr::from_ne_bytes(bytes))
-    }
-
-    pub fn to_string(self) -> String {
-        let bytes = self.to_ne_bytes();
-        format!("{}.{}.{}.{}", bytes[0], bytes[1], bytes[2], bytes[3])
-    }
-}
+fn ip_print(data: &[u8]) {
+    let Some(ip_hdr) = IpHdr::from_bytes(data) else {
+        tracing::warn!("IP packet too short: len={}", data.len());
+        return;
+    };
 
-impl Display for IpAddr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let bytes = self.to_ne_bytes();
-        write!(f, "{}.{}.{}.{}", bytes[0], bytes[1], bytes[2], bytes[3])
-    }
+    tracing::info!("IP Header: {}", ip_hdr);
 }
 
-impl BitAnd for IpAddr {
-    type Output = Self;
-    fn bitand(self, rhs: Self) -> Self::Output {
-        IpAddr(self.0 & rhs.0)
-    }
+/// Whether `dst` is a broadcast address on the receiving device or a multicast
+/// group (never forwarded)
+fn is_broadcast_for(dev: &Device, dst: IpAddr) -> bool {
+    dst == IpAddr::BROADCAST
+        || dst.is_multicast()
+        || dev.ip_ifaces().any(|iface| iface.broadcast == dst)
 }
 
-impl BitOr for IpAddr {
-    type Output = Self;
-    fn bitor(self, rhs: Self) -> Self::Output {
-        IpAddr(self.0 | rhs.0)
+fn ip_input_handler(
+    data: &Frame<'_>,
+    dev: &Device,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) {
+    if let Err(e) = ip_input(data, dev, ctx, devices) {
+        tracing::error!("ip_input error: {}", e);
     }
 }
 
-impl Not for IpAddr {
-    type Output = Self;
-    fn not(self) -> Self::Output {
-        IpAddr(!self.0)
+pub fn ip_input(
+    data: &Frame<'_>,
+    dev: &Device,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    let log = LOG_IP_INPUT.allow(Level::INFO);
+    if log {
+        tracing::debug!("ip_input: dev={}, len={}", dev.name_string(), data.len());
     }
-}
 
-#[repr(C, packed)]
-#[derive(Debug, Clone, Copy)]
-pub struct IpHdr {
-    pub vhl: u8,
-    pub tos: u8,
-    pub total: u16,
-    pub id: u16,
-    pub offset: u16,
-    pub ttl: u8,
-    pub protocol: u8,
-    pub sum: u16,
-    pub src: IpAddr,
-    pub dst: IpAddr,
-}
+    ctx.stats.ip.rx.add(data.len());
+    let Some(hdr) = IpHdr::from_bytes(data) else {
+        ctx.drops.drop(DropReason::Malformed, data);
+        return Err(NetError::Truncated { len: data.len() });
+    };
 
-impl IpHdr {
-    pub fn new(
-        protocol: IpProtocol,
-        total: u16,
-        id: u16,
-        offset: u16,
-        src: IpAddr,
-        dst: IpAddr,
-    ) -> Self {
-        let hlen = IP_HDR_SIZE_MIN;
-        let vhl = (IP_VERSION_IPV4 << 4) | ((hlen / 4) as u8);
-        Self {
-            vhl,
-            tos: 0,
-            total: hton16(total),
-            id: hton16(id),
-            offset: hton16(offset),
-            ttl: IP_TTL_DEFAULT,
-            protocol: protocol.to_u8(),
-            sum: 0,
-            src,
-            dst,
-        }
+    if hdr.version() != IP_VERSION_IPV4 {
+        ctx.drops.drop(DropReason::Malformed, data);
+        return Err(NetError::Malformed(format!("IP version {}", hdr.version())));
     }
 
-    pub fn to_bytes(&self) -> [u8; IP_HDR_SIZE_MIN] {
-        // SAFETY: IpHdr is #[repr(C, packed)] and exactly IP_HDR_SIZE_MIN bytes
-        unsafe { std::mem::transmute_copy(self) }
+    let hlen = hdr.hdr_len();
+    if hlen < IP_HDR_SIZE_MIN {
+        ctx.drops.drop(DropReason::Malformed, data);
+        return Err(NetError::Malformed(format!("IP header length {}", hlen)));
     }
-
-    pub fn with_checksum(mut self) -> Self {
-        self.sum = 0;
-        let bytes = self.to_bytes();
-        self.sum = hton16(cksum16(&bytes, 0));
-        self
+    if data.len() < hlen {
+        ctx.drops.drop(DropReason::Malformed, data);
+        return Err(NetError::Truncated { len: data.len() });
     }
 
-    pub fn from_bytes(data: &[u8]) -> Option<&Self> {
-        if data.len() < IP_HDR_SIZE_MIN {
-            return None;
-        }
-        // SAFETY: We've verified the length is sufficient
-        Some(unsafe { &*(data.as_ptr() as *const IpHdr) })
+    if cksum16(&data[..hlen], 0) != 0 && ctx.drops.drop(DropReason::IpChecksum, data) {
+        return Err(NetError::ChecksumMismatch);
     }
 
-    pub fn version(&self) -> u8 {
-        (self.vhl >> 4) & 0x0f
+    let total = hdr.total() as usize;
+    if total < hlen {
+        ctx.drops.drop(DropReason::Malformed, data);
+        return Err(NetError::Malformed(format!(
+            "IP total length {} < header length {}",
+            total, hlen
+        )));
     }
-
-    pub fn hdr_len(&self) -> usize {
-        ((self.vhl & 0x0f) as usize) * 4
+    if data.len() < total {
+        ctx.drops.drop(DropReason::Malformed, data);
+        return Err(NetError::Truncated { len: data.len() });
     }
 
-    pub fn protocol(&self) -> IpProtocol {
-        IpProtocol::from_u8(self.protocol)
+    let offset = hdr.offset();
+    if offset & (IP_HDR_FLAG_MF | IP_HDR_OFFSET_MASK) != 0 {
+        ctx.drops.drop(DropReason::Fragmented, data);
+        return Err(NetError::Unsupported("IP fragments"));
     }
-}
 
-impl fmt::Display for IpHdr {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "vhl={:#04x}, tos={:#04x}, total={}, id={}, offset={:#06x}, ttl={}, protocol={}, sum={:#06x}, src={}, dst={}",
-            self.vhl,
-            self.tos,
-            u16::from_be(self.total),
-            u16::from_be(self.id),
-            u16::from_be(self.offset),
-            self.ttl,
-            self.protocol,
-            u16::from_be(self.sum),
-            self.src.to_string(),
-            self.dst.to_string()
-        )
+    let dst = hdr.dst();
+    if ctx.ip_config.forwarding
+        && (ctx.ip_config.masquerade == Some(dst) || ctx.nat.forwards_for(dst))
+    {
+        let mut packet = data[..total].to_vec();
+        if let Some(inside) = nat::translate_inbound(&mut packet, hlen, ctx) {
+            return forward(&packet, hlen, inside, None, ctx, devices);
+        }
     }
-}
 
-fn ip_print(data: &[u8]) {
-    let Some(ip_hdr) = IpHdr::from_bytes(data) else {
-        tracing::warn!("IP packet too short: len={}", data.len());
-        return;
+    let matched = if dst.is_multicast() {
+        dev.has_family(NetIfaceFamily::Ip) && ctx.igmp.is_member(dev.index, dst)
+    } else {
+        dev.ifaces.iter().any(|iface| match iface {
+            NetIface::Ip(ip_iface) => ip_iface.is_destination_match(dst),
+            NetIface::Ipv6(_) => false,
+        })
     };
 
-    tracing::info!("IP Header: {}", ip_hdr);
-    debugdump(data);
-}
-
-fn ip_input_handler(data: &[u8], dev: &Device, ctx: &ProtocolContexts) {
-    if let Err(e) = ip_input(data, dev, ctx) {
-        tracing::error!("ip_input error: {}", e);
-    }
-}
-
-pub fn ip_input(data: &[u8], dev: &Device, _ctx: &ProtocolContexts) -> Result<()> {
-    tracing::debug!("ip_input: dev={}, len={}", dev.name_string(), data.len());
-
-    let hdr = IpHdr::from_bytes(data)
-        .ok_or_else(|| anyhow::anyhow!("IP packet too short: len={}", data.len()))?;
-
-    if hdr.version() != IP_VERSION_IPV4 {
-        anyhow::bail!("Unsupported IP version: {}", hdr.version());
+    if !matched {
+        if ctx.ip_config.forwarding && !is_broadcast_for(dev, dst) {
+            return forward(&data[..total], hlen, dst, None, ctx, devices);
+        }
+        if ctx.drops.drop(DropReason::NotForUs, data) {
+            tracing::debug!("No matching IP interface found for dst={}", dst);
+            return Ok(());
+        }
     }
 
-    let hlen = hdr.hdr_len();
-    if data.len() < hlen {
-        anyhow::bail!(
-            "IP packet too short for header length: len={}, hlen={}",
-            data.len(),
-            hlen
+    if log {
+        tracing::debug!(
+            "Packet accepted: src={}, dst={}, protocol={:?}",
+            hdr.src(),
+            hdr.dst(),
+            hdr.protocol()
         );
     }
 
-    if cksum16(&data[..hlen], 0) != 0 {
-        anyhow::bail!("IP header checksum error");
-    }
-
-    let total = ntoh16(hdr.total) as usize;
-    if data.len() < total {
-        anyhow::bail!(
-            "IP packet too short for total length: len={}, total={}",
-            data.len(),
-            total
-        );
+    if let Some(route) =
+        SourceRoute::parse(&data[..hlen]).map_err(|e| NetError::Malformed(e.to_string()))?
+        && let Some(next) = route.next_hop()
+    {
+        if !ctx.ip_config.accept_source_route && ctx.drops.drop(DropReason::SourceRoute, data) {
+            tracing::debug!("Source routed packet dropped, src={}", hdr.src());
+            return Ok(());
+        }
+        return forward(&data[..total], hlen, next, Some(&route), ctx, devices);
     }
 
-    let offset = ntoh16(hdr.offset);
-    if offset & (IP_HDR_FLAG_MF | IP_HDR_OFFSET_MASK) != 0 {
-        anyhow::bail!("Fragmented IP packets are not supported");
+    if log {
+        ip_print(data);
     }
 
-    let dst = hdr.dst;
-    let matched = dev.ifaces.iter().any(|iface| match iface {
-        NetIface::Ip(ip_iface) => ip_iface.is_destination_match(dst),
-    });
+    ctx.peer_stats.record_in(hdr.src(), total);
 
-    if !matched {
-        tracing::debug!("No matching IP interface found for dst={}", dst.to_string());
-        return Ok(());
+    // The transports get a slice of the frame, and keep only what they must
+    let payload = data.slice(hlen..total);
+    if let Some(stats) = ctx.stats.transport(hdr.protocol()) {
+        stats.rx.add(payload.len());
     }
-
-    tracing::debug!(
-        "Packet accepted: src={}, dst={}, protocol={:?}",
-        hdr.src.to_string(),
-        hdr.dst.to_string(),
-        hdr.protocol()
-    );
-
-    ip_print(data);
-
-    let payload = &data[hlen..total];
+    let raw = raw::input(hdr.protocol(), &payload, hdr.src(), hdr.dst(), ctx);
     match hdr.protocol() {
         IpProtocol::Icmp => {
-            icmp::input(payload, hdr.src, hdr.dst, dev, _ctx);
+            icmp::input(&payload, hdr.src(), hdr.dst(), dev, ctx, devices);
+        }
+        IpProtocol::Igmp => {
+            igmp::input(&payload, hdr.src(), hdr.dst(), dev, ctx);
         }
         IpProtocol::Tcp => {
-            tracing::debug!("Dispatching to TCP (not yet implemented)");
+            tcp::input(&payload, hdr.src(), hdr.dst(), ctx, devices);
         }
         IpProtocol::Udp => {
-            tracing::debug!("Dispatching to UDP (not yet implemented)");
+            // RFC 1122 3.2.2.1: only unicast datagrams to a closed port get an error
+            if !udp::input(&payload, hdr.src(), dst, ctx) && !raw && !is_broadcast_for(dev, dst) {
+                icmp::output(
+                    IcmpType::DestUnreachable,
+                    ICMP_CODE_PORT_UNREACH,
+                    0,
+                    &data[..total.min(hlen + 8)],
+                    IpAddr::ANY,
+                    hdr.src(),
+                    ctx,
+                    devices,
+                )?;
+            }
         }
+        IpProtocol::Other(_) if raw => {}
         IpProtocol::Other(p) => {
+            ctx.drops.drop(DropReason::UnknownProtocol, data);
             tracing::debug!("Unknown IP protocol: {}", p);
         }
     }
@@ -308,16 +200,13 @@ pub fn ip_input(data: &[u8], dev: &Device, _ctx: &ProtocolContexts) -> Result<()
     Ok(())
 }
 
-const IP_TTL_DEFAULT: u8 = 0xff;
+/// Multicast stays on the local link unless asked otherwise (as `IP_MULTICAST_TTL`)
+const IP_MULTICAST_TTL_DEFAULT: u8 = 1;
 
 /// Generate a random 16-bit ID for IP packets
 fn random16() -> u16 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    let seed = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_nanos() as u16;
-    seed.wrapping_add(std::process::id() as u16)
+    let seed = platform::since_epoch().as_nanos() as u16;
+    seed.wrapping_add(platform::process_id() as u16)
 }
 
 /// Register an IP interface on a device and global registry (single API).
@@ -329,6 +218,13 @@ pub fn register_iface(
     ctx: &mut ProtocolContexts,
 ) -> Result<()> {
     let iface = IpIface::new(unicast, netmask, dev.index)?;
+    let conflicts = diagnose::iface_register(ctx, iface.unicast, iface.netmask);
+    if !conflicts.is_empty() {
+        return Err(NetError::AddressInUse(Conflicts {
+            request: format!("register iface {}/{}", unicast, iface.netmask.prefix_len()),
+            conflicts,
+        }));
+    }
 
     tracing::info!(
         "dev={}, unicast={}, netmask={}, broadcast={}",
@@ -342,67 +238,253 @@ pub fn register_iface(
     dev.ifaces.push(NetIface::Ip(iface.clone()));
 
     // 2. Register in global registry
-    ctx.ip_ifaces.register(iface)?;
+    ctx.ip_ifaces.register(iface.clone())?;
+
+    // 3. Register the connected route for the interface network
+    ctx.ip_routes.add(
+        iface.unicast & iface.netmask,
+        iface.netmask,
+        IpAddr::ANY,
+        iface,
+    )?;
 
     Ok(())
 }
 
+/// Add a route to `network`/`netmask` via `gateway`, which must be on-link.
+/// The outgoing interface is the one the gateway is reachable through.
+pub fn route_add_via(
+    network: IpAddr,
+    netmask: IpAddr,
+    gateway: IpAddr,
+    ctx: &ProtocolContexts,
+) -> Result<()> {
+    let iface = ctx
+        .ip_routes
+        .lookup(gateway)
+        .filter(|route| route.nexthop == IpAddr::ANY)
+        .map(|route| route.iface.clone())
+        .ok_or(NetError::NoRoute(gateway.into()))?;
+
+    ctx.ip_routes.add(network, netmask, gateway, iface)
+}
+
+/// Parse CIDR notation (`192.0.2.1/24`) into an address and its netmask
+pub fn parse_cidr(s: &str) -> Result<(IpAddr, IpAddr)> {
+    let (addr, prefix) = s
+        .split_once('/')
+        .ok_or(NetError::InvalidInput("CIDR without a prefix length"))?;
+    let prefix: u32 = prefix
+        .parse()
+        .ok()
+        .filter(|prefix| *prefix <= 32)
+        .ok_or(NetError::InvalidInput("invalid prefix length in CIDR"))?;
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+
+    Ok((parse_addr(addr)?, IpAddr::from_ne_bytes(mask.to_be_bytes())))
+}
+
+/// Parse a dotted-decimal address given as an argument
+fn parse_addr(s: &str) -> Result<IpAddr> {
+    IpAddr::from_str(s).map_err(|_| NetError::InvalidInput("invalid IPv4 address"))
+}
+
+/// Set the default gateway reachable through the interface with the given unicast address.
+/// Equivalent to C's ip_route_set_default_gateway.
+pub fn route_set_default_gateway(
+    unicast: &str,
+    gateway: &str,
+    ctx: &ProtocolContexts,
+) -> Result<()> {
+    let unicast = parse_addr(unicast)?;
+    let gateway = parse_addr(gateway)?;
+
+    let iface = ctx
+        .ip_ifaces
+        .select(unicast)
+        .cloned()
+        .ok_or(NetError::NoInterface(unicast.into()))?;
+
+    ctx.ip_routes.set_default_gateway(iface, gateway)
+}
+
 /// Output IP packet to the device associated with the given interface.
 fn output_device(
     iface: &IpIface,
-    data: &[u8],
+    packet: PacketBuf,
     target: IpAddr,
     devices: &DeviceManager,
 ) -> Result<()> {
     tracing::debug!(
         "ip_output_device: dev={}, len={}, target={}",
         iface.device_index,
-        data.len(),
-        target.to_string()
+        packet.len(),
+        target
     );
 
     let dev = devices
         .get(iface.device_index)
-        .ok_or_else(|| anyhow::anyhow!("Device not found: {}", iface.device_index))?;
+        .ok_or(NetError::NoDevice(iface.device_index))?;
 
+    let multicast = igmp::multicast_hwaddr(target);
     let hwaddr: Option<&[u8]> = if dev.flags & NET_DEVICE_FLAG_NEED_ARP != 0 {
         if target == iface.broadcast || target == IpAddr::BROADCAST {
             Some(&dev.broadcast[..dev.alen as usize])
+        } else if target.is_multicast() {
+            Some(&multicast.0)
         } else {
-            anyhow::bail!("ARP does not implement");
+            return Err(NetError::Unsupported("ARP"));
         }
     } else {
         None
     };
 
-    dev.output(PROTOCOL_TYPE_IP, data, hwaddr)
+    dev.output_buf(PROTOCOL_TYPE_IP, packet, hwaddr)
 }
 
-/// Build an IP packet with header and payload.
-fn build_packet(
-    protocol: IpProtocol,
+/// Forward a packet towards `next` (equivalent to a router's forwarding path).
+///
+/// With a source route, `next` is the next listed hop, which is replaced by the
+/// address of the outgoing interface as RFC 791 requires.
+fn forward(
     data: &[u8],
+    hlen: usize,
+    next: IpAddr,
+    source_route: Option<&SourceRoute>,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    let src = IpAddr::from_ne_bytes([data[12], data[13], data[14], data[15]]);
+    // ICMP errors carry the original IP header and the first 8 bytes of its payload
+    let quote = &data[..data.len().min(hlen + 8)];
+
+    let ttl = data[8];
+    if ttl <= 1 {
+        ctx.drops.drop(DropReason::TtlExceeded, data);
+        tracing::debug!("TTL exceeded while forwarding, src={}, dst={}", src, next);
+        icmp::output(
+            IcmpType::TimeExceeded,
+            ICMP_CODE_EXCEEDED_TTL,
+            0,
+            quote,
+            IpAddr::ANY,
+            src,
+            ctx,
+            devices,
+        )?;
+        return Ok(());
+    }
+
+    let Some(route) = ctx.ip_routes.lookup(next) else {
+        ctx.drops.drop(DropReason::NoRoute, data);
+        tracing::debug!("No route to forward, src={}, dst={}", src, next);
+        icmp::output(
+            IcmpType::DestUnreachable,
+            ICMP_CODE_NET_UNREACH,
+            0,
+            quote,
+            IpAddr::ANY,
+            src,
+            ctx,
+            devices,
+        )?;
+        return Ok(());
+    };
+    let iface = &route.iface;
+
+    let mut packet = PacketBuf::from(data.to_vec());
+    packet[8] = ttl - 1;
+
+    if let Some(source_route) = source_route {
+        // Record our address in the slot of the address we are forwarding to
+        let slot = source_route.offset + source_route.pointer as usize - 1;
+        packet[slot..slot + IP_ADDR_LEN].copy_from_slice(&iface.unicast.to_ne_bytes());
+        packet[source_route.offset + 2] = source_route.pointer + IP_ADDR_LEN as u8;
+        packet[16..20].copy_from_slice(&next.to_ne_bytes());
+    }
+
+    let masquerade = ctx
+        .ip_config
+        .masquerade
+        .filter(|addr| *addr == iface.unicast);
+    if source_route.is_none()
+        && (masquerade.is_some() || !ctx.nat.is_empty())
+        && !nat::translate_outbound(&mut packet, hlen, masquerade, ctx)
+    {
+        ctx.drops.drop(DropReason::Nat, data);
+        tracing::debug!(
+            "No NAT translation for forwarded packet, src={}, dst={}",
+            src,
+            next
+        );
+        return Ok(());
+    }
+
+    packet[10..12].fill(0);
+    let sum = cksum16(&packet[..hlen], 0);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    let nexthop = if route.nexthop != IpAddr::ANY {
+        route.nexthop
+    } else {
+        next
+    };
+
+    tracing::debug!(
+        "Forwarding packet: src={}, dst={}, nexthop={}, len={}",
+        src,
+        next,
+        nexthop,
+        packet.len()
+    );
+
+    let len = packet.len();
+    output_device(iface, packet, nexthop, devices)?;
+    ctx.stats.ip.tx.add(len);
+    Ok(())
+}
+
+/// Prepend the IP header and options to the payload in `packet`, with `ttl`
+/// or the default for `dst`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn push_header(
+    packet: &mut PacketBuf,
+    protocol: IpProtocol,
+    options: &[u8],
     id: u16,
     offset: u16,
     src: IpAddr,
     dst: IpAddr,
-    buf: &mut [u8],
-) -> Result<usize> {
-    let hlen = IP_HDR_SIZE_MIN;
-    let total = hlen + data.len();
-
-    if buf.len() < total {
-        anyhow::bail!("Buffer too small: need {}, have {}", total, buf.len());
+    ttl: Option<u8>,
+) -> Result<()> {
+    if options.len() > IP_OPT_SIZE_MAX || !options.len().is_multiple_of(4) {
+        return Err(NetError::InvalidInput("IP options length"));
     }
 
-    let hdr = IpHdr::new(protocol, total as u16, id, offset, src, dst).with_checksum();
+    let hlen = IP_HDR_SIZE_MIN + options.len();
+    let total = hlen + packet.len();
+    if total > IP_TOTAL_SIZE_MAX {
+        return Err(NetError::MessageTooLong {
+            len: total,
+            max: IP_TOTAL_SIZE_MAX,
+        });
+    }
 
-    buf[..hlen].copy_from_slice(&hdr.to_bytes());
-    buf[hlen..total].copy_from_slice(data);
+    let mut hdr = IpHdr::new(protocol, total as u16, id, offset, src, dst);
+    hdr.set_hdr_len(hlen);
+    match ttl {
+        Some(ttl) => hdr.set_ttl(ttl),
+        None if dst.is_multicast() => hdr.set_ttl(IP_MULTICAST_TTL_DEFAULT),
+        None => {}
+    }
 
-    ip_print(&buf[..total]);
+    let buf = packet.push(hlen);
+    buf[..IP_HDR_SIZE_MIN].copy_from_slice(&hdr.to_bytes());
+    buf[IP_HDR_SIZE_MIN..].copy_from_slice(options);
+    let sum = cksum16(buf, 0);
+    buf[10..12].copy_from_slice(&sum.to_be_bytes());
 
-    Ok(total)
+    Ok(())
 }
 
 /// Send an IP packet with the given payload.
@@ -414,66 +496,189 @@ pub fn ip_output(
     ctx: &ProtocolContexts,
     devices: &DeviceManager,
 ) -> Result<isize> {
-    tracing::debug!(
-        "ip_output: {} => {}, protocol={:?}, len={}",
-        src.to_string(),
-        dst.to_string(),
-        protocol,
-        payload.len()
-    );
+    let packet = ctx.packets.packet_from(payload);
+    ip_output_buf(protocol, packet, src, dst, ctx, devices)
+}
 
-    // Routing not implemented - require explicit source address
-    if src == IpAddr::ANY {
-        anyhow::bail!("ip routing does not implement");
-    }
+/// Send an IP packet with the given payload and TTL (the default for `dst` if
+/// `None`), as traceroute probes need.
+pub fn ip_output_ttl(
+    protocol: IpProtocol,
+    payload: &[u8],
+    src: IpAddr,
+    dst: IpAddr,
+    ttl: Option<u8>,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<isize> {
+    let packet = ctx.packets.packet_from(payload);
+    ip_output_options(protocol, packet, &[], src, dst, ttl, ctx, devices)
+}
 
-    // Find interface by source address
-    let iface = ctx
-        .ip_ifaces
-        .select(src)
-        .ok_or_else(|| anyhow::anyhow!("iface not found, src={}", src.to_string()))?;
+/// Like [`ip_output`], prepending the header to the payload in place; a
+/// payload built with [`PacketBuf::with_capacity`] reaches the device uncopied
+pub fn ip_output_buf(
+    protocol: IpProtocol,
+    payload: PacketBuf,
+    src: IpAddr,
+    dst: IpAddr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<isize> {
+    ip_output_options(protocol, payload, &[], src, dst, None, ctx, devices)
+}
+
+/// Send an IP packet to `dst` loosely source routed through `hops` (RFC 791 LSRR).
+/// The packet is addressed to the first hop and carries the remaining hops
+/// followed by the final destination in the option.
+pub fn ip_output_source_route(
+    protocol: IpProtocol,
+    payload: &[u8],
+    src: IpAddr,
+    hops: &[IpAddr],
+    dst: IpAddr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<isize> {
+    let Some((&first, rest)) = hops.split_first() else {
+        return ip_output(protocol, payload, src, dst, ctx, devices);
+    };
+
+    let mut route: Vec<IpAddr> = rest.to_vec();
+    route.push(dst);
+    let options = SourceRoute::build(&route)
+        .map_err(|_| NetError::InvalidInput("too many source route hops"))?;
+
+    ip_output_options(
+        protocol,
+        ctx.packets.packet_from(payload),
+        &options,
+        src,
+        first,
+        None,
+        ctx,
+        devices,
+    )
+}
 
-    // Check if destination is reachable (same network or broadcast)
-    let src_network = iface.unicast & iface.netmask;
-    let dst_network = dst & iface.netmask;
-    if dst_network != src_network && dst != IpAddr::BROADCAST {
-        anyhow::bail!("not reached, dst={}", dst.to_string());
+/// Send an IP packet carrying `options` (a multiple of 4 bytes) before the payload
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn ip_output_options(
+    protocol: IpProtocol,
+    mut packet: PacketBuf,
+    options: &[u8],
+    src: IpAddr,
+    dst: IpAddr,
+    ttl: Option<u8>,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<isize> {
+    let log = LOG_IP_OUTPUT.allow(Level::INFO);
+    if log {
+        tracing::debug!(
+            "ip_output: {} => {}, protocol={:?}, len={}",
+            src,
+            dst,
+            protocol,
+            packet.len()
+        );
     }
 
+    // Resolve the outgoing interface and the next hop
+    // Multicast from a given source leaves through the source's interface
+    let (iface, nexthop) = if dst == IpAddr::BROADCAST || (dst.is_multicast() && src != IpAddr::ANY)
+    {
+        if src == IpAddr::ANY {
+            return Err(NetError::InvalidInput(
+                "source address is required for broadcast addresses",
+            ));
+        }
+        let iface = ctx
+            .ip_ifaces
+            .select(src)
+            .ok_or(NetError::NoInterface(src.into()))?;
+        (iface.clone(), dst)
+    } else {
+        let route = ctx
+            .ip_routes
+            .lookup(dst)
+            .ok_or(NetError::NoRoute(dst.into()))?;
+        if src != IpAddr::ANY && src != route.iface.unicast {
+            tracing::debug!(
+                "ip_output: src={} is not the address of iface={}",
+                src,
+                route.iface.unicast
+            );
+            return Err(NetError::NoInterface(src.into()));
+        }
+        let nexthop = if route.nexthop != IpAddr::ANY {
+            route.nexthop
+        } else {
+            dst
+        };
+        (route.iface, nexthop)
+    };
+
     // Check MTU
     let dev = devices
         .get(iface.device_index)
-        .ok_or_else(|| anyhow::anyhow!("Device not found: {}", iface.device_index))?;
-
-    if (dev.mtu as usize) < IP_HDR_SIZE_MIN + payload.len() {
-        anyhow::bail!(
-            "too long, dev={}, mtu={} < {}",
-            dev.name_string(),
-            dev.mtu,
-            IP_HDR_SIZE_MIN + payload.len()
-        );
+        .ok_or(NetError::NoDevice(iface.device_index))?;
+
+    let hlen = IP_HDR_SIZE_MIN + options.len();
+    if (dev.mtu as usize) < hlen + packet.len() {
+        return Err(NetError::MtuExceeded {
+            len: hlen + packet.len(),
+            mtu: dev.mtu as usize,
+        });
     }
 
     // Build packet
     let id = random16();
-    let mut buf = [0u8; IP_TOTAL_SIZE_MAX];
-    let packet_len = build_packet(protocol, payload, id, 0, iface.unicast, dst, &mut buf)?;
+    push_header(
+        &mut packet,
+        protocol,
+        options,
+        id,
+        0,
+        iface.unicast,
+        dst,
+        ttl,
+    )?;
+    if log {
+        ip_print(&packet);
+    }
 
     // Send packet
-    output_device(iface, &buf[..packet_len], dst, devices)?;
+    let packet_len = packet.len();
+    output_device(&iface, packet, nexthop, devices)?;
+
+    ctx.stats.ip.tx.add(packet_len);
+    if let Some(stats) = ctx.stats.transport(protocol) {
+        stats.tx.add(packet_len - hlen);
+    }
+    ctx.peer_stats.record_out(dst, packet_len);
 
     Ok(packet_len as isize)
 }
 
 pub fn init(protocols: &mut ProtocolManager) -> Result<()> {
-    protocols.register(ProtocolType::Ip, ip_input_handler)?;
+    protocols.register(ProtocolType::Ip, Arc::new(ip_input_handler))?;
     tracing::info!("IP protocol initialized");
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
     use super::*;
+    use crate::device::DeviceIndex;
+    use crate::testing::setup_loopback;
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
 
     #[test]
     fn test_ip_addr_from_str() {
@@ -500,14 +705,14 @@ mod tests {
 
     #[test]
     fn test_ip_addr_to_string() {
-        assert_eq!(IpAddr::to_string(IpAddr::ANY), "0.0.0.0");
-        assert_eq!(IpAddr::to_string(IpAddr::BROADCAST), "255.255.255.255");
+        assert_eq!(IpAddr::to_string(&IpAddr::ANY), "0.0.0.0");
+        assert_eq!(IpAddr::to_string(&IpAddr::BROADCAST), "255.255.255.255");
         assert_eq!(
-            IpAddr::to_string(IpAddr::from_ne_bytes([127, 0, 0, 1])),
+            IpAddr::to_string(&IpAddr::from_ne_bytes([127, 0, 0, 1])),
             "127.0.0.1"
         );
         assert_eq!(
-            IpAddr::to_string(IpAddr::from_ne_bytes([192, 168, 1, 1])),
+            IpAddr::to_string(&IpAddr::from_ne_bytes([192, 168, 1, 1])),
             "192.168.1.1"
         );
     }
@@ -517,7 +722,353 @@ mod tests {
         let addrs = ["0.0.0.0", "127.0.0.1", "192.168.1.1", "255.255.255.255"];
         for addr_str in addrs {
             let addr = IpAddr::from_str(addr_str).unwrap();
-            assert_eq!(IpAddr::to_string(addr), addr_str);
+            assert_eq!(IpAddr::to_string(&addr), addr_str);
         }
     }
+
+    #[test]
+    fn test_ip_hdr_accessors_unaligned() {
+        let hdr = IpHdr::new(
+            IpProtocol::Icmp,
+            0x1234,
+            0xabcd,
+            IP_HDR_FLAG_DF,
+            addr("192.0.2.1"),
+            addr("198.51.100.1"),
+        )
+        .with_checksum();
+
+        // Parse from an odd offset so the multi-byte fields are misaligned
+        let mut buf = vec![0u8; 1];
+        buf.extend_from_slice(&hdr.to_bytes());
+        let parsed = IpHdr::from_bytes(&buf[1..]).unwrap();
+        assert_eq!(parsed.version(), IP_VERSION_IPV4);
+        assert_eq!(parsed.hdr_len(), IP_HDR_SIZE_MIN);
+        assert_eq!(parsed.total(), 0x1234);
+        assert_eq!(parsed.id(), 0xabcd);
+        assert_eq!(parsed.offset(), IP_HDR_FLAG_DF);
+        assert_eq!(parsed.ttl(), IP_TTL_DEFAULT);
+        assert_eq!(parsed.protocol(), IpProtocol::Icmp);
+        assert_eq!(parsed.src(), addr("192.0.2.1"));
+        assert_eq!(parsed.dst(), addr("198.51.100.1"));
+        assert_eq!(cksum16(&buf[1..], 0), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn test_ip_hdr_bytes_roundtrip(bytes in any::<[u8; IP_HDR_SIZE_MIN]>()) {
+            let hdr = IpHdr::from_bytes(&bytes).unwrap();
+            prop_assert_eq!(hdr.to_bytes(), bytes);
+        }
+
+        #[test]
+        fn test_ip_push_header_roundtrip(
+            protocol in any::<u8>(),
+            id in any::<u16>(),
+            offset in any::<u16>(),
+            src in any::<[u8; 4]>(),
+            dst in any::<[u8; 4]>(),
+            options in (0..=IP_OPT_SIZE_MAX / 4).prop_flat_map(|words| vec(any::<u8>(), words * 4)),
+            payload in vec(any::<u8>(), 0..1024),
+        ) {
+            let (src, dst) = (IpAddr::from_ne_bytes(src), IpAddr::from_ne_bytes(dst));
+            let protocol = IpProtocol::from_u8(protocol);
+            let mut packet = PacketBuf::from(&payload[..]);
+            push_header(&mut packet, protocol, &options, id, offset, src, dst, None).unwrap();
+
+            let hlen = IP_HDR_SIZE_MIN + options.len();
+            let hdr = IpHdr::from_bytes(&packet).unwrap();
+            prop_assert_eq!(hdr.version(), IP_VERSION_IPV4);
+            prop_assert_eq!(hdr.hdr_len(), hlen);
+            prop_assert_eq!(hdr.total() as usize, hlen + payload.len());
+            prop_assert_eq!(hdr.id(), id);
+            prop_assert_eq!(hdr.offset(), offset);
+            let ttl = if dst.is_multicast() { IP_MULTICAST_TTL_DEFAULT } else { IP_TTL_DEFAULT };
+            prop_assert_eq!(hdr.ttl(), ttl);
+            prop_assert_eq!(hdr.protocol(), protocol);
+            prop_assert_eq!(hdr.src(), src);
+            prop_assert_eq!(hdr.dst(), dst);
+            prop_assert_eq!(cksum16(&packet[..hlen], 0), 0);
+            prop_assert_eq!(&packet[IP_HDR_SIZE_MIN..hlen], &options[..]);
+            prop_assert_eq!(&packet[hlen..], &payload[..]);
+        }
+    }
+
+    #[test]
+    fn test_source_route_build_parse() {
+        let addrs = [addr("192.0.2.1"), addr("198.51.100.1")];
+        let opt = SourceRoute::build(&addrs).unwrap();
+        assert_eq!(opt.len(), 12);
+        assert_eq!(&opt[..4], &[IP_OPT_NOP, IP_OPT_LSRR, 11, 4]);
+
+        let mut hdr = vec![0u8; IP_HDR_SIZE_MIN];
+        hdr.extend_from_slice(&opt);
+        let route = SourceRoute::parse(&hdr).unwrap().unwrap();
+        assert_eq!(route.offset, IP_HDR_SIZE_MIN + 1);
+        assert_eq!(route.pointer, 4);
+        assert_eq!(route.addrs, addrs);
+        assert_eq!(route.next_hop(), Some(addrs[0]));
+
+        assert!(SourceRoute::build(&[]).is_err());
+        assert!(SourceRoute::build(&[IpAddr::ANY; IP_OPT_SRR_ADDRS_MAX + 1]).is_err());
+    }
+
+    #[test]
+    fn test_source_route_parse_malformed() {
+        let mut hdr = vec![0u8; IP_HDR_SIZE_MIN];
+        hdr.extend_from_slice(&[IP_OPT_LSRR, 40, 4, 0]);
+        assert!(SourceRoute::parse(&hdr).is_err());
+
+        let mut hdr = vec![0u8; IP_HDR_SIZE_MIN];
+        hdr.extend_from_slice(&[IP_OPT_NOP, IP_OPT_NOP, IP_OPT_NOP, IP_OPT_EOL]);
+        assert!(SourceRoute::parse(&hdr).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_ip_output_no_route() {
+        let (devices, ctx, captured) = setup_loopback();
+        let result = ip_output(
+            IpProtocol::Icmp,
+            &[0u8; 8],
+            IpAddr::ANY,
+            addr("192.0.2.1"),
+            &ctx,
+            &devices,
+        );
+        assert!(matches!(result, Err(NetError::NoRoute(dst)) if dst.to_string() == "192.0.2.1"));
+        assert!(captured.lock().unwrap().is_empty());
+
+        let mtu = devices.get(DeviceIndex(0)).unwrap().mtu as usize;
+        let result = ip_output(
+            IpProtocol::Icmp,
+            &vec![0u8; mtu],
+            IpAddr::ANY,
+            addr("127.0.0.1"),
+            &ctx,
+            &devices,
+        );
+        assert!(
+            matches!(result, Err(NetError::MtuExceeded { len, .. }) if len == mtu + IP_HDR_SIZE_MIN)
+        );
+    }
+
+    #[test]
+    fn test_ip_output_selects_source_from_route() {
+        let (devices, ctx, captured) = setup_loopback();
+        ip_output(
+            IpProtocol::Icmp,
+            &[0u8; 8],
+            IpAddr::ANY,
+            addr("127.0.0.2"),
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+
+        let captured = captured.lock().unwrap();
+        let hdr = IpHdr::from_bytes(&captured[0]).unwrap();
+        assert_eq!(hdr.src(), addr("127.0.0.1"));
+        assert_eq!(hdr.dst(), addr("127.0.0.2"));
+    }
+
+    #[test]
+    fn test_ip_output_ttl() {
+        let (devices, ctx, captured) = setup_loopback();
+        for ttl in [Some(3), None] {
+            ip_output_ttl(
+                IpProtocol::Icmp,
+                &[0u8; 8],
+                IpAddr::ANY,
+                addr("127.0.0.2"),
+                ttl,
+                &ctx,
+                &devices,
+            )
+            .unwrap();
+        }
+
+        let captured = captured.lock().unwrap();
+        let hdr = IpHdr::from_bytes(&captured[0]).unwrap();
+        assert_eq!(hdr.ttl(), 3);
+        assert_eq!(cksum16(&captured[0][..hdr.hdr_len()], 0), 0);
+        assert_eq!(
+            IpHdr::from_bytes(&captured[1]).unwrap().ttl(),
+            IP_TTL_DEFAULT
+        );
+    }
+
+    #[test]
+    fn test_ip_output_source_route_and_forward() {
+        let (devices, mut ctx, captured) = setup_loopback();
+        let payload = [0xaau8; 8];
+        ip_output_source_route(
+            IpProtocol::Other(253),
+            &payload,
+            IpAddr::ANY,
+            &[addr("127.0.0.1")],
+            addr("127.0.0.9"),
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+
+        let packet = captured.lock().unwrap().pop().unwrap();
+        let hdr = IpHdr::from_bytes(&packet).unwrap();
+        assert_eq!(hdr.hdr_len(), IP_HDR_SIZE_MIN + 8);
+        assert_eq!(hdr.dst(), addr("127.0.0.1"));
+        assert_eq!(cksum16(&packet[..hdr.hdr_len()], 0), 0);
+        assert_eq!(&packet[hdr.hdr_len()..], &payload);
+
+        // Source routing is refused unless explicitly enabled
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+        assert!(captured.lock().unwrap().is_empty());
+
+        ctx.ip_config.accept_source_route = true;
+        ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+        let forwarded = captured.lock().unwrap().pop().unwrap();
+        let hdr = IpHdr::from_bytes(&forwarded).unwrap();
+        assert_eq!(hdr.dst(), addr("127.0.0.9"));
+        assert_eq!(hdr.ttl(), IP_TTL_DEFAULT - 1);
+        assert_eq!(cksum16(&forwarded[..hdr.hdr_len()], 0), 0);
+
+        let route = SourceRoute::parse(&forwarded[..hdr.hdr_len()])
+            .unwrap()
+            .unwrap();
+        assert_eq!(route.pointer, 8);
+        assert_eq!(route.addrs, [addr("127.0.0.1")]);
+        assert_eq!(route.next_hop(), None);
+    }
+
+    #[test]
+    fn test_udp_to_closed_port_is_port_unreachable() {
+        let (devices, ctx, captured) = setup_loopback();
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        let src = IpEndpoint::new(addr("127.0.0.1"), 40000);
+        let dst = IpEndpoint::new(addr("127.0.0.1"), 33434);
+        udp::output(src, dst, b"probe", &ctx, &devices).unwrap();
+        let probe = captured.lock().unwrap().pop().unwrap();
+
+        ip_input(&Frame::Borrowed(&probe), dev, &ctx, &devices).unwrap();
+        let reply = captured.lock().unwrap().pop().unwrap();
+        let hdr = IpHdr::from_bytes(&reply).unwrap();
+        assert_eq!(hdr.protocol(), IpProtocol::Icmp);
+        let icmp = icmp::IcmpHdr::from_bytes(&reply[hdr.hdr_len()..]).unwrap();
+        assert_eq!(icmp.type_enum(), Some(IcmpType::DestUnreachable));
+        assert_eq!(icmp.code(), ICMP_CODE_PORT_UNREACH);
+        // The quote is the probe's header and the UDP header
+        assert_eq!(
+            &reply[hdr.hdr_len() + icmp::ICMP_HDR_SIZE..],
+            &probe[..IP_HDR_SIZE_MIN + 8]
+        );
+    }
+
+    #[test]
+    fn test_ip_input_queues_slices_of_the_frame() {
+        let (devices, ctx, captured) = setup_loopback();
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        let server = ctx.udp.open().unwrap();
+        ctx.udp
+            .bind(server, IpEndpoint::new(addr("127.0.0.1"), 7))
+            .unwrap();
+        let raw = ctx.raw.open(IpProtocol::Udp).unwrap();
+        let src = IpEndpoint::new(addr("127.0.0.1"), 9);
+        let dst = ctx.udp.local(server).unwrap();
+        let data = [7; 1000];
+        udp::output(src, dst, &data, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        let frame = Frame::Shared(ctx.packets.bytes_from(&packet));
+
+        ip_input(&frame, dev, &ctx, &devices).unwrap();
+        // Both sockets hold the queued frame itself, not copies of it
+        let datagram = ctx.udp.recvfrom(server).unwrap();
+        assert_eq!(*datagram.data, data);
+        assert_eq!(
+            datagram.data.as_ptr(),
+            frame[IP_HDR_SIZE_MIN + 8..].as_ptr()
+        );
+        let packet = ctx.raw.recvfrom(raw).unwrap();
+        assert_eq!(packet.data.as_ptr(), frame[IP_HDR_SIZE_MIN..].as_ptr());
+
+        // A small datagram is copied out, and so is a borrowed frame
+        udp::output(src, dst, b"data", &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        let frame = Frame::Shared(ctx.packets.bytes_from(&packet));
+        ip_input(&frame, dev, &ctx, &devices).unwrap();
+        let datagram = ctx.udp.recvfrom(server).unwrap();
+        assert_eq!(datagram.data, b"data");
+        assert_ne!(
+            datagram.data.as_ptr(),
+            frame[IP_HDR_SIZE_MIN + 8..].as_ptr()
+        );
+        udp::output(src, dst, &data, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+        let datagram = ctx.udp.recvfrom(server).unwrap();
+        assert_ne!(
+            datagram.data.as_ptr(),
+            packet[IP_HDR_SIZE_MIN + 8..].as_ptr()
+        );
+    }
+
+    #[test]
+    fn test_ip_input_total_shorter_than_header() {
+        let (devices, ctx, captured) = setup_loopback();
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        udp::output(
+            IpEndpoint::new(addr("127.0.0.1"), 9),
+            IpEndpoint::new(addr("127.0.0.1"), 7),
+            b"data",
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+
+        // Total length 10 with a valid checksum, then IHL 4
+        let mut short = packet.clone();
+        short[2..4].copy_from_slice(&10u16.to_be_bytes());
+        let mut bad_ihl = packet;
+        bad_ihl[0] = 0x44;
+        for mut packet in [short, bad_ihl] {
+            packet[10..12].fill(0);
+            let sum = cksum16(&packet[..(packet[0] & 0x0f) as usize * 4], 0);
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+            assert!(ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).is_err());
+        }
+        assert_eq!(ctx.drops.count(DropReason::Malformed), 2);
+        assert!(captured.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_drop_hook_can_rescue_bad_checksum() {
+        let (devices, ctx, captured) = setup_loopback();
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        icmp::output(
+            IcmpType::Echo,
+            0,
+            0,
+            b"ping",
+            addr("127.0.0.1"),
+            addr("127.0.0.1"),
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let mut packet = captured.lock().unwrap().pop().unwrap();
+        packet[10] ^= 0xff;
+
+        assert!(ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).is_err());
+        assert_eq!(ctx.drops.count(DropReason::IpChecksum), 1);
+        assert!(captured.lock().unwrap().is_empty());
+
+        ctx.drops
+            .set_hook(Some(std::sync::Arc::new(|reason, _: &[u8]| match reason {
+                DropReason::IpChecksum => crate::drop::DropVerdict::Rescue,
+                _ => crate::drop::DropVerdict::Drop,
+            })));
+        ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+        assert_eq!(ctx.drops.count(DropReason::IpChecksum), 1);
+        assert_eq!(captured.lock().unwrap().len(), 1, "echo reply sent");
+    }
 }
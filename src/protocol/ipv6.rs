@@ -0,0 +1,748 @@
+//! IPv6 (RFC 8200): addresses, the fixed header, and input and output of
+//! packets without extension headers.
+//!
+//! Interfaces are [`Ipv6Iface`]s on the devices. Registering one adds an
+//! on-link route for its prefix to the [`Ipv6RouteTable`]; destinations no
+//! route covers are sent through a default router.
+
+use std::fmt;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use tracing::Level;
+
+use super::{PROTOCOL_TYPE_IPV6, ProtocolManager, ProtocolType};
+use crate::bytes::Frame;
+#[cfg(doc)]
+use crate::context::Ipv6RouteTable;
+use crate::context::ProtocolContexts;
+use crate::device::{Device, DeviceManager, NET_DEVICE_FLAG_NEED_ARP};
+use crate::drop::DropReason;
+use crate::error::{NetError, Result};
+use crate::iface::{Ipv6Iface, NetIface};
+use crate::protocol::ip::IpProtocol;
+use crate::protocol::ndp;
+use crate::util::{LOG_IPV6_INPUT, LOG_IPV6_OUTPUT, cksum16};
+use crate::util::{header_accessors, wire_codec};
+use crate::wire::WireField;
+
+pub const IPV6_VERSION: u8 = 6;
+
+pub const IPV6_HDR_SIZE: usize = 40;
+pub const IPV6_PAYLOAD_SIZE_MAX: usize = u16::MAX as usize;
+
+pub const IPV6_ADDR_LEN: usize = 16;
+
+/// Hop limit of packets sent through an interface that was not given one
+pub const IPV6_HOP_LIMIT_DEFAULT: u8 = 64;
+
+/// Next header value of ICMPv6 (RFC 4443)
+pub const IPV6_NEXT_HEADER_ICMPV6: u8 = 58;
+/// Next header value of a packet that carries nothing after its headers
+pub const IPV6_NEXT_HEADER_NONE: u8 = 59;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Ipv6Addr([u8; IPV6_ADDR_LEN]);
+
+impl WireField for Ipv6Addr {
+    const SIZE: usize = IPV6_ADDR_LEN;
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Self {
+        let mut octets = [0; IPV6_ADDR_LEN];
+        octets.copy_from_slice(bytes);
+        Ipv6Addr(octets)
+    }
+
+    #[inline]
+    fn encode(self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.0);
+    }
+}
+
+impl Ipv6Addr {
+    /// `::`
+    pub const UNSPECIFIED: Self = Ipv6Addr([0; IPV6_ADDR_LEN]);
+    /// `::1`
+    pub const LOOPBACK: Self = Ipv6Addr([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    /// `ff02::1`, every node on the link
+    pub const ALL_NODES: Self = Ipv6Addr([0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+    #[inline]
+    pub const fn from_octets(octets: [u8; IPV6_ADDR_LEN]) -> Self {
+        Ipv6Addr(octets)
+    }
+
+    #[inline]
+    pub const fn octets(self) -> [u8; IPV6_ADDR_LEN] {
+        self.0
+    }
+
+    pub fn is_unspecified(self) -> bool {
+        self == Self::UNSPECIFIED
+    }
+
+    pub fn is_multicast(self) -> bool {
+        self.0[0] == 0xff
+    }
+
+    /// `fe80::/10`
+    pub fn is_link_local(self) -> bool {
+        self.0[0] == 0xfe && self.0[1] & 0xc0 == 0x80
+    }
+
+    /// The first `prefix_len` bits, the rest cleared
+    pub fn prefix(self, prefix_len: u8) -> Self {
+        let mut octets = self.0;
+        for (i, octet) in octets.iter_mut().enumerate() {
+            let bits = (prefix_len as usize).saturating_sub(i * 8).min(8);
+            *octet &= !(0xffu8.checked_shr(bits as u32).unwrap_or(0));
+        }
+        Ipv6Addr(octets)
+    }
+
+    /// Solicited-node multicast address (`ff02::1:ffXX:XXXX`, RFC 4291 Section 2.7.1),
+    /// which Neighbor Solicitations for this address are sent to
+    pub fn solicited_node(self) -> Self {
+        let mut octets = [0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0, 0, 0];
+        octets[13..].copy_from_slice(&self.0[13..]);
+        Ipv6Addr(octets)
+    }
+
+    /// Whether the first `prefix_len` bits are those of `other`
+    pub fn in_prefix(self, other: Ipv6Addr, prefix_len: u8) -> bool {
+        self.prefix(prefix_len) == other.prefix(prefix_len)
+    }
+}
+
+impl FromStr for Ipv6Addr {
+    type Err = NetError;
+
+    /// Parse the text forms of RFC 4291 Section 2.2 (`2001:db8::1`, `::ffff:192.0.2.1`)
+    fn from_str(s: &str) -> Result<Self> {
+        s.parse::<std::net::Ipv6Addr>()
+            .map(Self::from)
+            .map_err(|_| NetError::InvalidInput("invalid IPv6 address"))
+    }
+}
+
+impl Display for Ipv6Addr {
+    /// The canonical text form of RFC 5952 (`2001:db8::1`)
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&std::net::Ipv6Addr::from(*self), f)
+    }
+}
+
+impl From<std::net::Ipv6Addr> for Ipv6Addr {
+    fn from(addr: std::net::Ipv6Addr) -> Self {
+        Ipv6Addr(addr.octets())
+    }
+}
+
+impl From<Ipv6Addr> for std::net::Ipv6Addr {
+    fn from(addr: Ipv6Addr) -> Self {
+        std::net::Ipv6Addr::from(addr.0)
+    }
+}
+
+impl From<Ipv6Addr> for std::net::IpAddr {
+    fn from(addr: Ipv6Addr) -> Self {
+        std::net::IpAddr::V6(addr.into())
+    }
+}
+
+/// Parse `addr/prefix` (`2001:db8::1/64`) into an address and its prefix length
+pub fn parse_cidr(s: &str) -> Result<(Ipv6Addr, u8)> {
+    let (addr, prefix) = s
+        .split_once('/')
+        .ok_or(NetError::InvalidInput("CIDR without a prefix length"))?;
+    let prefix: u8 = prefix
+        .parse()
+        .ok()
+        .filter(|prefix| *prefix <= 128)
+        .ok_or(NetError::InvalidInput("invalid prefix length in CIDR"))?;
+    Ok((Ipv6Addr::from_str(addr)?, prefix))
+}
+
+/// One's complement sum of the IPv6 pseudo header (RFC 8200 Section 8.1) covered
+/// by upper-layer checksums, to be passed as the initial value of [`cksum16`]
+pub fn pseudo_sum(src: Ipv6Addr, dst: Ipv6Addr, next_header: IpProtocol, len: usize) -> u32 {
+    let mut pseudo = [0u8; 40];
+    pseudo[0..16].copy_from_slice(&src.octets());
+    pseudo[16..32].copy_from_slice(&dst.octets());
+    pseudo[32..36].copy_from_slice(&(len as u32).to_be_bytes());
+    pseudo[39] = next_header.to_u8();
+    !cksum16(&pseudo, 0) as u32
+}
+
+/// IPv6 fixed header, in host byte order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Hdr {
+    vtf: u32,
+    payload_len: u16,
+    next_header: u8,
+    hop_limit: u8,
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+}
+
+wire_codec!(Ipv6Hdr, IPV6_HDR_SIZE, {
+    vtf: u32,
+    payload_len: u16,
+    next_header: u8,
+    hop_limit: u8,
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+});
+
+header_accessors!(Ipv6Hdr, {
+    /// Version, traffic class and flow label
+    vtf: u32,
+    /// Payload length (everything after the fixed header)
+    payload_len: u16,
+    hop_limit: u8,
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+});
+
+impl Ipv6Hdr {
+    pub fn new(
+        next_header: IpProtocol,
+        payload_len: u16,
+        hop_limit: u8,
+        src: Ipv6Addr,
+        dst: Ipv6Addr,
+    ) -> Self {
+        Self {
+            vtf: (IPV6_VERSION as u32) << 28,
+            payload_len,
+            next_header: next_header.to_u8(),
+            hop_limit,
+            src,
+            dst,
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        (self.vtf() >> 28) as u8
+    }
+
+    pub fn traffic_class(&self) -> u8 {
+        (self.vtf() >> 20) as u8
+    }
+
+    pub fn flow_label(&self) -> u32 {
+        self.vtf() & 0x000f_ffff
+    }
+
+    pub fn next_header(&self) -> IpProtocol {
+        IpProtocol::from_u8(self.next_header)
+    }
+}
+
+impl fmt::Display for Ipv6Hdr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "version={}, class={:#04x}, flow={:#07x}, len={}, next={}, hlim={}, src={}, dst={}",
+            self.version(),
+            self.traffic_class(),
+            self.flow_label(),
+            self.payload_len(),
+            self.next_header().to_u8(),
+            self.hop_limit(),
+            self.src(),
+            self.dst()
+        )
+    }
+}
+
+fn ipv6_print(data: &[u8]) {
+    let Some(hdr) = Ipv6Hdr::from_bytes(data) else {
+        tracing::warn!("IPv6 packet too short: len={}", data.len());
+        return;
+    };
+
+    tracing::info!("IPv6 Header: {}", hdr);
+}
+
+/// Outgoing interface and next hop for `dst`: the longest matching prefix
+/// route, or else a default router
+fn route(dst: Ipv6Addr, ctx: &ProtocolContexts) -> Option<(Ipv6Iface, Ipv6Addr)> {
+    if let Some(route) = ctx.ipv6_routes.lookup(dst) {
+        return Some((route.iface.clone(), route.nexthop.unwrap_or(dst)));
+    }
+    ctx.ipv6_routes
+        .select_default_router(&ctx.neighbors)
+        .map(|router| (router.iface, router.addr))
+}
+
+fn ipv6_input_handler(
+    data: &Frame<'_>,
+    dev: &Device,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) {
+    if let Err(e) = ipv6_input(data, dev, ctx, devices) {
+        tracing::error!("ipv6_input error: {}", e);
+    }
+}
+
+pub fn ipv6_input(
+    data: &Frame<'_>,
+    dev: &Device,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    let log = LOG_IPV6_INPUT.allow(Level::INFO);
+    if log {
+        tracing::debug!("ipv6_input: dev={}, len={}", dev.name_string(), data.len());
+    }
+
+    ctx.stats.ipv6.rx.add(data.len());
+    let Some(hdr) = Ipv6Hdr::from_bytes(data) else {
+        ctx.drops.drop(DropReason::Malformed, data);
+        return Err(NetError::Truncated { len: data.len() });
+    };
+
+    if hdr.version() != IPV6_VERSION {
+        ctx.drops.drop(DropReason::Malformed, data);
+        return Err(NetError::Malformed(format!(
+            "IPv6 version {}",
+            hdr.version()
+        )));
+    }
+
+    let total = IPV6_HDR_SIZE + hdr.payload_len() as usize;
+    if data.len() < total {
+        ctx.drops.drop(DropReason::Malformed, data);
+        return Err(NetError::Truncated { len: data.len() });
+    }
+
+    let dst = hdr.dst();
+    let matched = dev
+        .ipv6_ifaces()
+        .any(|iface| iface.is_destination_match(dst));
+
+    if !matched {
+        if ctx.ip_config.forwarding && !dst.is_multicast() {
+            return forward(&data[..total], dst, ctx, devices);
+        }
+        if ctx.drops.drop(DropReason::NotForUs, data) {
+            tracing::debug!("No matching IPv6 interface found for dst={}", dst);
+            return Ok(());
+        }
+    }
+
+    if log {
+        tracing::debug!(
+            "Packet accepted: src={}, dst={}, next={:?}",
+            hdr.src(),
+            dst,
+            hdr.next_header()
+        );
+        ipv6_print(data);
+    }
+
+    let payload = &data[IPV6_HDR_SIZE..total];
+    if let Some(stats) = ctx.stats.transport(hdr.next_header()) {
+        stats.rx.add(payload.len());
+    }
+    match hdr.next_header() {
+        IpProtocol::Other(IPV6_NEXT_HEADER_ICMPV6) => {
+            ndp::input(payload, hdr.src(), dst, hdr.hop_limit(), dev, ctx, devices);
+        }
+        IpProtocol::Other(IPV6_NEXT_HEADER_NONE) => {}
+        other => {
+            ctx.drops.drop(DropReason::UnknownProtocol, data);
+            tracing::debug!("Unsupported IPv6 next header: {}", other.to_u8());
+        }
+    }
+
+    Ok(())
+}
+
+/// Forward a packet towards `dst`, spending one hop of its hop limit
+fn forward(
+    data: &[u8],
+    dst: Ipv6Addr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    let hop_limit = data[7];
+    if hop_limit <= 1 {
+        ctx.drops.drop(DropReason::TtlExceeded, data);
+        tracing::debug!("Hop limit exceeded while forwarding, dst={}", dst);
+        return Ok(());
+    }
+
+    let Some((iface, nexthop)) = route(dst, ctx) else {
+        ctx.drops.drop(DropReason::NoRoute, data);
+        tracing::debug!("No IPv6 route to forward to, dst={}", dst);
+        return Ok(());
+    };
+
+    let mut packet = data.to_vec();
+    packet[7] = hop_limit - 1;
+    output_device(&iface, &packet, nexthop, ctx, devices)?;
+    ctx.stats.ipv6.tx.add(packet.len());
+    Ok(())
+}
+
+/// Output an IPv6 packet for `target` on the device of `iface`. On devices that
+/// need link-layer addresses, a unicast target is resolved by Neighbor
+/// Discovery; the packet waits in the neighbor cache until it is.
+fn output_device(
+    iface: &Ipv6Iface,
+    data: &[u8],
+    target: Ipv6Addr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    let dev = devices
+        .get(iface.device_index)
+        .ok_or(NetError::NoDevice(iface.device_index))?;
+
+    if dev.flags & NET_DEVICE_FLAG_NEED_ARP == 0 {
+        return dev.output(PROTOCOL_TYPE_IPV6, data, None);
+    }
+    let hwaddr = if target.is_multicast() {
+        ndp::multicast_hwaddr(target)
+    } else {
+        match ndp::resolve(iface, target, data, ctx, devices)? {
+            Some(hwaddr) => hwaddr,
+            None => return Ok(()),
+        }
+    };
+    dev.output(PROTOCOL_TYPE_IPV6, data, Some(&hwaddr.0))
+}
+
+/// Send an IPv6 packet with the given payload, with the hop limit of the
+/// outgoing interface
+pub fn ipv6_output(
+    next_header: IpProtocol,
+    payload: &[u8],
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<isize> {
+    ipv6_output_hop_limit(next_header, payload, src, dst, None, ctx, devices)
+}
+
+/// Send an IPv6 packet with the given payload and hop limit (the outgoing
+/// interface's if `None`).
+pub fn ipv6_output_hop_limit(
+    next_header: IpProtocol,
+    payload: &[u8],
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    hop_limit: Option<u8>,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<isize> {
+    let log = LOG_IPV6_OUTPUT.allow(Level::INFO);
+    if log {
+        tracing::debug!(
+            "ipv6_output: {} => {}, next={:?}, len={}",
+            src,
+            dst,
+            next_header,
+            payload.len()
+        );
+    }
+
+    if payload.len() > IPV6_PAYLOAD_SIZE_MAX {
+        return Err(NetError::MessageTooLong {
+            len: payload.len(),
+            max: IPV6_PAYLOAD_SIZE_MAX,
+        });
+    }
+
+    // Multicast goes out of the interface of the source address
+    let (iface, nexthop) = if dst.is_multicast() {
+        if src.is_unspecified() {
+            return Err(NetError::InvalidInput(
+                "source address is required for multicast addresses",
+            ));
+        }
+        let iface = devices
+            .iter()
+            .flat_map(|dev| dev.ipv6_ifaces())
+            .find(|iface| iface.unicast == src)
+            .ok_or(NetError::NoInterface(src.into()))?;
+        (iface.clone(), dst)
+    } else {
+        let (iface, nexthop) = route(dst, ctx).ok_or(NetError::NoRoute(dst.into()))?;
+        if !src.is_unspecified() && src != iface.unicast {
+            tracing::debug!(
+                "ipv6_output: src={} is not the address of iface={}",
+                src,
+                iface.unicast
+            );
+            return Err(NetError::NoInterface(src.into()));
+        }
+        (iface, nexthop)
+    };
+
+    let dev = devices
+        .get(iface.device_index)
+        .ok_or(NetError::NoDevice(iface.device_index))?;
+    let total = IPV6_HDR_SIZE + payload.len();
+    if (dev.mtu as usize) < total {
+        return Err(NetError::MtuExceeded {
+            len: total,
+            mtu: dev.mtu as usize,
+        });
+    }
+
+    let hdr = Ipv6Hdr::new(
+        next_header,
+        payload.len() as u16,
+        hop_limit.unwrap_or(iface.hop_limit),
+        iface.unicast,
+        dst,
+    );
+    let mut packet = Vec::with_capacity(total);
+    packet.extend_from_slice(&hdr.to_bytes());
+    packet.extend_from_slice(payload);
+    if log {
+        ipv6_print(&packet);
+    }
+
+    output_device(&iface, &packet, nexthop, ctx, devices)?;
+    ctx.stats.ipv6.tx.add(total);
+    if let Some(stats) = ctx.stats.transport(next_header) {
+        stats.tx.add(payload.len());
+    }
+    Ok(total as isize)
+}
+
+/// Register an IPv6 interface with address `cidr` (`2001:db8::1/64`) on a
+/// device, and the on-link route for its prefix
+pub fn register_iface(dev: &mut Device, cidr: &str, ctx: &mut ProtocolContexts) -> Result<()> {
+    let (unicast, prefix_len) = parse_cidr(cidr)?;
+    if unicast.is_multicast() || unicast.is_unspecified() {
+        return Err(NetError::InvalidInput("not a unicast address"));
+    }
+    let iface = Ipv6Iface::new(unicast, prefix_len, dev.index);
+    tracing::info!("dev={}, {}", dev.name_string(), iface.info());
+    dev.register_iface(NetIface::Ipv6(iface.clone()))?;
+    ctx.ipv6_routes.add(unicast, prefix_len, None, iface)
+}
+
+/// Interface `gateway` is on-link through
+fn on_link_iface(gateway: Ipv6Addr, ctx: &ProtocolContexts) -> Result<Ipv6Iface> {
+    ctx.ipv6_routes
+        .lookup(gateway)
+        .filter(|route| route.is_on_link())
+        .map(|route| route.iface.clone())
+        .ok_or(NetError::NoRoute(gateway.into()))
+}
+
+/// Add a route to `network`/`prefix_len` via `gateway`, which must be on-link.
+/// The outgoing interface is the one the gateway is reachable through.
+pub fn route_add_via(
+    network: Ipv6Addr,
+    prefix_len: u8,
+    gateway: Ipv6Addr,
+    ctx: &mut ProtocolContexts,
+) -> Result<()> {
+    let iface = on_link_iface(gateway, ctx)?;
+    ctx.ipv6_routes
+        .add(network, prefix_len, Some(gateway), iface)
+}
+
+/// Add `router`, which must be on-link, to the default routers for good
+pub fn add_default_router(router: Ipv6Addr, ctx: &ProtocolContexts) -> Result<()> {
+    let iface = on_link_iface(router, ctx)?;
+    ctx.ipv6_routes.add_default_router(router, iface, None)
+}
+
+pub fn init(protocols: &mut ProtocolManager) -> Result<()> {
+    protocols.register(ProtocolType::Ipv6, Arc::new(ipv6_input_handler))?;
+    tracing::info!("IPv6 protocol initialized");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::testing::setup_loopback;
+
+    fn addr(s: &str) -> Ipv6Addr {
+        Ipv6Addr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_ipv6_addr() {
+        assert_eq!(addr("::"), Ipv6Addr::UNSPECIFIED);
+        assert_eq!(addr("::1"), Ipv6Addr::LOOPBACK);
+        assert_eq!(addr("ff02::1"), Ipv6Addr::ALL_NODES);
+        assert_eq!(
+            addr("2001:0db8:0000:0000:0000:0000:0000:0001").to_string(),
+            "2001:db8::1"
+        );
+        assert!(Ipv6Addr::from_str("2001:db8::1::2").is_err());
+        assert!(Ipv6Addr::from_str("192.0.2.1").is_err());
+        assert!(addr("fe80::1").is_link_local());
+        assert!(Ipv6Addr::ALL_NODES.is_multicast());
+
+        assert_eq!(addr("2001:db8:1:2::5").prefix(48), addr("2001:db8:1::"));
+        assert_eq!(addr("2001:db8::ff").prefix(121), addr("2001:db8::80"));
+        assert!(addr("2001:db8::5").in_prefix(addr("2001:db8::1"), 64));
+        assert!(!addr("2001:db9::5").in_prefix(addr("2001:db8::1"), 32));
+        assert!(addr("2001:db9::5").in_prefix(addr("::"), 0));
+
+        assert_eq!(
+            parse_cidr("2001:db8::1/64").unwrap(),
+            (addr("2001:db8::1"), 64)
+        );
+        assert!(parse_cidr("2001:db8::1/129").is_err());
+        assert!(parse_cidr("2001:db8::1").is_err());
+    }
+
+    #[test]
+    fn test_ipv6_hdr() {
+        let hdr = Ipv6Hdr::new(
+            IpProtocol::Udp,
+            8,
+            IPV6_HOP_LIMIT_DEFAULT,
+            addr("2001:db8::1"),
+            addr("2001:db8::2"),
+        );
+        let bytes = hdr.to_bytes();
+        assert_eq!(&bytes[..8], &[0x60, 0, 0, 0, 0, 8, 17, 64]);
+        let parsed = Ipv6Hdr::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.version(), 6);
+        assert_eq!(parsed.payload_len(), 8);
+        assert_eq!(parsed.next_header(), IpProtocol::Udp);
+        assert_eq!(parsed.dst(), addr("2001:db8::2"));
+        assert!(Ipv6Hdr::from_bytes(&bytes[..39]).is_none());
+    }
+
+    proptest! {
+        #[test]
+        fn test_ipv6_hdr_bytes_roundtrip(bytes in any::<[u8; IPV6_HDR_SIZE]>()) {
+            let hdr = Ipv6Hdr::from_bytes(&bytes).unwrap();
+            prop_assert_eq!(hdr.to_bytes(), bytes);
+        }
+
+        #[test]
+        fn test_ipv6_hdr_build_parse(
+            (next_header, payload_len, hop_limit) in any::<(u8, u16, u8)>(),
+            src in any::<[u8; IPV6_ADDR_LEN]>(),
+            dst in any::<[u8; IPV6_ADDR_LEN]>(),
+        ) {
+            let (src, dst) = (Ipv6Addr::from_octets(src), Ipv6Addr::from_octets(dst));
+            let next_header = IpProtocol::from_u8(next_header);
+            let hdr = Ipv6Hdr::new(next_header, payload_len, hop_limit, src, dst);
+            let parsed = Ipv6Hdr::from_bytes(&hdr.to_bytes()).unwrap();
+            prop_assert_eq!(parsed.version(), IPV6_VERSION);
+            prop_assert_eq!(parsed.traffic_class(), 0);
+            prop_assert_eq!(parsed.flow_label(), 0);
+            prop_assert_eq!(parsed.next_header(), next_header);
+            prop_assert_eq!(parsed.payload_len(), payload_len);
+            prop_assert_eq!(parsed.hop_limit(), hop_limit);
+            prop_assert_eq!(parsed.src(), src);
+            prop_assert_eq!(parsed.dst(), dst);
+        }
+    }
+
+    #[test]
+    fn test_ipv6_input_and_output() {
+        let (mut devices, mut ctx, captured) = setup_loopback();
+        let index = devices.iter().next().unwrap().index;
+        let dev = devices.get_mut(index).unwrap();
+        register_iface(dev, "::1/128", &mut ctx).unwrap();
+        assert!(register_iface(dev, "::1/128", &mut ctx).is_err());
+
+        // Hop limit of the interface, or the one asked for
+        ipv6_output(
+            IpProtocol::Other(IPV6_NEXT_HEADER_NONE),
+            &[],
+            Ipv6Addr::UNSPECIFIED,
+            Ipv6Addr::LOOPBACK,
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        ipv6_output_hop_limit(
+            IpProtocol::Other(IPV6_NEXT_HEADER_NONE),
+            &[],
+            Ipv6Addr::LOOPBACK,
+            Ipv6Addr::LOOPBACK,
+            Some(1),
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        assert!(
+            ipv6_output(
+                IpProtocol::Udp,
+                &[],
+                Ipv6Addr::UNSPECIFIED,
+                addr("2001:db8::1"),
+                &ctx,
+                &devices
+            )
+            .is_err()
+        );
+        let packets = captured.lock().unwrap().clone();
+        assert_eq!(packets.len(), 2);
+        let hdr = Ipv6Hdr::from_bytes(&packets[0]).unwrap();
+        assert_eq!(hdr.hop_limit(), IPV6_HOP_LIMIT_DEFAULT);
+        assert_eq!(hdr.src(), Ipv6Addr::LOOPBACK);
+        assert_eq!(Ipv6Hdr::from_bytes(&packets[1]).unwrap().hop_limit(), 1);
+
+        let dev = devices.get(index).unwrap();
+        ipv6_input(&Frame::Borrowed(&packets[0]), dev, &ctx, &devices).unwrap();
+        assert_eq!(ctx.drops.total(), 0);
+
+        let mut other = packets[0].clone();
+        other[24..40].copy_from_slice(&addr("2001:db8::1").octets());
+        ipv6_input(&Frame::Borrowed(&other), dev, &ctx, &devices).unwrap();
+        assert_eq!(ctx.drops.count(DropReason::NotForUs), 1);
+
+        let mut udp = packets[0].clone();
+        udp[6] = IpProtocol::Udp.to_u8();
+        ipv6_input(&Frame::Borrowed(&udp), dev, &ctx, &devices).unwrap();
+        assert_eq!(ctx.drops.count(DropReason::UnknownProtocol), 1);
+
+        let mut v4 = packets[0].clone();
+        v4[0] = 0x45;
+        assert!(ipv6_input(&Frame::Borrowed(&v4), dev, &ctx, &devices).is_err());
+        assert!(ipv6_input(&Frame::Borrowed(&packets[0][..39]), dev, &ctx, &devices).is_err());
+        assert_eq!(ctx.drops.count(DropReason::Malformed), 2);
+    }
+
+    #[test]
+    fn test_ipv6_forward_hop_limit() {
+        let (mut devices, mut ctx, captured) = setup_loopback();
+        ctx.ip_config.forwarding = true;
+        let index = devices.iter().next().unwrap().index;
+        register_iface(devices.get_mut(index).unwrap(), "2001:db8::1/64", &mut ctx).unwrap();
+        let dev = devices.get(index).unwrap();
+
+        let packet = |hop_limit| {
+            Ipv6Hdr::new(
+                IpProtocol::Other(IPV6_NEXT_HEADER_NONE),
+                0,
+                hop_limit,
+                addr("2001:db8::2"),
+                addr("2001:db8::3"),
+            )
+            .to_bytes()
+        };
+        ipv6_input(&Frame::Borrowed(&packet(2)), dev, &ctx, &devices).unwrap();
+        let forwarded = captured.lock().unwrap()[0].clone();
+        assert_eq!(Ipv6Hdr::from_bytes(&forwarded).unwrap().hop_limit(), 1);
+
+        ipv6_input(&Frame::Borrowed(&packet(1)), dev, &ctx, &devices).unwrap();
+        assert_eq!(captured.lock().unwrap().len(), 1);
+        assert_eq!(ctx.drops.count(DropReason::TtlExceeded), 1);
+    }
+}
@@ -1,20 +1,145 @@
 pub mod icmp;
+pub mod igmp;
 pub mod ip;
+pub mod ipv6;
+pub mod nat;
+pub mod ndp;
+pub mod raw;
+pub mod tcp;
+pub mod udp;
 
-use anyhow::Result;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, mpsc};
+use std::time::Duration;
 
+use tracing::Level;
+
+use crate::bytes::{Bytes, Frame};
+use crate::clock::Clock;
 use crate::context::ProtocolContexts;
-use crate::device::Device;
+use crate::device::{Device, DeviceIndex, DeviceManager};
+use crate::drop::{DropMonitor, DropReason};
+use crate::error::{NetError, Result};
+use crate::event::EventBus;
+use crate::irq::SoftIrq;
+use crate::limits::StackLimits;
+use crate::platform::Instant;
+use crate::ring::{self, Consumer, Gauge, Producer};
+use crate::timer::{TimerHandler, TimerTable};
+use crate::trace::{TRACE, TraceEvent};
+use crate::util::LOG_DEVICE;
 
+// Frame types (ethertypes). Any other value can be registered as `ProtocolType::Unknown`.
 pub const PROTOCOL_TYPE_IP: u16 = 0x0800;
 pub const PROTOCOL_TYPE_ARP: u16 = 0x0806;
+pub const PROTOCOL_TYPE_VLAN: u16 = 0x8100;
 pub const PROTOCOL_TYPE_IPV6: u16 = 0x86dd;
+pub const PROTOCOL_TYPE_LLDP: u16 = 0x88cc;
+/// IEEE 802 local experimental ethertype, for custom protocols
+pub const PROTOCOL_TYPE_EXPERIMENTAL: u16 = 0x88b5;
+
+// Receive flags (values follow Linux recv(2))
+
+/// Return the data without removing it from the receive queue
+pub const MSG_PEEK: u32 = 0x02;
+/// Report the full length of a datagram even if it did not fit the buffer
+pub const MSG_TRUNC: u32 = 0x20;
+/// Fail with [`NetError::WouldBlock`] instead of waiting for data
+pub const MSG_DONTWAIT: u32 = 0x40;
+/// Block until the buffer is full (streams only; datagrams always return one)
+pub const MSG_WAITALL: u32 = 0x100;
+
+// Poll events (values follow Linux poll(2))
+
+/// Data can be read, a connection accepted, or the peer closed its side
+pub const POLLIN: u16 = 0x001;
+/// Data can be sent
+pub const POLLOUT: u16 = 0x004;
+/// The connection failed; always reported
+pub const POLLERR: u16 = 0x008;
+/// The connection is closed; always reported
+pub const POLLHUP: u16 = 0x010;
+/// Not an open socket; always reported
+pub const POLLNVAL: u16 = 0x020;
+
+/// Change counter the UDP and TCP tables bump whenever a control block may have
+/// become ready, so pollers can wait for all of them at once
+pub struct Readiness {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+/// Readiness of every stack in the process. A change in one stack wakes the
+/// pollers of all of them; they re-check and go back to sleep.
+pub static READINESS: Readiness = Readiness::new();
+
+impl Readiness {
+    pub const fn new() -> Self {
+        Self {
+            generation: Mutex::new(0),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Wake everyone waiting
+    pub fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.changed.notify_all();
+    }
+
+    /// Changes so far; pass to [`wait`](Self::wait)
+    pub fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    /// Wait until something changed after `seen`, giving up at `deadline`
+    /// (never if `None`). Returns false on timeout.
+    pub fn wait(&self, seen: u64, deadline: Option<Instant>) -> bool {
+        let mut generation = self.generation.lock().unwrap();
+        while *generation == seen {
+            match wait_until(&self.changed, generation, deadline) {
+                Some(guard) => generation = guard,
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Wait on `cond` until notified or `deadline` passes (never if `None`).
+/// Returns `None` once the deadline has passed.
+pub(crate) fn wait_until<'a, T>(
+    cond: &Condvar,
+    guard: MutexGuard<'a, T>,
+    deadline: Option<Instant>,
+) -> Option<MutexGuard<'a, T>> {
+    match deadline {
+        None => Some(cond.wait(guard).unwrap()),
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            Some(cond.wait_timeout(guard, deadline - now).unwrap().0)
+        }
+    }
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProtocolType {
     Ip,
     Arp,
+    Vlan,
     Ipv6,
+    Lldp,
     Unknown(u16),
 }
 
@@ -23,7 +148,9 @@ impl From<u16> for ProtocolType {
         match value {
             PROTOCOL_TYPE_IP => ProtocolType::Ip,
             PROTOCOL_TYPE_ARP => ProtocolType::Arp,
+            PROTOCOL_TYPE_VLAN => ProtocolType::Vlan,
             PROTOCOL_TYPE_IPV6 => ProtocolType::Ipv6,
+            PROTOCOL_TYPE_LLDP => ProtocolType::Lldp,
             other => ProtocolType::Unknown(other),
         }
     }
@@ -34,56 +161,627 @@ impl From<ProtocolType> for u16 {
         match value {
             ProtocolType::Ip => PROTOCOL_TYPE_IP,
             ProtocolType::Arp => PROTOCOL_TYPE_ARP,
+            ProtocolType::Vlan => PROTOCOL_TYPE_VLAN,
             ProtocolType::Ipv6 => PROTOCOL_TYPE_IPV6,
+            ProtocolType::Lldp => PROTOCOL_TYPE_LLDP,
             ProtocolType::Unknown(v) => v,
         }
     }
 }
 
-pub type ProtocolHandler = fn(&[u8], &Device, &ProtocolContexts);
+/// Handler of received frames of one type.
+///
+/// A protocol may keep state of its own (caches, control blocks) behind
+/// `&self`; it is shared by every thread that receives frames, hence `Sync`.
+/// Plain functions with the signature of [`input`](Self::input) are
+/// protocols without state. The frame is a [`Frame`], which a protocol can
+/// slice without copying, and keep with [`Frame::to_bytes`].
+pub trait Protocol: Send + Sync {
+    fn input(
+        &self,
+        data: &Frame<'_>,
+        dev: &Device,
+        ctx: &ProtocolContexts,
+        devices: &DeviceManager,
+    );
+
+    /// How often [`on_timer`](Self::on_timer) runs; `None` (the default) for
+    /// protocols without periodic work
+    fn timer_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    fn on_timer(&self, _ctx: &ProtocolContexts, _devices: &DeviceManager) {}
+}
+
+impl<F> Protocol for F
+where
+    F: Fn(&Frame<'_>, &Device, &ProtocolContexts, &DeviceManager) + Send + Sync,
+{
+    fn input(
+        &self,
+        data: &Frame<'_>,
+        dev: &Device,
+        ctx: &ProtocolContexts,
+        devices: &DeviceManager,
+    ) {
+        self(data, dev, ctx, devices)
+    }
+}
+
+/// Frames a protocol queue holds per device by default before dropping (like `netdev_max_backlog`)
+pub const RX_QUEUE_LEN: usize = 1000;
+
+/// Received frames of one device waiting for a protocol, as queued by one
+/// thread.
+///
+/// The ring's producer belongs to the thread that receives the frames (see
+/// [`RX_PRODUCERS`]) and its consumer to the protocol worker (see
+/// [`RxWorker`]), so neither end takes a lock and the two never wait for each
+/// other. Frames the drop hook rescues from a full ring go to `overflow`, and
+/// while it holds any, new frames go there too so that they stay in order.
+struct RxQueue {
+    dev: DeviceIndex,
+    ring: Gauge<Bytes>,
+    overflow: Mutex<VecDeque<Bytes>>,
+    /// Length of `overflow`, read without its lock
+    overflowed: AtomicUsize,
+}
+
+impl RxQueue {
+    fn len(&self) -> usize {
+        self.ring.len() + self.overflowed.load(Ordering::Acquire)
+    }
+}
+
+/// The receiving thread's end of an [`RxQueue`]
+struct RxProducer {
+    /// [`ProtocolManager::id`] of the stack the queue belongs to
+    manager: u64,
+    /// Position of the protocol in [`ProtocolManager::protocols`]
+    protocol: usize,
+    queue: Arc<RxQueue>,
+    producer: Producer<Bytes>,
+}
+
+impl RxProducer {
+    /// Queue `data`, or drop it unless the hook rescues it; returns the
+    /// queue's depth if it was queued
+    fn push(&mut self, data: Bytes, drops: &DropMonitor) -> Option<usize> {
+        let queue = &*self.queue;
+        let data = if queue.overflowed.load(Ordering::Acquire) == 0 {
+            match self.producer.push(data) {
+                Ok(()) => return Some(self.producer.len()),
+                Err(data) => data,
+            }
+        } else {
+            data
+        };
+        if drops.drop(DropReason::RxQueueFull, &data) {
+            return None;
+        }
+        let mut overflow = queue.overflow.lock().unwrap();
+        overflow.push_back(data);
+        queue.overflowed.store(overflow.len(), Ordering::Release);
+        Some(self.producer.len() + overflow.len())
+    }
+}
+
+/// The protocol worker's end of an [`RxQueue`]
+struct RxConsumer {
+    protocol: usize,
+    queue: Arc<RxQueue>,
+    consumer: Consumer<Bytes>,
+}
+
+impl RxConsumer {
+    /// Take the oldest frame, with the depth left behind it. Everything in
+    /// the ring was queued before the overflow, which is only drained once the
+    /// ring is empty.
+    fn pop(&mut self) -> Option<(Bytes, usize)> {
+        let queue = &*self.queue;
+        if let Some(data) = self.consumer.pop() {
+            let depth = self.consumer.len() + queue.overflowed.load(Ordering::Acquire);
+            return Some((data, depth));
+        }
+        if queue.overflowed.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        let mut overflow = queue.overflow.lock().unwrap();
+        let data = overflow.pop_front()?;
+        queue.overflowed.store(overflow.len(), Ordering::Release);
+        Some((data, overflow.len()))
+    }
+
+    /// Whether the receiving thread is gone and everything it queued handled
+    fn is_finished(&self) -> bool {
+        self.consumer.is_closed() && self.queue.len() == 0
+    }
+}
+
+/// Consumers of every receive queue of a stack. Queues are created by the
+/// receiving threads and handed over on `incoming`, so that the worker never
+/// waits for them.
+struct RxWorker {
+    /// By position of the protocol, in the order the queues were created
+    queues: Vec<Vec<RxConsumer>>,
+    incoming: mpsc::Receiver<RxConsumer>,
+}
+
+impl RxWorker {
+    /// Take over the queues created since the last call
+    fn adopt(&mut self) {
+        while let Ok(consumer) = self.incoming.try_recv() {
+            if self.queues.len() <= consumer.protocol {
+                self.queues.resize_with(consumer.protocol + 1, Vec::new);
+            }
+            self.queues[consumer.protocol].push(consumer);
+        }
+    }
+}
+
+/// Identifies protocol managers in [`RX_PRODUCERS`]; never reused, unlike an
+/// address
+static MANAGERS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Producers of the receive queues this thread has fed, of every stack
+    static RX_PRODUCERS: RefCell<Vec<RxProducer>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A frame handled by [`ProtocolManager::step`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// Steps taken so far, this one included
+    pub seq: u64,
+    pub dev: DeviceIndex,
+    pub type_: ProtocolType,
+    pub len: usize,
+    /// Headers of the frame in one line, e.g. the IP header of an IP packet
+    pub summary: String,
+    /// Frames still queued after this one
+    pub remaining: usize,
+}
 
-struct Protocol {
+struct Registered {
     type_: ProtocolType,
-    handler: ProtocolHandler,
+    protocol: Arc<dyn Protocol>,
+    /// Queues of this protocol, one per device and receiving thread, for
+    /// [`ProtocolManager::backlog`]
+    rx: RwLock<Vec<Arc<RxQueue>>>,
 }
 
 pub struct ProtocolManager {
-    protocols: Vec<Protocol>,
+    /// Identifies this manager's queues in [`RX_PRODUCERS`]
+    id: u64,
+    protocols: Vec<Registered>,
+    /// Frames each receive queue holds
+    rx_queue_len: usize,
+    /// Consumers of the receive queues, taken by whoever polls
+    rx_worker: Mutex<RxWorker>,
+    /// Hands new receive queues to [`rx_worker`](Self::rx_worker)
+    rx_created: mpsc::Sender<RxConsumer>,
+    /// Frames dropped because a receive queue was full
+    rx_dropped: AtomicU64,
+    /// Queue received frames for [`ProtocolManager::poll`] instead of handling them at once
+    deferred: bool,
+    /// Queue received frames and handle none until resumed, except by [`ProtocolManager::step`]
+    paused: bool,
+    /// Frames handled by [`ProtocolManager::step`]
+    steps: AtomicU64,
+    timers: Arc<TimerTable>,
+    events: EventBus,
+    softirq: Arc<SoftIrq>,
 }
 
 impl ProtocolManager {
     pub fn new() -> Self {
+        Self::with_limits(&StackLimits::default())
+    }
+
+    pub fn with_limits(limits: &StackLimits) -> Self {
+        Self::with_clock(limits, Clock::default())
+    }
+
+    /// Like [`with_limits`](Self::with_limits), with timers due by `clock`
+    pub fn with_clock(limits: &StackLimits, clock: Clock) -> Self {
+        let (rx_created, incoming) = mpsc::channel();
         Self {
+            id: MANAGERS.fetch_add(1, Ordering::Relaxed),
             protocols: Vec::new(),
+            rx_queue_len: limits.rx_queue_len,
+            rx_worker: Mutex::new(RxWorker {
+                queues: Vec::new(),
+                incoming,
+            }),
+            rx_created,
+            rx_dropped: AtomicU64::new(0),
+            deferred: false,
+            paused: false,
+            steps: AtomicU64::new(0),
+            timers: Arc::new(TimerTable::with_clock(clock)),
+            events: EventBus::new(),
+            softirq: Arc::new(SoftIrq::new()),
         }
     }
 
-    pub fn register(&mut self, type_: ProtocolType, handler: ProtocolHandler) -> Result<()> {
+    pub fn set_deferred(&mut self, deferred: bool) {
+        self.deferred = deferred;
+    }
+
+    pub fn is_deferred(&self) -> bool {
+        self.deferred
+    }
+
+    /// Stop handling received frames; they are queued (and dropped when a
+    /// queue is full) until [`step`](Self::step) or a poll after resuming
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Handle frames of `type_` with `protocol`, and run its timer if it has
+    /// one. Built-in and custom frame types register the same way; `type_` is
+    /// normalized so `Unknown(0x0800)` is `Ip`.
+    pub fn register(&mut self, type_: ProtocolType, protocol: Arc<dyn Protocol>) -> Result<()> {
+        let type_ = ProtocolType::from(u16::from(type_));
         if self.protocols.iter().any(|p| p.type_ == type_) {
-            anyhow::bail!("Protocol already registered: {:?}", type_);
+            return Err(NetError::AlreadyExists(format!("protocol {:?}", type_)));
+        }
+        if let Some(interval) = protocol.timer_interval() {
+            let protocol = Arc::clone(&protocol);
+            self.timers.register(
+                &format!("protocol 0x{:04x}", u16::from(type_)),
+                interval,
+                Arc::new(move |ctx, devices| protocol.on_timer(ctx, devices)),
+            )?;
         }
 
         tracing::debug!("Protocol registered: {:?}", type_);
-        self.protocols.push(Protocol { type_, handler });
+        self.protocols.push(Registered {
+            type_,
+            protocol,
+            rx: RwLock::new(Vec::new()),
+        });
         Ok(())
     }
 
-    pub fn dispatch(&self, type_: u16, data: &[u8], dev: &Device, ctx: &ProtocolContexts) {
+    /// Run `handler` every `interval` (see [`timer`](crate::timer))
+    pub fn register_timer(
+        &mut self,
+        name: &str,
+        interval: Duration,
+        handler: TimerHandler,
+    ) -> Result<()> {
+        self.timers.register(name, interval, handler)
+    }
+
+    pub fn timers(&self) -> &Arc<TimerTable> {
+        &self.timers
+    }
+
+    /// Subscribers to the stack's events (see [`event`](crate::event))
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Raised whenever a frame is queued (see [`SoftIrq`])
+    pub fn softirq(&self) -> &Arc<SoftIrq> {
+        &self.softirq
+    }
+
+    pub fn dispatch(
+        &self,
+        type_: u16,
+        data: &Frame<'_>,
+        dev: &Device,
+        ctx: &ProtocolContexts,
+        devices: &DeviceManager,
+    ) {
         let protocol_type = ProtocolType::from(type_);
 
-        for protocol in &self.protocols {
-            if protocol.type_ == protocol_type {
-                (protocol.handler)(data, dev, ctx);
+        for registered in &self.protocols {
+            if registered.type_ == protocol_type {
+                registered.protocol.input(data, dev, ctx, devices);
                 return;
             }
         }
 
+        ctx.drops.drop(DropReason::NoHandler, data);
         tracing::debug!("No handler for protocol type: 0x{:04x}", type_);
     }
 
+    /// Frame types with a registered handler
+    pub fn types(&self) -> Vec<ProtocolType> {
+        self.protocols.iter().map(|p| p.type_).collect()
+    }
+
+    /// Hand a received frame to its protocol: queued in deferred mode, handled now otherwise.
+    /// A frame handled now is borrowed; a queued one is copied once, into a
+    /// buffer of [`ProtocolContexts::packets`], and shared from there on.
+    pub fn receive(
+        &self,
+        type_: u16,
+        data: &[u8],
+        dev: &Device,
+        ctx: &ProtocolContexts,
+        devices: &DeviceManager,
+    ) {
+        self.receive_batch(&[(type_, data)], dev, ctx, devices);
+    }
+
+    /// Like [`receive`](Self::receive), for frames a driver took off its ring
+    /// in one go. In deferred mode each run of frames of one type is queued
+    /// after a single lookup of this thread's receive queue, and the softirq
+    /// is raised once for the whole batch.
+    pub fn receive_batch(
+        &self,
+        frames: &[(u16, &[u8])],
+        dev: &Device,
+        ctx: &ProtocolContexts,
+        devices: &DeviceManager,
+    ) {
+        if !self.deferred && !self.paused {
+            for &(type_, data) in frames {
+                self.dispatch(type_, &Frame::Borrowed(data), dev, ctx, devices);
+            }
+            return;
+        }
+        let mut queued = false;
+        for run in frames.chunk_by(|a, b| a.0 == b.0) {
+            queued |= self.enqueue(run, dev, ctx);
+        }
+        if queued && !self.paused {
+            self.softirq.raise();
+        }
+    }
+
+    /// Queue `frames`, all of one type, for their protocol; returns whether any
+    /// was queued
+    fn enqueue(&self, frames: &[(u16, &[u8])], dev: &Device, ctx: &ProtocolContexts) -> bool {
+        let Some(&(type_, _)) = frames.first() else {
+            return false;
+        };
+        let protocol_type = ProtocolType::from(type_);
+        let Some(index) = self.protocols.iter().position(|p| p.type_ == protocol_type) else {
+            for (_, data) in frames {
+                ctx.drops.drop(DropReason::NoHandler, data);
+            }
+            tracing::debug!("No handler for protocol type: 0x{:04x}", type_);
+            return false;
+        };
+
+        let push = |producer: &mut RxProducer| {
+            let mut queued = false;
+            for (_, data) in frames {
+                let data = ctx.packets.bytes_from(data);
+                let Some(depth) = producer.push(data, &ctx.drops) else {
+                    self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+                    if LOG_DEVICE.allow(Level::DEBUG) {
+                        tracing::debug!(
+                            "rx queue full: protocol={:?}, dev={}, frame dropped",
+                            protocol_type,
+                            dev.name_string()
+                        );
+                    }
+                    continue;
+                };
+                TRACE.record(TraceEvent::Enqueue {
+                    dev: dev.index,
+                    type_,
+                    depth,
+                });
+                queued = true;
+            }
+            queued
+        };
+        let queued = RX_PRODUCERS.try_with(|producers| {
+            // Already borrowed when the drop hook receives frames itself
+            let mut producers = producers.try_borrow_mut().ok()?;
+            let pos = match producers.iter().position(|p| {
+                p.manager == self.id && p.protocol == index && p.queue.dev == dev.index
+            }) {
+                Some(pos) => pos,
+                None => {
+                    producers.retain(|p| !p.producer.is_closed());
+                    producers.push(self.rx_queue(index, dev.index));
+                    producers.len() - 1
+                }
+            };
+            Some(push(&mut producers[pos]))
+        });
+        match queued {
+            Ok(Some(queued)) => queued,
+            // A queue of its own, which the worker forgets once it is drained
+            _ => push(&mut self.rx_queue(index, dev.index)),
+        }
+    }
+
+    /// Create a receive queue of protocol `index` for frames of `dev` that the
+    /// calling thread receives; the worker gets its consumer
+    #[cold]
+    fn rx_queue(&self, index: usize, dev: DeviceIndex) -> RxProducer {
+        let (producer, consumer) = ring::ring(self.rx_queue_len);
+        let queue = Arc::new(RxQueue {
+            dev,
+            ring: producer.gauge(),
+            overflow: Mutex::new(VecDeque::new()),
+            overflowed: AtomicUsize::new(0),
+        });
+        self.protocols[index]
+            .rx
+            .write()
+            .unwrap()
+            .push(Arc::clone(&queue));
+        // Cannot fail: the receiver is in `self.rx_worker`
+        let _ = self.rx_created.send(RxConsumer {
+            protocol: index,
+            queue: Arc::clone(&queue),
+            consumer,
+        });
+        RxProducer {
+            manager: self.id,
+            protocol: index,
+            queue,
+            producer,
+        }
+    }
+
+    /// Handle up to `budget` queued frames and return how many were handled.
+    ///
+    /// Queues are served round-robin, one frame from each protocol and device per
+    /// round, so a flood on one device cannot starve the others. Frames queued
+    /// by the handlers themselves (e.g. replies on loopback) count against the
+    /// same budget.
+    /// Nothing is handled while paused. Pollers take turns; receiving threads
+    /// never wait for them.
+    pub fn poll(&self, budget: usize, ctx: &ProtocolContexts, devices: &DeviceManager) -> usize {
+        if self.paused {
+            return 0;
+        }
+        let mut worker = self.rx_worker.lock().unwrap();
+        let mut handled = 0;
+        while handled < budget {
+            worker.adopt();
+            let mut progressed = false;
+            for (protocol, queues) in self.protocols.iter().zip(worker.queues.iter_mut()) {
+                for queue in queues.iter_mut() {
+                    if handled >= budget {
+                        break;
+                    }
+                    let Some((data, depth)) = queue.pop() else {
+                        continue;
+                    };
+                    let index = queue.queue.dev;
+                    TRACE.record(TraceEvent::Dequeue {
+                        dev: index,
+                        type_: protocol.type_.into(),
+                        depth,
+                    });
+                    progressed = true;
+                    handled += 1;
+                    Self::handle(protocol, index, &Frame::Shared(data), ctx, devices);
+                }
+            }
+            if !progressed {
+                break;
+            }
+        }
+        self.forget_finished(&mut worker);
+        handled
+    }
+
+    /// Drop the queues of threads that are gone, once they are drained
+    fn forget_finished(&self, worker: &mut RxWorker) {
+        for (protocol, queues) in self.protocols.iter().zip(worker.queues.iter_mut()) {
+            if !queues.iter().any(RxConsumer::is_finished) {
+                continue;
+            }
+            queues.retain(|queue| !queue.is_finished());
+            protocol
+                .rx
+                .write()
+                .unwrap()
+                .retain(|queue| queues.iter().any(|q| Arc::ptr_eq(&q.queue, queue)));
+        }
+    }
+
+    /// Handle the next queued frame only, paused or not, inside a `step` span
+    /// so that everything the pipeline logs for it can be told apart.
+    /// Returns `None` when no frame is queued.
+    pub fn step(&self, ctx: &ProtocolContexts, devices: &DeviceManager) -> Option<Step> {
+        let mut worker = self.rx_worker.lock().unwrap();
+        worker.adopt();
+        let (protocol, index, data) = self
+            .protocols
+            .iter()
+            .zip(worker.queues.iter_mut())
+            .find_map(|(protocol, queues)| {
+                queues.iter_mut().find_map(|queue| {
+                    let (data, depth) = queue.pop()?;
+                    TRACE.record(TraceEvent::Dequeue {
+                        dev: queue.queue.dev,
+                        type_: protocol.type_.into(),
+                        depth,
+                    });
+                    Some((protocol, queue.queue.dev, data))
+                })
+            })?;
+        let seq = self.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        let summary = match protocol.type_ {
+            ProtocolType::Ip => ip::IpHdr::from_bytes(&data)
+                .map(|hdr| hdr.to_string())
+                .unwrap_or_else(|| "truncated IP header".to_string()),
+            _ => format!("{} bytes", data.len()),
+        };
+        let span = tracing::info_span!(
+            "step",
+            seq,
+            dev = %index,
+            type_ = ?protocol.type_,
+            len = data.len()
+        );
+        let _enter = span.enter();
+        tracing::info!(summary = %summary, "step: handling frame");
+        let len = data.len();
+        Self::handle(protocol, index, &Frame::Shared(data), ctx, devices);
+        let remaining = self.backlog();
+        tracing::info!(remaining, "step: done");
+        Some(Step {
+            seq,
+            dev: index,
+            type_: protocol.type_,
+            len,
+            summary,
+            remaining,
+        })
+    }
+
+    /// Run the handler of a dequeued frame, unless its device went down
+    fn handle(
+        registered: &Registered,
+        index: DeviceIndex,
+        data: &Frame<'_>,
+        ctx: &ProtocolContexts,
+        devices: &DeviceManager,
+    ) {
+        match devices.get(index) {
+            Some(dev) if dev.is_up() || !ctx.drops.drop(DropReason::DeviceDown, data) => {
+                registered.protocol.input(data, dev, ctx, devices)
+            }
+            Some(_) => tracing::debug!("device {} is down, frame dropped", index),
+            None => {
+                ctx.drops.drop(DropReason::DeviceDown, data);
+                tracing::debug!("device {} is gone, frame dropped", index);
+            }
+        }
+    }
+
+    /// Frames waiting in the receive queues
+    pub fn backlog(&self) -> usize {
+        self.protocols
+            .iter()
+            .map(|p| p.rx.read().unwrap().iter().map(|q| q.len()).sum::<usize>())
+            .sum()
+    }
+
+    /// Frames dropped because a receive queue was full, not counting those
+    /// the drop hook rescued
+    pub fn rx_dropped(&self) -> u64 {
+        self.rx_dropped.load(Ordering::Relaxed)
+    }
+
     pub fn init(&mut self) -> Result<()> {
         tracing::info!("Initializing protocols...");
         ip::init(self)?;
+        ipv6::init(self)?;
+        igmp::init(self)?;
+        udp::init(self)?;
+        tcp::init(self)?;
+        nat::init(self)?;
         tracing::info!("Protocols initialized");
         Ok(())
     }
@@ -94,3 +792,242 @@ impl Default for ProtocolManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    thread_local! {
+        static HANDLED: RefCell<Vec<DeviceIndex>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(_data: &Frame<'_>, dev: &Device, _ctx: &ProtocolContexts, _devices: &DeviceManager) {
+        HANDLED.with(|handled| handled.borrow_mut().push(dev.index));
+    }
+
+    #[test]
+    fn test_poll_budget_is_shared_fairly() {
+        let mut devices = DeviceManager::new();
+        let flooded = devices.register(Device::default()).unwrap();
+        let quiet = devices.register(Device::default()).unwrap();
+        devices.run().unwrap();
+        let ctx = ProtocolContexts::new();
+
+        let mut protocols = ProtocolManager::new();
+        protocols
+            .register(ProtocolType::Ip, Arc::new(record))
+            .unwrap();
+        protocols.set_deferred(true);
+        for _ in 0..5 {
+            protocols.receive(
+                PROTOCOL_TYPE_IP,
+                b"x",
+                devices.get(flooded).unwrap(),
+                &ctx,
+                &devices,
+            );
+        }
+        protocols.receive(
+            PROTOCOL_TYPE_IP,
+            b"x",
+            devices.get(quiet).unwrap(),
+            &ctx,
+            &devices,
+        );
+        assert!(HANDLED.with(|handled| handled.borrow().is_empty()));
+
+        assert_eq!(protocols.poll(2, &ctx, &devices), 2);
+        assert_eq!(HANDLED.with(|h| h.borrow().clone()), [flooded, quiet]);
+        assert_eq!(protocols.backlog(), 4);
+        assert_eq!(protocols.poll(64, &ctx, &devices), 4);
+        assert_eq!(protocols.poll(64, &ctx, &devices), 0);
+    }
+
+    #[test]
+    fn test_receive_batch() {
+        let mut devices = DeviceManager::new();
+        let index = devices.register(Device::default()).unwrap();
+        devices.run().unwrap();
+        let ctx = ProtocolContexts::new();
+
+        let mut protocols = ProtocolManager::new();
+        protocols
+            .register(ProtocolType::Ip, Arc::new(record))
+            .unwrap();
+        protocols.set_deferred(true);
+        let frames: [(u16, &[u8]); 4] = [
+            (PROTOCOL_TYPE_IP, b"a"),
+            (PROTOCOL_TYPE_IP, b"b"),
+            (PROTOCOL_TYPE_LLDP, b"c"),
+            (PROTOCOL_TYPE_IP, b"d"),
+        ];
+        let dev = devices.get(index).unwrap();
+        protocols.receive_batch(&frames, dev, &ctx, &devices);
+        assert_eq!(protocols.backlog(), 3);
+        assert_eq!(ctx.drops.count(DropReason::NoHandler), 1);
+
+        assert_eq!(protocols.poll(64, &ctx, &devices), 3);
+        assert_eq!(HANDLED.with(|h| h.borrow().len()), 3);
+    }
+
+    #[test]
+    fn test_rescued_frames_queue_past_the_limit() {
+        let mut devices = DeviceManager::new();
+        let index = devices.register(Device::default()).unwrap();
+        devices.run().unwrap();
+        let ctx = ProtocolContexts::new();
+        let handled = Arc::new(Mutex::new(Vec::new()));
+
+        let limits = StackLimits {
+            rx_queue_len: 2,
+            ..StackLimits::default()
+        };
+        let mut protocols = ProtocolManager::with_limits(&limits);
+        let seen = Arc::clone(&handled);
+        protocols
+            .register(
+                ProtocolType::Ip,
+                Arc::new(
+                    move |data: &Frame<'_>, _: &Device, _: &ProtocolContexts, _: &DeviceManager| {
+                        seen.lock().unwrap().push(data[0]);
+                    },
+                ),
+            )
+            .unwrap();
+        protocols.set_deferred(true);
+        let dev = devices.get(index).unwrap();
+        let frames: Vec<(u16, &[u8])> = [b"a", b"b", b"c", b"d"]
+            .iter()
+            .map(|data| (PROTOCOL_TYPE_IP, &data[..]))
+            .collect();
+
+        protocols.receive_batch(&frames[..3], dev, &ctx, &devices);
+        assert_eq!(protocols.backlog(), 2);
+        assert_eq!(protocols.rx_dropped(), 1);
+        assert_eq!(ctx.drops.count(DropReason::RxQueueFull), 1);
+
+        // Rescued frames wait behind the ring, and so does everything after
+        // them until they are handled
+        ctx.drops
+            .set_hook(Some(Arc::new(|_, _| crate::drop::DropVerdict::Rescue)));
+        protocols.receive_batch(&frames[2..], dev, &ctx, &devices);
+        assert_eq!(protocols.backlog(), 4);
+        assert_eq!(protocols.poll(1, &ctx, &devices), 1);
+        protocols.receive_batch(&frames[..1], dev, &ctx, &devices);
+        assert_eq!(protocols.poll(64, &ctx, &devices), 4);
+        assert_eq!(*handled.lock().unwrap(), b"abcda");
+        assert_eq!(protocols.rx_dropped(), 1);
+        assert_eq!(ctx.drops.count(DropReason::RxQueueFull), 1);
+    }
+
+    #[test]
+    fn test_receive_queues_per_thread() {
+        let mut devices = DeviceManager::new();
+        let index = devices.register(Device::default()).unwrap();
+        devices.run().unwrap();
+        let ctx = ProtocolContexts::new();
+        let handled = Arc::new(AtomicUsize::new(0));
+
+        let mut protocols = ProtocolManager::new();
+        let count = Arc::clone(&handled);
+        protocols
+            .register(
+                ProtocolType::Ip,
+                Arc::new(
+                    move |_: &Frame<'_>, _: &Device, _: &ProtocolContexts, _: &DeviceManager| {
+                        count.fetch_add(1, Ordering::Relaxed);
+                    },
+                ),
+            )
+            .unwrap();
+        protocols.set_deferred(true);
+        // Joined rather than scoped, which also waits for the threads' locals
+        // to be dropped
+        let shared = Arc::new((protocols, ctx, devices));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                std::thread::spawn(move || {
+                    let (protocols, ctx, devices) = &*shared;
+                    let dev = devices.get(index).unwrap();
+                    for _ in 0..3 {
+                        protocols.receive(PROTOCOL_TYPE_IP, b"x", dev, ctx, devices);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let (protocols, ctx, devices) = Arc::into_inner(shared).unwrap();
+        let dev = devices.get(index).unwrap();
+        protocols.receive(PROTOCOL_TYPE_IP, b"x", dev, &ctx, &devices);
+        assert_eq!(protocols.protocols[0].rx.read().unwrap().len(), 3);
+        assert_eq!(protocols.backlog(), 7);
+
+        // The queues of the threads that are gone go once drained
+        assert_eq!(protocols.poll(64, &ctx, &devices), 7);
+        assert_eq!(handled.load(Ordering::Relaxed), 7);
+        assert_eq!(protocols.protocols[0].rx.read().unwrap().len(), 1);
+        protocols.receive(PROTOCOL_TYPE_IP, b"x", dev, &ctx, &devices);
+        assert_eq!(protocols.poll(64, &ctx, &devices), 1);
+    }
+
+    /// Counts frames per device itself and forgets them on its timer
+    #[derive(Default)]
+    struct Counter {
+        frames: Mutex<Vec<(DeviceIndex, usize)>>,
+    }
+
+    impl Protocol for Counter {
+        fn input(&self, _data: &Frame<'_>, dev: &Device, _: &ProtocolContexts, _: &DeviceManager) {
+            let mut frames = self.frames.lock().unwrap();
+            match frames.iter_mut().find(|(index, _)| *index == dev.index) {
+                Some((_, count)) => *count += 1,
+                None => frames.push((dev.index, 1)),
+            }
+        }
+
+        fn timer_interval(&self) -> Option<Duration> {
+            Some(Duration::from_millis(1))
+        }
+
+        fn on_timer(&self, _: &ProtocolContexts, _: &DeviceManager) {
+            self.frames.lock().unwrap().clear();
+        }
+    }
+
+    #[test]
+    fn test_stateful_protocol() {
+        let mut devices = DeviceManager::new();
+        let index = devices.register(Device::default()).unwrap();
+        let ctx = ProtocolContexts::new();
+        let counter = Arc::new(Counter::default());
+
+        let mut protocols = ProtocolManager::new();
+        protocols
+            .register(
+                ProtocolType::Lldp,
+                Arc::clone(&counter) as Arc<dyn Protocol>,
+            )
+            .unwrap();
+        assert_eq!(protocols.timers().timers()[0].name, "protocol 0x88cc");
+        for _ in 0..3 {
+            let dev = devices.get(index).unwrap();
+            protocols.dispatch(
+                PROTOCOL_TYPE_LLDP,
+                &Frame::Borrowed(b"x"),
+                dev,
+                &ctx,
+                &devices,
+            );
+        }
+        assert_eq!(*counter.frames.lock().unwrap(), [(index, 3)]);
+
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(protocols.timers().run(&ctx, &devices), 1);
+        assert!(counter.frames.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,721 @@
+//! NAT masquerade (network address and port translation, RFC 3022).
+//!
+//! With [`IpConfig::masquerade`] set to the address of an interface (the WAN
+//! side), packets forwarded out that interface leave with that address as
+//! their source and, for TCP and UDP, a source port from the translation range;
+//! ICMP echo requests get a translated identifier instead. Every translation is
+//! kept in the stack's [`NatTable`], one per inside endpoint and remote
+//! endpoint, and packets from that remote to the translated address and port
+//! are rewritten back and forwarded to the inside host. A translation is
+//! forgotten once it has been idle for its protocol's timeout.
+//!
+//! Other protocols cannot be translated and are dropped rather than forwarded
+//! with an inside source address. ICMP errors about translated packets are
+//! not rewritten.
+//!
+//! Port forwarding (static DNAT) works the other way around: a [`PortForward`]
+//! rule sends TCP or UDP packets for one of the router's addresses and ports to
+//! an inside host. The first packet from a remote creates a translation, which
+//! then rewrites the inside host's replies to come from the external address
+//! and port, whether or not masquerading is on.
+//!
+//! [`IpConfig::masquerade`]: crate::context::IpConfig::masquerade
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::context::ProtocolContexts;
+use crate::device::DeviceManager;
+use crate::error::{NetError, Result};
+use crate::limits::StackLimits;
+use crate::platform::Instant;
+use crate::protocol::ProtocolManager;
+use crate::protocol::icmp::IcmpType;
+use crate::protocol::ip::{self, IP_ADDR_LEN, IpAddr, IpEndpoint, IpProtocol};
+use crate::util::cksum16;
+
+/// Translations the table holds by default
+pub const NAT_TABLE_SIZE: usize = 256;
+
+/// Translated ports, below the stack's own ephemeral ports so that replies to
+/// the router itself are never mistaken for translated traffic
+const NAT_PORT_MIN: u16 = 40000;
+const NAT_PORT_MAX: u16 = 49151;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_RST: u8 = 0x04;
+
+/// How long a translation may sit idle before it is forgotten
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatTimeouts {
+    /// TCP connections (RFC 5382 asks for at least 2 hours 4 minutes)
+    pub tcp: Duration,
+    /// TCP connections after a FIN or RST was seen
+    pub tcp_closing: Duration,
+    /// UDP flows (RFC 4787 recommends 5 minutes)
+    pub udp: Duration,
+    /// ICMP echo queries (RFC 5508 asks for at least 60 seconds)
+    pub icmp: Duration,
+}
+
+impl Default for NatTimeouts {
+    fn default() -> Self {
+        Self {
+            tcp: Duration::from_secs(2 * 60 * 60 + 4 * 60),
+            tcp_closing: Duration::from_secs(4 * 60),
+            udp: Duration::from_secs(5 * 60),
+            icmp: Duration::from_secs(60),
+        }
+    }
+}
+
+impl NatTimeouts {
+    fn of(&self, entry: &NatEntry) -> Duration {
+        match entry.protocol {
+            IpProtocol::Tcp if entry.closing => self.tcp_closing,
+            IpProtocol::Tcp => self.tcp,
+            IpProtocol::Udp => self.udp,
+            _ => self.icmp,
+        }
+    }
+}
+
+/// One translation as the table reports it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatMapping {
+    pub protocol: IpProtocol,
+    /// Source of the inside host (the echo identifier as port for ICMP)
+    pub inside: IpEndpoint,
+    /// The source the inside host appears as on the WAN side
+    pub outside: IpEndpoint,
+    /// The host the inside host talks to (port 0 for ICMP)
+    pub remote: IpEndpoint,
+    /// Time since a packet last used the translation
+    pub idle: Duration,
+}
+
+/// A static port forwarding rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortForward {
+    /// TCP or UDP
+    pub protocol: IpProtocol,
+    /// Address and port of the router that packets are sent to
+    pub external: IpEndpoint,
+    /// Inside host and port the packets are forwarded to
+    pub internal: IpEndpoint,
+}
+
+struct NatEntry {
+    protocol: IpProtocol,
+    inside: IpEndpoint,
+    outside: IpEndpoint,
+    remote: IpEndpoint,
+    last_used: Instant,
+    /// A FIN or RST was seen (TCP only)
+    closing: bool,
+}
+
+struct NatState {
+    entries: Vec<NatEntry>,
+    forwards: Vec<PortForward>,
+    next_port: u16,
+    timeouts: NatTimeouts,
+}
+
+impl NatState {
+    fn expire(&mut self, now: Instant) -> usize {
+        let before = self.entries.len();
+        let timeouts = self.timeouts;
+        self.entries
+            .retain(|entry| now.duration_since(entry.last_used) < timeouts.of(entry));
+        before - self.entries.len()
+    }
+
+    /// A port of `protocol` on `addr` that no translation or port forwarding
+    /// rule uses
+    fn free_port(&mut self, protocol: IpProtocol, addr: IpAddr) -> Option<u16> {
+        let start = self.next_port;
+        let mut port = start;
+        loop {
+            let next = if port == NAT_PORT_MAX {
+                NAT_PORT_MIN
+            } else {
+                port + 1
+            };
+            let outside = IpEndpoint::new(addr, port);
+            let used = self
+                .entries
+                .iter()
+                .any(|entry| entry.protocol == protocol && entry.outside == outside)
+                || self
+                    .forwards
+                    .iter()
+                    .any(|rule| rule.protocol == protocol && rule.external == outside);
+            if !used {
+                self.next_port = next;
+                return Some(port);
+            }
+            if next == start {
+                break;
+            }
+            port = next;
+        }
+        None
+    }
+}
+
+/// Translations and port forwarding rules of a NAT router. Expired
+/// translations are forgotten as the table is used, or with
+/// [`expire`](Self::expire).
+pub struct NatTable {
+    state: Mutex<NatState>,
+    limit: usize,
+    clock: Clock,
+}
+
+impl NatTable {
+    pub fn new() -> Self {
+        Self::with_limit(StackLimits::default().nat_entries)
+    }
+
+    /// Table of at most `limit` translations
+    pub fn with_limit(limit: usize) -> Self {
+        Self::with_clock(limit, Clock::default())
+    }
+
+    /// Like [`with_limit`](Self::with_limit), with translations idling by
+    /// `clock`
+    pub fn with_clock(limit: usize, clock: Clock) -> Self {
+        Self {
+            state: Mutex::new(NatState {
+                entries: Vec::new(),
+                forwards: Vec::new(),
+                next_port: NAT_PORT_MIN,
+                timeouts: NatTimeouts::default(),
+            }),
+            limit,
+            clock,
+        }
+    }
+
+    pub fn timeouts(&self) -> NatTimeouts {
+        self.state.lock().unwrap().timeouts
+    }
+
+    pub fn set_timeouts(&self, timeouts: NatTimeouts) {
+        self.state.lock().unwrap().timeouts = timeouts;
+    }
+
+    /// Every live translation, ordered by protocol and outside port
+    pub fn mappings(&self) -> Vec<NatMapping> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        state.expire(now);
+        let mut mappings: Vec<_> = state
+            .entries
+            .iter()
+            .map(|entry| NatMapping {
+                protocol: entry.protocol,
+                inside: entry.inside,
+                outside: entry.outside,
+                remote: entry.remote,
+                idle: now.duration_since(entry.last_used),
+            })
+            .collect();
+        mappings.sort_by_key(|mapping| (mapping.protocol.to_u8(), mapping.outside.port));
+        mappings
+    }
+
+    /// Forward `rule.protocol` packets for `rule.external` to `rule.internal`
+    pub fn add_forward(&self, rule: PortForward) -> Result<()> {
+        if !matches!(rule.protocol, IpProtocol::Tcp | IpProtocol::Udp) {
+            return Err(NetError::InvalidInput(
+                "only TCP and UDP ports can be forwarded",
+            ));
+        }
+        let mut state = self.state.lock().unwrap();
+        if state
+            .forwards
+            .iter()
+            .any(|other| other.protocol == rule.protocol && other.external == rule.external)
+        {
+            return Err(NetError::AlreadyExists(format!(
+                "forward of {:?} {}",
+                rule.protocol, rule.external
+            )));
+        }
+        state.forwards.push(rule);
+        Ok(())
+    }
+
+    /// Remove the rule for `external`, along with the translations it made
+    pub fn remove_forward(&self, protocol: IpProtocol, external: IpEndpoint) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(pos) = state
+            .forwards
+            .iter()
+            .position(|rule| rule.protocol == protocol && rule.external == external)
+        else {
+            return Err(NetError::NotFound(format!(
+                "forward of {:?} {}",
+                protocol, external
+            )));
+        };
+        state.forwards.remove(pos);
+        state
+            .entries
+            .retain(|entry| entry.protocol != protocol || entry.outside != external);
+        Ok(())
+    }
+
+    pub fn forwards(&self) -> Vec<PortForward> {
+        self.state.lock().unwrap().forwards.clone()
+    }
+
+    /// Whether a port forwarding rule has `addr` as its external address
+    pub(crate) fn forwards_for(&self, addr: IpAddr) -> bool {
+        let state = self.state.lock().unwrap();
+        state.forwards.iter().any(|rule| rule.external.addr == addr)
+    }
+
+    /// Forget idle translations; returns how many
+    pub fn expire(&self) -> usize {
+        self.state.lock().unwrap().expire(self.clock.now())
+    }
+
+    pub fn clear(&self) {
+        self.state.lock().unwrap().entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Outside endpoint for a packet from `inside` to `remote`. Without a
+    /// translation for them one is made to `masquerade` if given; `None` if
+    /// there is none to use or the table is full or out of ports.
+    fn outbound(
+        &self,
+        protocol: IpProtocol,
+        inside: IpEndpoint,
+        remote: IpEndpoint,
+        masquerade: Option<IpAddr>,
+        closing: bool,
+    ) -> Option<IpEndpoint> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.entries.iter_mut().find(|entry| {
+            entry.protocol == protocol && entry.inside == inside && entry.remote == remote
+        }) {
+            entry.last_used = now;
+            entry.closing |= closing;
+            return Some(entry.outside);
+        }
+
+        let addr = masquerade?;
+        let outside = IpEndpoint::new(addr, state.free_port(protocol, addr)?);
+        self.insert(&mut state, protocol, inside, outside, remote, closing)
+            .then_some(outside)
+    }
+
+    /// Inside endpoint of the translation a packet from `remote` to `outside`
+    /// belongs to, made from a port forwarding rule if there is none yet
+    fn inbound(
+        &self,
+        protocol: IpProtocol,
+        outside: IpEndpoint,
+        remote: IpEndpoint,
+        closing: bool,
+    ) -> Option<IpEndpoint> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        state.expire(now);
+        if let Some(entry) = state.entries.iter_mut().find(|entry| {
+            entry.protocol == protocol && entry.outside == outside && entry.remote == remote
+        }) {
+            entry.last_used = now;
+            entry.closing |= closing;
+            return Some(entry.inside);
+        }
+
+        let rule = *state
+            .forwards
+            .iter()
+            .find(|rule| rule.protocol == protocol && rule.external == outside)?;
+        self.insert(
+            &mut state,
+            protocol,
+            rule.internal,
+            outside,
+            remote,
+            closing,
+        )
+        .then_some(rule.internal)
+    }
+
+    /// Add a translation; false if the table is full
+    fn insert(
+        &self,
+        state: &mut NatState,
+        protocol: IpProtocol,
+        inside: IpEndpoint,
+        outside: IpEndpoint,
+        remote: IpEndpoint,
+        closing: bool,
+    ) -> bool {
+        let now = self.clock.now();
+        if state.entries.len() >= self.limit && state.expire(now) == 0 {
+            return false;
+        }
+        tracing::debug!(
+            "nat: {:?} {} => {} (with {})",
+            protocol,
+            inside,
+            outside,
+            remote
+        );
+        state.entries.push(NatEntry {
+            protocol,
+            inside,
+            outside,
+            remote,
+            last_used: now,
+            closing,
+        });
+        true
+    }
+}
+
+impl Default for NatTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How often [`timer`] forgets idle translations
+pub const NAT_TIMER_INTERVAL: Duration = Duration::from_secs(10);
+
+pub fn init(protocols: &mut ProtocolManager) -> Result<()> {
+    protocols.register_timer("nat", NAT_TIMER_INTERVAL, Arc::new(timer))
+}
+
+/// Forget idle translations, so they do not hold the table until the next
+/// packet is translated
+pub fn timer(ctx: &ProtocolContexts, _devices: &DeviceManager) {
+    let expired = ctx.nat.expire();
+    if expired > 0 {
+        tracing::debug!("nat: {} translations expired", expired);
+    }
+}
+
+/// What translation rewrites in a transport header
+struct Transport {
+    protocol: IpProtocol,
+    /// Offset of the port (or echo identifier) on the inside host's side
+    inside_port: usize,
+    /// Offset of the remote's port, if there is one
+    remote_port: Option<usize>,
+    /// Offset of the checksum
+    sum: usize,
+    closing: bool,
+}
+
+/// How to translate `payload` of `protocol`; `outbound` tells which side the
+/// inside host is on. `None` if it cannot be translated.
+fn transport(protocol: IpProtocol, payload: &[u8], outbound: bool) -> Option<Transport> {
+    let (local, remote) = if outbound { (0, 2) } else { (2, 0) };
+    match protocol {
+        IpProtocol::Tcp if payload.len() >= 20 => Some(Transport {
+            protocol,
+            inside_port: local,
+            remote_port: Some(remote),
+            sum: 16,
+            closing: payload[13] & (TCP_FLAG_FIN | TCP_FLAG_RST) != 0,
+        }),
+        IpProtocol::Udp if payload.len() >= 8 => Some(Transport {
+            protocol,
+            inside_port: local,
+            remote_port: Some(remote),
+            sum: 6,
+            closing: false,
+        }),
+        IpProtocol::Icmp if payload.len() >= 8 => {
+            let query = if outbound {
+                IcmpType::Echo
+            } else {
+                IcmpType::EchoReply
+            };
+            (payload[0] == query as u8).then_some(Transport {
+                protocol,
+                inside_port: 4,
+                remote_port: None,
+                sum: 2,
+                closing: false,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn be16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn addr_at(packet: &[u8], offset: usize) -> IpAddr {
+    let mut octets = [0u8; IP_ADDR_LEN];
+    octets.copy_from_slice(&packet[offset..offset + IP_ADDR_LEN]);
+    IpAddr::from_ne_bytes(octets)
+}
+
+/// Recompute the transport checksum of a packet whose addresses or ports changed
+fn fix_checksum(packet: &mut [u8], hlen: usize, t: &Transport) {
+    let src = addr_at(packet, 12);
+    let dst = addr_at(packet, 16);
+    let payload = &mut packet[hlen..];
+    // A UDP datagram sent without a checksum stays without one
+    if t.protocol == IpProtocol::Udp && be16(payload, t.sum) == 0 {
+        return;
+    }
+    payload[t.sum..t.sum + 2].fill(0);
+    let init = match t.protocol {
+        IpProtocol::Icmp => 0,
+        protocol => ip::pseudo_sum(src, dst, protocol, payload.len()),
+    };
+    let sum = match cksum16(payload, init) {
+        0 if t.protocol == IpProtocol::Udp => 0xffff,
+        sum => sum,
+    };
+    payload[t.sum..t.sum + 2].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Rewrite the source of a forwarded `packet` (a whole IP packet with an
+/// `hlen` byte header) that belongs to a translation, making one to
+/// `masquerade` if given. Returns false if a packet to masquerade cannot be
+/// translated; others without a translation are left alone. The caller
+/// recomputes the IP header checksum.
+pub(crate) fn translate_outbound(
+    packet: &mut [u8],
+    hlen: usize,
+    masquerade: Option<IpAddr>,
+    ctx: &ProtocolContexts,
+) -> bool {
+    let protocol = IpProtocol::from_u8(packet[9]);
+    let Some(t) = transport(protocol, &packet[hlen..], true) else {
+        return masquerade.is_none();
+    };
+    let payload = &packet[hlen..];
+    let inside = IpEndpoint::new(addr_at(packet, 12), be16(payload, t.inside_port));
+    let remote = IpEndpoint::new(
+        addr_at(packet, 16),
+        t.remote_port.map_or(0, |offset| be16(payload, offset)),
+    );
+    let Some(outside) = ctx
+        .nat
+        .outbound(protocol, inside, remote, masquerade, t.closing)
+    else {
+        return masquerade.is_none();
+    };
+
+    packet[12..16].copy_from_slice(&outside.addr.to_ne_bytes());
+    let offset = hlen + t.inside_port;
+    packet[offset..offset + 2].copy_from_slice(&outside.port.to_be_bytes());
+    fix_checksum(packet, hlen, &t);
+    true
+}
+
+/// Rewrite the destination of a `packet` coming back to a translated address
+/// to the inside host; returns the inside host, or `None` if the packet
+/// belongs to no translation. The caller recomputes the IP header checksum.
+pub(crate) fn translate_inbound(
+    packet: &mut [u8],
+    hlen: usize,
+    ctx: &ProtocolContexts,
+) -> Option<IpAddr> {
+    let protocol = IpProtocol::from_u8(packet[9]);
+    let t = transport(protocol, &packet[hlen..], false)?;
+    let payload = &packet[hlen..];
+    let outside = IpEndpoint::new(addr_at(packet, 16), be16(payload, t.inside_port));
+    let remote = IpEndpoint::new(
+        addr_at(packet, 12),
+        t.remote_port.map_or(0, |offset| be16(payload, offset)),
+    );
+    let inside = ctx.nat.inbound(protocol, outside, remote, t.closing)?;
+
+    packet[16..20].copy_from_slice(&inside.addr.to_ne_bytes());
+    let offset = hlen + t.inside_port;
+    packet[offset..offset + 2].copy_from_slice(&inside.port.to_be_bytes());
+    fix_checksum(packet, hlen, &t);
+    Some(inside.addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Read, Write};
+    use std::str::FromStr;
+
+    use crate::clock::VirtualClock;
+    use crate::drop::DropReason;
+    use crate::net::{TcpListener, TcpStream, UdpSocket};
+    use crate::topology::Topology;
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_nat_port_forward() {
+        let topo = Topology::builder()
+            .host("h1")
+            .router("r1")
+            .host("h2")
+            .link(("h1", "192.168.0.2/24"), ("r1", "192.168.0.1/24"))
+            .link(("r1", "203.0.113.1/24"), ("h2", "203.0.113.2/24"))
+            .gateway("h1", "192.168.0.1")
+            .build()
+            .unwrap();
+        let (h1, h2) = (topo.node("h1").unwrap(), topo.node("h2").unwrap());
+        let r1 = topo.node("r1").unwrap();
+        let external = IpEndpoint::new(addr("203.0.113.1"), 8080);
+        for protocol in [IpProtocol::Tcp, IpProtocol::Udp] {
+            let rule = PortForward {
+                protocol,
+                external,
+                internal: IpEndpoint::new(addr("192.168.0.2"), 80),
+            };
+            r1.ctx().nat.add_forward(rule).unwrap();
+            assert!(r1.ctx().nat.add_forward(rule).is_err());
+        }
+        let icmp = PortForward {
+            protocol: IpProtocol::Icmp,
+            external,
+            internal: external,
+        };
+        assert!(r1.ctx().nat.add_forward(icmp).is_err());
+
+        let listener = TcpListener::bind(h1, "0.0.0.0:80").unwrap();
+        let mut client = TcpStream::connect(h2, "203.0.113.1:8080").unwrap();
+        let (mut server, peer) = listener.accept().unwrap();
+        assert_eq!(peer.to_string(), client.local_addr().unwrap().to_string());
+        client.write_all(b"GET").unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(server.read(&mut buf).unwrap(), 3);
+        server.write_all(b"OK").unwrap();
+        assert_eq!(client.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"OK");
+
+        let server = UdpSocket::bind(h1, "0.0.0.0:80").unwrap();
+        let client = UdpSocket::bind(h2, "203.0.113.2:0").unwrap();
+        client.send_to(b"query", "203.0.113.1:8080").unwrap();
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"query");
+        server.send_to(b"answer", from).unwrap();
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"answer");
+        assert_eq!(from, external);
+        assert_eq!(r1.ctx().nat.len(), 2);
+
+        r1.ctx()
+            .nat
+            .remove_forward(IpProtocol::Udp, external)
+            .unwrap();
+        assert_eq!(r1.ctx().nat.forwards().len(), 1);
+        assert_eq!(r1.ctx().nat.len(), 1);
+    }
+
+    #[test]
+    fn test_nat_masquerade_udp() {
+        // h2 has no route back to 192.168.0.0/24
+        let topo = Topology::builder()
+            .host("h1")
+            .router("r1")
+            .host("h2")
+            .link(("h1", "192.168.0.2/24"), ("r1", "192.168.0.1/24"))
+            .link(("r1", "203.0.113.1/24"), ("h2", "203.0.113.2/24"))
+            .gateway("h1", "192.168.0.1")
+            .build()
+            .unwrap();
+        let (h1, h2) = (topo.node("h1").unwrap(), topo.node("h2").unwrap());
+        let r1 = topo.node("r1").unwrap();
+        r1.ctx_mut().ip_config.masquerade = Some(addr("203.0.113.1"));
+
+        let server = UdpSocket::bind(h2, "0.0.0.0:7000").unwrap();
+        server.set_nonblocking(true).unwrap();
+        let client = UdpSocket::bind(h1, "192.168.0.2:5000").unwrap();
+        client.set_nonblocking(true).unwrap();
+
+        let mut buf = [0u8; 8];
+        client.send_to(b"ping", "203.0.113.2:7000").unwrap();
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from, IpEndpoint::new(addr("203.0.113.1"), NAT_PORT_MIN));
+
+        server.send_to(b"pong", from).unwrap();
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from.to_string(), "203.0.113.2:7000");
+
+        let mappings = r1.ctx().nat.mappings();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].protocol, IpProtocol::Udp);
+        assert_eq!(mappings[0].inside.to_string(), "192.168.0.2:5000");
+        assert_eq!(mappings[0].remote.to_string(), "203.0.113.2:7000");
+
+        // Only the remote the translation was made for gets through
+        let other = UdpSocket::bind(h2, "0.0.0.0:7001").unwrap();
+        other
+            .send_to(b"spoof", IpEndpoint::new(addr("203.0.113.1"), NAT_PORT_MIN))
+            .unwrap();
+        let err = client.recv_from(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // A full table refuses new translations
+        r1.ctx_mut().nat = NatTable::with_limit(1);
+        client.send_to(b"one", "203.0.113.2:7000").unwrap();
+        client.send_to(b"two", "203.0.113.2:7001").unwrap();
+        assert_eq!(r1.ctx().drops.count(DropReason::Nat), 1);
+
+        // Idle translations expire
+        r1.ctx().nat.set_timeouts(NatTimeouts {
+            udp: Duration::ZERO,
+            ..NatTimeouts::default()
+        });
+        assert_eq!(r1.ctx().nat.expire(), 1);
+        assert!(r1.ctx().nat.is_empty());
+    }
+
+    #[test]
+    fn test_nat_idles_on_stack_clock() {
+        let time = VirtualClock::new();
+        let nat = NatTable::with_clock(4, Clock::new(time.clone()));
+        let inside = IpEndpoint::new(addr("192.168.0.2"), 5000);
+        let remote = IpEndpoint::new(addr("203.0.113.2"), 7000);
+        let outside = nat
+            .outbound(
+                IpProtocol::Udp,
+                inside,
+                remote,
+                Some(addr("203.0.113.1")),
+                false,
+            )
+            .unwrap();
+
+        time.advance(Duration::from_secs(60));
+        assert_eq!(nat.mappings()[0].idle, Duration::from_secs(60));
+        assert_eq!(
+            nat.inbound(IpProtocol::Udp, outside, remote, false),
+            Some(inside)
+        );
+
+        // Used just now, so only the full timeout after it expires it
+        time.advance(NatTimeouts::default().udp - Duration::from_secs(1));
+        assert_eq!(nat.expire(), 0);
+        time.advance(Duration::from_secs(1));
+        assert_eq!(nat.expire(), 1);
+    }
+}
@@ -0,0 +1,1114 @@
+//! Neighbor Discovery for IPv6 (RFC 4861).
+//!
+//! Neighbor Solicitations and Advertisements resolve the link-layer addresses
+//! of neighbors on devices that need them
+//! ([`NET_DEVICE_FLAG_NEED_ARP`](crate::device::NET_DEVICE_FLAG_NEED_ARP)), and
+//! the answers are kept in the stack's [`NeighborCache`]. A Router
+//! Advertisement makes its sender a default router for the advertised lifetime
+//! and caches its link-layer address; its prefixes and other parameters are
+//! parsed but not applied. Router Solicitations are parsed and ignored.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::Level;
+
+use super::PROTOCOL_TYPE_IPV6;
+use crate::clock::Clock;
+use crate::context::ProtocolContexts;
+use crate::device::ether::{ETHER_ADDR_LEN, EtherAddr};
+use crate::device::{Device, DeviceIndex, DeviceManager};
+use crate::drop::DropReason;
+use crate::error::{NetError, Result};
+use crate::iface::Ipv6Iface;
+use crate::limits::StackLimits;
+use crate::platform::Instant;
+use crate::protocol::ip::IpProtocol;
+use crate::protocol::ipv6::{self, IPV6_ADDR_LEN, IPV6_NEXT_HEADER_ICMPV6, Ipv6Addr};
+use crate::snapshot::SnapshotCell;
+use crate::util::{LOG_ICMP_INPUT, LOG_ICMP_OUTPUT, cksum16};
+
+pub const ICMPV6_TYPE_ROUTER_SOLICIT: u8 = 133;
+pub const ICMPV6_TYPE_ROUTER_ADVERT: u8 = 134;
+pub const ICMPV6_TYPE_NEIGHBOR_SOLICIT: u8 = 135;
+pub const ICMPV6_TYPE_NEIGHBOR_ADVERT: u8 = 136;
+
+/// Hop limit of every NDP message; anything else was forwarded by a router
+pub const NDP_HOP_LIMIT: u8 = 255;
+
+const NDP_OPT_SOURCE_LLADDR: u8 = 1;
+const NDP_OPT_TARGET_LLADDR: u8 = 2;
+const NDP_OPT_PREFIX_INFO: u8 = 3;
+const NDP_OPT_MTU: u8 = 5;
+
+const NDP_RA_FLAG_MANAGED: u8 = 0x80;
+const NDP_RA_FLAG_OTHER: u8 = 0x40;
+const NDP_NA_FLAG_ROUTER: u8 = 0x80;
+const NDP_NA_FLAG_SOLICITED: u8 = 0x40;
+const NDP_NA_FLAG_OVERRIDE: u8 = 0x20;
+const NDP_PREFIX_FLAG_ON_LINK: u8 = 0x80;
+const NDP_PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// Neighbors the cache holds by default
+pub const NEIGHBOR_CACHE_SIZE: usize = 64;
+/// How long a neighbor counts as reachable after it was confirmed (`REACHABLE_TIME`)
+const NEIGHBOR_REACHABLE_TIME: Duration = Duration::from_secs(30);
+/// Solicitations for a neighbor are sent at most this often (`RETRANS_TIMER`)
+const NEIGHBOR_RETRANS_TIMER: Duration = Duration::from_secs(1);
+
+/// Prefix Information option of a Router Advertisement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixInfo {
+    pub prefix: Ipv6Addr,
+    pub prefix_len: u8,
+    pub on_link: bool,
+    pub autonomous: bool,
+    /// Seconds; `u32::MAX` is forever
+    pub valid_lifetime: u32,
+    /// Seconds; `u32::MAX` is forever
+    pub preferred_lifetime: u32,
+}
+
+/// Router Advertisement (RFC 4861 Section 4.2)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterAdvert {
+    /// Hop limit hosts should use; 0 leaves it unspecified
+    pub cur_hop_limit: u8,
+    pub managed: bool,
+    pub other_config: bool,
+    /// Seconds the router serves as a default router; 0 if it does not
+    pub router_lifetime: u16,
+    /// Milliseconds; 0 leaves it unspecified
+    pub reachable_time: u32,
+    /// Milliseconds; 0 leaves it unspecified
+    pub retrans_timer: u32,
+    pub source: Option<EtherAddr>,
+    pub mtu: Option<u32>,
+    pub prefixes: Vec<PrefixInfo>,
+}
+
+/// A Neighbor Discovery message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NdpMessage {
+    RouterSolicit {
+        source: Option<EtherAddr>,
+    },
+    RouterAdvert(RouterAdvert),
+    NeighborSolicit {
+        target: Ipv6Addr,
+        source: Option<EtherAddr>,
+    },
+    NeighborAdvert {
+        target: Ipv6Addr,
+        router: bool,
+        solicited: bool,
+        override_: bool,
+        target_hwaddr: Option<EtherAddr>,
+    },
+}
+
+/// Options of a message, as far as they are understood
+#[derive(Default)]
+struct NdpOptions {
+    source: Option<EtherAddr>,
+    target: Option<EtherAddr>,
+    mtu: Option<u32>,
+    prefixes: Vec<PrefixInfo>,
+}
+
+fn be32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn ipv6_addr(data: &[u8]) -> Ipv6Addr {
+    let mut octets = [0u8; IPV6_ADDR_LEN];
+    octets.copy_from_slice(&data[..IPV6_ADDR_LEN]);
+    Ipv6Addr::from_octets(octets)
+}
+
+fn parse_options(mut data: &[u8]) -> Result<NdpOptions> {
+    let mut options = NdpOptions::default();
+    while !data.is_empty() {
+        if data.len() < 2 {
+            return Err(NetError::Truncated { len: data.len() });
+        }
+        let len = data[1] as usize * 8;
+        if len == 0 || len > data.len() {
+            return Err(NetError::Malformed(format!(
+                "NDP option length: type={}, len={}",
+                data[0], len
+            )));
+        }
+        let opt = &data[..len];
+        match opt[0] {
+            NDP_OPT_SOURCE_LLADDR | NDP_OPT_TARGET_LLADDR if len == 8 => {
+                let mut addr = [0u8; ETHER_ADDR_LEN];
+                addr.copy_from_slice(&opt[2..2 + ETHER_ADDR_LEN]);
+                if opt[0] == NDP_OPT_SOURCE_LLADDR {
+                    options.source = Some(EtherAddr(addr));
+                } else {
+                    options.target = Some(EtherAddr(addr));
+                }
+            }
+            NDP_OPT_PREFIX_INFO if len == 32 => options.prefixes.push(PrefixInfo {
+                prefix_len: opt[2],
+                on_link: opt[3] & NDP_PREFIX_FLAG_ON_LINK != 0,
+                autonomous: opt[3] & NDP_PREFIX_FLAG_AUTONOMOUS != 0,
+                valid_lifetime: be32(&opt[4..]),
+                preferred_lifetime: be32(&opt[8..]),
+                prefix: ipv6_addr(&opt[16..]),
+            }),
+            NDP_OPT_MTU if len == 8 => options.mtu = Some(be32(&opt[4..])),
+            // Options for link layers other than Ethernet, or not understood
+            _ => {}
+        }
+        data = &data[len..];
+    }
+    Ok(options)
+}
+
+fn push_lladdr_option(buf: &mut Vec<u8>, type_: u8, addr: Option<EtherAddr>) {
+    if let Some(addr) = addr {
+        buf.extend_from_slice(&[type_, 1]);
+        buf.extend_from_slice(&addr.0);
+    }
+}
+
+impl NdpMessage {
+    /// Parse an ICMPv6 message whose checksum has been verified
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let min = match data.first() {
+            Some(&ICMPV6_TYPE_ROUTER_SOLICIT) => 8,
+            Some(&ICMPV6_TYPE_ROUTER_ADVERT) => 16,
+            Some(&ICMPV6_TYPE_NEIGHBOR_SOLICIT | &ICMPV6_TYPE_NEIGHBOR_ADVERT) => 24,
+            Some(other) => {
+                return Err(NetError::Malformed(format!(
+                    "not an NDP message: type={}",
+                    other
+                )));
+            }
+            None => return Err(NetError::Truncated { len: 0 }),
+        };
+        if data.len() < min {
+            return Err(NetError::Truncated { len: data.len() });
+        }
+        if data[1] != 0 {
+            return Err(NetError::Malformed(format!(
+                "NDP code: type={}, code={}",
+                data[0], data[1]
+            )));
+        }
+        let options = parse_options(&data[min..])?;
+
+        Ok(match data[0] {
+            ICMPV6_TYPE_ROUTER_SOLICIT => NdpMessage::RouterSolicit {
+                source: options.source,
+            },
+            ICMPV6_TYPE_ROUTER_ADVERT => NdpMessage::RouterAdvert(RouterAdvert {
+                cur_hop_limit: data[4],
+                managed: data[5] & NDP_RA_FLAG_MANAGED != 0,
+                other_config: data[5] & NDP_RA_FLAG_OTHER != 0,
+                router_lifetime: u16::from_be_bytes([data[6], data[7]]),
+                reachable_time: be32(&data[8..]),
+                retrans_timer: be32(&data[12..]),
+                source: options.source,
+                mtu: options.mtu,
+                prefixes: options.prefixes,
+            }),
+            ICMPV6_TYPE_NEIGHBOR_SOLICIT => NdpMessage::NeighborSolicit {
+                target: ipv6_addr(&data[8..]),
+                source: options.source,
+            },
+            _ => NdpMessage::NeighborAdvert {
+                target: ipv6_addr(&data[8..]),
+                router: data[4] & NDP_NA_FLAG_ROUTER != 0,
+                solicited: data[4] & NDP_NA_FLAG_SOLICITED != 0,
+                override_: data[4] & NDP_NA_FLAG_OVERRIDE != 0,
+                target_hwaddr: options.target,
+            },
+        })
+    }
+
+    /// The message on the wire, with a zero checksum
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32);
+        match self {
+            NdpMessage::RouterSolicit { source } => {
+                buf.extend_from_slice(&[ICMPV6_TYPE_ROUTER_SOLICIT, 0, 0, 0, 0, 0, 0, 0]);
+                push_lladdr_option(&mut buf, NDP_OPT_SOURCE_LLADDR, *source);
+            }
+            NdpMessage::RouterAdvert(ra) => {
+                let mut flags = 0;
+                if ra.managed {
+                    flags |= NDP_RA_FLAG_MANAGED;
+                }
+                if ra.other_config {
+                    flags |= NDP_RA_FLAG_OTHER;
+                }
+                buf.extend_from_slice(&[ICMPV6_TYPE_ROUTER_ADVERT, 0, 0, 0]);
+                buf.extend_from_slice(&[ra.cur_hop_limit, flags]);
+                buf.extend_from_slice(&ra.router_lifetime.to_be_bytes());
+                buf.extend_from_slice(&ra.reachable_time.to_be_bytes());
+                buf.extend_from_slice(&ra.retrans_timer.to_be_bytes());
+                push_lladdr_option(&mut buf, NDP_OPT_SOURCE_LLADDR, ra.source);
+                if let Some(mtu) = ra.mtu {
+                    buf.extend_from_slice(&[NDP_OPT_MTU, 1, 0, 0]);
+                    buf.extend_from_slice(&mtu.to_be_bytes());
+                }
+                for prefix in &ra.prefixes {
+                    let mut flags = 0;
+                    if prefix.on_link {
+                        flags |= NDP_PREFIX_FLAG_ON_LINK;
+                    }
+                    if prefix.autonomous {
+                        flags |= NDP_PREFIX_FLAG_AUTONOMOUS;
+                    }
+                    buf.extend_from_slice(&[NDP_OPT_PREFIX_INFO, 4, prefix.prefix_len, flags]);
+                    buf.extend_from_slice(&prefix.valid_lifetime.to_be_bytes());
+                    buf.extend_from_slice(&prefix.preferred_lifetime.to_be_bytes());
+                    buf.extend_from_slice(&[0; 4]);
+                    buf.extend_from_slice(&prefix.prefix.octets());
+                }
+            }
+            NdpMessage::NeighborSolicit { target, source } => {
+                buf.extend_from_slice(&[ICMPV6_TYPE_NEIGHBOR_SOLICIT, 0, 0, 0, 0, 0, 0, 0]);
+                buf.extend_from_slice(&target.octets());
+                push_lladdr_option(&mut buf, NDP_OPT_SOURCE_LLADDR, *source);
+            }
+            NdpMessage::NeighborAdvert {
+                target,
+                router,
+                solicited,
+                override_,
+                target_hwaddr,
+            } => {
+                let mut flags = 0;
+                if *router {
+                    flags |= NDP_NA_FLAG_ROUTER;
+                }
+                if *solicited {
+                    flags |= NDP_NA_FLAG_SOLICITED;
+                }
+                if *override_ {
+                    flags |= NDP_NA_FLAG_OVERRIDE;
+                }
+                buf.extend_from_slice(&[ICMPV6_TYPE_NEIGHBOR_ADVERT, 0, 0, 0, flags, 0, 0, 0]);
+                buf.extend_from_slice(&target.octets());
+                push_lladdr_option(&mut buf, NDP_OPT_TARGET_LLADDR, *target_hwaddr);
+            }
+        }
+        buf
+    }
+}
+
+/// Reachability of a neighbor (a subset of RFC 4861 Section 7.3.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NeighborState {
+    /// Solicited, no answer yet
+    Incomplete,
+    /// Confirmed within the last `REACHABLE_TIME`
+    Reachable,
+    /// Link-layer address known but not recently confirmed; still used
+    Stale,
+}
+
+/// A neighbor cache entry, as reported by [`NeighborCache::entries`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Neighbor {
+    pub addr: Ipv6Addr,
+    pub hwaddr: Option<EtherAddr>,
+    pub state: NeighborState,
+    pub router: bool,
+    pub device: DeviceIndex,
+}
+
+struct NeighborEntry {
+    hwaddr: Option<EtherAddr>,
+    device: DeviceIndex,
+    router: bool,
+    /// When reachability was last confirmed by a solicited advertisement
+    confirmed: Option<Instant>,
+    /// When a solicitation was last sent
+    solicited: Option<Instant>,
+    /// Latest packet waiting for the link-layer address
+    pending: Option<Vec<u8>>,
+    /// Tick of the last use, shared with the published snapshot so lookups
+    /// can mark the entry without the lock
+    used: Arc<AtomicU64>,
+    /// The entry's key in `Neighbors::lru`; `used` may have moved past it
+    listed: u64,
+}
+
+impl NeighborEntry {
+    fn new(device: DeviceIndex) -> Self {
+        Self {
+            hwaddr: None,
+            device,
+            router: false,
+            confirmed: None,
+            solicited: None,
+            pending: None,
+            used: Arc::new(AtomicU64::new(0)),
+            listed: 0,
+        }
+    }
+
+    fn state(&self, now: Instant) -> NeighborState {
+        match (self.hwaddr, self.confirmed) {
+            (None, _) => NeighborState::Incomplete,
+            (Some(_), Some(at)) if now.duration_since(at) < NEIGHBOR_REACHABLE_TIME => {
+                NeighborState::Reachable
+            }
+            _ => NeighborState::Stale,
+        }
+    }
+}
+
+/// A resolved neighbor as the transmit path sees it
+#[derive(Clone)]
+struct Resolved {
+    hwaddr: EtherAddr,
+    used: Arc<AtomicU64>,
+}
+
+/// Cache entries and the order they were last used in
+#[derive(Default)]
+struct Neighbors {
+    map: HashMap<Ipv6Addr, NeighborEntry>,
+    /// Addresses by the tick they were listed at, oldest first. Lookups only
+    /// move `used`, so an entry may have been used later than its key says.
+    lru: BTreeMap<(u64, [u8; IPV6_ADDR_LEN]), Ipv6Addr>,
+    /// A resolved entry changed since the snapshot was last published
+    changed: bool,
+}
+
+impl Neighbors {
+    fn get(&self, addr: Ipv6Addr) -> Option<&NeighborEntry> {
+        self.map.get(&addr)
+    }
+
+    /// Entry for `addr`, marked as used at `tick`
+    fn touch(&mut self, addr: Ipv6Addr, tick: u64) -> Option<&mut NeighborEntry> {
+        let entry = self.map.get_mut(&addr)?;
+        self.lru.remove(&(entry.listed, addr.octets()));
+        entry.used.store(tick, Ordering::Relaxed);
+        entry.listed = tick;
+        self.lru.insert((tick, addr.octets()), addr);
+        Some(entry)
+    }
+
+    /// Remove the least recently used entry. Entries used since they were
+    /// listed are listed again under their last use on the way.
+    fn evict(&mut self) {
+        while let Some(((listed, _), addr)) = self.lru.pop_first() {
+            let entry = self.map.get_mut(&addr).expect("listed entries exist");
+            let used = entry.used.load(Ordering::Relaxed);
+            if used > listed {
+                entry.listed = used;
+                self.lru.insert((used, addr.octets()), addr);
+                continue;
+            }
+            self.remove(addr);
+            return;
+        }
+    }
+
+    /// Entry for `addr`, created (evicting the least recently used if full)
+    /// if there is none, and marked as used at `tick`
+    fn entry(
+        &mut self,
+        limit: usize,
+        addr: Ipv6Addr,
+        device: DeviceIndex,
+        tick: u64,
+    ) -> &mut NeighborEntry {
+        if !self.map.contains_key(&addr) {
+            if self.map.len() >= limit {
+                self.evict();
+            }
+            self.map.insert(addr, NeighborEntry::new(device));
+        }
+        let entry = self.touch(addr, tick).expect("the entry was just inserted");
+        entry.device = device;
+        entry
+    }
+
+    fn remove(&mut self, addr: Ipv6Addr) -> bool {
+        let Some(entry) = self.map.remove(&addr) else {
+            return false;
+        };
+        self.lru.remove(&(entry.listed, addr.octets()));
+        self.changed |= entry.hwaddr.is_some();
+        true
+    }
+}
+
+/// Link-layer addresses of IPv6 neighbors (RFC 4861 Section 5.1).
+///
+/// Entries are hashed by address. Every lookup on the transmit path and
+/// every update marks its entry as used; when the cache is full, the least
+/// recently used neighbor is evicted.
+///
+/// The transmit path reads the resolved neighbors from a
+/// [`SnapshotCell`], republished when one is added, changed or removed, so
+/// lookups take no lock. A lookup marks its neighbor with the current
+/// epoch, which only updates move on, so neighbors looked up between two
+/// updates count as used at the same time. The mark is written only when the
+/// epoch has moved since the neighbor's last lookup.
+pub struct NeighborCache {
+    entries: Mutex<Neighbors>,
+    resolved: SnapshotCell<HashMap<Ipv6Addr, Resolved>>,
+    /// Tick lookups mark neighbors with. Updates take the tick after it and
+    /// move it on by two, so a lookup is always later than the updates before
+    /// it and earlier than the ones after.
+    epoch: AtomicU64,
+    limit: usize,
+    clock: Clock,
+}
+
+impl NeighborCache {
+    pub fn new() -> Self {
+        Self::with_limit(StackLimits::default().neighbors)
+    }
+
+    /// Cache holding at most `limit` neighbors
+    pub fn with_limit(limit: usize) -> Self {
+        Self::with_clock(limit, Clock::default())
+    }
+
+    /// Like [`with_limit`](Self::with_limit), with entries aging by `clock`
+    pub fn with_clock(limit: usize, clock: Clock) -> Self {
+        Self {
+            entries: Mutex::new(Neighbors::default()),
+            resolved: SnapshotCell::default(),
+            epoch: AtomicU64::new(0),
+            limit,
+            clock,
+        }
+    }
+
+    /// Tick for an update, taken while `entries` is locked
+    fn tick(&self) -> u64 {
+        self.epoch.fetch_add(2, Ordering::Relaxed) + 1
+    }
+
+    /// Republish the resolved neighbors if any changed
+    fn publish(&self, entries: &mut Neighbors) {
+        if !std::mem::take(&mut entries.changed) {
+            return;
+        }
+        let resolved = entries
+            .map
+            .iter()
+            .filter_map(|(&addr, entry)| {
+                let used = Arc::clone(&entry.used);
+                entry.hwaddr.map(|hwaddr| (addr, Resolved { hwaddr, used }))
+            })
+            .collect();
+        self.resolved.store(resolved);
+    }
+
+    /// Link-layer address of `addr`, if known; marks the neighbor as used.
+    /// Takes no lock.
+    pub fn lookup(&self, addr: Ipv6Addr) -> Option<EtherAddr> {
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        self.resolved.read(|resolved| {
+            let neighbor = resolved.get(&addr)?;
+            if neighbor.used.load(Ordering::Relaxed) < epoch {
+                neighbor.used.store(epoch, Ordering::Relaxed);
+            }
+            Some(neighbor.hwaddr)
+        })
+    }
+
+    pub fn get(&self, addr: Ipv6Addr) -> Option<Neighbor> {
+        let now = self.clock.now();
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(addr)?;
+        Some(Neighbor {
+            addr,
+            hwaddr: entry.hwaddr,
+            state: entry.state(now),
+            router: entry.router,
+            device: entry.device,
+        })
+    }
+
+    /// Every neighbor, ordered by address
+    pub fn entries(&self) -> Vec<Neighbor> {
+        let now = self.clock.now();
+        let entries = self.entries.lock().unwrap();
+        let mut neighbors: Vec<_> = entries
+            .map
+            .iter()
+            .map(|(&addr, entry)| Neighbor {
+                addr,
+                hwaddr: entry.hwaddr,
+                state: entry.state(now),
+                router: entry.router,
+                device: entry.device,
+            })
+            .collect();
+        neighbors.sort_by_key(|neighbor| neighbor.addr.octets());
+        neighbors
+    }
+
+    /// Add a neighbor whose link-layer address is known (a static entry)
+    pub fn insert(&self, addr: Ipv6Addr, hwaddr: EtherAddr, device: DeviceIndex) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(self.limit, addr, device, self.tick());
+        entry.hwaddr = Some(hwaddr);
+        entries.changed = true;
+        self.publish(&mut entries);
+    }
+
+    pub fn remove(&self, addr: Ipv6Addr) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let removed = entries.remove(addr);
+        self.publish(&mut entries);
+        removed
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        *entries = Neighbors {
+            changed: true,
+            ..Neighbors::default()
+        };
+        self.publish(&mut entries);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Hold `packet` until `addr` is resolved; returns whether a solicitation
+    /// should be sent now
+    fn solicit(&self, addr: Ipv6Addr, device: DeviceIndex, packet: &[u8]) -> bool {
+        if self.limit == 0 {
+            return false;
+        }
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(self.limit, addr, device, self.tick());
+        entry.pending = Some(packet.to_vec());
+        let due = entry
+            .solicited
+            .is_none_or(|at| now.duration_since(at) >= NEIGHBOR_RETRANS_TIMER);
+        if due {
+            entry.solicited = Some(now);
+        }
+        self.publish(&mut entries);
+        due
+    }
+
+    /// Learn the link-layer address of `addr` from a solicitation or a router
+    /// advertisement; returns the packet that was waiting for it
+    fn learn(
+        &self,
+        addr: Ipv6Addr,
+        hwaddr: EtherAddr,
+        device: DeviceIndex,
+        router: bool,
+    ) -> Option<Vec<u8>> {
+        if self.limit == 0 {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(self.limit, addr, device, self.tick());
+        let changed = entry.hwaddr != Some(hwaddr);
+        if changed {
+            entry.hwaddr = Some(hwaddr);
+            entry.confirmed = None;
+        }
+        entry.router |= router;
+        let pending = entry.pending.take();
+        entries.changed |= changed;
+        self.publish(&mut entries);
+        pending
+    }
+
+    /// Apply a Neighbor Advertisement for `addr` (RFC 4861 Section 7.2.5);
+    /// returns the link-layer address and the packet that was waiting for it.
+    /// Advertisements for neighbors not in the cache are ignored.
+    fn advertised(
+        &self,
+        addr: Ipv6Addr,
+        hwaddr: Option<EtherAddr>,
+        router: bool,
+        solicited: bool,
+        override_: bool,
+    ) -> Option<(EtherAddr, Option<Vec<u8>>)> {
+        let now = self.clock.now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.map.get_mut(&addr)?;
+        let before = entry.hwaddr;
+        match (entry.hwaddr, hwaddr) {
+            (None, None) => return None,
+            (None, Some(new)) => entry.hwaddr = Some(new),
+            (Some(old), Some(new)) if old != new => {
+                if !override_ {
+                    return None;
+                }
+                entry.hwaddr = Some(new);
+            }
+            _ => {}
+        }
+        if solicited {
+            entry.confirmed = Some(now);
+        }
+        entry.router = router;
+        entries.changed |= entry.hwaddr != before;
+        let entry = entries.touch(addr, self.tick())?;
+        let answer = (entry.hwaddr?, entry.pending.take());
+        self.publish(&mut entries);
+        Some(answer)
+    }
+}
+
+impl Default for NeighborCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Link-layer address an IPv6 multicast address maps to (RFC 2464 Section 7)
+pub fn multicast_hwaddr(addr: Ipv6Addr) -> EtherAddr {
+    let octets = addr.octets();
+    EtherAddr([0x33, 0x33, octets[12], octets[13], octets[14], octets[15]])
+}
+
+/// Link-layer address of the neighbor `target` on the link of `iface`. If it
+/// is not known yet, `packet` waits for it in the cache, a Neighbor
+/// Solicitation is sent, and `None` is returned.
+pub(crate) fn resolve(
+    iface: &Ipv6Iface,
+    target: Ipv6Addr,
+    packet: &[u8],
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<Option<EtherAddr>> {
+    if let Some(hwaddr) = ctx.neighbors.lookup(target) {
+        return Ok(Some(hwaddr));
+    }
+    if ctx.neighbors.solicit(target, iface.device_index, packet) {
+        let source = devices
+            .get(iface.device_index)
+            .and_then(|dev| dev.hw_addr());
+        let solicit = NdpMessage::NeighborSolicit { target, source };
+        output(
+            &solicit,
+            iface.unicast,
+            target.solicited_node(),
+            ctx,
+            devices,
+        )?;
+    }
+    Ok(None)
+}
+
+/// Send `message` from `src` to `dst`
+pub fn output(
+    message: &NdpMessage,
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<isize> {
+    let protocol = IpProtocol::Other(IPV6_NEXT_HEADER_ICMPV6);
+    let mut data = message.to_bytes();
+    let sum = cksum16(&data, ipv6::pseudo_sum(src, dst, protocol, data.len()));
+    data[2..4].copy_from_slice(&sum.to_be_bytes());
+    if LOG_ICMP_OUTPUT.allow(Level::INFO) {
+        tracing::debug!("ndp_output: {} => {}, {:?}", src, dst, message);
+    }
+    ipv6::ipv6_output_hop_limit(protocol, &data, src, dst, Some(NDP_HOP_LIMIT), ctx, devices)
+}
+
+/// Send a packet that was waiting for `hwaddr` on `dev`
+fn flush(dev: &Device, hwaddr: EtherAddr, packet: Option<Vec<u8>>) {
+    if let Some(packet) = packet
+        && let Err(e) = dev.output(PROTOCOL_TYPE_IPV6, &packet, Some(&hwaddr.0))
+    {
+        tracing::error!("ndp: failed to send pending packet: {}", e);
+    }
+}
+
+/// Handle an ICMPv6 message. Only Neighbor Discovery is understood; other
+/// types are dropped as unknown.
+pub fn input(
+    data: &[u8],
+    src: Ipv6Addr,
+    dst: Ipv6Addr,
+    hop_limit: u8,
+    dev: &Device,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) {
+    let protocol = IpProtocol::Other(IPV6_NEXT_HEADER_ICMPV6);
+    if data.len() < 4 {
+        ctx.drops.drop(DropReason::Malformed, data);
+        tracing::error!("icmpv6_input: too short, len={}", data.len());
+        return;
+    }
+    if cksum16(data, ipv6::pseudo_sum(src, dst, protocol, data.len())) != 0
+        && ctx.drops.drop(DropReason::IcmpChecksum, data)
+    {
+        tracing::error!("icmpv6_input: checksum error");
+        return;
+    }
+    if !(ICMPV6_TYPE_ROUTER_SOLICIT..=ICMPV6_TYPE_NEIGHBOR_ADVERT).contains(&data[0]) {
+        ctx.drops.drop(DropReason::UnknownProtocol, data);
+        tracing::debug!("icmpv6_input: unsupported type {}", data[0]);
+        return;
+    }
+    if hop_limit != NDP_HOP_LIMIT {
+        ctx.drops.drop(DropReason::Malformed, data);
+        tracing::debug!(
+            "ndp_input: hop limit {} from {}, not a neighbor",
+            hop_limit,
+            src
+        );
+        return;
+    }
+    let message = match NdpMessage::parse(data) {
+        Ok(message) => message,
+        Err(e) => {
+            ctx.drops.drop(DropReason::Malformed, data);
+            tracing::debug!("ndp_input: {}", e);
+            return;
+        }
+    };
+    if LOG_ICMP_INPUT.allow(Level::INFO) {
+        tracing::debug!("ndp_input: {} => {}, {:?}", src, dst, message);
+    }
+
+    match message {
+        NdpMessage::NeighborSolicit { target, source } => {
+            let Some(iface) = dev.ipv6_ifaces().find(|iface| iface.unicast == target) else {
+                tracing::debug!("ndp_input: solicitation for {}, not ours", target);
+                return;
+            };
+            // An unspecified source probes for duplicates; the answer goes to everyone
+            let reply_to = if src.is_unspecified() {
+                Ipv6Addr::ALL_NODES
+            } else {
+                if let Some(hwaddr) = source {
+                    let pending = ctx.neighbors.learn(src, hwaddr, dev.index, false);
+                    flush(dev, hwaddr, pending);
+                }
+                src
+            };
+            let advert = NdpMessage::NeighborAdvert {
+                target,
+                router: false,
+                solicited: !src.is_unspecified(),
+                override_: true,
+                target_hwaddr: dev.hw_addr(),
+            };
+            if let Err(e) = output(&advert, iface.unicast, reply_to, ctx, devices) {
+                tracing::error!("ndp: failed to send advertisement: {}", e);
+            }
+        }
+        NdpMessage::NeighborAdvert {
+            target,
+            router,
+            solicited,
+            override_,
+            target_hwaddr,
+        } => {
+            if let Some((hwaddr, pending)) =
+                ctx.neighbors
+                    .advertised(target, target_hwaddr, router, solicited, override_)
+            {
+                flush(dev, hwaddr, pending);
+            }
+        }
+        NdpMessage::RouterAdvert(advert) => {
+            if !src.is_link_local() {
+                ctx.drops.drop(DropReason::Malformed, data);
+                tracing::debug!(
+                    "ndp_input: router advertisement from {}, not link-local",
+                    src
+                );
+                return;
+            }
+            if let Some(hwaddr) = advert.source {
+                let pending = ctx.neighbors.learn(src, hwaddr, dev.index, true);
+                flush(dev, hwaddr, pending);
+            }
+            // Routers advertise from their link-local address; so should we reach them
+            let Some(iface) = dev
+                .ipv6_ifaces()
+                .find(|iface| iface.unicast.is_link_local())
+                .or_else(|| dev.get_ipv6_iface())
+            else {
+                return;
+            };
+            if advert.router_lifetime == 0 {
+                ctx.ipv6_routes.remove_default_router(src);
+            } else if let Err(e) = ctx.ipv6_routes.add_default_router(
+                src,
+                iface.clone(),
+                Some(Duration::from_secs(advert.router_lifetime.into())),
+            ) {
+                tracing::debug!("ndp_input: {}", e);
+            }
+        }
+        // Only routers answer solicitations
+        NdpMessage::RouterSolicit { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::device::NET_DEVICE_FLAG_NEED_ARP;
+    use crate::device::memory::MemoryQueue;
+    use crate::protocol::ipv6::{IPV6_HDR_SIZE, IPV6_NEXT_HEADER_NONE, Ipv6Hdr};
+    use crate::stack::NetStack;
+
+    fn addr(s: &str) -> Ipv6Addr {
+        Ipv6Addr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_ndp_message_roundtrip() {
+        let hwaddr = EtherAddr::from_seed("router");
+        let messages = [
+            NdpMessage::RouterSolicit {
+                source: Some(hwaddr),
+            },
+            NdpMessage::RouterAdvert(RouterAdvert {
+                cur_hop_limit: 64,
+                managed: false,
+                other_config: true,
+                router_lifetime: 1800,
+                reachable_time: 0,
+                retrans_timer: 0,
+                source: Some(hwaddr),
+                mtu: Some(1500),
+                prefixes: vec![PrefixInfo {
+                    prefix: addr("2001:db8::"),
+                    prefix_len: 64,
+                    on_link: true,
+                    autonomous: true,
+                    valid_lifetime: u32::MAX,
+                    preferred_lifetime: 3600,
+                }],
+            }),
+            NdpMessage::NeighborSolicit {
+                target: addr("2001:db8::1"),
+                source: None,
+            },
+            NdpMessage::NeighborAdvert {
+                target: addr("2001:db8::1"),
+                router: true,
+                solicited: true,
+                override_: false,
+                target_hwaddr: Some(hwaddr),
+            },
+        ];
+        for message in messages {
+            assert_eq!(NdpMessage::parse(&message.to_bytes()).unwrap(), message);
+        }
+
+        let mut bad = NdpMessage::RouterSolicit {
+            source: Some(hwaddr),
+        }
+        .to_bytes();
+        bad[9] = 0;
+        assert!(NdpMessage::parse(&bad).is_err());
+        assert!(NdpMessage::parse(&[128, 0, 0, 0]).is_err());
+        assert_eq!(
+            multicast_hwaddr(addr("2001:db8::1").solicited_node()).to_string(),
+            "33:33:ff:00:00:01"
+        );
+    }
+
+    /// `message` in an IPv6 packet with a valid checksum
+    fn packet(message: &NdpMessage, src: Ipv6Addr, dst: Ipv6Addr, hop_limit: u8) -> Vec<u8> {
+        let protocol = IpProtocol::Other(IPV6_NEXT_HEADER_ICMPV6);
+        let mut body = message.to_bytes();
+        let sum = cksum16(&body, ipv6::pseudo_sum(src, dst, protocol, body.len()));
+        body[2..4].copy_from_slice(&sum.to_be_bytes());
+        let hdr = Ipv6Hdr::new(protocol, body.len() as u16, hop_limit, src, dst);
+        let mut packet = hdr.to_bytes().to_vec();
+        packet.extend_from_slice(&body);
+        packet
+    }
+
+    fn host(name: &str, cidr: &str) -> (NetStack, DeviceIndex, MemoryQueue) {
+        let stack = NetStack::new().unwrap();
+        let (index, tx) = stack.add_memory(EtherAddr::from_seed(name)).unwrap();
+        stack.devices_mut().get_mut(index).unwrap().flags |= NET_DEVICE_FLAG_NEED_ARP;
+        stack.register_ipv6_iface(index, cidr).unwrap();
+        stack.run().unwrap();
+        (stack, index, tx)
+    }
+
+    #[test]
+    fn test_ndp_resolution() {
+        let (a, a_index, a_tx) = host("a", "2001:db8::1/64");
+        let (b, b_index, b_tx) = host("b", "2001:db8::2/64");
+        let pass = |tx: &MemoryQueue, to: &NetStack, index| {
+            let frames: Vec<_> = tx.lock().unwrap().drain(..).collect();
+            for frame in &frames {
+                to.inject(index, frame.type_, &frame.data).unwrap();
+            }
+            frames
+        };
+
+        let send = || {
+            ipv6::ipv6_output(
+                IpProtocol::Other(IPV6_NEXT_HEADER_NONE),
+                b"",
+                Ipv6Addr::UNSPECIFIED,
+                addr("2001:db8::2"),
+                &a.ctx(),
+                &a.devices(),
+            )
+            .unwrap()
+        };
+        send();
+        // The packet waits while a solicitation goes out; another one does not
+        // solicit again so soon
+        send();
+        let entry = a.ctx().neighbors.get(addr("2001:db8::2")).unwrap();
+        assert_eq!(entry.state, NeighborState::Incomplete);
+        let frames = pass(&a_tx, &b, b_index);
+        assert_eq!(frames.len(), 1);
+        let hdr = Ipv6Hdr::from_bytes(&frames[0].data).unwrap();
+        assert_eq!(hdr.dst(), addr("ff02::1:ff00:2"));
+        assert_eq!(hdr.hop_limit(), NDP_HOP_LIMIT);
+        assert_eq!(frames[0].data[IPV6_HDR_SIZE], ICMPV6_TYPE_NEIGHBOR_SOLICIT);
+
+        // b learned a from the solicitation and advertises itself
+        let entry = b.ctx().neighbors.get(addr("2001:db8::1")).unwrap();
+        assert_eq!(entry.hwaddr, Some(EtherAddr::from_seed("a")));
+        assert_eq!(entry.state, NeighborState::Stale);
+        let frames = pass(&b_tx, &a, a_index);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data[IPV6_HDR_SIZE], ICMPV6_TYPE_NEIGHBOR_ADVERT);
+
+        let entry = a.ctx().neighbors.get(addr("2001:db8::2")).unwrap();
+        assert_eq!(entry.hwaddr, Some(EtherAddr::from_seed("b")));
+        assert_eq!(entry.state, NeighborState::Reachable);
+        // The held packet went out once the address was known
+        let frames: Vec<_> = a_tx.lock().unwrap().drain(..).collect();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data[6], IPV6_NEXT_HEADER_NONE);
+        send();
+        assert_eq!(a_tx.lock().unwrap().len(), 1);
+        assert_eq!(b.ctx().drops.total(), 0);
+
+        // NDP from beyond the link is refused
+        let solicit = NdpMessage::NeighborSolicit {
+            target: addr("2001:db8::2"),
+            source: None,
+        };
+        let forwarded = packet(&solicit, addr("2001:db8::1"), addr("2001:db8::2"), 64);
+        b.inject(b_index, PROTOCOL_TYPE_IPV6, &forwarded).unwrap();
+        assert_eq!(b.ctx().drops.count(DropReason::Malformed), 1);
+        assert!(b_tx.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_neighbor_cache_limit() {
+        let cache = NeighborCache::with_limit(2);
+        let hwaddr = EtherAddr::from_seed("n");
+        cache.insert(addr("fe80::1"), hwaddr, DeviceIndex(0));
+        cache.insert(addr("fe80::2"), hwaddr, DeviceIndex(0));
+        cache.insert(addr("fe80::3"), hwaddr, DeviceIndex(0));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.lookup(addr("fe80::3")), Some(hwaddr));
+        assert_eq!(
+            cache.get(addr("fe80::3")).unwrap().state,
+            NeighborState::Stale
+        );
+        assert!(cache.remove(addr("fe80::3")));
+        assert_eq!(cache.entries().len(), 1);
+    }
+
+    #[test]
+    fn test_neighbor_cache_evicts_least_recently_used() {
+        let cache = NeighborCache::with_limit(3);
+        let hwaddr = EtherAddr::from_seed("n");
+        for n in 1..=3 {
+            cache.insert(addr(&format!("fe80::{}", n)), hwaddr, DeviceIndex(0));
+        }
+        // A transmit to fe80::1 keeps it, so fe80::2 goes first
+        assert_eq!(cache.lookup(addr("fe80::1")), Some(hwaddr));
+        cache.insert(addr("fe80::4"), hwaddr, DeviceIndex(0));
+        assert!(cache.get(addr("fe80::2")).is_none());
+        // Reading an entry for display does not count as use
+        assert!(cache.get(addr("fe80::3")).is_some());
+        cache.insert(addr("fe80::5"), hwaddr, DeviceIndex(0));
+        assert!(cache.get(addr("fe80::3")).is_none());
+
+        let left: Vec<_> = cache.entries().into_iter().map(|n| n.addr).collect();
+        assert_eq!(left, [addr("fe80::1"), addr("fe80::4"), addr("fe80::5")]);
+        assert!(cache.remove(addr("fe80::1")));
+        cache.insert(addr("fe80::6"), hwaddr, DeviceIndex(0));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_router_advert_default_router() {
+        let (a, a_index, a_tx) = host("a", "2001:db8::1/64");
+        let router = addr("fe80::1");
+        let advert = |lifetime| {
+            NdpMessage::RouterAdvert(RouterAdvert {
+                cur_hop_limit: 64,
+                managed: false,
+                other_config: false,
+                router_lifetime: lifetime,
+                reachable_time: 0,
+                retrans_timer: 0,
+                source: Some(EtherAddr::from_seed("router")),
+                mtu: None,
+                prefixes: Vec::new(),
+            })
+        };
+        let send = || {
+            ipv6::ipv6_output(
+                IpProtocol::Other(IPV6_NEXT_HEADER_NONE),
+                b"",
+                Ipv6Addr::UNSPECIFIED,
+                addr("2001:db9::5"),
+                &a.ctx(),
+                &a.devices(),
+            )
+        };
+        assert!(send().is_err());
+
+        let ra = packet(&advert(1800), router, Ipv6Addr::ALL_NODES, NDP_HOP_LIMIT);
+        a.inject(a_index, PROTOCOL_TYPE_IPV6, &ra).unwrap();
+        let routers = a.ctx().ipv6_routes.default_routers();
+        assert_eq!(routers.len(), 1);
+        assert_eq!(routers[0].addr, router);
+        assert!(a.ctx().neighbors.get(router).unwrap().router);
+
+        // Off-link traffic goes to the router, whose address is already known
+        send().unwrap();
+        let frames: Vec<_> = a_tx.lock().unwrap().drain(..).collect();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(
+            Ipv6Hdr::from_bytes(&frames[0].data).unwrap().dst(),
+            addr("2001:db9::5")
+        );
+
+        let ra = packet(&advert(0), router, Ipv6Addr::ALL_NODES, NDP_HOP_LIMIT);
+        a.inject(a_index, PROTOCOL_TYPE_IPV6, &ra).unwrap();
+        assert!(a.ctx().ipv6_routes.default_routers().is_empty());
+        assert!(send().is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//! Raw IP sockets.
+//!
+//! A raw control block is opened for one IP protocol number and receives a copy
+//! of the payload of every IP packet of that protocol addressed to this host,
+//! after the IP header has been checked. Protocols the stack implements itself
+//! (ICMP, TCP, UDP) are still handled as usual; for any other protocol the raw
+//! control blocks are the only receivers. Payloads sent through a raw control
+//! block go out via [`ip::ip_output`] as they are, so protocols such as OSPF can
+//! be implemented in user code.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Condvar, Mutex};
+use std::task::Waker;
+use std::time::Duration;
+
+use crate::bytes::{Bytes, Frame};
+use crate::context::ProtocolContexts;
+use crate::device::DeviceManager;
+use crate::drop::DropReason;
+use crate::error::{NetError, Result};
+use crate::limits::StackLimits;
+use crate::platform::Instant;
+use crate::protocol::ip::{self, IpAddr, IpProtocol};
+use crate::protocol::{MSG_DONTWAIT, POLLIN, POLLOUT, READINESS, wait_until};
+
+/// Raw control blocks a stack holds by default
+pub(crate) const RAW_PCB_SIZE: usize = 8;
+
+/// Handle of an open raw control block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RawPcbId(u32);
+
+impl fmt::Display for RawPcbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// An IP payload waiting in a raw control block's receive queue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawPacket {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// A slice of the received frame, or a copy (see [`crate::bytes`])
+    pub data: Bytes,
+}
+
+/// Result of [`RawPcbTable::recv_timeout`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawRecv {
+    /// Bytes copied into the buffer
+    pub len: usize,
+    pub src: IpAddr,
+    /// The payload was longer than the buffer and the excess was discarded
+    pub truncated: bool,
+}
+
+struct RawPcb {
+    protocol: IpProtocol,
+    /// Only packets to this address are received; any if unspecified
+    local: IpAddr,
+    queue: VecDeque<RawPacket>,
+}
+
+struct PcbState {
+    pcbs: HashMap<RawPcbId, RawPcb>,
+    next_id: u32,
+    /// Control blocks open at most
+    limit: usize,
+    /// Packets a control block queues at most
+    queue_len: usize,
+}
+
+/// Raw control blocks and their receive queues
+pub struct RawPcbTable {
+    state: Mutex<PcbState>,
+    arrived: Condvar,
+    /// Tasks waiting for a packet, woken with `arrived`
+    wakers: Mutex<Vec<(RawPcbId, Waker)>>,
+}
+
+impl RawPcbTable {
+    pub fn new() -> Self {
+        let limits = StackLimits::default();
+        Self::with_limits(limits.raw_sockets, limits.socket_queue_len)
+    }
+
+    /// Table of at most `limit` control blocks, each queueing at most
+    /// `queue_len` packets
+    pub fn with_limits(limit: usize, queue_len: usize) -> Self {
+        Self {
+            state: Mutex::new(PcbState {
+                pcbs: HashMap::new(),
+                next_id: 0,
+                limit,
+                queue_len,
+            }),
+            arrived: Condvar::new(),
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Allocate a control block receiving packets of `protocol`
+    pub fn open(&self, protocol: IpProtocol) -> Result<RawPcbId> {
+        let mut state = self.state.lock().unwrap();
+        if state.pcbs.len() >= state.limit {
+            return Err(NetError::LimitReached {
+                what: "raw control blocks",
+                limit: state.limit,
+            });
+        }
+        let id = RawPcbId(state.next_id);
+        state.next_id = state.next_id.wrapping_add(1);
+        state.pcbs.insert(
+            id,
+            RawPcb {
+                protocol,
+                local: IpAddr::ANY,
+                queue: VecDeque::new(),
+            },
+        );
+        tracing::debug!("raw_open: id={}, protocol={:?}", id, protocol);
+        Ok(id)
+    }
+
+    /// Release the control block, waking up any blocked receiver
+    pub fn close(&self, id: RawPcbId) -> Result<()> {
+        if self.state.lock().unwrap().pcbs.remove(&id).is_none() {
+            return Err(NetError::NotFound(format!("raw control block {}", id)));
+        }
+        self.notify();
+        tracing::debug!("raw_close: id={}", id);
+        Ok(())
+    }
+
+    /// Receive only packets addressed to `local`, and send from it
+    pub fn bind(&self, id: RawPcbId, local: IpAddr) -> Result<()> {
+        match self.state.lock().unwrap().pcbs.get_mut(&id) {
+            Some(pcb) => {
+                pcb.local = local;
+                Ok(())
+            }
+            None => Err(NetError::NotFound(format!("raw control block {}", id))),
+        }
+    }
+
+    /// Address the control block is bound to
+    pub fn local(&self, id: RawPcbId) -> Option<IpAddr> {
+        self.state
+            .lock()
+            .unwrap()
+            .pcbs
+            .get(&id)
+            .map(|pcb| pcb.local)
+    }
+
+    pub fn protocol(&self, id: RawPcbId) -> Option<IpProtocol> {
+        self.state
+            .lock()
+            .unwrap()
+            .pcbs
+            .get(&id)
+            .map(|pcb| pcb.protocol)
+    }
+
+    /// Block until a packet arrives for the control block and return it
+    pub fn recvfrom(&self, id: RawPcbId) -> Result<RawPacket> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let Some(pcb) = state.pcbs.get_mut(&id) else {
+                return Err(NetError::Closed);
+            };
+            if let Some(packet) = pcb.queue.pop_front() {
+                return Ok(packet);
+            }
+            state = self.arrived.wait(state).unwrap();
+        }
+    }
+
+    /// Wait at most `timeout` (forever if `None`) for a packet and copy its
+    /// payload into `buf`. With [`MSG_DONTWAIT`], fails with [`NetError::WouldBlock`]
+    /// instead of waiting.
+    pub fn recv_timeout(
+        &self,
+        id: RawPcbId,
+        buf: &mut [u8],
+        flags: u32,
+        timeout: Option<Duration>,
+    ) -> Result<RawRecv> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let Some(pcb) = state.pcbs.get_mut(&id) else {
+                return Err(NetError::Closed);
+            };
+            if let Some(packet) = pcb.queue.pop_front() {
+                let len = packet.data.len().min(buf.len());
+                buf[..len].copy_from_slice(&packet.data[..len]);
+                return Ok(RawRecv {
+                    len,
+                    src: packet.src,
+                    truncated: len < packet.data.len(),
+                });
+            }
+            if flags & MSG_DONTWAIT != 0 {
+                return Err(NetError::WouldBlock);
+            }
+            state = match wait_until(&self.arrived, state, deadline) {
+                Some(state) => state,
+                None => {
+                    return Err(NetError::TimedOut);
+                }
+            };
+        }
+    }
+
+    /// Poll events the control block is ready for: always [`POLLOUT`], and
+    /// [`POLLIN`] while packets are queued. `None` once it is closed.
+    pub fn poll_events(&self, id: RawPcbId) -> Option<u16> {
+        let state = self.state.lock().unwrap();
+        let pcb = state.pcbs.get(&id)?;
+        Some(if pcb.queue.is_empty() {
+            POLLOUT
+        } else {
+            POLLIN | POLLOUT
+        })
+    }
+
+    /// Wake `waker` when a packet arrives or a control block is closed; for
+    /// futures that found control block `id` not ready
+    pub fn register_waker(&self, id: RawPcbId, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|(i, w)| *i == id && w.will_wake(waker)) {
+            wakers.push((id, waker.clone()));
+        }
+    }
+
+    /// Number of open control blocks
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().pcbs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wake up blocked receivers, pollers and waiting tasks
+    fn notify(&self) {
+        self.arrived.notify_all();
+        READINESS.notify();
+        for (_, waker) in self.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Queue `data` on every control block of `protocol` bound to
+    /// `dst` or to any address. Returns how many control blocks took it, and
+    /// how many dropped it because their queue was full.
+    fn deliver(
+        &self,
+        protocol: IpProtocol,
+        data: &Frame<'_>,
+        src: IpAddr,
+        dst: IpAddr,
+    ) -> (usize, usize) {
+        let mut state = self.state.lock().unwrap();
+        let queue_len = state.queue_len;
+        let (mut delivered, mut full) = (0, 0);
+        // Kept once, shared by every control block that takes it
+        let mut kept = None;
+        for pcb in state.pcbs.values_mut() {
+            if pcb.protocol != protocol || (pcb.local != IpAddr::ANY && pcb.local != dst) {
+                continue;
+            }
+            if pcb.queue.len() >= queue_len {
+                full += 1;
+                continue;
+            }
+            pcb.queue.push_back(RawPacket {
+                src,
+                dst,
+                data: Bytes::clone(kept.get_or_insert_with(|| data.to_bytes())),
+            });
+            delivered += 1;
+        }
+        if delivered > 0 {
+            self.notify();
+        }
+        (delivered, full)
+    }
+}
+
+impl Default for RawPcbTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hand an IP payload to the raw control blocks of `protocol`, which share
+/// it; returns whether any took it
+pub fn input(
+    protocol: IpProtocol,
+    data: &Frame<'_>,
+    src: IpAddr,
+    dst: IpAddr,
+    ctx: &ProtocolContexts,
+) -> bool {
+    let (delivered, full) = ctx.raw.deliver(protocol, data, src, dst);
+    for _ in 0..full {
+        ctx.drops.drop(DropReason::SocketQueueFull, data);
+    }
+    if delivered > 0 {
+        tracing::debug!(
+            "raw_input: protocol={:?}, {} => {}, len={}, sockets={}",
+            protocol,
+            src,
+            dst,
+            data.len(),
+            delivered
+        );
+    }
+    delivered > 0
+}
+
+/// Send `data` as the payload of an IP packet to `dst`, with the protocol and
+/// source address of control block `id`
+pub fn output(
+    id: RawPcbId,
+    data: &[u8],
+    dst: IpAddr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    let (Some(protocol), Some(local)) = (ctx.raw.protocol(id), ctx.raw.local(id)) else {
+        return Err(NetError::NotFound(format!("raw control block {}", id)));
+    };
+    ip::ip_output(protocol, data, local, dst, ctx, devices)?;
+    Ok(data.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::stack::NetStack;
+
+    #[test]
+    fn test_raw_send_recv() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+        let lo = IpAddr::from_str("127.0.0.1").unwrap();
+        let ospf = IpProtocol::Other(89);
+
+        let rx = ctx.raw.open(ospf).unwrap();
+        let other = ctx.raw.open(IpProtocol::Other(253)).unwrap();
+        let tx = ctx.raw.open(ospf).unwrap();
+        ctx.raw.bind(tx, lo).unwrap();
+        assert_eq!(output(tx, b"hello", lo, &ctx, &devices).unwrap(), 5);
+
+        let packet = ctx.raw.recvfrom(rx).unwrap();
+        assert_eq!(packet.src, lo);
+        assert_eq!(packet.data, b"hello");
+        // The sender is bound to the destination, so it gets the packet too
+        assert_eq!(ctx.raw.recvfrom(tx).unwrap().data, b"hello");
+        let mut buf = [0u8; 4];
+        let err = ctx.raw.recv_timeout(other, &mut buf, MSG_DONTWAIT, None);
+        assert!(matches!(err, Err(NetError::WouldBlock)));
+
+        // Protocols the stack handles itself are delivered as well
+        let icmp = ctx.raw.open(IpProtocol::Icmp).unwrap();
+        ctx.icmp_echo.register(1).unwrap();
+        crate::protocol::icmp::output(
+            crate::protocol::icmp::IcmpType::Echo,
+            0,
+            crate::protocol::icmp::echo_values(1, 1),
+            b"ping",
+            lo,
+            lo,
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let got = ctx.raw.recv_timeout(icmp, &mut buf, 0, None).unwrap();
+        assert!(got.truncated);
+        assert_eq!(ctx.icmp_echo.take(1).len(), 1);
+
+        ctx.raw.close(rx).unwrap();
+        assert!(ctx.raw.recvfrom(rx).is_err());
+    }
+}
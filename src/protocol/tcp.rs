@@ -0,0 +1,4099 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::Waker;
+use std::time::Duration;
+
+use tracing::Level;
+
+use crate::clock::Clock;
+use crate::context::{ConnectionInfo, ProtocolContexts};
+use crate::device::DeviceManager;
+use crate::diagnose::{Conflict, Conflicts};
+use crate::drop::DropReason;
+use crate::error::{NetError, Result};
+use crate::event::NetEvent;
+use crate::limits::StackLimits;
+use crate::packet::PacketBuf;
+use crate::platform::{self, Instant};
+use crate::protocol::ip::{self, IpAddr, IpEndpoint, IpProtocol};
+use crate::protocol::{POLLHUP, POLLIN, POLLOUT, ProtocolManager, READINESS, wait_until};
+use crate::services::print_ports;
+use crate::trace::{TRACE, TraceEvent};
+use crate::util::{LOG_TCP_INPUT, LOG_TCP_OUTPUT, cksum16, header_accessors, wire_codec};
+
+pub const TCP_HDR_SIZE_MIN: usize = 20;
+pub const TCP_HDR_SIZE_MAX: usize = 60;
+
+pub const TCP_FLG_FIN: u8 = 0x01;
+pub const TCP_FLG_SYN: u8 = 0x02;
+pub const TCP_FLG_RST: u8 = 0x04;
+pub const TCP_FLG_PSH: u8 = 0x08;
+pub const TCP_FLG_ACK: u8 = 0x10;
+pub const TCP_FLG_URG: u8 = 0x20;
+
+pub const TCP_OPT_EOL: u8 = 0;
+pub const TCP_OPT_NOP: u8 = 1;
+pub const TCP_OPT_MSS: u8 = 2;
+pub const TCP_OPT_WSCALE: u8 = 3;
+pub const TCP_OPT_SACK_PERMITTED: u8 = 4;
+pub const TCP_OPT_TIMESTAMP: u8 = 8;
+
+/// TCP header without options, in host byte order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpHdr {
+    src: u16,
+    dst: u16,
+    seq: u32,
+    ack: u32,
+    off: u8,
+    flg: u8,
+    wnd: u16,
+    sum: u16,
+    up: u16,
+}
+
+wire_codec!(TcpHdr, TCP_HDR_SIZE_MIN, {
+    src: u16,
+    dst: u16,
+    seq: u32,
+    ack: u32,
+    off: u8,
+    flg: u8,
+    wnd: u16,
+    sum: u16,
+    up: u16,
+});
+
+header_accessors!(TcpHdr, {
+    /// Source port
+    src: u16,
+    /// Destination port
+    dst: u16,
+    /// Sequence number
+    seq: u32,
+    /// Acknowledgment number
+    ack: u32,
+    off: u8,
+    /// Control bits (`TCP_FLG_*`)
+    flg: u8,
+    /// Window
+    wnd: u16,
+    /// Checksum
+    sum: u16,
+    /// Urgent pointer
+    up: u16,
+});
+
+impl TcpHdr {
+    /// Header length including options, in bytes
+    pub fn hdr_len(&self) -> usize {
+        ((self.off() >> 4) as usize) * 4
+    }
+
+    /// Whether all of `flags` are set
+    pub fn has(&self, flags: u8) -> bool {
+        self.flg() & flags == flags
+    }
+}
+
+impl fmt::Display for TcpHdr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "src={}, dst={}, seq={}, ack={}, off={}, flg={}, wnd={}, sum={:#06x}, up={}",
+            self.src(),
+            self.dst(),
+            self.seq(),
+            self.ack(),
+            self.hdr_len(),
+            flags_ntoa(self.flg()),
+            self.wnd(),
+            self.sum(),
+            self.up()
+        )
+    }
+}
+
+/// Render control bits as `--UAPRSF` with unset bits shown as `-`
+pub fn flags_ntoa(flg: u8) -> String {
+    const NAMES: [(u8, char); 6] = [
+        (TCP_FLG_URG, 'U'),
+        (TCP_FLG_ACK, 'A'),
+        (TCP_FLG_PSH, 'P'),
+        (TCP_FLG_RST, 'R'),
+        (TCP_FLG_SYN, 'S'),
+        (TCP_FLG_FIN, 'F'),
+    ];
+    let mut s = String::from("--");
+    for (flag, name) in NAMES {
+        s.push(if flg & flag != 0 { name } else { '-' });
+    }
+    s
+}
+
+/// A TCP option (RFC 9293 Section 3.2, RFC 7323, RFC 2018)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpOption {
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Timestamp { val: u32, ecr: u32 },
+    Unknown { kind: u8, len: u8 },
+}
+
+impl TcpOption {
+    /// Append the encoded option to `buf`
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match *self {
+            TcpOption::Mss(mss) => {
+                buf.extend_from_slice(&[TCP_OPT_MSS, 4]);
+                buf.extend_from_slice(&mss.to_be_bytes());
+            }
+            TcpOption::WindowScale(shift) => {
+                buf.extend_from_slice(&[TCP_OPT_WSCALE, 3, shift]);
+            }
+            TcpOption::SackPermitted => buf.extend_from_slice(&[TCP_OPT_SACK_PERMITTED, 2]),
+            TcpOption::Timestamp { val, ecr } => {
+                buf.extend_from_slice(&[TCP_OPT_TIMESTAMP, 10]);
+                buf.extend_from_slice(&val.to_be_bytes());
+                buf.extend_from_slice(&ecr.to_be_bytes());
+            }
+            TcpOption::Unknown { .. } => {}
+        }
+    }
+}
+
+/// Parse the options area of a TCP header (the bytes after the fixed header)
+pub fn parse_options(data: &[u8]) -> Result<Vec<TcpOption>> {
+    let mut options = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let kind = data[i];
+        match kind {
+            TCP_OPT_EOL => break,
+            TCP_OPT_NOP => {
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+        let Some(&len) = data.get(i + 1) else {
+            return Err(NetError::Truncated { len: data.len() });
+        };
+        let len = len as usize;
+        if len < 2 || i + len > data.len() {
+            return Err(NetError::Malformed(format!(
+                "TCP option length: kind={}, len={}",
+                kind, len
+            )));
+        }
+        let body = &data[i + 2..i + len];
+        let option = match (kind, body.len()) {
+            (TCP_OPT_MSS, 2) => TcpOption::Mss(u16::from_be_bytes([body[0], body[1]])),
+            (TCP_OPT_WSCALE, 1) => TcpOption::WindowScale(body[0]),
+            (TCP_OPT_SACK_PERMITTED, 0) => TcpOption::SackPermitted,
+            (TCP_OPT_TIMESTAMP, 8) => TcpOption::Timestamp {
+                val: u32::from_be_bytes([body[0], body[1], body[2], body[3]]),
+                ecr: u32::from_be_bytes([body[4], body[5], body[6], body[7]]),
+            },
+            (TCP_OPT_MSS | TCP_OPT_WSCALE | TCP_OPT_SACK_PERMITTED | TCP_OPT_TIMESTAMP, _) => {
+                return Err(NetError::Malformed(format!(
+                    "TCP option length: kind={}, len={}",
+                    kind, len
+                )));
+            }
+            _ => TcpOption::Unknown {
+                kind,
+                len: len as u8,
+            },
+        };
+        options.push(option);
+        i += len;
+    }
+    Ok(options)
+}
+
+/// Body of the first option of `kind` in an options area
+fn find_option(data: &[u8], kind: u8) -> Option<&[u8]> {
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            TCP_OPT_EOL => return None,
+            TCP_OPT_NOP => i += 1,
+            k => {
+                let len = *data.get(i + 1)? as usize;
+                if len < 2 || i + len > data.len() {
+                    return None;
+                }
+                if k == kind {
+                    return Some(&data[i + 2..i + len]);
+                }
+                i += len;
+            }
+        }
+    }
+    None
+}
+
+/// What an authentication MAC covers besides the key (RFC 2385 Section 2.0)
+#[derive(Debug, Clone, Copy)]
+pub struct AuthSegment<'a> {
+    pub src: IpEndpoint,
+    pub dst: IpEndpoint,
+    /// Fixed TCP header with the checksum zeroed; options are not covered
+    pub header: &'a [u8],
+    pub data: &'a [u8],
+}
+
+/// Authentication option computed for every segment a connection sends and
+/// checked on every segment it receives, e.g. TCP MD5 (RFC 2385) or a MAC of
+/// the application's choosing.
+///
+/// Segments without the option or with a wrong MAC are dropped without a reply.
+pub trait TcpAuth: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// Option kind on the wire (19 for TCP MD5)
+    fn kind(&self) -> u8;
+    /// Bytes of MAC the option carries (16 for TCP MD5)
+    fn mac_len(&self) -> usize;
+    fn mac(&self, seg: &AuthSegment) -> Vec<u8>;
+    /// Whether `mac` is right for `seg`
+    fn verify(&self, seg: &AuthSegment, mac: &[u8]) -> bool {
+        self.mac(seg) == mac
+    }
+}
+
+impl fmt::Debug for dyn TcpAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Option bytes an authentication option takes in every segment
+fn auth_option_len(auth: &dyn TcpAuth) -> usize {
+    2 + auth.mac_len()
+}
+
+/// Fill in the MAC of the authentication option that ends the options of
+/// `buf`, then redo the checksum
+fn sign(buf: &mut [u8], src: IpEndpoint, dst: IpEndpoint, auth: &dyn TcpAuth) {
+    let hlen = ((buf[12] >> 4) as usize) * 4;
+    let mut header = [0u8; TCP_HDR_SIZE_MIN];
+    header.copy_from_slice(&buf[..TCP_HDR_SIZE_MIN]);
+    header[16..18].fill(0);
+    let mac = auth.mac(&AuthSegment {
+        src,
+        dst,
+        header: &header,
+        data: &buf[hlen..],
+    });
+    let Some(options) = buf.get(TCP_HDR_SIZE_MIN..hlen) else {
+        return;
+    };
+    let Some(at) = options
+        .windows(2)
+        .rposition(|w| w == [auth.kind(), auth_option_len(auth) as u8])
+    else {
+        return;
+    };
+    let at = TCP_HDR_SIZE_MIN + at + 2;
+    let len = mac.len().min(auth.mac_len());
+    buf[at..at + len].copy_from_slice(&mac[..len]);
+    buf[16..18].fill(0);
+    let sum = cksum16(
+        buf,
+        ip::pseudo_sum(src.addr, dst.addr, IpProtocol::Tcp, buf.len()),
+    );
+    buf[16..18].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// Whether the raw segment `data` from `src` carries a valid authentication option
+fn verify_auth(data: &[u8], src: IpEndpoint, dst: IpEndpoint, auth: &dyn TcpAuth) -> bool {
+    let Some(hdr) = TcpHdr::from_bytes(data) else {
+        return false;
+    };
+    let hlen = hdr.hdr_len();
+    let Some(mac) = find_option(&data[TCP_HDR_SIZE_MIN..hlen], auth.kind()) else {
+        return false;
+    };
+    let mut header = [0u8; TCP_HDR_SIZE_MIN];
+    header.copy_from_slice(&data[..TCP_HDR_SIZE_MIN]);
+    header[16..18].fill(0);
+    let seg = AuthSegment {
+        src,
+        dst,
+        header: &header,
+        data: &data[hlen..],
+    };
+    mac.len() == auth.mac_len() && auth.verify(&seg, mac)
+}
+
+fn tcp_print(data: &[u8]) {
+    let Some(hdr) = TcpHdr::from_bytes(data) else {
+        return;
+    };
+    tracing::debug!("TCP Header: {}", hdr);
+    print_ports(hdr.src(), hdr.dst(), IpProtocol::Tcp);
+    if let Some(options) = data.get(TCP_HDR_SIZE_MIN..hdr.hdr_len()) {
+        match parse_options(options) {
+            Ok(options) if !options.is_empty() => tracing::debug!("    opt: {:?}", options),
+            Ok(_) => {}
+            Err(e) => tracing::debug!("    opt: {}", e),
+        }
+    }
+}
+
+/// Fields of an outgoing segment
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpSegment<'a> {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub wnd: u16,
+    /// Encoded options; padded to a multiple of 4 bytes with EOL
+    pub options: &'a [u8],
+    pub data: &'a [u8],
+}
+
+/// Encode `seg` from `src` to `dst` with its checksum filled in
+pub fn build(src: IpEndpoint, dst: IpEndpoint, seg: &TcpSegment) -> Result<PacketBuf> {
+    let opt_len = seg.options.len().next_multiple_of(4);
+    let hlen = TCP_HDR_SIZE_MIN + opt_len;
+    if hlen > TCP_HDR_SIZE_MAX {
+        return Err(NetError::InvalidInput("too many TCP options"));
+    }
+    let len = hlen + seg.data.len();
+    let mut buf = PacketBuf::from(seg.data);
+    let hdr = buf.push(hlen);
+    let fixed = TcpHdr {
+        src: src.port,
+        dst: dst.port,
+        seq: seg.seq,
+        ack: seg.ack,
+        off: ((hlen / 4) as u8) << 4,
+        flg: seg.flags,
+        wnd: seg.wnd,
+        sum: 0,
+        up: 0,
+    };
+    hdr[..TCP_HDR_SIZE_MIN].copy_from_slice(&fixed.to_bytes());
+    let options = &mut hdr[TCP_HDR_SIZE_MIN..];
+    options[..seg.options.len()].copy_from_slice(seg.options);
+    options[seg.options.len()..].fill(TCP_OPT_EOL);
+
+    let sum = cksum16(
+        &buf,
+        ip::pseudo_sum(src.addr, dst.addr, IpProtocol::Tcp, len),
+    );
+    buf[16..18].copy_from_slice(&sum.to_be_bytes());
+    Ok(buf)
+}
+
+pub fn input(
+    data: &[u8],
+    src: IpAddr,
+    dst: IpAddr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) {
+    let Some(hdr) = TcpHdr::from_bytes(data) else {
+        ctx.drops.drop(DropReason::Malformed, data);
+        tracing::error!("tcp_input: too short, len={}", data.len());
+        return;
+    };
+    let hlen = hdr.hdr_len();
+    if hlen < TCP_HDR_SIZE_MIN || data.len() < hlen {
+        ctx.drops.drop(DropReason::Malformed, data);
+        tracing::error!("tcp_input: header length error, hlen={}", hlen);
+        return;
+    }
+    if cksum16(data, ip::pseudo_sum(src, dst, IpProtocol::Tcp, data.len())) != 0
+        && ctx.drops.drop(DropReason::TcpChecksum, data)
+    {
+        tracing::error!("tcp_input: checksum error");
+        return;
+    }
+    if src == IpAddr::BROADCAST || dst == IpAddr::BROADCAST {
+        ctx.drops.drop(DropReason::NotForUs, data);
+        tracing::error!("tcp_input: only supports unicast, src={}, dst={}", src, dst);
+        return;
+    }
+
+    if LOG_TCP_INPUT.allow(Level::DEBUG) {
+        tracing::debug!(
+            "{}:{} => {}:{}, len={} (payload={})",
+            src,
+            hdr.src(),
+            dst,
+            hdr.dst(),
+            data.len(),
+            data.len() - hlen
+        );
+        tcp_print(data);
+    }
+
+    let local = IpEndpoint::new(dst, hdr.dst());
+    let foreign = IpEndpoint::new(src, hdr.src());
+    let options = parse_options(&data[TCP_HDR_SIZE_MIN..hlen]).unwrap_or_default();
+    let seg = SegmentInfo::new(data, &hdr, &options, &data[hlen..]);
+
+    // Replies are sent after the table lock is released: on loopback they are
+    // delivered (and re-enter tcp::input) synchronously.
+    let replies = match ctx.tcp.segment_arrives(local, foreign, &seg) {
+        Ok(replies) => replies,
+        Err(reason) => {
+            ctx.drops.drop(reason, data);
+            match reason {
+                // Closed port: refuse the connection rather than leave the peer retrying
+                DropReason::NoSocket => Outgoing::reset(local, foreign, &seg).into_iter().collect(),
+                // Full backlog: the peer retries the SYN later
+                _ => Vec::new(),
+            }
+        }
+    };
+    for reply in replies {
+        if let Err(e) = reply.send(ctx, devices) {
+            tracing::error!("tcp_input: failed to send segment: {}", e);
+        }
+    }
+}
+
+/// Build a segment and hand it to IP
+pub fn output(
+    src: IpEndpoint,
+    dst: IpEndpoint,
+    seg: &TcpSegment,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    let buf = build(src, dst, seg)?;
+    transmit(src, dst, buf, ctx, devices)?;
+    Ok(seg.data.len())
+}
+
+/// Hand an encoded segment to IP
+fn transmit(
+    src: IpEndpoint,
+    dst: IpEndpoint,
+    buf: PacketBuf,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    if LOG_TCP_OUTPUT.allow(Level::DEBUG) {
+        let hlen = TcpHdr::from_bytes(&buf).map_or(0, |hdr| hdr.hdr_len());
+        tracing::debug!(
+            "{} => {}, len={} (payload={})",
+            src,
+            dst,
+            buf.len(),
+            buf.len().saturating_sub(hlen)
+        );
+        tcp_print(&buf);
+    }
+
+    ip::ip_output_buf(IpProtocol::Tcp, buf, src.addr, dst.addr, ctx, devices)?;
+    Ok(())
+}
+
+/// Active open: connect to `foreign`, optionally from `local`.
+///
+/// Sends a SYN and waits for the handshake to complete. The SYN is
+/// retransmitted with exponential backoff (starting at 1 second) until
+/// `timeout` expires, at which point the control block is released.
+pub fn connect(
+    local: Option<IpEndpoint>,
+    foreign: IpEndpoint,
+    timeout: Duration,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<TcpPcbId> {
+    connect_auth(local, foreign, timeout, None, ctx, devices)
+}
+
+/// Like [`connect`], signing every segment (the SYN included) with `auth`
+pub fn connect_auth(
+    local: Option<IpEndpoint>,
+    foreign: IpEndpoint,
+    timeout: Duration,
+    auth: Option<Arc<dyn TcpAuth>>,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<TcpPcbId> {
+    let local = connect_source(local, foreign, ctx)?;
+    let (id, syn) = ctx.tcp.connect_start(local, foreign, auth, false)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut rto = TCP_SYN_RTO_INITIAL;
+    loop {
+        if let Err(e) = syn.send(ctx, devices) {
+            tracing::debug!("tcp_connect: id={}, failed to send SYN: {}", id, e);
+        }
+        let retry_at = (Instant::now() + rto).min(deadline);
+        match ctx.tcp.wait_state_change(id, TcpState::SynSent, retry_at) {
+            Some(TcpState::SynSent) if Instant::now() < deadline => {
+                ctx.tcp.count_retransmission(id);
+                rto = (rto * 2).min(TCP_SYN_RTO_MAX);
+                tracing::debug!("tcp_connect: id={}, retransmit SYN, rto={:?}", id, rto);
+            }
+            Some(TcpState::SynSent) => {
+                let _ = ctx.tcp.release(id);
+                return Err(NetError::TimedOut);
+            }
+            Some(TcpState::SynReceived) => {
+                // Simultaneous open: wait for the ACK of our SYN
+                match ctx
+                    .tcp
+                    .wait_state_change(id, TcpState::SynReceived, deadline)
+                {
+                    Some(TcpState::Established) => return Ok(id),
+                    _ => {
+                        let _ = ctx.tcp.release(id);
+                        return Err(NetError::TimedOut);
+                    }
+                }
+            }
+            Some(TcpState::Established) => return Ok(id),
+            _ => {
+                let _ = ctx.tcp.release(id);
+                tracing::debug!("tcp_connect: id={}, refused by {}", id, foreign);
+                return Err(NetError::ConnectionRefused);
+            }
+        }
+    }
+}
+
+/// Start an active open without waiting for it: the SYN is sent, the
+/// handshake completes in the background and the retransmission timer resends
+/// the SYN. Watch [`TcpPcbTable::state`] to learn how it went.
+pub fn connect_nonblocking(
+    local: Option<IpEndpoint>,
+    foreign: IpEndpoint,
+    auth: Option<Arc<dyn TcpAuth>>,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<TcpPcbId> {
+    let local = connect_source(local, foreign, ctx)?;
+    let (id, syn) = ctx.tcp.connect_start(local, foreign, auth, true)?;
+    if let Err(e) = syn.send(ctx, devices) {
+        // Left on the retransmission queue
+        tracing::debug!("tcp_connect: id={}, failed to send SYN: {}", id, e);
+    }
+    Ok(id)
+}
+
+/// Local endpoint of an active open, with the address of the route to
+/// `foreign` filled in
+fn connect_source(
+    local: Option<IpEndpoint>,
+    foreign: IpEndpoint,
+    ctx: &ProtocolContexts,
+) -> Result<IpEndpoint> {
+    let mut local = local.unwrap_or_default();
+    if local.addr == IpAddr::ANY {
+        local.addr = ctx
+            .ip_routes
+            .lookup(foreign.addr)
+            .map(|route| route.iface.unicast)
+            .ok_or(NetError::NoRoute(foreign.addr.into()))?;
+    }
+    Ok(local)
+}
+
+/// Send `data` on an established connection.
+///
+/// Transmits as much as the peer's advertised window and the congestion window
+/// allow and returns the number of bytes taken, which may be less than
+/// `data.len()`. Blocks only while the window is completely full.
+pub fn send(
+    id: TcpPcbId,
+    data: &[u8],
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    send_timeout(id, data, None, ctx, devices)
+}
+
+/// Like [`send`], failing with [`NetError::WouldBlock`] instead of waiting for the window
+pub fn try_send(
+    id: TcpPcbId,
+    data: &[u8],
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    send_timeout(id, data, Some(Duration::ZERO), ctx, devices)
+}
+
+/// Like [`send`], waiting at most `timeout` (forever if `None`) for the window
+/// and failing with a timeout error after that; `Duration::ZERO` is [`try_send`]
+pub fn send_timeout(
+    id: TcpPcbId,
+    data: &[u8],
+    timeout: Option<Duration>,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    let mut total = 0;
+    loop {
+        // Only wait while nothing has been sent yet
+        let timeout = if total == 0 {
+            timeout
+        } else {
+            Some(Duration::ZERO)
+        };
+        let (len, segments) = ctx.tcp.send_start(id, &data[total..], timeout)?;
+        if len == 0 && total == 0 && !data.is_empty() {
+            return Err(NetError::WouldBlock);
+        }
+        for seg in segments {
+            if let Err(e) = seg.send(ctx, devices) {
+                // Left on the retransmission queue
+                tracing::debug!("tcp_send: id={}, failed to send segment: {}", id, e);
+            }
+        }
+        total += len;
+        // ACKs delivered synchronously (loopback) may already have opened the window
+        if len == 0 || total == data.len() {
+            return Ok(total);
+        }
+    }
+}
+
+/// Block until data is received and copy it into `buf`.
+///
+/// Returns 0 once the peer has closed its side (or the connection was reset)
+/// and all received data has been read. Reading opens the receive window; the peer is told
+/// when it grows back from less than one segment.
+pub fn receive(
+    id: TcpPcbId,
+    buf: &mut [u8],
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    receive_timeout(id, buf, None, ctx, devices)
+}
+
+/// Like [`receive`], failing with [`NetError::WouldBlock`] instead of waiting for data
+pub fn try_receive(
+    id: TcpPcbId,
+    buf: &mut [u8],
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    receive_timeout(id, buf, Some(Duration::ZERO), ctx, devices)
+}
+
+/// Like [`receive`], waiting at most `timeout` (forever if `None`) and failing
+/// with a timeout error after that; `Duration::ZERO` is [`try_receive`]
+pub fn receive_timeout(
+    id: TcpPcbId,
+    buf: &mut [u8],
+    timeout: Option<Duration>,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    let (len, update) = ctx.tcp.receive_start(id, buf, timeout)?;
+    if let Some(update) = update
+        && let Err(e) = update.send(ctx, devices)
+    {
+        tracing::debug!(
+            "tcp_receive: id={}, failed to send window update: {}",
+            id,
+            e
+        );
+    }
+    Ok(len)
+}
+
+/// Close the connection gracefully.
+///
+/// Sends a FIN and returns without waiting; the control block goes away on
+/// its own once the peer has closed too (after TIME-WAIT when we closed first).
+/// Received data can still be read until the peer's FIN arrives.
+/// A connection that was [`shutdown`] before is released right away when it
+/// has finished closing.
+pub fn close(id: TcpPcbId, ctx: &ProtocolContexts, devices: &DeviceManager) -> Result<()> {
+    if let Some(fin) = ctx.tcp.close_start(id)?
+        && let Err(e) = fin.send(ctx, devices)
+    {
+        // Left on the retransmission queue
+        tracing::debug!("tcp_close: id={}, failed to send FIN: {}", id, e);
+    }
+    Ok(())
+}
+
+/// Which directions of a connection [`shutdown`] ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// Stop reading: [`receive`] returns end of stream and arriving data is discarded
+    Read,
+    /// Stop sending: a FIN goes out, while data from the peer can still be read
+    Write,
+    Both,
+}
+
+/// Shut down one or both directions of a connection (half-close).
+///
+/// Unlike [`close`], the control block stays around once the connection is
+/// done, so the application can still read what arrived; release it with
+/// [`close`]. Shutting down a direction twice is not an error.
+pub fn shutdown(
+    id: TcpPcbId,
+    how: Shutdown,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    if let Some(fin) = ctx.tcp.shutdown_start(id, how)?
+        && let Err(e) = fin.send(ctx, devices)
+    {
+        // Left on the retransmission queue
+        tracing::debug!("tcp_shutdown: id={}, failed to send FIN: {}", id, e);
+    }
+    Ok(())
+}
+
+/// How often [`timer`] runs
+pub const TCP_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+
+pub fn init(protocols: &mut ProtocolManager) -> Result<()> {
+    protocols.events().subscribe(Arc::new(event));
+    protocols.register_timer("tcp", TCP_TIMER_INTERVAL, Arc::new(timer))
+}
+
+/// Give up blocked calls on an interrupt (microps' `tcp_event`)
+fn event(event: &NetEvent, ctx: &ProtocolContexts) {
+    if *event == NetEvent::Interrupt {
+        ctx.tcp.interrupt();
+    }
+}
+
+/// Periodic TCP work: send delayed ACKs that are due, retransmit segments whose
+/// timeout expired and release connections whose TIME-WAIT period is over.
+///
+/// Registered to run every [`TCP_TIMER_INTERVAL`]; stacks without a timer
+/// thread run it from [`NetStack::run_once`](crate::stack::NetStack::run_once).
+pub fn timer(ctx: &ProtocolContexts, devices: &DeviceManager) {
+    let now = ctx.clock.now();
+    ctx.tcp.time_wait_expired(now);
+    for seg in ctx.tcp.delayed_ack_expired(now) {
+        if let Err(e) = seg.send(ctx, devices) {
+            tracing::error!("tcp_timer: failed to send delayed ACK: {}", e);
+        }
+    }
+    for seg in ctx.tcp.retransmit_expired(now) {
+        if let Err(e) = seg.send(ctx, devices) {
+            tracing::error!("tcp_timer: failed to retransmit: {}", e);
+        }
+    }
+}
+
+/// TCP control blocks a stack holds by default
+pub(crate) const TCP_PCB_SIZE: usize = 16;
+
+/// Ephemeral port range (RFC 6335)
+const TCP_SOURCE_PORT_MIN: u16 = 49152;
+const TCP_SOURCE_PORT_MAX: u16 = 65535;
+
+/// Initial retransmission timeout for SYN segments (RFC 6298 Section 2.1)
+const TCP_SYN_RTO_INITIAL: Duration = Duration::from_secs(1);
+const TCP_SYN_RTO_MAX: Duration = Duration::from_secs(60);
+
+/// Retransmission timeout before the first RTT measurement (RFC 6298 Section 2.1)
+const TCP_RTO_INITIAL: Duration = Duration::from_secs(1);
+/// Lower bound of the computed RTO (RFC 6298 Section 2.4)
+const TCP_RTO_MIN: Duration = Duration::from_secs(1);
+const TCP_RTO_MAX: Duration = Duration::from_secs(60);
+/// Clock granularity G of RFC 6298 Section 2
+const TCP_CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+/// Give up on a connection when a segment stays unacknowledged this long
+const TCP_RETRANSMIT_DEADLINE: Duration = Duration::from_secs(12);
+
+/// Maximum segment lifetime; TIME-WAIT lasts twice this (RFC 9293 Section 3.4.2)
+const TCP_MSL: Duration = Duration::from_secs(30);
+
+/// Longest an acknowledgment of received data is held back
+const TCP_DELAYED_ACK_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Default MSS when the peer does not send the option (RFC 9293 Section 3.7.1)
+pub(crate) const TCP_DEFAULT_MSS: u16 = 536;
+/// Receive buffer size, and so the largest window we advertise
+pub(crate) const TCP_RECV_BUFFER_SIZE: usize = 65535;
+
+/// Duplicate ACKs that trigger a fast retransmit (RFC 5681 Section 3.2)
+const TCP_DUP_ACK_THRESHOLD: u32 = 3;
+
+/// Encoded timestamps option with its two leading NOPs (RFC 7323 Appendix A)
+const TCP_TIMESTAMP_OPT_LEN: usize = 12;
+/// TS.Recent older than this is no longer used to reject segments (RFC 7323 Section 5.5)
+const TCP_PAWS_IDLE: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
+/// Walks of released connections kept for [`TcpPcbTable::walks`]
+const TCP_WALKS_MAX: usize = 64;
+
+/// Round-trip time estimate and retransmission timeout of one connection
+/// (RFC 6298)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttEstimator {
+    /// Smoothed round-trip time; `None` until the first measurement
+    pub srtt: Option<Duration>,
+    /// Round-trip time variation
+    pub rttvar: Duration,
+    /// Current retransmission timeout, including any backoff
+    pub rto: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: TCP_RTO_INITIAL,
+        }
+    }
+
+    /// Take a measurement `r` (RFC 6298 Sections 2.2 and 2.3). The caller
+    /// follows Karn's algorithm: no samples from retransmitted segments.
+    pub fn sample(&mut self, r: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = r / 2;
+                r
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(r);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                (srtt * 7 + r) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto =
+            (srtt + (self.rttvar * 4).max(TCP_CLOCK_GRANULARITY)).clamp(TCP_RTO_MIN, TCP_RTO_MAX);
+    }
+
+    /// Double the timeout after it expired (RFC 6298 Section 5.5); the next
+    /// measurement recomputes it
+    pub fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(TCP_RTO_MAX);
+    }
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What made the sender conclude a segment was lost
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LossEvent {
+    /// Three duplicate ACKs: the segment is fast-retransmitted
+    DupAcks,
+    /// The retransmission timer expired
+    Timeout,
+}
+
+/// Congestion control algorithm of one connection.
+///
+/// The connection sends at most `min(cwnd, peer window)` unacknowledged bytes;
+/// the algorithm adjusts `cwnd` from the ACKs and losses it is told about.
+pub trait CongestionControl: Send {
+    fn name(&self) -> &'static str;
+    /// The connection is established with `mss` as its segment size
+    fn init(&mut self, mss: u16);
+    /// `acked` bytes were newly acknowledged (0 for a duplicate ACK past the
+    /// fast retransmit threshold); `in_flight` bytes remain unacknowledged
+    fn on_ack(&mut self, acked: u32, in_flight: u32);
+    /// A segment was lost while `in_flight` bytes were unacknowledged
+    fn on_loss(&mut self, event: LossEvent, in_flight: u32);
+    /// Congestion window in bytes
+    fn cwnd(&self) -> u32;
+}
+
+/// TCP Reno (RFC 5681): slow start, congestion avoidance, fast retransmit and
+/// fast recovery
+#[derive(Debug, Clone)]
+pub struct Reno {
+    mss: u32,
+    cwnd: u32,
+    ssthresh: u32,
+    recovering: bool,
+}
+
+impl Reno {
+    pub fn new() -> Self {
+        Self {
+            mss: TCP_DEFAULT_MSS as u32,
+            cwnd: Self::initial_window(TCP_DEFAULT_MSS as u32),
+            ssthresh: u32::MAX,
+            recovering: false,
+        }
+    }
+
+    /// RFC 5681 Section 3.1
+    fn initial_window(mss: u32) -> u32 {
+        match mss {
+            0..=1095 => 4 * mss,
+            1096..=2190 => 3 * mss,
+            _ => 2 * mss,
+        }
+    }
+
+    fn reduced_ssthresh(&self, in_flight: u32) -> u32 {
+        (in_flight / 2).max(2 * self.mss)
+    }
+}
+
+impl Default for Reno {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CongestionControl for Reno {
+    fn name(&self) -> &'static str {
+        "reno"
+    }
+
+    fn init(&mut self, mss: u16) {
+        self.mss = (mss as u32).max(1);
+        self.cwnd = Self::initial_window(self.mss);
+    }
+
+    fn on_ack(&mut self, acked: u32, _in_flight: u32) {
+        if acked == 0 {
+            // Each further duplicate ACK means a segment left the network
+            if self.recovering {
+                self.cwnd = self.cwnd.saturating_add(self.mss);
+            }
+            return;
+        }
+        if self.recovering {
+            // Deflate the window once the lost data is acknowledged
+            self.recovering = false;
+            self.cwnd = self.ssthresh;
+        } else if self.cwnd < self.ssthresh {
+            self.cwnd = self.cwnd.saturating_add(acked.min(self.mss));
+        } else {
+            self.cwnd = self
+                .cwnd
+                .saturating_add((self.mss * self.mss / self.cwnd).max(1));
+        }
+    }
+
+    fn on_loss(&mut self, event: LossEvent, in_flight: u32) {
+        self.ssthresh = self.reduced_ssthresh(in_flight);
+        match event {
+            LossEvent::DupAcks => {
+                self.cwnd = self.ssthresh + 3 * self.mss;
+                self.recovering = true;
+            }
+            LossEvent::Timeout => {
+                self.cwnd = self.mss;
+                self.recovering = false;
+            }
+        }
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+}
+
+/// Connection states (RFC 9293 Section 3.3.2)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
+    CloseWait,
+    LastAck,
+}
+
+impl TcpState {
+    /// Every state, in declaration order (`ALL[state as usize] == state`)
+    pub const ALL: [TcpState; 11] = [
+        TcpState::Closed,
+        TcpState::Listen,
+        TcpState::SynSent,
+        TcpState::SynReceived,
+        TcpState::Established,
+        TcpState::FinWait1,
+        TcpState::FinWait2,
+        TcpState::Closing,
+        TcpState::TimeWait,
+        TcpState::CloseWait,
+        TcpState::LastAck,
+    ];
+}
+
+/// Handle of a TCP control block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TcpPcbId(u32);
+
+impl fmt::Display for TcpPcbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Send sequence variables
+#[derive(Debug, Clone, Copy, Default)]
+struct SendVars {
+    nxt: u32,
+    una: u32,
+    wnd: u16,
+    wl1: u32,
+    wl2: u32,
+}
+
+/// Receive sequence variables
+#[derive(Debug, Clone, Copy, Default)]
+struct RecvVars {
+    nxt: u32,
+    wnd: u16,
+}
+
+/// A sent segment kept until it is acknowledged
+#[derive(Debug, Clone)]
+struct RetransmitEntry {
+    first: Instant,
+    last: Instant,
+    rto: Duration,
+    /// Sent more than once, so its ACK gives no RTT sample (Karn's algorithm)
+    retransmitted: bool,
+    seq: u32,
+    flags: u8,
+    data: Vec<u8>,
+}
+
+impl RetransmitEntry {
+    /// Sequence number just past this segment
+    fn end(&self) -> u32 {
+        seq_space(self.flags, self.data.len()).wrapping_add(self.seq)
+    }
+}
+
+/// Timestamps option state (RFC 7323) of a connection using the option
+#[derive(Debug, Clone, Copy)]
+struct Timestamps {
+    /// Added to the clock so TSval does not reveal how long the host is up
+    offset: u32,
+    /// TS.Recent: the peer's TSval we echo
+    recent: u32,
+    /// When `recent` was taken
+    recent_at: Instant,
+    /// Last.ACK.sent: the acknowledgment number of the last segment we sent
+    last_ack_sent: u32,
+}
+
+impl Timestamps {
+    fn new(offset: u32, recent: u32, now: Instant) -> Self {
+        Self {
+            offset,
+            recent,
+            recent_at: now,
+            last_ack_sent: 0,
+        }
+    }
+
+    /// Our TSval at `now`: milliseconds on a clock shared by all connections
+    fn val(&self, now: Instant) -> u32 {
+        static CLOCK: OnceLock<Instant> = OnceLock::new();
+        let ms = now.duration_since(*CLOCK.get_or_init(|| now)).as_millis();
+        (ms as u32).wrapping_add(self.offset)
+    }
+
+    /// PAWS (RFC 7323 Section 5.3): `val` is older than TS.Recent, which has
+    /// not gone idle
+    fn is_stale(&self, val: u32, now: Instant) -> bool {
+        seq_lt(val, self.recent) && now.duration_since(self.recent_at) < TCP_PAWS_IDLE
+    }
+}
+
+/// When the milestones of a connection happened
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpTimeline {
+    /// Control block created (listen or connect)
+    pub opened: Instant,
+    pub syn_sent: Option<Instant>,
+    pub syn_received: Option<Instant>,
+    pub syn_ack_sent: Option<Instant>,
+    pub syn_ack_received: Option<Instant>,
+    pub established: Option<Instant>,
+    pub first_data_sent: Option<Instant>,
+    pub first_data_received: Option<Instant>,
+    pub closed: Option<Instant>,
+}
+
+impl TcpTimeline {
+    fn new(now: Instant) -> Self {
+        Self {
+            opened: now,
+            syn_sent: None,
+            syn_received: None,
+            syn_ack_sent: None,
+            syn_ack_received: None,
+            established: None,
+            first_data_sent: None,
+            first_data_received: None,
+            closed: None,
+        }
+    }
+
+    /// Record `slot` as `now` unless it was already recorded
+    fn mark(slot: &mut Option<Instant>, now: Instant) {
+        slot.get_or_insert(now);
+    }
+
+    /// From the first SYN (sent or received) to ESTABLISHED
+    pub fn handshake(&self) -> Option<Duration> {
+        let start = match (self.syn_sent, self.syn_received) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => a.or(b)?,
+        };
+        Some(self.established?.duration_since(start))
+    }
+
+    /// From ESTABLISHED to the first byte of data received
+    pub fn time_to_first_byte(&self) -> Option<Duration> {
+        Some(self.first_data_received?.duration_since(self.established?))
+    }
+}
+
+/// What made a connection change state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpEvent {
+    /// `listen` or `connect` created the control block
+    Open,
+    /// A segment with these control bits arrived
+    Segment(u8),
+    /// The application closed the connection
+    Close,
+    /// The retransmission deadline or TIME-WAIT ran out
+    Timeout,
+    /// The control block was released without closing
+    Release,
+}
+
+impl fmt::Display for TcpEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const NAMES: [(u8, &str); 6] = [
+            (TCP_FLG_SYN, "SYN"),
+            (TCP_FLG_ACK, "ACK"),
+            (TCP_FLG_FIN, "FIN"),
+            (TCP_FLG_RST, "RST"),
+            (TCP_FLG_PSH, "PSH"),
+            (TCP_FLG_URG, "URG"),
+        ];
+        match self {
+            TcpEvent::Open => write!(f, "open"),
+            TcpEvent::Segment(flags) => {
+                let names: Vec<&str> = NAMES
+                    .iter()
+                    .filter(|(flag, _)| flags & flag != 0)
+                    .map(|&(_, name)| name)
+                    .collect();
+                write!(f, "rcv {}", names.join("+"))
+            }
+            TcpEvent::Close => write!(f, "close"),
+            TcpEvent::Timeout => write!(f, "timeout"),
+            TcpEvent::Release => write!(f, "release"),
+        }
+    }
+}
+
+/// A state change and what caused it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpTransition {
+    pub at: Instant,
+    pub from: TcpState,
+    pub to: TcpState,
+    pub event: TcpEvent,
+}
+
+/// The states one connection walked through, recorded once
+/// [`TcpPcbTable::record_walks`] is on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpWalk {
+    pub id: TcpPcbId,
+    pub local: IpEndpoint,
+    pub foreign: IpEndpoint,
+    pub transitions: Vec<TcpTransition>,
+}
+
+impl TcpWalk {
+    fn title(&self) -> String {
+        format!("tcp {} {} => {}", self.id, self.local, self.foreign)
+    }
+
+    /// Numbered edge labels with the time since the walk began
+    fn labels(&self) -> impl Iterator<Item = (&TcpTransition, String)> {
+        let start = self.transitions.first().map(|t| t.at);
+        self.transitions.iter().enumerate().map(move |(i, t)| {
+            let ms = start.map_or(0.0, |start| {
+                t.at.duration_since(start).as_secs_f64() * 1000.0
+            });
+            (t, format!("{}. {} (+{:.3}ms)", i + 1, t.event, ms))
+        })
+    }
+
+    /// Graphviz digraph of the walk
+    pub fn to_dot(&self) -> String {
+        let mut out = format!("digraph \"{}\" {{\n    rankdir=LR;\n", self.title());
+        for (t, label) in self.labels() {
+            out.push_str(&format!(
+                "    {:?} -> {:?} [label=\"{}\"];\n",
+                t.from, t.to, label
+            ));
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Mermaid state diagram of the walk
+    pub fn to_mermaid(&self) -> String {
+        let mut out = format!("---\ntitle: {}\n---\nstateDiagram-v2\n", self.title());
+        if let Some(first) = self.transitions.first() {
+            out.push_str(&format!("    [*] --> {:?}\n", first.from));
+        }
+        for (t, label) in self.labels() {
+            out.push_str(&format!("    {:?} --> {:?} : {}\n", t.from, t.to, label));
+        }
+        out
+    }
+}
+
+struct TcpPcb {
+    state: TcpState,
+    local: IpEndpoint,
+    /// Remote endpoint; unspecified (`0.0.0.0:0`) for a listener accepting anyone
+    foreign: IpEndpoint,
+    snd: SendVars,
+    iss: u32,
+    rcv: RecvVars,
+    irs: u32,
+    mss: u16,
+    retransmit: VecDeque<RetransmitEntry>,
+    /// Received in-order data not yet read by the application
+    rcvbuf: VecDeque<u8>,
+    /// When the control block leaves TIME-WAIT
+    time_wait: Option<Instant>,
+    /// The application stopped reading; data that arrives is acknowledged and discarded
+    read_shutdown: bool,
+    /// The application closed the connection; the block is released once it is done
+    closed: bool,
+    cc: Box<dyn CongestionControl>,
+    rtt: RttEstimator,
+    /// Set once both sides use the timestamps option (offered, in SYN-SENT)
+    ts: Option<Timestamps>,
+    /// Signs and checks every segment; a listener's connections inherit it
+    auth: Option<Arc<dyn TcpAuth>>,
+    /// Consecutive duplicate ACKs received
+    dup_acks: u32,
+    /// Segments sent again so far
+    retransmissions: u64,
+    /// Whether acknowledgments of received data may be delayed
+    delayed_ack: bool,
+    /// Segments received since we last sent an ACK
+    ack_delayed: u32,
+    /// When a delayed ACK must be sent at the latest
+    ack_deadline: Option<Instant>,
+    /// Pending connections a listener holds at most; `None` for a listener that
+    /// becomes the connection itself
+    backlog: Option<usize>,
+    /// Established connections waiting for [`TcpPcbTable::accept`]
+    accept_queue: VecDeque<TcpPcbId>,
+    /// Listener that created this connection, until it is accepted
+    parent: Option<TcpPcbId>,
+    timeline: TcpTimeline,
+    /// State changes so far, when walks are recorded
+    walk: Option<Vec<TcpTransition>>,
+    /// The table's clock, for timeouts and the timeline
+    clock: Clock,
+}
+
+impl TcpPcb {
+    fn new(state: TcpState, local: IpEndpoint, foreign: IpEndpoint, clock: Clock) -> Self {
+        Self {
+            state,
+            local,
+            foreign,
+            snd: SendVars::default(),
+            iss: 0,
+            rcv: RecvVars {
+                nxt: 0,
+                wnd: TCP_RECV_BUFFER_SIZE as u16,
+            },
+            irs: 0,
+            mss: TCP_DEFAULT_MSS,
+            retransmit: VecDeque::new(),
+            rcvbuf: VecDeque::new(),
+            time_wait: None,
+            read_shutdown: false,
+            closed: false,
+            cc: Box::new(Reno::new()),
+            rtt: RttEstimator::new(),
+            ts: None,
+            auth: None,
+            dup_acks: 0,
+            retransmissions: 0,
+            delayed_ack: true,
+            ack_delayed: 0,
+            ack_deadline: None,
+            backlog: None,
+            accept_queue: VecDeque::new(),
+            parent: None,
+            timeline: TcpTimeline::new(clock.now()),
+            walk: None,
+            clock,
+        }
+    }
+
+    fn set_state(&mut self, id: TcpPcbId, state: TcpState, event: TcpEvent) {
+        tracing::debug!(
+            "tcp: id={}, {} => {}, {:?} => {:?}",
+            id,
+            self.local,
+            self.foreign,
+            self.state,
+            state
+        );
+        TRACE.record(TraceEvent::State {
+            conn: id.0,
+            from: self.state,
+            to: state,
+        });
+        if let Some(walk) = &mut self.walk {
+            walk.push(TcpTransition {
+                at: self.clock.now(),
+                from: self.state,
+                to: state,
+                event,
+            });
+        }
+        self.state = state;
+        match state {
+            TcpState::Established => {
+                TcpTimeline::mark(&mut self.timeline.established, self.clock.now());
+                self.cc.init(self.mss);
+            }
+            TcpState::Closed => TcpTimeline::mark(&mut self.timeline.closed, self.clock.now()),
+            TcpState::TimeWait => self.time_wait = Some(self.clock.now() + TCP_MSL * 2),
+            _ => {}
+        }
+    }
+
+    /// Segment that occupies sequence space, kept for retransmission until acknowledged
+    fn emit(&mut self, seq: u32, flags: u8, data: &[u8]) -> Outgoing {
+        let now = self.clock.now();
+        // The segment carries the acknowledgment a delayed ACK would have sent
+        self.clear_delayed_ack();
+        if flags & TCP_FLG_SYN != 0 && flags & TCP_FLG_ACK != 0 {
+            TcpTimeline::mark(&mut self.timeline.syn_ack_sent, self.clock.now());
+        }
+        if !data.is_empty() {
+            TcpTimeline::mark(&mut self.timeline.first_data_sent, self.clock.now());
+        }
+        self.retransmit.push_back(RetransmitEntry {
+            first: now,
+            last: now,
+            rto: self.rtt.rto,
+            retransmitted: false,
+            seq,
+            flags,
+            data: data.to_vec(),
+        });
+        let mut seg = self.reply(seq, flags);
+        seg.data = data.to_vec();
+        seg
+    }
+
+    /// Advance SND.UNA to `ack` and free fully acknowledged segments
+    fn acknowledge(&mut self, seg: &SegmentInfo) {
+        let ack = seg.ack;
+        self.snd.una = ack;
+        let mut newest = None;
+        while let Some(entry) = self.retransmit.front() {
+            if !seq_le(entry.end(), ack) {
+                break;
+            }
+            newest = self.retransmit.pop_front();
+        }
+        // The echoed timestamp tells which transmission is acknowledged
+        // (RFC 7323 Section 4); without it, the newest segment acknowledged
+        // gives the sample unless it was retransmitted
+        if let Some(ts) = self.ts
+            && let Some((_, ecr)) = seg.timestamp
+            && ecr != 0
+        {
+            let rtt = Duration::from_millis(ts.val(self.clock.now()).wrapping_sub(ecr) as u64);
+            if rtt < TCP_RETRANSMIT_DEADLINE {
+                self.rtt.sample(rtt);
+            }
+        } else if let Some(entry) = newest
+            && !entry.retransmitted
+        {
+            self.rtt
+                .sample(self.clock.now().duration_since(entry.first));
+        }
+    }
+
+    /// Sent bytes not yet acknowledged
+    fn in_flight(&self) -> u32 {
+        self.snd.nxt.wrapping_sub(self.snd.una)
+    }
+
+    /// Bytes the peer's window and the congestion window allow us to send
+    /// beyond what is in flight
+    fn usable_window(&self) -> usize {
+        let window = (self.snd.wnd as u32).min(self.cc.cwnd());
+        window.saturating_sub(self.in_flight()) as usize
+    }
+
+    /// Resend the oldest unacknowledged segment
+    fn retransmit_first(&mut self) -> Option<Outgoing> {
+        let entry = self.retransmit.front_mut()?;
+        entry.last = self.clock.now();
+        entry.retransmitted = true;
+        self.retransmissions += 1;
+        let (seq, flags, data) = (entry.seq, entry.flags, entry.data.clone());
+        let mut seg = self.reply(seq, flags);
+        seg.data = data;
+        Some(seg)
+    }
+
+    /// Update SND.WND unless the segment is older than the last window update
+    /// (RFC 9293 Section 3.10.7.4)
+    fn update_window(&mut self, seg: &SegmentInfo) {
+        if seq_lt(self.snd.wl1, seg.seq)
+            || (self.snd.wl1 == seg.seq && seq_le(self.snd.wl2, seg.ack))
+        {
+            self.snd.wnd = seg.wnd;
+            self.snd.wl1 = seg.seq;
+            self.snd.wl2 = seg.ack;
+        }
+    }
+
+    /// Send our FIN unless it was sent already; later states have nothing to do
+    fn fin_start(&mut self, id: TcpPcbId) -> Option<Outgoing> {
+        let next = match self.state {
+            TcpState::Established | TcpState::SynReceived => TcpState::FinWait1,
+            TcpState::CloseWait => TcpState::LastAck,
+            _ => return None,
+        };
+        let fin = self.emit(self.snd.nxt, TCP_FLG_FIN | TCP_FLG_ACK, &[]);
+        self.snd.nxt = self.snd.nxt.wrapping_add(1);
+        self.set_state(id, next, TcpEvent::Close);
+        Some(fin)
+    }
+
+    /// Segment processing once the connection is synchronized (ESTABLISHED and
+    /// the closing states): acknowledgment, window, in-order data and FIN
+    fn synchronized_arrives(&mut self, id: TcpPcbId, seg: &SegmentInfo) -> Vec<Outgoing> {
+        let event = TcpEvent::Segment(seg.flags);
+        // A reset only closes the connection when it sits exactly at RCV.NXT;
+        // one elsewhere in the window gets a challenge ACK so that a blind
+        // attacker has to guess the sequence number (RFC 5961 Section 3.2)
+        if seg.has(TCP_FLG_RST) {
+            if seg.seq == self.rcv.nxt {
+                tracing::debug!("tcp: id={}, connection reset", id);
+                self.retransmit.clear();
+                self.set_state(id, TcpState::Closed, event);
+                return Vec::new();
+            }
+            if self.in_window(seg.seq) {
+                tracing::debug!("tcp: id={}, challenge ACK for RST seq={}", id, seg.seq);
+                return vec![self.reply(self.snd.nxt, TCP_FLG_ACK)];
+            }
+            return Vec::new();
+        }
+        // RFC 793 acceptability test: a segment without data only has to fall
+        // in the receive window, data is only accepted as the next expected
+        // segment. Anything else (including a retransmitted FIN in TIME-WAIT)
+        // is answered with an ACK telling the peer what we expect
+        let acceptable = if seg.len == 0 {
+            seg.seq == self.rcv.nxt || self.in_window(seg.seq)
+        } else {
+            seg.seq == self.rcv.nxt
+        };
+        if !acceptable {
+            if self.state == TcpState::TimeWait {
+                self.time_wait = Some(self.clock.now() + TCP_MSL * 2);
+            }
+            return vec![self.reply(self.snd.nxt, TCP_FLG_ACK)];
+        }
+        if !seg.has(TCP_FLG_ACK) {
+            return Vec::new();
+        }
+        if seq_lt(self.snd.nxt, seg.ack) {
+            // Acknowledges something not yet sent
+            return vec![self.reply(self.snd.nxt, TCP_FLG_ACK)];
+        }
+        let mut out = Vec::new();
+        if seq_lt(self.snd.una, seg.ack) {
+            let acked = seg.ack.wrapping_sub(self.snd.una);
+            self.acknowledge(seg);
+            self.dup_acks = 0;
+            self.cc.on_ack(acked, self.in_flight());
+            self.update_window(seg);
+        } else if seg.ack == self.snd.una {
+            // RFC 5681 Section 2: no data, same window, and something outstanding
+            if seg.data.is_empty()
+                && !seg.has(TCP_FLG_FIN)
+                && seg.wnd == self.snd.wnd
+                && !self.retransmit.is_empty()
+            {
+                self.dup_acks += 1;
+                if self.dup_acks == TCP_DUP_ACK_THRESHOLD {
+                    tracing::debug!("tcp: id={}, fast retransmit seq={}", id, self.snd.una);
+                    self.cc.on_loss(LossEvent::DupAcks, self.in_flight());
+                    out.extend(self.retransmit_first());
+                } else if self.dup_acks > TCP_DUP_ACK_THRESHOLD {
+                    self.cc.on_ack(0, self.in_flight());
+                }
+            }
+            self.update_window(seg);
+        }
+
+        let fin_acked = self.snd.una == self.snd.nxt;
+        match self.state {
+            TcpState::FinWait1 if fin_acked => self.set_state(id, TcpState::FinWait2, event),
+            TcpState::Closing if fin_acked => self.set_state(id, TcpState::TimeWait, event),
+            TcpState::LastAck if fin_acked => {
+                self.set_state(id, TcpState::Closed, event);
+                return out;
+            }
+            _ => {}
+        }
+
+        let mut ack = false;
+        let mut accepted = 0;
+        if !seg.data.is_empty()
+            && matches!(
+                self.state,
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+            )
+        {
+            TcpTimeline::mark(&mut self.timeline.first_data_received, self.clock.now());
+            // Data beyond the window we advertised is dropped and retransmitted by the peer
+            accepted = seg.data.len().min(self.rcv.wnd as usize);
+            if !self.read_shutdown {
+                self.rcvbuf.extend(&seg.data[..accepted]);
+            }
+            self.rcv.nxt = self.rcv.nxt.wrapping_add(accepted as u32);
+            self.rcv.wnd = (TCP_RECV_BUFFER_SIZE - self.rcvbuf.len()) as u16;
+            // ACK at least every second full segment (RFC 1122 Section 4.2.3.2),
+            // and at once when data was dropped or the window is nearly closed
+            if self.delayed_ack && accepted == seg.data.len() && self.rcv.wnd >= self.mss {
+                self.ack_delayed += 1;
+                if self.ack_delayed >= 2 {
+                    ack = true;
+                } else {
+                    self.ack_deadline
+                        .get_or_insert_with(|| self.clock.now() + TCP_DELAYED_ACK_TIMEOUT);
+                }
+            } else {
+                ack = true;
+            }
+        }
+
+        // A FIN counts only once everything before it has been accepted
+        if seg.has(TCP_FLG_FIN) && accepted == seg.data.len() {
+            self.rcv.nxt = self.rcv.nxt.wrapping_add(1);
+            match self.state {
+                TcpState::Established => self.set_state(id, TcpState::CloseWait, event),
+                // Our FIN is not acknowledged yet, or we would be in FIN-WAIT-2
+                TcpState::FinWait1 => self.set_state(id, TcpState::Closing, event),
+                TcpState::FinWait2 => self.set_state(id, TcpState::TimeWait, event),
+                _ => {}
+            }
+            ack = true;
+        }
+
+        if ack {
+            self.clear_delayed_ack();
+            out.push(self.reply(self.snd.nxt, TCP_FLG_ACK));
+        }
+        out
+    }
+
+    /// An ACK is going out, so nothing is left to acknowledge later
+    fn clear_delayed_ack(&mut self) {
+        self.ack_delayed = 0;
+        self.ack_deadline = None;
+    }
+
+    /// Segment from this connection with the current receive state
+    /// Whether `seq` lies in RCV.NXT =< seq < RCV.NXT + RCV.WND
+    fn in_window(&self, seq: u32) -> bool {
+        seq_le(self.rcv.nxt, seq) && seq_lt(seq, self.rcv.nxt.wrapping_add(u32::from(self.rcv.wnd)))
+    }
+
+    fn reply(&mut self, seq: u32, flags: u8) -> Outgoing {
+        let mut options = Vec::new();
+        if let Some(ts) = &mut self.ts {
+            // TSecr is only meaningful alongside an ACK
+            let ecr = if flags & TCP_FLG_ACK != 0 {
+                ts.last_ack_sent = self.rcv.nxt;
+                ts.recent
+            } else {
+                0
+            };
+            options.extend_from_slice(&[TCP_OPT_NOP, TCP_OPT_NOP]);
+            TcpOption::Timestamp {
+                val: ts.val(self.clock.now()),
+                ecr,
+            }
+            .encode(&mut options);
+        }
+        if let Some(auth) = &self.auth {
+            // The MAC is filled in once the segment is built
+            options.extend_from_slice(&[auth.kind(), auth_option_len(auth.as_ref()) as u8]);
+            options.resize(options.len() + auth.mac_len(), 0);
+        }
+        Outgoing {
+            src: self.local,
+            dst: self.foreign,
+            seq,
+            ack: self.rcv.nxt,
+            flags,
+            wnd: self.rcv.wnd,
+            options,
+            data: Vec::new(),
+            auth: self.auth.clone(),
+        }
+    }
+
+    /// Payload bytes per segment: the MSS less the options every segment carries
+    fn payload_mss(&self) -> usize {
+        let mut options = 0;
+        if self.ts.is_some() {
+            options += TCP_TIMESTAMP_OPT_LEN;
+        }
+        if let Some(auth) = &self.auth {
+            options += auth_option_len(auth.as_ref());
+        }
+        (self.mss as usize)
+            .saturating_sub(options.next_multiple_of(4))
+            .max(1)
+    }
+
+    /// Timestamp handling for a segment on a synchronized connection (RFC 7323
+    /// Sections 3.2, 4.3 and 5.3). Returns what to send instead of processing
+    /// the segment if it must not be processed.
+    fn check_timestamp(&mut self, id: TcpPcbId, seg: &SegmentInfo) -> Option<Vec<Outgoing>> {
+        let ts = self.ts.as_mut()?;
+        if seg.has(TCP_FLG_RST) {
+            return None;
+        }
+        let Some((val, _)) = seg.timestamp else {
+            tracing::debug!("tcp: id={}, segment without timestamp dropped", id);
+            return Some(Vec::new());
+        };
+        if ts.is_stale(val, self.clock.now()) {
+            tracing::debug!("tcp: id={}, PAWS: TSval={} < {}", id, val, ts.recent);
+            return Some(vec![self.reply(self.snd.nxt, TCP_FLG_ACK)]);
+        }
+        if seq_le(ts.recent, val) && seq_le(seg.seq, ts.last_ack_sent) {
+            ts.recent = val;
+            ts.recent_at = self.clock.now();
+        }
+        None
+    }
+}
+
+/// Fields of a received segment used by the state machine
+#[derive(Debug, Clone, Copy)]
+struct SegmentInfo<'a> {
+    seq: u32,
+    ack: u32,
+    /// Sequence space occupied: payload plus SYN and FIN
+    len: u32,
+    data: &'a [u8],
+    wnd: u16,
+    flags: u8,
+    mss: Option<u16>,
+    /// TSval and TSecr of the timestamps option
+    timestamp: Option<(u32, u32)>,
+    /// The whole segment as received, for authentication
+    raw: &'a [u8],
+}
+
+impl<'a> SegmentInfo<'a> {
+    fn new(raw: &'a [u8], hdr: &TcpHdr, options: &[TcpOption], data: &'a [u8]) -> Self {
+        let flags = hdr.flg();
+        Self {
+            seq: hdr.seq(),
+            ack: hdr.ack(),
+            len: seq_space(flags, data.len()),
+            data,
+            wnd: hdr.wnd(),
+            flags,
+            mss: options.iter().find_map(|opt| match opt {
+                TcpOption::Mss(mss) => Some(*mss),
+                _ => None,
+            }),
+            timestamp: options.iter().find_map(|opt| match opt {
+                TcpOption::Timestamp { val, ecr } => Some((*val, *ecr)),
+                _ => None,
+            }),
+            raw,
+        }
+    }
+
+    fn has(&self, flags: u8) -> bool {
+        self.flags & flags == flags
+    }
+}
+
+/// A segment queued for transmission once the table lock is released
+#[derive(Debug, Clone)]
+struct Outgoing {
+    src: IpEndpoint,
+    dst: IpEndpoint,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    wnd: u16,
+    options: Vec<u8>,
+    data: Vec<u8>,
+    /// Signs the segment; `options` ends with room for its option
+    auth: Option<Arc<dyn TcpAuth>>,
+}
+
+impl Outgoing {
+    fn send(&self, ctx: &ProtocolContexts, devices: &DeviceManager) -> Result<usize> {
+        let seg = TcpSegment {
+            seq: self.seq,
+            ack: self.ack,
+            flags: self.flags,
+            wnd: self.wnd,
+            options: &self.options,
+            data: &self.data,
+        };
+        let mut buf = build(self.src, self.dst, &seg)?;
+        if let Some(auth) = &self.auth {
+            sign(&mut buf, self.src, self.dst, auth.as_ref());
+        }
+        transmit(self.src, self.dst, buf, ctx, devices)?;
+        Ok(self.data.len())
+    }
+
+    /// The RST answering `seg` when no connection takes it (RFC 9293 Section 3.10.7.1).
+    /// A RST is never answered.
+    fn reset(local: IpEndpoint, foreign: IpEndpoint, seg: &SegmentInfo) -> Option<Self> {
+        if seg.has(TCP_FLG_RST) {
+            return None;
+        }
+        let (seq, ack, flags) = if seg.has(TCP_FLG_ACK) {
+            (seg.ack, 0, TCP_FLG_RST)
+        } else {
+            (0, seg.seq.wrapping_add(seg.len), TCP_FLG_RST | TCP_FLG_ACK)
+        };
+        Some(Self {
+            src: local,
+            dst: foreign,
+            seq,
+            ack,
+            flags,
+            wnd: 0,
+            options: Vec::new(),
+            data: Vec::new(),
+            auth: None,
+        })
+    }
+}
+
+/// Sequence space occupied by a segment: payload plus SYN and FIN
+fn seq_space(flags: u8, data_len: usize) -> u32 {
+    let mut len = data_len as u32;
+    if flags & TCP_FLG_SYN != 0 {
+        len += 1;
+    }
+    if flags & TCP_FLG_FIN != 0 {
+        len += 1;
+    }
+    len
+}
+
+/// `a < b` in sequence space (RFC 9293 Section 3.4)
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// Initial send sequence number: a clock ticking every 4 microseconds
+/// (RFC 9293 Section 3.4.1) offset by the process id
+fn initial_seq() -> u32 {
+    let ticks = platform::since_epoch().as_micros() / 4;
+    (ticks as u32).wrapping_add(platform::process_id().wrapping_mul(0x9e37_79b9))
+}
+
+struct PcbState {
+    pcbs: HashMap<TcpPcbId, TcpPcb>,
+    next_id: u32,
+    /// Control blocks allocated at most
+    limit: usize,
+    /// Whether new connections offer and accept the timestamps option
+    timestamps: bool,
+    /// Walks of released connections, oldest first; `None` when not recording
+    walks: Option<VecDeque<TcpWalk>>,
+    /// Interrupts so far; a call blocked across one gives up
+    interrupts: u64,
+    /// Retransmissions of the control blocks released so far
+    retransmissions: u64,
+}
+
+impl PcbState {
+    /// Connection an inbound segment belongs to.
+    ///
+    /// A control block whose local and foreign endpoints both match wins; otherwise a
+    /// LISTEN block bound to the local endpoint (or the wildcard address) whose
+    /// foreign endpoint is unspecified or matches.
+    fn select(&self, local: IpEndpoint, foreign: IpEndpoint) -> Option<TcpPcbId> {
+        let mut listener = None;
+        for (&id, pcb) in &self.pcbs {
+            if pcb.local.port != local.port
+                || (pcb.local.addr != IpAddr::ANY && pcb.local.addr != local.addr)
+            {
+                continue;
+            }
+            if pcb.foreign == foreign {
+                return Some(id);
+            }
+            if pcb.state == TcpState::Listen && pcb.foreign == IpEndpoint::default() {
+                listener = Some(id);
+            }
+        }
+        listener
+    }
+
+    /// Listeners that would keep a listen on `local` for `foreign` from succeeding
+    fn conflicts(&self, local: IpEndpoint, foreign: IpEndpoint) -> Vec<Conflict> {
+        self.pcbs
+            .iter()
+            .filter(|(_, pcb)| {
+                pcb.state == TcpState::Listen
+                    && pcb.local.port == local.port
+                    && pcb.foreign == foreign
+                    && (pcb.local.addr == IpAddr::ANY
+                        || local.addr == IpAddr::ANY
+                        || pcb.local.addr == local.addr)
+            })
+            .map(|(&owner, pcb)| Conflict::TcpPort {
+                owner,
+                local: pcb.local,
+                state: pcb.state,
+            })
+            .collect()
+    }
+
+    /// Connections of listener `id` that are not accepted yet
+    fn pending(&self, id: TcpPcbId) -> usize {
+        self.pcbs
+            .values()
+            .filter(|pcb| pcb.parent == Some(id) && pcb.state != TcpState::Closed)
+            .count()
+    }
+
+    /// Release the connections listener `id` never handed out
+    fn release_pending(&mut self, id: TcpPcbId) {
+        let orphans: Vec<TcpPcbId> = self
+            .pcbs
+            .iter()
+            .filter(|(_, pcb)| pcb.parent == Some(id))
+            .map(|(&child, _)| child)
+            .collect();
+        for child in orphans {
+            self.remove(child, TcpEvent::Release);
+        }
+    }
+
+    /// Drop control block `id`, keeping its walk if walks are recorded.
+    /// A block not yet CLOSED is taken there by `event`.
+    fn remove(&mut self, id: TcpPcbId, event: TcpEvent) -> Option<TcpPcb> {
+        let mut pcb = self.pcbs.remove(&id)?;
+        self.retransmissions += pcb.retransmissions;
+        if pcb.state != TcpState::Closed {
+            pcb.set_state(id, TcpState::Closed, event);
+        }
+        TcpTimeline::mark(&mut pcb.timeline.closed, pcb.clock.now());
+        tracing::debug!("tcp_release: id={}, timeline={:?}", id, pcb.timeline);
+        if let (Some(walks), Some(transitions)) = (&mut self.walks, pcb.walk.take()) {
+            if walks.len() >= TCP_WALKS_MAX {
+                walks.pop_front();
+            }
+            walks.push_back(TcpWalk {
+                id,
+                local: pcb.local,
+                foreign: pcb.foreign,
+                transitions,
+            });
+        }
+        Some(pcb)
+    }
+
+    fn ephemeral_port(&self) -> Option<u16> {
+        (TCP_SOURCE_PORT_MIN..=TCP_SOURCE_PORT_MAX)
+            .find(|&port| self.pcbs.values().all(|pcb| pcb.local.port != port))
+    }
+
+    fn alloc(&mut self, mut pcb: TcpPcb) -> Result<TcpPcbId> {
+        if self.pcbs.len() >= self.limit {
+            return Err(NetError::LimitReached {
+                what: "TCP control blocks",
+                limit: self.limit,
+            });
+        }
+        if self.walks.is_some() {
+            pcb.walk = Some(vec![TcpTransition {
+                at: pcb.timeline.opened,
+                from: TcpState::Closed,
+                to: pcb.state,
+                event: TcpEvent::Open,
+            }]);
+        }
+        let id = TcpPcbId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        self.pcbs.insert(id, pcb);
+        Ok(id)
+    }
+}
+
+/// TCP control blocks keyed by (local, foreign) endpoint pairs
+pub struct TcpPcbTable {
+    state: Mutex<PcbState>,
+    clock: Clock,
+    /// Signalled whenever a control block changes state
+    changed: Condvar,
+    /// Tasks waiting for a control block to change, woken with `changed`
+    wakers: Mutex<Vec<(TcpPcbId, Waker)>>,
+}
+
+impl TcpPcbTable {
+    pub fn new() -> Self {
+        Self::with_limit(StackLimits::default().tcp_sockets)
+    }
+
+    /// Table of at most `limit` control blocks
+    pub fn with_limit(limit: usize) -> Self {
+        Self::with_clock(limit, Clock::default())
+    }
+
+    /// Like [`with_limit`](Self::with_limit), with retransmission, delayed
+    /// ACK and TIME-WAIT timeouts on `clock`
+    pub fn with_clock(limit: usize, clock: Clock) -> Self {
+        Self {
+            state: Mutex::new(PcbState {
+                pcbs: HashMap::new(),
+                next_id: 0,
+                limit,
+                timestamps: true,
+                walks: None,
+                interrupts: 0,
+                retransmissions: 0,
+            }),
+            clock,
+            changed: Condvar::new(),
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Passive open: accept a connection to `local` from `foreign`
+    /// (or from anyone if `None`)
+    pub fn listen(&self, local: IpEndpoint, foreign: Option<IpEndpoint>) -> Result<TcpPcbId> {
+        let mut state = self.state.lock().unwrap();
+        let foreign = foreign.unwrap_or_default();
+        let conflicts = state.conflicts(local, foreign);
+        if !conflicts.is_empty() {
+            return Err(NetError::PortInUse(Conflicts {
+                request: format!("tcp listen {}", local),
+                conflicts,
+            }));
+        }
+        let id = state.alloc(TcpPcb::new(
+            TcpState::Listen,
+            local,
+            foreign,
+            self.clock.clone(),
+        ))?;
+        tracing::debug!("tcp_listen: id={}, local={}", id, local);
+        Ok(id)
+    }
+
+    /// Passive open that keeps listening: each connection to `local` gets its own
+    /// control block, taken with [`TcpPcbTable::accept`]. At most `backlog`
+    /// connections may be pending (handshaking or not yet accepted); further
+    /// SYNs are dropped and the peer retries them.
+    pub fn listen_backlog(&self, local: IpEndpoint, backlog: usize) -> Result<TcpPcbId> {
+        if backlog == 0 {
+            return Err(NetError::InvalidInput("backlog must be at least 1"));
+        }
+        let id = self.listen(local, None)?;
+        if let Some(pcb) = self.state.lock().unwrap().pcbs.get_mut(&id) {
+            pcb.backlog = Some(backlog);
+        }
+        Ok(id)
+    }
+
+    /// Take the next established connection of listener `id`, waiting up to
+    /// `timeout` (forever if `None`). Returns `None` on timeout.
+    pub fn accept(&self, id: TcpPcbId, timeout: Option<Duration>) -> Result<Option<TcpPcbId>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        let interrupts = state.interrupts;
+        loop {
+            let Some(listener) = state.pcbs.get_mut(&id) else {
+                return Err(NetError::NotFound(format!("TCP control block {}", id)));
+            };
+            if listener.backlog.is_none() {
+                return Err(NetError::InvalidInput("not listening with a backlog"));
+            }
+            if let Some(child) = listener.accept_queue.pop_front() {
+                // Skip connections released while they waited
+                if let Some(pcb) = state.pcbs.get_mut(&child) {
+                    pcb.parent = None;
+                    tracing::debug!("tcp_accept: id={}, connection={}", id, child);
+                    return Ok(Some(child));
+                }
+                continue;
+            }
+            state = match deadline {
+                None => self.changed.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(None);
+                    }
+                    self.changed.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+            if state.interrupts != interrupts {
+                return Err(NetError::Interrupted);
+            }
+        }
+    }
+
+    /// Listeners that would keep a listen on `local` for `foreign` from succeeding
+    pub fn conflicts(&self, local: IpEndpoint, foreign: IpEndpoint) -> Vec<Conflict> {
+        self.state.lock().unwrap().conflicts(local, foreign)
+    }
+
+    /// Release the control block immediately, without telling the peer.
+    /// Use [`close`] to tear a connection down gracefully.
+    pub fn release(&self, id: TcpPcbId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.remove(id, TcpEvent::Release).is_none() {
+            return Err(NetError::NotFound(format!("TCP control block {}", id)));
+        }
+        state.release_pending(id);
+        self.notify();
+        Ok(())
+    }
+
+    /// Start closing the connection and return the FIN to send, if any.
+    /// Control blocks with no connection to close are released right away.
+    fn close_start(&self, id: TcpPcbId) -> Result<Option<Outgoing>> {
+        let mut state = self.state.lock().unwrap();
+        let Some(pcb) = state.pcbs.get_mut(&id) else {
+            return Err(NetError::NotFound(format!("TCP control block {}", id)));
+        };
+        match pcb.state {
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent => {
+                state.remove(id, TcpEvent::Close);
+                state.release_pending(id);
+                self.notify();
+                return Ok(None);
+            }
+            _ if pcb.closed => return Err(NetError::Closed),
+            _ => {}
+        }
+        pcb.closed = true;
+        let fin = pcb.fin_start(id);
+        self.notify();
+        Ok(fin)
+    }
+
+    /// Shut down one or both directions and return the FIN to send, if any
+    fn shutdown_start(&self, id: TcpPcbId, how: Shutdown) -> Result<Option<Outgoing>> {
+        let mut state = self.state.lock().unwrap();
+        let Some(pcb) = state.pcbs.get_mut(&id) else {
+            return Err(NetError::NotFound(format!("TCP control block {}", id)));
+        };
+        if matches!(
+            pcb.state,
+            TcpState::Closed | TcpState::Listen | TcpState::SynSent
+        ) {
+            return Err(NetError::NotConnected);
+        }
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            pcb.read_shutdown = true;
+            pcb.rcvbuf.clear();
+            pcb.rcv.wnd = TCP_RECV_BUFFER_SIZE as u16;
+        }
+        let fin = if matches!(how, Shutdown::Write | Shutdown::Both) {
+            pcb.fin_start(id)
+        } else {
+            None
+        };
+        self.notify();
+        Ok(fin)
+    }
+
+    /// Collect delayed ACKs that are due at `now`
+    fn delayed_ack_expired(&self, now: Instant) -> Vec<Outgoing> {
+        let mut state = self.state.lock().unwrap();
+        let mut out = Vec::new();
+        for pcb in state.pcbs.values_mut() {
+            if pcb.ack_deadline.is_some_and(|deadline| now >= deadline) {
+                pcb.clear_delayed_ack();
+                out.push(pcb.reply(pcb.snd.nxt, TCP_FLG_ACK));
+            }
+        }
+        out
+    }
+
+    /// Acknowledge every segment at once instead of delaying ACKs (`TCP_QUICKACK`-like)
+    pub fn set_delayed_ack(&self, id: TcpPcbId, enabled: bool) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(pcb) = state.pcbs.get_mut(&id) else {
+            return Err(NetError::NotFound(format!("TCP control block {}", id)));
+        };
+        pcb.delayed_ack = enabled;
+        Ok(())
+    }
+
+    /// Release control blocks whose TIME-WAIT period ended at `now`
+    fn time_wait_expired(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let expired: Vec<TcpPcbId> = state
+            .pcbs
+            .iter()
+            .filter(|(_, pcb)| pcb.time_wait.is_some_and(|until| now >= until))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            tracing::debug!("tcp: id={}, TIME-WAIT expired", id);
+            let pcb = state.pcbs.get_mut(&id).unwrap();
+            if pcb.closed {
+                state.remove(id, TcpEvent::Timeout);
+            } else {
+                // Only shut down: kept until the application closes it
+                pcb.time_wait = None;
+                pcb.set_state(id, TcpState::Closed, TcpEvent::Timeout);
+            }
+        }
+        self.notify();
+    }
+
+    /// Milestone timestamps of the connection
+    pub fn timeline(&self, id: TcpPcbId) -> Option<TcpTimeline> {
+        self.state
+            .lock()
+            .unwrap()
+            .pcbs
+            .get(&id)
+            .map(|pcb| pcb.timeline)
+    }
+
+    /// Format open connections with their handshake time and time to first byte
+    pub fn report(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut ids: Vec<_> = state.pcbs.keys().copied().collect();
+        ids.sort_by_key(|id| id.0);
+
+        let ms = |d: Option<Duration>| match d {
+            Some(d) => format!("{:.3}ms", d.as_secs_f64() * 1000.0),
+            None => "-".to_string(),
+        };
+        let now = self.clock.now();
+        let mut out = format!(
+            "{:>4} {:<12} {:<22} {:<22} {:>12} {:>12} {:>9}\n",
+            "ID", "STATE", "LOCAL", "FOREIGN", "HANDSHAKE", "TTFB", "AGE"
+        );
+        for id in ids {
+            let pcb = &state.pcbs[&id];
+            out.push_str(&format!(
+                "{:>4} {:<12} {:<22} {:<22} {:>12} {:>12} {:>8.1}s\n",
+                id.to_string(),
+                format!("{:?}", pcb.state),
+                pcb.local.to_string(),
+                pcb.foreign.to_string(),
+                ms(pcb.timeline.handshake()),
+                ms(pcb.timeline.time_to_first_byte()),
+                now.duration_since(pcb.timeline.opened).as_secs_f64()
+            ));
+        }
+        out
+    }
+
+    /// Offer and accept the timestamps option (RFC 7323) on connections opened
+    /// from now on; on by default
+    pub fn set_timestamps(&self, enabled: bool) {
+        self.state.lock().unwrap().timestamps = enabled;
+    }
+
+    /// Record the states every connection opened from now on walks through
+    /// (or stop recording and forget the walks)
+    pub fn record_walks(&self, enabled: bool) {
+        let mut state = self.state.lock().unwrap();
+        match (enabled, state.walks.is_some()) {
+            (true, false) => state.walks = Some(VecDeque::new()),
+            (false, _) => state.walks = None,
+            _ => {}
+        }
+    }
+
+    /// Recorded walks: released connections (the last 64) followed by open ones
+    pub fn walks(&self) -> Vec<TcpWalk> {
+        let state = self.state.lock().unwrap();
+        let Some(released) = &state.walks else {
+            return Vec::new();
+        };
+        let mut open: Vec<TcpWalk> = state
+            .pcbs
+            .iter()
+            .filter_map(|(&id, pcb)| {
+                Some(TcpWalk {
+                    id,
+                    local: pcb.local,
+                    foreign: pcb.foreign,
+                    transitions: pcb.walk.clone()?,
+                })
+            })
+            .collect();
+        open.sort_by_key(|walk| walk.id.0);
+        released.iter().cloned().chain(open).collect()
+    }
+
+    /// Allocate a control block in SYN-SENT and return the SYN to send
+    fn connect_start(
+        &self,
+        local: IpEndpoint,
+        foreign: IpEndpoint,
+        auth: Option<Arc<dyn TcpAuth>>,
+        retransmit: bool,
+    ) -> Result<(TcpPcbId, Outgoing)> {
+        let mut state = self.state.lock().unwrap();
+        let mut local = local;
+        if local.port == 0 {
+            local.port = state.ephemeral_port().ok_or(NetError::NoFreePort)?;
+        } else if state
+            .pcbs
+            .values()
+            .any(|pcb| pcb.local == local && pcb.foreign == foreign)
+        {
+            return Err(NetError::AlreadyExists(format!(
+                "connection {} => {}",
+                local, foreign
+            )));
+        }
+
+        let mut pcb = TcpPcb::new(TcpState::SynSent, local, foreign, self.clock.clone());
+        pcb.iss = initial_seq();
+        pcb.snd.una = pcb.iss;
+        pcb.snd.nxt = pcb.iss.wrapping_add(1);
+        if state.timestamps {
+            // Offered; dropped again if the SYN/ACK does not carry the option
+            pcb.ts = Some(Timestamps::new(initial_seq(), 0, pcb.clock.now()));
+        }
+        pcb.auth = auth;
+        TcpTimeline::mark(&mut pcb.timeline.syn_sent, pcb.clock.now());
+        // With `retransmit` the timer resends the SYN; otherwise the caller does
+        let syn = if retransmit {
+            pcb.emit(pcb.iss, TCP_FLG_SYN, &[])
+        } else {
+            pcb.reply(pcb.iss, TCP_FLG_SYN)
+        };
+        let id = state.alloc(pcb)?;
+        tracing::debug!("tcp_connect: id={}, {} => {}", id, local, foreign);
+        Ok((id, syn))
+    }
+
+    /// Wait until the control block leaves `from` or `deadline` passes;
+    /// returns the current state (`None` once the block is gone)
+    fn wait_state_change(
+        &self,
+        id: TcpPcbId,
+        from: TcpState,
+        deadline: Instant,
+    ) -> Option<TcpState> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let current = state.pcbs.get(&id).map(|pcb| pcb.state)?;
+            let now = Instant::now();
+            if current != from || now >= deadline {
+                return Some(current);
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Local endpoint of the control block
+    pub fn local(&self, id: TcpPcbId) -> Option<IpEndpoint> {
+        self.state
+            .lock()
+            .unwrap()
+            .pcbs
+            .get(&id)
+            .map(|pcb| pcb.local)
+    }
+
+    /// Remote endpoint of the control block; unspecified for a listener
+    pub fn foreign(&self, id: TcpPcbId) -> Option<IpEndpoint> {
+        self.state
+            .lock()
+            .unwrap()
+            .pcbs
+            .get(&id)
+            .map(|pcb| pcb.foreign)
+    }
+
+    /// Sign and check every segment of the connection (or of the connections a
+    /// listener accepts from now on) with `auth`; `None` turns it off
+    pub fn set_auth(&self, id: TcpPcbId, auth: Option<Arc<dyn TcpAuth>>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(pcb) = state.pcbs.get_mut(&id) else {
+            return Err(NetError::NotFound(format!("TCP control block {}", id)));
+        };
+        pcb.auth = auth;
+        Ok(())
+    }
+
+    /// Use `cc` for the connection's congestion control instead of Reno
+    pub fn set_congestion_control(
+        &self,
+        id: TcpPcbId,
+        mut cc: Box<dyn CongestionControl>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(pcb) = state.pcbs.get_mut(&id) else {
+            return Err(NetError::NotFound(format!("TCP control block {}", id)));
+        };
+        cc.init(pcb.mss);
+        tracing::debug!("tcp: id={}, congestion control {}", id, cc.name());
+        pcb.cc = cc;
+        Ok(())
+    }
+
+    /// Congestion window of the connection in bytes
+    /// Round-trip time estimate and current RTO of the connection
+    pub fn rtt(&self, id: TcpPcbId) -> Option<RttEstimator> {
+        self.state.lock().unwrap().pcbs.get(&id).map(|pcb| pcb.rtt)
+    }
+
+    pub fn cwnd(&self, id: TcpPcbId) -> Option<u32> {
+        self.state
+            .lock()
+            .unwrap()
+            .pcbs
+            .get(&id)
+            .map(|pcb| pcb.cc.cwnd())
+    }
+
+    pub fn state(&self, id: TcpPcbId) -> Option<TcpState> {
+        self.state
+            .lock()
+            .unwrap()
+            .pcbs
+            .get(&id)
+            .map(|pcb| pcb.state)
+    }
+
+    /// Poll events ([`POLLIN`], [`POLLOUT`], [`POLLHUP`]) the control block is
+    /// ready for; `None` once it is gone
+    pub fn poll_events(&self, id: TcpPcbId) -> Option<u16> {
+        let state = self.state.lock().unwrap();
+        let pcb = state.pcbs.get(&id)?;
+        let mut events = 0;
+        match pcb.state {
+            TcpState::Listen => {
+                if !pcb.accept_queue.is_empty() {
+                    events |= POLLIN;
+                }
+            }
+            TcpState::SynSent | TcpState::SynReceived => {}
+            _ => {
+                let open = matches!(
+                    pcb.state,
+                    TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+                );
+                // End of stream is readable too
+                if pcb.read_shutdown || !pcb.rcvbuf.is_empty() || !open {
+                    events |= POLLIN;
+                }
+                if matches!(pcb.state, TcpState::Established | TcpState::CloseWait)
+                    && pcb.usable_window() > 0
+                {
+                    events |= POLLOUT;
+                }
+                if pcb.state == TcpState::Closed {
+                    events |= POLLHUP;
+                }
+            }
+        }
+        Some(events)
+    }
+
+    /// Wake `waker` the next time a control block changes; for futures that
+    /// found control block `id` not ready
+    pub fn register_waker(&self, id: TcpPcbId, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|(i, w)| *i == id && w.will_wake(waker)) {
+            wakers.push((id, waker.clone()));
+        }
+    }
+
+    /// Interrupts so far; calls blocked across one give up
+    pub fn interrupts(&self) -> u64 {
+        self.state.lock().unwrap().interrupts
+    }
+
+    /// Wake up blocked callers, pollers and waiting tasks
+    /// Wake every call blocked in accept, send or receive with [`NetError::Interrupted`]
+    pub fn interrupt(&self) {
+        self.state.lock().unwrap().interrupts += 1;
+        self.notify();
+    }
+
+    fn notify(&self) {
+        self.changed.notify_all();
+        READINESS.notify();
+        for (_, waker) in self.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Segments sent again, by open and released connections alike
+    pub fn retransmissions(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.retransmissions
+            + state
+                .pcbs
+                .values()
+                .map(|pcb| pcb.retransmissions)
+                .sum::<u64>()
+    }
+
+    /// Note a segment resent outside the timer (a blocking connect's SYN)
+    fn count_retransmission(&self, id: TcpPcbId) {
+        if let Some(pcb) = self.state.lock().unwrap().pcbs.get_mut(&id) {
+            pcb.retransmissions += 1;
+        }
+    }
+
+    /// Number of sent segments waiting to be acknowledged
+    pub fn unacked(&self, id: TcpPcbId) -> Option<usize> {
+        self.state
+            .lock()
+            .unwrap()
+            .pcbs
+            .get(&id)
+            .map(|pcb| pcb.retransmit.len())
+    }
+
+    /// Every control block as a [`ConnectionInfo`], oldest first. A listener's
+    /// receive queue counts the connections waiting to be accepted.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let state = self.state.lock().unwrap();
+        let mut pcbs: Vec<_> = state.pcbs.iter().collect();
+        pcbs.sort_by_key(|(id, _)| id.0);
+        pcbs.into_iter()
+            .map(|(_, pcb)| ConnectionInfo {
+                protocol: IpProtocol::Tcp,
+                local: pcb.local,
+                foreign: (pcb.foreign != IpEndpoint::default()).then_some(pcb.foreign),
+                state: Some(pcb.state),
+                recv_queue: if pcb.state == TcpState::Listen {
+                    pcb.accept_queue.len()
+                } else {
+                    pcb.rcvbuf.len()
+                },
+                send_queue: pcb.retransmit.iter().map(|e| e.data.len()).sum(),
+            })
+            .collect()
+    }
+
+    /// Queue as much of `data` as the window allows, waiting up to `timeout`
+    /// (forever if `None`) while it is full
+    fn send_start(
+        &self,
+        id: TcpPcbId,
+        data: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<(usize, Vec<Outgoing>)> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        let interrupts = state.interrupts;
+        let usable = loop {
+            let Some(pcb) = state.pcbs.get(&id) else {
+                return Err(NetError::NotFound(format!("TCP control block {}", id)));
+            };
+            match pcb.state {
+                TcpState::Established | TcpState::CloseWait => {}
+                TcpState::Closed | TcpState::Listen | TcpState::SynSent | TcpState::SynReceived => {
+                    return Err(NetError::NotConnected);
+                }
+                _ => return Err(NetError::Closed),
+            }
+            let usable = pcb.usable_window();
+            if usable > 0 || data.is_empty() || timeout == Some(Duration::ZERO) {
+                break usable;
+            }
+            // No persist timer: a lost window update leaves the sender waiting here
+            state = match wait_until(&self.changed, state, deadline) {
+                Some(state) => state,
+                None => {
+                    return Err(NetError::TimedOut);
+                }
+            };
+            if state.interrupts != interrupts {
+                return Err(NetError::Interrupted);
+            }
+        };
+        let Some(pcb) = state.pcbs.get_mut(&id) else {
+            return Err(NetError::NotFound(format!("TCP control block {}", id)));
+        };
+
+        let len = data.len().min(usable);
+        let chunks: Vec<&[u8]> = data[..len].chunks(pcb.payload_mss()).collect();
+        let mut segments = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut flags = TCP_FLG_ACK;
+            if i + 1 == chunks.len() {
+                flags |= TCP_FLG_PSH;
+            }
+            segments.push(pcb.emit(pcb.snd.nxt, flags, chunk));
+            pcb.snd.nxt = pcb.snd.nxt.wrapping_add(chunk.len() as u32);
+        }
+        tracing::debug!(
+            "tcp_send: id={}, len={}/{}, segments={}",
+            id,
+            len,
+            data.len(),
+            segments.len()
+        );
+        Ok((len, segments))
+    }
+
+    /// Wait up to `timeout` (forever if `None`) for received data and copy it to `buf`;
+    /// returns a window update to send if reading reopened a window smaller
+    /// than one segment
+    fn receive_start(
+        &self,
+        id: TcpPcbId,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<(usize, Option<Outgoing>)> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        let interrupts = state.interrupts;
+        loop {
+            let Some(pcb) = state.pcbs.get_mut(&id) else {
+                return Err(NetError::NotFound(format!("TCP control block {}", id)));
+            };
+            if pcb.read_shutdown {
+                return Ok((0, None));
+            }
+            if !pcb.rcvbuf.is_empty() {
+                let len = buf.len().min(pcb.rcvbuf.len());
+                for (dst, src) in buf.iter_mut().zip(pcb.rcvbuf.drain(..len)) {
+                    *dst = src;
+                }
+                let before = pcb.rcv.wnd;
+                pcb.rcv.wnd = (TCP_RECV_BUFFER_SIZE - pcb.rcvbuf.len()) as u16;
+                let update = (before < pcb.mss && pcb.rcv.wnd >= pcb.mss).then(|| {
+                    pcb.clear_delayed_ack();
+                    pcb.reply(pcb.snd.nxt, TCP_FLG_ACK)
+                });
+                return Ok((len, update));
+            }
+            if !matches!(
+                pcb.state,
+                TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2
+            ) {
+                // The peer closed (or the connection is gone): end of stream
+                return Ok((0, None));
+            }
+            if timeout == Some(Duration::ZERO) {
+                return Err(NetError::WouldBlock);
+            }
+            state = match wait_until(&self.changed, state, deadline) {
+                Some(state) => state,
+                None => {
+                    return Err(NetError::TimedOut);
+                }
+            };
+            if state.interrupts != interrupts {
+                return Err(NetError::Interrupted);
+            }
+        }
+    }
+
+    /// Collect segments whose RTO expired at `now`, doubling their RTO.
+    /// Connections with a segment unacknowledged past the deadline are closed.
+    fn retransmit_expired(&self, now: Instant) -> Vec<Outgoing> {
+        let mut state = self.state.lock().unwrap();
+        let mut out = Vec::new();
+        let mut changed = false;
+        let mut abandoned = Vec::new();
+        for (&id, pcb) in state.pcbs.iter_mut() {
+            if pcb
+                .retransmit
+                .front()
+                .is_some_and(|entry| now.duration_since(entry.first) >= TCP_RETRANSMIT_DEADLINE)
+            {
+                tracing::debug!("tcp: id={}, retransmission deadline exceeded", id);
+                if pcb.parent.is_some() && pcb.state == TcpState::SynReceived {
+                    // Half-open connection of a listener that nobody will accept
+                    abandoned.push(id);
+                }
+                pcb.retransmit.clear();
+                pcb.set_state(id, TcpState::Closed, TcpEvent::Timeout);
+                changed = true;
+                continue;
+            }
+            let in_flight = pcb.in_flight();
+            let mut lost = false;
+            let mut resend = Vec::new();
+            for entry in pcb.retransmit.iter_mut() {
+                if now.duration_since(entry.last) < entry.rto {
+                    continue;
+                }
+                if !lost {
+                    lost = true;
+                    pcb.cc.on_loss(LossEvent::Timeout, in_flight);
+                    pcb.dup_acks = 0;
+                    pcb.rtt.backoff();
+                }
+                entry.last = now;
+                entry.rto = (entry.rto * 2).min(TCP_RTO_MAX);
+                entry.retransmitted = true;
+                resend.push((entry.seq, entry.flags, entry.data.clone()));
+                tracing::debug!("tcp: id={}, retransmit seq={}", id, entry.seq);
+            }
+            pcb.retransmissions += resend.len() as u64;
+            for (seq, flags, data) in resend {
+                let mut seg = pcb.reply(seq, flags);
+                seg.data = data;
+                out.push(seg);
+            }
+        }
+        for id in abandoned {
+            state.remove(id, TcpEvent::Timeout);
+        }
+        if changed {
+            self.notify();
+        }
+        out
+    }
+
+    /// Control block an inbound segment from `foreign` to `local` would be delivered to
+    pub fn select(&self, local: IpEndpoint, foreign: IpEndpoint) -> Option<TcpPcbId> {
+        self.state.lock().unwrap().select(local, foreign)
+    }
+
+    /// Run the state machine for an inbound segment and return segments to send
+    fn segment_arrives(
+        &self,
+        local: IpEndpoint,
+        foreign: IpEndpoint,
+        seg: &SegmentInfo,
+    ) -> Result<Vec<Outgoing>, DropReason> {
+        let mut state = self.state.lock().unwrap();
+        let Some(id) = state.select(local, foreign) else {
+            tracing::debug!("tcp_input: no control block for {} => {}", foreign, local);
+            return Err(DropReason::NoSocket);
+        };
+        let timestamps = state.timestamps;
+        let pcb = state.pcbs.get_mut(&id).ok_or(DropReason::NoSocket)?;
+        if let Some(auth) = &pcb.auth
+            && !verify_auth(seg.raw, foreign, local, auth.as_ref())
+        {
+            tracing::debug!("tcp_input: id={}, authentication failed", id);
+            return Err(DropReason::TcpAuth);
+        }
+        let event = TcpEvent::Segment(seg.flags);
+        if !matches!(pcb.state, TcpState::Listen | TcpState::SynSent)
+            && let Some(out) = pcb.check_timestamp(id, seg)
+        {
+            return Ok(out);
+        }
+
+        Ok(match pcb.state {
+            TcpState::Listen => {
+                if seg.has(TCP_FLG_ACK) {
+                    // Nothing was sent to acknowledge yet
+                    return Ok(Outgoing::reset(local, foreign, seg).into_iter().collect());
+                }
+                if seg.has(TCP_FLG_RST) || !seg.has(TCP_FLG_SYN) {
+                    return Ok(Vec::new());
+                }
+                // A listener with a backlog stays in LISTEN and hands the connection
+                // to a new control block
+                let auth = pcb.auth.clone();
+                let (id, pcb) = match pcb.backlog {
+                    None => (id, pcb),
+                    Some(backlog) => {
+                        if state.pending(id) >= backlog {
+                            tracing::debug!("tcp_input: id={}, backlog full, SYN dropped", id);
+                            return Err(DropReason::BacklogFull);
+                        }
+                        let mut child =
+                            TcpPcb::new(TcpState::Listen, local, foreign, self.clock.clone());
+                        child.parent = Some(id);
+                        child.auth = auth;
+                        let child = state.alloc(child).map_err(|_| DropReason::NoSocket)?;
+                        (child, state.pcbs.get_mut(&child).unwrap())
+                    }
+                };
+                TcpTimeline::mark(&mut pcb.timeline.syn_received, pcb.clock.now());
+                pcb.local = local;
+                pcb.foreign = foreign;
+                pcb.rcv.nxt = seg.seq.wrapping_add(1);
+                pcb.irs = seg.seq;
+                pcb.iss = initial_seq();
+                pcb.snd.una = pcb.iss;
+                pcb.snd.nxt = pcb.iss.wrapping_add(1);
+                pcb.snd.wnd = seg.wnd;
+                pcb.snd.wl1 = seg.seq;
+                pcb.snd.wl2 = seg.ack;
+                if let Some(mss) = seg.mss {
+                    pcb.mss = mss;
+                }
+                pcb.ts = match seg.timestamp {
+                    Some((val, _)) if timestamps => {
+                        Some(Timestamps::new(initial_seq(), val, pcb.clock.now()))
+                    }
+                    _ => None,
+                };
+                pcb.set_state(id, TcpState::SynReceived, event);
+                vec![pcb.emit(pcb.iss, TCP_FLG_SYN | TCP_FLG_ACK, &[])]
+            }
+            TcpState::SynSent => {
+                let acceptable = seg.has(TCP_FLG_ACK)
+                    && seq_lt(pcb.iss, seg.ack)
+                    && seq_le(seg.ack, pcb.snd.nxt);
+                if seg.has(TCP_FLG_ACK) && !acceptable {
+                    return Ok(Outgoing::reset(local, foreign, seg).into_iter().collect());
+                }
+                if seg.has(TCP_FLG_RST) {
+                    if acceptable {
+                        tracing::debug!("tcp: id={}, connection refused", id);
+                        pcb.set_state(id, TcpState::Closed, event);
+                        self.notify();
+                    }
+                    return Ok(Vec::new());
+                }
+                if !seg.has(TCP_FLG_SYN) {
+                    return Ok(Vec::new());
+                }
+                pcb.rcv.nxt = seg.seq.wrapping_add(1);
+                pcb.irs = seg.seq;
+                pcb.snd.wnd = seg.wnd;
+                pcb.snd.wl1 = seg.seq;
+                pcb.snd.wl2 = seg.ack;
+                if let Some(mss) = seg.mss {
+                    pcb.mss = mss;
+                }
+                match (&mut pcb.ts, seg.timestamp) {
+                    (Some(ts), Some((val, _))) => {
+                        ts.recent = val;
+                        ts.recent_at = pcb.clock.now();
+                    }
+                    _ => pcb.ts = None,
+                }
+                self.notify();
+                if acceptable {
+                    TcpTimeline::mark(&mut pcb.timeline.syn_ack_received, pcb.clock.now());
+                    pcb.acknowledge(seg);
+                    pcb.set_state(id, TcpState::Established, event);
+                    vec![pcb.reply(pcb.snd.nxt, TCP_FLG_ACK)]
+                } else {
+                    // Simultaneous open
+                    TcpTimeline::mark(&mut pcb.timeline.syn_received, pcb.clock.now());
+                    pcb.set_state(id, TcpState::SynReceived, event);
+                    vec![pcb.emit(pcb.iss, TCP_FLG_SYN | TCP_FLG_ACK, &[])]
+                }
+            }
+            TcpState::SynReceived => {
+                if seg.has(TCP_FLG_RST) && pcb.parent.is_some() {
+                    // The listener carries on without it (RFC 9293 Section 3.10.7.4)
+                    state.remove(id, event);
+                    return Ok(Vec::new());
+                }
+                if !seg.has(TCP_FLG_ACK) {
+                    return Ok(Vec::new());
+                }
+                if !(seq_le(pcb.snd.una, seg.ack) && seq_le(seg.ack, pcb.snd.nxt)) {
+                    return Ok(Outgoing::reset(local, foreign, seg).into_iter().collect());
+                }
+                pcb.acknowledge(seg);
+                pcb.snd.wnd = seg.wnd;
+                pcb.snd.wl1 = seg.seq;
+                pcb.snd.wl2 = seg.ack;
+                pcb.set_state(id, TcpState::Established, event);
+                // The ACK completing the handshake may already carry data or a FIN
+                let out = pcb.synchronized_arrives(id, seg);
+                if let Some(parent) = pcb.parent
+                    && let Some(listener) = state.pcbs.get_mut(&parent)
+                {
+                    listener.accept_queue.push_back(id);
+                }
+                self.notify();
+                out
+            }
+            TcpState::Established
+            | TcpState::FinWait1
+            | TcpState::FinWait2
+            | TcpState::Closing
+            | TcpState::TimeWait
+            | TcpState::CloseWait
+            | TcpState::LastAck => {
+                let closing = pcb.state == TcpState::LastAck;
+                let out = pcb.synchronized_arrives(id, seg);
+                if closing && pcb.state == TcpState::Closed && pcb.closed {
+                    // Both sides are done; nobody holds on to this block any more
+                    state.remove(id, event);
+                }
+                self.notify();
+                out
+            }
+            TcpState::Closed => {
+                tracing::debug!("tcp_input: id={}, connection closed, seq={}", id, seg.seq);
+                Outgoing::reset(local, foreign, seg).into_iter().collect()
+            }
+        })
+    }
+}
+
+impl Default for TcpPcbTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::bytes::Frame;
+    use crate::device::DeviceIndex;
+    use crate::protocol::ip::IP_HDR_SIZE_MIN;
+    use crate::stack::NetStack;
+    use crate::testing::setup_loopback;
+
+    fn ep(s: &str) -> IpEndpoint {
+        IpEndpoint::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_tcp_build_parse_roundtrip() {
+        let mut options = Vec::new();
+        TcpOption::Mss(1460).encode(&mut options);
+        TcpOption::WindowScale(7).encode(&mut options);
+
+        let src = ep("192.0.2.1:49152");
+        let dst = ep("192.0.2.2:80");
+        let seg = TcpSegment {
+            seq: 0x01020304,
+            ack: 0,
+            flags: TCP_FLG_SYN,
+            wnd: 65535,
+            options: &options,
+            data: b"",
+        };
+        let buf = build(src, dst, &seg).unwrap();
+        assert_eq!(buf.len(), TCP_HDR_SIZE_MIN + 8);
+
+        let hdr = TcpHdr::from_bytes(&buf).unwrap();
+        assert_eq!(hdr.src(), 49152);
+        assert_eq!(hdr.dst(), 80);
+        assert_eq!(hdr.seq(), 0x01020304);
+        assert_eq!(hdr.hdr_len(), TCP_HDR_SIZE_MIN + 8);
+        assert!(hdr.has(TCP_FLG_SYN));
+        assert!(!hdr.has(TCP_FLG_ACK));
+        assert_eq!(flags_ntoa(hdr.flg()), "------S-");
+        assert_eq!(
+            parse_options(&buf[TCP_HDR_SIZE_MIN..hdr.hdr_len()]).unwrap(),
+            vec![TcpOption::Mss(1460), TcpOption::WindowScale(7)]
+        );
+        assert_eq!(
+            cksum16(
+                &buf,
+                ip::pseudo_sum(src.addr, dst.addr, IpProtocol::Tcp, buf.len())
+            ),
+            0
+        );
+    }
+
+    fn tcp_option() -> impl Strategy<Value = TcpOption> {
+        prop_oneof![
+            any::<u16>().prop_map(TcpOption::Mss),
+            any::<u8>().prop_map(TcpOption::WindowScale),
+            Just(TcpOption::SackPermitted),
+            any::<(u32, u32)>().prop_map(|(val, ecr)| TcpOption::Timestamp { val, ecr }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_tcp_hdr_bytes_roundtrip(bytes in any::<[u8; TCP_HDR_SIZE_MIN]>()) {
+            let hdr = TcpHdr::from_bytes(&bytes).unwrap();
+            prop_assert_eq!(hdr.to_bytes(), bytes);
+        }
+
+        #[test]
+        fn test_tcp_build_parse_any_segment(
+            (src_addr, src_port, dst_addr, dst_port) in any::<([u8; 4], u16, [u8; 4], u16)>(),
+            (seq, ack, flags, wnd) in any::<(u32, u32, u8, u16)>(),
+            // At most 40 bytes of options: four of the longest (10 bytes) fit
+            opts in vec(tcp_option(), 0..=4),
+            data in vec(any::<u8>(), 0..1024),
+        ) {
+            let src = IpEndpoint::new(IpAddr::from_ne_bytes(src_addr), src_port);
+            let dst = IpEndpoint::new(IpAddr::from_ne_bytes(dst_addr), dst_port);
+            let mut options = Vec::new();
+            for opt in &opts {
+                opt.encode(&mut options);
+            }
+            let seg = TcpSegment { seq, ack, flags, wnd, options: &options, data: &data };
+            let buf = build(src, dst, &seg).unwrap();
+
+            let hdr = TcpHdr::from_bytes(&buf).unwrap();
+            prop_assert_eq!(hdr.src(), src_port);
+            prop_assert_eq!(hdr.dst(), dst_port);
+            prop_assert_eq!(hdr.seq(), seq);
+            prop_assert_eq!(hdr.ack(), ack);
+            prop_assert_eq!(hdr.flg(), flags);
+            prop_assert_eq!(hdr.wnd(), wnd);
+            prop_assert_eq!(hdr.hdr_len(), TCP_HDR_SIZE_MIN + options.len().next_multiple_of(4));
+            prop_assert_eq!(parse_options(&buf[TCP_HDR_SIZE_MIN..hdr.hdr_len()]).unwrap(), opts);
+            prop_assert_eq!(&buf[hdr.hdr_len()..], &data[..]);
+            let pseudo = ip::pseudo_sum(src.addr, dst.addr, IpProtocol::Tcp, buf.len());
+            prop_assert_eq!(cksum16(&buf, pseudo), 0);
+        }
+    }
+
+    #[test]
+    fn test_tcp_hdr_codec_unaligned() {
+        let hdr = TcpHdr {
+            src: 49152,
+            dst: 80,
+            seq: 0x01020304,
+            ack: 0xa0b0c0d0,
+            off: 5 << 4,
+            flg: TCP_FLG_SYN | TCP_FLG_ACK,
+            wnd: 1024,
+            sum: 0xbeef,
+            up: 0,
+        };
+        let bytes = hdr.to_bytes();
+        assert_eq!(&bytes[4..8], &[1, 2, 3, 4]);
+        assert_eq!(&bytes[16..18], &[0xbe, 0xef]);
+
+        // At an odd offset, as after a 1-byte prefix in a receive buffer
+        let mut buf = vec![0xff];
+        buf.extend_from_slice(&bytes);
+        assert_eq!(TcpHdr::from_bytes(&buf[1..]), Some(hdr));
+        assert_eq!(TcpHdr::from_bytes(&buf[1..TCP_HDR_SIZE_MIN]), None);
+    }
+
+    fn feed(packet: &[u8], ctx: &ProtocolContexts, devices: &DeviceManager) {
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        ip::ip_input(&Frame::Borrowed(packet), dev, ctx, devices).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_select_prefers_exact_match() {
+        let table = TcpPcbTable::new();
+        let any = table.listen(ep("0.0.0.0:80"), None).unwrap();
+        let peer = ep("192.0.2.9:40000");
+        let specific = table.listen(ep("192.0.2.1:80"), Some(peer)).unwrap();
+        assert!(table.listen(ep("192.0.2.1:80"), None).is_err());
+
+        assert_eq!(table.select(ep("192.0.2.1:80"), peer), Some(specific));
+        assert_eq!(
+            table.select(ep("192.0.2.1:80"), ep("192.0.2.8:40000")),
+            Some(any)
+        );
+        assert_eq!(table.select(ep("192.0.2.1:81"), peer), None);
+    }
+
+    #[test]
+    fn test_tcp_passive_open_handshake() {
+        let (devices, ctx, captured) = setup_loopback();
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = ep("127.0.0.1:40000");
+        let local = ep("127.0.0.1:80");
+
+        let mut mss = Vec::new();
+        TcpOption::Mss(1460).encode(&mut mss);
+        let syn = TcpSegment {
+            seq: 1000,
+            flags: TCP_FLG_SYN,
+            wnd: 8192,
+            options: &mss,
+            ..Default::default()
+        };
+        output(client, local, &syn, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::SynReceived));
+
+        // SYN/ACK back to the client
+        let packet = captured.lock().unwrap().pop().unwrap();
+        let hdr = TcpHdr::from_bytes(&packet[IP_HDR_SIZE_MIN..]).unwrap();
+        assert_eq!(hdr.flg(), TCP_FLG_SYN | TCP_FLG_ACK);
+        assert_eq!(hdr.ack(), 1001);
+        assert_eq!((hdr.src(), hdr.dst()), (80, 40000));
+
+        let ack = TcpSegment {
+            seq: 1001,
+            ack: hdr.seq().wrapping_add(1),
+            flags: TCP_FLG_ACK,
+            wnd: 8192,
+            ..Default::default()
+        };
+        output(client, local, &ack, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Established));
+        assert!(ctx.tcp.rtt(server).unwrap().srtt.is_some());
+        assert_eq!(ctx.tcp.select(local, client), Some(server));
+        assert_eq!(ctx.tcp.select(local, ep("127.0.0.1:40001")), None);
+    }
+
+    #[test]
+    fn test_tcp_timestamps() {
+        let (devices, ctx, captured) = setup_loopback();
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = ep("127.0.0.1:40000");
+        let local = ep("127.0.0.1:80");
+        let timestamp = |val: u32, ecr: u32| {
+            let mut options = vec![TCP_OPT_NOP, TCP_OPT_NOP];
+            TcpOption::Timestamp { val, ecr }.encode(&mut options);
+            options
+        };
+        // Send a segment to the server and return its answer, if any
+        let send = |seg: &TcpSegment| {
+            output(client, local, seg, &ctx, &devices).unwrap();
+            let packet = captured.lock().unwrap().pop().unwrap();
+            feed(&packet, &ctx, &devices);
+            captured.lock().unwrap().pop()
+        };
+        let received = || ctx.tcp.state.lock().unwrap().pcbs[&server].rcvbuf.len();
+
+        let options = timestamp(100, 0);
+        let syn = TcpSegment {
+            seq: 1000,
+            flags: TCP_FLG_SYN,
+            wnd: 8192,
+            options: &options,
+            ..Default::default()
+        };
+        let syn_ack = send(&syn).unwrap();
+        let hdr = TcpHdr::from_bytes(&syn_ack[IP_HDR_SIZE_MIN..]).unwrap();
+        let opts = parse_options(&syn_ack[IP_HDR_SIZE_MIN + TCP_HDR_SIZE_MIN..][..12]).unwrap();
+        let TcpOption::Timestamp {
+            val: server_val,
+            ecr,
+        } = opts[0]
+        else {
+            panic!("no timestamps option: {:?}", opts);
+        };
+        assert_eq!(ecr, 100);
+
+        let options = timestamp(101, server_val);
+        let ack = TcpSegment {
+            seq: 1001,
+            ack: hdr.seq().wrapping_add(1),
+            flags: TCP_FLG_ACK,
+            wnd: 8192,
+            options: &options,
+            ..Default::default()
+        };
+        assert!(send(&ack).is_none());
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Established));
+        assert!(ctx.tcp.rtt(server).unwrap().srtt.is_some());
+
+        // PAWS: an older TSval is answered with an ACK and not accepted
+        let options = timestamp(50, server_val);
+        let stale = TcpSegment {
+            data: b"old",
+            options: &options,
+            ..ack
+        };
+        let reply = send(&stale).unwrap();
+        assert_eq!(
+            TcpHdr::from_bytes(&reply[IP_HDR_SIZE_MIN..]).unwrap().flg(),
+            TCP_FLG_ACK
+        );
+        assert_eq!(received(), 0);
+
+        // Once agreed, segments must carry the option
+        let bare = TcpSegment {
+            options: &[],
+            ..stale
+        };
+        assert!(send(&bare).is_none());
+        assert_eq!(received(), 0);
+
+        let options = timestamp(102, server_val);
+        let fresh = TcpSegment {
+            options: &options,
+            ..stale
+        };
+        send(&fresh);
+        assert_eq!(received(), 3);
+    }
+
+    #[test]
+    fn test_tcp_timestamps_not_offered() {
+        let (devices, ctx, captured) = setup_loopback();
+        ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let syn = TcpSegment {
+            seq: 1000,
+            flags: TCP_FLG_SYN,
+            wnd: 8192,
+            ..Default::default()
+        };
+        output(
+            ep("127.0.0.1:40000"),
+            ep("127.0.0.1:80"),
+            &syn,
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        let syn_ack = captured.lock().unwrap().pop().unwrap();
+        let hdr = TcpHdr::from_bytes(&syn_ack[IP_HDR_SIZE_MIN..]).unwrap();
+        assert_eq!(hdr.hdr_len(), TCP_HDR_SIZE_MIN);
+    }
+
+    /// Keyed FNV-1a, standing in for a real MAC
+    struct Fnv(u64);
+
+    impl TcpAuth for Fnv {
+        fn name(&self) -> &'static str {
+            "fnv"
+        }
+
+        fn kind(&self) -> u8 {
+            253
+        }
+
+        fn mac_len(&self) -> usize {
+            8
+        }
+
+        fn mac(&self, seg: &AuthSegment) -> Vec<u8> {
+            let mut hash = 0xcbf29ce484222325 ^ self.0;
+            let addrs = [seg.src.addr, seg.dst.addr].map(|addr| addr.to_string());
+            for bytes in [
+                addrs[0].as_bytes(),
+                addrs[1].as_bytes(),
+                seg.header,
+                seg.data,
+            ] {
+                for &b in bytes {
+                    hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+                }
+            }
+            hash.to_be_bytes().to_vec()
+        }
+    }
+
+    #[test]
+    fn test_tcp_auth() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        let listener = ctx.tcp.listen_backlog(ep("0.0.0.0:179"), 4).unwrap();
+        ctx.tcp.set_auth(listener, Some(Arc::new(Fnv(1)))).unwrap();
+
+        // Segments without the option, or with a MAC under another key, are dropped
+        let timeout = Duration::from_millis(50);
+        assert!(connect(None, ep("127.0.0.1:179"), timeout, &ctx, &devices).is_err());
+        let wrong = Some(Arc::new(Fnv(2)) as Arc<dyn TcpAuth>);
+        assert!(connect_auth(None, ep("127.0.0.1:179"), timeout, wrong, &ctx, &devices).is_err());
+        assert!(ctx.drops.count(DropReason::TcpAuth) >= 2);
+        assert_eq!(
+            ctx.tcp.accept(listener, Some(Duration::ZERO)).unwrap(),
+            None
+        );
+
+        let key = Some(Arc::new(Fnv(1)) as Arc<dyn TcpAuth>);
+        let client = connect_auth(
+            None,
+            ep("127.0.0.1:179"),
+            Duration::from_secs(1),
+            key,
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let server = ctx.tcp.accept(listener, None).unwrap().unwrap();
+        send(client, b"OPEN", &ctx, &devices).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(receive(server, &mut buf, &ctx, &devices).unwrap(), 4);
+        // The option takes room from every segment's payload
+        let pcb_mss = |id| ctx.tcp.state.lock().unwrap().pcbs[&id].payload_mss();
+        assert_eq!(pcb_mss(client), TCP_DEFAULT_MSS as usize - 24);
+    }
+
+    #[test]
+    fn test_tcp_reset_for_closed_port() {
+        let (devices, ctx, captured) = setup_loopback();
+        let client = ep("127.0.0.1:40000");
+        let closed = ep("127.0.0.1:81");
+        let send = |seg: &TcpSegment| {
+            output(client, closed, seg, &ctx, &devices).unwrap();
+            let packet = captured.lock().unwrap().pop().unwrap();
+            feed(&packet, &ctx, &devices);
+            captured.lock().unwrap().pop()
+        };
+
+        let syn = TcpSegment {
+            seq: 1000,
+            flags: TCP_FLG_SYN,
+            wnd: 8192,
+            ..Default::default()
+        };
+        let packet = send(&syn).unwrap();
+        let hdr = TcpHdr::from_bytes(&packet[IP_HDR_SIZE_MIN..]).unwrap();
+        assert_eq!(hdr.flg(), TCP_FLG_RST | TCP_FLG_ACK);
+        assert_eq!((hdr.seq(), hdr.ack()), (0, 1001));
+        assert_eq!((hdr.src(), hdr.dst()), (81, 40000));
+
+        let ack = TcpSegment {
+            seq: 1001,
+            ack: 5000,
+            flags: TCP_FLG_ACK,
+            wnd: 8192,
+            data: b"data",
+            ..Default::default()
+        };
+        let packet = send(&ack).unwrap();
+        let hdr = TcpHdr::from_bytes(&packet[IP_HDR_SIZE_MIN..]).unwrap();
+        assert_eq!(hdr.flg(), TCP_FLG_RST);
+        assert_eq!(hdr.seq(), 5000);
+
+        let rst = TcpSegment {
+            seq: 1001,
+            flags: TCP_FLG_RST,
+            ..Default::default()
+        };
+        assert!(send(&rst).is_none());
+    }
+
+    #[test]
+    fn test_tcp_connect_refused() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        let started = Instant::now();
+        let err = connect(
+            None,
+            ep("127.0.0.1:81"),
+            Duration::from_secs(5),
+            &ctx,
+            &devices,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("refused"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_tcp_active_open_loopback() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = connect(
+            None,
+            ep("127.0.0.1:80"),
+            Duration::from_secs(1),
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        assert_eq!(ctx.tcp.state(client), Some(TcpState::Established));
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Established));
+
+        let timeline = ctx.tcp.timeline(client).unwrap();
+        assert!(timeline.syn_sent.is_some() && timeline.syn_ack_received.is_some());
+        assert!(timeline.handshake().is_some());
+        assert!(timeline.time_to_first_byte().is_none());
+        let timeline = ctx.tcp.timeline(server).unwrap();
+        assert!(timeline.syn_received.is_some() && timeline.syn_ack_sent.is_some());
+        assert!(timeline.syn_sent.is_none());
+
+        let report = ctx.tcp.report();
+        assert_eq!(report.lines().count(), 3);
+        assert!(report.contains("Established"));
+    }
+
+    #[test]
+    fn test_tcp_listen_backlog() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+        let dial = || {
+            connect(
+                None,
+                ep("127.0.0.1:80"),
+                Duration::from_millis(50),
+                &ctx,
+                &devices,
+            )
+        };
+
+        let listener = ctx.tcp.listen_backlog(ep("0.0.0.0:80"), 1).unwrap();
+        let client = dial().unwrap();
+        assert_eq!(ctx.tcp.state(listener), Some(TcpState::Listen));
+
+        // The unaccepted connection fills the backlog
+        assert!(dial().unwrap_err().to_string().contains("timed out"));
+        assert!(ctx.drops.count(DropReason::BacklogFull) > 0);
+
+        let server = ctx
+            .tcp
+            .accept(listener, Some(Duration::ZERO))
+            .unwrap()
+            .unwrap();
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Established));
+        assert_eq!(
+            ctx.tcp
+                .select(ep("127.0.0.1:80"), ctx.tcp.local(client).unwrap()),
+            Some(server)
+        );
+        assert_eq!(
+            ctx.tcp.accept(listener, Some(Duration::ZERO)).unwrap(),
+            None
+        );
+
+        dial().unwrap();
+        assert!(
+            ctx.tcp
+                .accept(listener, Some(Duration::ZERO))
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_tcp_send_respects_peer_window() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = connect(
+            None,
+            ep("127.0.0.1:80"),
+            Duration::from_secs(1),
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+
+        // Only the server's receive buffer worth of data fits in the window
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let sent = send(client, &data, &ctx, &devices).unwrap();
+        assert_eq!(sent, TCP_RECV_BUFFER_SIZE);
+        assert_eq!(ctx.tcp.unacked(client), Some(0));
+
+        // Reading reopens the window, so the rest can be sent
+        let mut received = vec![0u8; data.len()];
+        let n = receive(server, &mut received, &ctx, &devices).unwrap();
+        assert_eq!(n, sent);
+        assert_eq!(
+            send(client, &data[sent..], &ctx, &devices).unwrap(),
+            data.len() - sent
+        );
+        let m = receive(server, &mut received[n..], &ctx, &devices).unwrap();
+        assert_eq!(n + m, data.len());
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn test_tcp_out_of_order_data_is_not_accepted() {
+        let (devices, ctx, captured) = setup_loopback();
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = ep("127.0.0.1:40000");
+        let local = ep("127.0.0.1:80");
+
+        let syn = TcpSegment {
+            seq: 1000,
+            flags: TCP_FLG_SYN,
+            wnd: 8192,
+            ..Default::default()
+        };
+        output(client, local, &syn, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        let syn_ack = captured.lock().unwrap().pop().unwrap();
+        let iss = TcpHdr::from_bytes(&syn_ack[IP_HDR_SIZE_MIN..])
+            .unwrap()
+            .seq();
+
+        // Data past RCV.NXT is answered with an ACK for what is expected
+        let seg = TcpSegment {
+            seq: 1005,
+            ack: iss.wrapping_add(1),
+            flags: TCP_FLG_ACK,
+            wnd: 8192,
+            data: b"late",
+            ..Default::default()
+        };
+        output(client, local, &seg, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Established));
+        let packet = captured.lock().unwrap().pop().unwrap();
+        let hdr = TcpHdr::from_bytes(&packet[IP_HDR_SIZE_MIN..]).unwrap();
+        assert_eq!(hdr.ack(), 1001);
+        assert_eq!(hdr.wnd() as usize, TCP_RECV_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_tcp_reset_must_match_rcv_nxt() {
+        let (devices, ctx, captured) = setup_loopback();
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = ep("127.0.0.1:40000");
+        let local = ep("127.0.0.1:80");
+
+        let syn = TcpSegment {
+            seq: 1000,
+            flags: TCP_FLG_SYN,
+            wnd: 8192,
+            ..Default::default()
+        };
+        output(client, local, &syn, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        let syn_ack = captured.lock().unwrap().pop().unwrap();
+        let iss = TcpHdr::from_bytes(&syn_ack[IP_HDR_SIZE_MIN..])
+            .unwrap()
+            .seq();
+        let ack = TcpSegment {
+            seq: 1001,
+            ack: iss.wrapping_add(1),
+            flags: TCP_FLG_ACK,
+            wnd: 8192,
+            ..Default::default()
+        };
+        output(client, local, &ack, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Established));
+        captured.lock().unwrap().clear();
+
+        let rst = |seq: u32| TcpSegment {
+            seq,
+            flags: TCP_FLG_RST,
+            ..Default::default()
+        };
+        // Outside the window: silently ignored
+        let outside = 1001u32.wrapping_add(TCP_RECV_BUFFER_SIZE as u32);
+        output(client, local, &rst(outside), &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Established));
+        assert!(captured.lock().unwrap().is_empty());
+
+        // In the window but not at RCV.NXT: challenge ACK
+        output(client, local, &rst(1100), &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Established));
+        let packet = captured.lock().unwrap().pop().unwrap();
+        let hdr = TcpHdr::from_bytes(&packet[IP_HDR_SIZE_MIN..]).unwrap();
+        assert_eq!(hdr.flg(), TCP_FLG_ACK);
+        assert_eq!(hdr.ack(), 1001);
+
+        // A zero-length ACK outside the window is not processed either
+        output(
+            client,
+            local,
+            &TcpSegment {
+                flags: TCP_FLG_ACK,
+                ..rst(outside)
+            },
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        let packet = captured.lock().unwrap().pop().unwrap();
+        let hdr = TcpHdr::from_bytes(&packet[IP_HDR_SIZE_MIN..]).unwrap();
+        assert_eq!(hdr.ack(), 1001);
+
+        // Exactly at RCV.NXT: the connection is reset
+        output(client, local, &rst(1001), &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        assert_ne!(ctx.tcp.state(server), Some(TcpState::Established));
+    }
+
+    #[test]
+    fn test_tcp_close_sequence() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = connect(
+            None,
+            ep("127.0.0.1:80"),
+            Duration::from_secs(1),
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let local = ctx.tcp.local(client).unwrap();
+        let server = ctx.tcp.select(ep("127.0.0.1:80"), local).unwrap();
+
+        send(client, b"bye", &ctx, &devices).unwrap();
+        close(client, &ctx, &devices).unwrap();
+        assert_eq!(ctx.tcp.state(client), Some(TcpState::FinWait2));
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::CloseWait));
+
+        // Data before the FIN is still delivered, then end of stream
+        let mut buf = [0u8; 16];
+        assert_eq!(receive(server, &mut buf, &ctx, &devices).unwrap(), 3);
+        assert_eq!(receive(server, &mut buf, &ctx, &devices).unwrap(), 0);
+
+        close(server, &ctx, &devices).unwrap();
+        assert_eq!(ctx.tcp.state(server), None);
+        assert_eq!(ctx.tcp.state(client), Some(TcpState::TimeWait));
+
+        let now = Instant::now();
+        ctx.tcp.time_wait_expired(now);
+        assert_eq!(ctx.tcp.state(client), Some(TcpState::TimeWait));
+        ctx.tcp.time_wait_expired(now + TCP_MSL * 2);
+        assert_eq!(ctx.tcp.state(client), None);
+    }
+
+    #[test]
+    fn test_tcp_shutdown() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = connect(
+            None,
+            ep("127.0.0.1:80"),
+            Duration::from_secs(1),
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let local = ctx.tcp.local(client).unwrap();
+        let server = ctx.tcp.select(ep("127.0.0.1:80"), local).unwrap();
+
+        // The request ends with our FIN, the response still comes back
+        send(client, b"GET /", &ctx, &devices).unwrap();
+        shutdown(client, Shutdown::Write, &ctx, &devices).unwrap();
+        shutdown(client, Shutdown::Write, &ctx, &devices).unwrap();
+        assert_eq!(ctx.tcp.state(client), Some(TcpState::FinWait2));
+        assert!(send(client, b"more", &ctx, &devices).is_err());
+        let mut buf = [0u8; 16];
+        assert_eq!(receive(server, &mut buf, &ctx, &devices).unwrap(), 5);
+        assert_eq!(receive(server, &mut buf, &ctx, &devices).unwrap(), 0);
+        send(server, b"200 OK", &ctx, &devices).unwrap();
+        shutdown(server, Shutdown::Write, &ctx, &devices).unwrap();
+        // Not released: the application has not closed it yet
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Closed));
+        assert_eq!(ctx.tcp.state(client), Some(TcpState::TimeWait));
+
+        ctx.tcp.time_wait_expired(Instant::now() + TCP_MSL * 2);
+        assert_eq!(ctx.tcp.state(client), Some(TcpState::Closed));
+        assert_eq!(receive(client, &mut buf, &ctx, &devices).unwrap(), 6);
+        assert_eq!(&buf[..6], b"200 OK");
+        assert_eq!(receive(client, &mut buf, &ctx, &devices).unwrap(), 0);
+        assert!(shutdown(client, Shutdown::Both, &ctx, &devices).is_err());
+        close(client, &ctx, &devices).unwrap();
+        close(server, &ctx, &devices).unwrap();
+        assert_eq!(ctx.tcp.state(client), None);
+        assert_eq!(ctx.tcp.state(server), None);
+    }
+
+    #[test]
+    fn test_tcp_shutdown_read() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = connect(
+            None,
+            ep("127.0.0.1:80"),
+            Duration::from_secs(1),
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let local = ctx.tcp.local(client).unwrap();
+        let server = ctx.tcp.select(ep("127.0.0.1:80"), local).unwrap();
+
+        send(client, b"unread", &ctx, &devices).unwrap();
+        shutdown(server, Shutdown::Read, &ctx, &devices).unwrap();
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Established));
+        let mut buf = [0u8; 16];
+        assert_eq!(receive(server, &mut buf, &ctx, &devices).unwrap(), 0);
+
+        // Later data is acknowledged but discarded; sending still works
+        send(client, b"ignored", &ctx, &devices).unwrap();
+        for out in ctx
+            .tcp
+            .delayed_ack_expired(Instant::now() + TCP_DELAYED_ACK_TIMEOUT)
+        {
+            out.send(&ctx, &devices).unwrap();
+        }
+        assert!(
+            ctx.tcp.state.lock().unwrap().pcbs[&client]
+                .retransmit
+                .is_empty()
+        );
+        assert!(
+            ctx.tcp.state.lock().unwrap().pcbs[&server]
+                .rcvbuf
+                .is_empty()
+        );
+        send(server, b"hello", &ctx, &devices).unwrap();
+        assert_eq!(receive(client, &mut buf, &ctx, &devices).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_tcp_walks() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        ctx.tcp.record_walks(true);
+        ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = connect(
+            None,
+            ep("127.0.0.1:80"),
+            Duration::from_secs(1),
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let local = ctx.tcp.local(client).unwrap();
+        let server = ctx.tcp.select(ep("127.0.0.1:80"), local).unwrap();
+        close(client, &ctx, &devices).unwrap();
+        close(server, &ctx, &devices).unwrap();
+        ctx.tcp.time_wait_expired(Instant::now() + TCP_MSL * 2);
+
+        let walks = ctx.tcp.walks();
+        let steps = |walk: &TcpWalk| -> Vec<(TcpState, String)> {
+            walk.transitions
+                .iter()
+                .map(|t| (t.to, t.event.to_string()))
+                .collect()
+        };
+        let step = |state, event: &str| (state, event.to_string());
+        assert_eq!(walks.len(), 2);
+        assert_eq!(walks[0].id, server);
+        assert_eq!(
+            steps(&walks[0]),
+            vec![
+                step(TcpState::Listen, "open"),
+                step(TcpState::SynReceived, "rcv SYN"),
+                step(TcpState::Established, "rcv ACK"),
+                step(TcpState::CloseWait, "rcv ACK+FIN"),
+                step(TcpState::LastAck, "close"),
+                step(TcpState::Closed, "rcv ACK"),
+            ]
+        );
+        assert_eq!(walks[1].id, client);
+        assert_eq!(
+            steps(&walks[1]),
+            vec![
+                step(TcpState::SynSent, "open"),
+                step(TcpState::Established, "rcv SYN+ACK"),
+                step(TcpState::FinWait1, "close"),
+                step(TcpState::FinWait2, "rcv ACK"),
+                step(TcpState::TimeWait, "rcv ACK+FIN"),
+                step(TcpState::Closed, "timeout"),
+            ]
+        );
+
+        let mermaid = walks[1].to_mermaid();
+        assert!(mermaid.contains("    [*] --> Closed\n"));
+        assert!(mermaid.contains("    SynSent --> Established : 2. rcv SYN+ACK (+"));
+        let dot = walks[1].to_dot();
+        assert!(dot.starts_with(&format!("digraph \"tcp {} 127.0.0.1:", client)));
+        assert!(dot.contains("    TimeWait -> Closed [label=\"6. timeout (+"));
+
+        ctx.tcp.record_walks(false);
+        assert!(ctx.tcp.walks().is_empty());
+    }
+
+    #[test]
+    fn test_tcp_simultaneous_close() {
+        let (devices, ctx, captured) = setup_loopback();
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = ep("127.0.0.1:40000");
+        let local = ep("127.0.0.1:80");
+        let segment = |seq, ack, flags| {
+            let seg = TcpSegment {
+                seq,
+                ack,
+                flags,
+                wnd: 8192,
+                ..Default::default()
+            };
+            output(client, local, &seg, &ctx, &devices).unwrap();
+            let packet = captured.lock().unwrap().pop().unwrap();
+            feed(&packet, &ctx, &devices);
+        };
+
+        segment(1000, 0, TCP_FLG_SYN);
+        let syn_ack = captured.lock().unwrap().pop().unwrap();
+        let iss = TcpHdr::from_bytes(&syn_ack[IP_HDR_SIZE_MIN..])
+            .unwrap()
+            .seq();
+        segment(1001, iss.wrapping_add(1), TCP_FLG_ACK);
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Established));
+
+        close(server, &ctx, &devices).unwrap();
+        let fin = captured.lock().unwrap().pop().unwrap();
+        assert!(
+            TcpHdr::from_bytes(&fin[IP_HDR_SIZE_MIN..])
+                .unwrap()
+                .has(TCP_FLG_FIN)
+        );
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::FinWait1));
+
+        // The peer's FIN crosses ours
+        segment(1001, iss.wrapping_add(1), TCP_FLG_FIN | TCP_FLG_ACK);
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Closing));
+        let ack = captured.lock().unwrap().pop().unwrap();
+        assert_eq!(
+            TcpHdr::from_bytes(&ack[IP_HDR_SIZE_MIN..]).unwrap().ack(),
+            1002
+        );
+
+        segment(1002, iss.wrapping_add(2), TCP_FLG_ACK);
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::TimeWait));
+        assert_eq!(ctx.tcp.unacked(server), Some(0));
+    }
+
+    #[test]
+    fn test_reno_window() {
+        let mut reno = Reno::new();
+        reno.init(1000);
+        assert_eq!(reno.cwnd(), 4000);
+
+        // Slow start grows by up to one MSS per ACK
+        reno.on_ack(1000, 0);
+        reno.on_ack(500, 0);
+        assert_eq!(reno.cwnd(), 5500);
+
+        reno.on_loss(LossEvent::DupAcks, 5000);
+        assert_eq!(reno.cwnd(), 2500 + 3000);
+        reno.on_ack(0, 5000);
+        assert_eq!(reno.cwnd(), 6500);
+        // Recovery ends with the window deflated to ssthresh, then congestion avoidance
+        reno.on_ack(1000, 0);
+        assert_eq!(reno.cwnd(), 2500);
+        reno.on_ack(1000, 0);
+        assert_eq!(reno.cwnd(), 2500 + 400);
+
+        reno.on_loss(LossEvent::Timeout, 1000);
+        assert_eq!(reno.cwnd(), 1000);
+    }
+
+    #[test]
+    fn test_tcp_fast_retransmit() {
+        let (devices, ctx, captured) = setup_loopback();
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = ep("127.0.0.1:40000");
+        let local = ep("127.0.0.1:80");
+        let segment = |seq, ack, flags| {
+            let seg = TcpSegment {
+                seq,
+                ack,
+                flags,
+                wnd: 8192,
+                ..Default::default()
+            };
+            output(client, local, &seg, &ctx, &devices).unwrap();
+            let packet = captured.lock().unwrap().pop().unwrap();
+            feed(&packet, &ctx, &devices);
+        };
+
+        segment(1000, 0, TCP_FLG_SYN);
+        let syn_ack = captured.lock().unwrap().pop().unwrap();
+        let iss = TcpHdr::from_bytes(&syn_ack[IP_HDR_SIZE_MIN..])
+            .unwrap()
+            .seq();
+        segment(1001, iss.wrapping_add(1), TCP_FLG_ACK);
+
+        // The initial congestion window limits the first flight to four segments
+        let mss = TCP_DEFAULT_MSS as usize;
+        let sent = send(server, &[0u8; 8192], &ctx, &devices).unwrap();
+        assert_eq!(sent, 4 * mss);
+        assert_eq!(ctx.tcp.unacked(server), Some(4));
+        captured.lock().unwrap().clear();
+
+        // The first segment is lost; the next three arrive and are duplicate-ACKed
+        for _ in 0..3 {
+            segment(1001, iss.wrapping_add(1), TCP_FLG_ACK);
+        }
+        let resent = captured.lock().unwrap().pop().unwrap();
+        let hdr = TcpHdr::from_bytes(&resent[IP_HDR_SIZE_MIN..]).unwrap();
+        assert_eq!(hdr.seq(), iss.wrapping_add(1));
+        assert_eq!(resent.len() - IP_HDR_SIZE_MIN - hdr.hdr_len(), mss);
+        assert_eq!(ctx.tcp.cwnd(server), Some((2 * mss + 3 * mss) as u32));
+    }
+
+    #[test]
+    fn test_tcp_delayed_ack() {
+        let (devices, ctx, captured) = setup_loopback();
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = ep("127.0.0.1:40000");
+        let local = ep("127.0.0.1:80");
+        let segment = |seq, ack, flags, data: &[u8]| {
+            let seg = TcpSegment {
+                seq,
+                ack,
+                flags,
+                wnd: 8192,
+                data,
+                ..Default::default()
+            };
+            output(client, local, &seg, &ctx, &devices).unwrap();
+            let packet = captured.lock().unwrap().pop().unwrap();
+            feed(&packet, &ctx, &devices);
+        };
+
+        segment(1000, 0, TCP_FLG_SYN, b"");
+        let syn_ack = captured.lock().unwrap().pop().unwrap();
+        let ack = TcpHdr::from_bytes(&syn_ack[IP_HDR_SIZE_MIN..])
+            .unwrap()
+            .seq()
+            .wrapping_add(1);
+        segment(1001, ack, TCP_FLG_ACK, b"");
+
+        // Every second segment is acknowledged right away
+        segment(1001, ack, TCP_FLG_ACK, b"abcd");
+        assert!(captured.lock().unwrap().is_empty());
+        segment(1005, ack, TCP_FLG_ACK, b"efgh");
+        let packet = captured.lock().unwrap().pop().unwrap();
+        assert_eq!(
+            TcpHdr::from_bytes(&packet[IP_HDR_SIZE_MIN..])
+                .unwrap()
+                .ack(),
+            1009
+        );
+
+        // A lone segment is acknowledged when the timer fires
+        segment(1009, ack, TCP_FLG_ACK, b"ijkl");
+        assert!(captured.lock().unwrap().is_empty());
+        let now = Instant::now();
+        assert!(ctx.tcp.delayed_ack_expired(now).is_empty());
+        let due = ctx.tcp.delayed_ack_expired(now + TCP_DELAYED_ACK_TIMEOUT);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].ack, 1013);
+
+        ctx.tcp.set_delayed_ack(server, false).unwrap();
+        segment(1013, ack, TCP_FLG_ACK, b"mnop");
+        assert_eq!(captured.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_tcp_connect_timeout_releases_pcb() {
+        let (devices, ctx, captured) = setup_loopback();
+        let err = connect(
+            None,
+            ep("127.0.0.1:80"),
+            Duration::from_millis(20),
+            &ctx,
+            &devices,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert_eq!(captured.lock().unwrap().len(), 1);
+        let hdr = TcpHdr::from_bytes(&captured.lock().unwrap()[0][IP_HDR_SIZE_MIN..])
+            .unwrap()
+            .flg();
+        assert_eq!(hdr, TCP_FLG_SYN);
+        assert!(
+            ctx.tcp
+                .conflicts(ep("0.0.0.0:0"), ep("0.0.0.0:0"))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_tcp_retransmit_syn_ack() {
+        let (devices, ctx, captured) = setup_loopback();
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let client = ep("127.0.0.1:40000");
+        let local = ep("127.0.0.1:80");
+
+        let syn = TcpSegment {
+            seq: 1000,
+            flags: TCP_FLG_SYN,
+            wnd: 8192,
+            ..Default::default()
+        };
+        output(client, local, &syn, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        let syn_ack = captured.lock().unwrap().pop().unwrap();
+        assert_eq!(ctx.tcp.unacked(server), Some(1));
+
+        let now = Instant::now();
+        assert!(ctx.tcp.retransmit_expired(now).is_empty());
+        let resent = ctx.tcp.retransmit_expired(now + TCP_RTO_INITIAL);
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].flags, TCP_FLG_SYN | TCP_FLG_ACK);
+        // Backed off: not due again after another initial RTO
+        assert!(
+            ctx.tcp
+                .retransmit_expired(now + TCP_RTO_INITIAL * 2)
+                .is_empty()
+        );
+        assert_eq!(
+            ctx.tcp.retransmit_expired(now + TCP_RTO_INITIAL * 3).len(),
+            1
+        );
+
+        // The ACK frees the queue
+        let hdr = TcpHdr::from_bytes(&syn_ack[IP_HDR_SIZE_MIN..]).unwrap();
+        let ack = TcpSegment {
+            seq: 1001,
+            ack: hdr.seq().wrapping_add(1),
+            flags: TCP_FLG_ACK,
+            wnd: 8192,
+            ..Default::default()
+        };
+        output(client, local, &ack, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+        assert_eq!(ctx.tcp.unacked(server), Some(0));
+
+        // Karn's algorithm: the ACK of a retransmitted segment is no sample,
+        // and the backed-off RTO stays
+        let rtt = ctx.tcp.rtt(server).unwrap();
+        assert_eq!(rtt.srtt, None);
+        assert_eq!(rtt.rto, TCP_RTO_INITIAL * 4);
+    }
+
+    #[test]
+    fn test_tcp_rtt_estimator() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.rto, TCP_RTO_INITIAL);
+
+        rtt.sample(Duration::from_secs(2));
+        assert_eq!(rtt.srtt, Some(Duration::from_secs(2)));
+        assert_eq!(rtt.rttvar, Duration::from_secs(1));
+        assert_eq!(rtt.rto, Duration::from_secs(6));
+
+        rtt.sample(Duration::from_secs(1));
+        assert_eq!(rtt.srtt, Some(Duration::from_millis(1875)));
+        assert_eq!(rtt.rttvar, Duration::from_secs(1));
+        assert_eq!(rtt.rto, Duration::from_millis(5875));
+
+        rtt.backoff();
+        assert_eq!(rtt.rto, Duration::from_millis(11750));
+
+        // Short round trips are rounded up to the minimum RTO
+        let mut rtt = RttEstimator::new();
+        rtt.sample(Duration::from_millis(10));
+        assert_eq!(rtt.rto, TCP_RTO_MIN);
+    }
+
+    #[test]
+    fn test_tcp_retransmit_deadline_closes() {
+        let (devices, ctx, captured) = setup_loopback();
+        let server = ctx.tcp.listen(ep("0.0.0.0:80"), None).unwrap();
+        let syn = TcpSegment {
+            seq: 1,
+            flags: TCP_FLG_SYN,
+            wnd: 8192,
+            ..Default::default()
+        };
+        output(
+            ep("127.0.0.1:40000"),
+            ep("127.0.0.1:80"),
+            &syn,
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        feed(&packet, &ctx, &devices);
+
+        let later = Instant::now() + TCP_RETRANSMIT_DEADLINE;
+        assert!(ctx.tcp.retransmit_expired(later).is_empty());
+        assert_eq!(ctx.tcp.state(server), Some(TcpState::Closed));
+    }
+
+    #[test]
+    fn test_seq_compare_wraps() {
+        assert!(seq_lt(0xffff_fff0, 0x10));
+        assert!(!seq_lt(0x10, 0xffff_fff0));
+        assert!(seq_le(5, 5));
+    }
+
+    #[test]
+    fn test_tcp_parse_options_malformed() {
+        assert!(parse_options(&[TCP_OPT_MSS]).is_err());
+        assert!(parse_options(&[TCP_OPT_MSS, 3, 0]).is_err());
+        assert!(parse_options(&[TCP_OPT_TIMESTAMP, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert_eq!(
+            parse_options(&[TCP_OPT_NOP, TCP_OPT_NOP, TCP_OPT_EOL, 0xff]).unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            parse_options(&[30, 3, 0]).unwrap(),
+            vec![TcpOption::Unknown { kind: 30, len: 3 }]
+        );
+    }
+}
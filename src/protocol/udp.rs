@@ -0,0 +1,887 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::Waker;
+use std::time::Duration;
+
+use tracing::Level;
+
+use crate::bytes::{Bytes, Frame};
+use crate::context::{ConnectionInfo, ProtocolContexts};
+use crate::device::DeviceManager;
+use crate::diagnose::{Conflict, Conflicts};
+use crate::drop::DropReason;
+use crate::error::{NetError, Result};
+use crate::event::NetEvent;
+use crate::limits::StackLimits;
+use crate::platform::Instant;
+use crate::pool::{BufferPool, Loan, LoanInfo, PoolStats};
+use crate::protocol::ip::{self, IP_PAYLOAD_SIZE_MAX, IpAddr, IpEndpoint, IpProtocol};
+use crate::protocol::{
+    MSG_DONTWAIT, MSG_PEEK, MSG_TRUNC, POLLIN, POLLOUT, ProtocolManager, READINESS, wait_until,
+};
+use crate::services::print_ports;
+use crate::util::{LOG_UDP_INPUT, LOG_UDP_OUTPUT, cksum16, header_accessors, wire_codec};
+
+pub const UDP_HDR_SIZE: usize = 8;
+pub const UDP_PAYLOAD_SIZE_MAX: usize = IP_PAYLOAD_SIZE_MAX - UDP_HDR_SIZE;
+
+/// Open UDP control blocks a stack holds by default
+pub(crate) const UDP_PCB_SIZE: usize = 16;
+
+/// Ephemeral port range (RFC 6335)
+const UDP_SOURCE_PORT_MIN: u16 = 49152;
+const UDP_SOURCE_PORT_MAX: u16 = 65535;
+
+/// UDP header, in host byte order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpHdr {
+    src: u16,
+    dst: u16,
+    len: u16,
+    sum: u16,
+}
+
+wire_codec!(UdpHdr, UDP_HDR_SIZE, {
+    src: u16,
+    dst: u16,
+    len: u16,
+    sum: u16,
+});
+
+header_accessors!(UdpHdr, {
+    /// Source port
+    src: u16,
+    /// Destination port
+    dst: u16,
+    /// Header plus payload length
+    #[allow(clippy::len_without_is_empty)]
+    len: u16,
+    /// Checksum
+    sum: u16,
+});
+
+impl fmt::Display for UdpHdr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "src={}, dst={}, len={}, sum={:#06x}",
+            self.src(),
+            self.dst(),
+            self.len(),
+            self.sum()
+        )
+    }
+}
+
+fn udp_print(data: &[u8]) {
+    let Some(hdr) = UdpHdr::from_bytes(data) else {
+        return;
+    };
+    tracing::debug!("UDP Header: {}", hdr);
+    print_ports(hdr.src(), hdr.dst(), IpProtocol::Udp);
+}
+
+/// Handle of an open UDP control block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UdpPcbId(u32);
+
+impl fmt::Display for UdpPcbId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A datagram waiting in a control block's receive queue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpDatagram {
+    pub foreign: IpEndpoint,
+    /// A slice of the received frame, or a copy (see [`crate::bytes`])
+    pub data: Bytes,
+}
+
+/// A datagram lent by [`UdpPcbTable::recv_loan`]; the loan is given back when
+/// this is dropped
+#[derive(Debug)]
+pub struct UdpLoan {
+    pub foreign: IpEndpoint,
+    pub data: Loan,
+}
+
+/// Result of [`UdpPcbTable::recv`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpRecv {
+    /// Bytes copied into the buffer, or the datagram length with `MSG_TRUNC`
+    pub len: usize,
+    pub foreign: IpEndpoint,
+    /// The datagram was longer than the buffer and the excess was discarded
+    pub truncated: bool,
+}
+
+#[derive(Default)]
+struct UdpPcb {
+    local: IpEndpoint,
+    queue: VecDeque<UdpDatagram>,
+    /// Multicast groups joined for the control block, with the interface address
+    groups: Vec<(IpAddr, IpAddr)>,
+}
+
+struct PcbState {
+    pcbs: HashMap<UdpPcbId, UdpPcb>,
+    next_id: u32,
+    /// Control blocks open at most
+    limit: usize,
+    /// Datagrams a control block queues at most
+    queue_len: usize,
+    /// Interrupts so far; a receiver blocked across one gives up
+    interrupts: u64,
+}
+
+impl PcbState {
+    /// Whether `local` conflicts with an endpoint already bound by another PCB
+    fn in_use(&self, local: IpEndpoint) -> bool {
+        self.conflicts(local).next().is_some()
+    }
+
+    fn conflicts(&self, local: IpEndpoint) -> impl Iterator<Item = Conflict> + '_ {
+        self.pcbs
+            .iter()
+            .filter(move |(_, pcb)| {
+                pcb.local.port == local.port
+                    && (pcb.local.addr == IpAddr::ANY
+                        || local.addr == IpAddr::ANY
+                        || pcb.local.addr == local.addr)
+            })
+            .map(|(&owner, pcb)| Conflict::UdpPort {
+                owner,
+                local: pcb.local,
+            })
+    }
+
+    /// PCB receiving datagrams addressed to `dst`
+    fn select_mut(&mut self, dst: IpEndpoint) -> Option<&mut UdpPcb> {
+        self.pcbs.values_mut().find(|pcb| {
+            pcb.local.port == dst.port
+                && (pcb.local.addr == IpAddr::ANY || pcb.local.addr == dst.addr)
+        })
+    }
+
+    fn ephemeral_port(&self, addr: IpAddr) -> Option<u16> {
+        (UDP_SOURCE_PORT_MIN..=UDP_SOURCE_PORT_MAX)
+            .find(|&port| !self.in_use(IpEndpoint::new(addr, port)))
+    }
+}
+
+/// UDP control blocks, their bindings and receive queues.
+///
+/// Receivers block in [`recvfrom`](Self::recvfrom) until a datagram arrives or the
+/// control block is closed.
+pub struct UdpPcbTable {
+    state: Mutex<PcbState>,
+    arrived: Condvar,
+    /// Tasks waiting for a datagram, woken with `arrived`
+    wakers: Mutex<Vec<(UdpPcbId, Waker)>>,
+    /// Keeps track of the datagrams lent by [`recv_loan`](Self::recv_loan)
+    pool: BufferPool,
+}
+
+impl UdpPcbTable {
+    pub fn new() -> Self {
+        let limits = StackLimits::default();
+        Self::with_limits(limits.udp_sockets, limits.socket_queue_len)
+    }
+
+    /// Table of at most `limit` control blocks, each queueing at most
+    /// `queue_len` datagrams
+    pub fn with_limits(limit: usize, queue_len: usize) -> Self {
+        Self {
+            state: Mutex::new(PcbState {
+                pcbs: HashMap::new(),
+                next_id: 0,
+                limit,
+                queue_len,
+                interrupts: 0,
+            }),
+            arrived: Condvar::new(),
+            wakers: Mutex::new(Vec::new()),
+            pool: BufferPool::default(),
+        }
+    }
+
+    /// Allocate an unbound control block
+    pub fn open(&self) -> Result<UdpPcbId> {
+        let mut state = self.state.lock().unwrap();
+        if state.pcbs.len() >= state.limit {
+            return Err(NetError::LimitReached {
+                what: "UDP control blocks",
+                limit: state.limit,
+            });
+        }
+        let id = UdpPcbId(state.next_id);
+        state.next_id = state.next_id.wrapping_add(1);
+        state.pcbs.insert(id, UdpPcb::default());
+        tracing::debug!("udp_open: id={}", id);
+        Ok(id)
+    }
+
+    /// Release the control block, waking up any blocked receiver
+    pub fn close(&self, id: UdpPcbId) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.pcbs.remove(&id).is_none() {
+            return Err(NetError::NotFound(format!("UDP control block {}", id)));
+        }
+        self.notify();
+        tracing::debug!("udp_close: id={}", id);
+        Ok(())
+    }
+
+    /// Record that the control block joined `group` on the interface with
+    /// address `iface`; returns false if it already had
+    pub fn add_group(&self, id: UdpPcbId, group: IpAddr, iface: IpAddr) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let Some(pcb) = state.pcbs.get_mut(&id) else {
+            return Err(NetError::NotFound(format!("UDP control block {}", id)));
+        };
+        if pcb.groups.contains(&(group, iface)) {
+            return Ok(false);
+        }
+        pcb.groups.push((group, iface));
+        Ok(true)
+    }
+
+    /// Forget a group recorded with [`add_group`](Self::add_group); returns
+    /// false if the control block had not joined it
+    pub fn remove_group(&self, id: UdpPcbId, group: IpAddr, iface: IpAddr) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let Some(pcb) = state.pcbs.get_mut(&id) else {
+            return Err(NetError::NotFound(format!("UDP control block {}", id)));
+        };
+        let before = pcb.groups.len();
+        pcb.groups.retain(|&joined| joined != (group, iface));
+        Ok(pcb.groups.len() != before)
+    }
+
+    /// Groups the control block joined, with the interface address
+    pub fn groups(&self, id: UdpPcbId) -> Vec<(IpAddr, IpAddr)> {
+        let state = self.state.lock().unwrap();
+        state
+            .pcbs
+            .get(&id)
+            .map(|pcb| pcb.groups.clone())
+            .unwrap_or_default()
+    }
+
+    /// Bind the control block to `local`; port 0 picks an ephemeral port.
+    /// Fails if the endpoint is already in use.
+    pub fn bind(&self, id: UdpPcbId, local: IpEndpoint) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if !state.pcbs.contains_key(&id) {
+            return Err(NetError::NotFound(format!("UDP control block {}", id)));
+        }
+        let mut local = local;
+        if local.port == 0 {
+            local.port = state
+                .ephemeral_port(local.addr)
+                .ok_or(NetError::NoFreePort)?;
+        }
+        let conflicts: Vec<_> = state.conflicts(local).collect();
+        if !conflicts.is_empty() {
+            return Err(NetError::PortInUse(Conflicts {
+                request: format!("udp bind {}", local),
+                conflicts,
+            }));
+        }
+        if let Some(pcb) = state.pcbs.get_mut(&id) {
+            pcb.local = local;
+        }
+        tracing::debug!("udp_bind: id={}, local={}", id, local);
+        Ok(())
+    }
+
+    /// Control blocks that would keep a bind to `local` from succeeding
+    pub fn conflicts(&self, local: IpEndpoint) -> Vec<Conflict> {
+        self.state.lock().unwrap().conflicts(local).collect()
+    }
+
+    /// Local endpoint the control block is bound to
+    pub fn local(&self, id: UdpPcbId) -> Option<IpEndpoint> {
+        self.state
+            .lock()
+            .unwrap()
+            .pcbs
+            .get(&id)
+            .map(|pcb| pcb.local)
+    }
+
+    /// Every control block as a [`ConnectionInfo`], oldest first
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let state = self.state.lock().unwrap();
+        let mut pcbs: Vec<_> = state.pcbs.iter().collect();
+        pcbs.sort_by_key(|(id, _)| id.0);
+        pcbs.into_iter()
+            .map(|(_, pcb)| ConnectionInfo {
+                protocol: IpProtocol::Udp,
+                local: pcb.local,
+                foreign: None,
+                state: None,
+                recv_queue: pcb.queue.iter().map(|d| d.data.len()).sum(),
+                send_queue: 0,
+            })
+            .collect()
+    }
+
+    /// Poll events the control block is ready for: always [`POLLOUT`], and
+    /// [`POLLIN`] while datagrams are queued. `None` once it is closed.
+    pub fn poll_events(&self, id: UdpPcbId) -> Option<u16> {
+        let state = self.state.lock().unwrap();
+        let pcb = state.pcbs.get(&id)?;
+        Some(if pcb.queue.is_empty() {
+            POLLOUT
+        } else {
+            POLLIN | POLLOUT
+        })
+    }
+
+    /// Wake `waker` when a datagram arrives or a control block is closed; for
+    /// futures that found control block `id` not ready
+    pub fn register_waker(&self, id: UdpPcbId, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|(i, w)| *i == id && w.will_wake(waker)) {
+            wakers.push((id, waker.clone()));
+        }
+    }
+
+    /// Wake up blocked receivers, pollers and waiting tasks
+    fn notify(&self) {
+        self.arrived.notify_all();
+        READINESS.notify();
+        for (_, waker) in self.wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Interrupts so far; calls blocked across one give up
+    pub fn interrupts(&self) -> u64 {
+        self.state.lock().unwrap().interrupts
+    }
+
+    /// Wake every blocked receiver with [`NetError::Interrupted`]
+    pub fn interrupt(&self) {
+        self.state.lock().unwrap().interrupts += 1;
+        self.notify();
+    }
+
+    /// Block until a datagram arrives for the control block and return it.
+    /// Fails if the control block is (or gets) closed, or on an interrupt.
+    pub fn recvfrom(&self, id: UdpPcbId) -> Result<UdpDatagram> {
+        let mut state = self.state.lock().unwrap();
+        let interrupts = state.interrupts;
+        loop {
+            let Some(pcb) = state.pcbs.get_mut(&id) else {
+                return Err(NetError::Closed);
+            };
+            if let Some(datagram) = pcb.queue.pop_front() {
+                return Ok(datagram);
+            }
+            state = self.arrived.wait(state).unwrap();
+            if state.interrupts != interrupts {
+                return Err(NetError::Interrupted);
+            }
+        }
+    }
+
+    /// Block until a datagram arrives and copy it into `buf`.
+    ///
+    /// With `MSG_PEEK` the datagram stays queued. Bytes beyond `buf.len()` are
+    /// discarded (unless peeking) and reported through `truncated`; with `MSG_TRUNC`
+    /// `len` is the full datagram length. With `MSG_DONTWAIT` an empty queue fails
+    /// with [`NetError::WouldBlock`]. `MSG_WAITALL` has no effect on datagrams.
+    pub fn recv(&self, id: UdpPcbId, buf: &mut [u8], flags: u32) -> Result<UdpRecv> {
+        self.recv_timeout(id, buf, flags, None)
+    }
+
+    /// Like [`recv`](Self::recv), waiting at most `timeout` (forever if `None`)
+    /// and failing with a timeout error after that
+    pub fn recv_timeout(
+        &self,
+        id: UdpPcbId,
+        buf: &mut [u8],
+        flags: u32,
+        timeout: Option<Duration>,
+    ) -> Result<UdpRecv> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        let interrupts = state.interrupts;
+        loop {
+            let Some(pcb) = state.pcbs.get_mut(&id) else {
+                return Err(NetError::Closed);
+            };
+            if let Some(datagram) = pcb.queue.front() {
+                let copied = datagram.data.len().min(buf.len());
+                buf[..copied].copy_from_slice(&datagram.data[..copied]);
+                let info = UdpRecv {
+                    len: if flags & MSG_TRUNC != 0 {
+                        datagram.data.len()
+                    } else {
+                        copied
+                    },
+                    foreign: datagram.foreign,
+                    truncated: copied < datagram.data.len(),
+                };
+                if flags & MSG_PEEK == 0 {
+                    pcb.queue.pop_front();
+                }
+                return Ok(info);
+            }
+            if flags & MSG_DONTWAIT != 0 {
+                return Err(NetError::WouldBlock);
+            }
+            state = match wait_until(&self.arrived, state, deadline) {
+                Some(state) => state,
+                None => {
+                    return Err(NetError::TimedOut);
+                }
+            };
+            if state.interrupts != interrupts {
+                return Err(NetError::Interrupted);
+            }
+        }
+    }
+
+    /// Block until a datagram arrives and lend it out without copying.
+    ///
+    /// The loan holds the datagram's buffer, the received frame's if it was
+    /// queued and large, until it is dropped; loans held for long show up in
+    /// [`loans`](Self::loans).
+    pub fn recv_loan(&self, id: UdpPcbId) -> Result<UdpLoan> {
+        let datagram = self.recvfrom(id)?;
+        Ok(UdpLoan {
+            foreign: datagram.foreign,
+            data: self.pool.lend(datagram.data),
+        })
+    }
+
+    /// Loans from [`recv_loan`](Self::recv_loan) not given back yet, oldest first
+    pub fn loans(&self) -> Vec<LoanInfo> {
+        self.pool.loans()
+    }
+
+    /// Counters of the loans handed out and given back
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// Resolve the source endpoint for sending to `foreign`, picking an
+    /// ephemeral port (and keeping it bound) if the control block has none.
+    fn source(
+        &self,
+        id: UdpPcbId,
+        route_src: impl FnOnce() -> Result<IpAddr>,
+    ) -> Result<IpEndpoint> {
+        let mut state = self.state.lock().unwrap();
+        let Some(mut local) = state.pcbs.get(&id).map(|pcb| pcb.local) else {
+            return Err(NetError::NotFound(format!("UDP control block {}", id)));
+        };
+        if local.port == 0 {
+            local.port = state
+                .ephemeral_port(local.addr)
+                .ok_or(NetError::NoFreePort)?;
+            if let Some(pcb) = state.pcbs.get_mut(&id) {
+                pcb.local.port = local.port;
+            }
+            tracing::debug!("udp: id={} bound to ephemeral port {}", id, local.port);
+        }
+        if local.addr == IpAddr::ANY {
+            local.addr = route_src()?;
+        }
+        Ok(local)
+    }
+
+    /// Queue a datagram for the control block bound to `dst`
+    fn deliver(&self, dst: IpEndpoint, datagram: UdpDatagram) -> Result<(), DropReason> {
+        let mut state = self.state.lock().unwrap();
+        let queue_len = state.queue_len;
+        let Some(pcb) = state.select_mut(dst) else {
+            return Err(DropReason::NoSocket);
+        };
+        if pcb.queue.len() >= queue_len {
+            return Err(DropReason::SocketQueueFull);
+        }
+        pcb.queue.push_back(datagram);
+        self.notify();
+        Ok(())
+    }
+}
+
+impl Default for UdpPcbTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn init(protocols: &mut ProtocolManager) -> Result<()> {
+    protocols.events().subscribe(Arc::new(event));
+    Ok(())
+}
+
+/// Give up blocked receives on an interrupt (microps' `udp_event`)
+fn event(event: &NetEvent, ctx: &ProtocolContexts) {
+    if *event == NetEvent::Interrupt {
+        ctx.udp.interrupt();
+    }
+}
+
+/// Deliver a datagram to the socket bound to its destination; returns false
+/// only if there is no such socket
+pub fn input(data: &Frame<'_>, src: IpAddr, dst: IpAddr, ctx: &ProtocolContexts) -> bool {
+    let Some(hdr) = UdpHdr::from_bytes(data) else {
+        ctx.drops.drop(DropReason::Malformed, data);
+        tracing::error!("udp_input: too short, len={}", data.len());
+        return true;
+    };
+    let len = hdr.len() as usize;
+    if len < UDP_HDR_SIZE || data.len() < len {
+        ctx.drops.drop(DropReason::Malformed, data);
+        tracing::error!(
+            "udp_input: length error, len={}, hdr.len={}",
+            data.len(),
+            len
+        );
+        return true;
+    }
+    let data = data.slice(..len);
+    if hdr.sum() != 0
+        && cksum16(&data, ip::pseudo_sum(src, dst, IpProtocol::Udp, len)) != 0
+        && ctx.drops.drop(DropReason::UdpChecksum, &data)
+    {
+        tracing::error!("udp_input: checksum error");
+        return true;
+    }
+
+    if LOG_UDP_INPUT.allow(Level::DEBUG) {
+        tracing::debug!(
+            "{}:{} => {}:{}, len={}",
+            src,
+            hdr.src(),
+            dst,
+            hdr.dst(),
+            len - UDP_HDR_SIZE
+        );
+        udp_print(&data);
+    }
+
+    let datagram = UdpDatagram {
+        foreign: IpEndpoint::new(src, hdr.src()),
+        data: data.slice(UDP_HDR_SIZE..).to_bytes(),
+    };
+    if let Err(reason) = ctx.udp.deliver(IpEndpoint::new(dst, hdr.dst()), datagram) {
+        ctx.drops.drop(reason, &data);
+        tracing::debug!("udp_input: {:?}, {}:{}", reason, dst, hdr.dst());
+        return reason != DropReason::NoSocket;
+    }
+    true
+}
+
+/// Build a UDP datagram from `src` to `dst` and hand it to IP
+pub fn output(
+    src: IpEndpoint,
+    dst: IpEndpoint,
+    data: &[u8],
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    output_vectored(src, dst, &[data], ctx, devices)
+}
+
+/// Like [`output`], with the payload gathered from `bufs` in order
+pub fn output_vectored(
+    src: IpEndpoint,
+    dst: IpEndpoint,
+    bufs: &[&[u8]],
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    let data_len: usize = bufs.iter().map(|b| b.len()).sum();
+    if data_len > UDP_PAYLOAD_SIZE_MAX {
+        return Err(NetError::MessageTooLong {
+            len: data_len,
+            max: UDP_PAYLOAD_SIZE_MAX,
+        });
+    }
+    let len = UDP_HDR_SIZE + data_len;
+    let mut buf = ctx.packets.packet(data_len);
+    for b in bufs {
+        buf.extend_from_slice(b);
+    }
+    let hdr = UdpHdr {
+        src: src.port,
+        dst: dst.port,
+        len: len as u16,
+        sum: 0,
+    };
+    buf.push(UDP_HDR_SIZE).copy_from_slice(&hdr.to_bytes());
+
+    let sum = match cksum16(
+        &buf,
+        ip::pseudo_sum(src.addr, dst.addr, IpProtocol::Udp, len),
+    ) {
+        // An all-zero checksum means "none"; send its one's complement equivalent
+        0 => 0xffff,
+        sum => sum,
+    };
+    buf[6..8].copy_from_slice(&sum.to_be_bytes());
+
+    if LOG_UDP_OUTPUT.allow(Level::DEBUG) {
+        tracing::debug!("{} => {}, len={}", src, dst, data_len);
+        udp_print(&buf);
+    }
+
+    ip::ip_output_buf(IpProtocol::Udp, buf, src.addr, dst.addr, ctx, devices)?;
+    Ok(data_len)
+}
+
+/// Send `data` to `foreign` from the control block `id`.
+///
+/// An unbound control block is given an ephemeral port, and the source address
+/// follows the route to `foreign` if the block is bound to the wildcard address.
+pub fn sendto(
+    id: UdpPcbId,
+    data: &[u8],
+    foreign: IpEndpoint,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    sendto_vectored(id, &[data], foreign, ctx, devices)
+}
+
+/// Like [`sendto`], sending the concatenation of `bufs` as one datagram
+/// (e.g. an application header and a body kept in separate buffers)
+pub fn sendto_vectored(
+    id: UdpPcbId,
+    bufs: &[&[u8]],
+    foreign: IpEndpoint,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    let local = ctx.udp.source(id, || {
+        ctx.ip_routes
+            .lookup(foreign.addr)
+            .map(|route| route.iface.unicast)
+            .ok_or_else(|| NetError::NoRoute(foreign.addr.into()))
+    })?;
+    output_vectored(local, foreign, bufs, ctx, devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::device::DeviceIndex;
+    use crate::protocol::MSG_WAITALL;
+    use crate::protocol::ip::{IP_HDR_SIZE_MIN, IpHdr};
+    use crate::protocol::{MSG_PEEK, MSG_TRUNC};
+    use crate::testing::setup_loopback;
+
+    fn ep(s: &str) -> IpEndpoint {
+        IpEndpoint::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_udp_bind_conflicts() {
+        let table = UdpPcbTable::new();
+        let a = table.open().unwrap();
+        let b = table.open().unwrap();
+        table.bind(a, ep("0.0.0.0:7")).unwrap();
+        assert!(table.bind(b, ep("127.0.0.1:7")).is_err());
+        table.bind(b, ep("127.0.0.1:8")).unwrap();
+
+        table.close(a).unwrap();
+        assert!(table.recvfrom(a).is_err());
+        table.bind(b, ep("127.0.0.1:7")).unwrap();
+    }
+
+    proptest! {
+        #[test]
+        fn test_udp_hdr_roundtrip((src, dst, len, sum) in any::<(u16, u16, u16, u16)>()) {
+            let hdr = UdpHdr { src, dst, len, sum };
+            let bytes = hdr.to_bytes();
+            let fields = [src, dst, len, sum].map(u16::to_be_bytes);
+            prop_assert_eq!(&bytes[..], fields.as_flattened());
+            let parsed = UdpHdr::from_bytes(&bytes).unwrap();
+            prop_assert_eq!(parsed, hdr);
+            prop_assert_eq!(parsed.src(), src);
+            prop_assert_eq!(parsed.dst(), dst);
+            prop_assert_eq!(parsed.len(), len);
+            prop_assert_eq!(parsed.sum(), sum);
+        }
+    }
+
+    #[test]
+    fn test_udp_sendto_recvfrom_loopback() {
+        let (devices, ctx, captured) = setup_loopback();
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        let server = ctx.udp.open().unwrap();
+        ctx.udp.bind(server, ep("0.0.0.0:7")).unwrap();
+        let client = ctx.udp.open().unwrap();
+
+        let sent = sendto(client, b"hello", ep("127.0.0.1:7"), &ctx, &devices).unwrap();
+        assert_eq!(sent, 5);
+        let local = ctx.udp.local(client).unwrap();
+        assert!(local.port >= UDP_SOURCE_PORT_MIN);
+
+        let packet = captured.lock().unwrap().pop().unwrap();
+        let hdr = IpHdr::from_bytes(&packet).unwrap();
+        assert_eq!(hdr.protocol(), IpProtocol::Udp);
+        let udp = UdpHdr::from_bytes(&packet[IP_HDR_SIZE_MIN..]).unwrap();
+        assert_eq!(udp.src(), local.port);
+        assert_eq!(udp.dst(), 7);
+        ip::ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+
+        let datagram = ctx.udp.recvfrom(server).unwrap();
+        assert_eq!(datagram.data, b"hello");
+        assert_eq!(datagram.foreign, ep(&format!("127.0.0.1:{}", local.port)));
+
+        // Reply goes back to the client's ephemeral port
+        sendto(server, b"world", datagram.foreign, &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        ip::ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+        let reply = ctx.udp.recvfrom(client).unwrap();
+        assert_eq!(reply.data, b"world");
+        assert_eq!(reply.foreign, ep("127.0.0.1:7"));
+    }
+
+    #[test]
+    fn test_udp_sendto_vectored() {
+        let (devices, ctx, captured) = setup_loopback();
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        let server = ctx.udp.open().unwrap();
+        ctx.udp.bind(server, ep("127.0.0.1:7")).unwrap();
+        let client = ctx.udp.open().unwrap();
+
+        let bufs: [&[u8]; 3] = [b"HDR:", b"", b"body"];
+        let sent = sendto_vectored(client, &bufs, ep("127.0.0.1:7"), &ctx, &devices).unwrap();
+        assert_eq!(sent, 8);
+        assert_eq!(captured.lock().unwrap().len(), 1);
+
+        let packet = captured.lock().unwrap().pop().unwrap();
+        ip::ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+        assert_eq!(ctx.udp.recvfrom(server).unwrap().data, b"HDR:body");
+    }
+
+    #[test]
+    fn test_udp_recv_peek_and_truncate() {
+        let (devices, ctx, captured) = setup_loopback();
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        let server = ctx.udp.open().unwrap();
+        ctx.udp.bind(server, ep("127.0.0.1:7")).unwrap();
+
+        output(
+            ep("127.0.0.1:9"),
+            ep("127.0.0.1:7"),
+            b"abcdef",
+            &ctx,
+            &devices,
+        )
+        .unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        ip::ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+
+        let mut buf = [0u8; 4];
+        let peeked = ctx
+            .udp
+            .recv(server, &mut buf, MSG_PEEK | MSG_TRUNC)
+            .unwrap();
+        assert_eq!(peeked.len, 6);
+        assert!(peeked.truncated);
+        assert_eq!(peeked.foreign, ep("127.0.0.1:9"));
+        assert_eq!(&buf, b"abcd");
+
+        let got = ctx.udp.recv(server, &mut buf, 0).unwrap();
+        assert_eq!((got.len, got.truncated), (4, true));
+
+        // The truncated datagram was consumed; the rest of it is gone
+        output(ep("127.0.0.1:9"), ep("127.0.0.1:7"), b"xy", &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        ip::ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+        let got = ctx.udp.recv(server, &mut buf, MSG_WAITALL).unwrap();
+        assert_eq!((got.len, got.truncated), (2, false));
+        assert_eq!(&buf[..2], b"xy");
+    }
+
+    #[test]
+    fn test_udp_recv_loan() {
+        let (devices, ctx, captured) = setup_loopback();
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        let server = ctx.udp.open().unwrap();
+        ctx.udp.bind(server, ep("127.0.0.1:7")).unwrap();
+        let deliver = |data: &[u8]| {
+            output(ep("127.0.0.1:9"), ep("127.0.0.1:7"), data, &ctx, &devices).unwrap();
+            let packet = captured.lock().unwrap().pop().unwrap();
+            let frame = Frame::Shared(ctx.packets.bytes_from(&packet));
+            ip::ip_input(&frame, dev, &ctx, &devices).unwrap();
+        };
+
+        // Large enough to be kept in the frame's buffer rather than copied
+        deliver(&[1; 1000]);
+        deliver(&[2; 1200]);
+        let first = ctx.udp.recv_loan(server).unwrap();
+        assert_eq!(first.foreign, ep("127.0.0.1:9"));
+        assert_eq!(*first.data, [1; 1000]);
+        let second = ctx.udp.recv_loan(server).unwrap();
+        assert_eq!(*second.data, [2; 1200]);
+
+        // Loans not given back are visible, oldest first
+        let loans = ctx.udp.loans();
+        assert_eq!(loans.len(), 2);
+        assert_eq!(loans[0].id, first.data.id());
+        assert_eq!(loans[1].len, 1200);
+        let buf = first.data.as_ptr();
+        drop(first);
+        assert_eq!(ctx.udp.loans().len(), 1);
+
+        // The loan held the frame's own buffer, which goes back to the
+        // stack's packet pool and carries the next frame
+        deliver(&[3; 1000]);
+        let third = ctx.udp.recv_loan(server).unwrap();
+        assert_eq!(third.data.as_ptr(), buf);
+        let stats = ctx.udp.pool_stats();
+        assert_eq!((stats.lent, stats.returned, stats.outstanding()), (3, 1, 2));
+        assert_eq!(stats.max_outstanding, 2);
+        drop((second, third));
+        assert!(ctx.udp.loans().is_empty());
+    }
+
+    #[test]
+    fn test_udp_input_checksum_error() {
+        let (devices, ctx, captured) = setup_loopback();
+        let dev = devices.get(DeviceIndex(0)).unwrap();
+        let server = ctx.udp.open().unwrap();
+        ctx.udp.bind(server, ep("127.0.0.1:7")).unwrap();
+
+        output(ep("127.0.0.1:9"), ep("127.0.0.1:7"), b"x", &ctx, &devices).unwrap();
+        let mut packet = captured.lock().unwrap().pop().unwrap();
+        *packet.last_mut().unwrap() ^= 0xff;
+        ip::ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+
+        output(ep("127.0.0.1:9"), ep("127.0.0.1:7"), b"y", &ctx, &devices).unwrap();
+        let packet = captured.lock().unwrap().pop().unwrap();
+        ip::ip_input(&Frame::Borrowed(&packet), dev, &ctx, &devices).unwrap();
+        assert_eq!(ctx.udp.recvfrom(server).unwrap().data, b"y");
+    }
+
+    #[test]
+    fn test_udp_recvfrom_wakes_on_close() {
+        let table = std::sync::Arc::new(UdpPcbTable::new());
+        let id = table.open().unwrap();
+        let waiter = {
+            let table = table.clone();
+            std::thread::spawn(move || table.recvfrom(id))
+        };
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        table.close(id).unwrap();
+        assert!(waiter.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Fixed-capacity single-producer, single-consumer ring.
+//!
+//! [`ring`] returns its two ends. The [`Producer`] pushes and the [`Consumer`]
+//! pops with no lock between them: each end advances its own index and
+//! only reads the other's, so a device receive path and the protocol worker
+//! draining it never wait for each other. Both ends take `&mut self`, which
+//! keeps each side to one thread at a time.
+//!
+//! A push to a full ring hands the item back and is counted in
+//! [`Producer::dropped`]. A [`Gauge`] reads the fill level from anywhere,
+//! without being either end.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Items popped so far; written by the consumer only
+    head: AtomicUsize,
+    /// Items pushed so far; written by the producer only
+    tail: AtomicUsize,
+    /// Pushes refused because the ring was full
+    dropped: AtomicU64,
+}
+
+// SAFETY: there is one `Producer` and one `Consumer`, and each needs `&mut
+// self` to touch the slots. The slots from `head` up to `tail` hold
+// initialised items and only the consumer reads them; the others are only
+// written by the producer. Each end publishes its index with a release store
+// after it is done with a slot, and the other end reads it with an acquire
+// load before touching that slot, so no slot is accessed from two threads at
+// once. Items move between threads, hence `T: Send`; they are never shared,
+// so `T: Sync` is not needed.
+unsafe impl<T: Send> Send for Shared<T> {}
+// SAFETY: as for `Send` above
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    fn slot(&self, pos: usize) -> *mut MaybeUninit<T> {
+        self.slots[pos % self.slots.len()].get()
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let (head, tail) = (*self.head.get_mut(), *self.tail.get_mut());
+        for i in 0..tail.wrapping_sub(head) {
+            // SAFETY: the slots from `head` up to `tail` were pushed, so they are
+            // initialised, and never popped; `&mut self` means neither end is
+            // left to touch them
+            unsafe { (*self.slot(head.wrapping_add(i))).assume_init_drop() };
+        }
+    }
+}
+
+/// The pushing end of a [`ring`]
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The popping end of a [`ring`]
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// The fill level of a [`ring`], read from its indices alone
+pub struct Gauge<T> {
+    shared: Weak<Shared<T>>,
+}
+
+/// A ring holding up to `capacity` items (at least one)
+pub fn ring<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let slots = (0..capacity.max(1))
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect();
+    let shared = Arc::new(Shared {
+        slots,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        dropped: AtomicU64::new(0),
+    });
+    (
+        Producer {
+            shared: Arc::clone(&shared),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Append `item`, or hand it back if the ring is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        let shared = &*self.shared;
+        let tail = shared.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(shared.head.load(Ordering::Acquire)) == shared.slots.len() {
+            shared.dropped.fetch_add(1, Ordering::Relaxed);
+            return Err(item);
+        }
+        // SAFETY: the ring is not full, so the slot at `tail` is not between
+        // `head` and `tail` and the consumer does not read it; this is the only
+        // producer. Its old item, if any, was moved out by `pop`, so
+        // overwriting it leaks nothing.
+        unsafe { (*shared.slot(tail)).write(item) };
+        shared.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Items waiting to be popped
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    /// Items refused because the ring was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the consumer is gone, so nothing pushed would be popped
+    pub fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+
+    pub fn gauge(&self) -> Gauge<T> {
+        Gauge {
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Take the oldest item
+    pub fn pop(&mut self) -> Option<T> {
+        let shared = &*self.shared;
+        let head = shared.head.load(Ordering::Relaxed);
+        if head == shared.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // SAFETY: `head` is before `tail`, whose acquire load above makes the
+        // producer's write of this slot visible, so it is initialised; this is
+        // the only consumer, and moving `head` past the slot below stops it
+        // being read twice or dropped in `Drop`.
+        let item = unsafe { (*shared.slot(head)).assume_init_read() };
+        shared.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(item)
+    }
+
+    /// Items waiting to be popped
+    pub fn len(&self) -> usize {
+        self.shared.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.slots.len()
+    }
+
+    /// Items refused because the ring was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the producer is gone, so nothing more will be pushed
+    pub fn is_closed(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+impl<T> Gauge<T> {
+    /// Items waiting to be popped; 0 once both ends are gone
+    pub fn len(&self) -> usize {
+        self.shared.upgrade().map_or(0, |shared| shared.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_overflow_and_wrap() {
+        let (mut tx, mut rx) = ring(2);
+        assert_eq!(tx.push(1), Ok(()));
+        assert_eq!(tx.push(2), Ok(()));
+        assert_eq!(tx.push(3), Err(3));
+        assert_eq!((rx.len(), rx.dropped()), (2, 1));
+
+        // Positions keep going past the capacity
+        for i in 0..10 {
+            assert_eq!(rx.pop(), Some(i + 1));
+            tx.push(i + 3).unwrap();
+        }
+        assert_eq!(rx.pop(), Some(11));
+        assert_eq!(rx.pop(), Some(12));
+        assert_eq!(rx.pop(), None);
+        assert!(tx.is_empty());
+    }
+
+    #[test]
+    fn test_ring_across_threads() {
+        const ITEMS: usize = 100_000;
+        let (mut tx, mut rx) = ring(16);
+        let producer = std::thread::spawn(move || {
+            for i in 0..ITEMS {
+                let mut item = vec![i];
+                while let Err(back) = tx.push(item) {
+                    item = back;
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut next = 0;
+        while next < ITEMS {
+            match rx.pop() {
+                Some(item) => {
+                    assert_eq!(item, [next]);
+                    next += 1;
+                }
+                None => std::thread::yield_now(),
+            }
+        }
+        producer.join().unwrap();
+        assert!(rx.is_empty());
+    }
+
+    #[test]
+    fn test_ring_drops_unpopped_items() {
+        let item = Arc::new(());
+        let (mut tx, rx) = ring(4);
+        tx.push(Arc::clone(&item)).unwrap();
+        tx.push(Arc::clone(&item)).unwrap();
+        drop((tx, rx));
+        assert_eq!(Arc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn test_ring_gauge_and_closed_ends() {
+        let (mut tx, rx) = ring(4);
+        let gauge = tx.gauge();
+        tx.push(1).unwrap();
+        tx.push(2).unwrap();
+        assert_eq!(gauge.len(), 2);
+        assert!(!tx.is_closed() && !rx.is_closed());
+
+        drop(tx);
+        assert!(rx.is_closed());
+        assert_eq!(gauge.len(), 2);
+        drop(rx);
+        assert!(gauge.is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+//! Network discovery utilities.
+
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::platform::Instant;
+use crate::protocol::icmp;
+use crate::protocol::ip::{self, IpAddr};
+use crate::stack::NetStack;
+
+/// Largest range a sweep accepts (a /16)
+const SWEEP_HOSTS_MAX: u32 = 1 << 16;
+
+const SWEEP_PAYLOAD: &[u8] = b"microps-sweep";
+const SWEEP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct SweepOptions {
+    /// Probes per second; 0 sends as fast as possible
+    pub rate: u32,
+    /// How long to wait for replies after the last probe
+    pub timeout: Duration,
+    /// Echo identifier used for the probes
+    pub id: u16,
+}
+
+impl Default for SweepOptions {
+    fn default() -> Self {
+        Self {
+            rate: 100,
+            timeout: Duration::from_secs(1),
+            id: std::process::id() as u16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Responder {
+    pub addr: IpAddr,
+    pub rtt: Duration,
+}
+
+fn to_u32(addr: IpAddr) -> u32 {
+    u32::from_be_bytes(addr.to_ne_bytes())
+}
+
+fn from_u32(n: u32) -> IpAddr {
+    IpAddr::from_ne_bytes(n.to_be_bytes())
+}
+
+/// Host addresses of a CIDR range, excluding network and broadcast addresses
+/// for prefixes shorter than /31
+fn sweep_targets(cidr: &str) -> Result<Vec<IpAddr>> {
+    let (addr, netmask) = ip::parse_cidr(cidr)?;
+    let network = to_u32(addr & netmask);
+    let size = (!to_u32(netmask)) as u64 + 1;
+    if size > SWEEP_HOSTS_MAX as u64 {
+        anyhow::bail!("sweep range too large: {} ({} addresses)", cidr, size);
+    }
+
+    let (first, last) = if size > 2 {
+        (network + 1, network + size as u32 - 2)
+    } else {
+        (network, network + size as u32 - 1)
+    };
+    Ok((first..=last).map(from_u32).collect())
+}
+
+/// Outstanding echo probes of a sweep, indexed by sequence number
+struct Probes {
+    id: u16,
+    targets: Vec<IpAddr>,
+    sent_at: Vec<Option<Instant>>,
+    rtts: Vec<Option<Duration>>,
+}
+
+impl Probes {
+    fn new(id: u16, targets: Vec<IpAddr>) -> Self {
+        let len = targets.len();
+        Self {
+            id,
+            targets,
+            sent_at: vec![None; len],
+            rtts: vec![None; len],
+        }
+    }
+
+    fn collect(&mut self, stack: &NetStack) {
+        for reply in stack.ctx().icmp_echo.take(self.id) {
+            let index = reply.seq as usize;
+            if let (Some(Some(sent)), Some(target)) =
+                (self.sent_at.get(index), self.targets.get(index))
+                && reply.src == *target
+                && self.rtts[index].is_none()
+            {
+                self.rtts[index] = Some(reply.received_at.duration_since(*sent));
+            }
+        }
+    }
+
+    fn pending(&self) -> bool {
+        self.sent_at
+            .iter()
+            .zip(&self.rtts)
+            .any(|(sent, rtt)| sent.is_some() && rtt.is_none())
+    }
+
+    fn responders(self) -> Vec<Responder> {
+        self.targets
+            .into_iter()
+            .zip(self.rtts)
+            .filter_map(|(addr, rtt)| rtt.map(|rtt| Responder { addr, rtt }))
+            .collect()
+    }
+}
+
+/// Send an ICMP echo to every host of `cidr` at `opts.rate` and report the
+/// hosts that answered, ordered by address, with their round-trip time.
+pub fn ping_sweep(stack: &NetStack, cidr: &str, opts: &SweepOptions) -> Result<Vec<Responder>> {
+    let mut probes = Probes::new(opts.id, sweep_targets(cidr)?);
+    let interval = (opts.rate > 0).then(|| Duration::from_secs(1) / opts.rate);
+
+    stack.ctx().icmp_echo.register(opts.id)?;
+
+    let start = Instant::now();
+    for index in 0..probes.targets.len() {
+        if let Some(interval) = interval {
+            let slot = start + interval * index as u32;
+            let now = Instant::now();
+            if slot > now {
+                std::thread::sleep(slot - now);
+            }
+        }
+
+        let target = probes.targets[index];
+        probes.sent_at[index] = Some(Instant::now());
+        let result = icmp::send_echo(
+            opts.id,
+            index as u16,
+            SWEEP_PAYLOAD,
+            target,
+            &stack.ctx(),
+            &stack.devices(),
+        );
+        if let Err(e) = result {
+            tracing::debug!("sweep: probe to {} failed: {}", target, e);
+            probes.sent_at[index] = None;
+        }
+        probes.collect(stack);
+    }
+
+    let deadline = Instant::now() + opts.timeout;
+    loop {
+        probes.collect(stack);
+        if !probes.pending() || Instant::now() >= deadline {
+            break;
+        }
+        std::thread::sleep(SWEEP_POLL_INTERVAL);
+    }
+
+    stack.ctx().icmp_echo.unregister(opts.id);
+
+    Ok(probes.responders())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::topology::Topology;
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_sweep_targets() {
+        let targets = sweep_targets("192.0.2.0/30").unwrap();
+        assert_eq!(targets, [addr("192.0.2.1"), addr("192.0.2.2")]);
+
+        let targets = sweep_targets("192.0.2.9/31").unwrap();
+        assert_eq!(targets, [addr("192.0.2.8"), addr("192.0.2.9")]);
+
+        assert_eq!(sweep_targets("192.0.2.7/32").unwrap(), [addr("192.0.2.7")]);
+        assert_eq!(sweep_targets("10.0.0.0/16").unwrap().len(), 65534);
+        assert!(sweep_targets("10.0.0.0/8").is_err());
+    }
+
+    #[test]
+    fn test_ping_sweep() {
+        let topo = Topology::builder()
+            .host("h1")
+            .router("r1")
+            .host("h2")
+            .link(("h1", "192.0.2.1/24"), ("r1", "192.0.2.254/24"))
+            .link(("r1", "198.51.100.254/24"), ("h2", "198.51.100.5/24"))
+            .gateway("h1", "192.0.2.254")
+            .gateway("h2", "198.51.100.254")
+            .build()
+            .unwrap();
+        let opts = SweepOptions {
+            rate: 0,
+            timeout: Duration::from_millis(20),
+            id: 42,
+        };
+
+        let responders = ping_sweep(topo.node("h1").unwrap(), "198.51.100.0/29", &opts).unwrap();
+        let addrs: Vec<_> = responders.iter().map(|r| r.addr).collect();
+        assert_eq!(addrs, [addr("198.51.100.5")]);
+    }
+}
@@ -0,0 +1,283 @@
+//! Names of well-known ports, like `/etc/services`.
+//!
+//! The stack-wide [`SERVICES`] registry starts with a built-in table and can be
+//! extended with text in the `/etc/services` format:
+//!
+//! ```text
+//! # name  port/proto  [aliases...]
+//! http    8080/tcp    www
+//! ```
+//!
+//! Entries added later win over earlier ones and over the built-in table. The
+//! packet printers show port names from it, and [`resolve`] accepts them in
+//! endpoints (`192.0.2.1:http`, `http://192.0.2.1`).
+
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+use anyhow::{Context, Result, bail};
+
+use crate::protocol::ip::{IpAddr, IpEndpoint, IpProtocol};
+
+/// Ports known without any configuration
+const WELL_KNOWN: &[(&str, u16, IpProtocol)] = &[
+    ("echo", 7, IpProtocol::Tcp),
+    ("echo", 7, IpProtocol::Udp),
+    ("discard", 9, IpProtocol::Tcp),
+    ("discard", 9, IpProtocol::Udp),
+    ("daytime", 13, IpProtocol::Tcp),
+    ("daytime", 13, IpProtocol::Udp),
+    ("ftp-data", 20, IpProtocol::Tcp),
+    ("ftp", 21, IpProtocol::Tcp),
+    ("ssh", 22, IpProtocol::Tcp),
+    ("telnet", 23, IpProtocol::Tcp),
+    ("smtp", 25, IpProtocol::Tcp),
+    ("domain", 53, IpProtocol::Tcp),
+    ("domain", 53, IpProtocol::Udp),
+    ("bootps", 67, IpProtocol::Udp),
+    ("bootpc", 68, IpProtocol::Udp),
+    ("tftp", 69, IpProtocol::Udp),
+    ("http", 80, IpProtocol::Tcp),
+    ("pop3", 110, IpProtocol::Tcp),
+    ("ntp", 123, IpProtocol::Udp),
+    ("imap", 143, IpProtocol::Tcp),
+    ("snmp", 161, IpProtocol::Udp),
+    ("https", 443, IpProtocol::Tcp),
+    ("syslog", 514, IpProtocol::Udp),
+];
+
+/// A named port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    pub name: String,
+    pub port: u16,
+    pub protocol: IpProtocol,
+    pub aliases: Vec<String>,
+}
+
+impl Service {
+    fn is_named(&self, name: &str) -> bool {
+        self.name == name || self.aliases.iter().any(|alias| alias == name)
+    }
+}
+
+/// Port names on top of the built-in table
+pub struct ServiceRegistry {
+    added: RwLock<Vec<Service>>,
+}
+
+/// The stack-wide service registry
+pub static SERVICES: ServiceRegistry = ServiceRegistry::new();
+
+impl ServiceRegistry {
+    pub const fn new() -> Self {
+        Self {
+            added: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Name `port` of `protocol`, replacing any earlier name for it
+    pub fn add(&self, service: Service) {
+        let mut added = self.added.write().unwrap();
+        added.retain(|s| !(s.port == service.port && s.protocol == service.protocol));
+        added.push(service);
+    }
+
+    /// Add the entries of `/etc/services`-style `text`; returns how many were added
+    pub fn load_str(&self, text: &str) -> Result<usize> {
+        let mut services = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(name) = fields.next() else {
+                continue;
+            };
+            let Some(port) = fields.next() else {
+                bail!("line {}: expected `name port/proto`", i + 1);
+            };
+            let (port, protocol) = parse_port(port).with_context(|| format!("line {}", i + 1))?;
+            services.push(Service {
+                name: name.to_string(),
+                port,
+                protocol,
+                aliases: fields.map(str::to_string).collect(),
+            });
+        }
+        let count = services.len();
+        for service in services {
+            self.add(service);
+        }
+        Ok(count)
+    }
+
+    /// Add the entries of the `/etc/services`-style file at `path`
+    pub fn load(&self, path: &Path) -> Result<usize> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        self.load_str(&text)
+            .with_context(|| format!("Invalid services file {}", path.display()))
+    }
+
+    /// Forget every entry added on top of the built-in table
+    pub fn clear(&self) {
+        self.added.write().unwrap().clear();
+    }
+
+    /// Name of `port` of `protocol`
+    pub fn name(&self, port: u16, protocol: IpProtocol) -> Option<String> {
+        let added = self.added.read().unwrap();
+        if let Some(service) = added
+            .iter()
+            .rev()
+            .find(|s| s.port == port && s.protocol == protocol)
+        {
+            return Some(service.name.clone());
+        }
+        WELL_KNOWN
+            .iter()
+            .find(|&&(_, p, proto)| p == port && proto == protocol)
+            .map(|&(name, _, _)| name.to_string())
+    }
+
+    /// Port named `name` (or one of its aliases); `protocol` of `None` matches either
+    pub fn port(&self, name: &str, protocol: Option<IpProtocol>) -> Option<u16> {
+        let matches = |proto: IpProtocol| protocol.is_none_or(|p| p == proto);
+        let added = self.added.read().unwrap();
+        if let Some(service) = added
+            .iter()
+            .rev()
+            .find(|s| s.is_named(name) && matches(s.protocol))
+        {
+            return Some(service.port);
+        }
+        WELL_KNOWN
+            .iter()
+            .find(|&&(n, _, proto)| n == name && matches(proto))
+            .map(|&(_, port, _)| port)
+    }
+
+    /// `port` followed by its name in parentheses if it has one, e.g. `80(http)`
+    pub fn describe(&self, port: u16, protocol: IpProtocol) -> String {
+        match self.name(port, protocol) {
+            Some(name) => format!("{}({})", port, name),
+            None => port.to_string(),
+        }
+    }
+}
+
+impl Default for ServiceRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `addr:port` with the port's name from [`SERVICES`] if it has one, e.g.
+/// `192.0.2.1:http`
+pub fn endpoint_name(endpoint: IpEndpoint, protocol: IpProtocol) -> String {
+    match SERVICES.name(endpoint.port, protocol) {
+        Some(name) => format!("{}:{}", endpoint.addr, name),
+        None => endpoint.to_string(),
+    }
+}
+
+/// Log the ports of a segment or datagram by name, when either has one
+pub(crate) fn print_ports(src: u16, dst: u16, protocol: IpProtocol) {
+    if SERVICES.name(src, protocol).is_some() || SERVICES.name(dst, protocol).is_some() {
+        tracing::debug!(
+            "    svc: {} > {}",
+            SERVICES.describe(src, protocol),
+            SERVICES.describe(dst, protocol)
+        );
+    }
+}
+
+/// Parse `port/proto`, e.g. `8080/tcp`
+pub fn parse_port(s: &str) -> Result<(u16, IpProtocol)> {
+    let Some((port, protocol)) = s.split_once('/') else {
+        bail!("expected port/proto: {}", s);
+    };
+    let port = port
+        .parse()
+        .with_context(|| format!("invalid port {}", port))?;
+    let protocol = match protocol {
+        "tcp" => IpProtocol::Tcp,
+        "udp" => IpProtocol::Udp,
+        other => bail!("unknown protocol {}", other),
+    };
+    Ok((port, protocol))
+}
+
+/// Parse `addr:port`, `addr:service` or `service://addr[:port]`, looking
+/// service names up in [`SERVICES`]
+pub fn resolve(s: &str) -> Result<IpEndpoint> {
+    let port = |name: &str| -> Result<u16> {
+        match name.parse() {
+            Ok(port) => Ok(port),
+            Err(_) => SERVICES
+                .port(name, None)
+                .ok_or_else(|| anyhow::anyhow!("Unknown service: {}", name)),
+        }
+    };
+    if let Some((scheme, rest)) = s.split_once("://") {
+        let rest = rest.trim_end_matches('/');
+        return match rest.rsplit_once(':') {
+            Some((addr, p)) => Ok(IpEndpoint::new(addr.parse()?, port(p)?)),
+            None => Ok(IpEndpoint::new(rest.parse::<IpAddr>()?, port(scheme)?)),
+        };
+    }
+    let (addr, p) = s
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Invalid endpoint format: {}", s))?;
+    Ok(IpEndpoint::new(addr.parse()?, port(p)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_services() {
+        let registry = ServiceRegistry::new();
+        assert_eq!(registry.name(80, IpProtocol::Tcp).as_deref(), Some("http"));
+        assert_eq!(registry.name(80, IpProtocol::Udp), None);
+        assert_eq!(registry.port("domain", Some(IpProtocol::Udp)), Some(53));
+        assert_eq!(registry.describe(7, IpProtocol::Udp), "7(echo)");
+        assert_eq!(registry.describe(40000, IpProtocol::Udp), "40000");
+
+        let text = "# local services\nhttp 8080/tcp www\n\ngame 7777/udp  # test\n";
+        assert_eq!(registry.load_str(text).unwrap(), 2);
+        assert_eq!(registry.port("http", None), Some(8080));
+        assert_eq!(registry.port("www", Some(IpProtocol::Tcp)), Some(8080));
+        assert_eq!(registry.port("game", Some(IpProtocol::Tcp)), None);
+        assert_eq!(
+            registry.name(7777, IpProtocol::Udp).as_deref(),
+            Some("game")
+        );
+        assert!(registry.load_str("bad 1/sctp").is_err());
+        assert!(registry.load_str("bad x/tcp").is_err());
+
+        registry.clear();
+        assert_eq!(registry.port("http", None), Some(80));
+
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(
+            resolve("192.0.2.1:http").unwrap(),
+            IpEndpoint::new(addr, 80)
+        );
+        assert_eq!(resolve("192.0.2.1:7").unwrap(), IpEndpoint::new(addr, 7));
+        assert_eq!(
+            resolve("https://192.0.2.1/").unwrap(),
+            IpEndpoint::new(addr, 443)
+        );
+        assert_eq!(
+            resolve("http://192.0.2.1:81").unwrap(),
+            IpEndpoint::new(addr, 81)
+        );
+        assert!(resolve("192.0.2.1:nope").is_err());
+        assert_eq!(
+            endpoint_name(IpEndpoint::new(addr, 22), IpProtocol::Tcp),
+            "192.0.2.1:ssh"
+        );
+    }
+}
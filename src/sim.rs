@@ -0,0 +1,201 @@
+//! Deterministic simulation of stacks on a virtual clock.
+//!
+//! A [`Simulation`] drives its stacks without threads. Their input is
+//! deferred, so a transmitted frame waits in the receiver's queue until
+//! [`Simulation::run`] handles the queues, one frame per stack per round in
+//! the order the stacks were added. Time only passes in
+//! [`Simulation::advance`], which runs the timers that fall due on the way.
+//! The same calls then produce the same frames in the same order on every
+//! run, so retransmissions and timeouts can be tested without sleeping:
+//!
+//! ```
+//! # use std::str::FromStr;
+//! # use microps::builder::memory;
+//! # use microps::device::ether::EtherAddr;
+//! # use microps::protocol::ip::IpEndpoint;
+//! # use microps::protocol::tcp;
+//! # use microps::sim::Simulation;
+//! # use std::time::Duration;
+//! let mut sim = Simulation::new();
+//! let nic = memory(EtherAddr::from_seed("sim"));
+//! let wire = nic.queue().unwrap();
+//! let stack = sim.add(
+//!     sim.builder()
+//!         .device(nic)
+//!         .ip("192.0.2.1/24")
+//!         .build()?,
+//! );
+//!
+//! // Nobody answers the SYN: it is resent after the initial RTO of 1s
+//! let foreign = IpEndpoint::from_str("192.0.2.2:80")?;
+//! tcp::connect_nonblocking(None, foreign, None, &stack.ctx(), &stack.devices())?;
+//! sim.advance(Duration::from_millis(900));
+//! assert_eq!(wire.lock().unwrap().len(), 1);
+//! sim.advance(Duration::from_millis(200));
+//! assert_eq!(wire.lock().unwrap().len(), 2);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::builder::NetStackBuilder;
+use crate::clock::{Clock, VirtualClock};
+use crate::stack::NetStack;
+use crate::timer::TIMER_RESOLUTION;
+
+/// Stacks sharing one [`VirtualClock`], run by hand
+#[derive(Default)]
+pub struct Simulation {
+    time: VirtualClock,
+    stacks: Vec<Arc<NetStack>>,
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The clock simulated stacks must be created with
+    pub fn clock(&self) -> Clock {
+        Clock::new(self.time.clone())
+    }
+
+    /// A builder for a stack on the simulation's clock. Do not start its
+    /// threads: the simulation runs the stack itself.
+    pub fn builder(&self) -> NetStackBuilder {
+        NetStack::builder().clock(self.clock())
+    }
+
+    /// Time advanced since the simulation started
+    pub fn elapsed(&self) -> Duration {
+        self.time.elapsed()
+    }
+
+    /// Take over running `stack`, which must be on [`clock`](Self::clock);
+    /// from now on its received frames wait for [`run`](Self::run)
+    pub fn add(&mut self, stack: NetStack) -> Arc<NetStack> {
+        stack.set_deferred_input(true);
+        let stack = Arc::new(stack);
+        self.stacks.push(Arc::clone(&stack));
+        stack
+    }
+
+    pub fn stacks(&self) -> &[Arc<NetStack>] {
+        &self.stacks
+    }
+
+    /// Handle queued frames, one per stack per round, until every queue is
+    /// empty; returns how many were handled. The clock does not move.
+    pub fn run(&self) -> usize {
+        let mut handled = 0;
+        loop {
+            let mut round = 0;
+            for stack in &self.stacks {
+                {
+                    let devices = stack.devices();
+                    devices.irqs().dispatch(&devices);
+                }
+                round += stack.poll(1);
+            }
+            if round == 0 {
+                return handled;
+            }
+            handled += round;
+        }
+    }
+
+    /// Move the clock forward by `by`, in steps of [`TIMER_RESOLUTION`]. At
+    /// every step the stacks' due timers run, then the frames they caused
+    /// (see [`run`](Self::run)). Returns the number of frames handled.
+    pub fn advance(&self, by: Duration) -> usize {
+        let mut handled = self.run();
+        let mut left = by;
+        while !left.is_zero() {
+            let step = left.min(TIMER_RESOLUTION);
+            self.time.advance(step);
+            left -= step;
+            for stack in &self.stacks {
+                stack.tick();
+            }
+            handled += self.run();
+        }
+        handled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::protocol::ip::IpEndpoint;
+    use crate::protocol::tcp::{self, TcpState};
+    use crate::stack::connect_veth;
+
+    fn ep(s: &str) -> IpEndpoint {
+        IpEndpoint::from_str(s).unwrap()
+    }
+
+    /// Two stacks on a veth link, 192.0.2.1 and 192.0.2.2
+    fn pair(sim: &mut Simulation) -> (Arc<NetStack>, Arc<NetStack>) {
+        let a = sim.add(sim.builder().build().unwrap());
+        let b = sim.add(sim.builder().build().unwrap());
+        let (a_index, b_index) = connect_veth(&a, &b).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        b.run().unwrap();
+        (a, b)
+    }
+
+    /// An established connection from `a` to port 80 of `b`
+    fn establish(sim: &Simulation, a: &NetStack, b: &NetStack) -> (tcp::TcpPcbId, tcp::TcpPcbId) {
+        let listener = b.ctx().tcp.listen_backlog(ep("192.0.2.2:80"), 1).unwrap();
+        let client =
+            tcp::connect_nonblocking(None, ep("192.0.2.2:80"), None, &a.ctx(), &a.devices())
+                .unwrap();
+        assert_eq!(a.ctx().tcp.state(client), Some(TcpState::SynSent));
+        sim.run();
+        assert_eq!(a.ctx().tcp.state(client), Some(TcpState::Established));
+        let server = b
+            .ctx()
+            .tcp
+            .accept(listener, Some(Duration::ZERO))
+            .unwrap()
+            .unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_sim_handshake_waits_for_run() {
+        let mut sim = Simulation::new();
+        let (a, b) = pair(&mut sim);
+        let (_, server) = establish(&sim, &a, &b);
+        assert_eq!(b.ctx().tcp.state(server), Some(TcpState::Established));
+        assert_eq!(sim.run(), 0);
+        assert_eq!(sim.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_sim_time_wait_ends_on_virtual_time() {
+        let mut sim = Simulation::new();
+        let (a, b) = pair(&mut sim);
+        let (client, server) = establish(&sim, &a, &b);
+
+        tcp::close(client, &a.ctx(), &a.devices()).unwrap();
+        sim.run();
+        tcp::close(server, &b.ctx(), &b.devices()).unwrap();
+        sim.run();
+        assert_eq!(a.ctx().tcp.state(client), Some(TcpState::TimeWait));
+
+        // 2 * MSL
+        sim.advance(Duration::from_secs(59));
+        assert_eq!(a.ctx().tcp.state(client), Some(TcpState::TimeWait));
+        sim.advance(Duration::from_secs(1));
+        assert_eq!(a.ctx().tcp.state(client), None);
+        assert_eq!(sim.elapsed(), Duration::from_secs(60));
+    }
+}
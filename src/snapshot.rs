@@ -0,0 +1,232 @@
+//! Tables the data path reads without taking a lock.
+//!
+//! A [`SnapshotCell`] holds the current version of a table in an `Arc`.
+//! Updates copy the table, change the copy and swap it in, one at a time;
+//! the lock readers may take is held only for the swap. Readers go through
+//! [`SnapshotCell::read`], which keeps the last few versions a thread has
+//! read in a thread-local cache: as long as the cell's version counter has
+//! not moved, a read is one atomic load and never writes to memory other
+//! threads read, so any number of threads look up routes and neighbors
+//! without contending. A reader sees an update from its first read after the
+//! swap.
+//!
+//! Old versions stay alive while a thread still caches them, that is until it
+//! reads the cell again or [`CACHE_SLOTS`] other cells push it out.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Cells a thread keeps its last read version of
+pub const CACHE_SLOTS: usize = 4;
+
+/// Identifies cells in the caches; never reused, unlike an address
+static CELLS: AtomicU64 = AtomicU64::new(0);
+
+struct Cached {
+    cell: u64,
+    version: u64,
+    value: Arc<dyn Any + Send + Sync>,
+}
+
+thread_local! {
+    /// Versions this thread read last, most recent first
+    static CACHE: RefCell<Vec<Cached>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A value replaced as a whole and read without a lock (see the
+/// [module](self) docs)
+pub struct SnapshotCell<T> {
+    id: u64,
+    /// Bumped by every swap, while `current` is locked
+    version: AtomicU64,
+    current: Mutex<Arc<T>>,
+    /// Held by an update from copying the current version to swapping
+    writer: Mutex<()>,
+}
+
+impl<T: Send + Sync + 'static> SnapshotCell<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            id: CELLS.fetch_add(1, Ordering::Relaxed),
+            version: AtomicU64::new(0),
+            current: Mutex::new(Arc::new(value)),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The current version, kept as long as the `Arc` is.
+    ///
+    /// This takes the lock of the current version, so it is not for the data
+    /// path, which uses [`read`](Self::read). It serves updates, listings
+    /// that keep a version while they iterate, and the reads `read` cannot
+    /// answer from the cache (a cell read inside another's `read`, or a
+    /// thread whose cache is already gone).
+    pub fn load(&self) -> Arc<T> {
+        Arc::clone(&self.current.lock().unwrap())
+    }
+
+    /// Run `f` on the current version, from this thread's cache when it is
+    /// up to date
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let version = self.version.load(Ordering::Acquire);
+        let mut f = Some(f);
+        let cached = CACHE.try_with(|cache| {
+            // Already borrowed when `f` reads another cell
+            let mut cache = cache.try_borrow_mut().ok()?;
+            let pos = match cache.iter().position(|cached| cached.cell == self.id) {
+                Some(pos) if cache[pos].version == version => pos,
+                pos => self.refresh(&mut cache, pos),
+            };
+            let cached = &cache[pos];
+            let value = cached.value.downcast_ref::<T>()?;
+            f.take().map(|f| f(value))
+        });
+        if let Ok(Some(result)) = cached {
+            return result;
+        }
+        let f = f.expect("not called on a cache miss");
+        f(&self.load())
+    }
+
+    /// Put the current version first in `cache`, in place of the stale one
+    /// at `pos` if any; returns its position
+    #[cold]
+    fn refresh(&self, cache: &mut Vec<Cached>, pos: Option<usize>) -> usize {
+        let (version, value) = {
+            let current = self.current.lock().unwrap();
+            (self.version.load(Ordering::Relaxed), Arc::clone(&current))
+        };
+        if let Some(pos) = pos {
+            cache.remove(pos);
+        }
+        cache.truncate(CACHE_SLOTS - 1);
+        let value: Arc<dyn Any + Send + Sync> = value;
+        let cell = self.id;
+        cache.insert(
+            0,
+            Cached {
+                cell,
+                version,
+                value,
+            },
+        );
+        0
+    }
+
+    /// Swap in the result of running `f` on a copy of the current version;
+    /// nothing changes if `f` fails
+    pub fn update<R, E>(&self, f: impl FnOnce(&mut T) -> Result<R, E>) -> Result<R, E>
+    where
+        T: Clone,
+    {
+        let _writer = self.writer.lock().unwrap();
+        let mut next = T::clone(&self.load());
+        let result = f(&mut next)?;
+        self.swap(next);
+        Ok(result)
+    }
+
+    /// Replace the value
+    pub fn store(&self, value: T) {
+        let _writer = self.writer.lock().unwrap();
+        self.swap(value);
+    }
+
+    fn swap(&self, value: T) {
+        let value = Arc::new(value);
+        let old = {
+            let mut current = self.current.lock().unwrap();
+            self.version.fetch_add(1, Ordering::Release);
+            std::mem::replace(&mut *current, value)
+        };
+        // The last reference, unless a thread still caches it, is dropped
+        // with no lock held
+        drop(old);
+    }
+}
+
+impl<T: Default + Send + Sync + 'static> Default for SnapshotCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_read_sees_updates() {
+        let cell = SnapshotCell::new(1);
+        assert_eq!(cell.read(|n| *n), 1);
+        cell.store(2);
+        assert_eq!(cell.read(|n| *n), 2);
+        cell.update(|n| {
+            *n += 1;
+            Ok::<_, ()>(())
+        })
+        .unwrap();
+        assert_eq!(cell.read(|n| *n), 3);
+
+        // A failed update swaps nothing in
+        let old = cell.load();
+        assert!(
+            cell.update(|n| {
+                *n = 10;
+                Err::<(), ()>(())
+            })
+            .is_err()
+        );
+        assert_eq!(cell.read(|n| *n), 3);
+        assert!(Arc::ptr_eq(&old, &cell.load()));
+    }
+
+    #[test]
+    fn test_read_nested_and_past_cache() {
+        let base = SnapshotCell::new(0);
+        let cells: Vec<_> = (0..CACHE_SLOTS * 2).map(SnapshotCell::new).collect();
+        for round in 0..3 {
+            for (i, cell) in cells.iter().enumerate() {
+                // The inner read finds the cache borrowed and loads instead
+                let sum = cell.read(|a| base.read(|b| a + b));
+                assert_eq!(sum, i + round);
+                assert_eq!(base.read(|b| *b), round);
+            }
+            base.store(round + 1);
+        }
+    }
+
+    #[test]
+    fn test_readers_see_whole_updates() {
+        let cell = SnapshotCell::new((0u64, 0u64));
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    let mut last = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let (a, b) = cell.read(|pair| *pair);
+                        assert_eq!(a, b);
+                        assert!(a >= last);
+                        last = a;
+                    }
+                });
+            }
+            for _ in 0..10_000 {
+                cell.update(|(a, b)| {
+                    *a += 1;
+                    *b += 1;
+                    Ok::<_, ()>(())
+                })
+                .unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(cell.read(|pair| *pair), (10_000, 10_000));
+    }
+}
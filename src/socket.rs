@@ -0,0 +1,1043 @@
+//! Descriptor-based socket API over the UDP and TCP control blocks.
+//!
+//! [`socket`] creates an unbound socket and returns a [`SocketFd`]; every other
+//! call takes that descriptor, whatever protocol is behind it. Datagram sockets
+//! map to a UDP control block from the start, stream sockets get a TCP control
+//! block once they [`listen`] or [`connect`].
+//!
+//! Sockets block by default, for as long as [`set_read_timeout`] and
+//! [`set_write_timeout`] allow. After [`set_nonblocking`], calls that would have
+//! to wait fail with [`NetError::WouldBlock`] instead. [`poll`] waits for any of a set of
+//! sockets to become ready.
+//!
+//! Every call fails with a [`NetError`]; see [`error`](crate::error).
+//!
+//! A blocked call keeps the `ctx` and `devices` it was given, so it holds the
+//! stack's read guards while it waits and configuration waits with it. The
+//! [`net`](crate::net) types wait with the stack unlocked instead.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use std::time::Duration;
+
+use crate::context::ProtocolContexts;
+use crate::device::DeviceManager;
+use crate::error::{NetError, Result};
+use crate::limits::StackLimits;
+use crate::platform::Instant;
+use crate::protocol::igmp;
+use crate::protocol::ip::{IpAddr, IpEndpoint, IpProtocol};
+use crate::protocol::raw::{self, RawPcbId};
+use crate::protocol::tcp::{self, Shutdown, TcpAuth, TcpPcbId, TcpState};
+use crate::protocol::udp::{self, UdpPcbId};
+use crate::protocol::{MSG_DONTWAIT, POLLERR, POLLHUP, POLLNVAL, POLLOUT, READINESS};
+use crate::services::endpoint_name;
+
+/// How long [`connect`] waits for a stream connection to be established
+pub const SOCKET_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketType {
+    /// Reliable byte stream (TCP)
+    Stream,
+    /// Datagrams (UDP)
+    Dgram,
+    /// Payloads of IP packets of one protocol (see [`crate::protocol::raw`]);
+    /// endpoints carry port 0
+    Raw(IpProtocol),
+}
+
+/// Handle of an open socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SocketFd(u32);
+
+impl fmt::Display for SocketFd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// What a descriptor refers to
+#[derive(Clone)]
+enum Socket {
+    /// `peer` is the default destination set by [`connect`]
+    Dgram {
+        pcb: UdpPcbId,
+        peer: Option<IpEndpoint>,
+    },
+    /// Stream socket that neither listens nor is connected yet; `auth` is
+    /// handed to the control block it gets
+    Stream {
+        local: Option<IpEndpoint>,
+        auth: Option<Arc<dyn TcpAuth>>,
+    },
+    Listener(TcpPcbId),
+    /// `peer` is the default destination set by [`connect`]
+    Raw {
+        pcb: RawPcbId,
+        peer: Option<IpAddr>,
+    },
+    /// Non-blocking connect still in progress
+    Connecting(TcpPcbId),
+    Connection(TcpPcbId),
+}
+
+struct Entry {
+    socket: Socket,
+    nonblocking: bool,
+    /// How long receiving and accepting wait at most; forever if `None`
+    read_timeout: Option<Duration>,
+    /// How long sending waits at most for window space; forever if `None`
+    write_timeout: Option<Duration>,
+}
+
+struct SocketState {
+    sockets: HashMap<SocketFd, Entry>,
+    next_fd: u32,
+    /// Sockets open at most
+    limit: usize,
+}
+
+/// Open sockets and the control blocks behind them
+pub struct SocketTable {
+    state: Mutex<SocketState>,
+}
+
+impl SocketTable {
+    pub fn new() -> Self {
+        let limits = StackLimits::default();
+        Self::with_limit(limits.udp_sockets + limits.tcp_sockets + limits.raw_sockets)
+    }
+
+    /// Table of at most `limit` sockets
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            state: Mutex::new(SocketState {
+                sockets: HashMap::new(),
+                next_fd: 0,
+                limit,
+            }),
+        }
+    }
+
+    fn insert(&self, socket: Socket) -> Result<SocketFd> {
+        let mut state = self.state.lock().unwrap();
+        if state.sockets.len() >= state.limit {
+            return Err(NetError::LimitReached {
+                what: "open sockets",
+                limit: state.limit,
+            });
+        }
+        let fd = SocketFd(state.next_fd);
+        state.next_fd = state.next_fd.wrapping_add(1);
+        state.sockets.insert(
+            fd,
+            Entry {
+                socket,
+                nonblocking: false,
+                read_timeout: None,
+                write_timeout: None,
+            },
+        );
+        Ok(fd)
+    }
+
+    fn get(&self, fd: SocketFd) -> Result<Socket> {
+        match self.state.lock().unwrap().sockets.get(&fd) {
+            Some(entry) => Ok(entry.socket.clone()),
+            None => Err(NetError::BadDescriptor(fd)),
+        }
+    }
+
+    fn set(&self, fd: SocketFd, socket: Socket) {
+        if let Some(entry) = self.state.lock().unwrap().sockets.get_mut(&fd) {
+            entry.socket = socket;
+        }
+    }
+
+    fn remove(&self, fd: SocketFd) -> Result<Socket> {
+        match self.state.lock().unwrap().sockets.remove(&fd) {
+            Some(entry) => Ok(entry.socket),
+            None => Err(NetError::BadDescriptor(fd)),
+        }
+    }
+
+    fn nonblocking(&self, fd: SocketFd) -> Result<bool> {
+        match self.state.lock().unwrap().sockets.get(&fd) {
+            Some(entry) => Ok(entry.nonblocking),
+            None => Err(NetError::BadDescriptor(fd)),
+        }
+    }
+
+    /// How long a receive (`write` false) or send on the socket may wait:
+    /// not at all when non-blocking, else its timeout
+    fn wait(&self, fd: SocketFd, write: bool) -> Result<Option<Duration>> {
+        match self.state.lock().unwrap().sockets.get(&fd) {
+            Some(entry) if entry.nonblocking => Ok(Some(Duration::ZERO)),
+            Some(entry) if write => Ok(entry.write_timeout),
+            Some(entry) => Ok(entry.read_timeout),
+            None => Err(NetError::BadDescriptor(fd)),
+        }
+    }
+
+    fn with_entry<T>(&self, fd: SocketFd, f: impl FnOnce(&mut Entry) -> T) -> Result<T> {
+        match self.state.lock().unwrap().sockets.get_mut(&fd) {
+            Some(entry) => Ok(f(entry)),
+            None => Err(NetError::BadDescriptor(fd)),
+        }
+    }
+
+    /// Open sockets, lowest descriptor first
+    fn entries(&self) -> Vec<(SocketFd, Socket)> {
+        let state = self.state.lock().unwrap();
+        let mut entries: Vec<_> = state
+            .sockets
+            .iter()
+            .map(|(&fd, entry)| (fd, entry.socket.clone()))
+            .collect();
+        entries.sort_by_key(|&(fd, _)| fd.0);
+        entries
+    }
+
+    /// Number of open sockets
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().sockets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for SocketTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create an unbound socket
+pub fn socket(type_: SocketType, ctx: &ProtocolContexts) -> Result<SocketFd> {
+    let socket = match type_ {
+        SocketType::Dgram => Socket::Dgram {
+            pcb: ctx.udp.open()?,
+            peer: None,
+        },
+        SocketType::Stream => Socket::Stream {
+            local: None,
+            auth: None,
+        },
+        SocketType::Raw(protocol) => Socket::Raw {
+            pcb: ctx.raw.open(protocol)?,
+            peer: None,
+        },
+    };
+    ctx.sockets
+        .insert(socket.clone())
+        .inspect_err(|_| match socket {
+            Socket::Dgram { pcb, .. } => {
+                let _ = ctx.udp.close(pcb);
+            }
+            Socket::Raw { pcb, .. } => {
+                let _ = ctx.raw.close(pcb);
+            }
+            _ => {}
+        })
+}
+
+/// Switch the socket between blocking and non-blocking mode
+pub fn set_nonblocking(fd: SocketFd, nonblocking: bool, ctx: &ProtocolContexts) -> Result<()> {
+    ctx.sockets
+        .with_entry(fd, |entry| entry.nonblocking = nonblocking)
+}
+
+/// Make [`recv`], [`recvfrom`] and [`accept`] fail with a timeout error after
+/// waiting `timeout`; `None` waits forever. A zero timeout is rejected.
+pub fn set_read_timeout(
+    fd: SocketFd,
+    timeout: Option<Duration>,
+    ctx: &ProtocolContexts,
+) -> Result<()> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(NetError::InvalidInput("zero timeout"));
+    }
+    ctx.sockets
+        .with_entry(fd, |entry| entry.read_timeout = timeout)
+}
+
+/// Make [`send`] fail with a timeout error after waiting `timeout` for window
+/// space; `None` waits forever. A zero timeout is rejected.
+pub fn set_write_timeout(
+    fd: SocketFd,
+    timeout: Option<Duration>,
+    ctx: &ProtocolContexts,
+) -> Result<()> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(NetError::InvalidInput("zero timeout"));
+    }
+    ctx.sockets
+        .with_entry(fd, |entry| entry.write_timeout = timeout)
+}
+
+pub fn read_timeout(fd: SocketFd, ctx: &ProtocolContexts) -> Result<Option<Duration>> {
+    ctx.sockets.with_entry(fd, |entry| entry.read_timeout)
+}
+
+pub fn write_timeout(fd: SocketFd, ctx: &ProtocolContexts) -> Result<Option<Duration>> {
+    ctx.sockets.with_entry(fd, |entry| entry.write_timeout)
+}
+
+/// How long a receive (`write` false) or send on the socket may wait:
+/// `Some(Duration::ZERO)` when non-blocking, else its timeout
+pub(crate) fn wait_time(
+    fd: SocketFd,
+    write: bool,
+    ctx: &ProtocolContexts,
+) -> Result<Option<Duration>> {
+    ctx.sockets.wait(fd, write)
+}
+
+/// Interrupts so far (see [`NetEvent::Interrupt`](crate::event::NetEvent::Interrupt)),
+/// which give up blocked calls
+pub(crate) fn interrupts(ctx: &ProtocolContexts) -> u64 {
+    ctx.tcp.interrupts() + ctx.udp.interrupts()
+}
+
+/// Control block of a connected stream socket. A non-blocking connect that
+/// has completed since makes the socket connected; one still in progress
+/// would block.
+fn connection(fd: SocketFd, socket: Socket, ctx: &ProtocolContexts) -> Result<TcpPcbId> {
+    match socket {
+        Socket::Connection(id) => Ok(id),
+        Socket::Connecting(id) => match ctx.tcp.state(id) {
+            Some(TcpState::SynSent | TcpState::SynReceived) => Err(NetError::WouldBlock),
+            Some(TcpState::Closed) | None => Err(NetError::ConnectionRefused),
+            Some(_) => {
+                ctx.sockets.set(fd, Socket::Connection(id));
+                Ok(id)
+            }
+        },
+        _ => Err(NetError::NotConnected),
+    }
+}
+
+/// Error for a call `fd` cannot take in its type or state
+fn invalid(fd: SocketFd, reason: &'static str) -> NetError {
+    NetError::InvalidSocket { fd, reason }
+}
+
+/// Bind the socket to `local`. A stream socket only records the endpoint;
+/// conflicts are reported by [`listen`] or [`connect`].
+pub fn bind(fd: SocketFd, local: IpEndpoint, ctx: &ProtocolContexts) -> Result<()> {
+    match ctx.sockets.get(fd)? {
+        Socket::Dgram { pcb, .. } => Ok(ctx.udp.bind(pcb, local)?),
+        Socket::Raw { pcb, .. } => Ok(ctx.raw.bind(pcb, local.addr)?),
+        Socket::Stream { local: None, auth } => {
+            ctx.sockets.set(
+                fd,
+                Socket::Stream {
+                    local: Some(local),
+                    auth,
+                },
+            );
+            Ok(())
+        }
+        _ => Err(invalid(fd, "socket already bound")),
+    }
+}
+
+/// Listen for connections on a bound stream socket, keeping at most `backlog`
+/// of them pending
+pub fn listen(fd: SocketFd, backlog: usize, ctx: &ProtocolContexts) -> Result<()> {
+    match ctx.sockets.get(fd)? {
+        Socket::Stream {
+            local: Some(local),
+            auth,
+        } => {
+            let id = ctx.tcp.listen_backlog(local, backlog)?;
+            ctx.tcp.set_auth(id, auth)?;
+            ctx.sockets.set(fd, Socket::Listener(id));
+            Ok(())
+        }
+        Socket::Stream { local: None, .. } => Err(invalid(fd, "socket not bound")),
+        _ => Err(invalid(fd, "not a stream socket waiting to listen")),
+    }
+}
+
+/// Block until a connection arrives on a listening socket and return a new
+/// socket for it along with the peer's endpoint
+pub fn accept(fd: SocketFd, ctx: &ProtocolContexts) -> Result<(SocketFd, IpEndpoint)> {
+    accept_timeout(fd, ctx.sockets.wait(fd, false)?, ctx)
+}
+
+/// Like [`accept`], waiting `timeout` instead of the socket's own
+pub(crate) fn accept_timeout(
+    fd: SocketFd,
+    timeout: Option<Duration>,
+    ctx: &ProtocolContexts,
+) -> Result<(SocketFd, IpEndpoint)> {
+    let Socket::Listener(listener) = ctx.sockets.get(fd)? else {
+        return Err(invalid(fd, "socket not listening"));
+    };
+    let Some(id) = ctx.tcp.accept(listener, timeout)? else {
+        if timeout == Some(Duration::ZERO) {
+            return Err(NetError::WouldBlock);
+        }
+        return Err(NetError::TimedOut);
+    };
+    let foreign = ctx.tcp.foreign(id).unwrap_or_default();
+    match ctx.sockets.insert(Socket::Connection(id)) {
+        Ok(conn) => Ok((conn, foreign)),
+        Err(e) => {
+            let _ = ctx.tcp.release(id);
+            Err(e)
+        }
+    }
+}
+
+/// Connect the socket to `foreign`.
+///
+/// A stream socket performs the handshake, waiting up to
+/// [`SOCKET_CONNECT_TIMEOUT`]. In non-blocking mode it fails with [`NetError::WouldBlock`]
+/// while the handshake is in progress; call it again to learn the outcome.
+/// A datagram socket only records `foreign` as the destination of [`send`].
+pub fn connect(
+    fd: SocketFd,
+    foreign: IpEndpoint,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    match ctx.sockets.get(fd)? {
+        Socket::Dgram { pcb, .. } => {
+            ctx.sockets.set(
+                fd,
+                Socket::Dgram {
+                    pcb,
+                    peer: Some(foreign),
+                },
+            );
+            Ok(())
+        }
+        Socket::Raw { pcb, .. } => {
+            ctx.sockets.set(
+                fd,
+                Socket::Raw {
+                    pcb,
+                    peer: Some(foreign.addr),
+                },
+            );
+            Ok(())
+        }
+        Socket::Stream { local, auth } if ctx.sockets.nonblocking(fd)? => {
+            let id = tcp::connect_nonblocking(local, foreign, auth, ctx, devices)?;
+            ctx.sockets.set(fd, Socket::Connecting(id));
+            connection(fd, Socket::Connecting(id), ctx).map(|_| ())
+        }
+        Socket::Stream { local, auth } => {
+            let id = tcp::connect_auth(local, foreign, SOCKET_CONNECT_TIMEOUT, auth, ctx, devices)?;
+            ctx.sockets.set(fd, Socket::Connection(id));
+            Ok(())
+        }
+        socket @ Socket::Connecting(_) => connection(fd, socket, ctx).map(|_| ()),
+        Socket::Listener(_) => Err(invalid(fd, "socket is listening")),
+        Socket::Connection(_) => Err(invalid(fd, "socket already connected")),
+    }
+}
+
+/// Send `data` on a connected socket and return the number of bytes taken
+pub fn send(
+    fd: SocketFd,
+    data: &[u8],
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    send_timeout(fd, data, ctx.sockets.wait(fd, true)?, ctx, devices)
+}
+
+/// Like [`send`], waiting `timeout` instead of the socket's own
+pub(crate) fn send_timeout(
+    fd: SocketFd,
+    data: &[u8],
+    timeout: Option<Duration>,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    match ctx.sockets.get(fd)? {
+        Socket::Dgram {
+            pcb,
+            peer: Some(peer),
+        } => Ok(udp::sendto(pcb, data, peer, ctx, devices)?),
+        Socket::Raw {
+            pcb,
+            peer: Some(peer),
+        } => Ok(raw::output(pcb, data, peer, ctx, devices)?),
+        Socket::Dgram { .. } | Socket::Raw { .. } => Err(NetError::NotConnected),
+        socket => {
+            let id = connection(fd, socket, ctx)?;
+            Ok(tcp::send_timeout(id, data, timeout, ctx, devices)?)
+        }
+    }
+}
+
+/// Send a datagram to `foreign`
+pub fn sendto(
+    fd: SocketFd,
+    data: &[u8],
+    foreign: IpEndpoint,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    match ctx.sockets.get(fd)? {
+        Socket::Dgram { pcb, .. } => Ok(udp::sendto(pcb, data, foreign, ctx, devices)?),
+        Socket::Raw { pcb, .. } => Ok(raw::output(pcb, data, foreign.addr, ctx, devices)?),
+        _ => Err(invalid(fd, "not a datagram socket")),
+    }
+}
+
+/// Block until data arrives and copy it into `buf`; like [`recvfrom`]
+/// without the sender
+pub fn recv(
+    fd: SocketFd,
+    buf: &mut [u8],
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<usize> {
+    recvfrom(fd, buf, ctx, devices).map(|(len, _)| len)
+}
+
+/// Block until data arrives, copy it into `buf` and return its length and
+/// sender. A datagram longer than `buf` is truncated; a stream returns 0 at
+/// end of stream.
+pub fn recvfrom(
+    fd: SocketFd,
+    buf: &mut [u8],
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<(usize, IpEndpoint)> {
+    recvfrom_timeout(fd, buf, ctx.sockets.wait(fd, false)?, ctx, devices)
+}
+
+/// Like [`recvfrom`], waiting `timeout` instead of the socket's own
+pub(crate) fn recvfrom_timeout(
+    fd: SocketFd,
+    buf: &mut [u8],
+    timeout: Option<Duration>,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<(usize, IpEndpoint)> {
+    let flags = if timeout == Some(Duration::ZERO) {
+        MSG_DONTWAIT
+    } else {
+        0
+    };
+    match ctx.sockets.get(fd)? {
+        Socket::Dgram { pcb, .. } => {
+            let got = ctx.udp.recv_timeout(pcb, buf, flags, timeout)?;
+            Ok((got.len, got.foreign))
+        }
+        Socket::Raw { pcb, .. } => {
+            let got = ctx.raw.recv_timeout(pcb, buf, flags, timeout)?;
+            Ok((got.len, IpEndpoint::new(got.src, 0)))
+        }
+        socket => {
+            let id = connection(fd, socket, ctx)?;
+            let len = tcp::receive_timeout(id, buf, timeout, ctx, devices)?;
+            Ok((len, ctx.tcp.foreign(id).unwrap_or_default()))
+        }
+    }
+}
+
+/// Shut down one or both directions of a stream connection
+pub fn shutdown(
+    fd: SocketFd,
+    how: Shutdown,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    let id = connection(fd, ctx.sockets.get(fd)?, ctx)?;
+    tcp::shutdown(id, how, ctx, devices)
+}
+
+/// Sign and check every segment of a stream socket with `auth` (a TCP MD5
+/// style option); `None` turns it off. Set it before [`listen`] or [`connect`]
+/// to cover the handshake too.
+pub fn set_tcp_auth(
+    fd: SocketFd,
+    auth: Option<Arc<dyn TcpAuth>>,
+    ctx: &ProtocolContexts,
+) -> Result<()> {
+    match ctx.sockets.get(fd)? {
+        Socket::Stream { local, .. } => {
+            ctx.sockets.set(fd, Socket::Stream { local, auth });
+            Ok(())
+        }
+        Socket::Listener(id) | Socket::Connecting(id) | Socket::Connection(id) => {
+            Ok(ctx.tcp.set_auth(id, auth)?)
+        }
+        Socket::Dgram { .. } | Socket::Raw { .. } => Err(invalid(fd, "not a stream socket")),
+    }
+}
+
+/// Local endpoint of the socket; unspecified while unbound
+pub fn local_addr(fd: SocketFd, ctx: &ProtocolContexts) -> Result<IpEndpoint> {
+    let local = match ctx.sockets.get(fd)? {
+        Socket::Dgram { pcb, .. } => ctx.udp.local(pcb),
+        Socket::Raw { pcb, .. } => ctx.raw.local(pcb).map(|addr| IpEndpoint::new(addr, 0)),
+        Socket::Stream { local, .. } => Some(local.unwrap_or_default()),
+        Socket::Listener(id) | Socket::Connecting(id) | Socket::Connection(id) => ctx.tcp.local(id),
+    };
+    local.ok_or_else(|| invalid(fd, "socket closed"))
+}
+
+/// Endpoint of the peer the socket is connected to
+pub fn peer_addr(fd: SocketFd, ctx: &ProtocolContexts) -> Result<IpEndpoint> {
+    match ctx.sockets.get(fd)? {
+        Socket::Dgram {
+            peer: Some(peer), ..
+        } => Ok(peer),
+        Socket::Raw {
+            peer: Some(peer), ..
+        } => Ok(IpEndpoint::new(peer, 0)),
+        Socket::Dgram { .. } | Socket::Raw { .. } => Err(NetError::NotConnected),
+        socket => ctx
+            .tcp
+            .foreign(connection(fd, socket, ctx)?)
+            .ok_or_else(|| invalid(fd, "socket closed")),
+    }
+}
+
+/// Join the datagram socket to multicast `group` on the interface with
+/// address `iface`, or the one the route to the group leaves through if
+/// unspecified. The socket leaves its groups when closed.
+pub fn join_multicast(
+    fd: SocketFd,
+    group: IpAddr,
+    iface: IpAddr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    let Socket::Dgram { pcb, .. } = ctx.sockets.get(fd)? else {
+        return Err(invalid(fd, "not a datagram socket"));
+    };
+    if ctx
+        .udp
+        .groups(pcb)
+        .iter()
+        .any(|&(joined, addr)| joined == group && (iface == IpAddr::ANY || addr == iface))
+    {
+        return Err(invalid(fd, "already a member of the group"));
+    }
+    let iface = igmp::join(iface, group, ctx, devices)?;
+    ctx.udp.add_group(pcb, group, iface)?;
+    Ok(())
+}
+
+/// Leave a group joined with [`join_multicast`]
+pub fn leave_multicast(
+    fd: SocketFd,
+    group: IpAddr,
+    iface: IpAddr,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) -> Result<()> {
+    let Socket::Dgram { pcb, .. } = ctx.sockets.get(fd)? else {
+        return Err(invalid(fd, "not a datagram socket"));
+    };
+    let Some((_, iface)) = ctx
+        .udp
+        .groups(pcb)
+        .into_iter()
+        .find(|&(joined, addr)| joined == group && (iface == IpAddr::ANY || addr == iface))
+    else {
+        return Err(invalid(fd, "not a member of the group"));
+    };
+    ctx.udp.remove_group(pcb, group, iface)?;
+    igmp::leave(iface, group, ctx, devices)
+}
+
+/// Close the socket. A stream connection is closed gracefully with
+/// [`tcp::close`]; the descriptor is released right away.
+pub fn close(fd: SocketFd, ctx: &ProtocolContexts, devices: &DeviceManager) -> Result<()> {
+    match ctx.sockets.remove(fd)? {
+        Socket::Dgram { pcb, .. } => {
+            for (group, iface) in ctx.udp.groups(pcb) {
+                if let Err(e) = igmp::leave(iface, group, ctx, devices) {
+                    tracing::debug!("socket: leaving {} failed: {}", group, e);
+                }
+            }
+            Ok(ctx.udp.close(pcb)?)
+        }
+        Socket::Raw { pcb, .. } => Ok(ctx.raw.close(pcb)?),
+        Socket::Stream { .. } => Ok(()),
+        Socket::Listener(id) | Socket::Connecting(id) | Socket::Connection(id) => {
+            Ok(tcp::close(id, ctx, devices)?)
+        }
+    }
+}
+
+/// A socket and the events to wait for, like `struct pollfd`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollFd {
+    pub fd: SocketFd,
+    /// Events of interest ([`POLLIN`](crate::protocol::POLLIN), [`POLLOUT`])
+    pub events: u16,
+    /// Events that occurred, filled in by [`poll`]; [`POLLERR`], [`POLLHUP`]
+    /// and [`POLLNVAL`] are reported whether asked for or not
+    pub revents: u16,
+}
+
+impl PollFd {
+    pub fn new(fd: SocketFd, events: u16) -> Self {
+        Self {
+            fd,
+            events,
+            revents: 0,
+        }
+    }
+}
+
+/// Wake `waker` when the control block behind the socket changes, for a
+/// future that got [`NetError::WouldBlock`]
+pub fn register_waker(fd: SocketFd, waker: &Waker, ctx: &ProtocolContexts) -> Result<()> {
+    match ctx.sockets.get(fd)? {
+        Socket::Dgram { pcb, .. } => ctx.udp.register_waker(pcb, waker),
+        Socket::Raw { pcb, .. } => ctx.raw.register_waker(pcb, waker),
+        Socket::Stream { .. } => return Err(NetError::NotConnected),
+        Socket::Listener(id) | Socket::Connecting(id) | Socket::Connection(id) => {
+            ctx.tcp.register_waker(id, waker)
+        }
+    }
+    Ok(())
+}
+
+/// Events the socket is ready for right now
+fn poll_events(fd: SocketFd, ctx: &ProtocolContexts) -> u16 {
+    let Ok(socket) = ctx.sockets.get(fd) else {
+        return POLLNVAL;
+    };
+    match socket {
+        Socket::Dgram { pcb, .. } => ctx.udp.poll_events(pcb).unwrap_or(POLLHUP),
+        Socket::Raw { pcb, .. } => ctx.raw.poll_events(pcb).unwrap_or(POLLHUP),
+        // Like an unconnected socket on Linux
+        Socket::Stream { .. } => POLLOUT | POLLHUP,
+        Socket::Connecting(id) => match ctx.tcp.poll_events(id) {
+            Some(events) if events & POLLHUP != 0 => POLLERR | POLLHUP,
+            Some(events) => events,
+            None => POLLERR | POLLHUP,
+        },
+        Socket::Listener(id) | Socket::Connection(id) => ctx.tcp.poll_events(id).unwrap_or(POLLHUP),
+    }
+}
+
+/// Wait until at least one of `fds` is ready for the events it asks for, or
+/// `timeout` passes (never if `None`; `Duration::ZERO` just checks).
+///
+/// Fills in `revents` of every entry and returns how many have any set;
+/// 0 means the timeout passed.
+pub fn poll(fds: &mut [PollFd], timeout: Option<Duration>, ctx: &ProtocolContexts) -> usize {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        // Read the generation first so that a change while checking is not missed
+        let seen = READINESS.generation();
+        let mut ready = 0;
+        for pollfd in fds.iter_mut() {
+            let always = POLLERR | POLLHUP | POLLNVAL;
+            pollfd.revents = poll_events(pollfd.fd, ctx) & (pollfd.events | always);
+            if pollfd.revents != 0 {
+                ready += 1;
+            }
+        }
+        if ready > 0 || !READINESS.wait(seen, deadline) {
+            return ready;
+        }
+    }
+}
+
+/// One line per open socket: descriptor, protocol, local and foreign
+/// endpoints (ports by service name where known) and TCP state
+pub fn dump(ctx: &ProtocolContexts) -> String {
+    let name = |ep: Option<IpEndpoint>, protocol| match ep {
+        Some(ep) => endpoint_name(ep, protocol),
+        None => "*".to_string(),
+    };
+    let addr = |addr: Option<IpAddr>| addr.map_or("*".to_string(), |addr| addr.to_string());
+    let mut out = String::new();
+    for (fd, socket) in ctx.sockets.entries() {
+        let (proto, local, foreign, state) = match socket {
+            Socket::Dgram { pcb, peer } => (
+                "udp".to_string(),
+                name(ctx.udp.local(pcb), IpProtocol::Udp),
+                name(peer, IpProtocol::Udp),
+                None,
+            ),
+            Socket::Raw { pcb, peer } => (
+                format!("raw/{}", ctx.raw.protocol(pcb).map_or(0, IpProtocol::to_u8)),
+                addr(ctx.raw.local(pcb)),
+                addr(peer),
+                None,
+            ),
+            Socket::Stream { local, .. } => (
+                "tcp".to_string(),
+                name(local, IpProtocol::Tcp),
+                name(None, IpProtocol::Tcp),
+                None,
+            ),
+            Socket::Listener(id) | Socket::Connecting(id) | Socket::Connection(id) => (
+                "tcp".to_string(),
+                name(ctx.tcp.local(id), IpProtocol::Tcp),
+                name(ctx.tcp.foreign(id), IpProtocol::Tcp),
+                ctx.tcp.state(id),
+            ),
+        };
+        out.push_str(&format!(
+            "{:>3} {} {:<21} {:<21}",
+            fd, proto, local, foreign
+        ));
+        if let Some(state) = state {
+            out.push_str(&format!(" {:?}", state));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::device::ether::EtherAddr;
+    use crate::device::memory::MemoryQueue;
+    use crate::protocol::POLLIN;
+    use crate::stack::NetStack;
+
+    fn ep(s: &str) -> IpEndpoint {
+        IpEndpoint::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_socket_stream() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        let server = socket(SocketType::Stream, &ctx).unwrap();
+        assert!(listen(server, 4, &ctx).is_err());
+        bind(server, ep("0.0.0.0:80"), &ctx).unwrap();
+        listen(server, 4, &ctx).unwrap();
+
+        let client = socket(SocketType::Stream, &ctx).unwrap();
+        connect(client, ep("127.0.0.1:80"), &ctx, &devices).unwrap();
+        let (conn, peer) = accept(server, &ctx).unwrap();
+        assert_eq!(peer, local_addr(client, &ctx).unwrap());
+        assert_eq!(peer_addr(client, &ctx).unwrap(), ep("127.0.0.1:80"));
+
+        assert_eq!(send(client, b"hello", &ctx, &devices).unwrap(), 5);
+        let mut buf = [0u8; 16];
+        let (len, from) = recvfrom(conn, &mut buf, &ctx, &devices).unwrap();
+        assert_eq!((&buf[..len], from), (&b"hello"[..], peer));
+        assert!(sendto(conn, b"x", peer, &ctx, &devices).is_err());
+
+        close(client, &ctx, &devices).unwrap();
+        assert_eq!(recv(conn, &mut buf, &ctx, &devices).unwrap(), 0);
+        close(conn, &ctx, &devices).unwrap();
+        close(server, &ctx, &devices).unwrap();
+        assert!(ctx.sockets.is_empty());
+        assert!(send(client, b"gone", &ctx, &devices).is_err());
+    }
+
+    #[test]
+    fn test_socket_dgram() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        let server = socket(SocketType::Dgram, &ctx).unwrap();
+        bind(server, ep("127.0.0.1:7"), &ctx).unwrap();
+        let client = socket(SocketType::Dgram, &ctx).unwrap();
+        assert!(send(client, b"nowhere", &ctx, &devices).is_err());
+        connect(client, ep("127.0.0.1:7"), &ctx, &devices).unwrap();
+        send(client, b"ping", &ctx, &devices).unwrap();
+
+        let mut buf = [0u8; 16];
+        let (len, from) = recvfrom(server, &mut buf, &ctx, &devices).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from.port, local_addr(client, &ctx).unwrap().port);
+        sendto(server, b"pong", from, &ctx, &devices).unwrap();
+        assert_eq!(recv(client, &mut buf, &ctx, &devices).unwrap(), 4);
+        assert!(listen(server, 1, &ctx).is_err());
+        assert!(dump(&ctx).contains(&format!("{:>3} udp 127.0.0.1:echo ", server)));
+
+        close(server, &ctx, &devices).unwrap();
+        close(client, &ctx, &devices).unwrap();
+        assert!(close(client, &ctx, &devices).is_err());
+    }
+
+    #[test]
+    fn test_socket_errors() {
+        let limits = StackLimits {
+            udp_sockets: 1,
+            tcp_sockets: 1,
+            raw_sockets: 1,
+            ..StackLimits::default()
+        };
+        let stack = NetStack::with_limits(limits).unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        let dgram = socket(SocketType::Dgram, &ctx).unwrap();
+        let err = set_read_timeout(dgram, Some(Duration::ZERO), &ctx).unwrap_err();
+        assert!(matches!(err, NetError::InvalidInput(_)));
+        let err = send(dgram, b"nowhere", &ctx, &devices).unwrap_err();
+        assert!(matches!(err, NetError::NotConnected));
+        let err = listen(dgram, 1, &ctx).unwrap_err();
+        assert!(matches!(err, NetError::InvalidSocket { fd, .. } if fd == dgram));
+
+        bind(dgram, ep("127.0.0.1:7"), &ctx).unwrap();
+        let stream = socket(SocketType::Stream, &ctx).unwrap();
+        let raw = socket(SocketType::Raw(IpProtocol::Icmp), &ctx).unwrap();
+        let err = socket(SocketType::Stream, &ctx).unwrap_err();
+        assert!(matches!(err, NetError::LimitReached { limit: 3, .. }));
+        close(raw, &ctx, &devices).unwrap();
+        // Causes from the protocol below come through as they are
+        let err = socket(SocketType::Dgram, &ctx).unwrap_err();
+        assert_eq!(err.to_string(), "too many UDP control blocks (limit 1)");
+        close(stream, &ctx, &devices).unwrap();
+        let err = connect(stream, ep("127.0.0.1:7"), &ctx, &devices).unwrap_err();
+        assert!(matches!(err, NetError::BadDescriptor(fd) if fd == stream));
+    }
+
+    #[test]
+    fn test_socket_raw() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        let rx = socket(SocketType::Raw(IpProtocol::Other(253)), &ctx).unwrap();
+        bind(rx, ep("127.0.0.1:0"), &ctx).unwrap();
+        let tx = socket(SocketType::Raw(IpProtocol::Other(253)), &ctx).unwrap();
+        connect(tx, ep("127.0.0.1:0"), &ctx, &devices).unwrap();
+        assert_eq!(send(tx, b"experimental", &ctx, &devices).unwrap(), 12);
+
+        let mut buf = [0u8; 32];
+        let (len, from) = recvfrom(rx, &mut buf, &ctx, &devices).unwrap();
+        assert_eq!(&buf[..len], b"experimental");
+        assert_eq!(from, ep("127.0.0.1:0"));
+        assert!(listen(rx, 1, &ctx).is_err());
+        assert!(dump(&ctx).contains(&format!("{:>3} raw/253 127.0.0.1 ", rx)));
+
+        close(rx, &ctx, &devices).unwrap();
+        close(tx, &ctx, &devices).unwrap();
+        assert!(ctx.raw.is_empty());
+    }
+
+    #[test]
+    fn test_socket_poll() {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        let ctx = stack.ctx();
+        let devices = stack.devices();
+
+        let dgram = socket(SocketType::Dgram, &ctx).unwrap();
+        bind(dgram, ep("127.0.0.1:7"), &ctx).unwrap();
+        let listener = socket(SocketType::Stream, &ctx).unwrap();
+        bind(listener, ep("127.0.0.1:80"), &ctx).unwrap();
+        listen(listener, 1, &ctx).unwrap();
+        let mut fds = [PollFd::new(dgram, POLLIN), PollFd::new(listener, POLLIN)];
+        assert_eq!(poll(&mut fds, Some(Duration::from_millis(10)), &ctx), 0);
+        assert_eq!(fds[0].revents, 0);
+
+        let client = socket(SocketType::Dgram, &ctx).unwrap();
+        sendto(client, b"ping", ep("127.0.0.1:7"), &ctx, &devices).unwrap();
+        assert_eq!(poll(&mut fds, None, &ctx), 1);
+        assert_eq!(fds[0].revents, POLLIN);
+        assert_eq!(fds[1].revents, 0);
+
+        let stream = socket(SocketType::Stream, &ctx).unwrap();
+        connect(stream, ep("127.0.0.1:80"), &ctx, &devices).unwrap();
+        assert_eq!(poll(&mut fds, Some(Duration::ZERO), &ctx), 2);
+        assert_eq!(fds[1].revents, POLLIN);
+        let (conn, _) = accept(listener, &ctx).unwrap();
+
+        let mut fds = [PollFd::new(conn, POLLIN | POLLOUT)];
+        assert_eq!(poll(&mut fds, Some(Duration::ZERO), &ctx), 1);
+        assert_eq!(fds[0].revents, POLLOUT);
+        send(stream, b"hello", &ctx, &devices).unwrap();
+        poll(&mut fds, Some(Duration::ZERO), &ctx);
+        assert_eq!(fds[0].revents, POLLIN | POLLOUT);
+
+        close(client, &ctx, &devices).unwrap();
+        let mut fds = [PollFd::new(client, POLLIN)];
+        assert_eq!(poll(&mut fds, Some(Duration::ZERO), &ctx), 1);
+        assert_eq!(fds[0].revents, POLLNVAL);
+    }
+
+    #[test]
+    fn test_socket_nonblocking() {
+        let a = NetStack::new().unwrap();
+        let (a_index, a_tx) = a.add_memory(EtherAddr::from_seed("a")).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        let b = NetStack::new().unwrap();
+        let (b_index, b_tx) = b.add_memory(EtherAddr::from_seed("b")).unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        b.run().unwrap();
+        let pass = |tx: &MemoryQueue, to: &NetStack, index| {
+            while let Some(frame) = tx.lock().unwrap().pop_front() {
+                to.inject(index, frame.type_, &frame.data).unwrap();
+            }
+        };
+        let would_block = |r: Result<()>| matches!(r, Err(NetError::WouldBlock));
+
+        let server = socket(SocketType::Stream, &b.ctx()).unwrap();
+        set_nonblocking(server, true, &b.ctx()).unwrap();
+        bind(server, ep("0.0.0.0:80"), &b.ctx()).unwrap();
+        listen(server, 4, &b.ctx()).unwrap();
+        assert!(would_block(accept(server, &b.ctx()).map(|_| ())));
+
+        // The handshake goes on while the application does something else
+        let client = socket(SocketType::Stream, &a.ctx()).unwrap();
+        set_nonblocking(client, true, &a.ctx()).unwrap();
+        let connect_a = || connect(client, ep("192.0.2.2:80"), &a.ctx(), &a.devices());
+        assert!(would_block(connect_a()));
+        assert!(would_block(connect_a()));
+        let mut buf = [0u8; 16];
+        assert!(would_block(
+            recv(client, &mut buf, &a.ctx(), &a.devices()).map(|_| ())
+        ));
+        pass(&a_tx, &b, b_index);
+        pass(&b_tx, &a, a_index);
+        pass(&a_tx, &b, b_index);
+        connect_a().unwrap();
+        assert!(connect_a().is_err());
+        let (conn, _) = accept(server, &b.ctx()).unwrap();
+
+        // Reads fail until data is there; a blocking socket would wait
+        set_nonblocking(conn, true, &b.ctx()).unwrap();
+        assert!(would_block(
+            recv(conn, &mut buf, &b.ctx(), &b.devices()).map(|_| ())
+        ));
+        send(client, b"hi", &a.ctx(), &a.devices()).unwrap();
+        pass(&a_tx, &b, b_index);
+        assert_eq!(recv(conn, &mut buf, &b.ctx(), &b.devices()).unwrap(), 2);
+
+        let dgram = socket(SocketType::Dgram, &a.ctx()).unwrap();
+        bind(dgram, ep("0.0.0.0:7"), &a.ctx()).unwrap();
+        set_nonblocking(dgram, true, &a.ctx()).unwrap();
+        assert!(would_block(
+            recv(dgram, &mut buf, &a.ctx(), &a.devices()).map(|_| ())
+        ));
+    }
+}
@@ -0,0 +1,1055 @@
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use crate::builder::NetStackBuilder;
+use crate::capabilities::Capabilities;
+use crate::capture::{Capture, Direction};
+use crate::clock::Clock;
+use crate::context::{ProtocolContexts, RouteTable};
+#[cfg(unix)]
+use crate::control::{self, ControlServer};
+use crate::device::ether::EtherAddr;
+use crate::device::memory::MemoryQueue;
+use crate::device::veth::Impairment;
+use crate::device::{self, BatchCallback, DeviceIndex, DeviceManager, OutputCallback};
+use crate::drop::DropReason;
+use crate::error::{NetError, Result};
+use crate::event::{EventHandler, NetEvent, SubscriptionId};
+use crate::limits::StackLimits;
+use crate::lock::{ReadGuard, StackLock, WriteGuard};
+use crate::metrics::{self, Exporter, Registry};
+use crate::protocol::ndp::NeighborCache;
+use crate::protocol::{
+    PROTOCOL_TYPE_IP, Protocol, ProtocolManager, ProtocolType, Step, icmp, ip, ipv6,
+};
+use crate::timer::TimerHandler;
+use crate::trace::{TRACE, TraceEvent};
+
+/// Frames handled per [`NetStack::run_once`] (the NAPI default weight)
+pub const RX_BUDGET: usize = 64;
+
+pub type SharedDeviceManager = Arc<StackLock<DeviceManager>>;
+pub type SharedProtocolManager = Arc<StackLock<ProtocolManager>>;
+pub type SharedProtocolContexts = Arc<StackLock<ProtocolContexts>>;
+
+/// An independent protocol stack instance.
+///
+/// Each stack owns its devices, protocol handlers and protocol state, so several
+/// stacks can live in one process and be wired together with [`connect_veth`].
+///
+/// A stack is `Send + Sync`: device receive threads, a timer thread and
+/// application threads can share it (e.g. in an `Arc`). Packet processing and
+/// socket calls only read-lock the managers, whose tables lock themselves;
+/// configuration (`devices_mut`, `ctx_mut`) sleeps until no other thread is
+/// inside the stack (see [`lock`](crate::lock)). The [`net`](crate::net)
+/// sockets leave the stack while they block.
+pub struct NetStack {
+    devices: SharedDeviceManager,
+    protocols: SharedProtocolManager,
+    ctx: SharedProtocolContexts,
+    limits: StackLimits,
+}
+
+impl NetStack {
+    pub fn new() -> Result<Self> {
+        Self::with_limits(StackLimits::default())
+    }
+
+    /// Configure a stack with its devices, interfaces and routes in one go
+    pub fn builder() -> NetStackBuilder {
+        NetStackBuilder::default()
+    }
+
+    /// A stack whose devices, interfaces, routes, sockets and queues are
+    /// bounded by `limits`
+    pub fn with_limits(limits: StackLimits) -> Result<Self> {
+        Self::with_clock(limits, Clock::default())
+    }
+
+    /// Like [`with_limits`](Self::with_limits), with timers and timeouts on
+    /// `clock` (see [`clock`](crate::clock))
+    pub fn with_clock(limits: StackLimits, clock: Clock) -> Result<Self> {
+        limits.validate()?;
+        let mut protocols = ProtocolManager::with_clock(&limits, clock.clone());
+        protocols.init()?;
+
+        Ok(Self {
+            devices: Arc::new(StackLock::new(DeviceManager::with_limits(&limits))),
+            protocols: Arc::new(StackLock::new(protocols)),
+            ctx: Arc::new(StackLock::new(ProtocolContexts::with_clock(&limits, clock))),
+            limits,
+        })
+    }
+
+    pub fn limits(&self) -> &StackLimits {
+        &self.limits
+    }
+
+    pub fn devices(&self) -> ReadGuard<'_, DeviceManager> {
+        self.devices.read()
+    }
+
+    pub fn devices_mut(&self) -> WriteGuard<'_, DeviceManager> {
+        self.devices.write()
+    }
+
+    pub fn protocols(&self) -> ReadGuard<'_, ProtocolManager> {
+        self.protocols.read()
+    }
+
+    pub fn ctx(&self) -> ReadGuard<'_, ProtocolContexts> {
+        self.ctx.read()
+    }
+
+    pub fn ctx_mut(&self) -> WriteGuard<'_, ProtocolContexts> {
+        self.ctx.write()
+    }
+
+    /// The IPv4 routing table, to look up and update without holding
+    /// [`ctx`](Self::ctx) (see [`snapshot`](crate::snapshot))
+    pub fn routes(&self) -> Arc<RouteTable> {
+        Arc::clone(&self.ctx().ip_routes)
+    }
+
+    /// The IPv6 neighbor cache, to look up without holding [`ctx`](Self::ctx)
+    pub fn neighbors(&self) -> Arc<NeighborCache> {
+        Arc::clone(&self.ctx().neighbors)
+    }
+
+    /// Callback that feeds received frames into this stack.
+    ///
+    /// With `index`, frames are received on that device of this stack; otherwise on the
+    /// device passed by the transmitter (loopback). The callback holds weak references,
+    /// so frames sent to a dropped stack are discarded.
+    pub fn input_callback(&self, index: Option<DeviceIndex>) -> OutputCallback {
+        let devices = Arc::downgrade(&self.devices);
+        let protocols = Arc::downgrade(&self.protocols);
+        let ctx = Arc::downgrade(&self.ctx);
+
+        Arc::new(move |type_, data, dev| {
+            let (Some(devices), Some(protocols), Some(ctx)) = (
+                Weak::upgrade(&devices),
+                Weak::upgrade(&protocols),
+                Weak::upgrade(&ctx),
+            ) else {
+                tracing::debug!("receiving stack is gone, frame dropped");
+                return;
+            };
+            let devices = devices.read();
+            let protocols = protocols.read();
+            let ctx = ctx.read();
+
+            let dev = match index {
+                Some(index) => match devices.get(index) {
+                    Some(dev) => dev,
+                    None => return,
+                },
+                None => dev,
+            };
+            receive(type_, data, dev, &protocols, &ctx, &devices);
+        })
+    }
+
+    /// Like [`input_callback`](Self::input_callback), taking the stack's locks
+    /// once for every batch of frames
+    pub fn input_batch_callback(&self, index: Option<DeviceIndex>) -> BatchCallback {
+        let devices = Arc::downgrade(&self.devices);
+        let protocols = Arc::downgrade(&self.protocols);
+        let ctx = Arc::downgrade(&self.ctx);
+
+        Arc::new(move |frames, dev| {
+            let (Some(devices), Some(protocols), Some(ctx)) = (
+                Weak::upgrade(&devices),
+                Weak::upgrade(&protocols),
+                Weak::upgrade(&ctx),
+            ) else {
+                tracing::debug!("receiving stack is gone, {} frames dropped", frames.len());
+                return;
+            };
+            let devices = devices.read();
+            let protocols = protocols.read();
+            let ctx = ctx.read();
+
+            let dev = match index {
+                Some(index) => match devices.get(index) {
+                    Some(dev) => dev,
+                    None => return,
+                },
+                None => dev,
+            };
+            receive_batch(frames, dev, &protocols, &ctx, &devices);
+        })
+    }
+
+    /// Feed a frame received on device `index`, for devices whose wire is the
+    /// embedder (see [`NetStack::add_memory`])
+    pub fn inject(&self, index: DeviceIndex, type_: u16, data: &[u8]) -> Result<()> {
+        self.inject_batch(index, &[(type_, data)])
+    }
+
+    /// Like [`inject`](Self::inject), for several frames (type and data) at
+    /// once; the locks are taken and the protocols looked up once per batch
+    pub fn inject_batch(&self, index: DeviceIndex, frames: &[(u16, &[u8])]) -> Result<()> {
+        let devices = self.devices();
+        let dev = devices.get(index).ok_or(NetError::NoDevice(index))?;
+        receive_batch(frames, dev, &self.protocols(), &self.ctx(), &devices);
+        Ok(())
+    }
+
+    /// Protocols, drivers, features and limits of this stack
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::new(self.protocols().types(), self.limits)
+    }
+
+    /// Handle received frames of `type_` (an ethertype such as LLDP or a
+    /// custom experimental protocol) with `protocol`
+    pub fn register_protocol(&self, type_: u16, protocol: Arc<dyn Protocol>) -> Result<()> {
+        self.protocols
+            .write()
+            .register(ProtocolType::from(type_), protocol)
+    }
+
+    /// Add an in-memory device; the embedder collects its transmitted frames from
+    /// the returned queue and feeds received ones with [`NetStack::inject`]
+    pub fn add_memory(&self, addr: EtherAddr) -> Result<(DeviceIndex, MemoryQueue)> {
+        device::memory::init(&mut self.devices_mut(), addr)
+    }
+
+    /// Add a loopback device with 127.0.0.1/8 and ::1/128
+    pub fn add_loopback(&self) -> Result<DeviceIndex> {
+        let callback = self.input_batch_callback(None);
+        let index = device::loopback::init(&mut self.devices_mut(), callback)?;
+        self.register_ip_iface(index, "127.0.0.1", "255.0.0.0")?;
+        self.register_ipv6_iface(index, "::1/128")?;
+        Ok(index)
+    }
+
+    /// Register an IP interface on the device (see [`ip::register_iface`])
+    pub fn register_ip_iface(
+        &self,
+        index: DeviceIndex,
+        unicast: &str,
+        netmask: &str,
+    ) -> Result<()> {
+        let mut devices = self.devices_mut();
+        let dev = devices.get_mut(index).ok_or(NetError::NoDevice(index))?;
+        ip::register_iface(dev, unicast, netmask, &mut self.ctx_mut())?;
+        drop(devices);
+        self.raise_event(NetEvent::AddressChanged(index));
+        Ok(())
+    }
+
+    /// Register an IPv6 interface with address `cidr` on the device (see
+    /// [`ipv6::register_iface`])
+    pub fn register_ipv6_iface(&self, index: DeviceIndex, cidr: &str) -> Result<()> {
+        let mut devices = self.devices_mut();
+        let dev = devices.get_mut(index).ok_or(NetError::NoDevice(index))?;
+        ipv6::register_iface(dev, cidr, &mut self.ctx_mut())?;
+        drop(devices);
+        self.raise_event(NetEvent::AddressChanged(index));
+        Ok(())
+    }
+
+    /// Set the hardware address of an Ethernet-style device (veth).
+    ///
+    /// There is no ARP yet, so the address is only reported, not resolved.
+    pub fn set_hw_addr(&self, index: DeviceIndex, addr: EtherAddr) -> Result<()> {
+        let mut devices = self.devices_mut();
+        let dev = devices.get_mut(index).ok_or(NetError::NoDevice(index))?;
+        if dev.alen as usize != device::ether::ETHER_ADDR_LEN {
+            return Err(NetError::InvalidInput("device has no hardware address"));
+        }
+        if addr.is_multicast() {
+            return Err(NetError::InvalidInput("not a unicast address"));
+        }
+        dev.set_hw_addr(addr);
+        tracing::info!("Device {} hardware address: {}", dev.name_string(), addr);
+        Ok(())
+    }
+
+    /// Set the MTU of a device. IPv4 needs at least 68 bytes (RFC 791): the
+    /// longest header and 8 bytes of payload.
+    pub fn set_mtu(&self, index: DeviceIndex, mtu: u16) -> Result<()> {
+        if usize::from(mtu) < ip::IP_HDR_SIZE_MAX + 8 {
+            return Err(NetError::InvalidInput("MTU below 68"));
+        }
+        let mut devices = self.devices_mut();
+        let dev = devices.get_mut(index).ok_or(NetError::NoDevice(index))?;
+        dev.mtu = mtu;
+        tracing::info!("Device {} mtu: {}", dev.name_string(), mtu);
+        Ok(())
+    }
+
+    /// Answer ICMP Echo Requests on the device directly in its receive path.
+    ///
+    /// Meant for measuring raw driver latency: replies skip routing, statistics
+    /// and protocol dispatch but are encoded by the same code as normal replies.
+    /// ARP has no fast path since this stack does not implement ARP yet.
+    pub fn set_fast_responder(&self, index: DeviceIndex, enabled: bool) -> Result<()> {
+        let mut devices = self.devices_mut();
+        let dev = devices.get_mut(index).ok_or(NetError::NoDevice(index))?;
+        if enabled {
+            dev.flags |= device::NET_DEVICE_FLAG_FAST_RESPONDER;
+        } else {
+            dev.flags &= !device::NET_DEVICE_FLAG_FAST_RESPONDER;
+        }
+        Ok(())
+    }
+
+    /// Write the frames device `index` receives and transmits to `capture`
+    pub fn capture(&self, index: DeviceIndex, capture: &Arc<Capture>) -> anyhow::Result<()> {
+        let mut devices = self.devices_mut();
+        let dev = devices.get_mut(index).ok_or(NetError::NoDevice(index))?;
+        dev.attach_capture(Arc::clone(capture))
+    }
+
+    /// Stop capturing the frames of device `index`
+    pub fn stop_capture(&self, index: DeviceIndex) -> anyhow::Result<()> {
+        let mut devices = self.devices_mut();
+        let dev = devices.get_mut(index).ok_or(NetError::NoDevice(index))?;
+        if let Some(capture) = dev.detach_capture() {
+            capture.flush()?;
+        }
+        Ok(())
+    }
+
+    /// The stack's counters (see [`metrics`](crate::metrics))
+    pub fn metrics(&self) -> Registry {
+        metrics::gather(&self.devices(), &self.ctx())
+    }
+
+    /// Serve [`metrics`](Self::metrics) at `http://addr/metrics` until the
+    /// returned exporter is dropped
+    pub fn serve_metrics(&self, addr: impl ToSocketAddrs) -> anyhow::Result<Exporter> {
+        let devices = Arc::downgrade(&self.devices);
+        let ctx = Arc::downgrade(&self.ctx);
+        Exporter::start(addr, move || {
+            let (devices, ctx) = (devices.upgrade()?, ctx.upgrade()?);
+            let (devices, ctx) = (devices.read(), ctx.read());
+            Some(metrics::gather(&devices, &ctx))
+        })
+    }
+
+    /// Accept [`control`](crate::control) commands on the Unix domain socket
+    /// at `path` until the returned server is dropped
+    #[cfg(unix)]
+    pub fn serve_control(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> anyhow::Result<ControlServer> {
+        let devices = Arc::downgrade(&self.devices);
+        let protocols = Arc::downgrade(&self.protocols);
+        let ctx = Arc::downgrade(&self.ctx);
+        ControlServer::start(path, move |command| {
+            let (Some(devices), Some(protocols), Some(ctx)) =
+                (devices.upgrade(), protocols.upgrade(), ctx.upgrade())
+            else {
+                anyhow::bail!("stack is gone");
+            };
+            let target = control::Target {
+                devices: &devices,
+                protocols: &protocols,
+                ctx: &ctx,
+            };
+            control::execute(command, &target)
+        })
+    }
+
+    /// Run the protocol timers that are due (TCP retransmissions, IGMP
+    /// reports, NAT expiry and whatever else was registered)
+    pub fn tick(&self) {
+        run_timers(&self.protocols(), &self.ctx(), &self.devices());
+    }
+
+    /// Run `handler` every `interval` (see [`timer`](crate::timer))
+    pub fn register_timer(
+        &self,
+        name: &str,
+        interval: Duration,
+        handler: TimerHandler,
+    ) -> Result<()> {
+        self.protocols
+            .write()
+            .register_timer(name, interval, handler)
+    }
+
+    /// Run timers on a thread of their own, so they keep running while no
+    /// thread calls [`NetStack::run_once`]. The thread stops on
+    /// [`NetStack::shutdown`] or when the stack is dropped.
+    pub fn start_timer_thread(&self) -> Result<()> {
+        let timers = Arc::clone(self.protocols().timers());
+        let protocols = Arc::downgrade(&self.protocols);
+        let ctx = Arc::downgrade(&self.ctx);
+        let devices = Arc::downgrade(&self.devices);
+        timers.start(move || {
+            let (Some(protocols), Some(ctx), Some(devices)) =
+                (protocols.upgrade(), ctx.upgrade(), devices.upgrade())
+            else {
+                return false;
+            };
+            let protocols = protocols.read();
+            if !protocols.is_paused() {
+                run_timers(&protocols, &ctx.read(), &devices.read());
+            }
+            true
+        })
+    }
+
+    /// Queue received frames until [`NetStack::poll`] instead of handling them
+    /// as they arrive. Drivers still deliver synchronously; only protocol
+    /// processing is deferred, so blocking calls such as `tcp::connect` need
+    /// another thread of control polling the stack.
+    pub fn set_deferred_input(&self, deferred: bool) {
+        self.protocols.write().set_deferred(deferred);
+    }
+
+    /// Pause all packet processing for debugging: received frames are queued
+    /// and protocol timers stop running in [`NetStack::run_once`]. Use
+    /// [`NetStack::step`] to handle the queued frames one at a time.
+    pub fn pause(&self) {
+        self.protocols.write().set_paused(true);
+        tracing::info!("stack paused");
+    }
+
+    /// Resume processing after [`NetStack::pause`]. Frames queued while paused
+    /// are handled now, unless input is deferred anyway; returns how many.
+    pub fn resume(&self) -> usize {
+        self.protocols.write().set_paused(false);
+        tracing::info!("stack resumed");
+        if self.protocols().is_deferred() {
+            self.protocols().softirq().raise();
+            return 0;
+        }
+        self.poll(usize::MAX)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.protocols().is_paused()
+    }
+
+    /// Handle the next queued frame (see [`ProtocolManager::step`])
+    pub fn step(&self) -> Option<Step> {
+        self.protocols().step(&self.ctx(), &self.devices())
+    }
+
+    /// Handle up to `budget` queued frames (see [`ProtocolManager::poll`])
+    pub fn poll(&self, budget: usize) -> usize {
+        self.protocols().poll(budget, &self.ctx(), &self.devices())
+    }
+
+    /// Dispatch IRQs raised by device backends on a thread of their own (see
+    /// [`irq`](crate::irq)) instead of in [`NetStack::run_once`]. The thread
+    /// stops on [`NetStack::shutdown`] or when the stack is dropped.
+    pub fn start_irq_thread(&self) -> Result<()> {
+        let irqs = Arc::clone(self.devices().irqs());
+        irqs.start(Arc::downgrade(&self.devices))
+    }
+
+    /// Handle received frames on a worker thread (see
+    /// [`SoftIrq`](crate::irq::SoftIrq)): input is deferred, so device threads
+    /// only queue frames, and the worker handles them in batches of
+    /// [`RX_BUDGET`]. The thread stops on
+    /// [`NetStack::shutdown`] or when the stack is dropped; frames still queued
+    /// then wait for [`NetStack::poll`].
+    pub fn start_softirq_thread(&self) -> Result<()> {
+        self.set_deferred_input(true);
+        let softirq = Arc::clone(self.protocols().softirq());
+        let protocols = Arc::downgrade(&self.protocols);
+        let ctx = Arc::downgrade(&self.ctx);
+        let devices = Arc::downgrade(&self.devices);
+        softirq.start(move || {
+            let (Some(protocols), Some(ctx), Some(devices)) =
+                (protocols.upgrade(), ctx.upgrade(), devices.upgrade())
+            else {
+                return None;
+            };
+            // The guards are dropped between batches, so configuration waits
+            // for one batch at most
+            let handled = protocols
+                .read()
+                .poll(RX_BUDGET, &ctx.read(), &devices.read());
+            Some(handled == RX_BUDGET)
+        })
+    }
+
+    /// One iteration of the processing loop: pending IRQs, a budget of
+    /// received frames, then timers, so a flood cannot delay retransmissions
+    /// indefinitely. Returns the number of frames handled.
+    pub fn run_once(&self) -> usize {
+        if self.is_paused() {
+            return 0;
+        }
+        {
+            let devices = self.devices();
+            devices.irqs().dispatch(&devices);
+        }
+        let handled = self.poll(RX_BUDGET);
+        self.tick();
+        handled
+    }
+
+    /// Open all devices
+    pub fn run(&self) -> Result<()> {
+        self.devices_mut().run()?;
+        for index in self.device_indices() {
+            self.raise_event(NetEvent::InterfaceUp(index));
+        }
+        Ok(())
+    }
+
+    /// Stop the IRQ, softirq and timer threads and close all devices
+    pub fn shutdown(&self) -> Result<()> {
+        self.stop_threads();
+        self.devices_mut().shutdown()?;
+        for index in self.device_indices() {
+            self.raise_event(NetEvent::InterfaceDown(index));
+        }
+        Ok(())
+    }
+
+    fn device_indices(&self) -> Vec<DeviceIndex> {
+        self.devices().iter().map(|dev| dev.index).collect()
+    }
+
+    /// Deliver `event` to the stack's subscribers (see [`event`](crate::event));
+    /// returns how many got it
+    pub fn raise_event(&self, event: NetEvent) -> usize {
+        self.protocols().events().raise(event, &self.ctx())
+    }
+
+    /// Run `handler` for every event raised from now on
+    pub fn subscribe_event(&self, handler: EventHandler) -> SubscriptionId {
+        self.protocols().events().subscribe(handler)
+    }
+
+    /// Receive every event raised from now on, e.g. to wait for an interface
+    /// to come up without polling
+    pub fn listen_events(&self) -> Receiver<NetEvent> {
+        self.protocols().events().listen()
+    }
+
+    fn stop_threads(&self) {
+        let irqs = Arc::clone(self.devices().irqs());
+        irqs.stop();
+        let softirq = Arc::clone(self.protocols().softirq());
+        softirq.stop();
+        let timers = Arc::clone(self.protocols().timers());
+        timers.stop();
+    }
+}
+
+impl Drop for NetStack {
+    fn drop(&mut self) {
+        self.stop_threads();
+    }
+}
+
+fn run_timers(protocols: &ProtocolManager, ctx: &ProtocolContexts, devices: &DeviceManager) {
+    TRACE.record(TraceEvent::Timer);
+    protocols.timers().run(ctx, devices);
+}
+
+/// Receive path shared by device callbacks and [`NetStack::inject`]
+fn receive(
+    type_: u16,
+    data: &[u8],
+    dev: &device::Device,
+    protocols: &ProtocolManager,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) {
+    if admit(type_, data, dev, protocols, ctx) {
+        protocols.receive(type_, data, dev, ctx, devices);
+    }
+}
+
+/// Like [`receive`], handing the frames that get past the device to the
+/// protocols in one batch
+fn receive_batch(
+    frames: &[(u16, &[u8])],
+    dev: &device::Device,
+    protocols: &ProtocolManager,
+    ctx: &ProtocolContexts,
+    devices: &DeviceManager,
+) {
+    let admitted: Vec<(u16, &[u8])> = frames
+        .iter()
+        .copied()
+        .filter(|&(type_, data)| admit(type_, data, dev, protocols, ctx))
+        .collect();
+    if !admitted.is_empty() {
+        protocols.receive_batch(&admitted, dev, ctx, devices);
+    }
+}
+
+/// Account for a received frame; returns whether it goes on to the protocols
+/// (not if the device is down or the fast responder answered it)
+fn admit(
+    type_: u16,
+    data: &[u8],
+    dev: &device::Device,
+    protocols: &ProtocolManager,
+    ctx: &ProtocolContexts,
+) -> bool {
+    TRACE.record(TraceEvent::PacketIn {
+        dev: dev.index,
+        type_,
+        len: data.len(),
+    });
+    dev.record(Direction::In, type_, data);
+    dev.stats.rx.add(data.len());
+    if !dev.is_up() && ctx.drops.drop(DropReason::DeviceDown, data) {
+        tracing::debug!("device {} is down, frame dropped", dev.name_string());
+        return false;
+    }
+    if dev.flags & device::NET_DEVICE_FLAG_FAST_RESPONDER != 0
+        && !protocols.is_paused()
+        && type_ == PROTOCOL_TYPE_IP
+        && let Some(reply) = icmp::fast_echo_reply(data, dev)
+    {
+        if let Err(e) = dev.output_buf(PROTOCOL_TYPE_IP, reply, None) {
+            tracing::error!("fast responder: {}", e);
+        }
+        return false;
+    }
+    true
+}
+
+/// Create a veth pair with one end in each stack and return the device indexes
+/// of the ends in `a` and `b`.
+pub fn connect_veth(a: &NetStack, b: &NetStack) -> Result<(DeviceIndex, DeviceIndex)> {
+    connect_veth_with(a, b, &Impairment::default())
+}
+
+/// Like [`connect_veth`], applying `impairment` to frames in both directions
+pub fn connect_veth_with(
+    a: &NetStack,
+    b: &NetStack,
+    impairment: &Impairment,
+) -> Result<(DeviceIndex, DeviceIndex)> {
+    // Addresses follow link creation order, so a program builds the same ones every run
+    static VETH_PAIRS: AtomicU64 = AtomicU64::new(0);
+    let pair = VETH_PAIRS.fetch_add(1, Ordering::Relaxed);
+    let a_addr = EtherAddr::from_seed(&format!("veth{}a", pair));
+    let b_addr = EtherAddr::from_seed(&format!("veth{}b", pair));
+
+    let a_index = device::veth::init(&mut a.devices_mut(), a_addr)?;
+    let b_index = device::veth::init(&mut b.devices_mut(), b_addr)?;
+
+    let to_b = b.input_callback(Some(b_index));
+    let to_a = a.input_callback(Some(a_index));
+    if let Some(dev) = a.devices_mut().get_mut(a_index) {
+        device::veth::attach(dev, to_b, impairment);
+    }
+    if let Some(dev) = b.devices_mut().get_mut(b_index) {
+        device::veth::attach(dev, to_a, impairment);
+    }
+
+    Ok((a_index, b_index))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::bytes::Frame;
+    use crate::device::Device;
+    use crate::pool::PACKET_BUFFERS;
+    use crate::protocol::icmp::IcmpType;
+    use crate::protocol::ip::{IpAddr, IpEndpoint};
+    use crate::protocol::{PROTOCOL_TYPE_EXPERIMENTAL, PROTOCOL_TYPE_LLDP};
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    fn send_echo(stack: &NetStack, dst: &str) -> Result<isize> {
+        icmp::output(
+            IcmpType::Echo,
+            0,
+            icmp::echo_values(1, 1),
+            b"ping",
+            IpAddr::ANY,
+            addr(dst),
+            &stack.ctx(),
+            &stack.devices(),
+        )
+    }
+
+    #[test]
+    fn test_stacks_connected_by_veth() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        let (a_index, b_index) = connect_veth(&a, &b).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        b.run().unwrap();
+
+        send_echo(&a, "192.0.2.2").unwrap();
+
+        let stats = b.ctx().peer_stats.get(addr("192.0.2.1")).unwrap();
+        assert_eq!(stats.packets_in, 1);
+        assert!(a.ctx().peer_stats.get(addr("192.0.2.2")).is_some());
+    }
+
+    #[test]
+    fn test_memory_devices_are_wired_by_caller() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        let (a_index, a_tx) = a.add_memory(EtherAddr::from_seed("a")).unwrap();
+        let (b_index, b_tx) = b.add_memory(EtherAddr::from_seed("b")).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        b.run().unwrap();
+
+        a.ctx().icmp_echo.register(1).unwrap();
+        send_echo(&a, "192.0.2.2").unwrap();
+        assert!(
+            b.ctx().peer_stats.is_empty(),
+            "nothing moves until passed on"
+        );
+
+        let request = a_tx.lock().unwrap().pop_front().unwrap();
+        b.inject(b_index, request.type_, &request.data).unwrap();
+        let reply = b_tx.lock().unwrap().pop_front().unwrap();
+        a.inject(a_index, reply.type_, &reply.data).unwrap();
+
+        assert_eq!(a.ctx().icmp_echo.take(1).len(), 1);
+        assert!(a_tx.lock().unwrap().is_empty() && b_tx.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_inject_batch() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        let (a_index, a_tx) = a.add_memory(EtherAddr::from_seed("a")).unwrap();
+        let (b_index, b_tx) = b.add_memory(EtherAddr::from_seed("b")).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        b.run().unwrap();
+        b.set_deferred_input(true);
+
+        a.ctx().icmp_echo.register(1).unwrap();
+        for _ in 0..3 {
+            send_echo(&a, "192.0.2.2").unwrap();
+        }
+        let requests: Vec<_> = a_tx.lock().unwrap().drain(..).collect();
+        let batch: Vec<(u16, &[u8])> = requests
+            .iter()
+            .map(|frame| (frame.type_, frame.data.as_slice()))
+            .collect();
+        b.inject_batch(b_index, &batch).unwrap();
+        assert_eq!(b.protocols().backlog(), 3);
+        assert_eq!(b.poll(64), 3);
+
+        let replies: Vec<_> = b_tx.lock().unwrap().drain(..).collect();
+        assert_eq!(replies.len(), 3);
+        for reply in replies {
+            a.inject(a_index, reply.type_, &reply.data).unwrap();
+        }
+        assert_eq!(a.ctx().icmp_echo.take(1).len(), 3);
+    }
+
+    #[test]
+    fn test_stack_limits() {
+        let zero = StackLimits {
+            routes: 0,
+            ..Default::default()
+        };
+        assert!(NetStack::with_limits(zero).is_err());
+
+        let limits = StackLimits {
+            devices: 1,
+            tcp_sockets: 1,
+            tx_queue_len: 2,
+            socket_queue_len: 1,
+            ..Default::default()
+        };
+        let a = NetStack::with_limits(limits).unwrap();
+        assert_eq!(a.capabilities().stack_limits, limits);
+        let (a_index, a_tx) = a.add_memory(EtherAddr::from_seed("a")).unwrap();
+        assert!(a.add_loopback().is_err());
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        let b = NetStack::new().unwrap();
+        let (b_index, b_tx) = b.add_memory(EtherAddr::from_seed("b")).unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        b.run().unwrap();
+
+        // Transmit queue
+        send_echo(&a, "192.0.2.2").unwrap();
+        send_echo(&a, "192.0.2.2").unwrap();
+        assert!(send_echo(&a, "192.0.2.2").is_err());
+        assert_eq!(a_tx.lock().unwrap().len(), 2);
+
+        // Socket queue: a second reply waits for the first to be read
+        a.ctx().icmp_echo.register(1).unwrap();
+        let request = a_tx.lock().unwrap().pop_front().unwrap();
+        b.inject(b_index, request.type_, &request.data).unwrap();
+        let reply = b_tx.lock().unwrap().pop_front().unwrap();
+        a.inject(a_index, reply.type_, &reply.data).unwrap();
+        a.inject(a_index, reply.type_, &reply.data).unwrap();
+        assert_eq!(a.ctx().drops.count(DropReason::SocketQueueFull), 1);
+        assert_eq!(a.ctx().icmp_echo.take(1).len(), 1);
+
+        let local = ip::IpEndpoint::new(addr("192.0.2.1"), 80);
+        a.ctx().tcp.listen(local, None).unwrap();
+        let other = ip::IpEndpoint::new(addr("192.0.2.1"), 81);
+        assert!(a.ctx().tcp.listen(other, None).is_err());
+    }
+
+    #[test]
+    fn test_stack_shared_between_threads() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        let (a_index, b_index) = connect_veth(&a, &b).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        b.run().unwrap();
+        a.ctx().icmp_echo.register(1).unwrap();
+
+        // One thread waits inside the stack for the reply another thread's request brings
+        let reply = std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                a.ctx()
+                    .icmp_echo
+                    .recv(1, Some(std::time::Duration::from_secs(5)))
+            });
+            scope
+                .spawn(|| send_echo(&a, "192.0.2.2"))
+                .join()
+                .unwrap()
+                .unwrap();
+            waiter.join().unwrap().unwrap()
+        });
+        assert_eq!(reply.unwrap().src, addr("192.0.2.2"));
+
+        // Configuration goes ahead once nobody is inside the stack
+        a.ctx_mut().ip_config.forwarding = true;
+        assert!(a.ctx().ip_config.forwarding);
+    }
+
+    #[test]
+    fn test_stacks_are_independent() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        a.add_loopback().unwrap();
+        b.add_loopback().unwrap();
+        a.run().unwrap();
+        b.run().unwrap();
+
+        send_echo(&a, "127.0.0.1").unwrap();
+
+        assert!(a.ctx().peer_stats.get(addr("127.0.0.1")).is_some());
+        assert!(b.ctx().peer_stats.is_empty());
+    }
+
+    #[test]
+    fn test_fast_responder_answers_echo() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        let (a_index, b_index) = connect_veth(&a, &b).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        b.set_fast_responder(b_index, true).unwrap();
+        a.run().unwrap();
+        b.run().unwrap();
+
+        a.ctx().icmp_echo.register(1).unwrap();
+        send_echo(&a, "192.0.2.2").unwrap();
+
+        let replies = a.ctx().icmp_echo.take(1);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].src, addr("192.0.2.2"));
+        assert_eq!(replies[0].data, b"ping");
+        // The request never reached b's IP layer
+        assert!(b.ctx().peer_stats.is_empty());
+    }
+
+    #[test]
+    fn test_deferred_input_is_polled_with_budget() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        let (a_index, b_index) = connect_veth(&a, &b).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        b.run().unwrap();
+        a.set_deferred_input(true);
+        b.set_deferred_input(true);
+
+        a.ctx().icmp_echo.register(1).unwrap();
+        send_echo(&a, "192.0.2.2").unwrap();
+        send_echo(&a, "192.0.2.2").unwrap();
+        assert!(b.ctx().peer_stats.is_empty());
+        assert_eq!(b.protocols().backlog(), 2);
+
+        // The budget limits how many requests b answers per call
+        assert_eq!(b.poll(1), 1);
+        assert_eq!(b.protocols().backlog(), 1);
+        assert_eq!(a.run_once(), 1);
+        assert_eq!(a.ctx().icmp_echo.take(1).len(), 1);
+        assert_eq!(b.run_once(), 1);
+        assert_eq!(a.run_once(), 1);
+        assert_eq!(a.run_once(), 0);
+    }
+
+    #[test]
+    fn test_queued_datagrams_give_pool_buffers_back() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        let (a_index, b_index) = connect_veth(&a, &b).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        b.run().unwrap();
+        b.set_deferred_input(true);
+        let server = b.ctx().udp.open().unwrap();
+        let dst = IpEndpoint::new(addr("192.0.2.2"), 7);
+        b.ctx().udp.bind(server, dst).unwrap();
+        let src = IpEndpoint::new(addr("192.0.2.1"), 9);
+        let send = |data: &[u8]| {
+            crate::protocol::udp::output(src, dst, data, &a.ctx(), &a.devices()).unwrap();
+            assert_eq!(b.poll(1), 1);
+        };
+
+        // Far more small datagrams wait on the socket than the pool has
+        // buffers, and none of them holds one
+        let packets = b.ctx().packets.clone();
+        for _ in 0..PACKET_BUFFERS * 2 {
+            send(b"small");
+        }
+        assert_eq!(packets.available(), PACKET_BUFFERS);
+        assert_eq!(packets.stats().exhausted, 0);
+
+        // A large one keeps the frame it arrived in
+        send(&[0; 1000]);
+        assert_eq!(packets.available(), PACKET_BUFFERS - 1);
+        for _ in 0..PACKET_BUFFERS * 2 {
+            b.ctx().udp.recvfrom(server).unwrap();
+        }
+        drop(b.ctx().udp.recvfrom(server).unwrap());
+        assert_eq!(packets.available(), PACKET_BUFFERS);
+    }
+
+    #[test]
+    fn test_pause_and_step() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        let (a_index, b_index) = connect_veth(&a, &b).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        b.register_ip_iface(b_index, "192.0.2.2", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        b.run().unwrap();
+        a.ctx().icmp_echo.register(1).unwrap();
+
+        b.pause();
+        assert!(b.is_paused());
+        send_echo(&a, "192.0.2.2").unwrap();
+        send_echo(&a, "192.0.2.2").unwrap();
+        assert_eq!(b.run_once(), 0);
+        assert_eq!(b.protocols().backlog(), 2);
+
+        let step = b.step().unwrap();
+        assert_eq!(step.seq, 1);
+        assert_eq!(step.type_, ProtocolType::Ip);
+        assert_eq!(step.remaining, 1);
+        assert!(step.summary.contains("src=192.0.2.1, dst=192.0.2.2"));
+        assert_eq!(a.ctx().icmp_echo.take(1).len(), 1);
+
+        assert_eq!(b.resume(), 1);
+        assert!(!b.is_paused());
+        assert_eq!(a.ctx().icmp_echo.take(1).len(), 1);
+        assert!(b.step().is_none());
+    }
+
+    thread_local! {
+        static CUSTOM_FRAMES: std::cell::RefCell<Vec<Vec<u8>>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    fn custom_input(data: &Frame<'_>, _dev: &Device, _ctx: &ProtocolContexts, _: &DeviceManager) {
+        CUSTOM_FRAMES.with(|frames| frames.borrow_mut().push(data.to_vec()));
+    }
+
+    #[test]
+    fn test_custom_protocol_registration() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        let (a_index, _) = connect_veth(&a, &b).unwrap();
+        b.register_protocol(PROTOCOL_TYPE_EXPERIMENTAL, Arc::new(custom_input))
+            .unwrap();
+        assert!(
+            b.register_protocol(PROTOCOL_TYPE_IP, Arc::new(custom_input))
+                .is_err()
+        );
+        a.run().unwrap();
+        b.run().unwrap();
+
+        let devices = a.devices();
+        let dev = devices.get(a_index).unwrap();
+        dev.output(PROTOCOL_TYPE_EXPERIMENTAL, b"hello", None)
+            .unwrap();
+        // Nobody handles LLDP on b: the frame is ignored
+        dev.output(PROTOCOL_TYPE_LLDP, b"lldp", None).unwrap();
+        assert_eq!(
+            CUSTOM_FRAMES.with(|frames| frames.borrow().clone()),
+            [b"hello".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let stack = NetStack::new().unwrap();
+        let caps = stack.capabilities();
+        assert_eq!(caps.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(caps.link_protocols, [ProtocolType::Ip, ProtocolType::Ipv6]);
+        assert!(caps.has_feature("deferred-input"));
+        assert_eq!(caps.limits.rx_budget, RX_BUDGET);
+
+        stack
+            .register_protocol(PROTOCOL_TYPE_EXPERIMENTAL, Arc::new(custom_input))
+            .unwrap();
+        assert_eq!(stack.capabilities().link_protocols.len(), 3);
+        assert!(stack.capabilities().to_string().contains("tcp_sockets=16"));
+        assert_eq!(caps.stack_limits, *stack.limits());
+    }
+
+    #[test]
+    fn test_veth_peer_dropped() {
+        let a = NetStack::new().unwrap();
+        let b = NetStack::new().unwrap();
+        let (a_index, _) = connect_veth(&a, &b).unwrap();
+        a.register_ip_iface(a_index, "192.0.2.1", "255.255.255.0")
+            .unwrap();
+        a.run().unwrap();
+        drop(b);
+
+        send_echo(&a, "192.0.2.2").unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+use crate::platform::Instant;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::clock::Clock;
+use crate::protocol::ip::{IpAddr, IpProtocol};
+use crate::protocol::ipv6::IPV6_NEXT_HEADER_ICMPV6;
+
+pub const PEER_STATS_CAPACITY_DEFAULT: usize = 256;
+
+/// Traffic counters for a single remote address
+#[derive(Debug, Clone, Copy)]
+pub struct PeerStats {
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+    pub last_seen: Instant,
+}
+
+impl PeerStats {
+    fn new(now: Instant) -> Self {
+        Self {
+            packets_in: 0,
+            bytes_in: 0,
+            packets_out: 0,
+            bytes_out: 0,
+            last_seen: now,
+        }
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes_in + self.bytes_out
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Direction {
+    In,
+    Out,
+}
+
+/// Per-remote-address counters bounded to `capacity` entries.
+/// When full, the least recently seen address is evicted.
+pub struct PeerStatsTable {
+    capacity: usize,
+    peers: Mutex<HashMap<IpAddr, PeerStats>>,
+    clock: Clock,
+}
+
+impl PeerStatsTable {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_clock(capacity, Clock::default())
+    }
+
+    /// Like [`new`](Self::new), with peers last seen by `clock`
+    pub fn with_clock(capacity: usize, clock: Clock) -> Self {
+        Self {
+            capacity,
+            peers: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    /// Time the peers' `last_seen` is on
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    pub fn record_in(&self, peer: IpAddr, len: usize) {
+        self.record(peer, len, Direction::In);
+    }
+
+    pub fn record_out(&self, peer: IpAddr, len: usize) {
+        self.record(peer, len, Direction::Out);
+    }
+
+    fn record(&self, peer: IpAddr, len: usize, direction: Direction) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let now = self.clock.now();
+        let mut peers = self.peers.lock().unwrap();
+
+        if !peers.contains_key(&peer) && peers.len() >= self.capacity {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, stats)| stats.last_seen)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+
+        let stats = peers.entry(peer).or_insert_with(|| PeerStats::new(now));
+        match direction {
+            Direction::In => {
+                stats.packets_in += 1;
+                stats.bytes_in += len as u64;
+            }
+            Direction::Out => {
+                stats.packets_out += 1;
+                stats.bytes_out += len as u64;
+            }
+        }
+        stats.last_seen = now;
+    }
+
+    pub fn get(&self, peer: IpAddr) -> Option<PeerStats> {
+        self.peers.lock().unwrap().get(&peer).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The `n` peers with the most traffic (bytes in + out), busiest first
+    pub fn top(&self, n: usize) -> Vec<(IpAddr, PeerStats)> {
+        let peers = self.peers.lock().unwrap();
+        let mut entries: Vec<_> = peers.iter().map(|(addr, stats)| (*addr, *stats)).collect();
+        entries.sort_by_key(|(_, stats)| Reverse(stats.total_bytes()));
+        entries.truncate(n);
+        entries
+    }
+
+    /// Format the top talkers as a table
+    pub fn report(&self, n: usize) -> String {
+        let now = self.clock.now();
+        let mut out = format!(
+            "{:<16} {:>10} {:>12} {:>10} {:>12} {:>10}\n",
+            "PEER", "PKTS_IN", "BYTES_IN", "PKTS_OUT", "BYTES_OUT", "LAST_SEEN"
+        );
+        for (addr, stats) in self.top(n) {
+            let idle = now.duration_since(stats.last_seen);
+            out.push_str(&format!(
+                "{:<16} {:>10} {:>12} {:>10} {:>12} {:>9.1}s\n",
+                addr.to_string(),
+                stats.packets_in,
+                stats.bytes_in,
+                stats.packets_out,
+                stats.bytes_out,
+                idle.as_secs_f64()
+            ));
+        }
+        out
+    }
+}
+
+impl Default for PeerStatsTable {
+    fn default() -> Self {
+        Self::new(PEER_STATS_CAPACITY_DEFAULT)
+    }
+}
+
+/// Packets and bytes counted in one direction
+#[derive(Debug, Default)]
+pub struct Counter {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Counter {
+    pub fn add(&self, len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn packets(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Received and sent traffic of a device or protocol
+#[derive(Debug, Default)]
+pub struct Traffic {
+    pub rx: Counter,
+    pub tx: Counter,
+}
+
+/// Traffic per protocol. The network layers count whole packets, including
+/// forwarded ones; the transport protocols count the payload of the packets
+/// delivered to and sent by this host.
+#[derive(Debug, Default)]
+pub struct ProtocolStats {
+    pub ip: Traffic,
+    pub ipv6: Traffic,
+    pub icmp: Traffic,
+    pub icmpv6: Traffic,
+    pub igmp: Traffic,
+    pub udp: Traffic,
+    pub tcp: Traffic,
+}
+
+impl ProtocolStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counters of the protocol carried in an IP or IPv6 packet, if counted
+    pub fn transport(&self, protocol: IpProtocol) -> Option<&Traffic> {
+        match protocol {
+            IpProtocol::Icmp => Some(&self.icmp),
+            IpProtocol::Igmp => Some(&self.igmp),
+            IpProtocol::Tcp => Some(&self.tcp),
+            IpProtocol::Udp => Some(&self.udp),
+            IpProtocol::Other(IPV6_NEXT_HEADER_ICMPV6) => Some(&self.icmpv6),
+            IpProtocol::Other(_) => None,
+        }
+    }
+
+    /// Every protocol with its name
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &Traffic)> {
+        [
+            ("ip", &self.ip),
+            ("ipv6", &self.ipv6),
+            ("icmp", &self.icmp),
+            ("icmpv6", &self.icmpv6),
+            ("igmp", &self.igmp),
+            ("udp", &self.udp),
+            ("tcp", &self.tcp),
+        ]
+        .into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> IpAddr {
+        IpAddr::from_ne_bytes([192, 0, 2, last])
+    }
+
+    #[test]
+    fn test_peer_stats_counters() {
+        let table = PeerStatsTable::new(4);
+        table.record_in(addr(1), 100);
+        table.record_in(addr(1), 50);
+        table.record_out(addr(1), 20);
+
+        let stats = table.get(addr(1)).unwrap();
+        assert_eq!(stats.packets_in, 2);
+        assert_eq!(stats.bytes_in, 150);
+        assert_eq!(stats.packets_out, 1);
+        assert_eq!(stats.bytes_out, 20);
+        assert!(table.get(addr(2)).is_none());
+    }
+
+    #[test]
+    fn test_peer_stats_evicts_least_recently_seen() {
+        let table = PeerStatsTable::new(2);
+        table.record_in(addr(1), 10);
+        table.record_in(addr(2), 10);
+        table.record_in(addr(1), 10);
+        table.record_in(addr(3), 10);
+
+        assert_eq!(table.len(), 2);
+        assert!(table.get(addr(1)).is_some());
+        assert!(table.get(addr(2)).is_none());
+        assert!(table.get(addr(3)).is_some());
+    }
+
+    #[test]
+    fn test_peer_stats_top() {
+        let table = PeerStatsTable::new(8);
+        table.record_in(addr(1), 10);
+        table.record_out(addr(2), 300);
+        table.record_in(addr(3), 200);
+
+        let top = table.top(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, addr(2));
+        assert_eq!(top[1].0, addr(3));
+    }
+
+    #[test]
+    fn test_peer_stats_last_seen_on_clock() {
+        let time = crate::clock::VirtualClock::new();
+        let table = PeerStatsTable::with_clock(4, Clock::new(time.clone()));
+        table.record_in(addr(1), 10);
+        time.advance(std::time::Duration::from_secs(5));
+        let idle = table.now() - table.get(addr(1)).unwrap().last_seen;
+        assert_eq!(idle.as_secs(), 5);
+        assert!(table.report(1).contains("5.0s"));
+    }
+}
@@ -0,0 +1,36 @@
+//! Shared fixtures for unit tests.
+
+use std::sync::{Arc, Mutex};
+
+use crate::context::ProtocolContexts;
+use crate::device::{DeviceManager, loopback};
+use crate::protocol::ip;
+
+pub type Captured = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Loopback device at 127.0.0.1/8 whose transmitted packets are captured instead of delivered
+pub fn setup_loopback() -> (DeviceManager, ProtocolContexts, Captured) {
+    let captured: Captured = Arc::new(Mutex::new(Vec::new()));
+    let captured_for_cb = Arc::clone(&captured);
+    let mut devices = DeviceManager::new();
+    let mut ctx = ProtocolContexts::new();
+
+    let index = loopback::init(
+        &mut devices,
+        Arc::new(move |frames: &[(u16, &[u8])], _: &_| {
+            let mut captured = captured_for_cb.lock().unwrap();
+            captured.extend(frames.iter().map(|(_, data)| data.to_vec()));
+        }),
+    )
+    .unwrap();
+    ip::register_iface(
+        devices.get_mut(index).unwrap(),
+        "127.0.0.1",
+        "255.0.0.0",
+        &mut ctx,
+    )
+    .unwrap();
+    devices.run().unwrap();
+
+    (devices, ctx, captured)
+}
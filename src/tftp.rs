@@ -0,0 +1,723 @@
+//! TFTP server (RFC 1350).
+//!
+//! [`TftpServer`] answers read and write requests for files under a root
+//! directory. Each transfer gets its own UDP socket on an ephemeral port (the
+//! server's transfer ID), and the last packet of a transfer is sent again when
+//! the peer has not answered within [`TftpOptions::timeout`]. The server never
+//! blocks: call [`TftpServer::poll`] from the loop that drives the stack.
+//!
+//! Files are held in memory for the length of a transfer, which suits the
+//! small files TFTP is used for. `netascii` transfers convert line endings;
+//! `mail` is not supported.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+
+use crate::net::{ToEndpoint, UdpSocket};
+use crate::platform::Instant;
+use crate::protocol::ip::{IpAddr, IpEndpoint};
+use crate::stack::NetStack;
+
+pub const TFTP_PORT: u16 = 69;
+
+/// Payload of every DATA packet but the last
+pub const TFTP_BLOCK_SIZE: usize = 512;
+
+const TFTP_OP_RRQ: u16 = 1;
+const TFTP_OP_WRQ: u16 = 2;
+const TFTP_OP_DATA: u16 = 3;
+const TFTP_OP_ACK: u16 = 4;
+const TFTP_OP_ERROR: u16 = 5;
+
+/// Large enough for any request; DATA packets are at most 516 bytes
+const TFTP_PACKET_MAX: usize = 1024;
+
+/// Error codes of ERROR packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum TftpErrorCode {
+    NotDefined = 0,
+    FileNotFound = 1,
+    AccessViolation = 2,
+    DiskFull = 3,
+    IllegalOperation = 4,
+    UnknownTid = 5,
+    FileExists = 6,
+    NoSuchUser = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TftpMode {
+    Netascii,
+    Octet,
+    Mail,
+}
+
+impl TftpMode {
+    fn parse(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "netascii" => Ok(TftpMode::Netascii),
+            "octet" => Ok(TftpMode::Octet),
+            "mail" => Ok(TftpMode::Mail),
+            _ => bail!("unknown mode: {}", s),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            TftpMode::Netascii => "netascii",
+            TftpMode::Octet => "octet",
+            TftpMode::Mail => "mail",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TftpPacket {
+    ReadRequest { filename: String, mode: TftpMode },
+    WriteRequest { filename: String, mode: TftpMode },
+    Data { block: u16, data: Vec<u8> },
+    Ack { block: u16 },
+    Error { code: u16, message: String },
+}
+
+/// Split a NUL-terminated string off the front of `data`
+fn take_str(data: &[u8]) -> Result<(String, &[u8])> {
+    let Some(end) = data.iter().position(|&b| b == 0) else {
+        bail!("unterminated string");
+    };
+    let s = String::from_utf8(data[..end].to_vec())?;
+    Ok((s, &data[end + 1..]))
+}
+
+impl TftpPacket {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 4 {
+            bail!("too short: {} bytes", data.len());
+        }
+        let op = u16::from_be_bytes([data[0], data[1]]);
+        let number = u16::from_be_bytes([data[2], data[3]]);
+        match op {
+            TFTP_OP_RRQ | TFTP_OP_WRQ => {
+                let (filename, rest) = take_str(&data[2..])?;
+                let (mode, _) = take_str(rest)?;
+                let mode = TftpMode::parse(&mode)?;
+                Ok(if op == TFTP_OP_RRQ {
+                    TftpPacket::ReadRequest { filename, mode }
+                } else {
+                    TftpPacket::WriteRequest { filename, mode }
+                })
+            }
+            TFTP_OP_DATA => {
+                if data.len() > 4 + TFTP_BLOCK_SIZE {
+                    bail!("data block too long: {} bytes", data.len() - 4);
+                }
+                Ok(TftpPacket::Data {
+                    block: number,
+                    data: data[4..].to_vec(),
+                })
+            }
+            TFTP_OP_ACK => Ok(TftpPacket::Ack { block: number }),
+            TFTP_OP_ERROR => {
+                // Some peers leave the message unterminated
+                let message = take_str(&data[4..])
+                    .map(|(s, _)| s)
+                    .unwrap_or_else(|_| String::from_utf8_lossy(&data[4..]).into_owned());
+                Ok(TftpPacket::Error {
+                    code: number,
+                    message,
+                })
+            }
+            _ => bail!("unknown opcode: {}", op),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        match self {
+            TftpPacket::ReadRequest { filename, mode }
+            | TftpPacket::WriteRequest { filename, mode } => {
+                let op = if matches!(self, TftpPacket::ReadRequest { .. }) {
+                    TFTP_OP_RRQ
+                } else {
+                    TFTP_OP_WRQ
+                };
+                buf.extend_from_slice(&op.to_be_bytes());
+                buf.extend_from_slice(filename.as_bytes());
+                buf.push(0);
+                buf.extend_from_slice(mode.as_str().as_bytes());
+                buf.push(0);
+            }
+            TftpPacket::Data { block, data } => {
+                buf.extend_from_slice(&TFTP_OP_DATA.to_be_bytes());
+                buf.extend_from_slice(&block.to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            TftpPacket::Ack { block } => {
+                buf.extend_from_slice(&TFTP_OP_ACK.to_be_bytes());
+                buf.extend_from_slice(&block.to_be_bytes());
+            }
+            TftpPacket::Error { code, message } => {
+                buf.extend_from_slice(&TFTP_OP_ERROR.to_be_bytes());
+                buf.extend_from_slice(&code.to_be_bytes());
+                buf.extend_from_slice(message.as_bytes());
+                buf.push(0);
+            }
+        }
+        buf
+    }
+
+    fn error(code: TftpErrorCode, message: impl Into<String>) -> Self {
+        TftpPacket::Error {
+            code: code as u16,
+            message: message.into(),
+        }
+    }
+}
+
+/// Local line endings to netascii: LF becomes CR LF and a bare CR becomes CR NUL
+fn netascii_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        match b {
+            b'\n' => out.extend_from_slice(b"\r\n"),
+            b'\r' => out.extend_from_slice(b"\r\0"),
+            _ => out.push(b),
+        }
+    }
+    out
+}
+
+/// Netascii to local line endings, the inverse of [`netascii_encode`]
+fn netascii_decode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut iter = data.iter().peekable();
+    while let Some(&b) = iter.next() {
+        if b == b'\r' {
+            match iter.peek() {
+                Some(b'\n') => {
+                    iter.next();
+                    out.push(b'\n');
+                    continue;
+                }
+                Some(0) => {
+                    iter.next();
+                }
+                _ => {}
+            }
+        }
+        out.push(b);
+    }
+    out
+}
+
+#[derive(Debug, Clone)]
+pub struct TftpOptions {
+    /// How long to wait for the peer before sending the last packet again
+    pub timeout: Duration,
+    /// How many times a packet is sent again before the transfer is abandoned
+    pub retries: u32,
+    /// Accept write requests (which only ever create new files)
+    pub writable: bool,
+}
+
+impl Default for TftpOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            retries: 5,
+            writable: true,
+        }
+    }
+}
+
+enum TransferKind {
+    /// Sending `data`; `offset` is where the block last sent starts
+    Read { data: Vec<u8>, offset: usize },
+    /// Receiving into `data`, written to `path` once the last block arrives
+    Write {
+        path: PathBuf,
+        mode: TftpMode,
+        data: Vec<u8>,
+    },
+}
+
+struct Transfer<'a> {
+    socket: UdpSocket<'a>,
+    peer: IpEndpoint,
+    kind: TransferKind,
+    /// Block last sent (read) or acknowledged (write)
+    block: u16,
+    /// Last packet sent, kept for retransmission
+    last: Vec<u8>,
+    sent_at: Instant,
+    retries: u32,
+    /// The last block has been sent (read) or acknowledged (write)
+    complete: bool,
+    finished: bool,
+}
+
+impl Transfer<'_> {
+    fn send(&mut self, packet: &TftpPacket) {
+        self.last = packet.to_bytes();
+        self.sent_at = Instant::now();
+        self.retries = 0;
+        if let Err(e) = self.socket.send_to(&self.last, self.peer) {
+            tracing::debug!("tftp: send to {} failed: {}", self.peer, e);
+        }
+    }
+
+    fn abort(&mut self, code: TftpErrorCode, message: &str) {
+        tracing::debug!("tftp: transfer with {} aborted: {}", self.peer, message);
+        let _ = self
+            .socket
+            .send_to(&TftpPacket::error(code, message).to_bytes(), self.peer);
+        self.finished = true;
+    }
+
+    /// Send the block starting at `offset` of a read transfer
+    fn send_block(&mut self) {
+        let TransferKind::Read { data, offset } = &self.kind else {
+            return;
+        };
+        let end = (*offset + TFTP_BLOCK_SIZE).min(data.len());
+        let packet = TftpPacket::Data {
+            block: self.block,
+            data: data[*offset..end].to_vec(),
+        };
+        self.complete = end - *offset < TFTP_BLOCK_SIZE;
+        self.send(&packet);
+    }
+
+    fn input(&mut self, packet: TftpPacket) {
+        match (&mut self.kind, packet) {
+            (_, TftpPacket::Error { code, message }) => {
+                tracing::debug!("tftp: {} gave up: {} {}", self.peer, code, message);
+                self.finished = true;
+            }
+            (TransferKind::Read { offset, .. }, TftpPacket::Ack { block }) => {
+                // A duplicate ACK is ignored rather than answered, so that a
+                // delayed packet does not double every block from then on
+                if block != self.block {
+                    return;
+                }
+                if self.complete {
+                    tracing::debug!("tftp: sent {} blocks to {}", self.block, self.peer);
+                    self.finished = true;
+                    return;
+                }
+                *offset += TFTP_BLOCK_SIZE;
+                self.block += 1;
+                self.send_block();
+            }
+            (TransferKind::Write { .. }, TftpPacket::Data { block, data }) => {
+                self.receive(block, &data)
+            }
+            _ => self.abort(TftpErrorCode::IllegalOperation, "unexpected packet"),
+        }
+    }
+
+    /// Take block `block` of a write transfer
+    fn receive(&mut self, block: u16, chunk: &[u8]) {
+        if block == self.block {
+            // Our ACK was lost; say it again
+            let _ = self.socket.send_to(&self.last, self.peer);
+            return;
+        }
+        if self.complete || Some(block) != self.block.checked_add(1) {
+            return;
+        }
+        let last = chunk.len() < TFTP_BLOCK_SIZE;
+        if !last && block == u16::MAX {
+            self.abort(TftpErrorCode::DiskFull, "file too large");
+            return;
+        }
+        let TransferKind::Write { path, mode, data } = &mut self.kind else {
+            return;
+        };
+        data.extend_from_slice(chunk);
+        if last {
+            let contents = match mode {
+                TftpMode::Netascii => netascii_decode(data),
+                _ => std::mem::take(data),
+            };
+            if let Err(e) = std::fs::write(&*path, contents) {
+                self.abort(TftpErrorCode::DiskFull, &e.to_string());
+                return;
+            }
+            tracing::debug!("tftp: received {} from {}", path.display(), self.peer);
+            self.complete = true;
+        }
+        self.block = block;
+        self.send(&TftpPacket::Ack { block });
+    }
+
+    fn on_timer(&mut self, opts: &TftpOptions) {
+        if self.finished || Instant::now().duration_since(self.sent_at) < opts.timeout {
+            return;
+        }
+        if self.complete && matches!(self.kind, TransferKind::Write { .. }) {
+            // The peer had a timeout's time to resend a last block whose ACK was lost
+            self.finished = true;
+            return;
+        }
+        if self.retries >= opts.retries {
+            tracing::debug!("tftp: transfer with {} timed out", self.peer);
+            self.finished = true;
+            return;
+        }
+        self.retries += 1;
+        self.sent_at = Instant::now();
+        let _ = self.socket.send_to(&self.last, self.peer);
+    }
+}
+
+/// Receive a datagram without waiting; `None` when there is none
+fn try_recv(socket: &UdpSocket, buf: &mut [u8]) -> Result<Option<(usize, IpEndpoint)>> {
+    match socket.recv_from(buf) {
+        Ok(received) => Ok(Some(received)),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Path of `filename` under `root`, refusing anything that could leave it
+fn resolve_path(root: &Path, filename: &str) -> Option<PathBuf> {
+    let path = Path::new(filename);
+    let inside = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    (inside && !filename.is_empty()).then(|| root.join(path))
+}
+
+fn read_error(e: &io::Error) -> TftpErrorCode {
+    match e.kind() {
+        io::ErrorKind::NotFound => TftpErrorCode::FileNotFound,
+        io::ErrorKind::PermissionDenied => TftpErrorCode::AccessViolation,
+        _ => TftpErrorCode::NotDefined,
+    }
+}
+
+/// A TFTP server serving the files of a directory
+pub struct TftpServer<'a> {
+    stack: &'a NetStack,
+    socket: UdpSocket<'a>,
+    root: PathBuf,
+    opts: TftpOptions,
+    transfers: Vec<Transfer<'a>>,
+}
+
+impl<'a> TftpServer<'a> {
+    /// Listen for requests on `addr` (usually port [`TFTP_PORT`]) for files under `root`
+    pub fn bind(
+        stack: &'a NetStack,
+        addr: impl ToEndpoint,
+        root: impl Into<PathBuf>,
+        opts: TftpOptions,
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind(stack, addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            stack,
+            socket,
+            root: root.into(),
+            opts,
+            transfers: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<IpEndpoint> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Number of transfers in progress
+    pub fn transfers(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// Handle whatever has arrived since the last call and send again what has
+    /// gone unanswered for too long
+    pub fn poll(&mut self) -> Result<()> {
+        let mut buf = [0u8; TFTP_PACKET_MAX];
+        while let Some((len, peer)) = try_recv(&self.socket, &mut buf)? {
+            self.request(&buf[..len], peer);
+        }
+
+        for transfer in &mut self.transfers {
+            while !transfer.finished
+                && let Some((len, from)) = try_recv(&transfer.socket, &mut buf)?
+            {
+                if from != transfer.peer {
+                    let error = TftpPacket::error(TftpErrorCode::UnknownTid, "unknown transfer ID");
+                    let _ = transfer.socket.send_to(&error.to_bytes(), from);
+                    continue;
+                }
+                match TftpPacket::parse(&buf[..len]) {
+                    Ok(packet) => transfer.input(packet),
+                    Err(e) => transfer.abort(
+                        TftpErrorCode::IllegalOperation,
+                        &format!("malformed packet: {}", e),
+                    ),
+                }
+            }
+            transfer.on_timer(&self.opts);
+        }
+        self.transfers.retain(|transfer| !transfer.finished);
+        Ok(())
+    }
+
+    /// Start a transfer for the request in `data` from `peer`
+    fn request(&mut self, data: &[u8], peer: IpEndpoint) {
+        let reply_error = |code: TftpErrorCode, message: &str| {
+            tracing::debug!("tftp: request from {} refused: {}", peer, message);
+            let _ = self
+                .socket
+                .send_to(&TftpPacket::error(code, message).to_bytes(), peer);
+        };
+        let (filename, mode, write) = match TftpPacket::parse(data) {
+            Ok(TftpPacket::ReadRequest { filename, mode }) => (filename, mode, false),
+            Ok(TftpPacket::WriteRequest { filename, mode }) => (filename, mode, true),
+            Ok(TftpPacket::Error { .. }) => return,
+            Ok(_) => return reply_error(TftpErrorCode::UnknownTid, "unknown transfer ID"),
+            Err(e) => return reply_error(TftpErrorCode::IllegalOperation, &e.to_string()),
+        };
+        if mode == TftpMode::Mail {
+            return reply_error(TftpErrorCode::IllegalOperation, "mail mode not supported");
+        }
+        let Some(path) = resolve_path(&self.root, &filename) else {
+            return reply_error(TftpErrorCode::AccessViolation, "access violation");
+        };
+
+        let kind = if write {
+            if !self.opts.writable {
+                return reply_error(TftpErrorCode::AccessViolation, "server is read-only");
+            }
+            if path.exists() {
+                return reply_error(TftpErrorCode::FileExists, "file already exists");
+            }
+            TransferKind::Write {
+                path,
+                mode,
+                data: Vec::new(),
+            }
+        } else {
+            let data = match std::fs::read(&path) {
+                Ok(data) => data,
+                Err(e) => return reply_error(read_error(&e), &e.to_string()),
+            };
+            let data = match mode {
+                TftpMode::Netascii => netascii_encode(&data),
+                _ => data,
+            };
+            if data.len() / TFTP_BLOCK_SIZE >= u16::MAX as usize {
+                return reply_error(TftpErrorCode::NotDefined, "file too large");
+            }
+            TransferKind::Read { data, offset: 0 }
+        };
+
+        let socket = match UdpSocket::bind(self.stack, IpEndpoint::new(IpAddr::ANY, 0))
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+        {
+            Ok(socket) => socket,
+            Err(e) => return reply_error(TftpErrorCode::NotDefined, &e.to_string()),
+        };
+        tracing::debug!(
+            "tftp: {} {} ({}) for {}",
+            if write { "write" } else { "read" },
+            filename,
+            mode.as_str(),
+            peer
+        );
+        let mut transfer = Transfer {
+            socket,
+            peer,
+            kind,
+            block: 0,
+            last: Vec::new(),
+            sent_at: Instant::now(),
+            retries: 0,
+            complete: false,
+            finished: false,
+        };
+        if write {
+            transfer.send(&TftpPacket::Ack { block: 0 });
+        } else {
+            transfer.block = 1;
+            transfer.send_block();
+        }
+        self.transfers.push(transfer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("microps-tftp-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn stack() -> NetStack {
+        let stack = NetStack::new().unwrap();
+        stack.add_loopback().unwrap();
+        stack.run().unwrap();
+        stack
+    }
+
+    fn recv(socket: &UdpSocket) -> (TftpPacket, IpEndpoint) {
+        let mut buf = [0u8; TFTP_PACKET_MAX];
+        let (len, from) = socket.recv_from(&mut buf).unwrap();
+        (TftpPacket::parse(&buf[..len]).unwrap(), from)
+    }
+
+    #[test]
+    fn test_tftp_packet_roundtrip() {
+        let packets = [
+            TftpPacket::ReadRequest {
+                filename: "boot/kernel".into(),
+                mode: TftpMode::Octet,
+            },
+            TftpPacket::WriteRequest {
+                filename: "notes.txt".into(),
+                mode: TftpMode::Netascii,
+            },
+            TftpPacket::Data {
+                block: 7,
+                data: vec![1, 2, 3],
+            },
+            TftpPacket::Ack { block: 65535 },
+            TftpPacket::error(TftpErrorCode::FileNotFound, "no such file"),
+        ];
+        for packet in packets {
+            assert_eq!(TftpPacket::parse(&packet.to_bytes()).unwrap(), packet);
+        }
+        assert!(TftpPacket::parse(b"\x00\x01name\x00binary\x00").is_err());
+        assert!(TftpPacket::parse(b"\x00\x09\x00\x00").is_err());
+
+        let text = b"a\nb\rc\r\n";
+        assert_eq!(netascii_encode(text), b"a\r\nb\r\0c\r\0\r\n");
+        assert_eq!(netascii_decode(&netascii_encode(text)), text);
+    }
+
+    #[test]
+    fn test_tftp_read() {
+        let dir = root("read");
+        let contents: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        std::fs::write(dir.join("file.bin"), &contents).unwrap();
+        let stack = stack();
+        let opts = TftpOptions {
+            timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut server = TftpServer::bind(&stack, "127.0.0.1:69", &dir, opts).unwrap();
+        let client = UdpSocket::bind(&stack, "0.0.0.0:0").unwrap();
+        let rrq = TftpPacket::ReadRequest {
+            filename: "file.bin".into(),
+            mode: TftpMode::Octet,
+        };
+        client.send_to(&rrq.to_bytes(), "127.0.0.1:69").unwrap();
+        server.poll().unwrap();
+
+        let (packet, tid) = recv(&client);
+        assert_ne!(tid.port, TFTP_PORT);
+        assert_eq!(
+            packet,
+            TftpPacket::Data {
+                block: 1,
+                data: contents[..512].to_vec()
+            }
+        );
+
+        // Unanswered, the block is sent again
+        std::thread::sleep(Duration::from_millis(20));
+        server.poll().unwrap();
+        assert_eq!(recv(&client).0, packet);
+
+        client
+            .send_to(&TftpPacket::Ack { block: 1 }.to_bytes(), tid)
+            .unwrap();
+        server.poll().unwrap();
+        let (packet, _) = recv(&client);
+        assert_eq!(
+            packet,
+            TftpPacket::Data {
+                block: 2,
+                data: contents[512..].to_vec()
+            }
+        );
+        client
+            .send_to(&TftpPacket::Ack { block: 2 }.to_bytes(), tid)
+            .unwrap();
+        server.poll().unwrap();
+        assert_eq!(server.transfers(), 0);
+
+        for name in ["missing", "../file.bin", "/etc/passwd"] {
+            let rrq = TftpPacket::ReadRequest {
+                filename: name.into(),
+                mode: TftpMode::Octet,
+            };
+            client.send_to(&rrq.to_bytes(), "127.0.0.1:69").unwrap();
+            server.poll().unwrap();
+            assert!(matches!(
+                recv(&client).0,
+                TftpPacket::Error { code: 1 | 2, .. }
+            ));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tftp_write() {
+        let dir = root("write");
+        let stack = stack();
+        let mut server =
+            TftpServer::bind(&stack, "127.0.0.1:69", &dir, TftpOptions::default()).unwrap();
+        let client = UdpSocket::bind(&stack, "0.0.0.0:0").unwrap();
+        let wrq = TftpPacket::WriteRequest {
+            filename: "upload.txt".into(),
+            mode: TftpMode::Netascii,
+        };
+        client.send_to(&wrq.to_bytes(), "127.0.0.1:69").unwrap();
+        server.poll().unwrap();
+        let (packet, tid) = recv(&client);
+        assert_eq!(packet, TftpPacket::Ack { block: 0 });
+
+        // A stranger using the transfer's port is turned away
+        let stranger = UdpSocket::bind(&stack, "0.0.0.0:0").unwrap();
+        let data = TftpPacket::Data {
+            block: 1,
+            data: netascii_encode(b"hello\nworld\n"),
+        };
+        stranger.send_to(&data.to_bytes(), tid).unwrap();
+        server.poll().unwrap();
+        assert!(matches!(
+            recv(&stranger).0,
+            TftpPacket::Error { code: 5, .. }
+        ));
+
+        client.send_to(&data.to_bytes(), tid).unwrap();
+        server.poll().unwrap();
+        assert_eq!(recv(&client).0, TftpPacket::Ack { block: 1 });
+        assert_eq!(
+            std::fs::read(dir.join("upload.txt")).unwrap(),
+            b"hello\nworld\n"
+        );
+
+        // The last block sent again is acknowledged again
+        client.send_to(&data.to_bytes(), tid).unwrap();
+        server.poll().unwrap();
+        assert_eq!(recv(&client).0, TftpPacket::Ack { block: 1 });
+
+        client.send_to(&wrq.to_bytes(), "127.0.0.1:69").unwrap();
+        server.poll().unwrap();
+        assert!(matches!(recv(&client).0, TftpPacket::Error { code: 6, .. }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+//! Periodic protocol timers (microps' `net_timer_register`).
+//!
+//! Protocols register a handler with the interval it should run at (see
+//! [`ProtocolManager::register_timer`]); TCP retransmission and IGMP reports
+//! do so when the stack is created. Every [`NetStack::tick`] runs the handlers
+//! whose interval has passed since they last ran, by the stack's [`Clock`].
+//! Ticks come from [`NetStack::run_once`] in an application's main loop, or
+//! from the thread of [`NetStack::start_timer_thread`], which ticks every
+//! [`TIMER_RESOLUTION`].
+//!
+//! [`ProtocolManager::register_timer`]: crate::protocol::ProtocolManager::register_timer
+//! [`NetStack::tick`]: crate::stack::NetStack::tick
+//! [`NetStack::run_once`]: crate::stack::NetStack::run_once
+//! [`NetStack::start_timer_thread`]: crate::stack::NetStack::start_timer_thread
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::{NetError, Result};
+
+use crate::clock::Clock;
+use crate::context::ProtocolContexts;
+use crate::device::DeviceManager;
+use crate::platform::Instant;
+
+/// How often the timer thread ticks
+pub const TIMER_RESOLUTION: Duration = Duration::from_millis(10);
+
+pub type TimerHandler = Arc<dyn Fn(&ProtocolContexts, &DeviceManager) + Send + Sync>;
+
+/// A registered timer as [`TimerTable::timers`] reports it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimerInfo {
+    pub name: String,
+    pub interval: Duration,
+    /// Times the handler was run
+    pub runs: u64,
+}
+
+struct Timer {
+    info: TimerInfo,
+    handler: TimerHandler,
+    last: Instant,
+}
+
+#[derive(Default)]
+struct TimerThread {
+    handle: Option<JoinHandle<()>>,
+    stop: bool,
+}
+
+/// Registered timers of one stack, and its timer thread
+#[derive(Default)]
+pub struct TimerTable {
+    clock: Clock,
+    timers: Mutex<Vec<Timer>>,
+    thread: Mutex<TimerThread>,
+    wake: Condvar,
+}
+
+impl TimerTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Timers that are due by `clock` rather than the host clock
+    pub fn with_clock(clock: Clock) -> Self {
+        Self {
+            clock,
+            ..Self::default()
+        }
+    }
+
+    /// Run `handler` every `interval`, the first time one interval from now
+    pub fn register(&self, name: &str, interval: Duration, handler: TimerHandler) -> Result<()> {
+        if interval.is_zero() {
+            return Err(NetError::InvalidInput("timer interval must not be zero"));
+        }
+        let mut timers = self.timers.lock().unwrap();
+        if timers.iter().any(|timer| timer.info.name == name) {
+            return Err(NetError::AlreadyExists(format!("timer {}", name)));
+        }
+        tracing::debug!("Timer registered: {}, interval={:?}", name, interval);
+        timers.push(Timer {
+            info: TimerInfo {
+                name: name.to_string(),
+                interval,
+                runs: 0,
+            },
+            handler,
+            last: self.clock.now(),
+        });
+        Ok(())
+    }
+
+    /// Registered timers in the order they were registered
+    pub fn timers(&self) -> Vec<TimerInfo> {
+        let timers = self.timers.lock().unwrap();
+        timers.iter().map(|timer| timer.info.clone()).collect()
+    }
+
+    /// Run the handlers that are due; returns how many ran
+    pub fn run(&self, ctx: &ProtocolContexts, devices: &DeviceManager) -> usize {
+        let now = self.clock.now();
+        let due: Vec<TimerHandler> = {
+            let mut timers = self.timers.lock().unwrap();
+            timers
+                .iter_mut()
+                .filter(|timer| now.duration_since(timer.last) >= timer.info.interval)
+                .map(|timer| {
+                    timer.last = now;
+                    timer.info.runs += 1;
+                    Arc::clone(&timer.handler)
+                })
+                .collect()
+        };
+        // Not locked while handlers run, so they may register timers
+        for handler in &due {
+            handler(ctx, devices);
+        }
+        due.len()
+    }
+
+    /// Start a thread calling `tick` every [`TIMER_RESOLUTION`] until
+    /// [`stop`](Self::stop) or until `tick` returns false
+    pub(crate) fn start(self: &Arc<Self>, tick: impl Fn() -> bool + Send + 'static) -> Result<()> {
+        let mut thread = self.thread.lock().unwrap();
+        if thread.handle.is_some() {
+            return Err(NetError::AlreadyExists("timer thread".to_string()));
+        }
+        thread.stop = false;
+        let table = Arc::clone(self);
+        let handle = thread::Builder::new()
+            .name("microps-timer".to_string())
+            .spawn(move || while table.sleep() && tick() {})?;
+        thread.handle = Some(handle);
+        tracing::info!("Timer thread started");
+        Ok(())
+    }
+
+    /// Wait one resolution; false if the thread is to stop
+    fn sleep(&self) -> bool {
+        let thread = self.thread.lock().unwrap();
+        if thread.stop {
+            return false;
+        }
+        let (thread, _) = self.wake.wait_timeout(thread, TIMER_RESOLUTION).unwrap();
+        !thread.stop
+    }
+
+    /// Whether the timer thread is running
+    pub fn has_thread(&self) -> bool {
+        self.thread.lock().unwrap().handle.is_some()
+    }
+
+    /// Stop the timer thread, if any, and wait for it to finish
+    pub fn stop(&self) {
+        let handle = {
+            let mut thread = self.thread.lock().unwrap();
+            thread.stop = true;
+            thread.handle.take()
+        };
+        self.wake.notify_all();
+        if let Some(handle) = handle
+            && handle.thread().id() != thread::current().id()
+        {
+            let _ = handle.join();
+            tracing::info!("Timer thread stopped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::stack::NetStack;
+
+    static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(_ctx: &ProtocolContexts, _devices: &DeviceManager) {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn noop(_ctx: &ProtocolContexts, _devices: &DeviceManager) {}
+
+    #[test]
+    fn test_timer_intervals() {
+        let timers = TimerTable::new();
+        let (ctx, devices) = (ProtocolContexts::new(), DeviceManager::new());
+        timers
+            .register("slow", Duration::from_secs(3600), Arc::new(noop))
+            .unwrap();
+        timers
+            .register("fast", Duration::from_millis(1), Arc::new(noop))
+            .unwrap();
+        assert!(
+            timers
+                .register("fast", Duration::from_secs(1), Arc::new(noop))
+                .is_err()
+        );
+        assert!(
+            timers
+                .register("zero", Duration::ZERO, Arc::new(noop))
+                .is_err()
+        );
+
+        assert_eq!(timers.run(&ctx, &devices), 0);
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(timers.run(&ctx, &devices), 1);
+        let runs: Vec<_> = timers.timers().iter().map(|t| t.runs).collect();
+        assert_eq!(runs, [0, 1]);
+    }
+
+    #[test]
+    fn test_timer_thread() {
+        let stack = NetStack::new().unwrap();
+        stack
+            .register_timer("count", Duration::from_millis(1), Arc::new(count))
+            .unwrap();
+        stack.start_timer_thread().unwrap();
+        assert!(stack.start_timer_thread().is_err());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while TICKS.load(Ordering::Relaxed) < 2 && Instant::now() < deadline {
+            thread::sleep(TIMER_RESOLUTION);
+        }
+        assert!(TICKS.load(Ordering::Relaxed) >= 2);
+
+        stack.shutdown().unwrap();
+        assert!(!stack.protocols().timers().has_thread());
+    }
+}
@@ -0,0 +1,324 @@
+//! Declarative multi-stack topologies.
+//!
+//! ```
+//! # use microps::topology::Topology;
+//! let topo = Topology::builder()
+//!     .host("h1")
+//!     .router("r1")
+//!     .host("h2")
+//!     .link(("h1", "192.0.2.1/24"), ("r1", "192.0.2.254/24"))
+//!     .link(("r1", "198.51.100.254/24"), ("h2", "198.51.100.1/24"))
+//!     .gateway("h1", "192.0.2.254")
+//!     .gateway("h2", "198.51.100.254")
+//!     .build()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+use crate::device::ether::EtherAddr;
+use crate::device::veth::Impairment;
+use crate::protocol::ip::{self, IpAddr};
+use crate::stack::{self, NetStack};
+
+struct NodeSpec {
+    name: String,
+    forwarding: bool,
+}
+
+struct LinkSpec {
+    a: (String, String),
+    b: (String, String),
+    impairment: Impairment,
+}
+
+struct HwAddrSpec {
+    node: String,
+    cidr: String,
+    addr: String,
+}
+
+struct RouteSpec {
+    node: String,
+    network: String,
+    gateway: String,
+}
+
+#[derive(Default)]
+pub struct TopologyBuilder {
+    nodes: Vec<NodeSpec>,
+    links: Vec<LinkSpec>,
+    routes: Vec<RouteSpec>,
+    hw_addrs: Vec<HwAddrSpec>,
+}
+
+impl TopologyBuilder {
+    /// Declare an end host
+    pub fn host(mut self, name: &str) -> Self {
+        self.nodes.push(NodeSpec {
+            name: name.to_string(),
+            forwarding: false,
+        });
+        self
+    }
+
+    /// Declare a node that forwards packets between its links
+    pub fn router(mut self, name: &str) -> Self {
+        self.nodes.push(NodeSpec {
+            name: name.to_string(),
+            forwarding: true,
+        });
+        self
+    }
+
+    /// Connect two nodes; each end is `(node, "addr/prefix")`
+    pub fn link(self, a: (&str, &str), b: (&str, &str)) -> Self {
+        self.link_with(a, b, Impairment::default())
+    }
+
+    /// Connect two nodes over an impaired link
+    pub fn link_with(mut self, a: (&str, &str), b: (&str, &str), impairment: Impairment) -> Self {
+        self.links.push(LinkSpec {
+            a: (a.0.to_string(), a.1.to_string()),
+            b: (b.0.to_string(), b.1.to_string()),
+            impairment,
+        });
+        self
+    }
+
+    /// Add a route to `network` (CIDR) via `gateway` on `node`
+    pub fn route(mut self, node: &str, network: &str, gateway: &str) -> Self {
+        self.routes.push(RouteSpec {
+            node: node.to_string(),
+            network: network.to_string(),
+            gateway: gateway.to_string(),
+        });
+        self
+    }
+
+    /// Use `addr` as the MAC of the link end `(node, cidr)`.
+    /// Ends without one get an address derived from the node name and CIDR.
+    pub fn hw_addr(mut self, end: (&str, &str), addr: &str) -> Self {
+        self.hw_addrs.push(HwAddrSpec {
+            node: end.0.to_string(),
+            cidr: end.1.to_string(),
+            addr: addr.to_string(),
+        });
+        self
+    }
+
+    /// Add a default route via `gateway` on `node`
+    pub fn gateway(self, node: &str, gateway: &str) -> Self {
+        self.route(node, "0.0.0.0/0", gateway)
+    }
+
+    /// Instantiate every node, link and route, and bring all devices up
+    pub fn build(self) -> Result<Topology> {
+        let mut nodes: Vec<(String, NetStack)> = Vec::new();
+        for spec in &self.nodes {
+            if nodes.iter().any(|(name, _)| *name == spec.name) {
+                anyhow::bail!("duplicate node: {}", spec.name);
+            }
+            let stack = NetStack::new()?;
+            stack.add_loopback()?;
+            stack.ctx_mut().ip_config.forwarding = spec.forwarding;
+            nodes.push((spec.name.clone(), stack));
+        }
+
+        let topology = Topology { nodes };
+
+        for link in &self.links {
+            let a = topology.require(&link.a.0)?;
+            let b = topology.require(&link.b.0)?;
+            let (a_index, b_index) = stack::connect_veth_with(a, b, &link.impairment)?;
+            a.set_hw_addr(a_index, self.link_hw_addr(&link.a)?)?;
+            b.set_hw_addr(b_index, self.link_hw_addr(&link.b)?)?;
+            register_cidr(a, a_index, &link.a.1)
+                .with_context(|| format!("link {} <-> {}", link.a.0, link.b.0))?;
+            register_cidr(b, b_index, &link.b.1)
+                .with_context(|| format!("link {} <-> {}", link.a.0, link.b.0))?;
+        }
+
+        for route in &self.routes {
+            let stack = topology.require(&route.node)?;
+            let (network, netmask) = ip::parse_cidr(&route.network)?;
+            let gateway = IpAddr::from_str(&route.gateway)?;
+            ip::route_add_via(network, netmask, gateway, &stack.ctx())
+                .with_context(|| format!("route on {}", route.node))?;
+        }
+
+        for (_, stack) in &topology.nodes {
+            stack.run()?;
+        }
+
+        Ok(topology)
+    }
+
+    fn link_hw_addr(&self, end: &(String, String)) -> Result<EtherAddr> {
+        match self
+            .hw_addrs
+            .iter()
+            .find(|spec| spec.node == end.0 && spec.cidr == end.1)
+        {
+            Some(spec) => EtherAddr::from_str(&spec.addr)
+                .with_context(|| format!("hardware address of {} {}", end.0, end.1)),
+            None => Ok(EtherAddr::from_seed(&format!("{}/{}", end.0, end.1))),
+        }
+    }
+}
+
+fn register_cidr(stack: &NetStack, index: crate::device::DeviceIndex, cidr: &str) -> Result<()> {
+    let (unicast, netmask) = ip::parse_cidr(cidr)?;
+    stack.register_ip_iface(index, &unicast.to_string(), &netmask.to_string())?;
+    Ok(())
+}
+
+/// A set of named stacks wired together
+pub struct Topology {
+    nodes: Vec<(String, NetStack)>,
+}
+
+impl Topology {
+    pub fn builder() -> TopologyBuilder {
+        TopologyBuilder::default()
+    }
+
+    pub fn node(&self, name: &str) -> Option<&NetStack> {
+        self.nodes
+            .iter()
+            .find(|(node, _)| node == name)
+            .map(|(_, stack)| stack)
+    }
+
+    fn require(&self, name: &str) -> Result<&NetStack> {
+        self.node(name)
+            .ok_or_else(|| anyhow::anyhow!("unknown node: {}", name))
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = (&str, &NetStack)> {
+        self.nodes
+            .iter()
+            .map(|(name, stack)| (name.as_str(), stack))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceIndex;
+    use crate::protocol::icmp::{self, IcmpType};
+
+    fn addr(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    fn send_echo(stack: &NetStack, dst: &str) -> crate::error::Result<isize> {
+        icmp::output(
+            IcmpType::Echo,
+            0,
+            icmp::echo_values(1, 1),
+            b"ping",
+            IpAddr::ANY,
+            addr(dst),
+            &stack.ctx(),
+            &stack.devices(),
+        )
+    }
+
+    fn two_hosts_one_router(impairment: Impairment) -> Topology {
+        Topology::builder()
+            .host("h1")
+            .router("r1")
+            .host("h2")
+            .link(("h1", "192.0.2.1/24"), ("r1", "192.0.2.254/24"))
+            .link_with(
+                ("r1", "198.51.100.254/24"),
+                ("h2", "198.51.100.1/24"),
+                impairment,
+            )
+            .gateway("h1", "192.0.2.254")
+            .gateway("h2", "198.51.100.254")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_router_between_two_hosts() {
+        let topo = two_hosts_one_router(Impairment::default());
+        let h1 = topo.node("h1").unwrap();
+        let h2 = topo.node("h2").unwrap();
+
+        send_echo(h1, "198.51.100.1").unwrap();
+
+        let stats = h2.ctx().peer_stats.get(addr("192.0.2.1")).unwrap();
+        assert_eq!(stats.packets_in, 1);
+        // The router forwarded the packet without accepting it locally
+        assert!(
+            topo.node("r1")
+                .unwrap()
+                .ctx()
+                .peer_stats
+                .get(addr("192.0.2.1"))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_lossy_link() {
+        let topo = two_hosts_one_router(Impairment::loss(1.0));
+        send_echo(topo.node("h1").unwrap(), "198.51.100.1").unwrap();
+        assert!(topo.node("h2").unwrap().ctx().peer_stats.is_empty());
+    }
+
+    #[test]
+    fn test_link_hw_addrs() {
+        let build = || {
+            Topology::builder()
+                .host("h1")
+                .host("h2")
+                .link(("h1", "192.0.2.1/24"), ("h2", "192.0.2.2/24"))
+                .hw_addr(("h2", "192.0.2.2/24"), "02:00:00:00:00:02")
+                .build()
+                .unwrap()
+        };
+        let hw_addr = |topo: &Topology, node: &str| {
+            topo.node(node)
+                .unwrap()
+                .devices()
+                .get(DeviceIndex(1))
+                .unwrap()
+                .hw_addr()
+        };
+
+        let topo = build();
+        let h1 = hw_addr(&topo, "h1").unwrap();
+        assert!(h1.is_local() && !h1.is_multicast());
+        // Derived addresses do not depend on how many links were built before
+        assert_eq!(hw_addr(&build(), "h1"), Some(h1));
+        assert_eq!(
+            hw_addr(&topo, "h2").unwrap().to_string(),
+            "02:00:00:00:00:02"
+        );
+    }
+
+    #[test]
+    fn test_build_errors() {
+        assert!(Topology::builder().host("h1").host("h1").build().is_err());
+        assert!(
+            Topology::builder()
+                .host("h1")
+                .link(("h1", "192.0.2.1/24"), ("h2", "192.0.2.2/24"))
+                .build()
+                .is_err()
+        );
+        assert!(
+            Topology::builder()
+                .host("h1")
+                .gateway("h1", "192.0.2.254")
+                .build()
+                .is_err()
+        );
+    }
+}
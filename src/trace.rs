@@ -0,0 +1,370 @@
+//! Compact binary event trace.
+//!
+//! When enabled, the stack appends one fixed-size record per event (packet in
+//! and out, receive queue enqueue and dequeue, timer fire, TCP state change) to
+//! the trace sink. Recording costs an atomic load when tracing is off. The
+//! `microps-trace` tool converts a trace into chrome://tracing JSON or text.
+//!
+//! File layout: the 8-byte [`TRACE_MAGIC`], then [`TRACE_RECORD_SIZE`]-byte
+//! little-endian records:
+//!
+//! ```text
+//! +--------------------+------+------+--------+--------+--------+
+//! | time (ns, u64)     | kind | arg0 | arg1   | arg2   | arg3   |
+//! |                    | (u8) | (u8) | (u16)  | (u32)  | (u32)  |
+//! +--------------------+------+------+--------+--------+--------+
+//! ```
+
+use std::fmt::{self, Display};
+use std::io::{BufWriter, Read, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::device::DeviceIndex;
+use crate::platform::Instant;
+use crate::protocol::tcp::TcpState;
+
+pub const TRACE_MAGIC: &[u8; 8] = b"MPTRACE1";
+pub const TRACE_RECORD_SIZE: usize = 20;
+
+const KIND_PACKET_IN: u8 = 1;
+const KIND_PACKET_OUT: u8 = 2;
+const KIND_ENQUEUE: u8 = 3;
+const KIND_DEQUEUE: u8 = 4;
+const KIND_TIMER: u8 = 5;
+const KIND_STATE: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// A frame was received on `dev`
+    PacketIn {
+        dev: DeviceIndex,
+        type_: u16,
+        len: usize,
+    },
+    /// A frame was handed to the driver of `dev`
+    PacketOut {
+        dev: DeviceIndex,
+        type_: u16,
+        len: usize,
+    },
+    /// A frame was queued for its protocol; `depth` is the queue length after
+    Enqueue {
+        dev: DeviceIndex,
+        type_: u16,
+        depth: usize,
+    },
+    /// A queued frame was taken for handling; `depth` is the queue length after
+    Dequeue {
+        dev: DeviceIndex,
+        type_: u16,
+        depth: usize,
+    },
+    /// The protocol timers ran
+    Timer,
+    /// TCP connection `conn` moved from `from` to `to`
+    State {
+        conn: u32,
+        from: TcpState,
+        to: TcpState,
+    },
+}
+
+impl TraceEvent {
+    fn encode(&self, at: Duration) -> [u8; TRACE_RECORD_SIZE] {
+        let (kind, arg0, arg1, arg2, arg3) = match *self {
+            TraceEvent::PacketIn { dev, type_, len } => {
+                (KIND_PACKET_IN, 0, dev.0 as u16, type_ as u32, len as u32)
+            }
+            TraceEvent::PacketOut { dev, type_, len } => {
+                (KIND_PACKET_OUT, 0, dev.0 as u16, type_ as u32, len as u32)
+            }
+            TraceEvent::Enqueue { dev, type_, depth } => {
+                (KIND_ENQUEUE, 0, dev.0 as u16, type_ as u32, depth as u32)
+            }
+            TraceEvent::Dequeue { dev, type_, depth } => {
+                (KIND_DEQUEUE, 0, dev.0 as u16, type_ as u32, depth as u32)
+            }
+            TraceEvent::Timer => (KIND_TIMER, 0, 0, 0, 0),
+            TraceEvent::State { conn, from, to } => (KIND_STATE, from as u8, to as u16, conn, 0),
+        };
+        let mut buf = [0u8; TRACE_RECORD_SIZE];
+        buf[0..8].copy_from_slice(&(at.as_nanos() as u64).to_le_bytes());
+        buf[8] = kind;
+        buf[9] = arg0;
+        buf[10..12].copy_from_slice(&arg1.to_le_bytes());
+        buf[12..16].copy_from_slice(&arg2.to_le_bytes());
+        buf[16..20].copy_from_slice(&arg3.to_le_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8; TRACE_RECORD_SIZE]) -> Result<(Duration, Self)> {
+        let at = Duration::from_nanos(u64::from_le_bytes(buf[0..8].try_into().unwrap()));
+        let arg0 = buf[9];
+        let arg1 = u16::from_le_bytes([buf[10], buf[11]]);
+        let arg2 = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+        let arg3 = u32::from_le_bytes(buf[16..20].try_into().unwrap());
+        let dev = DeviceIndex(arg1 as usize);
+        let state = |v: u32| {
+            TcpState::ALL
+                .get(v as usize)
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("invalid TCP state in trace: {}", v))
+        };
+        let event = match buf[8] {
+            KIND_PACKET_IN => TraceEvent::PacketIn {
+                dev,
+                type_: arg2 as u16,
+                len: arg3 as usize,
+            },
+            KIND_PACKET_OUT => TraceEvent::PacketOut {
+                dev,
+                type_: arg2 as u16,
+                len: arg3 as usize,
+            },
+            KIND_ENQUEUE => TraceEvent::Enqueue {
+                dev,
+                type_: arg2 as u16,
+                depth: arg3 as usize,
+            },
+            KIND_DEQUEUE => TraceEvent::Dequeue {
+                dev,
+                type_: arg2 as u16,
+                depth: arg3 as usize,
+            },
+            KIND_TIMER => TraceEvent::Timer,
+            KIND_STATE => TraceEvent::State {
+                conn: arg2,
+                from: state(arg0 as u32)?,
+                to: state(arg1 as u32)?,
+            },
+            other => anyhow::bail!("unknown trace record kind: {}", other),
+        };
+        Ok((at, event))
+    }
+}
+
+impl Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceEvent::PacketIn { dev, type_, len } => {
+                write!(f, "packet_in dev={} type=0x{:04x} len={}", dev, type_, len)
+            }
+            TraceEvent::PacketOut { dev, type_, len } => {
+                write!(f, "packet_out dev={} type=0x{:04x} len={}", dev, type_, len)
+            }
+            TraceEvent::Enqueue { dev, type_, depth } => {
+                write!(
+                    f,
+                    "enqueue dev={} type=0x{:04x} depth={}",
+                    dev, type_, depth
+                )
+            }
+            TraceEvent::Dequeue { dev, type_, depth } => {
+                write!(
+                    f,
+                    "dequeue dev={} type=0x{:04x} depth={}",
+                    dev, type_, depth
+                )
+            }
+            TraceEvent::Timer => write!(f, "timer"),
+            TraceEvent::State { conn, from, to } => {
+                write!(f, "tcp_state conn={} {:?} => {:?}", conn, from, to)
+            }
+        }
+    }
+}
+
+/// A decoded trace record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Time since tracing started
+    pub at: Duration,
+    pub event: TraceEvent,
+}
+
+struct Sink {
+    out: BufWriter<Box<dyn Write + Send>>,
+    start: Instant,
+}
+
+/// Where the stack's trace records go
+pub struct Tracer {
+    enabled: AtomicBool,
+    sink: Mutex<Option<Sink>>,
+}
+
+/// The stack-wide tracer
+pub static TRACE: Tracer = Tracer::new();
+
+impl Tracer {
+    pub const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            sink: Mutex::new(None),
+        }
+    }
+
+    /// Start writing records to `out`, replacing any previous sink
+    pub fn start(&self, out: Box<dyn Write + Send>) -> Result<()> {
+        let mut out = BufWriter::new(out);
+        out.write_all(TRACE_MAGIC)?;
+        let previous = self.sink.lock().unwrap().replace(Sink {
+            out,
+            start: Instant::now(),
+        });
+        self.enabled.store(true, Ordering::Release);
+        if let Some(mut previous) = previous {
+            previous.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Stop tracing and flush the sink
+    pub fn stop(&self) -> Result<()> {
+        self.enabled.store(false, Ordering::Release);
+        if let Some(mut sink) = self.sink.lock().unwrap().take() {
+            sink.out.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn record(&self, event: TraceEvent) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut sink = self.sink.lock().unwrap();
+        let Some(sink) = sink.as_mut() else {
+            return;
+        };
+        let record = event.encode(sink.start.elapsed());
+        if let Err(e) = sink.out.write_all(&record) {
+            tracing::error!("trace: write failed, tracing stopped: {}", e);
+            self.enabled.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decode a trace written by [`Tracer`]
+pub fn read(mut input: impl Read) -> Result<Vec<TraceRecord>> {
+    let mut magic = [0u8; TRACE_MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    if &magic != TRACE_MAGIC {
+        anyhow::bail!("not a microps trace");
+    }
+
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+    if !data.len().is_multiple_of(TRACE_RECORD_SIZE) {
+        tracing::warn!("trace: ignoring truncated last record");
+    }
+    data.chunks_exact(TRACE_RECORD_SIZE)
+        .map(|chunk| {
+            let (at, event) = TraceEvent::decode(chunk.try_into().unwrap())?;
+            Ok(TraceRecord { at, event })
+        })
+        .collect()
+}
+
+/// One line per record: time in microseconds and the event
+pub fn to_text(records: &[TraceRecord]) -> String {
+    records
+        .iter()
+        .map(|r| format!("{:>12.3}us {}\n", r.at.as_nanos() as f64 / 1000.0, r.event))
+        .collect()
+}
+
+/// The Trace Event Format read by chrome://tracing and Perfetto.
+///
+/// Each device is a thread; queue depths are counters and TCP state changes are
+/// instant events on a thread per connection.
+pub fn to_chrome_json(records: &[TraceRecord]) -> String {
+    let events: Vec<String> = records
+        .iter()
+        .map(|r| {
+            let ts = r.at.as_nanos() as f64 / 1000.0;
+            match r.event {
+                TraceEvent::PacketIn { dev, type_, len } | TraceEvent::PacketOut { dev, type_, len } => {
+                    let name = match r.event {
+                        TraceEvent::PacketIn { .. } => "in",
+                        _ => "out",
+                    };
+                    format!(
+                        r#"{{"name":"{} 0x{:04x}","ph":"i","s":"t","ts":{:.3},"pid":0,"tid":{},"args":{{"len":{}}}}}"#,
+                        name, type_, ts, dev, len
+                    )
+                }
+                TraceEvent::Enqueue { dev, type_, depth } | TraceEvent::Dequeue { dev, type_, depth } => {
+                    format!(
+                        r#"{{"name":"rxq dev{} 0x{:04x}","ph":"C","ts":{:.3},"pid":0,"args":{{"depth":{}}}}}"#,
+                        dev, type_, ts, depth
+                    )
+                }
+                TraceEvent::Timer => format!(
+                    r#"{{"name":"timer","ph":"i","s":"p","ts":{:.3},"pid":0,"tid":0}}"#,
+                    ts
+                ),
+                TraceEvent::State { conn, from, to } => format!(
+                    r#"{{"name":"{:?}","ph":"i","s":"t","ts":{:.3},"pid":1,"tid":{},"args":{{"from":"{:?}"}}}}"#,
+                    to, ts, conn, from
+                ),
+            }
+        })
+        .collect();
+    format!("{{\"traceEvents\":[\n{}\n]}}\n", events.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_roundtrip() {
+        let events = [
+            TraceEvent::PacketIn {
+                dev: DeviceIndex(1),
+                type_: 0x0800,
+                len: 84,
+            },
+            TraceEvent::Dequeue {
+                dev: DeviceIndex(1),
+                type_: 0x0800,
+                depth: 3,
+            },
+            TraceEvent::Timer,
+            TraceEvent::State {
+                conn: 7,
+                from: TcpState::SynSent,
+                to: TcpState::Established,
+            },
+        ];
+        let mut trace = TRACE_MAGIC.to_vec();
+        for (i, event) in events.iter().enumerate() {
+            trace.extend_from_slice(&event.encode(Duration::from_micros(i as u64)));
+        }
+
+        let records = read(trace.as_slice()).unwrap();
+        let decoded: Vec<_> = records.iter().map(|r| r.event).collect();
+        assert_eq!(decoded, events);
+        assert_eq!(records[3].at, Duration::from_micros(3));
+
+        let json = to_chrome_json(&records);
+        assert!(json.starts_with("{\"traceEvents\":["));
+        assert_eq!(json.matches("\"ph\"").count(), events.len());
+        assert!(to_text(&records).contains("SynSent => Established"));
+        assert!(read(&b"NOTATRACE"[..]).is_err());
+    }
+}
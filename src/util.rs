@@ -1,120 +1,149 @@
-use std::io::Write;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-/// Convert 16-bit value from network byte order to host byte order
-#[inline]
-pub fn ntoh16(n: u16) -> u16 {
-    u16::from_be(n)
-}
+use tracing::Level;
 
-/// Convert 16-bit value from host byte order to network byte order
-#[inline]
-pub fn hton16(h: u16) -> u16 {
-    h.to_be()
-}
+use crate::platform::Instant;
+
+pub use crate::wire::{cksum16, hton16, hton32, ntoh16, ntoh32};
+pub(crate) use crate::wire::{header_accessors, wire_codec};
 
-/// Convert 32-bit value from network byte order to host byte order
-#[inline]
-pub fn ntoh32(n: u32) -> u32 {
-    u32::from_be(n)
+/// Log 1 in N data-plane events (1 = log everything)
+static LOG_SAMPLE_EVERY: AtomicU64 = AtomicU64::new(1);
+/// Max data-plane events logged per second per call site (0 = unlimited)
+static LOG_RATE_LIMIT: AtomicU64 = AtomicU64::new(0);
+
+/// Configure sampling and rate limiting of per-packet log output
+pub fn set_log_sampling(sample_every: u64, max_per_sec: u64) {
+    LOG_SAMPLE_EVERY.store(sample_every.max(1), Ordering::Relaxed);
+    LOG_RATE_LIMIT.store(max_per_sec, Ordering::Relaxed);
 }
 
-/// Convert 32-bit value from host byte order to network byte order
-#[inline]
-pub fn hton32(h: u32) -> u32 {
-    h.to_be()
+fn log_epoch_secs() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_secs()
 }
 
-/// Internet checksum (RFC 1071)
-/// Computes 16-bit one's complement sum
-///
-/// # Arguments
-/// * `data` - byte slice to checksum
-/// * `init` - initial value (used for pseudo-header in TCP/UDP)
+/// Sampling and rate limiting state for one data-plane log site.
 ///
-/// # Returns
-/// The one's complement of the one's complement sum
-pub fn cksum16(data: &[u8], init: u32) -> u16 {
-    let mut sum = init;
-
-    // Process 16-bit words
-    let mut chunks = data.chunks_exact(2);
-    for chunk in chunks.by_ref() {
-        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
-    }
+/// Per-packet logging at DEBUG level can dominate processing time under load;
+/// sites check [`LogLimiter::allow`] before emitting, and the limiter keeps
+/// counters of what was suppressed.
+pub struct LogLimiter {
+    name: &'static str,
+    seen: AtomicU64,
+    suppressed: AtomicU64,
+    window: AtomicU64,
+    window_count: AtomicU64,
+    window_suppressed: AtomicU64,
+}
 
-    // Handle odd byte
-    if let Some(&last) = chunks.remainder().first() {
-        sum += (last as u32) << 8;
+impl LogLimiter {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            seen: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+            window: AtomicU64::new(0),
+            window_count: AtomicU64::new(0),
+            window_suppressed: AtomicU64::new(0),
+        }
     }
 
-    // Fold 32-bit sum to 16 bits
-    while sum >> 16 != 0 {
-        sum = (sum & 0xffff) + (sum >> 16);
+    pub fn name(&self) -> &'static str {
+        self.name
     }
 
-    !(sum as u16)
-}
-
-/// Hexdump utility for debugging
-/// Outputs data in hexadecimal and ASCII format
-fn hexdump(data: &[u8]) {
-    let mut stderr = std::io::stderr();
-    let _ = writeln!(
-        stderr,
-        "+------+-------------------------------------------------+------------------+"
-    );
-
-    for offset in (0..data.len()).step_by(16) {
-        // Print offset
-        let _ = write!(stderr, "| {:04x} | ", offset);
-
-        // Print hex values
-        for index in 0..16 {
-            if offset + index < data.len() {
-                let _ = write!(stderr, "{:02x} ", data[offset + index]);
-            } else {
-                let _ = write!(stderr, "   ");
-            }
+    /// Whether an event at `level` should be logged now
+    pub fn allow(&self, level: Level) -> bool {
+        if !tracing::level_enabled!(level) {
+            return false;
         }
+        self.sample(
+            LOG_SAMPLE_EVERY.load(Ordering::Relaxed),
+            LOG_RATE_LIMIT.load(Ordering::Relaxed),
+            log_epoch_secs(),
+        )
+    }
 
-        let _ = write!(stderr, "| ");
+    fn sample(&self, every: u64, max_per_sec: u64, now: u64) -> bool {
+        let seen = self.seen.fetch_add(1, Ordering::Relaxed);
+        if !seen.is_multiple_of(every.max(1)) {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
 
-        // Print ASCII representation
-        for index in 0..16 {
-            if offset + index < data.len() {
-                let byte = data[offset + index];
-                if byte.is_ascii() && !byte.is_ascii_control() {
-                    let _ = write!(stderr, "{}", byte as char);
-                } else {
-                    let _ = write!(stderr, ".");
+        if max_per_sec > 0 {
+            let window = self.window.load(Ordering::Relaxed);
+            if window != now
+                && self
+                    .window
+                    .compare_exchange(window, now, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            {
+                self.window_count.store(0, Ordering::Relaxed);
+                let dropped = self.window_suppressed.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    tracing::info!("{}: {} log messages suppressed", self.name, dropped);
                 }
-            } else {
-                let _ = write!(stderr, " ");
+            }
+            if self.window_count.fetch_add(1, Ordering::Relaxed) >= max_per_sec {
+                self.suppressed.fetch_add(1, Ordering::Relaxed);
+                self.window_suppressed.fetch_add(1, Ordering::Relaxed);
+                return false;
             }
         }
 
-        let _ = writeln!(stderr, " |");
+        true
     }
 
-    let _ = writeln!(
-        stderr,
-        "+------+-------------------------------------------------+------------------+"
-    );
-}
-
-/// Conditional debug dump - only active in debug builds
-#[cfg(debug_assertions)]
-pub fn debugdump(data: &[u8]) {
-    hexdump(data);
+    /// Events seen and events suppressed so far
+    pub fn counters(&self) -> (u64, u64) {
+        (
+            self.seen.load(Ordering::Relaxed),
+            self.suppressed.load(Ordering::Relaxed),
+        )
+    }
 }
 
-#[cfg(not(debug_assertions))]
-pub fn debugdump(_data: &[u8]) {
-    // No-op in release builds
+pub static LOG_IP_INPUT: LogLimiter = LogLimiter::new("ip_input");
+pub static LOG_IP_OUTPUT: LogLimiter = LogLimiter::new("ip_output");
+pub static LOG_IPV6_INPUT: LogLimiter = LogLimiter::new("ipv6_input");
+pub static LOG_IPV6_OUTPUT: LogLimiter = LogLimiter::new("ipv6_output");
+pub static LOG_ICMP_INPUT: LogLimiter = LogLimiter::new("icmp_input");
+pub static LOG_ICMP_OUTPUT: LogLimiter = LogLimiter::new("icmp_output");
+pub static LOG_UDP_INPUT: LogLimiter = LogLimiter::new("udp_input");
+pub static LOG_UDP_OUTPUT: LogLimiter = LogLimiter::new("udp_output");
+pub static LOG_TCP_INPUT: LogLimiter = LogLimiter::new("tcp_input");
+pub static LOG_TCP_OUTPUT: LogLimiter = LogLimiter::new("tcp_output");
+pub static LOG_DEVICE: LogLimiter = LogLimiter::new("device");
+pub static LOG_DRIVER: LogLimiter = LogLimiter::new("driver");
+pub static LOG_PACKET: LogLimiter = LogLimiter::new("packet");
+
+/// All data-plane log sites, for reporting their counters
+pub fn log_limiters() -> [&'static LogLimiter; 13] {
+    [
+        &LOG_IP_INPUT,
+        &LOG_IP_OUTPUT,
+        &LOG_IPV6_INPUT,
+        &LOG_IPV6_OUTPUT,
+        &LOG_ICMP_INPUT,
+        &LOG_ICMP_OUTPUT,
+        &LOG_UDP_INPUT,
+        &LOG_UDP_OUTPUT,
+        &LOG_TCP_INPUT,
+        &LOG_TCP_OUTPUT,
+        &LOG_DEVICE,
+        &LOG_DRIVER,
+        &LOG_PACKET,
+    ]
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
@@ -158,4 +187,64 @@ mod tests {
         let data = [0x01, 0x02, 0x03];
         let _ = cksum16(&data, 0); // Should not panic
     }
+
+    /// RFC 1071 done the long way: a 64-bit sum of big-endian words, the odd
+    /// byte padded with zero, folded once at the end
+    fn cksum16_reference(data: &[u8], init: u32) -> u16 {
+        let mut sum = init as u64;
+        for i in (0..data.len()).step_by(2) {
+            let hi = data[i] as u64;
+            let lo = data.get(i + 1).copied().unwrap_or(0) as u64;
+            sum += (hi << 8) | lo;
+        }
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    proptest! {
+        #[test]
+        fn test_cksum16_matches_reference(
+            data in vec(any::<u8>(), 0..2048),
+            init in 0u32..0x0004_0000,
+        ) {
+            prop_assert_eq!(cksum16(&data, init), cksum16_reference(&data, init));
+        }
+
+        #[test]
+        fn test_cksum16_verifies_inserted_sum(
+            mut data in vec(any::<u8>(), 2..2048).prop_map(|mut v| {
+                v.truncate(v.len() & !1);
+                v
+            }),
+            at in any::<prop::sample::Index>(),
+        ) {
+            // A checksum field anywhere on a word boundary
+            let at = at.index(data.len() / 2) * 2;
+            data[at..at + 2].fill(0);
+            let sum = cksum16(&data, 0);
+            data[at..at + 2].copy_from_slice(&sum.to_be_bytes());
+            prop_assert_eq!(cksum16(&data, 0), 0);
+        }
+    }
+
+    #[test]
+    fn test_log_limiter_sampling() {
+        let limiter = LogLimiter::new("test");
+        let allowed: Vec<bool> = (0..6).map(|_| limiter.sample(3, 0, 0)).collect();
+        assert_eq!(allowed, [true, false, false, true, false, false]);
+        assert_eq!(limiter.counters(), (6, 4));
+    }
+
+    #[test]
+    fn test_log_limiter_rate_limit() {
+        let limiter = LogLimiter::new("test");
+        let allowed: Vec<bool> = (0..4).map(|_| limiter.sample(1, 2, 10)).collect();
+        assert_eq!(allowed, [true, true, false, false]);
+
+        // A new one-second window resets the budget
+        assert!(limiter.sample(1, 2, 11));
+        assert_eq!(limiter.counters(), (5, 2));
+    }
 }
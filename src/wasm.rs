@@ -0,0 +1,220 @@
+//! Browser demo: two stacks in one WebAssembly instance, linked by memory
+//! devices whose wire is JavaScript (`web/microps.js`).
+//!
+//! Frames transmitted by a stack wait until the page passes them to the other
+//! side (or drops them), so every exchange can be shown frame by frame. Side 0
+//! is 192.0.2.1 and side 1 is 192.0.2.2. Functions return a negative value on
+//! error.
+
+use std::cell::RefCell;
+
+use anyhow::Result;
+
+use crate::device::DeviceIndex;
+use crate::device::ether::EtherAddr;
+use crate::device::memory::{MemoryFrame, MemoryQueue};
+use crate::protocol::icmp::{self, IcmpType};
+use crate::protocol::ip::{IpAddr, IpEndpoint};
+use crate::protocol::udp;
+use crate::stack::NetStack;
+
+const DEMO_ADDRS: [&str; 2] = ["192.0.2.1", "192.0.2.2"];
+const DEMO_NETMASK: &str = "255.255.255.0";
+const DEMO_ECHO_ID: u16 = 1;
+const DEMO_UDP_PORT: u16 = 7;
+/// Large enough for any frame of a memory device
+const DEMO_FRAME_BUFFER_SIZE: usize = 2048;
+
+struct Side {
+    stack: NetStack,
+    index: DeviceIndex,
+    tx: MemoryQueue,
+}
+
+struct Demo {
+    sides: [Side; 2],
+    /// Where the page reads frames copied by [`microps_demo_peek`]
+    buffer: Vec<u8>,
+}
+
+thread_local! {
+    static DEMO: RefCell<Option<Demo>> = const { RefCell::new(None) };
+}
+
+fn side(n: usize) -> Result<Side> {
+    let stack = NetStack::new()?;
+    let (index, tx) = stack.add_memory(EtherAddr::from_seed(DEMO_ADDRS[n]))?;
+    stack.register_ip_iface(index, DEMO_ADDRS[n], DEMO_NETMASK)?;
+    stack.run()?;
+    Ok(Side { stack, index, tx })
+}
+
+fn with_demo<T>(f: impl FnOnce(&mut Demo) -> Result<T>) -> Result<T> {
+    DEMO.with_borrow_mut(|demo| match demo {
+        Some(demo) => f(demo),
+        None => anyhow::bail!("demo not initialized"),
+    })
+}
+
+fn status(result: Result<i32>) -> i32 {
+    result.unwrap_or_else(|e| {
+        tracing::error!("demo: {}", e);
+        -1
+    })
+}
+
+fn addr(n: usize) -> Result<IpAddr> {
+    DEMO_ADDRS[n].parse()
+}
+
+/// Create (or recreate) both stacks
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_init() -> i32 {
+    status((|| {
+        let sides = [side(0)?, side(1)?];
+        sides[0].stack.ctx().icmp_echo.register(DEMO_ECHO_ID)?;
+        let udp = sides[1].stack.ctx().udp.open()?;
+        sides[1]
+            .stack
+            .ctx()
+            .udp
+            .bind(udp, IpEndpoint::new(addr(1)?, DEMO_UDP_PORT))?;
+        DEMO.set(Some(Demo {
+            sides,
+            buffer: vec![0; DEMO_FRAME_BUFFER_SIZE],
+        }));
+        Ok(0)
+    })())
+}
+
+/// Address of the buffer [`microps_demo_peek`] copies frames into
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_buffer() -> *const u8 {
+    with_demo(|demo| Ok(demo.buffer.as_ptr())).unwrap_or(std::ptr::null())
+}
+
+/// Side 0 pings side 1 with sequence number `seq`
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_ping(seq: u32) -> i32 {
+    status(with_demo(|demo| {
+        let a = &demo.sides[0].stack;
+        icmp::output(
+            IcmpType::Echo,
+            0,
+            icmp::echo_values(DEMO_ECHO_ID, seq as u16),
+            b"microps demo",
+            addr(0)?,
+            addr(1)?,
+            &a.ctx(),
+            &a.devices(),
+        )?;
+        Ok(0)
+    }))
+}
+
+/// Echo replies side 0 has received since the last call
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_replies() -> i32 {
+    status(with_demo(|demo| {
+        Ok(demo.sides[0].stack.ctx().icmp_echo.take(DEMO_ECHO_ID).len() as i32)
+    }))
+}
+
+/// Side 0 sends a UDP datagram to port 7 of side 1
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_udp() -> i32 {
+    status(with_demo(|demo| {
+        let a = &demo.sides[0].stack;
+        let src = IpEndpoint::new(addr(0)?, 40000);
+        let dst = IpEndpoint::new(addr(1)?, DEMO_UDP_PORT);
+        udp::output(src, dst, b"hello from side 0", &a.ctx(), &a.devices())?;
+        Ok(0)
+    }))
+}
+
+/// Frames `side` has transmitted that are still on the wire
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_pending(side: u32) -> i32 {
+    status(with_demo(|demo| {
+        let side = demo
+            .sides
+            .get(side as usize)
+            .ok_or(anyhow::anyhow!("bad side"))?;
+        Ok(side.tx.lock().unwrap().len() as i32)
+    }))
+}
+
+/// Copy the oldest frame on the wire from `side` into the buffer and return
+/// its length; the frame stays on the wire. The ethertype is in [`microps_demo_type`].
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_peek(side: u32) -> i32 {
+    status(with_demo(|demo| {
+        let tx = &demo
+            .sides
+            .get(side as usize)
+            .ok_or(anyhow::anyhow!("bad side"))?
+            .tx;
+        let tx = tx.lock().unwrap();
+        let Some(frame) = tx.front() else {
+            return Ok(0);
+        };
+        let len = frame.data.len().min(demo.buffer.len());
+        demo.buffer[..len].copy_from_slice(&frame.data[..len]);
+        Ok(len as i32)
+    }))
+}
+
+/// Ethertype of the oldest frame on the wire from `side`
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_type(side: u32) -> i32 {
+    status(with_demo(|demo| {
+        let tx = &demo
+            .sides
+            .get(side as usize)
+            .ok_or(anyhow::anyhow!("bad side"))?
+            .tx;
+        Ok(tx
+            .lock()
+            .unwrap()
+            .front()
+            .map_or(0, |frame| frame.type_ as i32))
+    }))
+}
+
+fn take(demo: &Demo, side: usize) -> Result<Option<MemoryFrame>> {
+    let side = demo.sides.get(side).ok_or(anyhow::anyhow!("bad side"))?;
+    Ok(side.tx.lock().unwrap().pop_front())
+}
+
+/// Deliver the oldest frame on the wire from `side` to the other side.
+/// Replies it triggers are put on the wire, not delivered.
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_pass(side: u32) -> i32 {
+    status(with_demo(|demo| {
+        let Some(frame) = take(demo, side as usize)? else {
+            return Ok(0);
+        };
+        let peer = &demo.sides[1 - side as usize];
+        peer.stack.inject(peer.index, frame.type_, &frame.data)?;
+        Ok(1)
+    }))
+}
+
+/// Drop the oldest frame on the wire from `side`
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_drop(side: u32) -> i32 {
+    status(with_demo(|demo| {
+        Ok(take(demo, side as usize)?.is_some() as i32)
+    }))
+}
+
+/// Run both stacks' timers (retransmissions are put on the wire)
+#[unsafe(no_mangle)]
+pub extern "C" fn microps_demo_tick() -> i32 {
+    status(with_demo(|demo| {
+        for side in &demo.sides {
+            side.stack.tick();
+        }
+        Ok(0)
+    }))
+}
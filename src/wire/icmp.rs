@@ -0,0 +1,128 @@
+//! ICMP types, codes and the header.
+
+use core::fmt;
+
+use super::{header_accessors, wire_codec};
+
+pub const ICMP_HDR_SIZE: usize = 8;
+
+/// Destination Unreachable codes
+pub const ICMP_CODE_NET_UNREACH: u8 = 0;
+pub const ICMP_CODE_HOST_UNREACH: u8 = 1;
+pub const ICMP_CODE_PROTO_UNREACH: u8 = 2;
+pub const ICMP_CODE_PORT_UNREACH: u8 = 3;
+
+/// Time Exceeded codes
+pub const ICMP_CODE_EXCEEDED_TTL: u8 = 0;
+pub const ICMP_CODE_EXCEEDED_FRAGMENT: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IcmpType {
+    EchoReply = 0,
+    DestUnreachable = 3,
+    SourceQuench = 4,
+    Redirect = 5,
+    Echo = 8,
+    TimeExceeded = 11,
+    ParameterProblem = 12,
+    Timestamp = 13,
+    TimestampReply = 14,
+    InfoRequest = 15,
+    InfoReply = 16,
+}
+
+impl IcmpType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(IcmpType::EchoReply),
+            3 => Some(IcmpType::DestUnreachable),
+            4 => Some(IcmpType::SourceQuench),
+            5 => Some(IcmpType::Redirect),
+            8 => Some(IcmpType::Echo),
+            11 => Some(IcmpType::TimeExceeded),
+            12 => Some(IcmpType::ParameterProblem),
+            13 => Some(IcmpType::Timestamp),
+            14 => Some(IcmpType::TimestampReply),
+            15 => Some(IcmpType::InfoRequest),
+            16 => Some(IcmpType::InfoReply),
+            _ => None,
+        }
+    }
+}
+
+/// ICMP Header (RFC 792)
+///
+/// Generic ICMP header format:
+/// ```text
+///  0                   1                   2                   3
+///  0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |     Type      |     Code      |          Checksum             |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// |                         Values (varies)                       |
+/// +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcmpHdr {
+    type_: u8,
+    code: u8,
+    sum: u16,
+    values: u32,
+}
+
+wire_codec!(IcmpHdr, ICMP_HDR_SIZE, {
+    type_: u8,
+    code: u8,
+    sum: u16,
+    values: u32,
+});
+
+header_accessors!(IcmpHdr, {
+    type_: u8,
+    code: u8,
+    /// Checksum
+    sum: u16,
+    /// Type-specific rest-of-header
+    values: u32,
+});
+
+impl IcmpHdr {
+    /// Header with a zero checksum
+    pub fn new(type_: IcmpType, code: u8, values: u32) -> Self {
+        Self {
+            type_: type_ as u8,
+            code,
+            sum: 0,
+            values,
+        }
+    }
+
+    /// Get the ICMP type as an enum
+    pub fn type_enum(&self) -> Option<IcmpType> {
+        IcmpType::from_u8(self.type_())
+    }
+
+    /// For Echo Request/Reply: extract identifier
+    pub fn echo_id(&self) -> u16 {
+        (self.values() >> 16) as u16
+    }
+
+    /// For Echo Request/Reply: extract sequence number
+    pub fn echo_seq(&self) -> u16 {
+        (self.values() & 0xFFFF) as u16
+    }
+}
+
+impl fmt::Display for IcmpHdr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "type={}, code={}, sum={:#06x}, values={:#010x}",
+            self.type_(),
+            self.code(),
+            self.sum(),
+            self.values()
+        )
+    }
+}
@@ -0,0 +1,449 @@
+//! IPv4 addresses, endpoints, the header and its options.
+
+use alloc::vec::Vec;
+use core::fmt::{self, Display};
+use core::ops::{BitAnd, BitOr, Not};
+use core::str::FromStr;
+
+use anyhow::Result;
+
+use super::{WireField, cksum16, header_accessors, wire_codec};
+
+pub const IP_VERSION_IPV4: u8 = 4;
+
+pub const IP_HDR_SIZE_MIN: usize = 20;
+pub const IP_HDR_SIZE_MAX: usize = 60;
+
+pub const IP_TOTAL_SIZE_MAX: usize = u16::MAX as usize;
+pub const IP_PAYLOAD_SIZE_MAX: usize = IP_TOTAL_SIZE_MAX - IP_HDR_SIZE_MIN;
+
+pub const IP_ADDR_LEN: usize = 4;
+pub const IP_ADDR_STR_LEN: usize = 16;
+
+pub const IP_HDR_FLAG_MF: u16 = 0x2000;
+pub const IP_HDR_FLAG_DF: u16 = 0x4000;
+pub const IP_HDR_FLAG_RF: u16 = 0x8000;
+pub const IP_HDR_OFFSET_MASK: u16 = 0x1fff;
+
+pub const IP_TTL_DEFAULT: u8 = 0xff;
+
+pub const IP_OPT_EOL: u8 = 0;
+pub const IP_OPT_NOP: u8 = 1;
+pub const IP_OPT_LSRR: u8 = 131;
+
+pub const IP_OPT_SIZE_MAX: usize = IP_HDR_SIZE_MAX - IP_HDR_SIZE_MIN;
+/// Offset of the first route address within a source route option (1-origin)
+const IP_OPT_SRR_PTR_MIN: u8 = 4;
+/// Max addresses in a source route option preceded by a NOP: (40 - 4) / 4
+pub const IP_OPT_SRR_ADDRS_MAX: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpProtocol {
+    Icmp,
+    Igmp,
+    Tcp,
+    Udp,
+    Other(u8),
+}
+
+impl IpProtocol {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => IpProtocol::Icmp,
+            2 => IpProtocol::Igmp,
+            6 => IpProtocol::Tcp,
+            17 => IpProtocol::Udp,
+            other => IpProtocol::Other(other),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            IpProtocol::Icmp => 1,
+            IpProtocol::Igmp => 2,
+            IpProtocol::Tcp => 6,
+            IpProtocol::Udp => 17,
+            IpProtocol::Other(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct IpAddr(u32);
+
+impl IpAddr {
+    pub const ANY: Self = IpAddr(0x00000000);
+    pub const BROADCAST: Self = IpAddr(0xffffffff);
+    /// Group of every multicast-capable host on the link
+    pub const ALL_HOSTS: Self = IpAddr(u32::from_ne_bytes([224, 0, 0, 1]));
+    /// Group of every multicast router on the link
+    pub const ALL_ROUTERS: Self = IpAddr(u32::from_ne_bytes([224, 0, 0, 2]));
+
+    #[inline]
+    pub fn from_ne_bytes(bytes: [u8; 4]) -> Self {
+        IpAddr(u32::from_ne_bytes(bytes))
+    }
+
+    #[inline]
+    pub fn to_ne_bytes(self) -> [u8; 4] {
+        self.0.to_ne_bytes()
+    }
+
+    /// Class D address (224.0.0.0/4)
+    pub fn is_multicast(self) -> bool {
+        self.to_ne_bytes()[0] & 0xf0 == 0xe0
+    }
+
+    /// Prefix length of a netmask (number of leading one bits)
+    pub fn prefix_len(self) -> u32 {
+        u32::from_be_bytes(self.to_ne_bytes()).leading_ones()
+    }
+}
+
+impl WireField for IpAddr {
+    const SIZE: usize = 4;
+
+    #[inline]
+    fn decode(bytes: &[u8]) -> Self {
+        let mut octets = [0; 4];
+        octets.copy_from_slice(bytes);
+        IpAddr::from_ne_bytes(octets)
+    }
+
+    #[inline]
+    fn encode(self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.to_ne_bytes());
+    }
+}
+
+impl FromStr for IpAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('.').collect();
+        if parts.len() != 4 {
+            anyhow::bail!("Invalid IP address format: {}", s);
+        }
+
+        let mut bytes = [0u8; 4];
+        for (i, part) in parts.iter().enumerate() {
+            let octet: u8 = part
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid octet in IP address: {}", part))?;
+            bytes[i] = octet;
+        }
+
+        Ok(IpAddr::from_ne_bytes(bytes))
+    }
+}
+
+impl Display for IpAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.to_ne_bytes();
+        write!(f, "{}.{}.{}.{}", bytes[0], bytes[1], bytes[2], bytes[3])
+    }
+}
+
+impl BitAnd for IpAddr {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self::Output {
+        IpAddr(self.0 & rhs.0)
+    }
+}
+
+impl BitOr for IpAddr {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        IpAddr(self.0 | rhs.0)
+    }
+}
+
+impl Not for IpAddr {
+    type Output = Self;
+    fn not(self) -> Self::Output {
+        IpAddr(!self.0)
+    }
+}
+
+impl From<core::net::Ipv4Addr> for IpAddr {
+    fn from(addr: core::net::Ipv4Addr) -> Self {
+        IpAddr::from_ne_bytes(addr.octets())
+    }
+}
+
+impl From<IpAddr> for core::net::Ipv4Addr {
+    fn from(addr: IpAddr) -> Self {
+        core::net::Ipv4Addr::from(addr.to_ne_bytes())
+    }
+}
+
+impl From<IpAddr> for core::net::IpAddr {
+    fn from(addr: IpAddr) -> Self {
+        core::net::IpAddr::V4(addr.into())
+    }
+}
+
+/// Transport endpoint: IP address and port (host byte order)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct IpEndpoint {
+    pub addr: IpAddr,
+    pub port: u16,
+}
+
+impl IpEndpoint {
+    pub const fn new(addr: IpAddr, port: u16) -> Self {
+        Self { addr, port }
+    }
+}
+
+impl From<core::net::SocketAddrV4> for IpEndpoint {
+    fn from(addr: core::net::SocketAddrV4) -> Self {
+        Self::new((*addr.ip()).into(), addr.port())
+    }
+}
+
+impl From<IpEndpoint> for core::net::SocketAddrV4 {
+    fn from(ep: IpEndpoint) -> Self {
+        core::net::SocketAddrV4::new(ep.addr.into(), ep.port)
+    }
+}
+
+impl FromStr for IpEndpoint {
+    type Err = anyhow::Error;
+
+    /// Parse `a.b.c.d:port`
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Invalid endpoint format: {}", s))?;
+        let port = port
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid port: {}", s))?;
+        Ok(Self::new(addr.parse()?, port))
+    }
+}
+
+impl Display for IpEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.addr, self.port)
+    }
+}
+
+/// IPv4 header, in host byte order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpHdr {
+    vhl: u8,
+    tos: u8,
+    total: u16,
+    id: u16,
+    offset: u16,
+    ttl: u8,
+    protocol: u8,
+    sum: u16,
+    src: IpAddr,
+    dst: IpAddr,
+}
+
+wire_codec!(IpHdr, IP_HDR_SIZE_MIN, {
+    vhl: u8,
+    tos: u8,
+    total: u16,
+    id: u16,
+    offset: u16,
+    ttl: u8,
+    protocol: u8,
+    sum: u16,
+    src: IpAddr,
+    dst: IpAddr,
+});
+
+header_accessors!(IpHdr, {
+    vhl: u8,
+    tos: u8,
+    /// Total length
+    total: u16,
+    /// Identification
+    id: u16,
+    /// Flags and fragment offset
+    offset: u16,
+    ttl: u8,
+    /// Header checksum
+    sum: u16,
+    src: IpAddr,
+    dst: IpAddr,
+});
+
+impl IpHdr {
+    pub fn new(
+        protocol: IpProtocol,
+        total: u16,
+        id: u16,
+        offset: u16,
+        src: IpAddr,
+        dst: IpAddr,
+    ) -> Self {
+        let hlen = IP_HDR_SIZE_MIN;
+        let vhl = (IP_VERSION_IPV4 << 4) | ((hlen / 4) as u8);
+        Self {
+            vhl,
+            tos: 0,
+            total,
+            id,
+            offset,
+            ttl: IP_TTL_DEFAULT,
+            protocol: protocol.to_u8(),
+            sum: 0,
+            src,
+            dst,
+        }
+    }
+
+    /// Header length including `hlen - IP_HDR_SIZE_MIN` bytes of options
+    pub fn set_hdr_len(&mut self, hlen: usize) {
+        self.vhl = (IP_VERSION_IPV4 << 4) | ((hlen / 4) as u8);
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+    }
+
+    pub fn with_checksum(mut self) -> Self {
+        self.sum = 0;
+        let bytes = self.to_bytes();
+        self.sum = cksum16(&bytes, 0);
+        self
+    }
+
+    pub fn version(&self) -> u8 {
+        (self.vhl() >> 4) & 0x0f
+    }
+
+    pub fn hdr_len(&self) -> usize {
+        ((self.vhl() & 0x0f) as usize) * 4
+    }
+
+    pub fn protocol(&self) -> IpProtocol {
+        IpProtocol::from_u8(self.protocol)
+    }
+}
+
+impl fmt::Display for IpHdr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "vhl={:#04x}, tos={:#04x}, total={}, id={}, offset={:#06x}, ttl={}, protocol={}, sum={:#06x}, src={}, dst={}",
+            self.vhl(),
+            self.tos(),
+            self.total(),
+            self.id(),
+            self.offset(),
+            self.ttl(),
+            self.protocol().to_u8(),
+            self.sum(),
+            self.src(),
+            self.dst()
+        )
+    }
+}
+
+/// One's complement sum of the pseudo header covered by the TCP and UDP
+/// checksums, to be passed as the initial value of [`cksum16`]
+pub fn pseudo_sum(src: IpAddr, dst: IpAddr, protocol: IpProtocol, len: usize) -> u32 {
+    let mut pseudo = [0u8; 12];
+    pseudo[0..4].copy_from_slice(&src.to_ne_bytes());
+    pseudo[4..8].copy_from_slice(&dst.to_ne_bytes());
+    pseudo[9] = protocol.to_u8();
+    pseudo[10..12].copy_from_slice(&(len as u16).to_be_bytes());
+    !cksum16(&pseudo, 0) as u32
+}
+
+/// Loose Source and Record Route option (RFC 791 Section 3.1)
+///
+/// ```text
+/// +--------+--------+--------+---------//--------+
+/// |10000011| length | pointer|     route data    |
+/// +--------+--------+--------+---------//--------+
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceRoute {
+    /// Offset of the option within the IP header
+    pub offset: usize,
+    /// Pointer field: 1-origin index of the next address within the option
+    pub pointer: u8,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl SourceRoute {
+    /// Build a NOP-padded LSRR option that visits `addrs` in order
+    pub fn build(addrs: &[IpAddr]) -> Result<Vec<u8>> {
+        if addrs.is_empty() || addrs.len() > IP_OPT_SRR_ADDRS_MAX {
+            anyhow::bail!("invalid number of source route addresses: {}", addrs.len());
+        }
+
+        let len = 3 + addrs.len() * IP_ADDR_LEN;
+        let mut opt = Vec::with_capacity(len + 1);
+        opt.push(IP_OPT_NOP);
+        opt.push(IP_OPT_LSRR);
+        opt.push(len as u8);
+        opt.push(IP_OPT_SRR_PTR_MIN);
+        for addr in addrs {
+            opt.extend_from_slice(&addr.to_ne_bytes());
+        }
+        Ok(opt)
+    }
+
+    /// Find and parse an LSRR option in the given IP header
+    pub fn parse(hdr: &[u8]) -> Result<Option<Self>> {
+        let Some((offset, opt)) = find_option(hdr, IP_OPT_LSRR)? else {
+            return Ok(None);
+        };
+        if opt.len() < 3 || !(opt.len() - 3).is_multiple_of(IP_ADDR_LEN) {
+            anyhow::bail!("malformed source route option: len={}", opt.len());
+        }
+        let pointer = opt[2];
+        if pointer < IP_OPT_SRR_PTR_MIN || !(pointer - IP_OPT_SRR_PTR_MIN).is_multiple_of(4) {
+            anyhow::bail!("malformed source route option: pointer={}", pointer);
+        }
+        let addrs = opt[3..]
+            .chunks_exact(IP_ADDR_LEN)
+            .map(|chunk| IpAddr::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+        Ok(Some(Self {
+            offset,
+            pointer,
+            addrs,
+        }))
+    }
+
+    /// The next address to visit, or `None` if the route is exhausted
+    pub fn next_hop(&self) -> Option<IpAddr> {
+        let index = (self.pointer - IP_OPT_SRR_PTR_MIN) as usize / IP_ADDR_LEN;
+        self.addrs.get(index).copied()
+    }
+}
+
+/// Find an option by type in the options area of an IP header.
+/// Returns the option offset within the header and its bytes (including type and length).
+fn find_option(hdr: &[u8], type_: u8) -> Result<Option<(usize, &[u8])>> {
+    let mut offset = IP_HDR_SIZE_MIN;
+    while offset < hdr.len() {
+        match hdr[offset] {
+            IP_OPT_EOL => break,
+            IP_OPT_NOP => offset += 1,
+            opt_type => {
+                let Some(&len) = hdr.get(offset + 1) else {
+                    anyhow::bail!("truncated IP option: type={}", opt_type);
+                };
+                let len = len as usize;
+                if len < 2 || offset + len > hdr.len() {
+                    anyhow::bail!("invalid IP option length: type={}, len={}", opt_type, len);
+                }
+                if opt_type == type_ {
+                    return Ok(Some((offset, &hdr[offset..offset + len])));
+                }
+                offset += len;
+            }
+        }
+    }
+    Ok(None)
+}
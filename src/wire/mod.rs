@@ -0,0 +1,169 @@
+//! Protocol parsing and serialization core: byte order, checksums, addresses
+//! and header layouts.
+//!
+//! Unlike the rest of the crate, this module only needs `core` and `alloc`,
+//! so it is all that is built without the `std` feature (embedded targets
+//! bring their own devices and drive it directly). The `protocol` modules
+//! re-export it, so [`IpHdr`](ip::IpHdr) is also
+//! `microps::protocol::ip::IpHdr` in a `std` build.
+
+pub mod icmp;
+pub mod ip;
+
+/// Convert 16-bit value from network byte order to host byte order
+#[inline]
+pub fn ntoh16(n: u16) -> u16 {
+    u16::from_be(n)
+}
+
+/// Convert 16-bit value from host byte order to network byte order
+#[inline]
+pub fn hton16(h: u16) -> u16 {
+    h.to_be()
+}
+
+/// Convert 32-bit value from network byte order to host byte order
+#[inline]
+pub fn ntoh32(n: u32) -> u32 {
+    u32::from_be(n)
+}
+
+/// Convert 32-bit value from host byte order to network byte order
+#[inline]
+pub fn hton32(h: u32) -> u32 {
+    h.to_be()
+}
+
+/// A header field as encoded on the wire: big-endian, at any alignment
+///
+/// Headers are plain structs in host byte order; `wire_codec!` reads and
+/// writes them field by field through this trait, so a header can be parsed
+/// out of any byte slice without pointer casts.
+pub trait WireField: Copy {
+    /// Encoded length in bytes
+    const SIZE: usize;
+
+    /// Decode from exactly [`SIZE`](Self::SIZE) bytes
+    fn decode(bytes: &[u8]) -> Self;
+
+    /// Encode into exactly [`SIZE`](Self::SIZE) bytes
+    fn encode(self, bytes: &mut [u8]);
+
+    /// Decode from the front of `data` and advance past the field
+    #[inline]
+    fn take(data: &mut &[u8]) -> Self {
+        let (field, rest) = data.split_at(Self::SIZE);
+        *data = rest;
+        Self::decode(field)
+    }
+
+    /// Encode into the front of `buf` and advance past the field
+    #[inline]
+    fn put(self, buf: &mut &mut [u8]) {
+        let (field, rest) = core::mem::take(buf).split_at_mut(Self::SIZE);
+        self.encode(field);
+        *buf = rest;
+    }
+}
+
+macro_rules! impl_wire_field_int {
+    ($($ty:ty),*) => {
+        $(
+            impl WireField for $ty {
+                const SIZE: usize = core::mem::size_of::<$ty>();
+
+                #[inline]
+                fn decode(bytes: &[u8]) -> Self {
+                    let mut raw = [0; core::mem::size_of::<$ty>()];
+                    raw.copy_from_slice(bytes);
+                    <$ty>::from_be_bytes(raw)
+                }
+
+                #[inline]
+                fn encode(self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_be_bytes());
+                }
+            }
+        )*
+    };
+}
+
+impl_wire_field_int!(u8, u16, u32);
+
+/// Generate `from_bytes` and `to_bytes` for a header struct
+///
+/// The fields are listed in wire order with their types; each is decoded
+/// and encoded through [`WireField`]. `from_bytes` copies the header out of
+/// the front of any slice at least `$size` bytes long, aligned or not. Also
+/// asserts at compile time that the fields add up to `$size` bytes.
+macro_rules! wire_codec {
+    ($ty:ty, $size:expr, { $($field:ident: $fty:ty),* $(,)? }) => {
+        const _: () = assert!(0 $(+ <$fty as $crate::wire::WireField>::SIZE)* == $size);
+
+        impl $ty {
+            /// Decode the header at the front of `data`, which may be unaligned
+            pub fn from_bytes(data: &[u8]) -> Option<Self> {
+                let mut data = data.get(..$size)?;
+                Some(Self {
+                    $($field: <$fty as $crate::wire::WireField>::take(&mut data),)*
+                })
+            }
+
+            /// The header as laid out on the wire
+            pub fn to_bytes(&self) -> [u8; $size] {
+                let mut buf = [0; $size];
+                let mut rest = &mut buf[..];
+                $($crate::wire::WireField::put(self.$field, &mut rest);)*
+                buf
+            }
+        }
+    };
+}
+pub(crate) use wire_codec;
+
+/// Generate by-value getters for the fields of a header
+macro_rules! header_accessors {
+    ($ty:ty, { $($(#[$meta:meta])* $field:ident: $fty:ty),* $(,)? }) => {
+        impl $ty {
+            $(
+                $(#[$meta])*
+                #[inline]
+                pub fn $field(&self) -> $fty {
+                    self.$field
+                }
+            )*
+        }
+    };
+}
+pub(crate) use header_accessors;
+
+/// Internet checksum (RFC 1071)
+/// Computes 16-bit one's complement sum
+///
+/// # Arguments
+/// * `data` - byte slice to checksum
+/// * `init` - initial value (used for pseudo-header in TCP/UDP)
+///
+/// # Returns
+/// The one's complement of the one's complement sum
+pub fn cksum16(data: &[u8], init: u32) -> u16 {
+    let mut sum = init;
+
+    // Process 16-bit words
+    let mut chunks = data.chunks_exact(2);
+    for chunk in chunks.by_ref() {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+
+    // Handle odd byte
+    if let Some(&last) = chunks.remainder().first() {
+        sum += (last as u32) << 8;
+    }
+
+    // Fold 32-bit sum to 16 bits
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
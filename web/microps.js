@@ -0,0 +1,110 @@
+// Shim between the page and the microps WebAssembly module (src/wasm.rs).
+//
+// The module runs two stacks, side 0 (192.0.2.1) and side 1 (192.0.2.2). Their
+// link is this shim: frames wait on the wire until `pass` delivers or `drop`
+// discards them, so the page can show each one.
+
+const ETHERTYPES = { 0x0800: "IPv4", 0x0806: "ARP", 0x86dd: "IPv6" };
+const IP_PROTOCOLS = { 1: "ICMP", 6: "TCP", 17: "UDP" };
+const ICMP_TYPES = { 0: "Echo Reply", 3: "Destination Unreachable", 8: "Echo", 11: "Time Exceeded" };
+
+export async function load(source) {
+  const imports = {
+    env: {
+      microps_now_ms: () => performance.now(),
+      microps_epoch_ms: () => Date.now(),
+    },
+  };
+  const { instance } = source instanceof WebAssembly.Module
+    ? { instance: await WebAssembly.instantiate(source, imports) }
+    : await WebAssembly.instantiate(source, imports);
+  const demo = new Demo(instance.exports);
+  demo.init();
+  return demo;
+}
+
+class Demo {
+  constructor(exports) {
+    this.wasm = exports;
+  }
+
+  #check(result, what) {
+    if (result < 0) {
+      throw new Error(`microps: ${what} failed`);
+    }
+    return result;
+  }
+
+  init() {
+    this.#check(this.wasm.microps_demo_init(), "init");
+  }
+
+  ping(seq) {
+    this.#check(this.wasm.microps_demo_ping(seq), "ping");
+  }
+
+  udp() {
+    this.#check(this.wasm.microps_demo_udp(), "udp");
+  }
+
+  /** Echo replies side 0 received since the last call */
+  replies() {
+    return this.#check(this.wasm.microps_demo_replies(), "replies");
+  }
+
+  pending(side) {
+    return this.#check(this.wasm.microps_demo_pending(side), "pending");
+  }
+
+  /** The oldest frame on the wire from `side`, decoded, or null */
+  peek(side) {
+    const len = this.#check(this.wasm.microps_demo_peek(side), "peek");
+    if (len === 0) {
+      return null;
+    }
+    const type = this.wasm.microps_demo_type(side);
+    const ptr = this.wasm.microps_demo_buffer();
+    const bytes = new Uint8Array(this.wasm.memory.buffer, ptr, len).slice();
+    return decode(side, type, bytes);
+  }
+
+  pass(side) {
+    return this.#check(this.wasm.microps_demo_pass(side), "pass") === 1;
+  }
+
+  drop(side) {
+    return this.#check(this.wasm.microps_demo_drop(side), "drop") === 1;
+  }
+
+  tick() {
+    this.#check(this.wasm.microps_demo_tick(), "tick");
+  }
+}
+
+function ipv4(bytes, offset) {
+  return Array.from(bytes.slice(offset, offset + 4)).join(".");
+}
+
+/** Summarize a frame for display */
+export function decode(side, type, bytes) {
+  const frame = { side, type: ETHERTYPES[type] ?? `0x${type.toString(16)}`, bytes, summary: "" };
+  if (type !== 0x0800 || bytes.length < 20) {
+    return frame;
+  }
+  const hlen = (bytes[0] & 0x0f) * 4;
+  const protocol = IP_PROTOCOLS[bytes[9]] ?? `proto ${bytes[9]}`;
+  const src = ipv4(bytes, 12);
+  const dst = ipv4(bytes, 16);
+  const payload = bytes.slice(hlen);
+  let detail = "";
+  if (protocol === "ICMP" && payload.length >= 8) {
+    const seq = (payload[6] << 8) | payload[7];
+    detail = `${ICMP_TYPES[payload[0]] ?? `type ${payload[0]}`} seq=${seq}`;
+  } else if ((protocol === "UDP" || protocol === "TCP") && payload.length >= 4) {
+    const sport = (payload[0] << 8) | payload[1];
+    const dport = (payload[2] << 8) | payload[3];
+    detail = `${sport} > ${dport}`;
+  }
+  frame.summary = `${src} > ${dst} ${protocol} ${detail}`.trim();
+  return frame;
+}